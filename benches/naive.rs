@@ -2,8 +2,11 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
 
-use madeleine::{Command, Madeleine};
+use madeleine::codec::{self, ZSTD_CODEC_ID};
+use madeleine::{Command, LogBackend, Madeleine, MutCommand, PayloadFormat, StorePath, SyncMode};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 enum Action {
@@ -32,8 +35,17 @@ impl Command<'_> for Action {
   }
 }
 
+impl MutCommand<'_> for Action {
+  fn execute_mut(&self, state: &mut Self::SystemState) {
+    match self {
+      Self::Increment(key, amount) => *state.entry(key.to_string()).or_insert(0) += amount,
+      Self::Decrement(key, amount) => *state.entry(key.to_string()).or_insert(0) -= amount,
+    }
+  }
+}
+
 pub fn increment_benchmark(c: &mut Criterion) {
-  let madeleine = Madeleine::new("naive_increment_benchmark".into(), &|| {
+  let madeleine = Madeleine::new_in_memory(&|| {
     let state: HashMap<String, isize> = HashMap::new();
 
     state
@@ -46,12 +58,29 @@ pub fn increment_benchmark(c: &mut Criterion) {
       madeleine
         .execute_command(action)
         .expect("unable to append command in benchmark")
-    })
+    });
+
+    assert!(
+      madeleine.metrics().commands_executed() > 0,
+      "no command latencies were recorded in benchmark"
+    );
   });
+
+  let metrics = madeleine.metrics();
+
+  // Skipped when the benchmark was filtered out.
+  if metrics.commands_executed() > 0 {
+    println!(
+      "increment: {} commands, mean {}ns, p99 at most {}ns",
+      metrics.commands_executed(),
+      metrics.mean_execute_ns(),
+      metrics.p99_execute_ns()
+    );
+  }
 }
 
 pub fn decrement_benchmark(c: &mut Criterion) {
-  let madeleine = Madeleine::new("naive_decrement_benchmark".into(), &|| {
+  let madeleine = Madeleine::new_in_memory(&|| {
     let state: HashMap<String, isize> = HashMap::new();
 
     state
@@ -69,7 +98,7 @@ pub fn decrement_benchmark(c: &mut Criterion) {
 }
 
 pub fn updown_benchmark(c: &mut Criterion) {
-  let madeleine = Madeleine::new("naive_updown_benchmark".into(), &|| {
+  let madeleine = Madeleine::new_in_memory(&|| {
     let state: HashMap<String, isize> = HashMap::new();
 
     state
@@ -97,8 +126,47 @@ pub fn updown_benchmark(c: &mut Criterion) {
   });
 }
 
+pub fn batched_updown_benchmark(c: &mut Criterion) {
+  let madeleine = Madeleine::new_in_memory(&|| {
+    let state: HashMap<String, isize> = HashMap::new();
+
+    state
+  })
+  .expect("unable to instantiate madeleine in benchmark");
+
+  let mut group = c.benchmark_group("batched_updown");
+
+  group.bench_function("one_at_a_time", |b| {
+    b.iter(|| {
+      for i in 1..1024 {
+        madeleine
+          .execute_command(Action::Increment("panda".to_string(), black_box(i)))
+          .expect("unable to append command in benchmark");
+        madeleine
+          .execute_command(Action::Decrement("panda".to_string(), black_box(i)))
+          .expect("unable to append command in benchmark");
+      }
+    })
+  });
+
+  group.bench_function("batched", |b| {
+    b.iter(|| {
+      madeleine
+        .execute_commands((1..1024).flat_map(|i| {
+          [
+            Action::Increment("panda".to_string(), black_box(i)),
+            Action::Decrement("panda".to_string(), black_box(i)),
+          ]
+        }))
+        .expect("unable to append commands in benchmark")
+    })
+  });
+
+  group.finish();
+}
+
 pub fn tap_benchmark(c: &mut Criterion) {
-  let madeleine = Madeleine::new("naive_tap_benchmark".into(), &|| {
+  let madeleine = Madeleine::new_in_memory(&|| {
     let state: HashMap<String, isize> = HashMap::new();
 
     state
@@ -106,15 +174,364 @@ pub fn tap_benchmark(c: &mut Criterion) {
   .expect("unable to instantiate madeleine in benchmark");
 
   c.bench_function("updown", |b| {
-    b.iter(|| madeleine.tap(|state| state.get("panda").unwrap_or(&0).to_owned() + black_box(1)))
+    b.iter(|| madeleine.tap(|state| state.get("panda").unwrap_or(&0).to_owned() + black_box(1)));
+
+    assert!(
+      madeleine.metrics().tap_histogram().count() > 0,
+      "no tap latencies were recorded in benchmark"
+    );
+  });
+}
+
+pub fn large_state_read_benchmark(c: &mut Criterion) {
+  let madeleine = Madeleine::new_in_memory(&|| {
+    let state: HashMap<String, isize> = (0..100_000).map(|i| (i.to_string(), i)).collect();
+
+    state
+  })
+  .expect("unable to instantiate madeleine in benchmark");
+
+  c.bench_function("large_state_tap", |b| {
+    b.iter(|| madeleine.tap(|state| state.get("613").copied().unwrap_or(0) + black_box(1)))
+  });
+
+  c.bench_function("large_state_tap_ref", |b| {
+    b.iter(|| {
+      madeleine
+        .tap_ref(|state| state.get("613").copied().unwrap_or(0) + black_box(1))
+        .expect("unable to tap state in benchmark")
+    })
+  });
+}
+
+pub fn large_state_write_benchmark(c: &mut Criterion) {
+  let madeleine = Madeleine::new_in_memory(&|| {
+    let state: HashMap<String, isize> = (0..100_000).map(|i| (i.to_string(), i)).collect();
+
+    state
+  })
+  .expect("unable to instantiate madeleine in benchmark");
+
+  c.bench_function("large_state_execute_command", |b| {
+    b.iter(|| {
+      let action = Action::Increment("613".to_string(), black_box(20));
+      madeleine
+        .execute_command(action)
+        .expect("unable to append command in benchmark")
+    })
+  });
+
+  c.bench_function("large_state_execute_command_mut", |b| {
+    b.iter(|| {
+      let action = Action::Increment("613".to_string(), black_box(20));
+      madeleine
+        .execute_command_mut(action)
+        .expect("unable to append command in benchmark")
+    })
   });
 }
 
+/// Bytes of every command log segment under a directory, leaving out preallocated indexes.
+fn segment_bytes(path: &Path) -> u64 {
+  fs::read_dir(path)
+    .expect("unable to list store in benchmark")
+    .map(|entry| {
+      let entry = entry.expect("unable to list store in benchmark");
+      let metadata = entry.metadata().expect("unable to stat store in benchmark");
+
+      if metadata.is_dir() {
+        segment_bytes(&entry.path())
+      } else if entry
+        .path()
+        .extension()
+        .is_some_and(|extension| extension == "log")
+      {
+        metadata.len()
+      } else {
+        0
+      }
+    })
+    .sum()
+}
+
+/// Compare appending repetitive commands with and without compressing the log, then print the bytes each store takes.
+/// Only the uncompressed store is benchmarked unless the `zstd` feature is enabled.
+pub fn payload_codec_benchmark(c: &mut Criterion) {
+  let codecs = [
+    ("uncompressed", None),
+    (ZSTD_CODEC_ID, codec::find(ZSTD_CODEC_ID).ok()),
+  ];
+  let key = "panda".repeat(200);
+
+  for (name, payload_codec) in codecs {
+    if name == ZSTD_CODEC_ID && payload_codec.is_none() {
+      continue;
+    }
+
+    let location = StorePath::ephemeral();
+    let madeleine = Madeleine::new(location.clone(), &|| {
+      let state: HashMap<String, isize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in benchmark");
+
+    madeleine
+      .set_payload_codec(payload_codec)
+      .expect("unable to set payload codec in benchmark");
+
+    c.bench_function(&format!("execute_command_{}", name), |b| {
+      b.iter(|| {
+        let action = Action::Increment(key.clone(), black_box(20));
+        madeleine
+          .execute_command(action)
+          .expect("unable to append command in benchmark")
+      })
+    });
+
+    let commands = madeleine.len();
+    drop(madeleine);
+    let store_bytes = segment_bytes(location.as_path());
+    fs::remove_dir_all(&location).expect("unable to remove store in benchmark");
+
+    println!(
+      "{}: {} commands in {} bytes, {} bytes each",
+      name,
+      commands,
+      store_bytes,
+      store_bytes / commands.max(1)
+    );
+  }
+}
+
+/// A numeric-heavy command, for comparing payload formats.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RecordReadings(Vec<u32>);
+
+impl Command<'_> for RecordReadings {
+  type SystemState = u64;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    old_state
+      + self
+        .0
+        .iter()
+        .map(|reading| u64::from(*reading))
+        .sum::<u64>()
+  }
+}
+
+/// Compare appending numeric-heavy commands serialized as JSON, bincode and MessagePack,
+/// then print the bytes each store takes. Formats whose features aren't enabled are skipped.
+pub fn payload_format_benchmark(c: &mut Criterion) {
+  let formats = [
+    ("json", PayloadFormat::Json, true),
+    ("bincode", PayloadFormat::Bincode, cfg!(feature = "bincode")),
+    ("msgpack", PayloadFormat::MessagePack, true),
+  ];
+  let readings: Vec<u32> = (0..100).map(|i| 1_000_000 + i * 7_919).collect();
+
+  for (name, payload_format, enabled) in formats {
+    if !enabled {
+      continue;
+    }
+
+    let location = StorePath::ephemeral();
+    let madeleine = Madeleine::builder(location.clone())
+      .payload_format(payload_format)
+      .build(|| 0_u64)
+      .expect("unable to instantiate madeleine in benchmark");
+
+    c.bench_function(&format!("execute_command_{}_payload", name), |b| {
+      b.iter(|| {
+        madeleine
+          .execute_command(RecordReadings(black_box(readings.clone())))
+          .expect("unable to append command in benchmark")
+      })
+    });
+
+    let commands = madeleine.len();
+    drop(madeleine);
+    let store_bytes = segment_bytes(location.as_path());
+    fs::remove_dir_all(&location).expect("unable to remove store in benchmark");
+
+    println!(
+      "{} payloads: {} commands in {} bytes, {} bytes each",
+      name,
+      commands,
+      store_bytes,
+      store_bytes / commands.max(1)
+    );
+  }
+}
+
+/// A command too cheap to execute for it to matter, so that executing it measures appending to the log.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Tick;
+
+impl Command<'_> for Tick {
+  type SystemState = u64;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    old_state + 1
+  }
+}
+
+/// Measure appending to each enabled backend without syncing every command, to isolate the cost of the append itself.
+pub fn append_benchmark(c: &mut Criterion) {
+  let backends = [
+    ("commit_log", LogBackend::CommitLog, true),
+    ("file", LogBackend::File, true),
+    ("sled", LogBackend::Sled, cfg!(feature = "sled")),
+  ];
+
+  let mut group = c.benchmark_group("append");
+
+  for (name, backend, enabled) in backends {
+    if !enabled {
+      continue;
+    }
+
+    let location = StorePath::ephemeral();
+    let madeleine = Madeleine::builder(location.clone())
+      .log_backend(backend)
+      .sync_mode(SyncMode::Normal)
+      .build(|| 0_u64)
+      .expect("unable to instantiate madeleine in benchmark");
+
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        madeleine
+          .execute_command(black_box(Tick))
+          .expect("unable to append command in benchmark")
+      })
+    });
+
+    drop(madeleine);
+    fs::remove_dir_all(&location).expect("unable to remove store in benchmark");
+  }
+
+  group.finish();
+}
+
+/// Compare appending commands when each is synced to disk and when they're only synced on flush,
+/// for the backends whose features are enabled.
+pub fn sync_mode_benchmark(c: &mut Criterion) {
+  let backends = [
+    ("commit_log", LogBackend::CommitLog, true),
+    ("file", LogBackend::File, true),
+    ("sled", LogBackend::Sled, cfg!(feature = "sled")),
+  ];
+
+  let mut group = c.benchmark_group("sync_mode");
+
+  for (backend_name, backend, enabled) in backends {
+    if !enabled {
+      continue;
+    }
+
+    for (sync_name, sync_mode) in [("full", SyncMode::Full), ("normal", SyncMode::Normal)] {
+      let location = StorePath::ephemeral();
+      let madeleine = Madeleine::builder(location.clone())
+        .log_backend(backend)
+        .sync_mode(sync_mode)
+        .build(HashMap::<String, isize>::new)
+        .expect("unable to instantiate madeleine in benchmark");
+
+      group.bench_function(format!("{}_{}", backend_name, sync_name), |b| {
+        b.iter(|| {
+          madeleine
+            .execute_command(Action::Increment("panda".to_string(), black_box(20)))
+            .expect("unable to append command in benchmark")
+        })
+      });
+
+      drop(madeleine);
+      fs::remove_dir_all(&location).expect("unable to remove store in benchmark");
+    }
+  }
+
+  group.finish();
+}
+
+/// Bytes of every full and differential snapshot file in a store directory.
+fn snapshot_bytes(path: &Path) -> u64 {
+  fs::read_dir(path)
+    .expect("unable to list store in benchmark")
+    .map(|entry| entry.expect("unable to list store in benchmark").path())
+    .filter(|path| {
+      path
+        .extension()
+        .is_some_and(|extension| extension == "snapshot" || extension == "diff")
+    })
+    .map(|path| {
+      fs::metadata(path)
+        .expect("unable to stat snapshot in benchmark")
+        .len()
+    })
+    .sum()
+}
+
+/// Compare resuming a large state from full snapshots alone and from a chain of differential snapshots,
+/// then print the bytes each store's snapshots take.
+pub fn differential_snapshot_benchmark(c: &mut Criterion) {
+  for full_snapshot_every in [1, 10] {
+    let location = StorePath::ephemeral();
+    let madeleine = Madeleine::new(location.clone(), &|| {
+      let state: HashMap<String, isize> = (0..100_000).map(|i| (i.to_string(), i)).collect();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in benchmark");
+
+    madeleine
+      .set_full_snapshot_every(full_snapshot_every)
+      .expect("unable to set full snapshot interval in benchmark");
+
+    for i in 0..10 {
+      madeleine
+        .execute_command(Action::Increment(i.to_string(), 613))
+        .expect("unable to append command in benchmark");
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in benchmark");
+    }
+
+    drop(madeleine);
+
+    c.bench_function(
+      &format!("resume_full_snapshot_every_{}", full_snapshot_every),
+      |b| {
+        b.iter(|| {
+          Madeleine::<HashMap<String, isize>>::resume(location.clone())
+            .expect("unable to resume madeleine in benchmark")
+        })
+      },
+    );
+
+    println!(
+      "full snapshot every {}: 10 snapshots in {} bytes",
+      full_snapshot_every,
+      snapshot_bytes(location.as_path())
+    );
+
+    fs::remove_dir_all(&location).expect("unable to remove store in benchmark");
+  }
+}
+
 criterion_group!(
   benches,
   increment_benchmark,
   decrement_benchmark,
   updown_benchmark,
-  tap_benchmark
+  batched_updown_benchmark,
+  tap_benchmark,
+  large_state_read_benchmark,
+  large_state_write_benchmark,
+  payload_codec_benchmark,
+  payload_format_benchmark,
+  append_benchmark,
+  sync_mode_benchmark,
+  differential_snapshot_benchmark
 );
 criterion_main!(benches);
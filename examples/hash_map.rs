@@ -1,4 +1,4 @@
-use madeleine::{Command, Madeleine, MadeleineError};
+use madeleine::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
@@ -10,16 +10,18 @@ enum Action {
   Decrement(String, usize),
 }
 
-// Implement the `Command` trait for `Action`.
-impl Command<'_> for Action {
+// Implement the `CommandWithOutput` trait for `Action`, which makes it a `Command` too.
+impl CommandWithOutput<'_> for Action {
   // The type of the system's internal state is a map from `String` to `usize`.
   type SystemState = HashMap<String, usize>;
+  // Each action returns the new value of its key to the caller.
+  type Output = usize;
 
-  // The actual logic lives in this `execute` method.
-  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+  // The actual logic lives in this `execute_with_output` method.
+  fn execute_with_output(&self, old_state: Self::SystemState) -> (Self::SystemState, Self::Output) {
     let mut new_state = old_state.clone();
 
-    match self {
+    let new_value = match self {
       Self::Increment(key, amount) => new_state
         .entry(key.to_string())
         .and_modify(|e| *e += amount)
@@ -28,15 +30,16 @@ impl Command<'_> for Action {
         .entry(key.to_string())
         .and_modify(|e| *e -= amount)
         .or_insert(*amount),
-    };
+    }
+    .to_owned();
 
-    new_state
+    (new_state, new_value)
   }
 }
 
 pub fn main() -> Result<(), MadeleineError> {
-  // Initialize the system.
-  let madeleine = Madeleine::new("hash_map_example".into(), &|| {
+  // Initialize the system, or resume it with every command from earlier runs.
+  let madeleine = Madeleine::new_or_resume::<Action, _>("hash_map_example", || {
     let state: HashMap<String, usize> = HashMap::new();
 
     state
@@ -48,12 +51,14 @@ pub fn main() -> Result<(), MadeleineError> {
 
   println!("Current value of 'panda': {:?}", internal_start);
 
+  let mut incremented = 0;
+
   for i in 1..1024 {
     let action = Action::Increment("panda".to_string(), i);
-    madeleine.execute_command(action)?;
+    incremented = madeleine.execute_command_with_output(action)?;
   }
 
-  println!("Finished increment run.");
+  println!("Finished increment run at {}.", incremented);
 
   let internal_mid = madeleine.tap(|state| state.get("panda").unwrap_or(&0).to_owned());
 
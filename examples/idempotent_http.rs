@@ -0,0 +1,185 @@
+use axum::body::{to_bytes, Body};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use madeleine::{CommandWithOutput, IdempotentOutcome, Madeleine, MadeleineError};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tower::ServiceExt;
+use ulid::Ulid;
+
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+// Define a command which increments a named counter, returning its new value.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Increment(String, usize);
+
+impl CommandWithOutput<'_> for Increment {
+  type SystemState = HashMap<String, usize>;
+  type Output = usize;
+
+  fn execute_with_output(&self, old_state: Self::SystemState) -> (Self::SystemState, usize) {
+    let mut new_state = old_state;
+
+    let value = new_state.entry(self.0.to_string()).or_insert(0);
+    *value += self.1;
+    let value = *value;
+
+    (new_state, value)
+  }
+}
+
+// A request for the thread which owns the Madeleine instance, along with where to send the outcome.
+struct StoreRequest {
+  idempotency_key: String,
+  command: Increment,
+  reply: oneshot::Sender<Result<IdempotentOutcome<usize>, MadeleineError>>,
+}
+
+// Madeleine instances can't be sent between threads, so one thread creates and owns the instance
+// and the HTTP handlers talk to it over a channel.
+fn spawn_store(location: &str) -> Result<mpsc::Sender<StoreRequest>, MadeleineError> {
+  let location = location.to_string();
+  let (sender, receiver) = mpsc::channel::<StoreRequest>();
+  let (started, startup) = mpsc::channel();
+
+  thread::spawn(move || {
+    let madeleine = match Madeleine::new(location, HashMap::new) {
+      Ok(madeleine) => {
+        let _ = started.send(Ok(()));
+        madeleine
+      }
+      Err(error) => {
+        let _ = started.send(Err(error));
+        return;
+      }
+    };
+
+    for request in receiver {
+      // The output, the counter's new value, is cached with the key so a retry receives the same response.
+      let outcome =
+        madeleine.execute_idempotent_with_output(&request.idempotency_key, request.command);
+
+      let _ = request.reply.send(outcome);
+    }
+  });
+
+  startup
+    .recv()
+    .expect("store thread exited before starting")?;
+
+  Ok(sender)
+}
+
+#[derive(Debug, Deserialize)]
+struct IncrementBody {
+  amount: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct CounterBody {
+  value: usize,
+}
+
+// Handle `POST /counters/:name/increment`, which requires an `Idempotency-Key` header.
+async fn increment(
+  State(store): State<mpsc::Sender<StoreRequest>>,
+  Path(name): Path<String>,
+  headers: HeaderMap,
+  Json(body): Json<IncrementBody>,
+) -> Response {
+  let Some(idempotency_key) = headers
+    .get("idempotency-key")
+    .and_then(|value| value.to_str().ok())
+  else {
+    return (StatusCode::BAD_REQUEST, "Missing Idempotency-Key header").into_response();
+  };
+
+  let (reply, outcome) = oneshot::channel();
+
+  let request = StoreRequest {
+    idempotency_key: idempotency_key.to_string(),
+    command: Increment(name, body.amount),
+    reply,
+  };
+
+  if store.send(request).is_err() {
+    return StatusCode::SERVICE_UNAVAILABLE.into_response();
+  }
+
+  match outcome.await {
+    Ok(Ok(IdempotentOutcome::Applied(value))) => {
+      (StatusCode::OK, Json(CounterBody { value })).into_response()
+    }
+    Ok(Ok(IdempotentOutcome::Replayed(value))) => (
+      StatusCode::OK,
+      [("idempotent-replayed", "true")],
+      Json(CounterBody { value }),
+    )
+      .into_response(),
+    // The command was applied, but its response wasn't cached, so all we can say is that it's done.
+    Ok(Ok(IdempotentOutcome::AlreadyApplied)) => {
+      (StatusCode::CONFLICT, "Request already applied").into_response()
+    }
+    Ok(Err(error)) => (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()).into_response(),
+    Err(_) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+  }
+}
+
+// Send a request to the router as a client would, printing the response.
+async fn send(app: &Router, idempotency_key: &str, name: &str, amount: usize) {
+  let request = Request::post(format!("/counters/{}/increment", name))
+    .header("content-type", "application/json")
+    .header("idempotency-key", idempotency_key)
+    .body(Body::from(format!("{{\"amount\":{}}}", amount)))
+    .expect("unable to build request");
+
+  let response = app
+    .clone()
+    .oneshot(request)
+    .await
+    .expect("unable to send request");
+
+  let status = response.status();
+  let replayed = response.headers().contains_key("idempotent-replayed");
+  let body = to_bytes(response.into_body(), 1024)
+    .await
+    .expect("unable to read response");
+
+  println!(
+    "{} {} (replayed: {})",
+    status,
+    String::from_utf8_lossy(&body),
+    replayed
+  );
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), MadeleineError> {
+  let store = spawn_store("idempotent_http_example")?;
+
+  let app = Router::new()
+    .route("/counters/:name/increment", post(increment))
+    .with_state(store);
+
+  // Clients pick a fresh key per logical request and reuse it for every retry of that request.
+  let first_key = Ulid::new().to_string();
+  let second_key = Ulid::new().to_string();
+
+  send(&app, &first_key, "panda", 600).await;
+
+  // Pretend the response to the first request was lost, so the client retries it.
+  // The counter isn't incremented again and the client receives the original response.
+  send(&app, &first_key, "panda", 600).await;
+
+  send(&app, &second_key, "panda", 13).await;
+
+  // In a real service, serve the router instead:
+  // axum::serve(tokio::net::TcpListener::bind("127.0.0.1:3000").await?, app).await?;
+
+  Ok(())
+}
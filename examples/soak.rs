@@ -0,0 +1,265 @@
+// A long-running soak test: executes a steady workload against a store with periodic snapshots and compactions,
+// randomly failing appends through a wrapping command store and regularly "killing" the store and resuming it,
+// checking after every restart that nothing was lost or applied twice.
+//
+// Run it with e.g.
+//
+//   cargo run --example soak --features testing -- --duration-secs=86400 --restart-every-secs=600
+//
+// Every option is given as `--name=value`, see `Config` for the defaults.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use madeleine::prelude::*;
+use madeleine::testing::{
+  FailpointCommandStore, FailpointStore, FaultInjector, INJECTED_FAULT_MESSAGE,
+};
+use madeleine::Follower;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+// The state is a fixed number of counters.
+type Counters = BTreeMap<u64, u64>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Bump {
+  key: u64,
+  amount: u64,
+}
+
+impl Command<'_> for Bump {
+  type SystemState = Counters;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    let mut new_state = old_state;
+    *new_state.entry(self.key).or_insert(0) += self.amount;
+    new_state
+  }
+}
+
+#[derive(Debug)]
+struct Config {
+  // How long to run for.
+  duration_secs: u64,
+  // Target rate of commands.
+  commands_per_sec: u64,
+  // Number of counters in the state.
+  state_size: u64,
+  // Take a snapshot after this many successful commands, or never if zero.
+  snapshot_every: u64,
+  // Compact the log this often, or never if zero.
+  compact_every_secs: u64,
+  // Kill and restart the store this often.
+  restart_every_secs: u64,
+  // Fail roughly one append in this many, or none if zero.
+  fail_one_in: u64,
+  // Seed for the workload and the injected faults.
+  seed: u64,
+  // Where to keep the store, a fresh temporary directory if not given.
+  store_path: Option<PathBuf>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      duration_secs: 10,
+      commands_per_sec: 500,
+      state_size: 1_000,
+      snapshot_every: 1_000,
+      compact_every_secs: 3,
+      restart_every_secs: 2,
+      fail_one_in: 50,
+      seed: 613,
+      store_path: None,
+    }
+  }
+}
+
+impl Config {
+  fn from_args() -> Self {
+    let mut config = Self::default();
+
+    for arg in std::env::args().skip(1) {
+      let (name, value) = arg
+        .trim_start_matches("--")
+        .split_once('=')
+        .unwrap_or_else(|| panic!("expected --name=value, got {}", arg));
+
+      let number = || {
+        value
+          .parse::<u64>()
+          .unwrap_or_else(|_| panic!("{} must be a number, got {}", name, value))
+      };
+
+      match name {
+        "duration-secs" => config.duration_secs = number(),
+        "commands-per-sec" => config.commands_per_sec = number().max(1),
+        "state-size" => config.state_size = number().max(1),
+        "snapshot-every" => config.snapshot_every = number(),
+        "compact-every-secs" => config.compact_every_secs = number(),
+        "restart-every-secs" => config.restart_every_secs = number(),
+        "fail-one-in" => config.fail_one_in = number(),
+        "seed" => config.seed = number(),
+        "store-path" => config.store_path = Some(PathBuf::from(value)),
+        _ => panic!("unknown option {}", name),
+      }
+    }
+
+    config
+  }
+}
+
+// Start the store again after a kill, resuming from its latest snapshot and replaying the log after it,
+// with appends to the log failing as the fault injector decides.
+fn restart(
+  store_path: &Path,
+  fault_injector: &Arc<FaultInjector>,
+) -> Result<Madeleine<Counters>, MadeleineError> {
+  let fault_injector = fault_injector.clone();
+
+  Madeleine::builder(store_path.to_path_buf())
+    .wrap_command_store(move |inner| {
+      Box::new(
+        FailpointCommandStore::new(inner, Arc::new(FailpointStore::new()))
+          .with_fault_injector(fault_injector.clone()),
+      )
+    })
+    .resume_replaying::<Bump, _>(Counters::new)
+}
+
+// Check that the restarted store holds exactly the commands which succeeded, and nothing else.
+fn check_invariants(
+  madeleine: &Madeleine<Counters>,
+  store_path: &Path,
+  expected: &Counters,
+  succeeded: u64,
+) -> Result<(), MadeleineError> {
+  let logged = Follower::open(store_path.to_path_buf())?.commands_after(Ulid::nil())?;
+
+  assert_eq!(
+    logged.len() as u64,
+    madeleine.len(),
+    "log doesn't hold every command since the last compaction"
+  );
+  assert_eq!(
+    madeleine.total_commands_ever(),
+    succeeded,
+    "store doesn't count every successful command"
+  );
+  assert!(
+    madeleine.tap_ref(|state| state == expected)?,
+    "state doesn't equal the fold of the successful commands"
+  );
+
+  Ok(())
+}
+
+pub fn main() -> Result<(), MadeleineError> {
+  let config = Config::from_args();
+
+  println!("Soaking with {:?}", config);
+
+  let temp_dir = std::env::temp_dir().join(format!("madeleine_soak_{}", std::process::id()));
+  let store_path = config.store_path.clone().unwrap_or(temp_dir.clone());
+
+  // Create the store if it doesn't exist yet, then start it as after any other kill.
+  drop(Madeleine::new(store_path.clone(), Counters::new)?);
+
+  let fault_injector = Arc::new(FaultInjector::failing_one_in(
+    config.fail_one_in,
+    config.seed,
+  ));
+  let mut madeleine = restart(&store_path, &fault_injector)?;
+
+  // What the state should be, maintained independently of the store.
+  let mut expected = madeleine.tap_ref(|state| state.clone())?;
+  let mut succeeded = madeleine.total_commands_ever();
+  let mut failed = 0;
+  let mut restarts = 0;
+  let mut snapshots = 0;
+  let mut compactions = 0;
+
+  let mut rng_state = config.seed.max(1);
+  let mut next_random = move || {
+    rng_state ^= rng_state << 13;
+    rng_state ^= rng_state >> 7;
+    rng_state ^= rng_state << 17;
+    rng_state
+  };
+
+  let interval = Duration::from_secs(1) / config.commands_per_sec as u32;
+  let started = Instant::now();
+  let deadline = started + Duration::from_secs(config.duration_secs);
+  let mut last_restart = started;
+  let mut last_compaction = started;
+  let mut next_command = started;
+
+  while Instant::now() < deadline {
+    next_command += interval;
+    thread::sleep(next_command.saturating_duration_since(Instant::now()));
+
+    let bump = Bump {
+      key: next_random() % config.state_size,
+      amount: next_random() % 100 + 1,
+    };
+
+    match madeleine.execute_command(bump.clone()) {
+      Ok(_offset) => {
+        expected = bump.execute(expected);
+        succeeded += 1;
+
+        if config.snapshot_every > 0 && succeeded.is_multiple_of(config.snapshot_every) {
+          madeleine.take_snapshot(false)?;
+          snapshots += 1;
+        }
+      }
+      Err(MadeleineError::FileIOError(error)) if error.to_string() == INJECTED_FAULT_MESSAGE => {
+        failed += 1;
+      }
+      Err(error) => return Err(error),
+    }
+
+    if config.compact_every_secs > 0
+      && last_compaction.elapsed() >= Duration::from_secs(config.compact_every_secs)
+    {
+      madeleine.compact()?;
+
+      last_compaction = Instant::now();
+      compactions += 1;
+    }
+
+    if last_restart.elapsed() >= Duration::from_secs(config.restart_every_secs) {
+      drop(madeleine);
+
+      madeleine = restart(&store_path, &fault_injector)?;
+      check_invariants(&madeleine, &store_path, &expected, succeeded)?;
+
+      last_restart = Instant::now();
+      restarts += 1;
+    }
+  }
+
+  check_invariants(&madeleine, &store_path, &expected, succeeded)?;
+
+  println!(
+    "Survived {:?}: {} commands succeeded, {} appends failed, {} snapshots, {} compactions, {} restarts",
+    started.elapsed(),
+    succeeded,
+    failed,
+    snapshots,
+    compactions,
+    restarts
+  );
+
+  drop(madeleine);
+
+  if config.store_path.is_none() {
+    std::fs::remove_dir_all(temp_dir)?;
+  }
+
+  Ok(())
+}
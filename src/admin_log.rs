@@ -0,0 +1,355 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command_log::next_id;
+use crate::compaction::CompactionStage;
+use crate::madeleine_error::MadeleineError;
+use crate::maintenance::MaintenanceTask;
+use crate::tenant::TenantId;
+
+/// Name of the file in which administrative operations are recorded, one JSON object per line.
+pub(crate) const ADMIN_LOG_FILE_NAME: &str = "admin_log";
+
+/// An opaque position in a store's admin log, returned in every `OpenReport`.
+/// Keep it, and pass it to `MadeleineBuilder::admin_ops_since` on the next open to learn what happened in between.
+/// It's stable across processes and can be stored as a string.
+#[derive(
+  Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct AdminMarker(Ulid);
+
+impl AdminMarker {
+  /// A marker before every operation, for services which have never seen the store.
+  pub fn start() -> Self {
+    Self(Ulid::nil())
+  }
+}
+
+impl fmt::Display for AdminMarker {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+impl FromStr for AdminMarker {
+  type Err = ulid::DecodeError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ulid::from_string(s).map(Self)
+  }
+}
+
+/// What an administrative operation did to the store.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum AdminOperationKind {
+  /// The log's history was replaced with a snapshot, see `Madeleine::compact`.
+  Compacted {
+    /// Id of the snapshot now holding the history.
+    snapshot_id: usize,
+    /// Number of commands removed from the log.
+    commands_removed: u64,
+  },
+  /// The commands up to one covered by a snapshot were removed from the log, see `Madeleine::compact_log`.
+  LogCompacted {
+    /// ULID of the last command removed.
+    up_to: Ulid,
+    /// Number of commands removed from the log.
+    commands_removed: u64,
+  },
+  /// A tenant's state and history were deleted, see `Madeleine::purge_tenant`.
+  TenantPurged {
+    /// The purged tenant.
+    tenant: TenantId,
+    /// Id of the snapshot taken without the tenant.
+    snapshot_id: usize,
+    /// Number of the tenant's commands removed from the log.
+    commands_removed: u64,
+  },
+  /// A compaction or purge interrupted by a crash was finished, or undone, while opening the store.
+  CompactionRecovered {
+    /// The stage it had reached.
+    stage: CompactionStage,
+    /// Whether it was undone rather than finished.
+    undone: bool,
+    /// The tenant being purged, if it was a purge.
+    purged_tenant: Option<TenantId>,
+  },
+  /// The store was created by importing history, see `export::import` and `import::from_rows`.
+  Imported {
+    /// Number of commands imported.
+    commands: u64,
+  },
+  /// The store was created by merging two stores' histories, see `merge::merge_stores`.
+  Merged {
+    /// Number of commands in the merged log.
+    commands: u64,
+    /// Number of conflicts the resolver decided.
+    conflicts: u64,
+  },
+  /// Commands in the log were replaced with tombstones or had their payloads rewritten, see `Madeleine::redact_matching`.
+  Redacted {
+    /// Number of commands checked.
+    commands_scanned: u64,
+    /// Number of commands replaced with tombstones.
+    commands_tombstoned: u64,
+    /// Number of commands whose payloads were rewritten.
+    commands_rewritten: u64,
+  },
+  /// The store was recreated from a dump, see `Madeleine::restore_bytes`, possibly rolling it back.
+  Restored,
+  /// The state and log were reverted to an earlier snapshot, see `Madeleine::rollback_to_snapshot`.
+  RolledBack {
+    /// Id of the snapshot rolled back to.
+    snapshot_id: usize,
+    /// Number of commands removed from the log.
+    commands_removed: u64,
+  },
+  /// The state was converted to a new type, see `Madeleine::migrate_state`.
+  StateMigrated {
+    /// Rust type name of the state before the migration.
+    from_type: String,
+    /// Rust type name of the state after the migration.
+    to_type: String,
+    /// Canonical hash of the state before the migration.
+    from_state_hash: String,
+    /// Canonical hash of the state after the migration.
+    to_state_hash: String,
+    /// Id of the snapshot holding the migrated state.
+    snapshot_id: usize,
+  },
+  /// A scheduled maintenance task was run, see `Madeleine::tick_maintenance`.
+  MaintenanceRan {
+    /// The task.
+    task: MaintenanceTask,
+    /// Why it failed, if it did.
+    error: Option<String>,
+  },
+}
+
+/// An administrative operation, as recorded in the store's admin log.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AdminOperation {
+  /// Position of the operation in the admin log.
+  pub marker: AdminMarker,
+  /// When the operation happened.
+  pub at: SystemTime,
+  /// What the operation did.
+  #[serde(flatten)]
+  pub kind: AdminOperationKind,
+}
+
+/// Append an operation to the store's admin log.
+pub(crate) fn record(
+  location_dir_path: &Path,
+  kind: AdminOperationKind,
+) -> Result<AdminOperation, MadeleineError> {
+  let operation = AdminOperation {
+    marker: AdminMarker(next_id()),
+    at: SystemTime::now(),
+    kind,
+  };
+
+  let mut line = serde_json::to_vec(&operation)?;
+  line.push(b'\n');
+
+  let mut admin_log = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(location_dir_path.join(ADMIN_LOG_FILE_NAME))?;
+
+  admin_log.write_all(&line)?;
+  admin_log.flush()?;
+
+  Ok(operation)
+}
+
+/// Read every operation recorded after `marker`, oldest first.
+pub fn admin_ops_since(
+  location_dir_path: &Path,
+  marker: AdminMarker,
+) -> Result<Vec<AdminOperation>, MadeleineError> {
+  let admin_log_path = location_dir_path.join(ADMIN_LOG_FILE_NAME);
+
+  if !admin_log_path.is_file() {
+    return Ok(Vec::new());
+  }
+
+  let raw = fs::read(admin_log_path)?;
+  let mut operations = Vec::new();
+
+  for line in raw
+    .split(|byte| *byte == b'\n')
+    .filter(|line| !line.is_empty())
+  {
+    let operation: AdminOperation = serde_json::from_slice(line)?;
+
+    if operation.marker > marker {
+      operations.push(operation);
+    }
+  }
+
+  Ok(operations)
+}
+
+/// The marker of the latest operation, or `AdminMarker::start()` if none were recorded.
+pub(crate) fn latest_marker(location_dir_path: &Path) -> Result<AdminMarker, MadeleineError> {
+  Ok(
+    admin_ops_since(location_dir_path, AdminMarker::start())?
+      .last()
+      .map_or_else(AdminMarker::start, |operation| operation.marker),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use std::sync::Arc;
+
+  use crate::testing::{Add, FailpointStore, StorageOperation};
+  use crate::Madeleine;
+
+  fn kinds(operations: &[AdminOperation]) -> Vec<AdminOperationKind> {
+    operations
+      .iter()
+      .map(|operation| operation.kind.clone())
+      .collect()
+  }
+
+  #[test]
+  fn test_out_of_band_compaction_is_reported_on_next_open() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let service = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    assert_eq!(service.open_report().admin_marker, AdminMarker::start());
+    assert_eq!(service.open_report().admin_ops_since, None);
+
+    service
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+    service
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let stored_marker = service.open_report().admin_marker.to_string();
+
+    service.close().expect("unable to close madeleine in test");
+
+    // An operator compacts the store while the service is down.
+    let operator =
+      Madeleine::<u64>::resume(store_path.clone()).expect("unable to resume madeleine in test");
+
+    operator
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+    operator.compact().expect("unable to compact in test");
+    operator.close().expect("unable to close madeleine in test");
+
+    let marker: AdminMarker = stored_marker
+      .parse()
+      .expect("unable to parse marker in test");
+    let service = Madeleine::<u64>::builder(store_path.clone())
+      .admin_ops_since(marker)
+      .resume()
+      .expect("unable to resume madeleine in test");
+
+    let report = service.open_report().clone();
+    let operations = report.admin_ops_since.expect("admin ops missing in test");
+
+    assert_eq!(
+      kinds(&operations),
+      vec![AdminOperationKind::Compacted {
+        snapshot_id: 1,
+        commands_removed: 2,
+      }]
+    );
+    assert_eq!(report.admin_marker, operations[0].marker);
+
+    drop(service);
+
+    let service = Madeleine::<u64>::builder(store_path)
+      .admin_ops_since(report.admin_marker)
+      .resume()
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(service.open_report().admin_ops_since, Some(Vec::new()));
+  }
+
+  #[test]
+  fn test_recovery_and_restore_are_recorded() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let dump = {
+      let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+        .expect("unable to instantiate madeleine in test");
+
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+
+      let dump = madeleine.dump_bytes().expect("unable to dump in test");
+
+      let failpoints = FailpointStore::new();
+      failpoints.fail_nth(StorageOperation::SnapshotWrite, 1);
+
+      madeleine
+        .set_failpoints(Some(Arc::new(failpoints)))
+        .expect("unable to set failpoints in test");
+
+      assert!(madeleine.compact().is_err());
+
+      dump
+    };
+
+    let resumed = Madeleine::<u64>::builder(store_path.clone())
+      .admin_ops_since(AdminMarker::start())
+      .resume()
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed.open_report().admin_ops_since.as_deref().map(kinds),
+      Some(vec![AdminOperationKind::CompactionRecovered {
+        stage: CompactionStage::Intent,
+        undone: true,
+        purged_tenant: None,
+      }])
+    );
+
+    let restored_path = temp_dir.path().join("restored_store");
+    let restored = Madeleine::<u64>::restore_bytes(restored_path.clone(), &dump)
+      .expect("unable to restore in test");
+
+    assert_eq!(
+      restored.open_report().admin_marker,
+      latest_marker(&restored_path).expect("unable to read marker in test")
+    );
+    assert_eq!(
+      kinds(
+        &admin_ops_since(&restored_path, AdminMarker::start())
+          .expect("unable to read admin log in test")
+      ),
+      vec![AdminOperationKind::Restored]
+    );
+  }
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::madeleine_error::MadeleineError;
+
+/// Reports how many bytes the calling thread has allocated, usually from a counting global allocator
+/// installed by the application. Madeleine forbids unsafe code, so it can't install one itself.
+pub trait AllocationCounter: Send + Sync {
+  /// Total bytes allocated by the calling thread so far. Only differences are used, so it may start from anything.
+  fn thread_bytes_allocated(&self) -> u64;
+}
+
+/// A check, made after each command returns, of how much it allocated while executing, see `Madeleine::set_allocation_check`.
+///
+/// This isn't a budget enforced during execution: Madeleine can't install an allocator, since it forbids unsafe code,
+/// and an allocator can't fail a command part way without aborting the process. So a command which exhausts memory
+/// still does, and the check only discards a command which allocated more than `max_bytes` but returned.
+/// Only allocations made by the thread executing the command are counted, not those of threads it spawns,
+/// and memory freed during execution isn't subtracted.
+#[derive(Clone)]
+pub struct AllocationCheck {
+  counter: Arc<dyn AllocationCounter>,
+  max_bytes: u64,
+}
+
+impl AllocationCheck {
+  /// Discard commands which allocated more than `max_bytes`, as measured by `counter`.
+  pub fn new(counter: Arc<dyn AllocationCounter>, max_bytes: u64) -> Self {
+    Self { counter, max_bytes }
+  }
+
+  /// The most bytes a command may allocate and still be applied.
+  pub fn max_bytes(&self) -> u64 {
+    self.max_bytes
+  }
+
+  /// Start measuring a command's allocations on the calling thread.
+  pub(crate) fn start(&self) -> AllocationMeter<'_> {
+    AllocationMeter {
+      check: self,
+      allocated_before: self.counter.thread_bytes_allocated(),
+    }
+  }
+}
+
+impl fmt::Debug for AllocationCheck {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("AllocationCheck")
+      .field("max_bytes", &self.max_bytes)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Measures the allocations of one command.
+pub(crate) struct AllocationMeter<'a> {
+  check: &'a AllocationCheck,
+  allocated_before: u64,
+}
+
+impl AllocationMeter<'_> {
+  /// Fail with `MadeleineError::CommandResourceLimit` if the command allocated more than `max_bytes`.
+  pub(crate) fn finish(self) -> Result<(), MadeleineError> {
+    let bytes_allocated = self
+      .check
+      .counter
+      .thread_bytes_allocated()
+      .saturating_sub(self.allocated_before);
+
+    if bytes_allocated > self.check.max_bytes {
+      Err(MadeleineError::CommandResourceLimit { bytes_allocated })
+    } else {
+      Ok(())
+    }
+  }
+}
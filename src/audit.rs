@@ -0,0 +1,393 @@
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::batch::rejected_commands;
+use crate::command_log::CommandLog;
+use crate::export::ExportRange;
+use crate::hashing::HashAlgo;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::require_json_entry;
+use crate::tenant::{payload_tenant, TenantId};
+
+/// Version of the audit event format written by this release.
+pub const AUDIT_FORMAT_VERSION: u32 = 1;
+
+/// What replaces redacted values in payload previews.
+pub const REDACTED: &str = "[redacted]";
+
+/// Options for `Madeleine::export_audit`. By default, events carry no actor and no payload preview.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditOptions {
+  /// JSON pointer to the actor metadata within each command, e.g. `/actor`, see `serde_json::Value::pointer`.
+  pub actor_pointer: Option<String>,
+  /// Include a preview of each command, cut to at most this many bytes.
+  pub payload_preview_bytes: Option<usize>,
+  /// Keys of objects, at any depth, whose values are replaced with `REDACTED` in previews.
+  pub redacted_keys: Vec<String>,
+  /// Also report commands rejected from best-effort batches, see `batch::rejected_commands`.
+  pub include_rejected: bool,
+}
+
+/// Whether an audited command was applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOutcome {
+  /// The command was applied to the state and logged.
+  Applied,
+  /// The command was rejected from a best-effort batch.
+  Rejected,
+}
+
+/// One line of an audit export, as written by `Madeleine::export_audit`.
+///
+/// Each line is one JSON object, for one command.
+/// Version 1 of the format, see `AUDIT_FORMAT_VERSION`, has these fields, always in this order:
+///
+/// - `version`: the format version, `1`.
+/// - `store_id`: ULID of the store.
+/// - `tenant`: the tenant the command was executed for, or `null` if it isn't a `TenantCommand`.
+/// - `actor`: the value found at `AuditOptions::actor_pointer` in the command, or `null`.
+/// - `command_type`: the command's enum variant, as serialized by serde's default, externally tagged
+///   representation, or `null` if the command isn't serialized that way. Tenant commands report their inner command's.
+/// - `id`: ULID of the command, or `null` for rejected commands, which are never logged.
+/// - `timestamp_ms`: milliseconds since the Unix epoch when the command was logged, or rejected.
+/// - `payload_hash`: hex-encoded hash of the command's canonical JSON, never the command itself.
+/// - `hash_algo`: the store's hash function, which computed `payload_hash`, see `HashAlgo`.
+/// - `outcome`: `"applied"`, or `"rejected"` for commands rejected from best-effort batches.
+/// - `reason`: why a rejected command was rejected. Only present for rejected commands.
+/// - `payload_preview`: the command's JSON with `AuditOptions::redacted_keys` redacted, cut to at most
+///   `AuditOptions::payload_preview_bytes` bytes. Only present when previews are requested.
+///
+/// Later versions may add fields, but never remove or reorder these.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AuditEvent {
+  /// Version of the format.
+  pub version: u32,
+  /// Identifier of the store.
+  pub store_id: Ulid,
+  /// The tenant the command was executed for, if it's a `TenantCommand`.
+  pub tenant: Option<TenantId>,
+  /// Actor metadata found in the command.
+  pub actor: Option<Value>,
+  /// The command's enum variant.
+  pub command_type: Option<String>,
+  /// Identifier of the command, if it was logged.
+  pub id: Option<Ulid>,
+  /// When the command was logged or rejected, in milliseconds since the Unix epoch.
+  pub timestamp_ms: u64,
+  /// Hex-encoded hash of the command's canonical JSON.
+  pub payload_hash: String,
+  /// Hash function which computed `payload_hash`.
+  pub hash_algo: HashAlgo,
+  /// Whether the command was applied.
+  pub outcome: AuditOutcome,
+  /// Why the command was rejected.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub reason: Option<String>,
+  /// Redacted and cut preview of the command.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub payload_preview: Option<String>,
+}
+
+/// Write an audit event for each command in `range` to `writer`, returning the number written.
+/// Applied commands come first, in log order, followed by rejected commands, oldest first.
+pub(crate) fn export_audit<W: Write>(
+  command_log: &CommandLog,
+  location_dir_path: &Path,
+  store_id: Ulid,
+  hash_algo: HashAlgo,
+  range: ExportRange,
+  options: &AuditOptions,
+  mut writer: W,
+) -> Result<u64, MadeleineError> {
+  let mut events = Vec::new();
+
+  command_log.for_each_entry(|_offset, entry| {
+    let (id, command): (Ulid, Value) =
+      serde_json::from_slice(require_json_entry(entry, "auditing")?)?;
+
+    if range.contains(id) {
+      events.push(AuditEvent {
+        id: Some(id),
+        timestamp_ms: id.timestamp_ms(),
+        ..audit_event(&command, store_id, hash_algo, options)?
+      });
+    }
+
+    Ok(())
+  })?;
+
+  if options.include_rejected {
+    for rejected in rejected_commands(location_dir_path)? {
+      let timestamp_ms = rejected
+        .at
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+
+      if range
+        .from
+        .is_none_or(|from| timestamp_ms >= from.timestamp_ms())
+        && range.to.is_none_or(|to| timestamp_ms <= to.timestamp_ms())
+      {
+        events.push(AuditEvent {
+          timestamp_ms,
+          outcome: AuditOutcome::Rejected,
+          reason: Some(rejected.failure.error.clone()),
+          ..audit_event(&rejected.command, store_id, hash_algo, options)?
+        });
+      }
+    }
+  }
+
+  for event in &events {
+    serde_json::to_writer(&mut writer, event)?;
+    writer.write_all(b"\n")?;
+  }
+
+  writer.flush()?;
+
+  Ok(events.len() as u64)
+}
+
+/// The parts of an event which depend only on the command, for an applied command without an id.
+fn audit_event(
+  command: &Value,
+  store_id: Ulid,
+  hash_algo: HashAlgo,
+  options: &AuditOptions,
+) -> Result<AuditEvent, MadeleineError> {
+  let tenant = payload_tenant(command);
+  let inner_command = match tenant {
+    Some(_) => command.get("command").unwrap_or(command),
+    None => command,
+  };
+
+  Ok(AuditEvent {
+    version: AUDIT_FORMAT_VERSION,
+    store_id,
+    tenant,
+    actor: options
+      .actor_pointer
+      .as_deref()
+      .and_then(|pointer| command.pointer(pointer))
+      .cloned(),
+    command_type: command_type(inner_command),
+    id: None,
+    timestamp_ms: 0,
+    payload_hash: hash_algo.canonical_hash(command)?,
+    hash_algo,
+    outcome: AuditOutcome::Applied,
+    reason: None,
+    payload_preview: match options.payload_preview_bytes {
+      Some(max_bytes) => Some(preview(command, &options.redacted_keys, max_bytes)?),
+      None => None,
+    },
+  })
+}
+
+/// The variant of an externally tagged enum: the string of a unit variant, or the only key of any other.
+fn command_type(command: &Value) -> Option<String> {
+  match command {
+    Value::String(variant) => Some(variant.clone()),
+    Value::Object(fields) if fields.len() == 1 => fields.keys().next().cloned(),
+    _ => None,
+  }
+}
+
+/// The command's JSON with redacted values, cut to at most `max_bytes` bytes without splitting a character.
+fn preview(
+  command: &Value,
+  redacted_keys: &[String],
+  max_bytes: usize,
+) -> Result<String, MadeleineError> {
+  let mut redacted = command.clone();
+  redact(&mut redacted, redacted_keys);
+
+  let mut preview = serde_json::to_string(&redacted)?;
+
+  if preview.len() > max_bytes {
+    let mut end = max_bytes;
+
+    while !preview.is_char_boundary(end) {
+      end -= 1;
+    }
+
+    preview.truncate(end);
+  }
+
+  Ok(preview)
+}
+
+fn redact(value: &mut Value, redacted_keys: &[String]) {
+  match value {
+    Value::Object(fields) => {
+      for (key, field) in fields.iter_mut() {
+        if redacted_keys.contains(key) {
+          *field = Value::String(REDACTED.to_string());
+        } else {
+          redact(field, redacted_keys);
+        }
+      }
+    }
+    Value::Array(items) => {
+      for item in items {
+        redact(item, redacted_keys);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::{BatchMode, Command, Madeleine, TenantCommand, TenantId};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  enum Account {
+    Open { owner: String, secret: String },
+    Deposit(u64),
+  }
+
+  impl Command<'_> for Account {
+    type SystemState = u64;
+
+    fn validate(&self, _state: &Self::SystemState) -> Result<(), String> {
+      match self {
+        Self::Deposit(0) => Err(String::from("deposits must not be empty")),
+        _ => Ok(()),
+      }
+    }
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      match self {
+        Self::Open { .. } => old_state,
+        Self::Deposit(amount) => old_state + amount,
+      }
+    }
+  }
+
+  fn read_events(exported: &[u8]) -> Vec<AuditEvent> {
+    exported
+      .split(|byte| *byte == b'\n')
+      .filter(|line| !line.is_empty())
+      .map(|line| serde_json::from_slice(line).expect("unable to parse audit event in test"))
+      .collect()
+  }
+
+  #[test]
+  fn test_audit_events_hash_payloads_and_redact_previews() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let open = Account::Open {
+      owner: String::from("ada"),
+      secret: String::from("hunter2"),
+    };
+
+    madeleine
+      .execute_command(open.clone())
+      .expect("unable to execute command in test");
+    madeleine
+      .execute_batch(
+        vec![Account::Deposit(5), Account::Deposit(0)],
+        BatchMode::BestEffort,
+      )
+      .expect("unable to execute batch in test");
+
+    let options = AuditOptions {
+      actor_pointer: Some(String::from("/Open/owner")),
+      payload_preview_bytes: Some(40),
+      redacted_keys: vec![String::from("secret")],
+      include_rejected: true,
+    };
+
+    let mut exported = Vec::new();
+    let written = madeleine
+      .export_audit(ExportRange::default(), &options, &mut exported)
+      .expect("unable to export audit in test");
+    let events = read_events(&exported);
+
+    assert_eq!(written, 3);
+    assert_eq!(
+      events
+        .iter()
+        .map(|event| (event.command_type.as_deref(), event.outcome))
+        .collect::<Vec<_>>(),
+      vec![
+        (Some("Open"), AuditOutcome::Applied),
+        (Some("Deposit"), AuditOutcome::Applied),
+        (Some("Deposit"), AuditOutcome::Rejected),
+      ]
+    );
+    assert_eq!(events[0].actor, Some(Value::from("ada")));
+    assert_eq!(events[1].actor, None);
+    assert_eq!(
+      events[0].payload_hash,
+      HashAlgo::default()
+        .canonical_hash(&open)
+        .expect("unable to hash command in test")
+    );
+    assert_eq!(
+      events[0].payload_preview.as_deref(),
+      Some("{\"Open\":{\"owner\":\"ada\",\"secret\":\"[redact")
+    );
+    assert!(!String::from_utf8_lossy(&exported).contains("hunter2"));
+    assert_eq!(events[2].id, None);
+    assert_eq!(
+      events[2].reason.as_deref(),
+      Some("deposits must not be empty")
+    );
+  }
+
+  #[test]
+  fn test_audit_events_report_tenants_and_respect_range() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), Default::default)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in 1..=3 {
+      madeleine
+        .execute_command(TenantCommand {
+          tenant: TenantId::new("acme"),
+          command: Account::Deposit(amount),
+        })
+        .expect("unable to execute command in test");
+    }
+
+    let second_id = crate::Follower::open(temp_dir.path().join("test_store"))
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read log in test")[1]
+      .id;
+
+    let mut exported = Vec::new();
+    let written = madeleine
+      .export_audit(
+        ExportRange {
+          from: Some(second_id),
+          to: None,
+        },
+        &AuditOptions::default(),
+        &mut exported,
+      )
+      .expect("unable to export audit in test");
+    let events = read_events(&exported);
+
+    assert_eq!(written, 2);
+    assert_eq!(events[0].id, Some(second_id));
+    assert_eq!(events[0].tenant, Some(TenantId::new("acme")));
+    assert_eq!(events[0].command_type.as_deref(), Some("Deposit"));
+    assert_eq!(events[0].payload_preview, None);
+    assert!(!String::from_utf8_lossy(&exported).contains("payload_preview"));
+  }
+}
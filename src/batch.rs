@@ -0,0 +1,307 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use commitlog::Offset;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::madeleine_error::MadeleineError;
+
+/// Name of the file in which commands rejected from best-effort batches are journaled, one JSON object per line.
+pub(crate) const REJECTIONS_FILE_NAME: &str = "rejections";
+
+/// How `Madeleine::execute_batch` treats a command which fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchMode {
+  /// Apply and log every command or none of them: the first command which fails rolls back the batch.
+  #[default]
+  Atomic,
+  /// Apply and log every command which passes validation, journaling and returning those which don't.
+  BestEffort,
+}
+
+/// A command in a batch which failed, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BatchFailure {
+  /// Position of the command in the batch.
+  pub index: usize,
+  /// Rust type of the command.
+  pub command_type: String,
+  /// Why the command failed.
+  pub error: String,
+}
+
+impl BatchFailure {
+  pub(crate) fn new<C>(index: usize, error: String) -> Self {
+    Self {
+      index,
+      command_type: std::any::type_name::<C>().to_string(),
+      error,
+    }
+  }
+}
+
+impl fmt::Display for BatchFailure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "command {} ({}) failed: {}",
+      self.index, self.command_type, self.error
+    )
+  }
+}
+
+/// Outcome of a batch.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+  /// Offsets of the commands which were applied and logged, in batch order.
+  pub offsets: Vec<Offset>,
+  /// Commands which were rejected, only ever present in `BatchMode::BestEffort`.
+  pub rejected: Vec<BatchFailure>,
+}
+
+/// A command rejected from a best-effort batch, as journaled in the store.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RejectedCommand {
+  /// When the command was rejected.
+  pub at: SystemTime,
+  /// The command's position in its batch, and why it was rejected.
+  pub failure: BatchFailure,
+  /// The command, serialized as JSON.
+  pub command: Value,
+}
+
+/// Append a rejected command to the store's journal of rejections.
+pub(crate) fn journal_rejection<C: Serialize>(
+  location_dir_path: &Path,
+  failure: &BatchFailure,
+  command: &C,
+) -> Result<(), MadeleineError> {
+  let rejected = RejectedCommand {
+    at: SystemTime::now(),
+    failure: failure.clone(),
+    command: serde_json::to_value(command)?,
+  };
+
+  let mut line = serde_json::to_vec(&rejected)?;
+  line.push(b'\n');
+
+  let mut journal = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(location_dir_path.join(REJECTIONS_FILE_NAME))?;
+
+  journal.write_all(&line)?;
+  journal.flush()?;
+
+  Ok(())
+}
+
+/// Read the commands rejected from best-effort batches, oldest first.
+pub fn rejected_commands(location_dir_path: &Path) -> Result<Vec<RejectedCommand>, MadeleineError> {
+  let journal_path = location_dir_path.join(REJECTIONS_FILE_NAME);
+
+  if !journal_path.is_file() {
+    return Ok(Vec::new());
+  }
+
+  let raw = fs::read(journal_path)?;
+  let mut rejections = Vec::new();
+
+  for line in raw
+    .split(|byte| *byte == b'\n')
+    .filter(|line| !line.is_empty())
+  {
+    rejections.push(serde_json::from_slice(line)?);
+  }
+
+  Ok(rejections)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use ulid::Ulid;
+
+  use crate::{Command, Follower, Madeleine, ReadOnlyMadeleine};
+
+  /// Moves money in and out of a balance which must never go negative.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Transfer(i64);
+
+  impl Command<'_> for Transfer {
+    type SystemState = i64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.0
+    }
+
+    fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+      if state + self.0 < 0 {
+        Err(format!("balance of {} can't cover {}", state, self.0))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  fn batch() -> Vec<Transfer> {
+    vec![Transfer(100), Transfer(-60), Transfer(-50), Transfer(10)]
+  }
+
+  fn logged_commands(location_dir_path: &Path) -> usize {
+    Follower::open(location_dir_path.to_path_buf())
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .map(|commands| commands.len())
+      .expect("unable to read log in test")
+  }
+
+  #[test]
+  fn test_atomic_batch_rolls_back_on_failure() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_i64)
+      .expect("unable to instantiate madeleine in test");
+
+    let error = madeleine
+      .execute_batch(batch(), BatchMode::Atomic)
+      .expect_err("failing batch accepted in test");
+
+    match error {
+      MadeleineError::BatchFailed { failure, staged } => {
+        assert_eq!(failure.index, 2);
+        assert!(failure.command_type.ends_with("Transfer"));
+        assert_eq!(failure.error, "balance of 40 can't cover -50");
+        assert_eq!(staged, 2);
+      }
+      error => panic!("unexpected error in test: {}", error),
+    }
+
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(0));
+    assert_eq!(logged_commands(&store_path), 0);
+
+    let report = madeleine
+      .execute_batch(
+        vec![Transfer(100), Transfer(-60), Transfer(10)],
+        BatchMode::Atomic,
+      )
+      .expect("unable to execute batch in test");
+
+    assert_eq!(report.offsets, vec![0, 1, 2]);
+    assert!(report.rejected.is_empty());
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(50));
+    assert!(rejected_commands(&store_path)
+      .expect("unable to read rejections in test")
+      .is_empty());
+
+    drop(madeleine);
+
+    let replica = ReadOnlyMadeleine::<Transfer, i64>::open(store_path, || 0_i64)
+      .expect("unable to open replica in test");
+
+    assert_eq!(replica.into_inner(), 50);
+  }
+
+  /// Adds to a balance, but can't be serialized when unlogged, e.g. because it holds a handle.
+  #[derive(Debug, Clone, Deserialize)]
+  struct Deposit {
+    amount: i64,
+    unlogged: bool,
+  }
+
+  impl Serialize for Deposit {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      if self.unlogged {
+        return Err(serde::ser::Error::custom("deposit can't be logged"));
+      }
+
+      serializer.serialize_newtype_struct("Deposit", &self.amount)
+    }
+  }
+
+  impl Command<'_> for Deposit {
+    type SystemState = i64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.amount
+    }
+  }
+
+  #[test]
+  fn test_atomic_batch_rolls_back_on_serialization_failure() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_i64)
+      .expect("unable to instantiate madeleine in test");
+
+    let deposit = |amount, unlogged| Deposit { amount, unlogged };
+
+    let error = madeleine
+      .execute_batch(
+        vec![deposit(10, false), deposit(20, true), deposit(30, false)],
+        BatchMode::Atomic,
+      )
+      .expect_err("unserializable batch accepted in test");
+
+    assert!(matches!(
+      error,
+      MadeleineError::BatchFailed { ref failure, staged: 1 }
+        if failure.index == 1 && failure.error.contains("deposit can't be logged")
+    ));
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(0));
+    assert_eq!(logged_commands(&store_path), 0);
+
+    madeleine
+      .execute_batch(vec![deposit(10, false)], BatchMode::Atomic)
+      .expect("unable to execute batch in test");
+
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(10));
+    assert_eq!(logged_commands(&store_path), 1);
+  }
+
+  #[test]
+  fn test_best_effort_batch_journals_rejections() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_i64)
+      .expect("unable to instantiate madeleine in test");
+
+    let report = madeleine
+      .execute_batch(batch(), BatchMode::BestEffort)
+      .expect("unable to execute batch in test");
+
+    assert_eq!(report.offsets, vec![0, 1, 2]);
+    assert_eq!(
+      report
+        .rejected
+        .iter()
+        .map(|failure| failure.index)
+        .collect::<Vec<usize>>(),
+      vec![2]
+    );
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(50));
+    assert_eq!(logged_commands(&store_path), 3);
+
+    let rejections = rejected_commands(&store_path).expect("unable to read rejections in test");
+
+    assert_eq!(rejections.len(), 1);
+    assert_eq!(rejections[0].failure, report.rejected[0]);
+    assert_eq!(rejections[0].command, serde_json::json!(-50));
+
+    let single = madeleine.execute_command(Transfer(-51));
+
+    assert!(matches!(single, Err(MadeleineError::CommandRejected(_))));
+    assert_eq!(logged_commands(&store_path), 3);
+  }
+}
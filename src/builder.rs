@@ -0,0 +1,1147 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::admin_log::AdminMarker;
+use crate::codec;
+use crate::command::Command;
+use crate::command_migration::{CommandMigration, MigrationList};
+use crate::command_store::{CommandStore, LogBackend, StoreWrapper, SuppliedStore, SyncMode};
+use crate::config::MadeleineConfig;
+use crate::directory_policy::DirectoryPolicy;
+use crate::hashing::HashAlgo;
+use crate::idempotency::IdempotencyOptions;
+use crate::integrity::VerificationLevel;
+use crate::layout::StoreLayout;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::maintenance::MaintenanceSchedule;
+use crate::metadata::StoreFormat;
+use crate::payload_format::{CommandSerializer, PayloadFormat};
+use crate::quota::Quotas;
+use crate::rate_limit::{RateLimit, RateLimits};
+use crate::snapshot_failure::SnapshotFailureMode;
+use crate::snapshot_policy::SnapshotPolicy;
+use crate::store_path::StorePath;
+
+/// Options for creating or resuming a store, returned by `Madeleine::builder`.
+/// Every option defaults to the behavior of `Madeleine::new` and `Madeleine::resume`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MadeleineBuilder<SystemState> {
+  location: StorePath,
+  directory_policy: Option<DirectoryPolicy>,
+  hash_algo: Option<HashAlgo>,
+  payload_format: Option<PayloadFormat>,
+  log_backend: Option<LogBackend>,
+  command_store: Option<SuppliedStore>,
+  store_wrapper: Option<StoreWrapper>,
+  sync_mode: Option<SyncMode>,
+  command_log_dir_name: Option<String>,
+  snapshot_dir_name: Option<String>,
+  verification: VerificationLevel,
+  strict: bool,
+  quotas: Quotas,
+  rate_limits: RateLimits,
+  snapshot_failure_mode: SnapshotFailureMode,
+  snapshot_policy: SnapshotPolicy,
+  maintenance_schedule: MaintenanceSchedule,
+  full_snapshot_every: usize,
+  idempotency_options: IdempotencyOptions,
+  snapshot_codec: Option<String>,
+  payload_codec: Option<String>,
+  export_codec: Option<String>,
+  admin_ops_since: Option<AdminMarker>,
+  command_migrations: MigrationList,
+  state_type: PhantomData<fn() -> SystemState>,
+}
+
+impl<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> MadeleineBuilder<SystemState> {
+  /// Start configuring the store at a location.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// use madeleine::MadeleineBuilder;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = MadeleineBuilder::new(&store).build(|| 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new(location: impl Into<StorePath>) -> Self {
+    Self {
+      location: location.into(),
+      directory_policy: None,
+      hash_algo: None,
+      payload_format: None,
+      log_backend: None,
+      command_store: None,
+      store_wrapper: None,
+      sync_mode: None,
+      command_log_dir_name: None,
+      snapshot_dir_name: None,
+      verification: VerificationLevel::default(),
+      strict: false,
+      quotas: Quotas::default(),
+      rate_limits: RateLimits::default(),
+      snapshot_failure_mode: SnapshotFailureMode::default(),
+      snapshot_policy: SnapshotPolicy::default(),
+      maintenance_schedule: MaintenanceSchedule::default(),
+      full_snapshot_every: 1,
+      idempotency_options: IdempotencyOptions::default(),
+      snapshot_codec: None,
+      payload_codec: None,
+      export_codec: None,
+      admin_ops_since: None,
+      command_migrations: MigrationList::default(),
+      state_type: PhantomData,
+    }
+  }
+
+  /// Configure a store from a config, e.g. one read from a file, after checking every field is valid.
+  /// All invalid fields are reported together in one `MadeleineError::InvalidConfig`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// use madeleine::{MadeleineBuilder, MadeleineConfig, SnapshotPolicy};
+  ///
+  /// let store = scratch_store();
+  /// let config = MadeleineConfig {
+  ///   path: store.path().to_path_buf(),
+  ///   snapshot_every_n_commands: Some(100),
+  ///   ..MadeleineConfig::default()
+  /// };
+  ///
+  /// let madeleine = MadeleineBuilder::from_config(config)?.build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.snapshot_policy(), SnapshotPolicy::EveryNCommands(100));
+  ///
+  /// let invalid = MadeleineConfig {
+  ///   full_snapshot_every: 0,
+  ///   ..MadeleineConfig::default()
+  /// };
+  /// assert!(MadeleineBuilder::<u64>::from_config(invalid).is_err());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn from_config(config: MadeleineConfig) -> Result<Self, MadeleineError> {
+    config.check()?;
+
+    let snapshot_policy = config.snapshot_policy();
+    let rate_limits = config.rate_limits();
+
+    Ok(Self {
+      location: config.path.into(),
+      directory_policy: config.directory_policy,
+      hash_algo: config.hash_algo,
+      payload_format: config.payload_format,
+      log_backend: config.log_backend,
+      command_store: None,
+      store_wrapper: None,
+      sync_mode: config.sync_mode,
+      command_log_dir_name: config.command_log_dir_name,
+      snapshot_dir_name: config.snapshot_dir_name,
+      verification: config.verification,
+      strict: config.strict,
+      quotas: Quotas {
+        max_store_bytes: config.max_store_bytes,
+        max_commands: config.max_commands,
+        warning_thresholds: config.warning_thresholds,
+      },
+      rate_limits,
+      snapshot_failure_mode: config.snapshot_failure_mode,
+      snapshot_policy,
+      maintenance_schedule: MaintenanceSchedule::default(),
+      full_snapshot_every: config.full_snapshot_every,
+      idempotency_options: IdempotencyOptions {
+        ttl: std::time::Duration::from_secs(config.idempotency_ttl_secs),
+        max_output_bytes: config.idempotency_max_output_bytes,
+        max_keys: config.idempotency_max_keys,
+      },
+      snapshot_codec: config.snapshot_codec,
+      payload_codec: config.payload_codec,
+      export_codec: config.export_codec,
+      admin_ops_since: None,
+      command_migrations: MigrationList::default(),
+      state_type: PhantomData,
+    })
+  }
+
+  /// Capture the options as a config, e.g. to write it to a file.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::MadeleineBuilder;
+  ///
+  /// let store = scratch_store();
+  /// let builder = Madeleine::<u64>::builder(&store).strict(true).full_snapshot_every(4);
+  ///
+  /// let config = builder.to_config();
+  ///
+  /// assert!(config.strict);
+  /// assert_eq!(config.full_snapshot_every, 4);
+  /// assert_eq!(MadeleineBuilder::from_config(config)?, builder);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn to_config(&self) -> MadeleineConfig {
+    MadeleineConfig {
+      path: self.location.as_path().to_path_buf(),
+      directory_policy: self.directory_policy,
+      hash_algo: self.hash_algo,
+      payload_format: self.payload_format,
+      log_backend: self.log_backend,
+      sync_mode: self.sync_mode,
+      command_log_dir_name: self.command_log_dir_name.clone(),
+      snapshot_dir_name: self.snapshot_dir_name.clone(),
+      verification: self.verification,
+      strict: self.strict,
+      max_store_bytes: self.quotas.max_store_bytes,
+      max_commands: self.quotas.max_commands,
+      warning_thresholds: self.quotas.warning_thresholds.clone(),
+      rate_limit_commands_per_sec: self.rate_limits.commands.map(|limit| limit.per_second),
+      rate_limit_command_burst: self.rate_limits.commands.and_then(explicit_burst),
+      rate_limit_bytes_per_sec: self.rate_limits.bytes.map(|limit| limit.per_second),
+      rate_limit_byte_burst: self.rate_limits.bytes.and_then(explicit_burst),
+      rate_limit_max_async_delay_ms: self.rate_limits.max_async_delay.as_millis() as u64,
+      snapshot_failure_mode: self.snapshot_failure_mode,
+      snapshot_every_n_commands: match self.snapshot_policy {
+        SnapshotPolicy::EveryNCommands(commands) => Some(commands),
+        _ => None,
+      },
+      snapshot_interval_secs: match self.snapshot_policy {
+        SnapshotPolicy::Interval(interval) => Some(interval.as_secs()),
+        _ => None,
+      },
+      full_snapshot_every: self.full_snapshot_every,
+      idempotency_ttl_secs: self.idempotency_options.ttl.as_secs(),
+      idempotency_max_output_bytes: self.idempotency_options.max_output_bytes,
+      idempotency_max_keys: self.idempotency_options.max_keys,
+      snapshot_codec: self.snapshot_codec.clone(),
+      payload_codec: self.payload_codec.clone(),
+      export_codec: self.export_codec.clone(),
+    }
+  }
+
+  /// Check the directory against a policy, instead of the default for creating or resuming.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::DirectoryPolicy;
+  ///
+  /// let store = scratch_store();
+  /// std::fs::create_dir_all(store.path().join("unrelated"))?;
+  ///
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .directory_policy(DirectoryPolicy::Permissive)
+  ///   .build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn directory_policy(mut self, directory_policy: DirectoryPolicy) -> Self {
+    self.directory_policy = Some(directory_policy);
+    self
+  }
+
+  /// Create the store with a hash function, or require that an existing store uses it, see `Madeleine::new_with_hash_algo`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::HashAlgo;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).hash_algo(HashAlgo::Sha256).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.hash_algo(), HashAlgo::Sha256);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+    self.hash_algo = Some(hash_algo);
+    self
+  }
+
+  /// Create the store serializing commands in a format, or require that an existing store uses it, see `PayloadFormat`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::PayloadFormat;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).payload_format(PayloadFormat::Json).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.payload_format(), PayloadFormat::Json);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn payload_format(mut self, payload_format: PayloadFormat) -> Self {
+    self.payload_format = Some(payload_format);
+    self
+  }
+
+  /// Create the store serializing commands with a `CommandSerializer`, or require that an existing store uses
+  /// the same one. Entries are tagged with the serializer's format, so a store can't be read with the wrong one.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{MessagePackSerializer, PayloadFormat};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).serializer(MessagePackSerializer).build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.payload_format(), PayloadFormat::MessagePack);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn serializer<S: CommandSerializer>(self, serializer: S) -> Self {
+    self.payload_format(serializer.format())
+  }
+
+  /// Create the store keeping its command log in a backend, or require that an existing store uses it,
+  /// see `LogBackend`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::LogBackend;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).log_backend(LogBackend::File).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.log_backend(), LogBackend::File);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn log_backend(mut self, log_backend: LogBackend) -> Self {
+    self.log_backend = Some(log_backend);
+    self
+  }
+
+  /// Keep the log in a `CommandStore` of the application's own, making the store's backend `LogBackend::Custom`.
+  /// The store goes to the first instance this builder opens, and has to be supplied again whenever the store is
+  /// resumed, since it can't be found from the store directory. Configs don't hold it, so it's left out of `to_config`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::LogBackend;
+  ///
+  /// let store = scratch_store();
+  /// let log = scratch_store();
+  ///
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .command_store(LogBackend::File.open(log.path())?)
+  ///   .build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.close()?;
+  ///
+  /// let resumed = Madeleine::builder(&store)
+  ///   .command_store(LogBackend::File.open(log.path())?)
+  ///   .resume_replaying::<Add, _>(|| 0)?;
+  ///
+  /// assert_eq!(resumed.log_backend(), LogBackend::Custom);
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn command_store(mut self, command_store: impl CommandStore + 'static) -> Self {
+    self.log_backend = Some(LogBackend::Custom);
+    self.command_store = Some(SuppliedStore::new(command_store));
+    self
+  }
+
+  /// Pass the store keeping the log through `wrapper` whenever it's opened, including when compaction swaps in
+  /// a fresh log, e.g. to inject faults into a built-in backend with a `testing::FailpointCommandStore`.
+  /// Unlike `command_store`, the backend is unchanged, so the store is resumed as usual. Configs don't hold it.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  ///
+  /// use madeleine::testing::{FailpointCommandStore, FailpointStore, StorageOperation};
+  /// use madeleine::LogBackend;
+  ///
+  /// let store = scratch_store();
+  /// let failpoints = Arc::new(FailpointStore::new());
+  /// failpoints.fail_nth(StorageOperation::Append, 1);
+  ///
+  /// let wrapper_failpoints = failpoints.clone();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .wrap_command_store(move |inner| {
+  ///     Box::new(FailpointCommandStore::new(inner, wrapper_failpoints.clone()))
+  ///   })
+  ///   .build(|| 0)?;
+  ///
+  /// assert!(madeleine.execute_command(Add(2)).is_err());
+  /// madeleine.execute_command(Add(3))?;
+  /// madeleine.compact()?;
+  /// madeleine.execute_command(Add(4))?;
+  ///
+  /// assert_eq!(madeleine.log_backend(), LogBackend::default());
+  /// assert_eq!(failpoints.calls(StorageOperation::Append), 3);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn wrap_command_store<F>(mut self, wrapper: F) -> Self
+  where
+    F: Fn(Box<dyn CommandStore>) -> Box<dyn CommandStore> + Send + Sync + 'static,
+  {
+    self.store_wrapper = Some(StoreWrapper::new(wrapper));
+    self
+  }
+
+  /// Sync commands to disk as `sync_mode` says, see `Madeleine::set_sync_mode`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SyncMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).sync_mode(SyncMode::Full).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.sync_mode(), SyncMode::Full);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+    self.sync_mode = Some(sync_mode);
+    self
+  }
+
+  /// Keep the command log of a new store in a directory of this name within the store directory,
+  /// rather than `command_log`. The name is recorded in the store, so it's found again when the store is reopened,
+  /// and reopening the store with a different name fails with `MadeleineError::ConfigurationError`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).command_log_dir_name("commands").build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(store.path().join("commands").is_dir());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn command_log_dir_name(mut self, name: &str) -> Self {
+    self.command_log_dir_name = Some(name.to_string());
+    self
+  }
+
+  /// Keep the snapshots of a new store in a subdirectory of this name within the store directory,
+  /// rather than in the store directory itself. Recorded in the store like `MadeleineBuilder::command_log_dir_name`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).snapshot_dir_name("snapshots").build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let snapshot_id = madeleine.take_snapshot(false)?;
+  ///
+  /// assert!(store.path().join("snapshots").join(format!("{}.snapshot", snapshot_id)).is_file());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_dir_name(mut self, name: &str) -> Self {
+    self.snapshot_dir_name = Some(name.to_string());
+    self
+  }
+
+  /// Verify the store this thoroughly on open if it wasn't shut down cleanly, see `Madeleine::close`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::VerificationLevel;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let reopened = Madeleine::<u64>::builder(&store)
+  ///   .verification(VerificationLevel::Off)
+  ///   .build(|| 0)?;
+  ///
+  /// assert_eq!(reopened.open_report().verification, Some(VerificationLevel::Off));
+  /// assert_eq!(reopened.open_report().entries_verified, 0);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn verification(mut self, verification: VerificationLevel) -> Self {
+    self.verification = verification;
+    self
+  }
+
+  /// Enable strict mode, see `Madeleine::set_strict`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).strict(true).build(|| 0)?;
+  ///
+  /// assert!(madeleine.is_strict());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
+  }
+
+  /// Limit the store's resources, see `Madeleine::set_quotas`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::quota::Quotas;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .quotas(Quotas {
+  ///     max_commands: Some(1),
+  ///     ..Quotas::default()
+  ///   })
+  ///   .build(|| 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.execute_command(Add(3)).is_err());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn quotas(mut self, quotas: Quotas) -> Self {
+    self.quotas = quotas;
+    self
+  }
+
+  /// Limit how quickly commands are executed, see `Madeleine::set_rate_limits`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{RateLimit, RateLimits};
+  ///
+  /// let store = scratch_store();
+  /// let limits = RateLimits {
+  ///   commands: Some(RateLimit::per_second(100)),
+  ///   ..RateLimits::default()
+  /// };
+  /// let madeleine = Madeleine::builder(&store).rate_limits(limits).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.rate_limits(), limits);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+    self.rate_limits = rate_limits;
+    self
+  }
+
+  /// Carry on, or not, when a scheduled snapshot fails, see `Madeleine::set_snapshot_failure_mode`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotFailureMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .snapshot_failure_mode(SnapshotFailureMode::WarnAndContinue)
+  ///   .build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.snapshot_failure_mode(), SnapshotFailureMode::WarnAndContinue);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_failure_mode(mut self, snapshot_failure_mode: SnapshotFailureMode) -> Self {
+    self.snapshot_failure_mode = snapshot_failure_mode;
+    self
+  }
+
+  /// Take snapshots automatically, see `Madeleine::set_snapshot_policy`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotPolicy;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .snapshot_policy(SnapshotPolicy::EveryNCommands(1))
+  ///   .build(|| 0)?;
+  /// let next_snapshot_id = madeleine.next_snapshot_id()?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.next_snapshot_id()?, next_snapshot_id + 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_policy(mut self, snapshot_policy: SnapshotPolicy) -> Self {
+    self.snapshot_policy = snapshot_policy;
+    self
+  }
+
+  /// Take a snapshot every `commands` commands, short for `snapshot_policy(SnapshotPolicy::EveryNCommands(commands))`.
+  /// Building fails with `MadeleineError::ConfigurationError` if `commands` is zero.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotPolicy;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).auto_snapshot_every(100).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.snapshot_policy(), SnapshotPolicy::EveryNCommands(100));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn auto_snapshot_every(self, commands: u64) -> Self {
+    self.snapshot_policy(SnapshotPolicy::EveryNCommands(commands))
+  }
+
+  /// Run maintenance tasks at intervals, see `Madeleine::set_maintenance_schedule`.
+  /// Configs don't hold a schedule, so it's left out of `to_config`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::{MaintenanceSchedule, MaintenanceTask};
+  ///
+  /// let schedule =
+  ///   MaintenanceSchedule::default().every(Duration::from_secs(3600), MaintenanceTask::Snapshot);
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .maintenance_schedule(schedule.clone())
+  ///   .build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.maintenance_schedule(), schedule);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn maintenance_schedule(mut self, maintenance_schedule: MaintenanceSchedule) -> Self {
+    self.maintenance_schedule = maintenance_schedule;
+    self
+  }
+
+  /// Write only every this many snapshots in full, see `Madeleine::set_full_snapshot_every`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).full_snapshot_every(4).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.full_snapshot_every(), 4);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn full_snapshot_every(mut self, every: usize) -> Self {
+    self.full_snapshot_every = every;
+    self
+  }
+
+  /// Limit what's remembered about idempotency keys, see `Madeleine::set_idempotency_options`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::IdempotencyOptions;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store)
+  ///   .idempotency_options(IdempotencyOptions {
+  ///     ttl: Duration::from_secs(60),
+  ///     ..IdempotencyOptions::default()
+  ///   })
+  ///   .build(|| 0)?;
+  ///
+  /// madeleine.execute_idempotent("request-1", Add(2))?;
+  ///
+  /// assert!(!madeleine.execute_idempotent("request-1", Add(2))?.was_applied());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn idempotency_options(mut self, idempotency_options: IdempotencyOptions) -> Self {
+    self.idempotency_options = idempotency_options;
+    self
+  }
+
+  /// Compress snapshots with the codec with this id, see `Madeleine::set_snapshot_codec` and `codec::find`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::GZIP_CODEC_ID;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).snapshot_codec(GZIP_CODEC_ID).build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// assert_eq!(Madeleine::<u64>::resume(&store)?.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_codec(mut self, codec_id: &str) -> Self {
+    self.snapshot_codec = Some(codec_id.to_string());
+    self
+  }
+
+  /// Compress logged commands with the codec with this id, see `Madeleine::set_payload_codec`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::GZIP_CODEC_ID;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).payload_codec(GZIP_CODEC_ID).build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// assert_eq!(Madeleine::resume_replaying::<Add, _>(&store, || 0)?.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn payload_codec(mut self, codec_id: &str) -> Self {
+    self.payload_codec = Some(codec_id.to_string());
+    self
+  }
+
+  /// Compress exports with the codec with this id, see `Madeleine::set_export_codec`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::GZIP_CODEC_ID;
+  /// use madeleine::export::{ExportFormat, ExportRange};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).export_codec(GZIP_CODEC_ID).build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let manifest = madeleine.export(ExportFormat::Jsonl, ExportRange::default(), Vec::new())?;
+  ///
+  /// assert_eq!(manifest.codec.as_deref(), Some(GZIP_CODEC_ID));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn export_codec(mut self, codec_id: &str) -> Self {
+    self.export_codec = Some(codec_id.to_string());
+    self
+  }
+
+  /// List the administrative operations since `marker`, kept from an earlier `OpenReport::admin_marker`,
+  /// in the open report's `admin_ops_since`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let marker = madeleine.open_report().admin_marker;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.compact()?;
+  /// madeleine.close()?;
+  ///
+  /// let reopened = Madeleine::<u64>::builder(&store).admin_ops_since(marker).resume()?;
+  /// let operations = reopened.open_report().admin_ops_since.clone().unwrap_or_default();
+  ///
+  /// assert_eq!(operations.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn admin_ops_since(mut self, marker: AdminMarker) -> Self {
+    self.admin_ops_since = Some(marker);
+    self
+  }
+
+  /// Migrate commands logged in earlier serialized forms, see `Madeleine::add_command_migration`.
+  /// Can be called once per migration in the chain, in any order.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{CommandMigration, MadeleineError};
+  ///
+  /// /// Version 1 of `Add` logged amounts in hundreds.
+  /// struct HundredsToUnits;
+  ///
+  /// impl CommandMigration for HundredsToUnits {
+  ///   fn version(&self) -> u32 {
+  ///     2
+  ///   }
+  ///
+  ///   fn migrate(&self, old_json: &str, _old_version: u32) -> Result<String, MadeleineError> {
+  ///     let hundreds: u64 = serde_json::from_str(old_json)?;
+  ///
+  ///     Ok(serde_json::to_string(&(hundreds * 100))?)
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let resumed = Madeleine::builder(&store)
+  ///   .command_migration(Box::new(HundredsToUnits))
+  ///   .resume_replaying::<Add, _>(|| 0)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 200);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn command_migration(mut self, migration: Box<dyn CommandMigration>) -> Self {
+    self.command_migrations.0.push(Arc::from(migration));
+    self
+  }
+
+  /// Create the store, or open an existing one, starting from the constructor's state as `Madeleine::new` does.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).strict(true).build(|| 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.close()?;
+  ///
+  /// let reopened = Madeleine::builder(&store).build(|| 0)?;
+  ///
+  /// assert_eq!(reopened.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn build<C>(self, constructor: C) -> Result<Madeleine<SystemState>, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    let location_dir_path = self.check()?;
+
+    let madeleine = Madeleine::create(
+      location_dir_path,
+      self
+        .directory_policy
+        .unwrap_or(DirectoryPolicy::RequireEmptyOrStore),
+      self.store_format(),
+      self.verification,
+      None,
+      constructor,
+    )?;
+
+    self.configure(madeleine)
+  }
+
+  /// Resume an existing store as `Madeleine::resume` does.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// let resumed = Madeleine::<u64>::builder(&store).strict(true).resume()?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// assert!(resumed.is_strict());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resume(self) -> Result<Madeleine<SystemState>, MadeleineError> {
+    let madeleine = Madeleine::resume_with(
+      self.location.clone().resolve()?,
+      self
+        .directory_policy
+        .unwrap_or(DirectoryPolicy::RequireExistingStore),
+      self.store_format(),
+      self.verification,
+      None,
+    )?;
+
+    self.configure(madeleine)
+  }
+
+  /// Resume an existing store, replaying the commands logged after its latest snapshot as `Madeleine::resume_replaying` does,
+  /// migrated by the migrations given with `command_migration`. The other options are applied once it's resumed.
+  pub fn resume_replaying<C, F>(
+    mut self,
+    constructor: F,
+  ) -> Result<Madeleine<SystemState>, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let madeleine = Madeleine::resume_replaying_with::<C, F>(
+      self.location.clone().resolve()?,
+      self.store_format(),
+      constructor,
+      None,
+      std::mem::take(&mut self.command_migrations.0),
+    )?;
+
+    self.configure(madeleine)
+  }
+
+  /// Fail with `MadeleineError::ConfigurationError` before anything is created if the options can't make a store:
+  /// if the path is empty, its parent directory doesn't exist, or the layout is invalid, see `StoreLayout::validate`.
+  /// Returns the store's root directory.
+  fn check(&self) -> Result<std::path::PathBuf, MadeleineError> {
+    if self.location.as_path().as_os_str().is_empty() {
+      return Err(MadeleineError::ConfigurationError(String::from(
+        "no store path was given",
+      )));
+    }
+
+    let location_dir_path = self.location.clone().resolve()?;
+
+    if let Some(parent) = location_dir_path.parent().filter(|parent| !parent.is_dir()) {
+      return Err(MadeleineError::ConfigurationError(format!(
+        "the parent directory {} of the store doesn't exist",
+        parent.display()
+      )));
+    }
+
+    if self.snapshot_policy == SnapshotPolicy::EveryNCommands(0) {
+      return Err(MadeleineError::ConfigurationError(String::from(
+        "snapshots can't be taken every 0 commands",
+      )));
+    }
+
+    if let Some(layout) = self.layout() {
+      layout.validate()?;
+    }
+
+    Ok(location_dir_path)
+  }
+
+  /// The layout requested of the store, if either of its directories was named.
+  fn layout(&self) -> Option<StoreLayout> {
+    if self.command_log_dir_name.is_none() && self.snapshot_dir_name.is_none() {
+      return None;
+    }
+
+    let default = StoreLayout::default();
+
+    Some(StoreLayout {
+      command_log_dir_name: self
+        .command_log_dir_name
+        .clone()
+        .unwrap_or(default.command_log_dir_name),
+      snapshot_dir_name: self.snapshot_dir_name.clone(),
+    })
+  }
+
+  /// The formats requested of the store, see `StoreMetadata::open_for_write`.
+  fn store_format(&self) -> StoreFormat {
+    StoreFormat {
+      hash_algo: self.hash_algo,
+      payload_format: self.payload_format,
+      log_backend: self.log_backend,
+      command_store: self.command_store.clone(),
+      store_wrapper: self.store_wrapper.clone(),
+      layout: self.layout(),
+    }
+  }
+
+  /// Apply the options which are set on an open instance.
+  fn configure(
+    self,
+    mut madeleine: Madeleine<SystemState>,
+  ) -> Result<Madeleine<SystemState>, MadeleineError> {
+    if let Some(marker) = self.admin_ops_since {
+      madeleine.report_admin_ops_since(marker)?;
+    }
+
+    madeleine.set_strict(self.strict);
+
+    if let Some(sync_mode) = self.sync_mode {
+      madeleine.set_sync_mode(sync_mode);
+    }
+
+    madeleine.set_quotas(self.quotas)?;
+    madeleine.set_rate_limits(self.rate_limits)?;
+    madeleine.set_snapshot_failure_mode(self.snapshot_failure_mode);
+    madeleine.set_snapshot_policy(self.snapshot_policy)?;
+    madeleine.set_maintenance_schedule(self.maintenance_schedule)?;
+    madeleine.set_full_snapshot_every(self.full_snapshot_every)?;
+    madeleine.set_idempotency_options(self.idempotency_options);
+    madeleine.set_snapshot_codec(
+      self
+        .snapshot_codec
+        .as_deref()
+        .map(codec::find)
+        .transpose()?,
+    )?;
+    madeleine.set_payload_codec(self.payload_codec.as_deref().map(codec::find).transpose()?)?;
+    madeleine.set_export_codec(self.export_codec.as_deref().map(codec::find).transpose()?)?;
+
+    for migration in self.command_migrations.0 {
+      madeleine.add_migration(migration)?;
+    }
+
+    Ok(madeleine)
+  }
+}
+
+/// A limit's burst, or `None` if it's the default of one second's worth of tokens.
+fn explicit_burst(limit: RateLimit) -> Option<u64> {
+  (limit.burst != limit.per_second).then_some(limit.burst)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::{Follower, ReadOnlyMadeleine};
+
+  #[test]
+  fn test_auto_snapshot_every_takes_snapshots_as_commands_are_executed() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::builder(store_path.clone())
+      .auto_snapshot_every(3)
+      .build(|| 0)
+      .expect("unable to instantiate madeleine in test");
+    let next_snapshot_id = madeleine
+      .next_snapshot_id()
+      .expect("unable to get snapshot id in test");
+    let snapshot_path = store_path.join(format!("{}.snapshot", next_snapshot_id));
+
+    for amount in 1..=2 {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    assert!(!snapshot_path.exists());
+
+    madeleine
+      .execute_command(Add(3))
+      .expect("unable to execute command in test");
+
+    assert!(snapshot_path.is_file());
+    assert!(matches!(
+      Madeleine::builder(temp_dir.path().join("other_store"))
+        .auto_snapshot_every(0)
+        .build(|| 0),
+      Err(MadeleineError::ConfigurationError(_))
+    ));
+  }
+
+  #[test]
+  fn test_store_keeps_its_layout_when_reopened() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::builder(store_path.clone())
+      .command_log_dir_name("commands")
+      .snapshot_dir_name("snapshots")
+      .build(|| 0)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Add(3))
+      .expect("unable to execute command in test");
+
+    assert!(store_path.join("commands").is_dir());
+    assert!(!store_path.join("command_log").exists());
+    assert!(store_path
+      .join("snapshots")
+      .join(format!("{}.snapshot", snapshot_id))
+      .is_file());
+
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    assert_eq!(
+      ReadOnlyMadeleine::<Add, u64>::open(store_path.clone(), || 0)
+        .expect("unable to open replica in test")
+        .into_inner(),
+      5
+    );
+    assert!(Follower::open(store_path.clone()).is_ok());
+
+    let resumed = Madeleine::resume_replaying::<Add, _>(store_path.join("commands"), || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state), 5);
+    assert_eq!(resumed.len(), 2);
+
+    resumed.compact().expect("unable to compact in test");
+    resumed.close().expect("unable to close madeleine in test");
+
+    assert_eq!(
+      Madeleine::<u64>::resume(store_path.clone())
+        .expect("unable to resume madeleine in test")
+        .tap(|state| state),
+      5
+    );
+
+    assert!(matches!(
+      Madeleine::builder(store_path)
+        .command_log_dir_name("log")
+        .snapshot_dir_name("snapshots")
+        .build(|| 0_u64),
+      Err(MadeleineError::ConfigurationError(message)) if message.contains("\"commands\"")
+    ));
+  }
+
+  #[test]
+  fn test_invalid_configurations_are_configuration_errors() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let build = |builder: MadeleineBuilder<u64>| builder.build(|| 0).err();
+
+    assert!(matches!(
+      build(Madeleine::builder("")),
+      Some(MadeleineError::ConfigurationError(message)) if message.contains("no store path")
+    ));
+    assert!(matches!(
+      build(Madeleine::builder(temp_dir.path().join("missing").join("test_store"))),
+      Some(MadeleineError::ConfigurationError(message)) if message.contains("doesn't exist")
+    ));
+
+    for name in [
+      "",
+      "..",
+      "nested/commands",
+      "metadata",
+      "snapshot",
+      "3.snapshot",
+    ] {
+      assert!(
+        matches!(
+          build(Madeleine::builder(store_path.clone()).command_log_dir_name(name)),
+          Some(MadeleineError::ConfigurationError(_))
+        ),
+        "{:?} was accepted",
+        name
+      );
+    }
+
+    assert!(matches!(
+      build(
+        Madeleine::builder(store_path.clone())
+          .command_log_dir_name("data")
+          .snapshot_dir_name("data")
+      ),
+      Some(MadeleineError::ConfigurationError(message)) if message.contains("share")
+    ));
+    assert!(!store_path.exists());
+  }
+}
@@ -0,0 +1,162 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::madeleine_error::MadeleineError;
+
+/// A handle for stopping long-running operations, such as replaying or exporting a large log, part way through.
+///
+/// Clones share the same flag, so one can be kept to cancel an operation running with another, e.g. on
+/// a blocking thread. Operations check it between batches of log entries, failing with `MadeleineError::Cancelled`,
+/// and a cancelled token stays cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+  /// A token which hasn't been cancelled.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Ask the operations checking this token, or any of its clones, to stop.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  /// Whether the token has been cancelled.
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Fail with `MadeleineError::Cancelled` if the token has been cancelled, after `completed` entries were processed.
+  pub(crate) fn check(&self, completed: u64) -> Result<(), MadeleineError> {
+    if self.is_cancelled() {
+      return Err(MadeleineError::Cancelled { completed });
+    }
+
+    Ok(())
+  }
+}
+
+/// Cancels a token when dropped, e.g. along with a future which was waiting on a blocking operation,
+/// unless disarmed once the operation finished.
+#[cfg(feature = "async")]
+pub(crate) struct CancelOnDrop(Option<CancellationToken>);
+
+#[cfg(feature = "async")]
+impl CancelOnDrop {
+  pub fn new(cancellation: CancellationToken) -> Self {
+    Self(Some(cancellation))
+  }
+
+  pub fn disarm(mut self) {
+    self.0 = None;
+  }
+}
+
+#[cfg(feature = "async")]
+impl Drop for CancelOnDrop {
+  fn drop(&mut self) {
+    if let Some(cancellation) = &self.0 {
+      cancellation.cancel();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use std::cell::RefCell;
+
+  use crate::export::{ExportFormat, ExportRange};
+  use crate::{Command, Madeleine};
+
+  thread_local! {
+    /// Cancelled by `Add` once the count reaches `CANCEL_AT`, while set.
+    static REPLAY_CANCELLATION: RefCell<Option<CancellationToken>> = const { RefCell::new(None) };
+  }
+
+  const CANCEL_AT: u64 = 50;
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Add(u64);
+
+  impl Command<'_> for Add {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      let new_state = old_state + self.0;
+
+      if new_state == CANCEL_AT {
+        REPLAY_CANCELLATION.with_borrow(|cancellation| {
+          if let Some(cancellation) = cancellation {
+            cancellation.cancel();
+          }
+        });
+      }
+
+      new_state
+    }
+  }
+
+  #[test]
+  fn test_cancelled_replay_leaves_store_reopenable() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(store_path.clone(), || 0).expect("unable to instantiate madeleine in test");
+
+    for _i in 0..100 {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    drop(madeleine);
+
+    let cancellation = CancellationToken::new();
+    REPLAY_CANCELLATION.with_borrow_mut(|replay| *replay = Some(cancellation.clone()));
+
+    let cancelled =
+      Madeleine::resume_replaying_cancellable::<Add, _>(store_path.clone(), || 0, cancellation);
+
+    REPLAY_CANCELLATION.with_borrow_mut(|replay| *replay = None);
+
+    assert!(matches!(
+      cancelled.err(),
+      Some(MadeleineError::Cancelled {
+        completed: CANCEL_AT
+      })
+    ));
+
+    let resumed = Madeleine::resume_replaying::<Add, _>(store_path, || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state), 100);
+    assert_eq!(resumed.len(), 100);
+
+    let cancellation = CancellationToken::new();
+    resumed.set_cancellation_token(Some(cancellation.clone()));
+    cancellation.cancel();
+
+    assert!(matches!(
+      resumed
+        .export(ExportFormat::Jsonl, ExportRange::default(), Vec::new())
+        .err(),
+      Some(MadeleineError::Cancelled { completed: 0 })
+    ));
+
+    resumed.set_cancellation_token(None);
+
+    assert!(resumed
+      .export(ExportFormat::Jsonl, ExportRange::default(), Vec::new())
+      .is_ok());
+  }
+}
@@ -0,0 +1,372 @@
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::madeleine_error::MadeleineError;
+
+/// Id of the gzip codec, available with the `gzip` feature.
+pub const GZIP_CODEC_ID: &str = "gzip";
+/// Id of the zstd codec, available with the `zstd` feature.
+pub const ZSTD_CODEC_ID: &str = "zstd";
+
+/// Starts every piece of compressed data, followed by the length of the codec's id, the id, then the compressed bytes.
+/// Neither serialized states nor log entries start with a NUL byte, so data without it is read as uncompressed.
+const FRAME_MAGIC: &[u8] = b"\0mc";
+
+/// Codecs registered with `register`, searched before the built-in ones.
+static REGISTERED: Mutex<Vec<Arc<dyn Codec>>> = Mutex::new(Vec::new());
+
+/// A compression algorithm, which can be chosen independently for snapshots, log payloads and exports.
+///
+/// Compressed data records the codec's id, so it's always decompressed with the same codec,
+/// whichever is configured when it's read.
+pub trait Codec: Send + Sync {
+  /// Identifies the codec in compressed data. Must be at most 255 bytes, and never change.
+  fn codec_id(&self) -> &str;
+
+  /// Compress everything read from `reader` into `writer`.
+  fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(), MadeleineError>;
+
+  /// Decompress everything read from `reader` into `writer`.
+  fn decompress(&self, reader: &mut dyn Read, writer: &mut dyn Write)
+    -> Result<(), MadeleineError>;
+}
+
+/// gzip compression.
+#[cfg(feature = "gzip")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GzipCodec {
+  /// Compression level, from 0 to 9.
+  pub level: u32,
+}
+
+#[cfg(feature = "gzip")]
+impl Default for GzipCodec {
+  fn default() -> Self {
+    Self { level: 6 }
+  }
+}
+
+#[cfg(feature = "gzip")]
+impl Codec for GzipCodec {
+  fn codec_id(&self) -> &str {
+    GZIP_CODEC_ID
+  }
+
+  fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(), MadeleineError> {
+    let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::new(self.level));
+    std::io::copy(reader, &mut encoder)?;
+    encoder.finish()?;
+
+    Ok(())
+  }
+
+  fn decompress(
+    &self,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+  ) -> Result<(), MadeleineError> {
+    std::io::copy(&mut flate2::read::GzDecoder::new(reader), writer)?;
+
+    Ok(())
+  }
+}
+
+/// zstd compression.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZstdCodec {
+  /// Compression level, from 1 to 22.
+  pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCodec {
+  fn default() -> Self {
+    Self { level: 3 }
+  }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for ZstdCodec {
+  fn codec_id(&self) -> &str {
+    ZSTD_CODEC_ID
+  }
+
+  fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(), MadeleineError> {
+    zstd::stream::copy_encode(reader, writer, self.level)?;
+
+    Ok(())
+  }
+
+  fn decompress(
+    &self,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+  ) -> Result<(), MadeleineError> {
+    zstd::stream::copy_decode(reader, writer)?;
+
+    Ok(())
+  }
+}
+
+/// Make a codec of your own available by its id, replacing any registered before with the same id.
+/// Codecs must be registered in every process which reads data they compressed.
+pub fn register(codec: Arc<dyn Codec>) {
+  let mut registered = REGISTERED
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+  registered.retain(|existing| existing.codec_id() != codec.codec_id());
+  registered.push(codec);
+}
+
+/// Find a codec by id, among those registered and those built in.
+/// Fails with `MadeleineError::MissingCapability` if a built-in codec's feature isn't enabled, or no codec has the id.
+pub fn find(codec_id: &str) -> Result<Arc<dyn Codec>, MadeleineError> {
+  let registered = REGISTERED
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+  if let Some(codec) = registered.iter().find(|codec| codec.codec_id() == codec_id) {
+    return Ok(codec.clone());
+  }
+
+  match codec_id {
+    #[cfg(feature = "gzip")]
+    GZIP_CODEC_ID => Ok(Arc::new(GzipCodec::default())),
+    #[cfg(not(feature = "gzip"))]
+    GZIP_CODEC_ID => Err(MadeleineError::MissingCapability(String::from(
+      "the gzip codec needs the gzip feature",
+    ))),
+    #[cfg(feature = "zstd")]
+    ZSTD_CODEC_ID => Ok(Arc::new(ZstdCodec::default())),
+    #[cfg(not(feature = "zstd"))]
+    ZSTD_CODEC_ID => Err(MadeleineError::MissingCapability(String::from(
+      "the zstd codec needs the zstd feature",
+    ))),
+    _ => Err(MadeleineError::MissingCapability(format!(
+      "no codec {} is registered",
+      codec_id
+    ))),
+  }
+}
+
+/// Compress data with a codec, framed with the codec's id, or leave it as it is without one.
+pub(crate) fn encode<'a>(
+  codec: Option<&dyn Codec>,
+  data: &'a [u8],
+) -> Result<Cow<'a, [u8]>, MadeleineError> {
+  let codec = match codec {
+    Some(codec) => codec,
+    None => return Ok(Cow::Borrowed(data)),
+  };
+
+  let codec_id = codec.codec_id().as_bytes();
+  let codec_id_len = u8::try_from(codec_id.len()).map_err(|_error| {
+    MadeleineError::CodecError(format!("codec id {} is too long", codec.codec_id()))
+  })?;
+
+  let mut framed = FRAME_MAGIC.to_vec();
+  framed.push(codec_id_len);
+  framed.extend_from_slice(codec_id);
+  codec.compress(&mut &data[..], &mut framed)?;
+
+  Ok(Cow::Owned(framed))
+}
+
+/// Decompress data framed by `encode` with the codec it names, or pass along data which isn't framed.
+pub(crate) fn decode(data: &[u8]) -> Result<Cow<'_, [u8]>, MadeleineError> {
+  let (codec_id, mut compressed) = match split_frame(data)? {
+    Some(frame) => frame,
+    None => return Ok(Cow::Borrowed(data)),
+  };
+
+  let mut decompressed = Vec::new();
+  find(codec_id)?.decompress(&mut compressed, &mut decompressed)?;
+
+  Ok(Cow::Owned(decompressed))
+}
+
+/// The id of the codec which compressed data, such as a snapshot file, or `None` if it isn't compressed.
+pub fn framed_codec_id(data: &[u8]) -> Result<Option<&str>, MadeleineError> {
+  Ok(split_frame(data)?.map(|(codec_id, _compressed)| codec_id))
+}
+
+fn split_frame(data: &[u8]) -> Result<Option<(&str, &[u8])>, MadeleineError> {
+  let rest = match data.strip_prefix(FRAME_MAGIC) {
+    Some(rest) => rest,
+    None => return Ok(None),
+  };
+
+  let truncated = || MadeleineError::CodecError(String::from("compressed data is truncated"));
+  let (codec_id_len, rest) = rest.split_first().ok_or_else(truncated)?;
+
+  if rest.len() < usize::from(*codec_id_len) {
+    return Err(truncated());
+  }
+
+  let (codec_id, compressed) = rest.split_at(usize::from(*codec_id_len));
+  let codec_id = std::str::from_utf8(codec_id)
+    .map_err(|_error| MadeleineError::CodecError(String::from("codec id isn't UTF-8")))?;
+
+  Ok(Some((codec_id, compressed)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use ulid::Ulid;
+
+  use crate::export::{import, ExportFormat, ExportRange};
+  use crate::madeleine::snapshot_file_path;
+  use crate::testing::Add;
+  use crate::{Follower, Madeleine};
+
+  /// Flips every bit, so that "compressed" data is easy to tell apart.
+  struct InvertCodec;
+
+  impl Codec for InvertCodec {
+    fn codec_id(&self) -> &str {
+      "invert-test"
+    }
+
+    fn compress(
+      &self,
+      reader: &mut dyn Read,
+      writer: &mut dyn Write,
+    ) -> Result<(), MadeleineError> {
+      let mut data = Vec::new();
+      reader.read_to_end(&mut data)?;
+      writer.write_all(&data.iter().map(|byte| !byte).collect::<Vec<u8>>())?;
+
+      Ok(())
+    }
+
+    fn decompress(
+      &self,
+      reader: &mut dyn Read,
+      writer: &mut dyn Write,
+    ) -> Result<(), MadeleineError> {
+      self.compress(reader, writer)
+    }
+  }
+
+  #[test]
+  fn test_codecs_are_chosen_independently_and_detected_on_read() {
+    register(Arc::new(InvertCodec));
+
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::builder(store_path.clone())
+      .snapshot_codec("invert-test")
+      .export_codec("invert-test")
+      .build(|| 0_u64)
+      .expect("unable to build madeleine in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+    madeleine
+      .set_payload_codec(Some(
+        find("invert-test").expect("unable to find codec in test"),
+      ))
+      .expect("unable to set payload codec in test");
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    let snapshot = std::fs::read(snapshot_file_path(snapshot_id, store_path.clone()))
+      .expect("unable to read snapshot in test");
+
+    assert_eq!(framed_codec_id(&snapshot).ok(), Some(Some("invert-test")));
+
+    // Entries written before and after the payload codec was chosen are both read back.
+    let commands = Follower::open(store_path.clone())
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read log in test");
+
+    assert_eq!(
+      commands
+        .iter()
+        .map(|command| command.deserialize::<Add>().map(|add| add.0).ok())
+        .collect::<Vec<Option<u64>>>(),
+      vec![Some(1), Some(2)]
+    );
+
+    let mut exported = Vec::new();
+    let manifest = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)
+      .expect("unable to export in test");
+
+    assert_eq!(manifest.codec.as_deref(), Some("invert-test"));
+    assert!(!exported.starts_with(b"{"));
+
+    import(
+      &manifest,
+      exported.as_slice(),
+      temp_dir.path().join("imported"),
+    )
+    .expect("unable to import compressed export in test");
+
+    drop(madeleine);
+
+    let resumed = Madeleine::<u64>::resume(store_path).expect("unable to resume in test");
+
+    assert_eq!(resumed.tap_ref(|state| *state).ok(), Some(3));
+  }
+
+  #[test]
+  fn test_built_in_codecs_need_their_features() {
+    for (codec_id, enabled) in [
+      (GZIP_CODEC_ID, cfg!(feature = "gzip")),
+      (ZSTD_CODEC_ID, cfg!(feature = "zstd")),
+    ] {
+      match find(codec_id) {
+        Ok(codec) => {
+          assert!(enabled, "{}", codec_id);
+
+          let framed =
+            encode(Some(codec.as_ref()), b"[1, 2, 3]").expect("unable to encode in test");
+
+          assert_eq!(framed_codec_id(&framed).ok(), Some(Some(codec_id)));
+          assert_eq!(
+            decode(&framed).expect("unable to decode in test").as_ref(),
+            b"[1, 2, 3]"
+          );
+        }
+        Err(error) => {
+          assert!(!enabled, "{}", codec_id);
+          assert!(matches!(error, MadeleineError::MissingCapability(_)));
+
+          let mut framed = FRAME_MAGIC.to_vec();
+          framed.push(codec_id.len() as u8);
+          framed.extend_from_slice(codec_id.as_bytes());
+
+          assert!(matches!(
+            decode(&framed),
+            Err(MadeleineError::MissingCapability(_))
+          ));
+        }
+      }
+    }
+
+    assert_eq!(
+      decode(b"[1, 2, 3]")
+        .expect("unable to decode in test")
+        .as_ref(),
+      b"[1, 2, 3]"
+    );
+    assert!(matches!(
+      decode(b"\0mc\x09zst"),
+      Err(MadeleineError::CodecError(_))
+    ));
+  }
+}
@@ -1,12 +1,647 @@
 use serde::{Deserialize, Serialize};
 
+use std::fmt::Display;
+
+/// Where a command falls in its store's history, passed to `Command::execute_with_ctx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandContext {
+  position: u64,
+}
+
+impl CommandContext {
+  pub(crate) fn at(position: u64) -> Self {
+    Self { position }
+  }
+
+  /// Position of the command in the store's whole history, counting from zero,
+  /// including commands since removed from the log by compactions and purges.
+  ///
+  /// Positions strictly increase from one command to the next and are the same whenever a command is replayed,
+  /// so they're the supported way to derive history-ordered identifiers, such as invoice numbers.
+  /// They may have gaps, e.g. where tombstoned commands are skipped, and a store created by importing
+  /// or merging commands numbers them afresh from zero.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::CommandContext;
+  ///
+  /// /// Records where each command fell in history.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct RecordPosition;
+  ///
+  /// impl Command<'_> for RecordPosition {
+  ///   type SystemState = Vec<u64>;
+  ///
+  ///   fn execute(&self, old_state: Vec<u64>) -> Vec<u64> {
+  ///     old_state
+  ///   }
+  ///
+  ///   fn execute_with_ctx(&self, mut old_state: Vec<u64>, ctx: &CommandContext) -> Vec<u64> {
+  ///     old_state.push(ctx.position());
+  ///     old_state
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, Vec::new)?;
+  ///
+  /// madeleine.execute_command(RecordPosition)?;
+  /// madeleine.compact()?;
+  /// madeleine.execute_command(RecordPosition)?;
+  ///
+  /// assert_eq!(madeleine.tap(|positions| positions), vec![0, 1]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn position(&self) -> u64 {
+    self.position
+  }
+}
+
 /// This trait must be implemented by every command.
 /// Specifically, every command (and its state) must be serializable and deserializable by serde.
 /// A command's state must also be `Clone`.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// use madeleine::{Command, Madeleine};
+///
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// struct Deposit(u64);
+///
+/// impl Command<'_> for Deposit {
+///   type SystemState = u64;
+///
+///   fn execute(&self, old_state: u64) -> u64 {
+///     old_state + self.0
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, || 0)?;
+///
+/// madeleine.execute_command(Deposit(2))?;
+/// madeleine.execute_command(Deposit(3))?;
+///
+/// assert_eq!(madeleine.tap(|balance| balance), 5);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
 pub trait Command<'a>: Serialize + Deserialize<'a> {
   /// The associated type which a Command must return. This must correspond to the type of the `Madeleine` instance's internal state.
   type SystemState: Serialize + Deserialize<'a> + Clone;
 
   /// Core logic for a Command, left to the implementor to specify.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::{Command, Madeleine};
+  /// assert_eq!(Add(2).execute(3), 5);
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 3)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
   fn execute(&self, old_state: Self::SystemState) -> Self::SystemState;
+
+  /// Core logic for a Command which depends on where it falls in history, see `CommandContext`.
+  /// Madeleine executes and replays commands through this, which calls `execute` unless overridden.
+  /// Commands which override it are only executed with `execute` by callers which have no context.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::CommandContext;
+  ///
+  /// /// Issues the next invoice, numbered by where the command falls in history.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct IssueInvoice;
+  ///
+  /// impl Command<'_> for IssueInvoice {
+  ///   type SystemState = Vec<u64>;
+  ///
+  ///   fn execute(&self, mut old_state: Vec<u64>) -> Vec<u64> {
+  ///     let next = old_state.last().map_or(0, |last| last + 1);
+  ///     old_state.push(next);
+  ///     old_state
+  ///   }
+  ///
+  ///   fn execute_with_ctx(&self, mut old_state: Vec<u64>, ctx: &CommandContext) -> Vec<u64> {
+  ///     old_state.push(ctx.position());
+  ///     old_state
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, Vec::new)?;
+  ///
+  /// madeleine.execute_command(IssueInvoice)?;
+  /// madeleine.execute_command(IssueInvoice)?;
+  ///
+  /// assert_eq!(madeleine.tap(|invoices| invoices), vec![0, 1]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    _ctx: &CommandContext,
+  ) -> Self::SystemState {
+    self.execute(old_state)
+  }
+
+  /// Execute the command as `execute_with_ctx` does, or reject the transition.
+  /// Madeleine executes new commands through this, which calls `execute_with_ctx` unless overridden.
+  /// A rejected command is neither logged nor applied, and fails with `MadeleineError::CommandRejected`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::{CommandContext, MadeleineError};
+  ///
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Withdraw(u64);
+  ///
+  /// impl Command<'_> for Withdraw {
+  ///   type SystemState = u64;
+  ///
+  ///   fn execute(&self, old_state: u64) -> u64 {
+  ///     old_state.saturating_sub(self.0)
+  ///   }
+  ///
+  ///   fn try_execute_with_ctx(&self, old_state: u64, _ctx: &CommandContext) -> Result<u64, String> {
+  ///     old_state
+  ///       .checked_sub(self.0)
+  ///       .ok_or_else(|| format!("can't withdraw {} from {}", self.0, old_state))
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 5)?;
+  ///
+  /// madeleine.execute_command(Withdraw(3))?;
+  /// let rejected = madeleine.execute_command(Withdraw(3));
+  ///
+  /// assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+  /// assert_eq!(madeleine.tap(|balance| balance), 2);
+  /// assert_eq!(madeleine.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn try_execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Result<Self::SystemState, String> {
+    Ok(self.execute_with_ctx(old_state, ctx))
+  }
+
+  /// Check that the command can be applied to a state, before it's executed.
+  /// A rejected command is neither executed nor logged, and fails with `MadeleineError::CommandRejected`.
+  /// By default every command is accepted.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::MadeleineError;
+  ///
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Rename(String);
+  ///
+  /// impl Command<'_> for Rename {
+  ///   type SystemState = String;
+  ///
+  ///   fn execute(&self, _old_state: String) -> String {
+  ///     self.0.clone()
+  ///   }
+  ///
+  ///   fn validate(&self, _state: &String) -> Result<(), String> {
+  ///     if self.0.is_empty() {
+  ///       return Err(String::from("names can't be empty"));
+  ///     }
+  ///
+  ///     Ok(())
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || String::from("draft"))?;
+  ///
+  /// let rejected = madeleine.execute_command(Rename(String::new()));
+  /// assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+  ///
+  /// madeleine.execute_command(Rename(String::from("final")))?;
+  ///
+  /// assert_eq!(madeleine.tap(|name| name), "final");
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn validate(&self, _state: &Self::SystemState) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+/// A command which changes the state in place, executed with `Madeleine::execute_command_mut`,
+/// so that executing it never clones the state.
+///
+/// Every such command is also a `Command`, whose `execute` is used when the log is replayed,
+/// and can simply change the state it's given in place: `self.execute_mut(&mut old_state); old_state`.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// use madeleine::{Command, Madeleine, MutCommand};
+///
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// struct Push(u8);
+///
+/// impl Command<'_> for Push {
+///   type SystemState = Vec<u8>;
+///
+///   fn execute(&self, mut old_state: Vec<u8>) -> Vec<u8> {
+///     self.execute_mut(&mut old_state);
+///     old_state
+///   }
+/// }
+///
+/// impl MutCommand<'_> for Push {
+///   fn execute_mut(&self, state: &mut Vec<u8>) {
+///     state.push(self.0);
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, Vec::new)?;
+///
+/// madeleine.execute_command_mut(Push(1))?;
+/// madeleine.execute_command(Push(2))?;
+///
+/// assert_eq!(madeleine.tap(|state| state), vec![1, 2]);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait MutCommand<'a>: Command<'a> {
+  /// Core logic for the command, changing the state in place.
+  /// This must not panic, since the command is logged before it's executed.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// # use madeleine::{Command, MutCommand};
+  /// # #[derive(serde::Deserialize, serde::Serialize)]
+  /// # struct Push(u8);
+  /// # impl Command<'_> for Push {
+  /// #   type SystemState = Vec<u8>;
+  /// #   fn execute(&self, mut old_state: Vec<u8>) -> Vec<u8> {
+  /// #     self.execute_mut(&mut old_state);
+  /// #     old_state
+  /// #   }
+  /// # }
+  /// # impl MutCommand<'_> for Push {
+  /// #   fn execute_mut(&self, state: &mut Vec<u8>) {
+  /// #     state.push(self.0);
+  /// #   }
+  /// # }
+  /// let mut state = vec![1];
+  /// Push(2).execute_mut(&mut state);
+  ///
+  /// assert_eq!(state, vec![1, 2]);
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, Vec::new)?;
+  /// madeleine.execute_command_mut(Push(1))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), vec![1]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn execute_mut(&self, state: &mut Self::SystemState);
+
+  /// Change the state in place as `execute_mut` does, given where the command falls in history.
+  /// Calls `execute_mut` unless overridden, and must agree with `Command::execute_with_ctx`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::{CommandContext, MutCommand};
+  ///
+  /// /// Records where each command fell in history.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct RecordPosition;
+  ///
+  /// impl Command<'_> for RecordPosition {
+  ///   type SystemState = Vec<u64>;
+  ///
+  ///   fn execute(&self, old_state: Vec<u64>) -> Vec<u64> {
+  ///     old_state
+  ///   }
+  ///
+  ///   fn execute_with_ctx(&self, mut old_state: Vec<u64>, ctx: &CommandContext) -> Vec<u64> {
+  ///     self.execute_mut_with_ctx(&mut old_state, ctx);
+  ///     old_state
+  ///   }
+  /// }
+  ///
+  /// impl MutCommand<'_> for RecordPosition {
+  ///   fn execute_mut(&self, _state: &mut Vec<u64>) {}
+  ///
+  ///   fn execute_mut_with_ctx(&self, state: &mut Vec<u64>, ctx: &CommandContext) {
+  ///     state.push(ctx.position());
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, Vec::new)?;
+  ///
+  /// madeleine.execute_command_mut(RecordPosition)?;
+  /// madeleine.execute_command_mut(RecordPosition)?;
+  ///
+  /// assert_eq!(madeleine.tap(|positions| positions), vec![0, 1]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn execute_mut_with_ctx(&self, state: &mut Self::SystemState, _ctx: &CommandContext) {
+    self.execute_mut(state);
+  }
+}
+
+/// A command which also produces an output for its caller, such as the key of a record it inserted,
+/// executed with `Madeleine::execute_command_with_output`.
+///
+/// Every such command is also a `Command`, which discards the output, e.g. when the log is replayed.
+/// Implement this instead of `Command`, not as well.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// use madeleine::{CommandWithOutput, Madeleine};
+///
+/// /// Adds a record, returning its key.
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// struct Insert(String);
+///
+/// impl CommandWithOutput<'_> for Insert {
+///   type SystemState = Vec<String>;
+///   type Output = usize;
+///
+///   fn execute_with_output(&self, mut old_state: Vec<String>) -> (Vec<String>, usize) {
+///     old_state.push(self.0.clone());
+///     let key = old_state.len() - 1;
+///
+///     (old_state, key)
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, Vec::new)?;
+///
+/// assert_eq!(madeleine.execute_command_with_output(Insert(String::from("first")))?, 0);
+/// assert_eq!(madeleine.execute_command_with_output(Insert(String::from("second")))?, 1);
+///
+/// madeleine.execute_command(Insert(String::from("third")))?;
+///
+/// assert_eq!(madeleine.tap(|records| records.len()), 3);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait CommandWithOutput<'a>: Serialize + Deserialize<'a> {
+  /// The type of the `Madeleine` instance's internal state, as for `Command::SystemState`.
+  type SystemState: Serialize + Deserialize<'a> + Clone;
+  /// What the command returns to its caller alongside the new state.
+  type Output;
+
+  /// Core logic for the command, returning the new state and the output.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::CommandWithOutput;
+  ///
+  /// /// Adds to the total, returning the total before.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct FetchAdd(u64);
+  ///
+  /// impl CommandWithOutput<'_> for FetchAdd {
+  ///   type SystemState = u64;
+  ///   type Output = u64;
+  ///
+  ///   fn execute_with_output(&self, old_state: u64) -> (u64, u64) {
+  ///     (old_state + self.0, old_state)
+  ///   }
+  /// }
+  ///
+  /// assert_eq!(FetchAdd(2).execute_with_output(3), (5, 3));
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 3)?;
+  ///
+  /// assert_eq!(madeleine.execute_command_with_output(FetchAdd(2))?, 3);
+  /// assert_eq!(madeleine.tap(|total| total), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn execute_with_output(&self, old_state: Self::SystemState) -> (Self::SystemState, Self::Output);
+
+  /// Execute the command as `execute_with_output` does, or reject the transition, see `Command::try_execute_with_ctx`.
+  /// Calls `execute_with_output` unless overridden.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{CommandWithOutput, MadeleineError};
+  ///
+  /// /// Books seats, returning how many are left.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Book(u64);
+  ///
+  /// impl CommandWithOutput<'_> for Book {
+  ///   type SystemState = u64;
+  ///   type Output = u64;
+  ///
+  ///   fn execute_with_output(&self, old_state: u64) -> (u64, u64) {
+  ///     let left = old_state.saturating_sub(self.0);
+  ///
+  ///     (left, left)
+  ///   }
+  ///
+  ///   fn try_execute_with_output(&self, old_state: u64) -> Result<(u64, u64), String> {
+  ///     match old_state.checked_sub(self.0) {
+  ///       Some(left) => Ok((left, left)),
+  ///       None => Err(format!("only {} seats are left", old_state)),
+  ///     }
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 10)?;
+  ///
+  /// assert_eq!(madeleine.execute_command_with_output(Book(8))?, 2);
+  ///
+  /// let rejected = madeleine.execute_command_with_output(Book(3));
+  ///
+  /// assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+  /// assert_eq!(madeleine.tap(|left| left), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn try_execute_with_output(
+    &self,
+    old_state: Self::SystemState,
+  ) -> Result<(Self::SystemState, Self::Output), String> {
+    Ok(self.execute_with_output(old_state))
+  }
+
+  /// Check that the command can be applied to a state, see `Command::validate`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{CommandWithOutput, MadeleineError};
+  ///
+  /// /// Removes the newest item, returning it.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Pop;
+  ///
+  /// impl CommandWithOutput<'_> for Pop {
+  ///   type SystemState = Vec<u8>;
+  ///   type Output = u8;
+  ///
+  ///   fn execute_with_output(&self, mut old_state: Vec<u8>) -> (Vec<u8>, u8) {
+  ///     let item = old_state.pop().unwrap_or_default();
+  ///
+  ///     (old_state, item)
+  ///   }
+  ///
+  ///   fn validate(&self, state: &Vec<u8>) -> Result<(), String> {
+  ///     if state.is_empty() {
+  ///       return Err(String::from("nothing to pop"));
+  ///     }
+  ///
+  ///     Ok(())
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || vec![7])?;
+  ///
+  /// assert_eq!(madeleine.execute_command_with_output(Pop)?, 7);
+  ///
+  /// let rejected = madeleine.execute_command_with_output(Pop);
+  ///
+  /// assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+  /// assert_eq!(madeleine.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn validate(&self, _state: &Self::SystemState) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+impl<'a, T: CommandWithOutput<'a>> Command<'a> for T {
+  type SystemState = T::SystemState;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    self.execute_with_output(old_state).0
+  }
+
+  fn try_execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    _ctx: &CommandContext,
+  ) -> Result<Self::SystemState, String> {
+    self
+      .try_execute_with_output(old_state)
+      .map(|(state, _output)| state)
+  }
+
+  fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+    CommandWithOutput::validate(self, state)
+  }
+}
+
+/// A command whose execution can fail, rejecting the transition, e.g. because it would break an invariant
+/// such as a balance never going below zero. Executed with `Madeleine::execute_command` like any other command,
+/// which fails with `MadeleineError::CommandRejected` carrying the error's message, without logging the command
+/// or changing the state, so replaying the log after a restart reproduces the live state.
+///
+/// Every such command is also a `CommandWithOutput` without an output, and so a `Command`.
+/// Implement this instead of either, not as well.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// use madeleine::{Madeleine, MadeleineError, TryCommand};
+///
+/// #[derive(serde::Deserialize, serde::Serialize)]
+/// struct Withdraw(u64);
+///
+/// impl TryCommand<'_> for Withdraw {
+///   type SystemState = u64;
+///   type Error = String;
+///
+///   fn try_execute(&self, balance: &u64) -> Result<u64, String> {
+///     balance
+///       .checked_sub(self.0)
+///       .ok_or_else(|| format!("can't withdraw {} from {}", self.0, balance))
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, || 5)?;
+///
+/// madeleine.execute_command(Withdraw(3))?;
+/// let rejected = madeleine.execute_command(Withdraw(3));
+///
+/// assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+/// assert_eq!(madeleine.tap(|balance| balance), 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait TryCommand<'a>: Serialize + Deserialize<'a> {
+  /// The type of the `Madeleine` instance's internal state, as for `Command::SystemState`.
+  type SystemState: Serialize + Deserialize<'a> + Clone;
+  /// Why the command rejected a transition.
+  type Error: Display;
+
+  /// Core logic for the command, returning the new state or rejecting the transition.
+  /// Since only commands which succeeded are logged, one which fails when the log is replayed leaves the state as it was.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::TryCommand;
+  ///
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Withdraw(u64);
+  ///
+  /// impl TryCommand<'_> for Withdraw {
+  ///   type SystemState = u64;
+  ///   type Error = String;
+  ///
+  ///   fn try_execute(&self, balance: &u64) -> Result<u64, String> {
+  ///     balance
+  ///       .checked_sub(self.0)
+  ///       .ok_or_else(|| format!("can't withdraw {} from {}", self.0, balance))
+  ///   }
+  /// }
+  ///
+  /// assert_eq!(Withdraw(3).try_execute(&5), Ok(2));
+  /// assert!(Withdraw(3).try_execute(&2).is_err());
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 5)?;
+  /// madeleine.execute_command(Withdraw(3))?;
+  ///
+  /// assert_eq!(madeleine.tap(|balance| balance), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn try_execute(&self, state: &Self::SystemState) -> Result<Self::SystemState, Self::Error>;
+}
+
+impl<'a, T: TryCommand<'a>> CommandWithOutput<'a> for T {
+  type SystemState = T::SystemState;
+  type Output = ();
+
+  fn execute_with_output(&self, old_state: Self::SystemState) -> (Self::SystemState, Self::Output) {
+    (self.try_execute(&old_state).unwrap_or(old_state), ())
+  }
+
+  fn try_execute_with_output(
+    &self,
+    old_state: Self::SystemState,
+  ) -> Result<(Self::SystemState, Self::Output), String> {
+    self
+      .try_execute(&old_state)
+      .map(|state| (state, ()))
+      .map_err(|error| error.to_string())
+  }
 }
@@ -1,48 +1,517 @@
-use std::cell::RefCell;
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 
-use commitlog::*;
-use ulid::Ulid;
+use commitlog::Offset;
+use serde_json::Value;
+use ulid::{Generator, Ulid};
 
+use crate::cancellation::CancellationToken;
+use crate::codec::{self, Codec};
 use crate::command::Command;
+use crate::command_store::{CommandStore, LogBackend, StoreWrapper, StoredRecord, SyncMode};
+use crate::export::CommandRecord;
+use crate::locks::{lock_recovering, read_recovering, write_recovering};
+use crate::logged_command::RawLoggedCommand;
 use crate::madeleine_error::MadeleineError;
+use crate::payload_format::{self, require_json_entry, PayloadFormat};
+#[cfg(any(test, feature = "testing"))]
+use crate::testing::{FailpointStore, StorageOperation};
+
+/// Largest serialized command, in bytes, which the commit log accepts by default.
+/// This leaves headroom for the ULID and framing within the commit log's one million byte message limit.
+pub(crate) const MAX_PAYLOAD_BYTES: usize = 999_000;
+
+/// Upper bound on the bytes read from the log in a single batch.
+/// Matches the commit log's default maximum message size so that any stored entry fits.
+const READ_LIMIT_BYTES: usize = 1_000_000;
+
+/// Source of command ULIDs, shared by every log in the process so that ULIDs increase
+/// monotonically even when several commands are logged within the same millisecond.
+static ID_GENERATOR: Mutex<Generator> = Mutex::new(Generator::new());
+
+/// A fresh ULID from the process-wide generator, greater than every one it handed out before.
+pub(crate) fn next_id() -> Ulid {
+  ID_GENERATOR
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+    .generate()
+    // Only possible after 2^80 ULIDs within one millisecond.
+    .unwrap_or_else(|_overflow| Ulid::new())
+}
 
 /// Represents an append-only log of commands.
-/// Backed by a stateful store on disk.
+/// Backed by a stateful store on disk, see `LogBackend`.
 pub(crate) struct CommandLog {
-  commit_log: RefCell<CommitLog>,
+  store: RwLock<Box<dyn CommandStore>>,
+  backend: LogBackend,
+  /// Passes the store through, whenever the log is opened, if set.
+  wrapper: Option<StoreWrapper>,
+  /// When appends are synced to disk.
+  sync_mode: Mutex<SyncMode>,
+  /// Compresses appended entries, if set.
+  payload_codec: Mutex<Option<Arc<dyn Codec>>>,
+  /// Names the `CommandSerializer` which serializes commands into entries, see `serialize_command`.
+  payload_format: Mutex<PayloadFormat>,
+  /// Checked between the batches of entries read when visiting the log, if set.
+  cancellation: Mutex<Option<CancellationToken>>,
+  /// Scripted failures for storage operations, see `failpoint`.
+  #[cfg(any(test, feature = "testing"))]
+  failpoints: Mutex<Option<Arc<FailpointStore>>>,
 }
 
 impl CommandLog {
-  /// Constructor function.
+  /// Open the log in a directory with whichever backend it was written with, or the default for a new log.
   pub fn new(store_dir: PathBuf) -> Result<Self, MadeleineError> {
-    let opts = LogOptions::new(store_dir);
-    let commit_log = RefCell::new(CommitLog::new(opts)?);
+    let backend = LogBackend::of_dir(&store_dir);
+
+    Self::with_backend(store_dir, backend)
+  }
+
+  /// Open the log in a directory with a backend, creating it if it's missing.
+  pub fn with_backend(store_dir: PathBuf, backend: LogBackend) -> Result<Self, MadeleineError> {
+    Ok(Self::with_store(backend.open(&store_dir)?, backend))
+  }
+
+  /// Keep the log in a store which is already open, see `LogBackend::Custom`.
+  pub fn with_store(store: Box<dyn CommandStore>, backend: LogBackend) -> Self {
+    Self {
+      store: RwLock::new(store),
+      backend,
+      wrapper: None,
+      sync_mode: Mutex::new(backend.default_sync_mode()),
+      payload_codec: Mutex::new(None),
+      payload_format: Mutex::new(PayloadFormat::default()),
+      cancellation: Mutex::new(None),
+      #[cfg(any(test, feature = "testing"))]
+      failpoints: Mutex::new(None),
+    }
+  }
+
+  /// Pass the store through `wrapper`, now and whenever the log is reopened, see `MadeleineBuilder::wrap_command_store`.
+  pub fn wrapped(mut self, wrapper: Option<StoreWrapper>) -> Self {
+    if let Some(wrapper) = &wrapper {
+      let store = self
+        .store
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+      self.store = RwLock::new(wrapper.wrap(store));
+    }
+
+    self.wrapper = wrapper;
+    self
+  }
+
+  /// Reopen the log from a directory with the same backend, e.g. after another log was swapped in for it.
+  pub fn reopen(&self, store_dir: PathBuf) -> Result<(), MadeleineError> {
+    let store = self.backend.open(&store_dir)?;
+    *write_recovering(&self.store) = match &self.wrapper {
+      Some(wrapper) => wrapper.wrap(store),
+      None => store,
+    };
+
+    Ok(())
+  }
+
+  /// How the log is kept on disk.
+  pub fn backend(&self) -> LogBackend {
+    self.backend
+  }
+
+  /// Fail with `MadeleineError::MissingCapability` unless the log can be rewritten into a fresh directory
+  /// and reopened from it, as compaction does, which a custom store can't.
+  pub fn check_rewritable(&self) -> Result<(), MadeleineError> {
+    match self.backend {
+      LogBackend::Custom => Err(MadeleineError::MissingCapability(String::from(
+        "a log kept by a custom command store can't be rewritten",
+      ))),
+      _ => Ok(()),
+    }
+  }
+
+  /// Sync appends from now on as `sync_mode` says.
+  pub fn set_sync_mode(&self, sync_mode: SyncMode) {
+    *lock_recovering(&self.sync_mode) = sync_mode;
+  }
+
+  /// When appends are synced to disk.
+  pub fn sync_mode(&self) -> SyncMode {
+    *lock_recovering(&self.sync_mode)
+  }
+
+  /// Append records to the store, syncing them to disk if the sync mode asks for it.
+  fn append_records(&self, records: &[(&[u8], Option<u64>)]) -> Result<Offset, MadeleineError> {
+    let mut store = write_recovering(&self.store);
+    let first = match records {
+      [(entry, None)] => store.append(entry)?,
+      _ => store.append_batch(records)?,
+    };
+
+    if self.sync_mode() == SyncMode::Full {
+      store.flush()?;
+    }
+
+    Ok(first)
+  }
+
+  /// Give the scripted failures, if any, the chance to intervene in a storage operation about to happen.
+  #[cfg(any(test, feature = "testing"))]
+  pub fn failpoint(&self, operation: StorageOperation) -> Result<(), MadeleineError> {
+    match lock_recovering(&self.failpoints).as_ref() {
+      Some(failpoints) => failpoints.check(operation),
+      None => Ok(()),
+    }
+  }
+
+  /// Script failures for storage operations, or stop by passing `None`.
+  #[cfg(any(test, feature = "testing"))]
+  pub fn set_failpoints(
+    &self,
+    failpoints: Option<Arc<FailpointStore>>,
+  ) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.failpoints) = failpoints;
+
+    Ok(())
+  }
+
+  /// Stop visiting the log between batches of entries once `cancellation` is cancelled, or never by passing `None`.
+  pub fn set_cancellation(&self, cancellation: Option<CancellationToken>) {
+    *lock_recovering(&self.cancellation) = cancellation;
+  }
+
+  /// The token checked while visiting the log, if any.
+  pub fn cancellation(&self) -> Option<CancellationToken> {
+    lock_recovering(&self.cancellation).clone()
+  }
+
+  /// Compress entries appended from now on with a codec, or stop compressing them by passing `None`.
+  pub fn set_payload_codec(&self, codec: Option<Arc<dyn Codec>>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.payload_codec) = codec;
+
+    Ok(())
+  }
+
+  /// The codec compressing appended entries, if any.
+  pub fn payload_codec(&self) -> Result<Option<Arc<dyn Codec>>, MadeleineError> {
+    Ok(lock_recovering(&self.payload_codec).clone())
+  }
+
+  /// Serialize commands from now on in a format, as recorded in the store's metadata.
+  pub fn set_payload_format(&self, payload_format: PayloadFormat) {
+    *lock_recovering(&self.payload_format) = payload_format;
+  }
+
+  /// The format commands are serialized in.
+  pub fn payload_format(&self) -> PayloadFormat {
+    *lock_recovering(&self.payload_format)
+  }
+
+  /// Serialize a command into an entry suitable for appending to the log, identified by a fresh ULID.
+  pub fn serialize_command<'a, C: Command<'a>>(
+    &self,
+    command: &C,
+  ) -> Result<(Ulid, Vec<u8>), MadeleineError> {
+    let id = next_id();
+    let serialized_command = self.payload_format().encode_entry(id, command)?;
+
+    Ok((id, serialized_command))
+  }
+
+  /// Append a serialized entry to the log.
+  pub fn append_entry(&self, entry: &[u8]) -> Result<Offset, MadeleineError> {
+    self.append_sequenced_entry(entry, None)
+  }
+
+  /// Append a serialized entry to the log, stamped with a global sequence number if one is given.
+  pub fn append_sequenced_entry(
+    &self,
+    entry: &[u8],
+    sequence: Option<u64>,
+  ) -> Result<Offset, MadeleineError> {
+    #[cfg(any(test, feature = "testing"))]
+    self.failpoint(StorageOperation::Append)?;
+
+    let entry = codec::encode(lock_recovering(&self.payload_codec).as_deref(), entry)?;
+
+    self.append_records(&[(entry.as_ref(), sequence)])
+  }
+
+  /// Append several serialized entries to the log with a single write, each stamped with its sequence number if it has one.
+  pub fn append_sequenced_entries(
+    &self,
+    entries: &[(Vec<u8>, Option<u64>)],
+  ) -> Result<Vec<Offset>, MadeleineError> {
+    #[cfg(any(test, feature = "testing"))]
+    self.failpoint(StorageOperation::Append)?;
+
+    let payload_codec = lock_recovering(&self.payload_codec);
+    let encoded = entries
+      .iter()
+      .map(|(entry, sequence)| Ok((codec::encode(payload_codec.as_deref(), entry)?, *sequence)))
+      .collect::<Result<Vec<_>, MadeleineError>>()?;
+    let records: Vec<(&[u8], Option<u64>)> = encoded
+      .iter()
+      .map(|(entry, sequence)| (entry.as_ref(), *sequence))
+      .collect();
+
+    let first = self.append_records(&records)?;
+
+    Ok((first..first + records.len() as u64).collect())
+  }
+
+  /// Discard every entry after the first `keep` entries, or every entry if `keep` is zero.
+  pub fn truncate(&self, keep: u64) -> Result<(), MadeleineError> {
+    write_recovering(&self.store).truncate(keep)
+  }
+
+  /// Sync appended entries to disk.
+  pub fn flush(&self) -> Result<(), MadeleineError> {
+    write_recovering(&self.store).flush()
+  }
+
+  /// Visit every entry in the log in the order it was appended, passing along its offset and raw payload.
+  pub fn for_each_entry<F>(&self, mut visitor: F) -> Result<(), MadeleineError>
+  where
+    F: FnMut(Offset, &[u8]) -> Result<(), MadeleineError>,
+  {
+    self.for_each_sequenced_entry(|offset, _sequence, entry| visitor(offset, entry))
+  }
+
+  /// Visit every entry in the log in the order it was appended, passing along its offset,
+  /// global sequence number if it was stamped with one, and raw payload.
+  pub fn for_each_sequenced_entry<F>(&self, mut visitor: F) -> Result<(), MadeleineError>
+  where
+    F: FnMut(Offset, Option<u64>, &[u8]) -> Result<(), MadeleineError>,
+  {
+    let cancellation = self.cancellation();
+    let store = read_recovering(&self.store);
+    let mut next_offset = 0;
+    let mut visited = 0;
+
+    loop {
+      if let Some(cancellation) = &cancellation {
+        cancellation.check(visited)?;
+      }
+
+      let records = store.read(next_offset, READ_LIMIT_BYTES)?;
+
+      if records.is_empty() {
+        break;
+      }
+
+      for record in records {
+        visitor(
+          record.offset,
+          record.sequence,
+          &codec::decode(&record.entry)?,
+        )?;
+        next_offset = record.offset + 1;
+        visited += 1;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Iterate over the commands in the log in the order they were logged, reading a page of entries at a time
+  /// so that the whole log is never held in memory. An entry which can't be parsed yields an error and iteration
+  /// carries on, but failing to read a page ends it.
+  pub fn commands(&self) -> CommandIter<'_> {
+    CommandIter {
+      log: self,
+      next_offset: 0,
+      page: Vec::new().into_iter(),
+      finished: false,
+    }
+  }
+
+  /// Write every command in the log to `writer` in the order it was logged, one `CommandRecord` per line,
+  /// returning how many were written. Fails with `MadeleineError::PayloadFormatMismatch` unless commands are JSON.
+  pub fn export_ndjson<W: Write>(&self, mut writer: W) -> Result<u64, MadeleineError> {
+    let mut written = 0;
+
+    self.for_each_entry(|_offset, entry| {
+      let (ulid, payload): (Ulid, Value) =
+        serde_json::from_slice(require_json_entry(entry, "exporting the command log")?)?;
+
+      serde_json::to_writer(
+        &mut writer,
+        &CommandRecord {
+          ulid,
+          recorded_at: ulid.timestamp_ms(),
+          payload,
+        },
+      )?;
+      writer.write_all(b"\n")?;
+      written += 1;
+
+      Ok(())
+    })?;
+
+    writer.flush()?;
+
+    Ok(written)
+  }
 
-    Ok(Self { commit_log })
+  /// Every command logged after the command identified by `after`,
+  /// or every command with a greater ULID if `after` isn't in the log.
+  pub fn commands_after(&self, after: Ulid) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    let mut all = Vec::new();
+    let mut position_of_after = None;
+
+    self.for_each_sequenced_entry(|offset, sequence, entry| {
+      let command = RawLoggedCommand::from_entry(offset, sequence, entry)?;
+
+      if command.id == after {
+        position_of_after = Some(all.len());
+      }
+
+      all.push(command);
+
+      Ok(())
+    })?;
+
+    let commands = match position_of_after {
+      Some(position) => all.split_off(position + 1),
+      None => all
+        .into_iter()
+        .filter(|command| command.id > after)
+        .collect(),
+    };
+
+    Ok(commands)
+  }
+
+  /// Up to `limit` commands in the order they were logged, starting with the first one, or the one logged after
+  /// the command identified by `after`. If `after` isn't in the log, they start with the first command with a greater
+  /// ULID instead, as `commands_after` does. The log is read a page at a time, and reading stops once the page
+  /// is full and `after` was found.
+  pub fn commands_in_range(
+    &self,
+    after: Option<Ulid>,
+    limit: usize,
+  ) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    let mut page = Vec::new();
+    let mut found_after = after.is_none();
+
+    for command in self.commands() {
+      let command = command?;
+
+      if !found_after && after == Some(command.id) {
+        // Commands before it with greater ULIDs were only candidates in case it wasn't in the log.
+        page.clear();
+        found_after = true;
+      } else if found_after || after.is_some_and(|after| command.id > after) {
+        if page.len() < limit {
+          page.push(command);
+        }
+
+        if found_after && page.len() == limit {
+          break;
+        }
+      }
+    }
+
+    Ok(page)
+  }
+
+  /// Every command logged up to and including the command identified by `up_to`, in the order they were logged,
+  /// leaving out any with a greater ULID.
+  pub fn commands_up_to(&self, up_to: Ulid) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    let mut commands = Vec::new();
+
+    self.for_each_sequenced_entry(|offset, sequence, entry| {
+      let command = RawLoggedCommand::from_entry(offset, sequence, entry)?;
+
+      if command.id <= up_to {
+        commands.push(command);
+      }
+
+      Ok(())
+    })?;
+
+    Ok(commands)
   }
 
-  /// Append a command to the log, serializing it first.
-  pub fn append_command<'a, C: Command<'a>>(&self, command: C) -> Result<Offset, MadeleineError> {
-    let log_entry = (Ulid::new(), command);
+  /// ULID of the most recently logged command, if any.
+  pub fn last_id(&self) -> Result<Option<Ulid>, MadeleineError> {
+    let store = read_recovering(&self.store);
 
-    let serialized_command = serde_json::to_string(&log_entry)?;
+    let Some(last_offset) = store.len().checked_sub(1) else {
+      return Ok(None);
+    };
 
-    let mut commit_log = self.commit_log.try_borrow_mut()?;
+    match store.read(last_offset, READ_LIMIT_BYTES)?.first() {
+      Some(record) => Ok(Some(payload_format::entry_id(&codec::decode(
+        &record.entry,
+      )?)?)),
+      None => Ok(None),
+    }
+  }
 
-    let offset = commit_log.append_msg(&serialized_command)?;
+  /// The command at `offset` in the log, or `None` if the log ends before it.
+  pub fn command_at(&self, offset: u64) -> Result<Option<RawLoggedCommand>, MadeleineError> {
+    let record = read_recovering(&self.store)
+      .read(offset, READ_LIMIT_BYTES)?
+      .into_iter()
+      .next();
 
-    Ok(offset)
+    record
+      .map(|record| {
+        RawLoggedCommand::from_entry(
+          record.offset,
+          record.sequence,
+          &codec::decode(&record.entry)?,
+        )
+      })
+      .transpose()
   }
 
-  /// Get the length of the underlying commit log.
+  /// Get the length of the underlying log.
   pub fn len(&self) -> u64 {
-    let extracted = self.commit_log.borrow().last_offset().unwrap_or(0);
+    read_recovering(&self.store).len()
+  }
+}
+
+/// Commands read a page at a time from a `CommandLog`, see `CommandLog::commands`.
+pub(crate) struct CommandIter<'a> {
+  log: &'a CommandLog,
+  /// Offset of the first entry of the next page.
+  next_offset: u64,
+  /// What's left of the page being iterated over.
+  page: std::vec::IntoIter<StoredRecord>,
+  /// Whether the end of the log was reached, or a page couldn't be read.
+  finished: bool,
+}
+
+impl Iterator for CommandIter<'_> {
+  type Item = Result<RawLoggedCommand, MadeleineError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    loop {
+      if let Some(record) = self.page.next() {
+        self.next_offset = record.offset + 1;
+
+        return Some(
+          codec::decode(&record.entry)
+            .and_then(|entry| RawLoggedCommand::from_entry(record.offset, record.sequence, &entry)),
+        );
+      }
+
+      if self.finished {
+        return None;
+      }
+
+      // The store is only locked while a page is read, so commands can be appended between pages.
+      match read_recovering(&self.log.store).read(self.next_offset, READ_LIMIT_BYTES) {
+        Ok(records) if records.is_empty() => self.finished = true,
+        Ok(records) => self.page = records.into_iter(),
+        Err(error) => {
+          self.finished = true;
 
-    if extracted > 0 {
-      extracted + 1
-    } else {
-      extracted
+          return Some(Err(error));
+        }
+      }
     }
   }
 }
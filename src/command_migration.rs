@@ -0,0 +1,406 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::hashing::HashAlgo;
+use crate::locks::{read_recovering, write_recovering};
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::StoreMetadata;
+use crate::payload_format::PayloadFormat;
+
+/// The version of commands logged before any `CommandMigration` was registered with their store.
+pub const INITIAL_COMMAND_VERSION: u32 = 1;
+
+/// A change of the command type's serialized form, which rewrites commands logged in the previous form as they're
+/// read back, see `Madeleine::add_command_migration`.
+///
+/// Registered migrations form a chain ordered by version, and the latest one's version is the one commands are logged at.
+/// A command logged at an earlier version is passed through each later migration in turn, so each only needs to
+/// migrate from the version before it. Migrations only apply to JSON payloads.
+pub trait CommandMigration: Send + Sync {
+  /// The version this migration brings commands to, greater than `INITIAL_COMMAND_VERSION` and unique in the chain.
+  fn version(&self) -> u32;
+
+  /// Rewrite a command's JSON, serialized at `old_version`, as it's serialized at `version()`.
+  /// Fails with `MadeleineError::CommandMigrationError` if it can't.
+  fn migrate(&self, old_json: &str, old_version: u32) -> Result<String, MadeleineError>;
+}
+
+/// The version commands were logged at from a point in a store's log on, as recorded in its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct CommandVersion {
+  /// The version of commands logged after `after`.
+  pub version: u32,
+  /// ULID of the last command logged before the version took effect, or nil if none had been.
+  pub after: Ulid,
+}
+
+/// The version a command was logged at, given the versions recorded in order.
+fn version_of(versions: &[CommandVersion], id: Ulid) -> u32 {
+  versions
+    .iter()
+    .rev()
+    .find(|recorded| recorded.after < id)
+    .map_or(INITIAL_COMMAND_VERSION, |recorded| recorded.version)
+}
+
+/// Record that commands logged after `after` are at `version`, unless they already are,
+/// returning every version recorded for the store.
+pub(crate) fn record_command_version(
+  location_dir_path: &Path,
+  hash_algo: HashAlgo,
+  version: u32,
+  after: Ulid,
+) -> Result<Vec<CommandVersion>, MadeleineError> {
+  let mut metadata = StoreMetadata::load_or_create(location_dir_path, hash_algo)?;
+  let versions = &mut metadata.command_versions;
+
+  let latest_version = versions
+    .last()
+    .map_or(INITIAL_COMMAND_VERSION, |recorded| recorded.version);
+
+  if latest_version != version {
+    // Migrations registered one after the other before any command is logged only change the version once.
+    match versions.last_mut() {
+      Some(recorded) if recorded.after == after => recorded.version = version,
+      _ => versions.push(CommandVersion { version, after }),
+    }
+
+    metadata.write(location_dir_path)?;
+  }
+
+  Ok(metadata.command_versions)
+}
+
+/// Migrations passed to a `MadeleineBuilder`, compared and shown by their versions.
+#[derive(Clone, Default)]
+pub(crate) struct MigrationList(pub Vec<Arc<dyn CommandMigration>>);
+
+impl fmt::Debug for MigrationList {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_list()
+      .entries(self.0.iter().map(|migration| migration.version()))
+      .finish()
+  }
+}
+
+impl PartialEq for MigrationList {
+  fn eq(&self, other: &Self) -> bool {
+    self
+      .0
+      .iter()
+      .map(|migration| migration.version())
+      .eq(other.0.iter().map(|migration| migration.version()))
+  }
+}
+
+impl Eq for MigrationList {}
+
+/// The migration chain of an instance, and the versions its store's commands were logged at.
+#[derive(Default)]
+pub(crate) struct CommandMigrations {
+  chain: RwLock<Vec<Arc<dyn CommandMigration>>>,
+  versions: RwLock<Vec<CommandVersion>>,
+}
+
+impl CommandMigrations {
+  /// Add a migration to the chain, returning the version commands are now logged at.
+  /// Fails with `MadeleineError::CommandMigrationError` if the chain already has a migration to its version.
+  pub fn add(&self, migration: Arc<dyn CommandMigration>) -> Result<u32, MadeleineError> {
+    let mut chain = write_recovering(&self.chain);
+    let version = migration.version();
+
+    if version <= INITIAL_COMMAND_VERSION {
+      return Err(MadeleineError::CommandMigrationError(format!(
+        "migrations must be to versions after {}, not {}",
+        INITIAL_COMMAND_VERSION, version
+      )));
+    }
+
+    if chain
+      .iter()
+      .any(|registered| registered.version() == version)
+    {
+      return Err(MadeleineError::CommandMigrationError(format!(
+        "a migration to version {} is already registered",
+        version
+      )));
+    }
+
+    chain.push(migration);
+    chain.sort_by_key(|registered| registered.version());
+
+    Ok(chain.last().map_or(version, |latest| latest.version()))
+  }
+
+  /// Replace the versions the store's commands were logged at, as recorded in its metadata.
+  pub fn set_versions(&self, versions: Vec<CommandVersion>) {
+    *write_recovering(&self.versions) = versions;
+  }
+
+  /// Deserialize a logged command, first migrating it if it was logged at an earlier version than the chain's latest.
+  /// Without migrations, commands are deserialized as they were logged.
+  pub fn decode<C: DeserializeOwned>(
+    &self,
+    logged: &RawLoggedCommand,
+  ) -> Result<C, MadeleineError> {
+    let chain = read_recovering(&self.chain);
+
+    let current_version = match chain.last() {
+      Some(latest) => latest.version(),
+      None => return logged.deserialize(),
+    };
+
+    let version = version_of(&read_recovering(&self.versions), logged.id);
+
+    if version == current_version {
+      return logged.deserialize();
+    }
+
+    if version > current_version {
+      return Err(MadeleineError::CommandMigrationError(format!(
+        "command {} was logged at version {}, but migrations only go up to version {}",
+        logged.id, version, current_version
+      )));
+    }
+
+    if logged.format != PayloadFormat::Json {
+      return Err(MadeleineError::PayloadFormatMismatch(format!(
+        "command migrations need JSON payloads, but command {} is serialized as {}",
+        logged.id,
+        logged.format.name()
+      )));
+    }
+
+    let mut json = String::from_utf8(logged.payload.clone()).map_err(|error| {
+      MadeleineError::CommandMigrationError(format!(
+        "command {} isn't valid UTF-8: {}",
+        logged.id, error
+      ))
+    })?;
+    let mut json_version = version;
+
+    for migration in chain
+      .iter()
+      .filter(|migration| migration.version() > version)
+    {
+      json = migration.migrate(&json, json_version)?;
+      json_version = migration.version();
+    }
+
+    Ok(serde_json::from_str(&json)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde_json::{json, Value};
+
+  use crate::{Command, HistoryEntry, Madeleine};
+
+  /// A deposit as first logged, in whole dollars.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct DepositV1 {
+    dollars: u64,
+  }
+
+  impl Command<'_> for DepositV1 {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.dollars * 100
+    }
+  }
+
+  /// A deposit once it was logged in cents.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct DepositV2 {
+    cents: u64,
+  }
+
+  impl Command<'_> for DepositV2 {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.cents
+    }
+  }
+
+  /// A deposit once it gained a memo.
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct Deposit {
+    cents: u64,
+    memo: String,
+  }
+
+  impl Command<'_> for Deposit {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.cents
+    }
+  }
+
+  /// Rewrite a command's JSON object with `edit`.
+  fn edit_object(
+    old_json: &str,
+    edit: impl FnOnce(&mut serde_json::Map<String, Value>) -> Result<(), String>,
+  ) -> Result<String, MadeleineError> {
+    let mut command: Value = serde_json::from_str(old_json)?;
+    let object = command
+      .as_object_mut()
+      .ok_or_else(|| MadeleineError::CommandMigrationError(String::from("not an object")))?;
+
+    edit(object).map_err(MadeleineError::CommandMigrationError)?;
+
+    Ok(command.to_string())
+  }
+
+  struct DollarsToCents;
+
+  impl CommandMigration for DollarsToCents {
+    fn version(&self) -> u32 {
+      2
+    }
+
+    fn migrate(&self, old_json: &str, _old_version: u32) -> Result<String, MadeleineError> {
+      edit_object(old_json, |object| {
+        let dollars = object
+          .remove("dollars")
+          .and_then(|dollars| dollars.as_u64())
+          .ok_or_else(|| String::from("no dollars"))?;
+        object.insert(String::from("cents"), json!(dollars * 100));
+
+        Ok(())
+      })
+    }
+  }
+
+  struct AddMemo;
+
+  impl CommandMigration for AddMemo {
+    fn version(&self) -> u32 {
+      3
+    }
+
+    fn migrate(&self, old_json: &str, old_version: u32) -> Result<String, MadeleineError> {
+      edit_object(old_json, |object| {
+        object.insert(
+          String::from("memo"),
+          json!(format!("migrated from version {}", old_version)),
+        );
+
+        Ok(())
+      })
+    }
+  }
+
+  #[test]
+  fn test_commands_are_migrated_through_the_chain_from_the_version_they_were_logged_at() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let store_path = temp_dir.path().join("test_store");
+
+    {
+      let madeleine =
+        Madeleine::new(&store_path, || 0_u64).expect("unable to instantiate madeleine in test");
+
+      madeleine
+        .execute_command(DepositV1 { dollars: 2 })
+        .expect("unable to execute command in test");
+    }
+
+    {
+      let madeleine = Madeleine::builder(&store_path)
+        .command_migration(Box::new(DollarsToCents))
+        .resume_replaying::<DepositV2, _>(|| 0)
+        .expect("unable to resume madeleine in test");
+
+      assert_eq!(madeleine.tap(|state| state), 200);
+
+      madeleine
+        .execute_command(DepositV2 { cents: 35 })
+        .expect("unable to execute command in test");
+    }
+
+    let madeleine = Madeleine::builder(&store_path)
+      .command_migration(Box::new(AddMemo))
+      .command_migration(Box::new(DollarsToCents))
+      .resume_replaying::<Deposit, _>(|| 0)
+      .expect("unable to resume madeleine in test");
+
+    madeleine
+      .execute_command(Deposit {
+        cents: 5,
+        memo: String::from("bamboo"),
+      })
+      .expect("unable to execute command in test");
+
+    let history: Vec<HistoryEntry<Deposit>> = madeleine
+      .iter_history()
+      .expect("unable to iterate over history in test")
+      .collect::<Result<_, _>>()
+      .expect("unable to read history in test");
+
+    assert_eq!(
+      history
+        .into_iter()
+        .map(|entry| entry.command)
+        .collect::<Vec<_>>(),
+      vec![
+        Deposit {
+          cents: 200,
+          memo: String::from("migrated from version 2"),
+        },
+        Deposit {
+          cents: 35,
+          memo: String::from("migrated from version 2"),
+        },
+        Deposit {
+          cents: 5,
+          memo: String::from("bamboo"),
+        },
+      ]
+    );
+    assert_eq!(madeleine.tap(|state| state), 240);
+  }
+
+  #[test]
+  fn test_migration_chains_reject_duplicate_and_initial_versions() {
+    let madeleine =
+      Madeleine::new_in_memory(|| 0_u64).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .add_command_migration(Box::new(DollarsToCents))
+      .expect("unable to add migration in test");
+
+    assert!(matches!(
+      madeleine.add_command_migration(Box::new(DollarsToCents)),
+      Err(MadeleineError::CommandMigrationError(_))
+    ));
+
+    struct ToInitial;
+
+    impl CommandMigration for ToInitial {
+      fn version(&self) -> u32 {
+        INITIAL_COMMAND_VERSION
+      }
+
+      fn migrate(&self, old_json: &str, _old_version: u32) -> Result<String, MadeleineError> {
+        Ok(old_json.to_string())
+      }
+    }
+
+    assert!(matches!(
+      madeleine.add_command_migration(Box::new(ToInitial)),
+      Err(MadeleineError::CommandMigrationError(_))
+    ));
+  }
+}
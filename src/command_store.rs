@@ -0,0 +1,1285 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use commitlog::message::{MessageBuf, MessageSet};
+use commitlog::{AppendError, CommitLog, LogOptions, ReadLimit};
+use serde::{Deserialize, Serialize};
+
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+
+/// Name of the file holding a `FileCommandLog`'s records. Its presence marks a log directory as using one.
+pub(crate) const FILE_LOG_NAME: &str = "commands.log";
+
+/// Name of the directory holding a `SledCommandLog`'s database. Its presence marks a log directory as using one.
+pub(crate) const SLED_LOG_DIR_NAME: &str = "sled";
+
+/// Bytes framing each record of a `FileCommandLog`: the entry's length and the record's CRC-32, both little-endian u32s,
+/// a byte saying whether the record has a sequence number, and the sequence number as a little-endian u64.
+const FRAME_HEADER_BYTES: usize = 17;
+
+/// How long to keep retrying a sled database whose lock is still held by the background threads
+/// of a `SledCommandLog` which was just dropped.
+#[cfg(feature = "sled")]
+const SLED_LOCK_WAIT: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How a store's command log is kept on disk. It's chosen when the store is created
+/// and recorded in its metadata, so asking for a different backend later fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogBackend {
+  /// Segmented files managed by the [`commitlog`](https://crates.io/crates/commitlog) crate.
+  #[default]
+  CommitLog,
+  /// A single append-only file of length-prefixed records, each with a CRC, synced to disk on every append
+  /// by default. Simpler, and more durable than the default, at the cost of an `fsync` per command or batch.
+  File,
+  /// A [sled](https://crates.io/crates/sled) database, written in pure Rust, with each record keyed by its offset
+  /// so that replay follows the order of appends. Needs the `sled` feature.
+  Sled,
+  /// A `CommandStore` supplied by the application with `MadeleineBuilder::command_store`.
+  /// The store has to be supplied again whenever it's opened, since it can't be found from the store directory,
+  /// and operations which rewrite the log into a fresh directory, such as compaction, fail
+  /// with `MadeleineError::MissingCapability`.
+  Custom,
+}
+
+/// When appended commands are synced to disk, trading durability for throughput.
+/// Each `LogBackend` has its own default, and it can be changed with `Madeleine::set_sync_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMode {
+  /// Sync each command or batch to disk before it's acknowledged, so that it survives a power loss.
+  /// The default for `LogBackend::File`.
+  Full,
+  /// Hand appended commands to the operating system, syncing them to disk only when the log is flushed,
+  /// e.g. by a snapshot, a compaction or `Madeleine::close`. Commands survive the process crashing,
+  /// but those since the last flush can be lost with the machine. The default for `LogBackend::CommitLog`
+  /// and `LogBackend::Sled`, though sled also syncs on its own every half second.
+  Normal,
+}
+
+impl LogBackend {
+  /// The backend of the log in a directory, or the default if it holds no log yet.
+  pub(crate) fn of_dir(log_dir_path: &Path) -> Self {
+    if log_dir_path.join(FILE_LOG_NAME).is_file() {
+      Self::File
+    } else if log_dir_path.join(SLED_LOG_DIR_NAME).is_dir() {
+      Self::Sled
+    } else {
+      Self::CommitLog
+    }
+  }
+
+  /// When appends are synced to disk unless configured otherwise.
+  pub fn default_sync_mode(self) -> SyncMode {
+    match self {
+      Self::CommitLog | Self::Sled => SyncMode::Normal,
+      Self::File | Self::Custom => SyncMode::Full,
+    }
+  }
+
+  /// Fail with `MadeleineError::MissingCapability` if the backend's feature isn't enabled.
+  pub(crate) fn check_available(self) -> Result<(), MadeleineError> {
+    match self {
+      Self::CommitLog | Self::File | Self::Custom => Ok(()),
+      #[cfg(feature = "sled")]
+      Self::Sled => Ok(()),
+      #[cfg(not(feature = "sled"))]
+      Self::Sled => Err(missing_sled()),
+    }
+  }
+
+  /// Open the log in a directory, creating it if it's missing, e.g. to wrap it in a `CommandStore` of one's own.
+  /// Fails with `MadeleineError::MissingCapability` for `LogBackend::Custom`, whose store only the application has.
+  pub fn open(self, log_dir_path: &Path) -> Result<Box<dyn CommandStore>, MadeleineError> {
+    Ok(match self {
+      Self::CommitLog => Box::new(CommitLogStore::open(log_dir_path)?),
+      Self::File => Box::new(FileCommandLog::open(log_dir_path)?),
+      #[cfg(feature = "sled")]
+      Self::Sled => Box::new(SledCommandLog::open(log_dir_path)?),
+      #[cfg(not(feature = "sled"))]
+      Self::Sled => return Err(missing_sled()),
+      Self::Custom => {
+        return Err(MadeleineError::MissingCapability(String::from(
+          "the log is kept by a custom command store, which has to be supplied with MadeleineBuilder::command_store",
+        )))
+      }
+    })
+  }
+}
+
+#[cfg(not(feature = "sled"))]
+fn missing_sled() -> MadeleineError {
+  MadeleineError::MissingCapability(String::from("the sled log backend needs the sled feature"))
+}
+
+/// A record read back from a `CommandStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredRecord {
+  /// Position of the record in the log, counting from zero.
+  pub offset: u64,
+  /// The global sequence number it was stamped with, if any.
+  pub sequence: Option<u64>,
+  /// The entry, as appended.
+  pub entry: Vec<u8>,
+}
+
+/// Append-only storage for the entries of a command log, each optionally stamped with a global sequence number.
+/// Entries are opaque bytes, numbered from zero in the order they were appended.
+///
+/// Each `LogBackend` is one, and implementing it keeps commands anywhere else, or wraps a built-in store
+/// opened with `LogBackend::open`, e.g. to inject failures. Give it to `MadeleineBuilder::command_store`.
+///
+/// ```
+/// # use madeleine::testing::{scratch_store, Add};
+/// use madeleine::command_store::{CommandStore, StoredRecord};
+/// use madeleine::{Madeleine, MadeleineError};
+///
+/// #[derive(Default)]
+/// struct VecStore(Vec<Vec<u8>>);
+///
+/// impl CommandStore for VecStore {
+///   fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+///     self.0.push(entry.to_vec());
+///     Ok(self.len() - 1)
+///   }
+///
+///   fn read(&self, offset: u64, _max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+///     Ok((offset..self.len())
+///       .map(|offset| StoredRecord {
+///         offset,
+///         sequence: None,
+///         entry: self.0[offset as usize].clone(),
+///       })
+///       .collect())
+///   }
+///
+///   fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+///     self.0.truncate(keep as usize);
+///     Ok(())
+///   }
+///
+///   fn len(&self) -> u64 {
+///     self.0.len() as u64
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::builder(&store)
+///   .command_store(VecStore::default())
+///   .build(|| 0)?;
+///
+/// madeleine.execute_command(Add(2))?;
+///
+/// assert_eq!(madeleine.len(), 1);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait CommandStore: Send + Sync {
+  /// Append an entry, returning its offset.
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError>;
+
+  /// Append records, each with the sequence number it's stamped with if any, returning the offset of the first.
+  /// Either every record is appended or none is. By default they're appended one at a time, truncating those
+  /// already appended if one fails, and sequence numbers fail with `MadeleineError::MissingCapability`;
+  /// the built-in stores append them with a single write.
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    if records.iter().any(|(_entry, sequence)| sequence.is_some()) {
+      return Err(MadeleineError::MissingCapability(String::from(
+        "this command store doesn't keep sequence numbers",
+      )));
+    }
+
+    let first = self.len();
+
+    for (entry, _sequence) in records {
+      if let Err(error) = self.append(entry) {
+        if self.len() > first {
+          self.truncate(first)?;
+        }
+
+        return Err(error);
+      }
+    }
+
+    Ok(first)
+  }
+
+  /// Read records in order from `offset`, stopping once about `max_bytes` were read, but always reading
+  /// at least one if there is any. Returns no records once `offset` is past the last.
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError>;
+
+  /// Discard every record after the first `keep`, or every record if `keep` is zero.
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError>;
+
+  /// Sync appended records to durable storage. Does nothing by default.
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    Ok(())
+  }
+
+  /// Number of records, which is also the offset of the next one appended.
+  /// It's called for every command, so it should be kept up to date by appends and truncations
+  /// rather than read from storage.
+  fn len(&self) -> u64;
+
+  /// Whether no record was appended yet.
+  fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+impl CommandStore for Box<dyn CommandStore> {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.as_mut().append(entry)
+  }
+
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    self.as_mut().append_batch(records)
+  }
+
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    self.as_ref().read(offset, max_bytes)
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    self.as_mut().truncate(keep)
+  }
+
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    self.as_mut().flush()
+  }
+
+  fn len(&self) -> u64 {
+    self.as_ref().len()
+  }
+}
+
+/// A `CommandStore` given to `MadeleineBuilder::command_store`, handed over to the first instance opened with it.
+/// Builders compare equal when they were given the same store.
+#[derive(Clone)]
+pub(crate) struct SuppliedStore(Arc<Mutex<Option<Box<dyn CommandStore>>>>);
+
+impl SuppliedStore {
+  pub fn new(store: impl CommandStore + 'static) -> Self {
+    Self(Arc::new(Mutex::new(Some(Box::new(store)))))
+  }
+
+  /// Take the store, failing with `MadeleineError::ConfigurationError` if an instance already took it.
+  pub fn take(&self) -> Result<Box<dyn CommandStore>, MadeleineError> {
+    lock_recovering(&self.0).take().ok_or_else(|| {
+      MadeleineError::ConfigurationError(String::from(
+        "the command store was already used to open a store",
+      ))
+    })
+  }
+}
+
+impl fmt::Debug for SuppliedStore {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("SuppliedStore")
+  }
+}
+
+impl PartialEq for SuppliedStore {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for SuppliedStore {}
+
+/// A wrapper given to `MadeleineBuilder::wrap_command_store`, which the log's store is passed through
+/// whenever it's opened. Builders compare equal when they were given the same wrapper.
+#[derive(Clone)]
+pub(crate) struct StoreWrapper(Arc<WrapStore>);
+
+type WrapStore = dyn Fn(Box<dyn CommandStore>) -> Box<dyn CommandStore> + Send + Sync;
+
+impl StoreWrapper {
+  pub fn new<F>(wrapper: F) -> Self
+  where
+    F: Fn(Box<dyn CommandStore>) -> Box<dyn CommandStore> + Send + Sync + 'static,
+  {
+    Self(Arc::new(wrapper))
+  }
+
+  pub fn wrap(&self, store: Box<dyn CommandStore>) -> Box<dyn CommandStore> {
+    (self.0)(store)
+  }
+}
+
+impl fmt::Debug for StoreWrapper {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("StoreWrapper")
+  }
+}
+
+impl PartialEq for StoreWrapper {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+
+impl Eq for StoreWrapper {}
+
+/// The default backend, see `LogBackend::CommitLog`. Sequence numbers are kept in each message's metadata.
+pub(crate) struct CommitLogStore {
+  log: CommitLog,
+  log_dir_path: PathBuf,
+  /// Reused by every append, so that appending doesn't allocate once it has grown to fit.
+  buffer: MessageBuf,
+}
+
+impl CommitLogStore {
+  fn open(log_dir_path: &Path) -> Result<Self, MadeleineError> {
+    Ok(Self {
+      log: CommitLog::new(LogOptions::new(log_dir_path))?,
+      log_dir_path: log_dir_path.to_path_buf(),
+      buffer: MessageBuf::default(),
+    })
+  }
+
+  /// Path of the segment being appended to, the one named for the greatest base offset.
+  fn active_segment_path(&self) -> Result<Option<PathBuf>, MadeleineError> {
+    let mut active = None;
+
+    for dir_entry in fs::read_dir(&self.log_dir_path)? {
+      let path = dir_entry?.path();
+
+      if path.extension().is_some_and(|extension| extension == "log")
+        && active.as_ref().is_none_or(|active| path > *active)
+      {
+        active = Some(path);
+      }
+    }
+
+    Ok(active)
+  }
+}
+
+impl CommandStore for CommitLogStore {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.append_batch(&[(entry, None)])
+  }
+
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    self.buffer.clear();
+
+    for (entry, sequence) in records {
+      match sequence {
+        Some(sequence) => self
+          .buffer
+          .push_with_metadata(sequence.to_le_bytes(), entry),
+        None => self.buffer.push(entry),
+      }
+      .map_err(|_error| MadeleineError::CommitLogAppendError(AppendError::MessageSizeExceeded))?;
+    }
+
+    Ok(self.log.append(&mut self.buffer)?.first())
+  }
+
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    let messages = self.log.read(offset, ReadLimit::max_bytes(max_bytes))?;
+
+    Ok(
+      messages
+        .iter()
+        .map(|message| StoredRecord {
+          offset: message.offset(),
+          sequence: message.metadata().try_into().ok().map(u64::from_le_bytes),
+          entry: message.payload().to_vec(),
+        })
+        .collect(),
+    )
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    match keep.checked_sub(1) {
+      Some(last_kept) => self.log.truncate(last_kept)?,
+      // The commit log can only truncate after an offset, so empty it by starting it afresh.
+      None => {
+        for dir_entry in fs::read_dir(&self.log_dir_path)? {
+          fs::remove_file(dir_entry?.path())?;
+        }
+
+        self.log = CommitLog::new(LogOptions::new(&self.log_dir_path))?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    self.log.flush()?;
+
+    // The commit log syncs its index, but only writes its segments, so sync the one appended to as well.
+    if let Some(segment_path) = self.active_segment_path()? {
+      OpenOptions::new()
+        .write(true)
+        .open(segment_path)?
+        .sync_data()?;
+    }
+
+    Ok(())
+  }
+
+  fn len(&self) -> u64 {
+    self.log.next_offset()
+  }
+}
+
+/// The `LogBackend::File` backend: one file of framed records, each checked against its CRC when read.
+///
+/// A crash during an append can leave the last records torn. On open, a final record which runs past the end
+/// of the file or fails its CRC is cut off, while a record failing its CRC anywhere else fails the open
+/// with `MadeleineError::CommandLogCorrupted`.
+pub(crate) struct FileCommandLog {
+  file: Mutex<File>,
+  /// Position in the file of each record, by offset.
+  positions: Vec<u64>,
+  /// End of the last record, where the next is written.
+  end: u64,
+  /// Reused by every append, so that appending doesn't allocate once it has grown to fit.
+  frames: Vec<u8>,
+}
+
+impl FileCommandLog {
+  fn open(log_dir_path: &Path) -> Result<Self, MadeleineError> {
+    fs::create_dir_all(log_dir_path)?;
+
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(log_dir_path.join(FILE_LOG_NAME))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(&file);
+    let mut positions = Vec::new();
+    let mut end = 0;
+
+    while end < file_len {
+      match read_frame(&mut reader)? {
+        Some(Frame { intact: true, .. }) => {
+          positions.push(end);
+          end = reader.stream_position()?;
+        }
+        Some(Frame { intact: false, .. }) if reader.stream_position()? < file_len => {
+          return Err(MadeleineError::CommandLogCorrupted(format!(
+            "record {} at byte {} fails its CRC",
+            positions.len(),
+            end
+          )));
+        }
+        // Torn by a crash during the last append.
+        Some(Frame { intact: false, .. }) | None => break,
+      }
+    }
+
+    drop(reader);
+
+    if end < file_len {
+      file.set_len(end)?;
+      file.sync_all()?;
+    }
+
+    Ok(Self {
+      file: Mutex::new(file),
+      positions,
+      end,
+      frames: Vec::new(),
+    })
+  }
+}
+
+impl CommandStore for FileCommandLog {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.append_batch(&[(entry, None)])
+  }
+
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    let first = self.len();
+    let mut positions = Vec::with_capacity(records.len());
+
+    self.frames.clear();
+
+    for (entry, sequence) in records {
+      let entry_len = u32::try_from(entry.len())
+        .map_err(|_error| MadeleineError::CommitLogAppendError(AppendError::MessageSizeExceeded))?;
+
+      positions.push(self.end + self.frames.len() as u64);
+
+      let has_sequence = [u8::from(sequence.is_some())];
+      let sequence = sequence.unwrap_or(0).to_le_bytes();
+      let mut crc = crc32fast::Hasher::new();
+      crc.update(&has_sequence);
+      crc.update(&sequence);
+      crc.update(entry);
+
+      self.frames.extend_from_slice(&entry_len.to_le_bytes());
+      self.frames.extend_from_slice(&crc.finalize().to_le_bytes());
+      self.frames.extend_from_slice(&has_sequence);
+      self.frames.extend_from_slice(&sequence);
+      self.frames.extend_from_slice(entry);
+    }
+
+    let file = self
+      .file
+      .get_mut()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    file.seek(SeekFrom::Start(self.end))?;
+    file.write_all(&self.frames)?;
+
+    self.end += self.frames.len() as u64;
+    self.positions.extend(positions);
+
+    Ok(first)
+  }
+
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    let Some(start) = usize::try_from(offset)
+      .ok()
+      .and_then(|offset| self.positions.get(offset))
+    else {
+      return Ok(Vec::new());
+    };
+
+    let mut file = lock_recovering(&self.file);
+    file.seek(SeekFrom::Start(*start))?;
+
+    let mut reader = BufReader::new(&*file);
+    let mut records = Vec::new();
+    let mut bytes_read = 0;
+
+    while offset + (records.len() as u64) < self.len()
+      && (records.is_empty() || bytes_read < max_bytes)
+    {
+      let record_offset = offset + records.len() as u64;
+
+      match read_frame(&mut reader)? {
+        Some(Frame {
+          sequence,
+          entry,
+          intact: true,
+        }) => {
+          bytes_read += FRAME_HEADER_BYTES + entry.len();
+          records.push(StoredRecord {
+            offset: record_offset,
+            sequence,
+            entry,
+          });
+        }
+        _ => {
+          return Err(MadeleineError::CommandLogCorrupted(format!(
+            "record {} fails its CRC",
+            record_offset
+          )))
+        }
+      }
+    }
+
+    Ok(records)
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    let keep = usize::try_from(keep).unwrap_or(usize::MAX);
+
+    if let Some(end) = self.positions.get(keep).copied() {
+      let file = self
+        .file
+        .get_mut()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+      file.set_len(end)?;
+      file.sync_all()?;
+
+      self.positions.truncate(keep);
+      self.end = end;
+    }
+
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    self
+      .file
+      .get_mut()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .sync_data()?;
+
+    Ok(())
+  }
+
+  fn len(&self) -> u64 {
+    self.positions.len() as u64
+  }
+}
+
+/// The `LogBackend::Sled` backend: a sled tree of records keyed by their offsets as big-endian u64s, so that
+/// they sort in the order they were appended. Each value is a byte saying whether the record has a sequence number,
+/// the sequence number as a little-endian u64, and the entry.
+#[cfg(feature = "sled")]
+pub(crate) struct SledCommandLog {
+  db: sled::Db,
+  /// Number of records, one more than the greatest key.
+  len: u64,
+}
+
+#[cfg(feature = "sled")]
+impl SledCommandLog {
+  fn open(log_dir_path: &Path) -> Result<Self, MadeleineError> {
+    fs::create_dir_all(log_dir_path)?;
+
+    let db_path = log_dir_path.join(SLED_LOG_DIR_NAME);
+    let deadline = std::time::Instant::now() + SLED_LOCK_WAIT;
+
+    // sled's threadpool can hold on to a dropped database for a moment, and with it the lock on its files.
+    let db = loop {
+      match sled::open(&db_path) {
+        Err(sled::Error::Io(error))
+          if error.to_string().contains("could not acquire lock")
+            && std::time::Instant::now() < deadline =>
+        {
+          std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        result => break result?,
+      }
+    };
+    let len = match db.last()? {
+      Some((key, _value)) => sled_offset(&key)? + 1,
+      None => 0,
+    };
+
+    Ok(Self { db, len })
+  }
+}
+
+#[cfg(feature = "sled")]
+impl CommandStore for SledCommandLog {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.append_batch(&[(entry, None)])
+  }
+
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    let first = self.len;
+    let mut batch = sled::Batch::default();
+
+    for (offset, (entry, sequence)) in (first..).zip(records) {
+      let mut value = Vec::with_capacity(9 + entry.len());
+      value.push(u8::from(sequence.is_some()));
+      value.extend_from_slice(&sequence.unwrap_or(0).to_le_bytes());
+      value.extend_from_slice(entry);
+
+      batch.insert(&offset.to_be_bytes(), value);
+    }
+
+    self.db.apply_batch(batch)?;
+    self.len += records.len() as u64;
+
+    Ok(first)
+  }
+
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    let mut records = Vec::new();
+    let mut bytes_read = 0;
+
+    for record in self.db.range(offset.to_be_bytes()..) {
+      if !records.is_empty() && bytes_read >= max_bytes {
+        break;
+      }
+
+      let (key, value) = record?;
+      let offset = sled_offset(&key)?;
+
+      if value.len() < 9 {
+        return Err(MadeleineError::CommandLogCorrupted(format!(
+          "record {} is too short",
+          offset
+        )));
+      }
+
+      let (has_sequence, rest) = value.split_at(1);
+      let (sequence, entry) = rest.split_at(8);
+
+      bytes_read += value.len();
+      records.push(StoredRecord {
+        offset,
+        sequence: (has_sequence[0] == 1)
+          .then(|| u64::from_le_bytes(sequence.try_into().unwrap_or_default())),
+        entry: entry.to_vec(),
+      });
+    }
+
+    Ok(records)
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    let mut batch = sled::Batch::default();
+
+    for key in self.db.range(keep.to_be_bytes()..).keys() {
+      batch.remove(key?);
+    }
+
+    self.db.apply_batch(batch)?;
+    self.db.flush()?;
+    self.len = self.len.min(keep);
+
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    self.db.flush()?;
+
+    Ok(())
+  }
+
+  fn len(&self) -> u64 {
+    self.len
+  }
+}
+
+/// The offset a `SledCommandLog` key stands for.
+#[cfg(feature = "sled")]
+fn sled_offset(key: &[u8]) -> Result<u64, MadeleineError> {
+  key
+    .try_into()
+    .map(u64::from_be_bytes)
+    .map_err(|_error| MadeleineError::CommandLogCorrupted(format!("{:?} isn't an offset", key)))
+}
+
+/// A record of a `FileCommandLog` as read from its file.
+struct Frame {
+  sequence: Option<u64>,
+  entry: Vec<u8>,
+  /// Whether the record passes its CRC.
+  intact: bool,
+}
+
+/// Read the next frame, or `None` if the file ends before the frame does.
+fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>, MadeleineError> {
+  let mut header = [0; FRAME_HEADER_BYTES];
+
+  if !read_all(reader, &mut header)? {
+    return Ok(None);
+  }
+
+  let (entry_len, rest) = header.split_at(4);
+  let (crc, checked_header) = rest.split_at(4);
+  let entry_len = u32::from_le_bytes(entry_len.try_into().unwrap_or_default());
+  let mut entry = vec![0; entry_len as usize];
+
+  if !read_all(reader, &mut entry)? {
+    return Ok(None);
+  }
+
+  let mut hasher = crc32fast::Hasher::new();
+  hasher.update(checked_header);
+  hasher.update(&entry);
+  let intact = hasher.finalize() == u32::from_le_bytes(crc.try_into().unwrap_or_default());
+
+  let (has_sequence, sequence) = checked_header.split_at(1);
+  let sequence =
+    (has_sequence[0] == 1).then(|| u64::from_le_bytes(sequence.try_into().unwrap_or_default()));
+
+  Ok(Some(Frame {
+    sequence,
+    entry,
+    intact,
+  }))
+}
+
+/// Fill `buffer` from `reader`, returning `false` if it ends first.
+fn read_all(reader: &mut impl Read, buffer: &mut [u8]) -> Result<bool, MadeleineError> {
+  match reader.read_exact(buffer) {
+    Ok(()) => Ok(true),
+    Err(error) if error.kind() == ErrorKind::UnexpectedEof => Ok(false),
+    Err(error) => Err(error.into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  const BACKENDS: &[LogBackend] = &[
+    LogBackend::CommitLog,
+    LogBackend::File,
+    #[cfg(feature = "sled")]
+    LogBackend::Sled,
+  ];
+
+  fn entries(store: &dyn CommandStore) -> Vec<(u64, Option<u64>, Vec<u8>)> {
+    store
+      .read(0, 1_000_000)
+      .expect("unable to read log in test")
+      .into_iter()
+      .map(|record| (record.offset, record.sequence, record.entry))
+      .collect()
+  }
+
+  #[test]
+  fn test_every_backend_appends_reads_and_truncates() {
+    for backend in BACKENDS.iter().copied() {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+      let log_dir_path = temp_dir.path().join("commands");
+
+      let mut store = backend
+        .open(&log_dir_path)
+        .expect("unable to open log in test");
+
+      assert_eq!(store.len(), 0);
+      assert_eq!(store.append(b"one").expect("unable to append in test"), 0);
+      assert_eq!(
+        store
+          .append_batch(&[(b"two".as_slice(), Some(7)), (b"three".as_slice(), None)])
+          .expect("unable to append in test"),
+        1
+      );
+      store.flush().expect("unable to flush log in test");
+
+      assert_eq!(LogBackend::of_dir(&log_dir_path), backend);
+      assert_eq!(store.len(), 3);
+      assert_eq!(
+        store
+          .read(1, 1_000_000)
+          .expect("unable to read log in test")
+          .iter()
+          .map(|record| record.offset)
+          .collect::<Vec<_>>(),
+        vec![1, 2]
+      );
+      assert_eq!(
+        store
+          .read(3, 1_000_000)
+          .expect("unable to read log in test"),
+        Vec::new()
+      );
+
+      drop(store);
+
+      let mut reopened = backend
+        .open(&log_dir_path)
+        .expect("unable to reopen log in test");
+
+      assert_eq!(
+        entries(reopened.as_ref()),
+        vec![
+          (0, None, b"one".to_vec()),
+          (1, Some(7), b"two".to_vec()),
+          (2, None, b"three".to_vec()),
+        ]
+      );
+
+      reopened
+        .truncate(1)
+        .expect("unable to truncate log in test");
+      reopened.append(b"four").expect("unable to append in test");
+      reopened.flush().expect("unable to flush log in test");
+
+      assert_eq!(
+        entries(reopened.as_ref()),
+        vec![(0, None, b"one".to_vec()), (1, None, b"four".to_vec())]
+      );
+    }
+  }
+
+  #[test]
+  fn test_every_backend_counts_appends_without_reading_the_log() {
+    for backend in BACKENDS.iter().copied() {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+      let log_dir_path = temp_dir.path().join("commands");
+
+      let mut store = backend
+        .open(&log_dir_path)
+        .expect("unable to open log in test");
+
+      for batch in 0..100_u64 {
+        let entries: Vec<Vec<u8>> = (0..99_u64)
+          .map(|index| (batch * 100 + index).to_le_bytes().to_vec())
+          .collect();
+        let records: Vec<(&[u8], Option<u64>)> = entries
+          .iter()
+          .map(|entry| (entry.as_slice(), None))
+          .collect();
+
+        store
+          .append_batch(&records)
+          .expect("unable to append in test");
+        store
+          .append_batch(&[(b"single".as_slice(), Some(batch))])
+          .expect("unable to append in test");
+      }
+
+      assert_eq!(store.len(), 10_000);
+
+      store.flush().expect("unable to flush log in test");
+      drop(store);
+
+      let reopened = backend
+        .open(&log_dir_path)
+        .expect("unable to reopen log in test");
+
+      assert_eq!(reopened.len(), 10_000);
+    }
+  }
+
+  #[test]
+  fn test_every_backend_truncates_to_empty() {
+    for backend in BACKENDS.iter().copied() {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+      let log_dir_path = temp_dir.path().join("commands");
+
+      let mut store = backend
+        .open(&log_dir_path)
+        .expect("unable to open log in test");
+
+      store.append(b"one").expect("unable to append in test");
+      store.append(b"two").expect("unable to append in test");
+      store.truncate(0).expect("unable to truncate log in test");
+
+      assert!(store.is_empty());
+
+      store.append(b"three").expect("unable to append in test");
+      store.flush().expect("unable to flush log in test");
+      drop(store);
+
+      let reopened = backend
+        .open(&log_dir_path)
+        .expect("unable to reopen log in test");
+
+      assert_eq!(
+        entries(reopened.as_ref()),
+        vec![(0, None, b"three".to_vec())]
+      );
+    }
+  }
+
+  /// Keeps records in memory, failing the append of the record at `fail_at`.
+  struct FlakyStore {
+    records: Vec<Vec<u8>>,
+    fail_at: u64,
+  }
+
+  impl CommandStore for FlakyStore {
+    fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+      if self.len() == self.fail_at {
+        return Err(MadeleineError::FileIOError(std::io::Error::other(
+          "disk full",
+        )));
+      }
+
+      self.records.push(entry.to_vec());
+
+      Ok(self.len() - 1)
+    }
+
+    fn read(&self, offset: u64, _max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+      Ok(
+        (offset..self.len())
+          .map(|offset| StoredRecord {
+            offset,
+            sequence: None,
+            entry: self.records[offset as usize].clone(),
+          })
+          .collect(),
+      )
+    }
+
+    fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+      self.records.truncate(keep as usize);
+
+      Ok(())
+    }
+
+    fn len(&self) -> u64 {
+      self.records.len() as u64
+    }
+  }
+
+  #[test]
+  fn test_default_batch_append_rolls_back_into_empty_store() {
+    let mut store = FlakyStore {
+      records: Vec::new(),
+      fail_at: 1,
+    };
+
+    assert!(store
+      .append_batch(&[(b"one".as_slice(), None), (b"two".as_slice(), None)])
+      .is_err());
+    assert!(store.is_empty());
+
+    store.fail_at = 3;
+    store.append(b"one").expect("unable to append in test");
+
+    assert!(store
+      .append_batch(&[
+        (b"two".as_slice(), None),
+        (b"three".as_slice(), None),
+        (b"four".as_slice(), None)
+      ])
+      .is_err());
+    assert_eq!(store.len(), 1);
+  }
+
+  #[test]
+  fn test_rejected_append_leaves_count_unchanged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let mut store = LogBackend::CommitLog
+      .open(&temp_dir.path().join("commands"))
+      .expect("unable to open log in test");
+    let oversized = vec![0; 2_000_000];
+
+    store.append(b"one").expect("unable to append in test");
+
+    assert!(store
+      .append_batch(&[(b"two".as_slice(), None), (oversized.as_slice(), None)])
+      .is_err());
+    assert_eq!(store.len(), 1);
+
+    store.append(b"three").expect("unable to append in test");
+
+    assert_eq!(
+      entries(store.as_ref()),
+      vec![(0, None, b"one".to_vec()), (1, None, b"three".to_vec())]
+    );
+  }
+
+  #[test]
+  fn test_file_log_cuts_off_torn_tail_and_rejects_corruption() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let log_dir_path = temp_dir.path().join("commands");
+    let log_file_path = log_dir_path.join(FILE_LOG_NAME);
+
+    let mut store = LogBackend::File
+      .open(&log_dir_path)
+      .expect("unable to open log in test");
+    store
+      .append_batch(&[(b"one".as_slice(), None), (b"two".as_slice(), None)])
+      .expect("unable to append in test");
+    drop(store);
+
+    let whole = fs::read(&log_file_path).expect("unable to read log file in test");
+
+    // A crash part way through appending a third record.
+    let mut torn = whole.clone();
+    torn.extend_from_slice(&whole[..FRAME_HEADER_BYTES + 1]);
+    fs::write(&log_file_path, &torn).expect("unable to write log file in test");
+
+    let store = LogBackend::File
+      .open(&log_dir_path)
+      .expect("unable to reopen torn log in test");
+
+    assert_eq!(store.len(), 2);
+    assert_eq!(
+      fs::read(&log_file_path).expect("unable to read log file in test"),
+      whole
+    );
+
+    drop(store);
+
+    // A flipped bit in the first record, which a crash can't explain.
+    let mut corrupted = whole.clone();
+    corrupted[FRAME_HEADER_BYTES] ^= 1;
+    fs::write(&log_file_path, &corrupted).expect("unable to write log file in test");
+
+    assert!(matches!(
+      LogBackend::File.open(&log_dir_path).err(),
+      Some(MadeleineError::CommandLogCorrupted(_))
+    ));
+
+    // The same bit flipped in the last record is taken to be torn.
+    let mut torn = whole.clone();
+    let last = torn.len() - 1;
+    torn[last] ^= 1;
+    fs::write(&log_file_path, &torn).expect("unable to write log file in test");
+
+    let store = LogBackend::File
+      .open(&log_dir_path)
+      .expect("unable to reopen torn log in test");
+
+    assert_eq!(entries(store.as_ref()), vec![(0, None, b"one".to_vec())]);
+  }
+
+  #[test]
+  fn test_sync_mode_defaults_to_the_backends() {
+    for (backend, default_sync_mode, other_sync_mode) in [
+      (LogBackend::CommitLog, SyncMode::Normal, SyncMode::Full),
+      (LogBackend::File, SyncMode::Full, SyncMode::Normal),
+      #[cfg(feature = "sled")]
+      (LogBackend::Sled, SyncMode::Normal, SyncMode::Full),
+    ] {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+      let store_path = temp_dir.path().join("test_store");
+
+      let madeleine = Madeleine::builder(store_path.clone())
+        .log_backend(backend)
+        .build(|| 0)
+        .expect("unable to instantiate madeleine in test");
+
+      assert_eq!(madeleine.sync_mode(), default_sync_mode);
+
+      madeleine
+        .execute_command(Add(2))
+        .expect("unable to execute command in test");
+      madeleine.set_sync_mode(other_sync_mode);
+      madeleine
+        .execute_command(Add(3))
+        .expect("unable to execute command in test");
+
+      assert_eq!(madeleine.sync_mode(), other_sync_mode);
+
+      madeleine
+        .close()
+        .expect("unable to close madeleine in test");
+
+      let resumed = Madeleine::resume_replaying::<Add, _>(store_path, || 0)
+        .expect("unable to resume madeleine in test");
+
+      assert_eq!(resumed.sync_mode(), default_sync_mode);
+      assert_eq!(resumed.tap(|state| state), 5);
+    }
+  }
+
+  #[test]
+  fn test_store_keeps_its_log_backend_through_compaction() {
+    for backend in BACKENDS
+      .iter()
+      .copied()
+      .filter(|backend| *backend != LogBackend::default())
+    {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+      let store_path = temp_dir.path().join("test_store");
+
+      let madeleine = Madeleine::builder(store_path.clone())
+        .log_backend(backend)
+        .build(|| 0)
+        .expect("unable to instantiate madeleine in test");
+
+      madeleine
+        .execute_command(Add(2))
+        .expect("unable to execute command in test");
+      let up_to = madeleine.head_id().expect("unable to get head in test");
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+      madeleine
+        .execute_command(Add(3))
+        .expect("unable to execute command in test");
+
+      assert_eq!(
+        madeleine
+          .compact_log(up_to)
+          .expect("unable to compact log in test"),
+        1
+      );
+      assert_eq!(madeleine.log_backend(), backend);
+
+      drop(madeleine);
+
+      let resumed = Madeleine::resume_replaying::<Add, _>(store_path.clone(), || 0)
+        .expect("unable to resume madeleine in test");
+
+      assert_eq!(resumed.log_backend(), backend);
+      assert_eq!(resumed.tap(|state| state), 5);
+      assert_eq!(resumed.len(), 1);
+
+      drop(resumed);
+
+      assert!(matches!(
+        Madeleine::builder(store_path)
+          .log_backend(LogBackend::CommitLog)
+          .build(|| 0)
+          .err(),
+        Some(MadeleineError::LogBackendMismatch(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn test_custom_store_keeps_the_log_and_must_be_supplied_again() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+    let log_dir_path = temp_dir.path().join("custom_log");
+    let open_store = || {
+      LogBackend::File
+        .open(&log_dir_path)
+        .expect("unable to open log in test")
+    };
+
+    let madeleine = Madeleine::builder(store_path.clone())
+      .command_store(open_store())
+      .build(|| 0)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [2, 3] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    assert!(matches!(
+      madeleine.compact(),
+      Err(MadeleineError::MissingCapability(_))
+    ));
+
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    assert_eq!(entries(open_store().as_ref()).len(), 2);
+    assert!(matches!(
+      Madeleine::resume_replaying::<Add, _>(store_path.clone(), || 0).err(),
+      Some(MadeleineError::MissingCapability(_))
+    ));
+    assert!(matches!(
+      Madeleine::builder(store_path.clone())
+        .log_backend(LogBackend::File)
+        .resume_replaying::<Add, _>(|| 0)
+        .err(),
+      Some(MadeleineError::LogBackendMismatch(_))
+    ));
+
+    let resumed = Madeleine::builder(store_path)
+      .command_store(open_store())
+      .resume_replaying::<Add, _>(|| 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state), 5);
+    assert_eq!(resumed.len(), 2);
+  }
+
+  #[cfg(not(feature = "sled"))]
+  #[test]
+  fn test_sled_backend_needs_its_feature() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    assert!(matches!(
+      Madeleine::builder(store_path.clone())
+        .log_backend(LogBackend::Sled)
+        .build(|| 0_u64)
+        .err(),
+      Some(MadeleineError::MissingCapability(_))
+    ));
+    assert!(!store_path
+      .join("command_log")
+      .join(SLED_LOG_DIR_NAME)
+      .exists());
+  }
+}
@@ -0,0 +1,583 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::admin_log::{self, AdminOperationKind};
+use crate::command_log::CommandLog;
+use crate::hashing::HashAlgo;
+use crate::madeleine::{
+  command_log_dir_path, list_snapshot_ids, snapshot_alias_file_path, snapshot_diff_file_path,
+  snapshot_file_path, snapshot_head_file_path, snapshot_id_file_path, snapshot_label_file_path,
+};
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::StoreMetadata;
+use crate::payload_format::entry_id;
+use crate::snapshot_diff::snapshot_dependencies;
+use crate::tenant::{copy_log_without_tenant, TenantId};
+
+/// Where a fresh command log is prepared before being swapped in for the live one.
+pub(crate) const COMPACTED_LOG_DIR_NAME: &str = "command_log.compacted";
+/// Where the live command log is moved when it's swapped out, until it's deleted.
+pub(crate) const RETIRED_LOG_DIR_NAME: &str = "command_log.retired";
+
+/// Stages of a compaction, in the order they're reached. Each is journaled in the store's metadata
+/// before moving on, so that an interrupted compaction is finished, or undone, the next time the store is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompactionStage {
+  /// About to take the compaction's snapshot. Interrupted here, the compaction is undone.
+  Intent,
+  /// The snapshot holding the whole history is written. From here on, an interrupted compaction is finished.
+  SnapshotDone,
+  /// An empty command log, or one without the purged tenant's commands, has replaced the one holding the history.
+  RowsDeleted,
+  /// The replaced command log is deleted, along with older snapshots if a tenant was purged.
+  Vacuumed,
+}
+
+/// A compaction in progress, as journaled in the store's metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct CompactionJournal {
+  /// The last stage reached.
+  pub stage: CompactionStage,
+  /// Id of the snapshot the compaction takes, decided before it's written so that recovery knows which file to look for.
+  pub snapshot_id: usize,
+  /// ULID of the last command in the log being compacted, or nil if it was empty.
+  pub head_id: Ulid,
+  /// The tenant being purged by `Madeleine::purge_tenant`, whose commands alone are removed from the log.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub purged_tenant: Option<TenantId>,
+  /// Number of commands the compaction removes from the log, counted once the old log is swapped out.
+  #[serde(default)]
+  pub commands_removed: u64,
+  /// ULID of the last command removed by `Madeleine::compact_log`, which keeps the commands after it.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub up_to: Option<Ulid>,
+}
+
+impl CompactionJournal {
+  /// Record in `metadata` that the compaction's commands are gone from the log.
+  fn record_rows_deleted(&self, metadata: &mut StoreMetadata) {
+    metadata.commands_compacted += self.commands_removed;
+    metadata.compacted_through = metadata
+      .compacted_through
+      .max(Some(self.up_to.unwrap_or(self.head_id)));
+  }
+}
+
+/// Outcome of `Madeleine::compact` or `Madeleine::purge_tenant`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionReport {
+  /// Id of the snapshot now holding the whole history.
+  pub snapshot_id: usize,
+  /// Number of commands removed from the log.
+  pub commands_removed: u64,
+}
+
+/// Record the compaction's progress, or its end if `journal` is `None`.
+pub(crate) fn write_journal(
+  location_dir_path: &Path,
+  hash_algo: HashAlgo,
+  journal: Option<&CompactionJournal>,
+) -> Result<(), MadeleineError> {
+  let mut metadata = StoreMetadata::load_or_create(location_dir_path, hash_algo)?;
+
+  // Count the removed commands in the same write which records that they're gone, so a crash can't count them twice.
+  if let Some(journal) = journal {
+    let recorded_stage = metadata.compaction.as_ref().map(|recorded| recorded.stage);
+
+    if journal.stage == CompactionStage::RowsDeleted
+      && recorded_stage != Some(CompactionStage::RowsDeleted)
+    {
+      journal.record_rows_deleted(&mut metadata);
+    }
+  }
+
+  metadata.compaction = journal.cloned();
+  metadata.write(location_dir_path)
+}
+
+/// Replace the live command log with a fresh one, which `fill` populates before the swap, e.g. with nothing.
+/// Safe to call again after being interrupted at any point.
+/// `between` is called between moving the live log away and moving the fresh one in.
+pub(crate) fn swap_in_log<P, F>(
+  location_dir_path: &Path,
+  fill: P,
+  between: F,
+) -> Result<(), MadeleineError>
+where
+  P: FnOnce(&Path) -> Result<(), MadeleineError>,
+  F: FnOnce() -> Result<(), MadeleineError>,
+{
+  let live = command_log_dir_path(location_dir_path);
+  let compacted = location_dir_path.join(COMPACTED_LOG_DIR_NAME);
+  let retired = location_dir_path.join(RETIRED_LOG_DIR_NAME);
+
+  if !retired.exists() {
+    if compacted.exists() {
+      fs::remove_dir_all(&compacted)?;
+    }
+
+    fs::create_dir_all(&compacted)?;
+    fill(&compacted)?;
+    fs::rename(&live, &retired)?;
+
+    between()?;
+  }
+
+  if !live.exists() {
+    fs::create_dir_all(&compacted)?;
+    fs::rename(&compacted, &live)?;
+  }
+
+  Ok(())
+}
+
+/// Copy the entries of a log which were logged after `up_to` into a fresh log, returning how many were left out.
+pub(crate) fn copy_log_after(
+  source: &CommandLog,
+  target_dir_path: &Path,
+  up_to: Ulid,
+) -> Result<u64, MadeleineError> {
+  let target = CommandLog::with_backend(target_dir_path.to_path_buf(), source.backend())?;
+  target.set_payload_codec(source.payload_codec()?)?;
+  let mut left_out = 0;
+
+  source.for_each_sequenced_entry(|_offset, sequence, entry| {
+    if entry_id(entry)? <= up_to {
+      left_out += 1;
+
+      Ok(())
+    } else {
+      target
+        .append_sequenced_entry(entry, sequence)
+        .map(|_offset| ())
+    }
+  })?;
+
+  target.flush()?;
+
+  Ok(left_out)
+}
+
+/// Delete the command log which was swapped out, if it's still there.
+pub(crate) fn remove_retired_log(location_dir_path: &Path) -> Result<(), MadeleineError> {
+  let retired = location_dir_path.join(RETIRED_LOG_DIR_NAME);
+
+  if retired.exists() {
+    fs::remove_dir_all(retired)?;
+  }
+
+  Ok(())
+}
+
+/// Delete every snapshot, snapshot alias and differential snapshot older than `snapshot_id`,
+/// except those which it or a later snapshot still needs to be read, see `snapshot_dependencies`.
+pub(crate) fn remove_snapshots_before(
+  location_dir_path: &Path,
+  snapshot_id: usize,
+) -> Result<(), MadeleineError> {
+  let (older, kept): (Vec<usize>, Vec<usize>) = list_snapshot_ids(location_dir_path)?
+    .into_iter()
+    .partition(|older| *older < snapshot_id);
+  let dependencies = snapshot_dependencies(kept, location_dir_path)?;
+
+  for older in older
+    .into_iter()
+    .filter(|older| !dependencies.contains(older))
+  {
+    for path in [
+      snapshot_file_path(older, location_dir_path.to_path_buf()),
+      snapshot_alias_file_path(older, location_dir_path.to_path_buf()),
+      snapshot_diff_file_path(older, location_dir_path.to_path_buf()),
+      snapshot_head_file_path(older, location_dir_path.to_path_buf()),
+      snapshot_label_file_path(older, location_dir_path.to_path_buf()),
+    ] {
+      if path.exists() {
+        fs::remove_file(path)?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Remove every snapshot after `snapshot_id`, e.g. because the state was rolled back to it,
+/// along with their aliases, diffs, heads and labels.
+pub(crate) fn remove_snapshots_after(
+  location_dir_path: &Path,
+  snapshot_id: usize,
+) -> Result<(), MadeleineError> {
+  for later in list_snapshot_ids(location_dir_path)?
+    .into_iter()
+    .filter(|later| *later > snapshot_id)
+  {
+    for path in [
+      snapshot_file_path(later, location_dir_path.to_path_buf()),
+      snapshot_alias_file_path(later, location_dir_path.to_path_buf()),
+      snapshot_diff_file_path(later, location_dir_path.to_path_buf()),
+      snapshot_head_file_path(later, location_dir_path.to_path_buf()),
+      snapshot_label_file_path(later, location_dir_path.to_path_buf()),
+    ] {
+      if path.exists() {
+        fs::remove_file(path)?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+/// Finish or undo a compaction which was interrupted, returning the stage it had reached, if there was one.
+/// A compaction interrupted before its snapshot was complete is undone, and any other is finished.
+pub(crate) fn recover(
+  location_dir_path: &Path,
+  metadata: &mut StoreMetadata,
+) -> Result<Option<CompactionStage>, MadeleineError> {
+  let journal = match metadata.compaction.take() {
+    Some(journal) => journal,
+    None => {
+      // Redactions swap in a complete copy of the log without journaling, so finish any they didn't.
+      if location_dir_path.join(RETIRED_LOG_DIR_NAME).exists() {
+        swap_in_log(location_dir_path, |_unused| Ok(()), || Ok(()))?;
+        remove_retired_log(location_dir_path)?;
+      }
+
+      return Ok(None);
+    }
+  };
+
+  let mut stage = journal.stage;
+  let mut undone = false;
+
+  if stage == CompactionStage::Intent {
+    if snapshot_is_complete(location_dir_path, journal.snapshot_id)? {
+      stage = CompactionStage::SnapshotDone;
+    } else {
+      undone = true;
+
+      let partial = snapshot_file_path(journal.snapshot_id, location_dir_path.to_path_buf());
+
+      if partial.exists() {
+        fs::remove_file(partial)?;
+      }
+    }
+  }
+
+  if stage == CompactionStage::SnapshotDone {
+    swap_in_log(
+      location_dir_path,
+      |compacted| match (&journal.purged_tenant, journal.up_to) {
+        (Some(tenant), _) => {
+          let live = CommandLog::new(command_log_dir_path(location_dir_path))?;
+
+          copy_log_without_tenant(&live, compacted, tenant).map(|_removed| ())
+        }
+        (None, Some(up_to)) => {
+          let live = CommandLog::new(command_log_dir_path(location_dir_path))?;
+
+          copy_log_after(&live, compacted, up_to).map(|_removed| ())
+        }
+        (None, None) => Ok(()),
+      },
+      || Ok(()),
+    )?;
+    stage = CompactionStage::RowsDeleted;
+    journal.record_rows_deleted(metadata);
+  }
+
+  if stage == CompactionStage::RowsDeleted {
+    remove_retired_log(location_dir_path)?;
+
+    if journal.purged_tenant.is_some() {
+      remove_snapshots_before(location_dir_path, journal.snapshot_id)?;
+    }
+  }
+
+  admin_log::record(
+    location_dir_path,
+    AdminOperationKind::CompactionRecovered {
+      stage: journal.stage,
+      undone,
+      purged_tenant: journal.purged_tenant.clone(),
+    },
+  )?;
+  metadata.write(location_dir_path)?;
+
+  Ok(Some(journal.stage))
+}
+
+/// Determine if a snapshot was completely written, i.e. it was recorded as the latest snapshot,
+/// which only happens once the snapshot file is written.
+fn snapshot_is_complete(
+  location_dir_path: &Path,
+  snapshot_id: usize,
+) -> Result<bool, MadeleineError> {
+  let snapshot_id_path = snapshot_id_file_path(location_dir_path.to_path_buf());
+
+  if !snapshot_id_path.is_file() {
+    return Ok(false);
+  }
+
+  let latest: usize = serde_json::from_slice(&fs::read(snapshot_id_path)?)?;
+
+  Ok(
+    latest == snapshot_id
+      && snapshot_file_path(snapshot_id, location_dir_path.to_path_buf()).is_file(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use std::sync::Arc;
+
+  use crate::testing::{Add, FailpointStore, StorageOperation};
+  use crate::{Follower, Madeleine};
+
+  /// Number of commands in a store's log.
+  fn logged_commands(location_dir_path: &Path) -> usize {
+    Follower::open(location_dir_path.to_path_buf())
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .map(|commands| commands.len())
+      .expect("unable to read log in test")
+  }
+
+  /// Create a store holding 6 in three commands, then run a compaction which crashes at `fail_point`, if any.
+  fn compact_crashing_at(location_dir_path: &Path, fail_point: Option<StorageOperation>) {
+    let madeleine = Madeleine::new(location_dir_path.to_path_buf(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2, 3] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    if let Some(operation) = fail_point {
+      crash_at(&madeleine, operation);
+    }
+
+    let compacted = madeleine.compact();
+
+    match fail_point {
+      Some(_operation) => assert!(compacted.is_err()),
+      None => assert_eq!(
+        compacted.ok(),
+        Some(CompactionReport {
+          snapshot_id: 0,
+          commands_removed: 3,
+        })
+      ),
+    }
+
+    if fail_point.is_none() {
+      assert_eq!(madeleine.total_commands_ever(), 3);
+    }
+  }
+
+  /// Simulate a crash at the first call of `operation`.
+  fn crash_at(madeleine: &Madeleine<u64>, operation: StorageOperation) {
+    let failpoints = FailpointStore::new();
+    failpoints.fail_nth(operation, 1);
+
+    madeleine
+      .set_failpoints(Some(Arc::new(failpoints)))
+      .expect("unable to set failpoints in test");
+  }
+
+  /// Resume a store, checking that no compaction is left in progress and the state survived,
+  /// and return the stage the compaction had reached.
+  fn resume_and_check(location_dir_path: &Path) -> Option<CompactionStage> {
+    let resumed = Madeleine::<u64>::resume(location_dir_path.to_path_buf())
+      .expect("unable to resume madeleine in test");
+
+    let metadata = StoreMetadata::load_or_create(location_dir_path, HashAlgo::default())
+      .expect("unable to load metadata in test");
+
+    assert_eq!(metadata.compaction, None);
+    assert_eq!(resumed.tap_ref(|state| *state).ok(), Some(6));
+    assert_eq!(resumed.total_commands_ever(), 3);
+    assert!(!location_dir_path.join(COMPACTED_LOG_DIR_NAME).exists());
+    assert!(!location_dir_path.join(RETIRED_LOG_DIR_NAME).exists());
+
+    resumed
+      .execute_command(Add(1))
+      .expect("unable to execute command after recovery in test");
+
+    resumed.open_report().compaction_recovered
+  }
+
+  #[test]
+  fn test_compaction_replaces_history_with_snapshot() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    compact_crashing_at(&store_path, None);
+
+    assert_eq!(logged_commands(&store_path), 0);
+    assert_eq!(resume_and_check(&store_path), None);
+  }
+
+  #[test]
+  fn test_crash_before_snapshot_is_undone() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    // The snapshot doesn't exist yet, so resuming needs one from before the compaction.
+    {
+      let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+        .expect("unable to instantiate madeleine in test");
+
+      for amount in [1, 2, 3] {
+        madeleine
+          .execute_command(Add(amount))
+          .expect("unable to execute command in test");
+      }
+
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+      crash_at(
+        &madeleine,
+        StorageOperation::CompactionStage(CompactionStage::Intent),
+      );
+
+      assert!(madeleine.compact().is_err());
+    }
+
+    // A partly written snapshot is left behind by the crash.
+    fs::write(snapshot_file_path(1, store_path.clone()), "{").expect("unable to write in test");
+
+    assert_eq!(resume_and_check(&store_path), Some(CompactionStage::Intent));
+    assert!(!snapshot_file_path(1, store_path.clone()).exists());
+    assert_eq!(logged_commands(&store_path), 4);
+  }
+
+  #[test]
+  fn test_crashes_after_snapshot_are_finished() {
+    let cases = [
+      (
+        StorageOperation::CompactionStage(CompactionStage::SnapshotDone),
+        CompactionStage::SnapshotDone,
+      ),
+      (
+        StorageOperation::CompactionSwap,
+        CompactionStage::SnapshotDone,
+      ),
+      (
+        StorageOperation::CompactionStage(CompactionStage::RowsDeleted),
+        CompactionStage::RowsDeleted,
+      ),
+      (
+        StorageOperation::CompactionStage(CompactionStage::Vacuumed),
+        CompactionStage::Vacuumed,
+      ),
+    ];
+
+    for (fail_point, stage) in cases {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+      let store_path = temp_dir.path().join("test_store");
+
+      compact_crashing_at(&store_path, Some(fail_point));
+
+      assert_eq!(
+        resume_and_check(&store_path),
+        Some(stage),
+        "{:?}",
+        fail_point
+      );
+      assert_eq!(logged_commands(&store_path), 1, "{:?}", fail_point);
+    }
+  }
+
+  #[test]
+  fn test_log_compacted_up_to_snapshot() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    let snapshot_head = madeleine
+      .snapshot_head_id(snapshot_id)
+      .expect("unable to read snapshot head in test")
+      .expect("snapshot head missing in test");
+
+    madeleine
+      .execute_command(Add(3))
+      .expect("unable to execute command in test");
+
+    let uncovered =
+      madeleine.compact_log(madeleine.head_id().expect("unable to read head in test"));
+
+    assert!(matches!(uncovered, Err(MadeleineError::SnapshotError(_))));
+    assert_eq!(madeleine.len(), 3);
+
+    // Crash while swapping logs, so the removal is finished on resume.
+    crash_at(&madeleine, StorageOperation::CompactionSwap);
+
+    assert!(madeleine.compact_log(snapshot_head).is_err());
+
+    drop(madeleine);
+
+    let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(store_path.clone(), || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed.open_report().compaction_recovered,
+      Some(CompactionStage::SnapshotDone)
+    );
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(resumed.total_commands_ever(), 3);
+    assert_eq!(resumed.tap_ref(|state| *state).ok(), Some(6));
+    assert_eq!(
+      resumed
+        .compact_log(snapshot_head)
+        .expect("unable to compact log in test"),
+      0
+    );
+
+    resumed
+      .execute_command(Add(4))
+      .expect("unable to execute command in test");
+    resumed
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    assert_eq!(
+      resumed
+        .compact_log(resumed.head_id().expect("unable to read head in test"))
+        .expect("unable to compact log in test"),
+      2
+    );
+    assert_eq!(resumed.len(), 0);
+    assert_eq!(logged_commands(&store_path), 0);
+
+    drop(resumed);
+
+    let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(store_path, || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap_ref(|state| *state).ok(), Some(10));
+    assert_eq!(resumed.total_commands_ever(), 4);
+  }
+}
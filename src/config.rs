@@ -0,0 +1,440 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command_store::{LogBackend, SyncMode};
+use crate::directory_policy::DirectoryPolicy;
+use crate::hashing::HashAlgo;
+use crate::idempotency::IdempotencyOptions;
+use crate::integrity::VerificationLevel;
+use crate::layout::dir_name_issue;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::PayloadFormat;
+use crate::quota::Quotas;
+use crate::rate_limit::{RateLimit, RateLimits};
+use crate::snapshot_failure::SnapshotFailureMode;
+use crate::snapshot_policy::SnapshotPolicy;
+
+/// Every option of a `MadeleineBuilder`, in a form which can be read from a configuration file.
+/// Missing fields take the same defaults as the builder, and unknown fields are rejected.
+/// See `examples/madeleine_config.json` for a complete example.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MadeleineConfig {
+  /// Location of the store directory. Required.
+  pub path: PathBuf,
+  /// Policy for the store directory's contents, by default `require-empty-or-store` when creating
+  /// and `require-existing-store` when resuming.
+  pub directory_policy: Option<DirectoryPolicy>,
+  /// Hash function for a new store, which an existing store must already use. By default `sha256` for new stores,
+  /// and whatever an existing store uses.
+  pub hash_algo: Option<HashAlgo>,
+  /// How a new store serializes commands, which an existing store must already use. By default `json` for new stores,
+  /// and whatever an existing store uses.
+  pub payload_format: Option<PayloadFormat>,
+  /// How a new store keeps its command log, which an existing store must already use. By default `commit-log`
+  /// for new stores, and whatever an existing store uses.
+  pub log_backend: Option<LogBackend>,
+  /// When commands are synced to disk, see `Madeleine::set_sync_mode`. By default whatever the log backend does.
+  pub sync_mode: Option<SyncMode>,
+  /// Name of a new store's command log directory, which an existing store must already use. By default `command_log`
+  /// for new stores, and whatever an existing store uses.
+  pub command_log_dir_name: Option<String>,
+  /// Name of a new store's snapshot directory, which an existing store must already use. By default snapshots are kept
+  /// in the store directory itself.
+  pub snapshot_dir_name: Option<String>,
+  /// How thoroughly to verify the store on open if it wasn't shut down cleanly, `full` by default.
+  pub verification: VerificationLevel,
+  /// Whether to check that reads don't mutate the state, see `Madeleine::set_strict`.
+  pub strict: bool,
+  /// Most bytes of command log segments and snapshots, unlimited by default.
+  pub max_store_bytes: Option<u64>,
+  /// Most commands in the log, unlimited by default.
+  pub max_commands: Option<u64>,
+  /// Percentages of each limit at which to warn, between 1 and 100.
+  pub warning_thresholds: Vec<u8>,
+  /// Most commands executed per second, unlimited by default.
+  pub rate_limit_commands_per_sec: Option<u64>,
+  /// Most commands executed at once after a quiet spell, by default one second's worth.
+  pub rate_limit_command_burst: Option<u64>,
+  /// Most bytes of serialized commands executed per second, unlimited by default.
+  pub rate_limit_bytes_per_sec: Option<u64>,
+  /// Most bytes of serialized commands executed at once after a quiet spell, by default one second's worth.
+  pub rate_limit_byte_burst: Option<u64>,
+  /// Longest async execution waits for the rate limits, in milliseconds, zero by default.
+  pub rate_limit_max_async_delay_ms: u64,
+  /// Whether to carry on when a scheduled snapshot fails, `fail-command` by default.
+  pub snapshot_failure_mode: SnapshotFailureMode,
+  /// Take a snapshot every this many commands. At most one of this and `snapshot_interval_secs` may be set.
+  pub snapshot_every_n_commands: Option<u64>,
+  /// Take a snapshot after commands once this many seconds have passed since the last one.
+  pub snapshot_interval_secs: Option<u64>,
+  /// Write only every this many snapshots in full, and patches against the previous snapshot in between, `1` by default.
+  pub full_snapshot_every: usize,
+  /// How long idempotency keys are remembered, in seconds.
+  pub idempotency_ttl_secs: u64,
+  /// Largest idempotent output cached, in bytes.
+  pub idempotency_max_output_bytes: usize,
+  /// Most idempotency keys remembered.
+  pub idempotency_max_keys: usize,
+  /// Id of the codec compressing snapshots, uncompressed by default.
+  pub snapshot_codec: Option<String>,
+  /// Id of the codec compressing logged commands, uncompressed by default.
+  pub payload_codec: Option<String>,
+  /// Id of the codec compressing exports, uncompressed by default.
+  pub export_codec: Option<String>,
+}
+
+impl Default for MadeleineConfig {
+  fn default() -> Self {
+    let quotas = Quotas::default();
+    let idempotency_options = IdempotencyOptions::default();
+
+    Self {
+      path: PathBuf::new(),
+      directory_policy: None,
+      hash_algo: None,
+      payload_format: None,
+      log_backend: None,
+      sync_mode: None,
+      command_log_dir_name: None,
+      snapshot_dir_name: None,
+      verification: VerificationLevel::default(),
+      strict: false,
+      max_store_bytes: quotas.max_store_bytes,
+      max_commands: quotas.max_commands,
+      warning_thresholds: quotas.warning_thresholds,
+      rate_limit_commands_per_sec: None,
+      rate_limit_command_burst: None,
+      rate_limit_bytes_per_sec: None,
+      rate_limit_byte_burst: None,
+      rate_limit_max_async_delay_ms: 0,
+      snapshot_failure_mode: SnapshotFailureMode::default(),
+      snapshot_every_n_commands: None,
+      snapshot_interval_secs: None,
+      full_snapshot_every: 1,
+      idempotency_ttl_secs: idempotency_options.ttl.as_secs(),
+      idempotency_max_output_bytes: idempotency_options.max_output_bytes,
+      idempotency_max_keys: idempotency_options.max_keys,
+      snapshot_codec: None,
+      payload_codec: None,
+      export_codec: None,
+    }
+  }
+}
+
+/// A field of a `MadeleineConfig` with an invalid value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+  /// Name of the field, as written in a configuration file.
+  pub field: String,
+  /// Why the value is invalid.
+  pub reason: String,
+}
+
+impl fmt::Display for ConfigIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}: {}", self.field, self.reason)
+  }
+}
+
+impl MadeleineConfig {
+  /// List every invalid field, or none if the config is valid.
+  pub fn validate(&self) -> Vec<ConfigIssue> {
+    let mut issues = Vec::new();
+    let mut issue = |field: &str, reason: String| {
+      issues.push(ConfigIssue {
+        field: field.to_string(),
+        reason,
+      })
+    };
+
+    if self.path.as_os_str().is_empty() {
+      issue("path", String::from("must not be empty"));
+    }
+
+    for (field, name) in [
+      ("command_log_dir_name", &self.command_log_dir_name),
+      ("snapshot_dir_name", &self.snapshot_dir_name),
+    ] {
+      if let Some(reason) = name.as_deref().and_then(dir_name_issue) {
+        issue(field, reason);
+      }
+    }
+
+    if self.max_store_bytes == Some(0) {
+      issue("max_store_bytes", String::from("must be positive if set"));
+    }
+
+    if self.max_commands == Some(0) {
+      issue("max_commands", String::from("must be positive if set"));
+    }
+
+    for threshold in &self.warning_thresholds {
+      if !(1..=100).contains(threshold) {
+        issue(
+          "warning_thresholds",
+          format!("{} is not a percentage between 1 and 100", threshold),
+        );
+      }
+    }
+
+    for (rate_field, burst_field, per_sec, burst) in [
+      (
+        "rate_limit_commands_per_sec",
+        "rate_limit_command_burst",
+        self.rate_limit_commands_per_sec,
+        self.rate_limit_command_burst,
+      ),
+      (
+        "rate_limit_bytes_per_sec",
+        "rate_limit_byte_burst",
+        self.rate_limit_bytes_per_sec,
+        self.rate_limit_byte_burst,
+      ),
+    ] {
+      if per_sec == Some(0) {
+        issue(rate_field, String::from("must be positive if set"));
+      }
+
+      if burst == Some(0) {
+        issue(burst_field, String::from("must be positive if set"));
+      }
+
+      if per_sec.is_none() && burst.is_some() {
+        issue(
+          burst_field,
+          format!("must not be set without {}", rate_field),
+        );
+      }
+    }
+
+    if self.snapshot_every_n_commands == Some(0) {
+      issue(
+        "snapshot_every_n_commands",
+        String::from("must be positive if set"),
+      );
+    }
+
+    if self.snapshot_interval_secs == Some(0) {
+      issue(
+        "snapshot_interval_secs",
+        String::from("must be positive if set"),
+      );
+    }
+
+    if self.snapshot_every_n_commands.is_some() && self.snapshot_interval_secs.is_some() {
+      issue(
+        "snapshot_interval_secs",
+        String::from("must not be set along with snapshot_every_n_commands"),
+      );
+    }
+
+    if self.full_snapshot_every == 0 {
+      issue("full_snapshot_every", String::from("must be positive"));
+    }
+
+    if self.idempotency_ttl_secs == 0 {
+      issue("idempotency_ttl_secs", String::from("must be positive"));
+    }
+
+    if self.idempotency_max_keys == 0 {
+      issue("idempotency_max_keys", String::from("must be positive"));
+    }
+
+    issues
+  }
+
+  /// The snapshot policy described by `snapshot_every_n_commands` and `snapshot_interval_secs`.
+  pub fn snapshot_policy(&self) -> SnapshotPolicy {
+    match (self.snapshot_every_n_commands, self.snapshot_interval_secs) {
+      (Some(commands), _) => SnapshotPolicy::EveryNCommands(commands),
+      (None, Some(secs)) => SnapshotPolicy::Interval(std::time::Duration::from_secs(secs)),
+      (None, None) => SnapshotPolicy::Never,
+    }
+  }
+
+  /// The rate limits described by the `rate_limit_` fields.
+  pub fn rate_limits(&self) -> RateLimits {
+    let limit = |per_sec: Option<u64>, burst: Option<u64>| {
+      per_sec.map(|per_second| RateLimit {
+        per_second,
+        burst: burst.unwrap_or(per_second),
+      })
+    };
+
+    RateLimits {
+      commands: limit(
+        self.rate_limit_commands_per_sec,
+        self.rate_limit_command_burst,
+      ),
+      bytes: limit(self.rate_limit_bytes_per_sec, self.rate_limit_byte_burst),
+      max_async_delay: std::time::Duration::from_millis(self.rate_limit_max_async_delay_ms),
+    }
+  }
+
+  /// Fail with every invalid field at once if the config isn't valid.
+  pub(crate) fn check(&self) -> Result<(), MadeleineError> {
+    let issues = self.validate();
+
+    if issues.is_empty() {
+      Ok(())
+    } else {
+      let descriptions: Vec<String> = issues.iter().map(ConfigIssue::to_string).collect();
+
+      Err(MadeleineError::InvalidConfig(descriptions.join("; ")))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::builder::MadeleineBuilder;
+  use crate::Madeleine;
+
+  const EXAMPLE_CONFIG: &str = include_str!("../examples/madeleine_config.json");
+
+  #[test]
+  fn test_config_round_trips_through_builder() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let config = MadeleineConfig {
+      path: temp_dir.path().join("test_store"),
+      directory_policy: Some(DirectoryPolicy::Permissive),
+      hash_algo: Some(HashAlgo::Sha256),
+      strict: true,
+      sync_mode: Some(SyncMode::Full),
+      max_commands: Some(613),
+      rate_limit_commands_per_sec: Some(1000),
+      rate_limit_bytes_per_sec: Some(1_000_000),
+      rate_limit_max_async_delay_ms: 250,
+      warning_thresholds: vec![50, 75, 100],
+      snapshot_failure_mode: SnapshotFailureMode::WarnAndContinue,
+      snapshot_interval_secs: Some(300),
+      full_snapshot_every: 10,
+      idempotency_ttl_secs: 60,
+      ..MadeleineConfig::default()
+    };
+
+    let builder = MadeleineBuilder::<u64>::from_config(config.clone())
+      .expect("unable to build from config in test");
+
+    assert_eq!(builder.to_config(), config);
+
+    let serialized = serde_json::to_string(&config).expect("unable to serialize config in test");
+    let deserialized: MadeleineConfig =
+      serde_json::from_str(&serialized).expect("unable to deserialize config in test");
+
+    assert_eq!(deserialized, config);
+
+    let madeleine = builder
+      .build(|| 0_u64)
+      .expect("unable to build madeleine in test");
+
+    assert!(madeleine.is_strict());
+    assert_eq!(madeleine.sync_mode(), SyncMode::Full);
+    assert_eq!(
+      madeleine.snapshot_failure_mode(),
+      SnapshotFailureMode::WarnAndContinue
+    );
+    assert_eq!(
+      madeleine.snapshot_policy(),
+      SnapshotPolicy::Interval(std::time::Duration::from_secs(300))
+    );
+    assert_eq!(
+      madeleine
+        .resource_usage()
+        .map(|usage| usage.quotas.max_commands)
+        .ok(),
+      Some(Some(613))
+    );
+    assert_eq!(
+      madeleine.rate_limits().commands,
+      Some(RateLimit::per_second(1000))
+    );
+    assert_eq!(madeleine.full_snapshot_every(), 10);
+
+    let defaults = MadeleineBuilder::<u64>::new(PathBuf::from("test_store")).to_config();
+
+    assert_eq!(
+      defaults,
+      MadeleineConfig {
+        path: PathBuf::from("test_store"),
+        ..MadeleineConfig::default()
+      }
+    );
+  }
+
+  #[test]
+  fn test_every_invalid_field_is_reported() {
+    let config: MadeleineConfig = serde_json::from_str(
+      r#"{
+        "max_commands": 0,
+        "warning_thresholds": [0, 80, 101],
+        "rate_limit_command_burst": 5,
+        "snapshot_every_n_commands": 5,
+        "snapshot_interval_secs": 60,
+        "full_snapshot_every": 0,
+        "idempotency_max_keys": 0
+      }"#,
+    )
+    .expect("unable to deserialize config in test");
+
+    let fields: Vec<String> = config
+      .validate()
+      .into_iter()
+      .map(|issue| issue.field)
+      .collect();
+
+    assert_eq!(
+      fields,
+      vec![
+        "path",
+        "max_commands",
+        "warning_thresholds",
+        "warning_thresholds",
+        "rate_limit_command_burst",
+        "snapshot_interval_secs",
+        "full_snapshot_every",
+        "idempotency_max_keys"
+      ]
+    );
+
+    let error = MadeleineBuilder::<u64>::from_config(config)
+      .map(|_builder| ())
+      .expect_err("invalid config accepted in test");
+
+    assert!(matches!(error, MadeleineError::InvalidConfig(ref message) if message.contains("101")));
+
+    let unknown_field = serde_json::from_str::<MadeleineConfig>(r#"{ "path": "a", "pth": "b" }"#);
+
+    assert!(unknown_field.is_err());
+  }
+
+  #[test]
+  fn test_example_config_is_valid() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let mut config: MadeleineConfig =
+      serde_json::from_str(EXAMPLE_CONFIG).expect("unable to parse example config in test");
+
+    assert!(config.validate().is_empty());
+
+    config.path = temp_dir.path().join(&config.path);
+
+    let madeleine = MadeleineBuilder::from_config(config)
+      .and_then(|builder| builder.build(|| 0_u64))
+      .expect("unable to build madeleine from example config in test");
+
+    assert_eq!(madeleine.hash_algo(), HashAlgo::Sha256);
+
+    let resumed = Madeleine::<u64>::builder(temp_dir.path().join("missing")).resume();
+
+    assert!(matches!(resumed, Err(MadeleineError::DirectoryError(_))));
+  }
+}
@@ -0,0 +1,104 @@
+use ulid::Ulid;
+
+/// Where replaying the log stopped matching the state it was compared with, see `Madeleine::verify_determinism`.
+///
+/// Only snapshots and the live state are kept to compare with, so the command which diverged is known to be among
+/// those logged after `matched_up_to` and no later than `diverged_by`. Taking snapshots more often narrows it down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DivergenceReport {
+  /// ULID of the last command after which the replayed state was seen to match, or `None` if it never was,
+  /// e.g. when the first state compared with already differed.
+  pub matched_up_to: Option<Ulid>,
+  /// ULID of the last command applied to the state which the replayed state differed from,
+  /// or `Ulid::nil()` if no command had been.
+  pub diverged_by: Ulid,
+  /// The snapshot holding the state which the replayed state differed from, or `None` for the live state.
+  pub snapshot_id: Option<usize>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use std::sync::atomic::{AtomicU64, Ordering};
+
+  use crate::{Command, Madeleine};
+
+  /// Incremented on every execution, standing in for the wall clock.
+  static TICKS: AtomicU64 = AtomicU64::new(0);
+
+  /// Records the time it was executed at, so that replaying it records a different time.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Stamp(u64);
+
+  impl Command<'_> for Stamp {
+    type SystemState = Vec<u64>;
+
+    fn execute(&self, mut old_state: Self::SystemState) -> Self::SystemState {
+      old_state.push(self.0 + TICKS.fetch_add(1, Ordering::SeqCst));
+      old_state
+    }
+  }
+
+  #[test]
+  fn test_verify_determinism_detects_divergence_from_live_state() {
+    let madeleine =
+      Madeleine::new_in_memory(Vec::new).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Stamp(0))
+      .expect("unable to execute command in test");
+
+    let report = madeleine
+      .verify_determinism::<Stamp, _>(Vec::new)
+      .expect("unable to verify determinism in test");
+
+    assert_eq!(
+      report,
+      Some(DivergenceReport {
+        matched_up_to: None,
+        diverged_by: madeleine.head_id().expect("unable to get head in test"),
+        snapshot_id: None,
+      })
+    );
+  }
+
+  #[test]
+  fn test_verify_determinism_narrows_divergence_down_with_snapshots() {
+    let madeleine =
+      Madeleine::new_in_memory(Vec::new).expect("unable to instantiate madeleine in test");
+
+    let before_commands = madeleine.head_id().expect("unable to get head in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .execute_command(Stamp(0))
+      .expect("unable to execute command in test");
+    let after_stamp = madeleine.head_id().expect("unable to get head in test");
+    let stamped = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .execute_command(Stamp(0))
+      .expect("unable to execute command in test");
+
+    let report = madeleine
+      .verify_determinism::<Stamp, _>(Vec::new)
+      .expect("unable to verify determinism in test");
+
+    assert_eq!(
+      report,
+      Some(DivergenceReport {
+        matched_up_to: Some(before_commands),
+        diverged_by: after_stamp,
+        snapshot_id: Some(stamped),
+      })
+    );
+  }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::layout::StoreLayout;
+use crate::madeleine::{is_store_entry, is_store_root};
+use crate::madeleine_error::MadeleineError;
+
+/// Rules about the contents of a store directory, checked before any files are created in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirectoryPolicy {
+  /// The directory must be missing, empty, or an existing store with no foreign entries.
+  /// This is the default when creating a new instance.
+  #[default]
+  RequireEmptyOrStore,
+  /// The directory must be an existing store with no foreign entries.
+  /// This is the default when resuming.
+  RequireExistingStore,
+  /// Use the directory regardless of what it contains.
+  Permissive,
+}
+
+impl DirectoryPolicy {
+  /// Check a store directory against the policy, naming any unexpected entries in the error.
+  pub(crate) fn evaluate(self, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    if self == Self::Permissive {
+      return Ok(());
+    }
+
+    let is_store = location_dir_path.is_dir() && is_store_root(location_dir_path);
+
+    if self == Self::RequireExistingStore && !is_store {
+      return Err(MadeleineError::DirectoryError(format!(
+        "{} is not a Madeleine store",
+        location_dir_path.display()
+      )));
+    }
+
+    if !location_dir_path.exists() {
+      return Ok(());
+    }
+
+    let layout = StoreLayout::of(location_dir_path);
+    let mut unexpected_entries = Vec::new();
+
+    for entry in fs::read_dir(location_dir_path)? {
+      let file_name = entry?.file_name().to_string_lossy().into_owned();
+
+      if !is_store_entry(&file_name, &layout) {
+        unexpected_entries.push(file_name);
+      }
+    }
+
+    if unexpected_entries.is_empty() {
+      Ok(())
+    } else {
+      unexpected_entries.sort();
+
+      Err(MadeleineError::DirectoryError(format!(
+        "Unexpected entries in store directory {}: {}",
+        location_dir_path.display(),
+        unexpected_entries.join(", ")
+      )))
+    }
+  }
+}
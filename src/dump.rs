@@ -0,0 +1,277 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::madeleine_error::MadeleineError;
+
+/// Bytes every dump starts with.
+const DUMP_MAGIC: &[u8] = b"MADELEINE-DUMP";
+
+/// Version of the dump format, written after the magic bytes.
+pub const DUMP_FORMAT_VERSION: u16 = 1;
+
+/// Most bytes a dump may hold once decompressed, guarding against dumping a huge store
+/// into memory and against restoring a malicious blob.
+pub const MAX_DUMP_BYTES: u64 = 1 << 30;
+
+/// The decoded contents of a dump.
+pub(crate) struct Dump {
+  /// The serialized system state.
+  pub state: Vec<u8>,
+  /// Every file in the store, with its path relative to the store directory.
+  pub files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Encode the serialized state and every file under a store directory into a compressed blob.
+pub(crate) fn dump_store(
+  location_dir_path: &Path,
+  state: &[u8],
+) -> Result<Vec<u8>, MadeleineError> {
+  let mut files = Vec::new();
+  collect_files(location_dir_path, Path::new(""), &mut files)?;
+
+  let total_bytes = files
+    .iter()
+    .fold(state.len() as u64, |total, (_path, contents)| {
+      total + contents.len() as u64
+    });
+
+  if total_bytes > MAX_DUMP_BYTES {
+    return Err(MadeleineError::DumpError(format!(
+      "store holds {} bytes, more than the limit of {}",
+      total_bytes, MAX_DUMP_BYTES
+    )));
+  }
+
+  let mut blob = DUMP_MAGIC.to_vec();
+  blob.extend_from_slice(&DUMP_FORMAT_VERSION.to_le_bytes());
+
+  let mut encoder = ZlibEncoder::new(blob, Compression::default());
+
+  write_chunk(&mut encoder, state)?;
+  encoder.write_all(&(files.len() as u64).to_le_bytes())?;
+
+  for (path, contents) in &files {
+    let path = path
+      .iter()
+      .map(|component| component.to_string_lossy())
+      .collect::<Vec<_>>()
+      .join("/");
+
+    write_chunk(&mut encoder, path.as_bytes())?;
+    write_chunk(&mut encoder, contents)?;
+  }
+
+  Ok(encoder.finish()?)
+}
+
+/// Decode a blob made by `dump_store`, checking its header, size and paths.
+pub(crate) fn read_dump(bytes: &[u8]) -> Result<Dump, MadeleineError> {
+  let header_len = DUMP_MAGIC.len() + 2;
+
+  if bytes.len() < header_len || &bytes[..DUMP_MAGIC.len()] != DUMP_MAGIC {
+    return Err(MadeleineError::DumpError(String::from(
+      "not a Madeleine dump",
+    )));
+  }
+
+  let version = u16::from_le_bytes([bytes[DUMP_MAGIC.len()], bytes[DUMP_MAGIC.len() + 1]]);
+
+  if version != DUMP_FORMAT_VERSION {
+    return Err(MadeleineError::DumpError(format!(
+      "unsupported dump format version {}, expected {}",
+      version, DUMP_FORMAT_VERSION
+    )));
+  }
+
+  // Read one byte past the limit, so that exceeding it can be told apart from reaching it.
+  let mut decoder = ZlibDecoder::new(&bytes[header_len..]).take(MAX_DUMP_BYTES + 1);
+  let mut decoded = Vec::new();
+  decoder.read_to_end(&mut decoded)?;
+
+  if decoded.len() as u64 > MAX_DUMP_BYTES {
+    return Err(MadeleineError::DumpError(format!(
+      "dump decompresses to more than the limit of {} bytes",
+      MAX_DUMP_BYTES
+    )));
+  }
+
+  let mut reader = decoded.as_slice();
+
+  let state = read_chunk(&mut reader)?;
+  let file_count = read_u64(&mut reader)?;
+  let mut files = Vec::new();
+
+  for _i in 0..file_count {
+    let raw_path = String::from_utf8(read_chunk(&mut reader)?)
+      .map_err(|error| MadeleineError::DumpError(format!("invalid file path: {}", error)))?;
+    let path = PathBuf::from(&raw_path);
+
+    if !path
+      .components()
+      .all(|component| matches!(component, Component::Normal(_)))
+    {
+      return Err(MadeleineError::DumpError(format!(
+        "file path '{}' escapes the store directory",
+        raw_path
+      )));
+    }
+
+    files.push((path, read_chunk(&mut reader)?));
+  }
+
+  Ok(Dump { state, files })
+}
+
+/// Recursively gather the files under `dir`, with paths relative to the store directory.
+fn collect_files(
+  dir: &Path,
+  relative: &Path,
+  files: &mut Vec<(PathBuf, Vec<u8>)>,
+) -> Result<(), MadeleineError> {
+  let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+  entries.sort_by_key(|entry| entry.file_name());
+
+  for entry in entries {
+    let relative = relative.join(entry.file_name());
+
+    if entry.file_type()?.is_dir() {
+      collect_files(&entry.path(), &relative, files)?;
+    } else {
+      files.push((relative, fs::read(entry.path())?));
+    }
+  }
+
+  Ok(())
+}
+
+fn write_chunk<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<(), MadeleineError> {
+  writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+  writer.write_all(bytes)?;
+
+  Ok(())
+}
+
+fn read_u64(reader: &mut &[u8]) -> Result<u64, MadeleineError> {
+  let mut raw = [0_u8; 8];
+
+  reader
+    .read_exact(&mut raw)
+    .map_err(|_error| MadeleineError::DumpError(String::from("dump is truncated")))?;
+
+  Ok(u64::from_le_bytes(raw))
+}
+
+fn read_chunk(reader: &mut &[u8]) -> Result<Vec<u8>, MadeleineError> {
+  let len = read_u64(reader)?;
+
+  if len > reader.len() as u64 {
+    return Err(MadeleineError::DumpError(String::from("dump is truncated")));
+  }
+
+  let (chunk, rest) = reader.split_at(len as usize);
+  *reader = rest;
+
+  Ok(chunk.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_round_trip_preserves_state_and_history() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("original"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [600, 13] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    let bytes = madeleine
+      .dump_bytes()
+      .expect("unable to dump store in test");
+
+    let restored = Madeleine::<u64>::restore_bytes(temp_dir.path().join("restored"), &bytes)
+      .expect("unable to restore store in test");
+
+    assert_eq!(restored.state_hash().ok(), madeleine.state_hash().ok());
+    assert_eq!(restored.len(), madeleine.len());
+    assert_eq!(restored.head_id().ok(), madeleine.head_id().ok());
+    assert_eq!(restored.store_id(), madeleine.store_id());
+
+    restored
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    assert_eq!(restored.into_inner(), 615);
+  }
+
+  #[test]
+  fn test_restore_rejects_existing_store_and_bad_blobs() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let bytes = madeleine
+      .dump_bytes()
+      .expect("unable to dump store in test");
+
+    assert!(matches!(
+      Madeleine::<u64>::restore_bytes(store_path, &bytes),
+      Err(MadeleineError::DirectoryError(_))
+    ));
+
+    let mut wrong_version = bytes.clone();
+    wrong_version[DUMP_MAGIC.len()] += 1;
+
+    let mut escaping = DUMP_MAGIC.to_vec();
+    escaping.extend_from_slice(&DUMP_FORMAT_VERSION.to_le_bytes());
+
+    let mut encoder = ZlibEncoder::new(escaping, Compression::default());
+    write_chunk(&mut encoder, b"0").expect("unable to write chunk in test");
+    encoder
+      .write_all(&1_u64.to_le_bytes())
+      .expect("unable to write count in test");
+    write_chunk(&mut encoder, b"../outside").expect("unable to write chunk in test");
+    write_chunk(&mut encoder, b"").expect("unable to write chunk in test");
+    let escaping = encoder.finish().expect("unable to compress in test");
+
+    for (name, blob) in [
+      ("garbage", b"not a dump".to_vec()),
+      ("wrong_version", wrong_version),
+      ("truncated", bytes[..bytes.len() / 2].to_vec()),
+      ("escaping", escaping),
+    ] {
+      let restored = Madeleine::<u64>::restore_bytes(temp_dir.path().join(name), &blob);
+
+      assert!(restored.is_err(), "restoring {} should fail", name);
+    }
+
+    assert!(!temp_dir.path().join("outside").exists());
+  }
+}
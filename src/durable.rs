@@ -0,0 +1,89 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Suffix of the file a store's file is written to before it's renamed over it, see `write_atomically`.
+pub(crate) const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// Replace a file's contents so that a crash leaves either the old or the new contents, never a torn mix.
+/// The contents are written and synced to a temporary file alongside it, which is renamed over the file,
+/// then the directory is synced so that the rename survives the crash too.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let temp_path = temp_file_path(path);
+
+  let mut file = File::create(&temp_path)?;
+  file.write_all(contents)?;
+  file.sync_all()?;
+  drop(file);
+
+  fs::rename(&temp_path, path)?;
+
+  match path.parent() {
+    Some(dir_path) if !dir_path.as_os_str().is_empty() => sync_dir(dir_path),
+    _ => sync_dir(Path::new(".")),
+  }
+}
+
+/// Where `write_atomically` writes a file's new contents before renaming them into place.
+fn temp_file_path(path: &Path) -> PathBuf {
+  let mut temp_path = path.as_os_str().to_owned();
+  temp_path.push(TEMP_FILE_SUFFIX);
+
+  PathBuf::from(temp_path)
+}
+
+/// Make renames within a directory durable.
+#[cfg(unix)]
+fn sync_dir(dir_path: &Path) -> io::Result<()> {
+  File::open(dir_path)?.sync_all()
+}
+
+/// Directories can't be opened for syncing here, and renames are durable once the file itself is.
+#[cfg(not(unix))]
+fn sync_dir(_dir_path: &Path) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  #[test]
+  fn test_write_atomically_replaces_contents() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let path = temp_dir.path().join("metadata");
+
+    write_atomically(&path, b"first").expect("unable to write in test");
+    write_atomically(&path, b"second").expect("unable to write in test");
+
+    assert_eq!(
+      fs::read(&path).expect("unable to read in test"),
+      b"second".to_vec()
+    );
+    assert!(!temp_file_path(&path).exists());
+  }
+
+  #[test]
+  fn test_torn_temp_file_leaves_contents() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let path = temp_dir.path().join("metadata");
+
+    write_atomically(&path, b"first").expect("unable to write in test");
+    // As left by a crash part way through writing the new contents.
+    fs::write(temp_file_path(&path), b"sec").expect("unable to write in test");
+
+    assert_eq!(
+      fs::read(&path).expect("unable to read in test"),
+      b"first".to_vec()
+    );
+
+    write_atomically(&path, b"second").expect("unable to write in test");
+
+    assert_eq!(
+      fs::read(&path).expect("unable to read in test"),
+      b"second".to_vec()
+    );
+  }
+}
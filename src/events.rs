@@ -0,0 +1,348 @@
+use std::time::SystemTime;
+
+use crate::maintenance::MaintenanceRun;
+use crate::quota::ResourceWarning;
+use crate::subscription::SubscriberLag;
+
+/// Something which happened to a store that may affect its health,
+/// delivered to the receivers returned by `Madeleine::events`.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum StoreEvent {
+  /// A snapshot was taken.
+  SnapshotTaken {
+    /// When the snapshot was taken.
+    at: SystemTime,
+    /// Id of the new snapshot.
+    snapshot_id: usize,
+    /// Whether the state was unchanged, so the snapshot refers to the previous one rather than being written out.
+    deduplicated: bool,
+  },
+  /// A scheduled snapshot failed, and `SnapshotFailureMode::WarnAndContinue` let the store carry on without it.
+  SnapshotFailed {
+    /// When the snapshot failed.
+    at: SystemTime,
+    /// Why it failed.
+    error: String,
+    /// Snapshots missed since the last one which succeeded, including this one.
+    pending_snapshot_debt: u64,
+  },
+  /// A batch of commands was redacted, see `Madeleine::redact_matching`.
+  RedactionProgress {
+    /// When the batch was written.
+    at: SystemTime,
+    /// Number of commands checked so far.
+    commands_scanned: u64,
+    /// Number of commands tombstoned or rewritten so far.
+    commands_changed: u64,
+  },
+  /// Resource usage crossed a warning threshold, as also passed to the resource warning hook.
+  ResourceWarning {
+    /// When the threshold was crossed.
+    at: SystemTime,
+    /// Details of the crossing.
+    warning: ResourceWarning,
+  },
+  /// A subscriber to appended commands started falling behind, or was disconnected for doing so.
+  SubscriberLagging {
+    /// When the subscriber's buffer was found full.
+    at: SystemTime,
+    /// How far behind the subscriber is.
+    lag: SubscriberLag,
+  },
+  /// A thread panicked while holding a shared instance's lock, see `SharedMadeleine`.
+  LockPoisoned {
+    /// When the poisoned lock was recovered.
+    at: SystemTime,
+  },
+  /// A projection's fold panicked, so it no longer reflects the log until rebuilt.
+  ProjectionPoisoned {
+    /// When the fold panicked.
+    at: SystemTime,
+    /// The projection's name.
+    name: String,
+  },
+  /// The state or the bookkeeping derived from it was found not to match what it should be.
+  CorruptionFound {
+    /// When the corruption was found.
+    at: SystemTime,
+    /// What was wrong.
+    description: String,
+  },
+  /// A scheduled maintenance task was run, see `Madeleine::tick_maintenance`.
+  MaintenanceRan {
+    /// When the task finished.
+    at: SystemTime,
+    /// The task, and how it went.
+    run: MaintenanceRun,
+  },
+}
+
+impl StoreEvent {
+  /// When the event happened.
+  pub fn at(&self) -> SystemTime {
+    match self {
+      Self::SnapshotTaken { at, .. }
+      | Self::SnapshotFailed { at, .. }
+      | Self::RedactionProgress { at, .. }
+      | Self::ResourceWarning { at, .. }
+      | Self::SubscriberLagging { at, .. }
+      | Self::LockPoisoned { at }
+      | Self::ProjectionPoisoned { at, .. }
+      | Self::CorruptionFound { at, .. }
+      | Self::MaintenanceRan { at, .. } => *at,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::cell::Cell;
+  use std::sync::Arc;
+  use std::thread;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use crate::quota::{Quotas, Resource};
+  use crate::subscription::{OverflowPolicy, Receiver, SubscribeOptions};
+  use crate::testing::{FailpointAction, FailpointStore, StorageOperation};
+  use crate::{Command, Madeleine, SharedMadeleine, SnapshotFailureMode};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Add(u64);
+
+  impl Command<'_> for Add {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      if self.0 == 0 {
+        panic!("adding nothing in test");
+      }
+
+      old_state + self.0
+    }
+  }
+
+  fn drain(events: &Receiver<StoreEvent>) -> Vec<StoreEvent> {
+    let mut drained = Vec::new();
+
+    while let Some(event) = events.try_recv().expect("unable to receive event in test") {
+      drained.push(event);
+    }
+
+    drained
+  }
+
+  #[test]
+  fn test_store_activity_raises_events() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let events = madeleine
+      .events(SubscribeOptions::default())
+      .expect("unable to receive events in test");
+
+    let _subscription = madeleine
+      .subscribe(SubscribeOptions {
+        capacity: 1,
+        policy: OverflowPolicy::DropOldest,
+      })
+      .expect("unable to subscribe in test");
+
+    madeleine
+      .register_projection::<Add, u64, _>("fragile", 0, |total, add| {
+        if add.0 > 1 {
+          panic!("fragile projection in test");
+        }
+
+        *total += add.0;
+      })
+      .expect("unable to register projection in test");
+
+    madeleine
+      .set_quotas(Quotas {
+        max_commands: Some(3),
+        warning_thresholds: vec![50],
+        ..Quotas::default()
+      })
+      .expect("unable to set quotas in test");
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let first_snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    let second_snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let received = drain(&events);
+
+    assert!(matches!(
+      &received[0],
+      StoreEvent::ResourceWarning { warning, .. } if warning.resource == Resource::Commands
+    ));
+    assert!(matches!(
+      &received[1],
+      StoreEvent::SubscriberLagging { lag, .. } if lag.dropped == 1
+    ));
+    assert!(matches!(
+      &received[2],
+      StoreEvent::ProjectionPoisoned { name, .. } if name == "fragile"
+    ));
+    assert!(matches!(
+      received[3],
+      StoreEvent::SnapshotTaken {
+        snapshot_id,
+        deduplicated: false,
+        ..
+      } if snapshot_id == first_snapshot_id
+    ));
+    assert!(matches!(
+      received[4],
+      StoreEvent::SnapshotTaken {
+        snapshot_id,
+        deduplicated: true,
+        ..
+      } if snapshot_id == second_snapshot_id
+    ));
+    assert_eq!(received.len(), 5);
+    assert!(received.windows(2).all(|pair| pair[0].at() <= pair[1].at()));
+  }
+
+  #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+  struct Sneaky {
+    visits: Cell<u64>,
+  }
+
+  #[test]
+  fn test_corruption_raises_events() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), Sneaky::default)
+      .expect("unable to instantiate madeleine in test");
+
+    let events = madeleine
+      .events(SubscribeOptions::default())
+      .expect("unable to receive events in test");
+
+    madeleine.set_strict(true);
+
+    assert!(madeleine
+      .tap_ref(|state| state.visits.set(state.visits.get() + 1))
+      .is_err());
+
+    madeleine
+      .take_snapshot(true)
+      .expect("unable to take snapshot in test");
+
+    std::fs::remove_file(store_path.join("snapshot")).expect("unable to remove file in test");
+
+    let report = madeleine
+      .rebuild_derived()
+      .expect("unable to rebuild derived in test");
+
+    let corruptions = drain(&events)
+      .into_iter()
+      .filter(|event| matches!(event, StoreEvent::CorruptionFound { .. }))
+      .count();
+
+    assert_eq!(corruptions, 1 + report.changes.len());
+    assert!(!report.is_noop());
+  }
+
+  #[test]
+  fn test_scheduled_snapshot_failures_follow_the_mode() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let events = madeleine
+      .events(SubscribeOptions::default())
+      .expect("unable to receive events in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.on_every(StorageOperation::SnapshotWrite, FailpointAction::Fail);
+
+    madeleine
+      .set_failpoints(Some(failpoints.clone()))
+      .expect("unable to set failpoints in test");
+
+    assert!(madeleine.take_scheduled_snapshot().is_err());
+
+    madeleine.set_snapshot_failure_mode(SnapshotFailureMode::WarnAndContinue);
+
+    for _i in 0..2 {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+
+      assert_eq!(
+        madeleine
+          .take_scheduled_snapshot()
+          .expect("scheduled snapshot failure not downgraded in test"),
+        None
+      );
+    }
+
+    assert!(madeleine.take_snapshot(false).is_err());
+    assert_eq!(madeleine.metrics().pending_snapshot_debt(), 3);
+
+    let debts: Vec<u64> = drain(&events)
+      .into_iter()
+      .filter_map(|event| match event {
+        StoreEvent::SnapshotFailed {
+          pending_snapshot_debt,
+          ..
+        } => Some(pending_snapshot_debt),
+        _ => None,
+      })
+      .collect();
+
+    assert_eq!(debts, vec![2, 3]);
+
+    failpoints.clear();
+
+    assert_eq!(madeleine.take_scheduled_snapshot().ok(), Some(Some(0)));
+    assert_eq!(madeleine.metrics().pending_snapshot_debt(), 0);
+  }
+
+  #[test]
+  fn test_poisoned_lock_raises_event() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    let events = shared
+      .events(SubscribeOptions::default())
+      .expect("unable to receive events in test");
+
+    let exploding = shared.clone();
+
+    assert!(thread::spawn(move || exploding.execute_command(Add(0)))
+      .join()
+      .is_err());
+
+    assert_eq!(shared.tap_ref(|state| *state).ok(), Some(0));
+
+    let received = drain(&events);
+
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], StoreEvent::LockPoisoned { .. }));
+  }
+}
@@ -0,0 +1,480 @@
+use std::fs;
+use std::io::{BufRead, Read, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::admin_log::{self, AdminOperationKind};
+use crate::codec::{self, Codec};
+use crate::command_log::CommandLog;
+use crate::hashing::HashAlgo;
+use crate::madeleine::{command_log_dir_path, is_store_root};
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::{StoreFormat, StoreMetadata};
+use crate::payload_format::require_json_entry;
+use crate::tenant::{entry_tenant, TenantId};
+
+/// Version of the manifest and export framing written by this release.
+pub const EXPORT_MANIFEST_VERSION: u32 = 1;
+
+/// Capability required to re-import an export whose commands are serialized as JSON.
+pub const JSON_PAYLOADS_CAPABILITY: &str = "json-payloads";
+
+/// Prefix of the capability required to re-import an export compressed with a codec, followed by the codec's id.
+pub const CODEC_CAPABILITY_PREFIX: &str = "codec:";
+
+/// Capabilities this release can import, besides codecs which are available.
+const SUPPORTED_CAPABILITIES: [&str; 1] = [JSON_PAYLOADS_CAPABILITY];
+
+/// How exported commands are framed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ExportFormat {
+  /// One JSON object per line, holding the command's `id` and the `command` itself.
+  Jsonl,
+}
+
+/// Commands to export, by ULID. Both bounds are inclusive, and missing bounds are unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExportRange {
+  /// Earliest command to export.
+  pub from: Option<Ulid>,
+  /// Latest command to export.
+  pub to: Option<Ulid>,
+}
+
+impl ExportRange {
+  /// Determine if a command is within the range.
+  pub fn contains(&self, id: Ulid) -> bool {
+    self.from.is_none_or(|from| id >= from) && self.to.is_none_or(|to| id <= to)
+  }
+}
+
+/// Which commands to export, as chosen by `Madeleine::export` and `Madeleine::export_tenant`.
+pub(crate) struct ExportSelection<'a> {
+  /// Commands to export, by ULID.
+  pub range: ExportRange,
+  /// The only tenant whose commands to export, if any.
+  pub tenant: Option<&'a TenantId>,
+}
+
+/// Describes an export, so that it can be verified and re-imported without knowing how it was produced.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExportManifest {
+  /// How the exported commands are framed.
+  pub format: ExportFormat,
+  /// Version of the manifest and framing.
+  pub version: u32,
+  /// Identifier of the exported store.
+  pub store_id: Ulid,
+  /// Which commands were exported.
+  pub range: ExportRange,
+  /// The only tenant whose commands were exported, if the export was limited to one.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tenant: Option<TenantId>,
+  /// Number of commands exported.
+  pub row_count: u64,
+  /// Hash function of the exported store, used for `content_hash`. Manifests written before it was recorded used SHA-256.
+  #[serde(default)]
+  pub hash_algo: HashAlgo,
+  /// Id of the codec which compressed the exported bytes, if they're compressed.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub codec: Option<String>,
+  /// Hex-encoded hash of the exported bytes, as written.
+  pub content_hash: String,
+  /// When the export was made.
+  pub created_at: SystemTime,
+  /// Capabilities an importer needs to re-import the export.
+  pub capabilities: Vec<String>,
+}
+
+/// A line of a dump of the whole command log, see `Madeleine::export_command_log`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CommandRecord {
+  /// Identifier of the command.
+  pub ulid: Ulid,
+  /// When the command was logged, in milliseconds since the Unix epoch, as recorded in its ULID.
+  pub recorded_at: u64,
+  /// The command, as serialized.
+  pub payload: Value,
+}
+
+/// A line of a JSONL export.
+#[derive(Debug, Deserialize, Serialize)]
+struct ExportedCommand {
+  id: Ulid,
+  command: Value,
+}
+
+/// Write the selected commands from a store's log to `writer`, compressed with a codec if one is given,
+/// returning the manifest describing the export.
+pub(crate) fn export_log<W: Write>(
+  command_log: &CommandLog,
+  store_id: Ulid,
+  hash_algo: HashAlgo,
+  format: ExportFormat,
+  selection: ExportSelection<'_>,
+  codec: Option<&dyn Codec>,
+  mut writer: W,
+) -> Result<ExportManifest, MadeleineError> {
+  let mut content = Vec::new();
+  let mut row_count = 0;
+
+  command_log.for_each_entry(|_offset, entry| {
+    let (id, command): (Ulid, Value) =
+      serde_json::from_slice(require_json_entry(entry, "exporting")?)?;
+
+    if selection.range.contains(id)
+      && selection
+        .tenant
+        .is_none_or(|tenant| entry_tenant(entry).as_ref() == Some(tenant))
+    {
+      match format {
+        ExportFormat::Jsonl => {
+          serde_json::to_writer(&mut content, &ExportedCommand { id, command })?;
+          content.push(b'\n');
+        }
+      }
+
+      row_count += 1;
+    }
+
+    Ok(())
+  })?;
+
+  let mut capabilities = vec![JSON_PAYLOADS_CAPABILITY.to_string()];
+
+  if let Some(codec) = codec {
+    let mut compressed = Vec::new();
+    codec.compress(&mut content.as_slice(), &mut compressed)?;
+    content = compressed;
+
+    capabilities.push(format!("{}{}", CODEC_CAPABILITY_PREFIX, codec.codec_id()));
+  }
+
+  writer.write_all(&content)?;
+  writer.flush()?;
+
+  Ok(ExportManifest {
+    format,
+    version: EXPORT_MANIFEST_VERSION,
+    store_id,
+    range: selection.range,
+    tenant: selection.tenant.cloned(),
+    row_count,
+    hash_algo,
+    codec: codec.map(|codec| codec.codec_id().to_string()),
+    content_hash: hash_algo.hex_digest(&content),
+    created_at: SystemTime::now(),
+    capabilities,
+  })
+}
+
+/// Check that an export matches its manifest and can be imported by this release.
+pub fn verify_export<R: Read>(manifest: &ExportManifest, reader: R) -> Result<(), MadeleineError> {
+  read_verified(manifest, reader).map(|_commands| ())
+}
+
+/// Create a fresh store at `location_dir_path` holding the commands of a verified export, under their original ULIDs.
+/// Returns the number of commands imported.
+pub fn import<R: Read>(
+  manifest: &ExportManifest,
+  reader: R,
+  location_dir_path: PathBuf,
+) -> Result<u64, MadeleineError> {
+  let commands = read_verified(manifest, reader)?;
+
+  let is_empty = !location_dir_path.exists() || fs::read_dir(&location_dir_path)?.next().is_none();
+
+  if !is_empty || is_store_root(&location_dir_path) {
+    return Err(MadeleineError::ExportError(format!(
+      "{} must be missing or empty to import into it",
+      location_dir_path.display()
+    )));
+  }
+
+  fs::create_dir_all(&location_dir_path)?;
+  StoreMetadata::open_for_write(
+    &location_dir_path,
+    StoreFormat {
+      hash_algo: Some(manifest.hash_algo),
+      ..StoreFormat::default()
+    },
+  )?;
+
+  let command_log = CommandLog::new(command_log_dir_path(&location_dir_path))?;
+
+  for exported in &commands {
+    command_log.append_entry(&serde_json::to_vec(&(exported.id, &exported.command))?)?;
+  }
+
+  command_log.flush()?;
+
+  admin_log::record(
+    &location_dir_path,
+    AdminOperationKind::Imported {
+      commands: commands.len() as u64,
+    },
+  )?;
+
+  Ok(commands.len() as u64)
+}
+
+/// Read an export, checking it against its manifest with a precise error for the first mismatch.
+fn read_verified<R: Read>(
+  manifest: &ExportManifest,
+  mut reader: R,
+) -> Result<Vec<ExportedCommand>, MadeleineError> {
+  if manifest.version > EXPORT_MANIFEST_VERSION {
+    return Err(MadeleineError::ExportError(format!(
+      "manifest version {} is newer than the supported version {}",
+      manifest.version, EXPORT_MANIFEST_VERSION
+    )));
+  }
+
+  let missing: Vec<&str> = manifest
+    .capabilities
+    .iter()
+    .map(String::as_str)
+    .filter(|capability| {
+      !SUPPORTED_CAPABILITIES.contains(capability)
+        && !capability.starts_with(CODEC_CAPABILITY_PREFIX)
+    })
+    .collect();
+
+  if !missing.is_empty() {
+    return Err(MadeleineError::ExportError(format!(
+      "missing capabilities required by the export: {}",
+      missing.join(", ")
+    )));
+  }
+
+  let mut content = Vec::new();
+  reader.read_to_end(&mut content)?;
+
+  let content_hash = manifest.hash_algo.hex_digest(&content);
+
+  if content_hash != manifest.content_hash {
+    return Err(MadeleineError::ExportError(format!(
+      "content hash {} doesn't match the manifest's {}",
+      content_hash, manifest.content_hash
+    )));
+  }
+
+  if let Some(codec_id) = &manifest.codec {
+    let mut decompressed = Vec::new();
+    codec::find(codec_id)?.decompress(&mut content.as_slice(), &mut decompressed)?;
+    content = decompressed;
+  }
+
+  let mut commands = Vec::new();
+
+  match manifest.format {
+    ExportFormat::Jsonl => {
+      for line in content.lines() {
+        commands.push(serde_json::from_str::<ExportedCommand>(&line?)?);
+      }
+    }
+  }
+
+  if commands.len() as u64 != manifest.row_count {
+    return Err(MadeleineError::ExportError(format!(
+      "export holds {} commands but the manifest lists {}",
+      commands.len(),
+      manifest.row_count
+    )));
+  }
+
+  if let Some(outside) = commands
+    .iter()
+    .find(|exported| !manifest.range.contains(exported.id))
+  {
+    return Err(MadeleineError::ExportError(format!(
+      "command {} is outside the manifest's range",
+      outside.id
+    )));
+  }
+
+  if let Some(tenant) = &manifest.tenant {
+    if let Some(foreign) = commands
+      .iter()
+      .find(|exported| exported.command.get("tenant") != Some(&Value::from(tenant.as_str())))
+    {
+      return Err(MadeleineError::ExportError(format!(
+        "command {} doesn't belong to the manifest's tenant {}",
+        foreign.id, tenant
+      )));
+    }
+  }
+
+  Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::follower::Follower;
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  fn exported_store(temp_dir: &assert_fs::TempDir) -> Madeleine<u64> {
+    let madeleine = Madeleine::new(temp_dir.path().join("source_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in 1..=4 {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+  }
+
+  fn commands(location_dir_path: PathBuf) -> Vec<(Ulid, Add)> {
+    Follower::open(location_dir_path)
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read commands in test")
+      .iter()
+      .map(|command| {
+        (
+          command.id,
+          command
+            .deserialize()
+            .expect("unable to deserialize in test"),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_command_log_dump_holds_every_command_in_order() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = exported_store(&temp_dir);
+
+    let mut exported = Vec::new();
+
+    assert_eq!(
+      madeleine
+        .export_command_log(&mut exported)
+        .expect("unable to export command log in test"),
+      4
+    );
+
+    let records: Vec<CommandRecord> = exported
+      .lines()
+      .map(|line| {
+        serde_json::from_str(&line.expect("unable to read line in test"))
+          .expect("unable to parse record in test")
+      })
+      .collect();
+
+    assert_eq!(
+      records
+        .iter()
+        .map(|record| (record.ulid, record.recorded_at, record.payload.clone()))
+        .collect::<Vec<_>>(),
+      commands(temp_dir.path().join("source_store"))
+        .into_iter()
+        .map(|(id, Add(amount))| (id, id.timestamp_ms(), Value::from(amount)))
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn test_jsonl_round_trip() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = exported_store(&temp_dir);
+
+    let mut exported = Vec::new();
+    let manifest = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)
+      .expect("unable to export in test");
+
+    assert_eq!(manifest.row_count, 4);
+    assert_eq!(manifest.store_id, madeleine.store_id());
+
+    let manifest: ExportManifest = serde_json::to_vec(&manifest)
+      .and_then(|serialized| serde_json::from_slice(&serialized))
+      .expect("unable to round trip manifest in test");
+
+    verify_export(&manifest, exported.as_slice()).expect("unable to verify export in test");
+
+    let imported_path = temp_dir.path().join("imported_store");
+    let imported = import(&manifest, exported.as_slice(), imported_path.clone())
+      .expect("unable to import in test");
+
+    assert_eq!(imported, 4);
+    assert_eq!(
+      commands(imported_path),
+      commands(temp_dir.path().join("source_store"))
+    );
+  }
+
+  #[test]
+  fn test_range_export() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = exported_store(&temp_dir);
+    let ids: Vec<Ulid> = commands(temp_dir.path().join("source_store"))
+      .into_iter()
+      .map(|(id, _command)| id)
+      .collect();
+
+    let range = ExportRange {
+      from: Some(ids[1]),
+      to: Some(ids[2]),
+    };
+
+    let mut exported = Vec::new();
+    let manifest = madeleine
+      .export(ExportFormat::Jsonl, range, &mut exported)
+      .expect("unable to export in test");
+
+    assert_eq!(manifest.row_count, 2);
+    verify_export(&manifest, exported.as_slice()).expect("unable to verify export in test");
+  }
+
+  #[test]
+  fn test_verification_failures() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = exported_store(&temp_dir);
+
+    let mut exported = Vec::new();
+    let manifest = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)
+      .expect("unable to export in test");
+
+    let mut tampered = exported.clone();
+    tampered[0] = b' ';
+
+    let mut needs_more = manifest.clone();
+    needs_more.capabilities.push(String::from("zstd-payloads"));
+
+    let mut miscounted = manifest.clone();
+    miscounted.row_count = 3;
+
+    for (manifest, content) in [
+      (&manifest, tampered.as_slice()),
+      (&needs_more, exported.as_slice()),
+      (&miscounted, exported.as_slice()),
+    ] {
+      assert!(matches!(
+        verify_export(manifest, content),
+        Err(MadeleineError::ExportError(_))
+      ));
+    }
+
+    let imported_path = temp_dir.path().join("imported_store");
+
+    assert!(import(&needs_more, exported.as_slice(), imported_path.clone()).is_err());
+    assert!(!imported_path.exists());
+  }
+}
@@ -0,0 +1,251 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use ulid::Ulid;
+
+use crate::command_log::CommandLog;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::StoreMetadata;
+use crate::store_path::StorePath;
+
+/// Longest a follower waits before checking the log on disk again,
+/// which bounds how late it notices commands appended by another process.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Wakes followers in the same process whenever a command is appended.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AppendNotifier {
+  appends: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl AppendNotifier {
+  /// Signal that a command was appended.
+  pub fn notify(&self) {
+    let (appends, condvar) = &*self.appends;
+    let mut count = appends
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    *count += 1;
+    condvar.notify_all();
+  }
+
+  /// Wait up to `timeout` for an append.
+  fn wait(&self, timeout: Duration) {
+    let (appends, condvar) = &*self.appends;
+    let count = appends
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let seen = *count;
+
+    // Spurious wakeups and timeouts are both fine, the caller checks the log either way.
+    let _ = condvar.wait_timeout_while(count, timeout, |count| *count == seen);
+  }
+}
+
+/// Follows a store's command log, waiting for new commands to be appended.
+/// Unlike `Madeleine`, a follower can be sent to and shared between threads.
+#[derive(Debug, Clone)]
+pub struct Follower {
+  log_dir_path: PathBuf,
+  notifier: Option<AppendNotifier>,
+}
+
+impl Follower {
+  /// Follow a store from outside the process writing to it. New commands are noticed by polling the log on disk.
+  pub fn open(location: impl Into<StorePath>) -> Result<Self, MadeleineError> {
+    let location_dir_path = location.into().resolve()?;
+    let log_dir_path = crate::madeleine::command_log_dir_path(&location_dir_path);
+
+    if !log_dir_path.is_dir() {
+      return Err(MadeleineError::DirectoryError(format!(
+        "{} is not a Madeleine store",
+        location_dir_path.display()
+      )));
+    }
+
+    Ok(Self {
+      log_dir_path,
+      notifier: None,
+    })
+  }
+
+  /// Follow a store written to by this process, waking as soon as commands are appended.
+  pub(crate) fn in_process(log_dir_path: PathBuf, notifier: AppendNotifier) -> Self {
+    Self {
+      log_dir_path,
+      notifier: Some(notifier),
+    }
+  }
+
+  /// Every command logged after the command identified by `after`.
+  /// If `after` isn't in the log, every command with a greater ULID is returned, so `Ulid::nil()` reads the whole log.
+  pub fn commands_after(&self, after: Ulid) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    CommandLog::new(self.log_dir_path.clone())?.commands_after(after)
+  }
+
+  /// Commands removed from the log by compactions and purges so far, from which positions in history are counted.
+  pub(crate) fn commands_compacted(&self) -> Result<u64, MadeleineError> {
+    match self.log_dir_path.parent() {
+      Some(location_dir_path) => StoreMetadata::read_commands_compacted(location_dir_path),
+      None => Ok(0),
+    }
+  }
+
+  /// Block until at least one command newer than `after` has been logged, or until the timeout elapses.
+  /// Returns the newer commands, which is empty if the timeout elapsed first.
+  pub fn wait_for_commands(
+    &self,
+    after: Ulid,
+    timeout: Duration,
+  ) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+      let commands = self.commands_after(after)?;
+      let now = Instant::now();
+
+      if !commands.is_empty() || now >= deadline {
+        return Ok(commands);
+      }
+
+      let wait = POLL_INTERVAL.min(deadline - now);
+
+      match &self.notifier {
+        Some(notifier) => notifier.wait(wait),
+        None => std::thread::sleep(wait),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use std::time::{SystemTime, UNIX_EPOCH};
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_wait_times_out_with_no_commands() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let commands = madeleine
+      .wait_for_commands(Ulid::nil(), Duration::from_millis(20))
+      .expect("unable to wait for commands in test");
+
+    assert!(commands.is_empty());
+  }
+
+  #[test]
+  fn test_commands_after_skips_seen_commands() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in 1..=3 {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let follower = Follower::open(store_path).expect("unable to open follower in test");
+
+    let all = follower
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(all.len(), 3);
+
+    let rest = follower
+      .commands_after(all[0].id)
+      .expect("unable to read commands in test");
+
+    let amounts: Vec<Add> = rest
+      .iter()
+      .map(|command| {
+        command
+          .deserialize()
+          .expect("unable to deserialize in test")
+      })
+      .collect();
+
+    assert_eq!(amounts, vec![Add(2), Add(3)]);
+    assert_eq!(rest[0].offset, 1);
+  }
+
+  #[test]
+  fn test_commands_record_when_they_were_logged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let now_ms = || {
+      SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock before epoch in test")
+        .as_millis() as u64
+    };
+
+    let before = now_ms();
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+    let after = now_ms();
+
+    let commands = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert!((before..=after).contains(&commands[0].recorded_at()));
+  }
+
+  #[test]
+  fn test_in_process_follower_wakes_on_append() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let follower = madeleine.follower();
+
+    let waiter = std::thread::spawn(move || {
+      follower
+        .wait_for_commands(Ulid::nil(), Duration::from_secs(10))
+        .expect("unable to wait for commands in test")
+    });
+
+    madeleine
+      .execute_command(Add(613))
+      .expect("unable to execute command in test");
+
+    let commands = waiter.join().expect("waiting thread panicked in test");
+
+    assert_eq!(commands.len(), 1);
+    assert_eq!(commands[0].deserialize::<Add>().ok(), Some(Add(613)));
+  }
+
+  #[test]
+  fn test_open_rejects_non_store() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    assert!(matches!(
+      Follower::open(temp_dir.path().to_path_buf()),
+      Err(MadeleineError::DirectoryError(_))
+    ));
+  }
+}
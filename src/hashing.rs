@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::madeleine_error::MadeleineError;
+
+/// Hash function used by a store's integrity features, such as state hashes, snapshot deduplication
+/// and export content hashes. It's chosen when the store is created and recorded in its metadata,
+/// so every hash within one store uses the same function.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum HashAlgo {
+  /// SHA-256, always available.
+  #[default]
+  Sha256,
+  /// BLAKE3, available with the `blake3` feature.
+  #[cfg(feature = "blake3")]
+  Blake3,
+  /// 128-bit XXH3, available with the `xxhash` feature. Fast, but not cryptographically secure.
+  #[cfg(feature = "xxhash")]
+  Xxh3,
+}
+
+impl HashAlgo {
+  /// Hex-encoded digest of some bytes.
+  pub fn hex_digest(self, bytes: &[u8]) -> String {
+    match self {
+      Self::Sha256 => to_hex(&Sha256::digest(bytes)),
+      #[cfg(feature = "blake3")]
+      Self::Blake3 => to_hex(blake3::hash(bytes).as_bytes()),
+      #[cfg(feature = "xxhash")]
+      Self::Xxh3 => to_hex(&xxhash_rust::xxh3::xxh3_128(bytes).to_be_bytes()),
+    }
+  }
+
+  /// Compute a stable hash of a value's canonical serialization.
+  /// The value is first converted to a `serde_json::Value`, whose maps are ordered by key,
+  /// so that e.g. two equal `HashMap`s always produce the same hash regardless of iteration order.
+  pub fn canonical_hash<T: Serialize>(self, value: &T) -> Result<String, MadeleineError> {
+    let canonical = serde_json::to_vec(&serde_json::to_value(value)?)?;
+
+    Ok(self.hex_digest(&canonical))
+  }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::export::{verify_export, ExportFormat, ExportRange};
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  /// Create a store using `hash_algo`, check that every integrity feature uses it, and return its state hash.
+  fn check_store_uses(temp_dir: &assert_fs::TempDir, hash_algo: HashAlgo) -> String {
+    let store_path = temp_dir.path().join(format!("{:?}", hash_algo));
+
+    let madeleine = Madeleine::new_with_hash_algo(store_path.clone(), hash_algo, || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(613))
+      .expect("unable to execute command in test");
+
+    let mut exported = Vec::new();
+
+    let manifest = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)
+      .expect("unable to export in test");
+
+    assert_eq!(manifest.hash_algo, hash_algo);
+    assert_eq!(manifest.content_hash, hash_algo.hex_digest(&exported));
+    assert!(verify_export(&manifest, exported.as_slice()).is_ok());
+
+    let state_hash = madeleine
+      .state_hash()
+      .expect("unable to hash state in test");
+
+    assert_eq!(
+      hash_algo.canonical_hash(&613_u64).ok(),
+      Some(state_hash.clone())
+    );
+
+    drop(madeleine);
+
+    let reopened =
+      Madeleine::new(store_path, || 0_u64).expect("unable to reopen madeleine in test");
+
+    assert_eq!(reopened.hash_algo(), hash_algo);
+
+    state_hash
+  }
+
+  #[test]
+  fn test_store_hash_algo_is_recorded_and_used() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let sha256_hash = check_store_uses(&temp_dir, HashAlgo::Sha256);
+
+    assert_eq!(sha256_hash.len(), 64);
+
+    #[cfg(feature = "blake3")]
+    assert!(check_store_uses(&temp_dir, HashAlgo::Blake3) != sha256_hash);
+
+    #[cfg(feature = "xxhash")]
+    assert_eq!(check_store_uses(&temp_dir, HashAlgo::Xxh3).len(), 32);
+  }
+
+  #[test]
+  fn test_mixing_hash_algos_is_refused() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let mut exported = Vec::new();
+
+    let mut manifest = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)
+      .expect("unable to export in test");
+
+    drop(madeleine);
+
+    assert!(Madeleine::new_with_hash_algo(store_path.clone(), HashAlgo::Sha256, || 0_u64).is_ok());
+
+    #[cfg(feature = "blake3")]
+    {
+      assert!(matches!(
+        Madeleine::new_with_hash_algo(store_path, HashAlgo::Blake3, || 0_u64),
+        Err(MadeleineError::HashAlgoMismatch(_))
+      ));
+
+      manifest.hash_algo = HashAlgo::Blake3;
+
+      assert!(verify_export(&manifest, exported.as_slice()).is_err());
+    }
+
+    manifest.content_hash = HashAlgo::Sha256.hex_digest(b"something else");
+
+    assert!(verify_export(&manifest, exported.as_slice()).is_err());
+  }
+}
@@ -0,0 +1,123 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::locks::{read_recovering, write_recovering};
+
+/// A callback run once an operation has succeeded, with what it acted on and how long it took,
+/// see `Madeleine::on_command_executed` and `Madeleine::on_snapshot_taken`.
+pub type ExecutionHook = Box<dyn Fn(&dyn Any, Duration) + Send + Sync>;
+
+/// A hook as kept in a `HookList`, shared so that it can be called without the list locked.
+type SharedHook = Arc<dyn Fn(&dyn Any, Duration) + Send + Sync>;
+
+/// The hooks of an instance for one kind of operation, called in the order they were added.
+#[derive(Default)]
+pub(crate) struct HookList {
+  hooks: RwLock<Vec<SharedHook>>,
+}
+
+impl HookList {
+  pub fn add(&self, hook: ExecutionHook) {
+    write_recovering(&self.hooks).push(Arc::from(hook));
+  }
+
+  pub fn is_empty(&self) -> bool {
+    read_recovering(&self.hooks).is_empty()
+  }
+
+  /// Call every hook, catching their panics so that a failing hook can't fail the operation it's told about.
+  /// Returns the number of hooks which panicked.
+  pub fn call(&self, subject: &dyn Any, duration: Duration) -> usize {
+    // Cloned, so that the hooks aren't called with the list locked.
+    let hooks = read_recovering(&self.hooks).clone();
+    let mut panicked = 0;
+
+    for hook in &hooks {
+      if let Err(_payload) = panic::catch_unwind(AssertUnwindSafe(|| hook(subject, duration))) {
+        panicked += 1;
+
+        #[cfg(feature = "tracing")]
+        tracing::error!(
+          panic = panic_message(_payload.as_ref()),
+          "a hook panicked, carrying on without it"
+        );
+      }
+    }
+
+    panicked
+  }
+}
+
+/// The message a panic was raised with, if it was raised with one.
+#[cfg(feature = "tracing")]
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  payload
+    .downcast_ref::<&str>()
+    .copied()
+    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+    .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use std::sync::Mutex;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_hooks_are_called_in_order_despite_panics() {
+    let hooks = HookList::default();
+    let calls = Arc::new(Mutex::new(Vec::new()));
+
+    for name in ["first", "panicking", "last"] {
+      let calls = calls.clone();
+
+      hooks.add(Box::new(move |subject, _duration| {
+        if name == "panicking" {
+          panic!("hook failed in test");
+        }
+
+        calls
+          .lock()
+          .expect("unable to lock calls in test")
+          .push((name, subject.downcast_ref::<usize>().copied()));
+      }));
+    }
+
+    assert_eq!(hooks.call(&7_usize, Duration::ZERO), 1);
+    assert_eq!(
+      *calls.lock().expect("unable to lock calls in test"),
+      vec![("first", Some(7)), ("last", Some(7))]
+    );
+  }
+
+  #[test]
+  fn test_panicking_hooks_dont_fail_commands_or_snapshots() {
+    let madeleine =
+      Madeleine::new_in_memory(|| 0).expect("unable to instantiate madeleine in test");
+
+    madeleine.on_command_executed(Box::new(|_command, _duration| {
+      panic!("hook failed in test")
+    }));
+    madeleine.on_snapshot_taken(Box::new(|_snapshot_id, _duration| {
+      panic!("hook failed in test")
+    }));
+
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    assert_eq!(madeleine.tap(|state| state), 2);
+    assert_eq!(madeleine.len(), 1);
+  }
+}
@@ -0,0 +1,449 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::command_log::CommandLog;
+use crate::durable::write_atomically;
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+
+pub(crate) const IDEMPOTENCY_FILE_NAME: &str = "idempotency";
+
+/// Result of executing a command under an idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotentOutcome<O> {
+  /// The key was new, so the command was executed and logged, producing this output.
+  Applied(O),
+  /// The key was seen before, so the command wasn't executed again. This is the output from the first time.
+  Replayed(O),
+  /// The key was seen before, so the command wasn't executed again,
+  /// but the output from the first time was too large to cache.
+  AlreadyApplied,
+}
+
+impl<O> IdempotentOutcome<O> {
+  /// The command's output, unless it wasn't cached.
+  pub fn output(self) -> Option<O> {
+    match self {
+      Self::Applied(output) | Self::Replayed(output) => Some(output),
+      Self::AlreadyApplied => None,
+    }
+  }
+
+  /// Determine if the command was executed by this call.
+  pub fn was_applied(&self) -> bool {
+    matches!(self, Self::Applied(_))
+  }
+}
+
+/// Limits on what's remembered about idempotency keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdempotencyOptions {
+  /// How long a key is remembered. Once it expires, a command with the same key is executed again.
+  pub ttl: Duration,
+  /// Largest serialized output, in bytes, which is cached. Keys with larger outputs are still remembered.
+  pub max_output_bytes: usize,
+  /// Most keys remembered. The oldest keys are forgotten first.
+  pub max_keys: usize,
+}
+
+impl Default for IdempotencyOptions {
+  fn default() -> Self {
+    Self {
+      ttl: Duration::from_secs(24 * 60 * 60),
+      max_output_bytes: 64 * 1024,
+      max_keys: 10_000,
+    }
+  }
+}
+
+/// What's remembered about a key.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct CachedOutcome {
+  pub command_id: Ulid,
+  recorded_at_ms: u128,
+  pub output: Option<Value>,
+  /// Whether the key was saved before its command was logged, and the command's outcome hasn't been saved since.
+  #[serde(default)]
+  pending: bool,
+}
+
+/// Idempotency keys seen by a store, persisted to a file in the store directory. A key is saved as pending
+/// before the command it was used with is logged, then again with the command's output once it's logged,
+/// so a crash in between can't lose a key whose command was logged.
+pub(crate) struct IdempotencyCache {
+  file_path: PathBuf,
+  options: Mutex<IdempotencyOptions>,
+  entries: Mutex<BTreeMap<String, CachedOutcome>>,
+}
+
+impl IdempotencyCache {
+  /// Read the keys a store has already seen.
+  pub fn load(location_dir_path: &Path) -> Result<Self, MadeleineError> {
+    let file_path = location_dir_path.join(IDEMPOTENCY_FILE_NAME);
+
+    let entries = if file_path.is_file() {
+      serde_json::from_slice(&fs::read(&file_path)?)?
+    } else {
+      BTreeMap::new()
+    };
+
+    Ok(Self {
+      file_path,
+      options: Mutex::new(IdempotencyOptions::default()),
+      entries: Mutex::new(entries),
+    })
+  }
+
+  pub fn set_options(&self, options: IdempotencyOptions) {
+    *lock_recovering(&self.options) = options;
+  }
+
+  /// What's remembered about an unexpired key. Called with the command lock held, so a key is only still pending
+  /// if its command was logged but its output couldn't be saved, in which case it has no output.
+  pub fn lookup(&self, key: &str) -> Result<Option<CachedOutcome>, MadeleineError> {
+    let now = now_ms();
+    let ttl = lock_recovering(&self.options).ttl.as_millis();
+
+    let cached = lock_recovering(&self.entries)
+      .get(key)
+      .filter(|cached| now.saturating_sub(cached.recorded_at_ms) < ttl)
+      .cloned();
+
+    Ok(cached)
+  }
+
+  /// Remember a key before its command, which will be logged as `command_id`, is appended to the log.
+  pub fn write_ahead(&self, key: &str, command_id: Ulid) -> Result<(), MadeleineError> {
+    self.insert(key, command_id, None, true)
+  }
+
+  /// Forget a pending key because it couldn't be saved or its command couldn't be logged. Any saved copy
+  /// is dropped the next time the store is opened, as its command isn't in the log.
+  pub fn abandon(&self, key: &str, command_id: Ulid) {
+    let mut entries = lock_recovering(&self.entries);
+
+    if entries
+      .get(key)
+      .is_some_and(|cached| cached.pending && cached.command_id == command_id)
+    {
+      entries.remove(key);
+    }
+  }
+
+  /// Remember a key and, if it's small enough, the output of its command.
+  pub fn record(&self, key: &str, command_id: Ulid, output: Value) -> Result<(), MadeleineError> {
+    let max_output_bytes = lock_recovering(&self.options).max_output_bytes;
+    let output_bytes = serde_json::to_vec(&output)?.len();
+    let output = (output_bytes <= max_output_bytes).then_some(output);
+
+    self.insert(key, command_id, output, false)
+  }
+
+  /// Settle the keys left pending by a crash: those whose commands were logged are kept without an output,
+  /// and the rest are forgotten so that retrying them executes them.
+  pub fn resolve_pending(&self, command_log: &CommandLog) -> Result<(), MadeleineError> {
+    let mut entries = lock_recovering(&self.entries);
+
+    let mut pending: HashSet<Ulid> = entries
+      .values()
+      .filter(|cached| cached.pending)
+      .map(|cached| cached.command_id)
+      .collect();
+
+    if pending.is_empty() {
+      return Ok(());
+    }
+
+    let mut logged = HashSet::new();
+
+    for command in command_log.commands() {
+      let command = command?;
+
+      if pending.remove(&command.id) {
+        logged.insert(command.id);
+      }
+
+      if pending.is_empty() {
+        break;
+      }
+    }
+
+    entries.retain(|_key, cached| !cached.pending || logged.contains(&cached.command_id));
+    entries
+      .values_mut()
+      .for_each(|cached| cached.pending = false);
+
+    write_atomically(&self.file_path, &serde_json::to_vec(&*entries)?)?;
+
+    Ok(())
+  }
+
+  fn insert(
+    &self,
+    key: &str,
+    command_id: Ulid,
+    output: Option<Value>,
+    pending: bool,
+  ) -> Result<(), MadeleineError> {
+    let options = *lock_recovering(&self.options);
+    let now = now_ms();
+    let ttl = options.ttl.as_millis();
+
+    let mut entries = lock_recovering(&self.entries);
+
+    entries.retain(|_key, cached| now.saturating_sub(cached.recorded_at_ms) < ttl);
+    entries.insert(
+      key.to_string(),
+      CachedOutcome {
+        command_id,
+        recorded_at_ms: now,
+        output,
+        pending,
+      },
+    );
+
+    while entries.len() > options.max_keys.max(1) {
+      let oldest = entries
+        .iter()
+        .min_by_key(|(_key, cached)| cached.recorded_at_ms)
+        .map(|(key, _cached)| key.clone());
+
+      match oldest {
+        Some(oldest) => entries.remove(&oldest),
+        None => break,
+      };
+    }
+
+    write_atomically(&self.file_path, &serde_json::to_vec(&*entries)?)?;
+
+    Ok(())
+  }
+
+  /// Forget the keys used with commands logged after `head_id`, e.g. because those commands were rolled back,
+  /// so that retrying them executes them again.
+  pub fn forget_after(&self, head_id: Ulid) -> Result<(), MadeleineError> {
+    let mut entries = lock_recovering(&self.entries);
+    let count = entries.len();
+
+    entries.retain(|_key, cached| cached.command_id <= head_id);
+
+    if entries.len() < count {
+      write_atomically(&self.file_path, &serde_json::to_vec(&*entries)?)?;
+    }
+
+    Ok(())
+  }
+}
+
+fn now_ms() -> u128 {
+  SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use std::sync::{Arc, Barrier};
+  use std::thread;
+
+  use crate::testing::{FailpointStore, StorageOperation};
+  use crate::{CommandWithOutput, Madeleine};
+
+  /// Adds to the total, returning the new total.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Deposit(u64);
+
+  impl CommandWithOutput<'_> for Deposit {
+    type SystemState = u64;
+    type Output = u64;
+
+    fn execute_with_output(&self, old_state: u64) -> (u64, u64) {
+      (old_state + self.0, old_state + self.0)
+    }
+  }
+
+  fn add(madeleine: &Madeleine<u64>, key: &str, amount: u64) -> IdempotentOutcome<u64> {
+    madeleine
+      .execute_idempotent_with_output(key, Deposit(amount))
+      .expect("unable to execute idempotent command in test")
+  }
+
+  #[test]
+  fn test_retry_receives_original_output() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    assert_eq!(
+      add(&madeleine, "first", 600),
+      IdempotentOutcome::Applied(600)
+    );
+    assert_eq!(
+      add(&madeleine, "second", 13),
+      IdempotentOutcome::Applied(613)
+    );
+    assert_eq!(
+      add(&madeleine, "first", 600),
+      IdempotentOutcome::Replayed(600)
+    );
+
+    drop(madeleine);
+
+    let reopened =
+      Madeleine::new(store_path, || 613_u64).expect("unable to instantiate madeleine in test");
+
+    assert_eq!(
+      add(&reopened, "second", 13),
+      IdempotentOutcome::Replayed(613)
+    );
+    assert_eq!(reopened.into_inner(), 613);
+  }
+
+  #[test]
+  fn test_concurrent_retries_execute_once() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Arc::new(
+      Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+        .expect("unable to instantiate madeleine in test"),
+    );
+    let barrier = Arc::new(Barrier::new(8));
+
+    let retries: Vec<_> = (0..8)
+      .map(|_| {
+        let madeleine = madeleine.clone();
+        let barrier = barrier.clone();
+
+        thread::spawn(move || {
+          barrier.wait();
+          add(&madeleine, "retried", 600)
+        })
+      })
+      .collect();
+
+    let outcomes: Vec<_> = retries
+      .into_iter()
+      .map(|retry| retry.join().expect("unable to join thread in test"))
+      .collect();
+
+    assert_eq!(
+      outcomes
+        .iter()
+        .filter(|outcome| outcome.was_applied())
+        .count(),
+      1
+    );
+    assert!(outcomes
+      .into_iter()
+      .all(|outcome| outcome.output() == Some(600)));
+    assert_eq!(madeleine.tap(|state| state), 600);
+    assert_eq!(madeleine.len(), 1);
+  }
+
+  #[test]
+  fn test_size_cap_and_ttl() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine.set_idempotency_options(IdempotencyOptions {
+      max_output_bytes: 1,
+      ..IdempotencyOptions::default()
+    });
+
+    assert_eq!(
+      add(&madeleine, "large", 613),
+      IdempotentOutcome::Applied(613)
+    );
+    assert_eq!(
+      add(&madeleine, "large", 613),
+      IdempotentOutcome::AlreadyApplied
+    );
+
+    madeleine.set_idempotency_options(IdempotencyOptions {
+      ttl: Duration::ZERO,
+      ..IdempotencyOptions::default()
+    });
+
+    assert!(add(&madeleine, "large", 1).was_applied());
+    assert_eq!(madeleine.into_inner(), 614);
+  }
+
+  #[test]
+  fn test_crash_after_logging_keeps_key() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.fail_nth(StorageOperation::IdempotencyRecord, 1);
+    madeleine
+      .set_failpoints(Some(failpoints))
+      .expect("unable to set failpoints in test");
+
+    assert!(madeleine
+      .execute_idempotent_with_output("crash", Deposit(600))
+      .is_err());
+
+    drop(madeleine);
+
+    let reopened = Madeleine::resume_replaying::<Deposit, _>(store_path, || 0_u64)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      add(&reopened, "crash", 600),
+      IdempotentOutcome::AlreadyApplied
+    );
+    assert_eq!(reopened.into_inner(), 600);
+  }
+
+  #[test]
+  fn test_crash_before_logging_forgets_key() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.fail_nth(StorageOperation::Append, 1);
+    madeleine
+      .set_failpoints(Some(failpoints))
+      .expect("unable to set failpoints in test");
+
+    assert!(madeleine
+      .execute_idempotent_with_output("crash", Deposit(600))
+      .is_err());
+    assert!(fs::read_to_string(store_path.join(IDEMPOTENCY_FILE_NAME))
+      .expect("unable to read idempotency keys in test")
+      .contains("crash"));
+
+    drop(madeleine);
+
+    let reopened = Madeleine::resume_replaying::<Deposit, _>(store_path, || 0_u64)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(add(&reopened, "crash", 13), IdempotentOutcome::Applied(13));
+    assert_eq!(reopened.into_inner(), 13);
+  }
+}
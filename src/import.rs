@@ -0,0 +1,566 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::admin_log::{self, AdminOperationKind};
+use crate::command::{Command, CommandContext};
+use crate::command_log::{CommandLog, MAX_PAYLOAD_BYTES};
+use crate::durable::write_atomically;
+use crate::madeleine::{command_log_dir_path, is_store_root, Madeleine};
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::{StoreFormat, StoreMetadata};
+
+pub(crate) const IMPORT_CHECKPOINT_FILE_NAME: &str = "import_checkpoint";
+pub(crate) const IMPORT_META_FILE_NAME: &str = "import_meta";
+
+/// One row of history from an existing application, converted for import.
+#[derive(Debug, Clone)]
+pub struct ImportedRow {
+  /// The command, serialized as JSON.
+  pub payload: Vec<u8>,
+  /// When the command originally happened, used as the timestamp of its ULID.
+  pub timestamp: SystemTime,
+  /// Optional metadata about the row, kept alongside the log in the `import_meta` file.
+  pub meta: Option<Value>,
+}
+
+/// Settings for an import.
+#[derive(Debug, Clone)]
+pub struct ImportOptions<SystemState> {
+  /// Number of rows imported between checkpoints.
+  pub checkpoint_every: u64,
+  /// If present, the imported commands are replayed into this state, which is then snapshotted.
+  pub initial_state: Option<SystemState>,
+}
+
+impl<SystemState> Default for ImportOptions<SystemState> {
+  fn default() -> Self {
+    Self {
+      checkpoint_every: 1000,
+      initial_state: None,
+    }
+  }
+}
+
+/// Outcome of an import.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportReport {
+  /// Total number of rows in the store's log once the import finished.
+  pub rows_imported: u64,
+  /// Number of rows which a previous, interrupted import had already checkpointed and which were skipped.
+  pub rows_resumed: u64,
+  /// Id of the snapshot taken at the end, if the commands were replayed.
+  pub snapshot_id: Option<usize>,
+}
+
+/// Progress of an import, persisted so that an interrupted import can pick up where it left off.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct ImportCheckpoint {
+  rows_imported: u64,
+  last_id: Option<Ulid>,
+  meta_bytes: u64,
+}
+
+impl ImportCheckpoint {
+  fn load(location_dir_path: &Path) -> Result<Option<Self>, MadeleineError> {
+    let checkpoint_path = location_dir_path.join(IMPORT_CHECKPOINT_FILE_NAME);
+
+    if checkpoint_path.is_file() {
+      let raw = fs::read(checkpoint_path)?;
+
+      Ok(Some(serde_json::from_slice(&raw)?))
+    } else {
+      Ok(None)
+    }
+  }
+
+  /// Replaced atomically, so that a crash while checkpointing leaves the previous checkpoint to resume from.
+  fn write(&self, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    write_atomically(
+      &location_dir_path.join(IMPORT_CHECKPOINT_FILE_NAME),
+      &serde_json::to_vec(self)?,
+    )?;
+
+    Ok(())
+  }
+}
+
+/// Create a store from the history of an existing application, e.g. the rows of an events table.
+///
+/// `rows` must yield the history in the order it should be replayed, and `mapper` converts each row.
+/// For an events table in a database, this is typically the rows of a query with an `ORDER BY` clause,
+/// which `from_query` runs itself for SQLite databases.
+/// Each command is logged under a ULID taken from its timestamp, bumped where needed so that ULIDs
+/// increase monotonically in row order. If `options.initial_state` is present, the commands are also
+/// deserialized as `C` and replayed into it, and the resulting state is snapshotted at the end.
+///
+/// Progress is checkpointed every `options.checkpoint_every` rows. If an import fails partway,
+/// calling this again with the same rows resumes after the last checkpoint, skipping the rows before it.
+pub fn from_rows<C, SystemState, R, I, M>(
+  location_dir_path: PathBuf,
+  rows: I,
+  mut mapper: M,
+  options: ImportOptions<SystemState>,
+) -> Result<ImportReport, MadeleineError>
+where
+  C: for<'a> Command<'a, SystemState = SystemState>,
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+  I: IntoIterator<Item = R>,
+  M: FnMut(R) -> Result<ImportedRow, MadeleineError>,
+{
+  let checkpoint = match ImportCheckpoint::load(&location_dir_path)? {
+    Some(checkpoint) => {
+      discard_after_checkpoint(&location_dir_path, &checkpoint)?;
+
+      checkpoint
+    }
+    None => {
+      require_fresh_directory(&location_dir_path)?;
+      fs::create_dir_all(&location_dir_path)?;
+
+      let checkpoint = ImportCheckpoint::default();
+      checkpoint.write(&location_dir_path)?;
+
+      checkpoint
+    }
+  };
+
+  StoreMetadata::open_for_write(&location_dir_path, StoreFormat::default())?;
+
+  let command_log = CommandLog::new(command_log_dir_path(&location_dir_path))?;
+  let rows_resumed = checkpoint.rows_imported;
+
+  let mut state = options.initial_state;
+
+  if let Some(initial_state) = state.take() {
+    let mut replayed = initial_state;
+
+    for logged in command_log.commands() {
+      let logged = logged?;
+      let command: C = logged.deserialize()?;
+
+      replayed = command.execute_with_ctx(replayed, &CommandContext::at(logged.offset));
+    }
+
+    state = Some(replayed);
+  }
+
+  let mut meta_file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(location_dir_path.join(IMPORT_META_FILE_NAME))?;
+
+  let mut progress = checkpoint;
+  let checkpoint_every = options.checkpoint_every.max(1);
+
+  for row in rows.into_iter().skip(rows_resumed as usize) {
+    let row = mapper(row)?;
+    let id = next_id(progress.last_id, row.timestamp)?;
+
+    let command: Value = serde_json::from_slice(&row.payload)?;
+    let entry = serde_json::to_vec(&(id, &command))?;
+
+    if entry.len() > MAX_PAYLOAD_BYTES {
+      return Err(MadeleineError::ImportError(format!(
+        "row {} is {} bytes, over the {} byte limit",
+        progress.rows_imported,
+        entry.len(),
+        MAX_PAYLOAD_BYTES
+      )));
+    }
+
+    if let Some(replayed) = state.take() {
+      let command: C = serde_json::from_value(command)?;
+      state = Some(command.execute_with_ctx(replayed, &CommandContext::at(progress.rows_imported)));
+    }
+
+    command_log.append_entry(&entry)?;
+
+    if let Some(meta) = row.meta {
+      let mut line = serde_json::to_vec(&(id, meta))?;
+      line.push(b'\n');
+      meta_file.write_all(&line)?;
+      progress.meta_bytes += line.len() as u64;
+    }
+
+    progress.rows_imported += 1;
+    progress.last_id = Some(id);
+
+    if progress.rows_imported % checkpoint_every == 0 {
+      command_log.flush()?;
+      meta_file.flush()?;
+      progress.write(&location_dir_path)?;
+    }
+  }
+
+  command_log.flush()?;
+  meta_file.flush()?;
+  progress.write(&location_dir_path)?;
+
+  drop(command_log);
+
+  if progress.meta_bytes == 0 {
+    fs::remove_file(location_dir_path.join(IMPORT_META_FILE_NAME))?;
+  }
+
+  admin_log::record(
+    &location_dir_path,
+    AdminOperationKind::Imported {
+      commands: progress.rows_imported,
+    },
+  )?;
+
+  let snapshot_id = match state {
+    Some(replayed) => {
+      let madeleine = Madeleine::new(location_dir_path.clone(), || replayed)?;
+
+      Some(madeleine.take_snapshot(true)?)
+    }
+    None => None,
+  };
+
+  fs::remove_file(location_dir_path.join(IMPORT_CHECKPOINT_FILE_NAME))?;
+
+  Ok(ImportReport {
+    rows_imported: progress.rows_imported,
+    rows_resumed,
+    snapshot_id,
+  })
+}
+
+/// Where `from_query` reads rows from.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub enum QuerySource<'c> {
+  /// A connection the application already has open.
+  Connection(&'c rusqlite::Connection),
+  /// The path of a database file, opened read-only for the import.
+  Path(PathBuf),
+}
+
+#[cfg(feature = "sqlite")]
+impl<'c> From<&'c rusqlite::Connection> for QuerySource<'c> {
+  fn from(connection: &'c rusqlite::Connection) -> Self {
+    Self::Connection(connection)
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<PathBuf> for QuerySource<'_> {
+  fn from(path: PathBuf) -> Self {
+    Self::Path(path)
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<&Path> for QuerySource<'_> {
+  fn from(path: &Path) -> Self {
+    Self::Path(path.to_path_buf())
+  }
+}
+
+/// Create a store from the rows of a SQL query against a SQLite database, as `from_rows` does.
+///
+/// `sql` must yield the history in the order it should be replayed, typically with an `ORDER BY` clause,
+/// and `mapper` converts each row. Resuming an interrupted import runs the query again and skips the rows
+/// imported before the last checkpoint, so the query must yield the same rows in the same order each time.
+/// Fails with `MadeleineError::SqliteError` if the database can't be opened or the query fails.
+#[cfg(feature = "sqlite")]
+pub fn from_query<'c, C, SystemState, M>(
+  location_dir_path: PathBuf,
+  source: impl Into<QuerySource<'c>>,
+  sql: &str,
+  mut mapper: M,
+  options: ImportOptions<SystemState>,
+) -> Result<ImportReport, MadeleineError>
+where
+  C: for<'a> Command<'a, SystemState = SystemState>,
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+  M: FnMut(&rusqlite::Row<'_>) -> Result<ImportedRow, MadeleineError>,
+{
+  let opened;
+  let connection = match source.into() {
+    QuerySource::Connection(connection) => connection,
+    QuerySource::Path(path) => {
+      opened =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+      &opened
+    }
+  };
+
+  let mut statement = connection.prepare(sql)?;
+  let rows = statement.query_map([], |row| Ok(mapper(row)))?;
+
+  from_rows::<C, _, _, _, _>(location_dir_path, rows, |row| row?, options)
+}
+
+/// Read the metadata recorded for imported rows, keyed by the ULID of each row's command.
+pub fn imported_meta(location_dir_path: &Path) -> Result<Vec<(Ulid, Value)>, MadeleineError> {
+  let meta_path = location_dir_path.join(IMPORT_META_FILE_NAME);
+
+  if !meta_path.is_file() {
+    return Ok(Vec::new());
+  }
+
+  let raw = fs::read(meta_path)?;
+  let mut entries = Vec::new();
+
+  for line in raw
+    .split(|byte| *byte == b'\n')
+    .filter(|line| !line.is_empty())
+  {
+    entries.push(serde_json::from_slice(line)?);
+  }
+
+  Ok(entries)
+}
+
+/// Imports start from nothing, unless resuming from a checkpoint.
+fn require_fresh_directory(location_dir_path: &Path) -> Result<(), MadeleineError> {
+  let is_empty = !location_dir_path.exists() || fs::read_dir(location_dir_path)?.next().is_none();
+
+  if is_empty && !is_store_root(location_dir_path) {
+    Ok(())
+  } else {
+    Err(MadeleineError::ImportError(format!(
+      "{} must be missing or empty to import into it",
+      location_dir_path.display()
+    )))
+  }
+}
+
+/// Throw away anything an interrupted import wrote after its last checkpoint.
+fn discard_after_checkpoint(
+  location_dir_path: &Path,
+  checkpoint: &ImportCheckpoint,
+) -> Result<(), MadeleineError> {
+  let log_dir_path = command_log_dir_path(location_dir_path);
+
+  if checkpoint.rows_imported == 0 {
+    if log_dir_path.is_dir() {
+      fs::remove_dir_all(&log_dir_path)?;
+    }
+  } else {
+    CommandLog::new(log_dir_path)?.truncate(checkpoint.rows_imported)?;
+  }
+
+  let meta_path = location_dir_path.join(IMPORT_META_FILE_NAME);
+
+  if meta_path.is_file() {
+    OpenOptions::new()
+      .write(true)
+      .open(meta_path)?
+      .set_len(checkpoint.meta_bytes)?;
+  }
+
+  Ok(())
+}
+
+/// A ULID for the given timestamp which sorts after the previous one, even if the timestamps don't.
+fn next_id(previous: Option<Ulid>, timestamp: SystemTime) -> Result<Ulid, MadeleineError> {
+  let candidate = Ulid::from_datetime(timestamp);
+
+  match previous {
+    Some(previous) if candidate.timestamp_ms() <= previous.timestamp_ms() => {
+      previous.increment().ok_or_else(|| {
+        MadeleineError::ImportError(String::from("ran out of ULIDs within one millisecond"))
+      })
+    }
+    _ => Ok(candidate),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use pretty_assertions::assert_eq;
+  use serde_json::json;
+
+  use crate::follower::Follower;
+  use crate::testing::Add;
+
+  /// Rows as they might come out of an events table: amount and seconds since the epoch.
+  fn events() -> Vec<(u64, u64)> {
+    vec![(1, 100), (2, 100), (3, 99), (4, 200), (5, 300)]
+  }
+
+  fn mapper((amount, seconds): (u64, u64)) -> Result<ImportedRow, MadeleineError> {
+    Ok(ImportedRow {
+      payload: serde_json::to_vec(&Add(amount))?,
+      timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+      meta: (amount % 2 == 0).then(|| json!({ "even": amount })),
+    })
+  }
+
+  fn options() -> ImportOptions<u64> {
+    ImportOptions {
+      checkpoint_every: 2,
+      initial_state: Some(0),
+    }
+  }
+
+  #[test]
+  fn test_import_replays_and_snapshots() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let report = from_rows::<Add, _, _, _, _>(store_path.clone(), events(), mapper, options())
+      .expect("unable to import in test");
+
+    assert_eq!(
+      report,
+      ImportReport {
+        rows_imported: 5,
+        rows_resumed: 0,
+        snapshot_id: Some(0),
+      }
+    );
+
+    let commands = Follower::open(store_path.clone())
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read commands in test");
+
+    assert!(commands.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert_eq!(commands[3].id.timestamp_ms(), 200_000);
+
+    let meta = imported_meta(&store_path).expect("unable to read meta in test");
+
+    assert_eq!(meta.len(), 2);
+    assert_eq!(meta[1], (commands[3].id, json!({ "even": 4 })));
+
+    let resumed: Madeleine<u64> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.into_inner(), 15);
+  }
+
+  #[cfg(feature = "sqlite")]
+  #[test]
+  fn test_import_from_query() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let database_path = temp_dir.path().join("events.db");
+    let connection =
+      rusqlite::Connection::open(&database_path).expect("unable to open database in test");
+
+    connection
+      .execute(
+        "CREATE TABLE events (amount INTEGER NOT NULL, at INTEGER NOT NULL)",
+        [],
+      )
+      .expect("unable to create table in test");
+
+    for (amount, seconds) in events().into_iter().rev() {
+      connection
+        .execute(
+          "INSERT INTO events (amount, at) VALUES (?1, ?2)",
+          [amount, seconds],
+        )
+        .expect("unable to insert row in test");
+    }
+
+    let row_mapper = |row: &rusqlite::Row<'_>| mapper((row.get(0)?, row.get(1)?));
+    let sql = "SELECT amount, at FROM events ORDER BY amount";
+
+    let from_connection = temp_dir.path().join("from_connection");
+    let report = from_query::<Add, _, _>(
+      from_connection.clone(),
+      &connection,
+      sql,
+      row_mapper,
+      options(),
+    )
+    .expect("unable to import in test");
+
+    assert_eq!(report.rows_imported, 5);
+
+    let from_path = temp_dir.path().join("from_path");
+    from_query::<Add, _, _>(
+      from_path.clone(),
+      database_path.as_path(),
+      sql,
+      row_mapper,
+      options(),
+    )
+    .expect("unable to import in test");
+
+    for store_path in [from_connection, from_path] {
+      let resumed: Madeleine<u64> =
+        Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+      assert_eq!(resumed.into_inner(), 15);
+    }
+
+    let failed = from_query::<Add, _, _>(
+      temp_dir.path().join("failed"),
+      &connection,
+      "SELECT amount FROM missing",
+      row_mapper,
+      options(),
+    );
+
+    assert!(matches!(failed, Err(MadeleineError::SqliteError(_))));
+  }
+
+  #[test]
+  fn test_interrupted_import_resumes_from_checkpoint() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let failing_mapper = |(amount, seconds): (u64, u64)| {
+      if amount == 4 {
+        Err(MadeleineError::ImportError(String::from(
+          "source went away",
+        )))
+      } else {
+        mapper((amount, seconds))
+      }
+    };
+
+    let failed =
+      from_rows::<Add, _, _, _, _>(store_path.clone(), events(), failing_mapper, options());
+
+    assert!(matches!(failed, Err(MadeleineError::ImportError(_))));
+
+    let report = from_rows::<Add, _, _, _, _>(store_path.clone(), events(), mapper, options())
+      .expect("unable to import in test");
+
+    assert_eq!(report.rows_imported, 5);
+    assert_eq!(report.rows_resumed, 2);
+    assert_eq!(
+      imported_meta(&store_path)
+        .expect("unable to read meta in test")
+        .len(),
+      2
+    );
+
+    let resumed: Madeleine<u64> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.into_inner(), 15);
+  }
+
+  #[test]
+  fn test_import_requires_fresh_directory() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    Madeleine::new(store_path.clone(), || 0_u64).expect("unable to instantiate madeleine in test");
+
+    let result = from_rows::<Add, _, _, _, _>(store_path, events(), mapper, options());
+
+    assert!(matches!(result, Err(MadeleineError::ImportError(_))));
+  }
+}
@@ -0,0 +1,247 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::admin_log::{AdminMarker, AdminOperation};
+use crate::command_log::CommandLog;
+use crate::compaction::CompactionStage;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::entry_id;
+
+/// How thoroughly a store is checked when it's opened after a crash,
+/// i.e. when it wasn't shut down with `Madeleine::close`.
+/// After a clean shutdown, verification is skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerificationLevel {
+  /// Never verify.
+  Off,
+  /// Read every entry in the command log, checking that each decodes and that ULID timestamps never go backwards.
+  #[default]
+  Full,
+}
+
+/// What happened while opening a store, returned by `Madeleine::open_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenReport {
+  /// Whether the store was last shut down cleanly, with the log ending where it did at shutdown.
+  pub clean_shutdown: bool,
+  /// The verification run because the shutdown wasn't clean, if any.
+  pub verification: Option<VerificationLevel>,
+  /// Number of command log entries verified.
+  pub entries_verified: u64,
+  /// ULID of the last command in the log, or nil if it's empty.
+  pub head_id: Ulid,
+  /// The stage an interrupted compaction had reached, if one was finished or undone while opening.
+  pub compaction_recovered: Option<CompactionStage>,
+  /// Position of the latest administrative operation, to keep and pass to `MadeleineBuilder::admin_ops_since` next time.
+  pub admin_marker: AdminMarker,
+  /// Administrative operations since the marker passed to `MadeleineBuilder::admin_ops_since`, if one was passed.
+  pub admin_ops_since: Option<Vec<AdminOperation>>,
+}
+
+/// Written to the store's metadata by `Madeleine::close`, and cleared whenever the store is opened for writing,
+/// so that its presence on open proves the previous session ended cleanly.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct CleanShutdown {
+  /// ULID of the last command in the log at shutdown, or nil if it was empty.
+  pub head_id: Ulid,
+  /// Canonical hash of the state at shutdown.
+  pub state_hash: String,
+}
+
+/// Decide whether the previous session ended cleanly, verifying the log to the configured level if not.
+pub(crate) fn check_on_open(
+  command_log: &CommandLog,
+  clean_shutdown: Option<CleanShutdown>,
+  level: VerificationLevel,
+  compaction_recovered: Option<CompactionStage>,
+  admin_marker: AdminMarker,
+) -> Result<OpenReport, MadeleineError> {
+  let head_id = command_log.last_id()?.unwrap_or_else(Ulid::nil);
+
+  if clean_shutdown.is_some_and(|marker| marker.head_id == head_id) {
+    return Ok(OpenReport {
+      clean_shutdown: true,
+      verification: None,
+      entries_verified: 0,
+      head_id,
+      compaction_recovered,
+      admin_marker,
+      admin_ops_since: None,
+    });
+  }
+
+  let entries_verified = match level {
+    VerificationLevel::Off => 0,
+    VerificationLevel::Full => verify_log(command_log)?,
+  };
+
+  Ok(OpenReport {
+    clean_shutdown: false,
+    verification: Some(level),
+    entries_verified,
+    head_id,
+    compaction_recovered,
+    admin_marker,
+    admin_ops_since: None,
+  })
+}
+
+/// Check every entry in the log decodes, and that ULID timestamps never go backwards, returning the number of entries.
+/// Only timestamps are compared, as stores written before ULIDs were generated monotonically
+/// may have ULIDs out of order within a millisecond.
+pub(crate) fn verify_log(command_log: &CommandLog) -> Result<u64, MadeleineError> {
+  let mut previous = Ulid::nil();
+  let mut entries = 0;
+
+  command_log.for_each_entry(|offset, entry| {
+    let id = entry_id(entry).map_err(|error| {
+      MadeleineError::VerificationFailed(format!("entry {} doesn't decode: {}", offset, error))
+    })?;
+
+    if id.timestamp_ms() < previous.timestamp_ms() {
+      return Err(MadeleineError::VerificationFailed(format!(
+        "entry {} has ULID {}, which is older than {}",
+        offset, id, previous
+      )));
+    }
+
+    previous = id;
+    entries += 1;
+
+    Ok(())
+  })?;
+
+  Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::madeleine::command_log_dir_path;
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_clean_shutdown_skips_verification() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2, 3] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let head_id = madeleine.head_id().expect("unable to read head in test");
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    let reopened = Madeleine::<u64>::resume(store_path.clone()).expect("unable to resume in test");
+
+    assert_eq!(
+      reopened.open_report(),
+      &OpenReport {
+        clean_shutdown: true,
+        verification: None,
+        entries_verified: 0,
+        head_id,
+        compaction_recovered: None,
+        admin_marker: AdminMarker::start(),
+        admin_ops_since: None,
+      }
+    );
+
+    // Dropping without closing looks like a crash, as the marker was cleared on open.
+    drop(reopened);
+
+    let reopened = Madeleine::<u64>::resume(store_path).expect("unable to resume in test");
+
+    assert_eq!(
+      reopened.open_report(),
+      &OpenReport {
+        clean_shutdown: false,
+        verification: Some(VerificationLevel::Full),
+        entries_verified: 3,
+        head_id,
+        compaction_recovered: None,
+        admin_marker: AdminMarker::start(),
+        admin_ops_since: None,
+      }
+    );
+  }
+
+  #[test]
+  fn test_crash_runs_configured_verification() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    // Another writer appends behind the store's back, so the log no longer ends at the marker.
+    let command_log =
+      CommandLog::new(command_log_dir_path(&store_path)).expect("unable to open log in test");
+    let entry =
+      serde_json::to_vec(&(Ulid::nil(), Add(3))).expect("unable to serialize entry in test");
+
+    command_log
+      .append_entry(&entry)
+      .and_then(|_offset| command_log.flush())
+      .expect("unable to append entry in test");
+
+    drop(command_log);
+
+    let unverified = Madeleine::builder(store_path.clone())
+      .verification(VerificationLevel::Off)
+      .build(|| 0_u64)
+      .expect("unable to open without verification in test");
+
+    assert_eq!(
+      unverified.open_report().verification,
+      Some(VerificationLevel::Off)
+    );
+    assert!(!unverified.open_report().clean_shutdown);
+
+    unverified
+      .close()
+      .expect("unable to close madeleine in test");
+
+    let clean = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to reopen after clean shutdown in test");
+
+    assert!(clean.open_report().clean_shutdown);
+
+    drop(clean);
+
+    let verified = Madeleine::new(store_path, || 0_u64);
+
+    assert!(matches!(
+      verified,
+      Err(MadeleineError::VerificationFailed(ref description)) if description.contains("entry 2")
+    ));
+  }
+}
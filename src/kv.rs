@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::command::Command;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::store_path::StorePath;
+
+/// Errors from the key-value convenience layer.
+#[derive(Error, Debug)]
+pub enum KvError {
+  /// Keys must not be empty.
+  #[error("Keys must not be empty")]
+  EmptyKey,
+  /// Only integer values can be incremented.
+  #[error("Value at key '{0}' is not an integer")]
+  NotAnInteger(String),
+  /// Incrementing would overflow a 64-bit integer.
+  #[error("Incrementing key '{0}' would overflow")]
+  Overflow(String),
+  /// Error from the underlying Madeleine instance.
+  #[error(transparent)]
+  Madeleine(#[from] MadeleineError),
+}
+
+/// System state of a key-value store: string keys mapped to JSON values.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct KvState {
+  entries: BTreeMap<String, Value>,
+}
+
+impl KvState {
+  /// Get the value stored at a key.
+  pub fn get(&self, key: &str) -> Option<&Value> {
+    self.entries.get(key)
+  }
+
+  /// Number of keys in the store.
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Determine if the store holds no keys.
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// Iterate over the entries in key order.
+  pub fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+    self.entries.iter()
+  }
+}
+
+/// Operations on a key-value store.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum KvCommand {
+  /// Store a value at a key, replacing any previous value.
+  Set(String, Value),
+  /// Remove a key.
+  Delete(String),
+  /// Add to the integer at a key, treating a missing key as zero.
+  Increment(String, i64),
+  /// Remove every key.
+  Clear,
+}
+
+impl KvCommand {
+  /// Check that the command can be applied to a state.
+  pub fn validate(&self, state: &KvState) -> Result<(), KvError> {
+    match self {
+      Self::Set(key, _) | Self::Delete(key) if key.is_empty() => Err(KvError::EmptyKey),
+      Self::Increment(key, _) if key.is_empty() => Err(KvError::EmptyKey),
+      Self::Increment(key, amount) => match state.get(key) {
+        None => Ok(()),
+        Some(value) => value
+          .as_i64()
+          .ok_or_else(|| KvError::NotAnInteger(key.to_string()))?
+          .checked_add(*amount)
+          .map(|_| ())
+          .ok_or_else(|| KvError::Overflow(key.to_string())),
+      },
+      _ => Ok(()),
+    }
+  }
+}
+
+impl Command<'_> for KvCommand {
+  type SystemState = KvState;
+
+  /// Apply the command. Commands which fail validation leave the state unchanged, so replay is deterministic.
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    if self.validate(&old_state).is_err() {
+      return old_state;
+    }
+
+    let mut new_state = old_state;
+
+    match self {
+      Self::Set(key, value) => {
+        new_state.entries.insert(key.to_string(), value.clone());
+      }
+      Self::Delete(key) => {
+        new_state.entries.remove(key);
+      }
+      Self::Increment(key, amount) => {
+        let current = new_state.get(key).and_then(Value::as_i64).unwrap_or(0);
+        new_state
+          .entries
+          .insert(key.to_string(), Value::from(current + amount));
+      }
+      Self::Clear => new_state.entries.clear(),
+    }
+
+    new_state
+  }
+
+  fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+    KvCommand::validate(self, state).map_err(|error| error.to_string())
+  }
+}
+
+/// A Madeleine instance holding a `KvState`, with convenience methods for each `KvCommand`.
+pub struct KvMadeleine {
+  madeleine: Madeleine<KvState>,
+}
+
+impl KvMadeleine {
+  /// Create an empty key-value store, or reopen one, at a location.
+  pub fn new(location: impl Into<StorePath>) -> Result<Self, KvError> {
+    let madeleine = Madeleine::new(location, KvState::default)?;
+
+    Ok(Self { madeleine })
+  }
+
+  /// Resume a key-value store from disk.
+  pub fn resume(location: impl Into<StorePath>) -> Result<Self, KvError> {
+    let madeleine = Madeleine::resume(location)?;
+
+    Ok(Self { madeleine })
+  }
+
+  /// Store a value at a key.
+  pub fn set(&self, key: &str, value: Value) -> Result<(), KvError> {
+    self.execute(KvCommand::Set(key.to_string(), value))
+  }
+
+  /// Get a clone of the value stored at a key.
+  pub fn get(&self, key: &str) -> Result<Option<Value>, KvError> {
+    let value = self.madeleine.tap_ref(|state| state.get(key).cloned())?;
+
+    Ok(value)
+  }
+
+  /// Add to the integer at a key, returning the new value.
+  pub fn incr(&self, key: &str, amount: i64) -> Result<i64, KvError> {
+    self.execute(KvCommand::Increment(key.to_string(), amount))?;
+
+    let value = self
+      .madeleine
+      .tap_ref(|state| state.get(key).and_then(Value::as_i64).unwrap_or(0))?;
+
+    Ok(value)
+  }
+
+  /// Remove a key.
+  pub fn delete(&self, key: &str) -> Result<(), KvError> {
+    self.execute(KvCommand::Delete(key.to_string()))
+  }
+
+  /// Remove every key.
+  pub fn clear(&self) -> Result<(), KvError> {
+    self.execute(KvCommand::Clear)
+  }
+
+  /// Access the underlying Madeleine instance, e.g. to take snapshots.
+  pub fn inner(&self) -> &Madeleine<KvState> {
+    &self.madeleine
+  }
+
+  /// Consume the wrapper and return its state.
+  pub fn into_inner(self) -> KvState {
+    self.madeleine.into_inner()
+  }
+
+  /// Validate a command against the current state, then execute it.
+  fn execute(&self, command: KvCommand) -> Result<(), KvError> {
+    self.madeleine.tap_ref(|state| command.validate(state))??;
+
+    self.madeleine.execute_command(command)?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde_json::json;
+
+  #[test]
+  fn test_set_get_incr_delete() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let kv = KvMadeleine::new(temp_dir.path().join("test_store"))
+      .expect("unable to instantiate kv store in test");
+
+    kv.set("name", json!("panda"))
+      .expect("unable to set key in test");
+
+    assert_eq!(kv.incr("count", 612).ok(), Some(612));
+    assert_eq!(kv.incr("count", 1).ok(), Some(613));
+    assert_eq!(kv.get("name").ok(), Some(Some(json!("panda"))));
+
+    kv.delete("name").expect("unable to delete key in test");
+
+    assert_eq!(kv.get("name").ok(), Some(None));
+
+    kv.clear().expect("unable to clear store in test");
+
+    assert!(kv.into_inner().is_empty());
+  }
+
+  #[test]
+  fn test_validation_rejects_without_logging() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let kv = KvMadeleine::new(temp_dir.path().join("test_store"))
+      .expect("unable to instantiate kv store in test");
+
+    kv.set("name", json!("panda"))
+      .expect("unable to set key in test");
+    kv.set("max", json!(i64::MAX))
+      .expect("unable to set key in test");
+
+    assert!(matches!(kv.set("", json!(1)), Err(KvError::EmptyKey)));
+    assert!(matches!(kv.incr("name", 1), Err(KvError::NotAnInteger(_))));
+    assert!(matches!(kv.incr("max", 1), Err(KvError::Overflow(_))));
+    assert_eq!(kv.inner().len(), 2);
+  }
+
+  #[test]
+  fn test_resume_and_replay() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let kv = KvMadeleine::new(store_path.clone()).expect("unable to instantiate kv store in test");
+
+    let commands = [
+      KvCommand::Set("name".to_string(), json!("panda")),
+      KvCommand::Increment("count".to_string(), 613),
+      KvCommand::Set("tags".to_string(), json!(["black", "white"])),
+      KvCommand::Delete("name".to_string()),
+    ];
+
+    for command in commands.iter().cloned() {
+      kv.execute(command)
+        .expect("unable to execute command in test");
+    }
+
+    kv.inner()
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let expected = kv.into_inner();
+
+    let replayed = commands
+      .iter()
+      .fold(KvState::default(), |state, command| command.execute(state));
+
+    assert_eq!(replayed, expected);
+
+    let resumed = KvMadeleine::resume(store_path).expect("unable to resume kv store in test");
+
+    assert_eq!(resumed.get("count").ok(), Some(Some(json!(613))));
+    assert_eq!(resumed.into_inner(), expected);
+  }
+}
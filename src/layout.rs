@@ -0,0 +1,113 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::madeleine::{is_reserved_entry_name, COMMAND_LOG_DIR_NAME};
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::StoreMetadata;
+
+/// Where a store keeps its command log and snapshots within its directory.
+/// Chosen when the store is created, see `MadeleineBuilder::command_log_dir_name`, and recorded in its metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct StoreLayout {
+  /// Name of the directory holding the command log.
+  pub command_log_dir_name: String,
+  /// Name of the directory holding snapshots, or `None` if they're kept in the store directory itself.
+  pub snapshot_dir_name: Option<String>,
+}
+
+impl Default for StoreLayout {
+  fn default() -> Self {
+    Self {
+      command_log_dir_name: String::from(COMMAND_LOG_DIR_NAME),
+      snapshot_dir_name: None,
+    }
+  }
+}
+
+impl fmt::Display for StoreLayout {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "command log in {:?}", self.command_log_dir_name)?;
+
+    match &self.snapshot_dir_name {
+      Some(snapshot_dir_name) => write!(f, " and snapshots in {:?}", snapshot_dir_name),
+      None => write!(f, " and snapshots in the store directory"),
+    }
+  }
+}
+
+impl StoreLayout {
+  /// The layout of the store at `location_dir_path`, read from its metadata without creating any.
+  /// A store without metadata has the default layout, as does one whose metadata can't be read,
+  /// which fails to open for that reason anyway.
+  pub fn of(location_dir_path: &Path) -> Self {
+    StoreMetadata::read_layout(location_dir_path).unwrap_or_default()
+  }
+
+  /// Location of the command log of a store with this layout.
+  pub fn command_log_dir_path(&self, location_dir_path: &Path) -> PathBuf {
+    location_dir_path.join(&self.command_log_dir_name)
+  }
+
+  /// Location of the snapshots of a store with this layout.
+  pub fn snapshot_dir_path(&self, location_dir_path: &Path) -> PathBuf {
+    match &self.snapshot_dir_name {
+      Some(snapshot_dir_name) => location_dir_path.join(snapshot_dir_name),
+      None => location_dir_path.to_path_buf(),
+    }
+  }
+
+  /// Determine if an entry in the store directory is one of the directories the layout names.
+  pub fn names_entry(&self, file_name: &str) -> bool {
+    self.command_log_dir_name == file_name || self.snapshot_dir_name.as_deref() == Some(file_name)
+  }
+
+  /// Fail with `MadeleineError::ConfigurationError` unless both directory names are usable, see `dir_name_issue`,
+  /// and tell the directories apart.
+  pub fn validate(&self) -> Result<(), MadeleineError> {
+    let names = std::iter::once(("command log", &self.command_log_dir_name)).chain(
+      self
+        .snapshot_dir_name
+        .iter()
+        .map(|snapshot_dir_name| ("snapshot", snapshot_dir_name)),
+    );
+
+    for (dir, name) in names {
+      if let Some(issue) = dir_name_issue(name) {
+        return Err(MadeleineError::ConfigurationError(format!(
+          "{} directory name {:?} {}",
+          dir, name, issue
+        )));
+      }
+    }
+
+    if self.snapshot_dir_name.as_ref() == Some(&self.command_log_dir_name) {
+      return Err(MadeleineError::ConfigurationError(format!(
+        "the command log and snapshots can't share the directory {:?}",
+        self.command_log_dir_name
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+/// Why `name` can't name a directory in a store's layout, if it can't:
+/// it must be a single path component, and not the name of one of the store's other files.
+pub(crate) fn dir_name_issue(name: &str) -> Option<String> {
+  let mut components = Path::new(name).components();
+
+  match (components.next(), components.next()) {
+    (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => {
+      if is_reserved_entry_name(name) {
+        Some(String::from("is taken by another of the store's files"))
+      } else {
+        None
+      }
+    }
+    _ => Some(String::from(
+      "must be a single path component, such as \"commands\"",
+    )),
+  }
+}
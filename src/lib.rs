@@ -3,14 +3,172 @@
 //! Transparent object persistence in the tradition of Ruby's [`madeleine` gem](https://github.com/ghostganz/madeleine).
 //! In turn, that's inspired by Java's earlier [Prevalayer](https://prevayler.org/).
 
+/// Recording administrative operations, so services can learn what changed while they were away.
+pub mod admin_log;
+/// Checking how much a command allocated once it returns, discarding it if that was too much.
+#[cfg(feature = "allocation-check")]
+pub mod allocation_check;
+/// Audit export of command history, for ingestion into a SIEM.
+pub mod audit;
+/// Executing several commands at once, atomically or skipping those which fail validation.
+pub mod batch;
+/// Configuring a store before creating or resuming it.
+pub mod builder;
+/// Stopping long-running operations part way through.
+pub mod cancellation;
+/// Pluggable compression for snapshots, logged commands and exports.
+pub mod codec;
 /// Module containing types and logic for Command implementations.
 pub mod command;
 mod command_log;
+/// Versioning logged commands, and migrating older versions as they're read back.
+pub mod command_migration;
+/// How a store's command log is kept on disk.
+pub mod command_store;
+/// Replacing the command log's history with a snapshot, safely across crashes.
+pub mod compaction;
+/// Configuring a store from a file.
+pub mod config;
+/// Detecting commands whose replay doesn't reproduce the state they produced.
+pub mod determinism;
+/// Rules about the contents of store directories.
+pub mod directory_policy;
+/// Capturing a whole store in a byte blob, and restoring it.
+pub mod dump;
+mod durable;
+/// Events affecting a store's health.
+pub mod events;
+/// Exporting commands with a self-describing manifest, and importing them again.
+pub mod export;
+/// Following a store's command log as it grows.
+pub mod follower;
+mod hashing;
+/// Callbacks run after commands are executed and snapshots are taken.
+pub mod hooks;
+/// Executing commands at most once per idempotency key.
+pub mod idempotency;
+/// Importing history from applications which didn't use Madeleine.
+pub mod import;
+/// Detecting crashes, and verifying stores which weren't shut down cleanly.
+pub mod integrity;
+/// Ready-made key-value store built on the public API.
+#[cfg(feature = "kv")]
+pub mod kv;
+mod layout;
+mod locks;
+/// Commands as read back from the log.
+pub mod logged_command;
 /// High-level public interface.
 pub mod madeleine;
 /// Error type.
 pub mod madeleine_error;
+/// Running maintenance tasks, such as snapshots and log verification, on a schedule.
+pub mod maintenance;
+/// Merging the histories of two stores which diverged.
+pub mod merge;
+mod metadata;
+/// Runtime counters and latency histograms.
+pub mod metrics;
+/// Hooks called around the execution of each command.
+pub mod middleware;
+/// Migrating a store's state to a new type.
+pub mod migration;
+/// How commands are serialized in the command log.
+pub mod payload_format;
+/// Commonly used items, for importing with `use madeleine::prelude::*`.
+pub mod prelude;
+mod projection;
+/// Read-only queries over a store's state, and caching their outputs.
+pub mod query;
+/// Resource limits and warnings ahead of them.
+pub mod quota;
+/// Limiting how quickly commands are executed.
+pub mod rate_limit;
+/// Borrowing a store's state without closures.
+pub mod read_guard;
+/// Read-only replicas of stores written elsewhere.
+pub mod read_only;
+/// Reporting for repairs of derived bookkeeping.
+pub mod rebuild_report;
+/// Built-in command for reconciling the state with a desired value.
+pub mod reconcile;
+/// Redacting logged commands in bulk.
+pub mod redaction;
+/// Process-wide registry of open stores, for diagnostics.
+#[cfg(feature = "registry")]
+pub mod registry;
+mod sealed;
+/// Global ordering of commands across stores.
+pub mod sequencer;
+/// Sharing an instance between threads.
+pub mod shared;
+mod snapshot_diff;
+/// What happens when a scheduled snapshot fails.
+pub mod snapshot_failure;
+/// Labelling snapshots, to find them by name.
+pub mod snapshot_label;
+/// Taking snapshots automatically as commands are executed.
+pub mod snapshot_policy;
+/// Paths of store root directories, told apart from the files and directories inside stores.
+pub mod store_path;
+/// Subscriptions to appended commands.
+pub mod subscription;
+/// Tagging commands with metadata for auditing.
+pub mod tagged;
+/// Sharing a store between tenants, each with its own state and history.
+pub mod tenant;
+/// Fault injection, scripted storage failures and a persistence harness, for testing code built on Madeleine.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+/// Undoing and redoing commands.
+pub mod undo;
 
-pub use crate::command::Command;
+pub use crate::admin_log::{AdminMarker, AdminOperation, AdminOperationKind};
+#[cfg(feature = "allocation-check")]
+pub use crate::allocation_check::{AllocationCheck, AllocationCounter};
+pub use crate::audit::{AuditEvent, AuditOptions, AuditOutcome};
+pub use crate::batch::{BatchFailure, BatchMode, BatchReport};
+pub use crate::builder::MadeleineBuilder;
+pub use crate::cancellation::CancellationToken;
+pub use crate::codec::Codec;
+pub use crate::command::{Command, CommandContext, CommandWithOutput, MutCommand, TryCommand};
+pub use crate::command_migration::{CommandMigration, INITIAL_COMMAND_VERSION};
+pub use crate::command_store::{CommandStore, LogBackend, StoredRecord, SyncMode};
+pub use crate::compaction::{CompactionReport, CompactionStage};
+pub use crate::config::MadeleineConfig;
+pub use crate::determinism::DivergenceReport;
+pub use crate::directory_policy::DirectoryPolicy;
+pub use crate::events::StoreEvent;
+pub use crate::follower::Follower;
+pub use crate::hashing::HashAlgo;
+pub use crate::hooks::ExecutionHook;
+pub use crate::idempotency::{IdempotencyOptions, IdempotentOutcome};
+pub use crate::integrity::{OpenReport, VerificationLevel};
+pub use crate::logged_command::{HistoryEntry, HistoryPage, RawLoggedCommand};
 pub use crate::madeleine::Madeleine;
 pub use crate::madeleine_error::MadeleineError;
+pub use crate::maintenance::{MaintenanceRun, MaintenanceSchedule, MaintenanceTask};
+pub use crate::metrics::Metrics;
+pub use crate::middleware::CommandMiddleware;
+pub use crate::migration::StateMigration;
+#[cfg(feature = "bincode")]
+pub use crate::payload_format::BincodeSerializer;
+pub use crate::payload_format::{
+  CommandSerializer, JsonSerializer, MessagePackSerializer, PayloadFormat,
+};
+pub use crate::query::{Query, QueryCacheOptions};
+pub use crate::rate_limit::{RateLimit, RateLimits};
+pub use crate::read_guard::{ArcStateSnapshot, ReadLock, StateReadGuard};
+pub use crate::read_only::ReadOnlyMadeleine;
+pub use crate::rebuild_report::{RebuildChange, RebuildReport};
+pub use crate::reconcile::ReconcileCommand;
+pub use crate::redaction::{RedactionAction, RedactionReport};
+pub use crate::shared::{PoisonPolicy, SharedMadeleine};
+pub use crate::snapshot_failure::SnapshotFailureMode;
+pub use crate::snapshot_label::SnapshotInfo;
+pub use crate::snapshot_policy::SnapshotPolicy;
+pub use crate::store_path::StorePath;
+pub use crate::subscription::{OverflowPolicy, Receiver, SubscribeOptions, Subscription};
+pub use crate::tagged::TaggedCommand;
+pub use crate::tenant::{TenantCommand, TenantId, TenantStates};
+pub use crate::undo::{InvertibleCommand, Undoable, UndoableCommand, UndoableMadeleine};
@@ -0,0 +1,129 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+use crate::madeleine_error::MadeleineError;
+
+thread_local! {
+  /// How many reads of each state lock, by address, this thread holds.
+  static READS_HELD: RefCell<HashMap<usize, usize>> = RefCell::new(HashMap::new());
+}
+
+/// Lock bookkeeping which is always left consistent, even if a thread panicked while holding it.
+pub(crate) fn lock_recovering<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Read bookkeeping which is always left consistent, as `lock_recovering` does.
+pub(crate) fn read_recovering<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+  lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Write bookkeeping which is always left consistent, as `lock_recovering` does.
+pub(crate) fn write_recovering<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+  lock
+    .write()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The state of an instance, read concurrently by many threads and written by one command at a time.
+///
+/// A thread waits for the write lock while other threads read, but fails fast if it holds a read itself,
+/// rather than waiting forever. The state is only ever replaced once a command has succeeded,
+/// so a panicking command leaves it as it was, and poisoning is ignored.
+#[derive(Debug, Default)]
+pub(crate) struct StateLock<T> {
+  state: RwLock<T>,
+}
+
+impl<T> StateLock<T> {
+  pub fn new(state: T) -> Self {
+    Self {
+      state: RwLock::new(state),
+    }
+  }
+
+  /// Read the state, waiting for any command executing on another thread.
+  pub fn read(&self) -> StateRead<'_, T> {
+    StateRead::new(read_recovering(&self.state), self.address())
+  }
+
+  /// Read the state, failing with `MadeleineError::Contended` rather than waiting for a command to finish.
+  pub fn try_read(&self) -> Result<StateRead<'_, T>, MadeleineError> {
+    match self.state.try_read() {
+      Ok(state) => Ok(StateRead::new(state, self.address())),
+      Err(TryLockError::Poisoned(poisoned)) => {
+        Ok(StateRead::new(poisoned.into_inner(), self.address()))
+      }
+      Err(TryLockError::WouldBlock) => Err(MadeleineError::Contended(String::from(
+        "a command is changing the state",
+      ))),
+    }
+  }
+
+  /// Lock the state for writing, waiting for reads on other threads to finish.
+  /// Fails with `MadeleineError::ReentrantAccess` if this thread is reading it.
+  pub fn write(&self) -> Result<RwLockWriteGuard<'_, T>, MadeleineError> {
+    let address = self.address();
+
+    if READS_HELD.with(|reads_held| reads_held.borrow().contains_key(&address)) {
+      return Err(MadeleineError::ReentrantAccess(String::from(
+        "the state can't change while this thread is reading it",
+      )));
+    }
+
+    Ok(write_recovering(&self.state))
+  }
+
+  pub fn into_inner(self) -> T {
+    self
+      .state
+      .into_inner()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn address(&self) -> usize {
+    &self.state as *const RwLock<T> as usize
+  }
+}
+
+/// A read of a `StateLock`, counted against the thread holding it until dropped.
+pub(crate) struct StateRead<'a, T> {
+  state: RwLockReadGuard<'a, T>,
+  address: usize,
+}
+
+impl<'a, T> StateRead<'a, T> {
+  fn new(state: RwLockReadGuard<'a, T>, address: usize) -> Self {
+    READS_HELD.with(|reads_held| *reads_held.borrow_mut().entry(address).or_insert(0) += 1);
+
+    Self { state, address }
+  }
+}
+
+impl<T> Deref for StateRead<'_, T> {
+  type Target = T;
+
+  fn deref(&self) -> &Self::Target {
+    &self.state
+  }
+}
+
+impl<T> Drop for StateRead<'_, T> {
+  fn drop(&mut self) {
+    READS_HELD.with(|reads_held| {
+      let mut reads_held = reads_held.borrow_mut();
+
+      if let Some(count) = reads_held.get_mut(&self.address) {
+        *count -= 1;
+
+        if *count == 0 {
+          reads_held.remove(&self.address);
+        }
+      }
+    });
+  }
+}
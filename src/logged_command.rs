@@ -0,0 +1,96 @@
+use serde::de::DeserializeOwned;
+use ulid::Ulid;
+
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::{self, PayloadFormat};
+
+/// A command as stored in the log, with its payload still serialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawLoggedCommand {
+  /// Position of the command in the log.
+  pub offset: u64,
+  /// Identifier assigned to the command when it was logged.
+  pub id: Ulid,
+  /// Position in the global order shared with other stores, if a `Sequencer` was installed when it was logged.
+  pub sequence: Option<u64>,
+  /// The command, serialized as `format` says.
+  pub payload: Vec<u8>,
+  /// How the payload is serialized.
+  pub format: PayloadFormat,
+}
+
+/// A command read back from the log, deserialized, see `Madeleine::iter_history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry<C> {
+  /// Position of the command in the log.
+  pub offset: u64,
+  /// Identifier assigned to the command when it was logged.
+  pub id: Ulid,
+  /// The command.
+  pub command: C,
+}
+
+/// A page of commands read back from the log, see `Madeleine::history_page`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryPage<C> {
+  /// The commands, in the order they were logged.
+  pub entries: Vec<HistoryEntry<C>>,
+  /// ULID of the last command read for the page, to fetch the next page after,
+  /// or `None` if the page wasn't full because the end of the log was reached.
+  pub next: Option<Ulid>,
+}
+
+impl RawLoggedCommand {
+  /// Parse a raw entry read from the log.
+  pub(crate) fn from_entry(
+    offset: u64,
+    sequence: Option<u64>,
+    entry: &[u8],
+  ) -> Result<Self, MadeleineError> {
+    let format = PayloadFormat::of_entry(entry);
+
+    let (id, payload) = match format {
+      PayloadFormat::Json => {
+        let (id, command): (Ulid, serde_json::Value) = serde_json::from_slice(entry)?;
+
+        (id, serde_json::to_vec(&command)?)
+      }
+      PayloadFormat::Bincode | PayloadFormat::MessagePack => {
+        let (id, payload) = payload_format::split_binary_entry(entry)?;
+
+        (id, payload.to_vec())
+      }
+    };
+
+    Ok(Self {
+      offset,
+      id,
+      sequence,
+      payload,
+      format,
+    })
+  }
+
+  /// When the command was logged, in milliseconds since the Unix epoch.
+  /// This is the wall-clock time recorded in its ULID, so it's known for commands logged by any version of the log.
+  pub fn recorded_at(&self) -> u64 {
+    self.id.timestamp_ms()
+  }
+
+  /// Whether the command was replaced with a tombstone by `Madeleine::redact_matching`.
+  /// Tombstones have a `null` payload, and are skipped when replaying the log.
+  pub fn is_tombstone(&self) -> bool {
+    self.format == PayloadFormat::Json && self.payload == b"null"
+  }
+
+  /// Whether the entry holds no command to replay, because it's a tombstone
+  /// or the marker `Madeleine::undo_last` logs after an inverse, see `undone_command`.
+  pub fn is_skipped_on_replay(&self) -> bool {
+    self.is_tombstone() || self.undone_command().is_some()
+  }
+
+  /// Deserialize the payload into a command.
+  pub fn deserialize<C: DeserializeOwned>(&self) -> Result<C, MadeleineError> {
+    self.format.decode_payload(&self.payload)
+  }
+}
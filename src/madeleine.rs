@@ -1,187 +1,5558 @@
-use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use commitlog::Offset;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-
-use crate::command::Command;
-use crate::command_log::CommandLog;
+use ulid::Ulid;
+
+use crate::admin_log::{self, AdminMarker, AdminOperationKind, ADMIN_LOG_FILE_NAME};
+#[cfg(feature = "allocation-check")]
+use crate::allocation_check::AllocationCheck;
+use crate::audit::{self, AuditOptions};
+use crate::batch::{journal_rejection, BatchFailure, BatchMode, BatchReport, REJECTIONS_FILE_NAME};
+use crate::builder::MadeleineBuilder;
+use crate::cancellation::CancellationToken;
+use crate::codec::{self, Codec};
+use crate::command::{Command, CommandContext, CommandWithOutput, MutCommand};
+use crate::command_log::{CommandLog, MAX_PAYLOAD_BYTES};
+use crate::command_migration::{record_command_version, CommandMigration, CommandMigrations};
+use crate::command_store::{CommandStore, LogBackend, SuppliedStore, SyncMode};
+use crate::compaction::{
+  self, copy_log_after, CompactionJournal, CompactionReport, CompactionStage,
+  COMPACTED_LOG_DIR_NAME, RETIRED_LOG_DIR_NAME,
+};
+use crate::determinism::DivergenceReport;
+use crate::directory_policy::DirectoryPolicy;
+use crate::dump::{dump_store, read_dump};
+use crate::durable::TEMP_FILE_SUFFIX;
+use crate::events::StoreEvent;
+use crate::export::{export_log, ExportFormat, ExportManifest, ExportRange, ExportSelection};
+use crate::follower::{AppendNotifier, Follower};
+use crate::hashing::HashAlgo;
+use crate::hooks::{ExecutionHook, HookList};
+use crate::idempotency::{
+  IdempotencyCache, IdempotencyOptions, IdempotentOutcome, IDEMPOTENCY_FILE_NAME,
+};
+use crate::import::{IMPORT_CHECKPOINT_FILE_NAME, IMPORT_META_FILE_NAME};
+use crate::integrity::{check_on_open, verify_log, CleanShutdown, OpenReport, VerificationLevel};
+use crate::layout::StoreLayout;
+use crate::locks::{lock_recovering, StateLock};
+use crate::logged_command::{HistoryEntry, HistoryPage, RawLoggedCommand};
 use crate::madeleine_error::MadeleineError;
-
-const COMMAND_LOG_DIR_NAME: &str = "command_log";
+use crate::maintenance::{
+  MaintenanceRun, MaintenanceSchedule, MaintenanceScheduler, MaintenanceTask,
+};
+use crate::merge::MERGE_CHECKPOINT_FILE_NAME;
+use crate::metadata::{StoreFormat, StoreMetadata, METADATA_FILE_NAME};
+use crate::metrics::{Metrics, Phase};
+use crate::middleware::{CommandMiddleware, MiddlewareChain};
+use crate::migration::{check_state_type, StateMigration};
+use crate::payload_format::{self, entry_id, PayloadFormat};
+use crate::projection::{ErasedProjection, Projection};
+use crate::query::{query_cache_key, Query, QueryCache, QueryCacheOptions};
+use crate::quota::{QuotaTracker, Quotas, ResourceUsage, ResourceWarningHook};
+use crate::rate_limit::{logged_bytes, Clock, RateLimiter, RateLimits};
+use crate::read_guard::{ArcStateSnapshot, StateReadGuard};
+use crate::rebuild_report::{RebuildChange, RebuildReport};
+use crate::reconcile::ReconcileCommand;
+use crate::redaction::{self, RedactionAction, RedactionReport};
+#[cfg(feature = "registry")]
+use crate::registry::Registration;
+use crate::sequencer::Sequencer;
+use crate::snapshot_diff::{read_snapshot_state, SnapshotDiff, SnapshotState};
+use crate::snapshot_failure::SnapshotFailureMode;
+use crate::snapshot_label::{check_label, read_snapshot_label, SnapshotInfo, SnapshotLabel};
+use crate::snapshot_policy::{SnapshotPolicy, SnapshotScheduler};
+use crate::store_path::{RemoveOnDrop, StorePath};
+use crate::subscription::{Receiver, SubscribeOptions, SubscriberLag, Subscribers, Subscription};
+use crate::tenant::{copy_log_without_tenant, count_by_tenant, TenantId};
+#[cfg(any(test, feature = "testing"))]
+use crate::testing::{FailpointStore, StorageOperation};
+use crate::undo::{undo_marker_entry, InvertibleCommand};
+
+pub(crate) const COMMAND_LOG_DIR_NAME: &str = "command_log";
 const SNAPSHOT_FILE_SUFFIX: &str = "snapshot";
+const SNAPSHOT_ALIAS_FILE_SUFFIX: &str = "alias";
+const SNAPSHOT_DIFF_FILE_SUFFIX: &str = "diff";
+const SNAPSHOT_HEAD_FILE_SUFFIX: &str = "head";
+const SNAPSHOT_LABEL_FILE_SUFFIX: &str = "label";
+
+/// Bookkeeping about the most recently written snapshot file.
+struct SnapshotRecord {
+  state_hash: String,
+  snapshot_id: usize,
+  /// The state as JSON, kept while differential snapshots are enabled for the next snapshot to diff against.
+  state: Option<serde_json::Value>,
+  /// Number of differential snapshots between this one and the full snapshot it builds on.
+  chain_length: usize,
+}
 
 /// Top-level struct providing the public interface for transparent object persistence.
+///
+/// An instance can be shared across threads, e.g. in an `Arc`: commands execute one at a time
+/// while holding the state's write lock, and reads share its read lock.
 pub struct Madeleine<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> {
   command_log: CommandLog,
-  internal_state: RefCell<SystemState>,
+  internal_state: StateLock<SystemState>,
+  command_lock: Mutex<()>,
+  undo_lock: Mutex<()>,
   location_dir_path: PathBuf,
+  store_id: Ulid,
+  hash_algo: HashAlgo,
+  snapshot_codec: Mutex<Option<Arc<dyn Codec>>>,
+  export_codec: Mutex<Option<Arc<dyn Codec>>>,
+  open_report: OpenReport,
+  last_snapshot: Mutex<Option<SnapshotRecord>>,
+  full_snapshot_every: AtomicUsize,
+  append_notifier: AppendNotifier,
+  strict: AtomicBool,
+  snapshot_failure_mode: Mutex<SnapshotFailureMode>,
+  snapshot_scheduler: SnapshotScheduler,
+  maintenance_scheduler: MaintenanceScheduler,
+  subscribers: Subscribers<RawLoggedCommand>,
+  events: Subscribers<StoreEvent>,
+  idempotency: IdempotencyCache,
+  quotas: QuotaTracker,
+  rate_limiter: Arc<RateLimiter>,
+  query_cache: QueryCache,
+  commands_compacted: AtomicU64,
+  sequencer: Mutex<Option<Arc<dyn Sequencer>>>,
+  #[cfg(feature = "allocation-check")]
+  allocation_check: Mutex<Option<AllocationCheck>>,
+  metrics: Metrics,
+  projections: Mutex<HashMap<String, Box<dyn ErasedProjection>>>,
+  middleware: MiddlewareChain,
+  command_migrations: CommandMigrations,
+  command_hooks: HookList,
+  snapshot_hooks: HookList,
+  #[cfg(feature = "registry")]
+  registration: Registration,
+  /// Declared last, so the directory outlives everything else which uses it.
+  removed_on_drop: Option<RemoveOnDrop>,
 }
 
 impl<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> Madeleine<SystemState> {
   /// Generalized constructor.
-  pub fn new<C>(location_dir_path: PathBuf, constructor: C) -> Result<Self, MadeleineError>
+  /// The directory must be missing, empty, or an existing store, see `DirectoryPolicy::RequireEmptyOrStore`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new<C>(location: impl Into<StorePath>, constructor: C) -> Result<Self, MadeleineError>
   where
     C: FnOnce() -> SystemState,
   {
-    let log_dir = location_dir_path.join(COMMAND_LOG_DIR_NAME);
-    let command_log = CommandLog::new(log_dir)?;
-    let internal_state = RefCell::new(constructor());
+    Self::new_with_directory_policy(location, DirectoryPolicy::RequireEmptyOrStore, constructor)
+  }
 
-    Ok(Self {
-      command_log,
-      internal_state,
+  /// Constructor which checks the store directory's contents against the given policy before creating anything.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::DirectoryPolicy;
+  ///
+  /// let store = scratch_store();
+  /// std::fs::create_dir_all(store.path().join("unrelated"))?;
+  ///
+  /// let rejected =
+  ///   Madeleine::new_with_directory_policy(&store, DirectoryPolicy::RequireEmptyOrStore, || 0);
+  /// assert!(rejected.is_err());
+  ///
+  /// let madeleine = Madeleine::new_with_directory_policy(&store, DirectoryPolicy::Permissive, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new_with_directory_policy<C>(
+    location: impl Into<StorePath>,
+    directory_policy: DirectoryPolicy,
+    constructor: C,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    Self::create(
+      location.into().resolve()?,
+      directory_policy,
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+      constructor,
+    )
+  }
+
+  /// Constructor which creates the store with the given hash function for its integrity features, see `HashAlgo`.
+  /// Opening an existing store created with a different hash function fails with `MadeleineError::HashAlgoMismatch`,
+  /// since one store never mixes hash functions.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::HashAlgo;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new_with_hash_algo(&store, HashAlgo::Sha256, || 0)?;
+  ///
+  /// assert_eq!(madeleine.hash_algo(), HashAlgo::Sha256);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new_with_hash_algo<C>(
+    location: impl Into<StorePath>,
+    hash_algo: HashAlgo,
+    constructor: C,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    Self::create(
+      location.into().resolve()?,
+      DirectoryPolicy::RequireEmptyOrStore,
+      StoreFormat {
+        hash_algo: Some(hash_algo),
+        ..StoreFormat::default()
+      },
+      VerificationLevel::default(),
+      None,
+      constructor,
+    )
+  }
+
+  /// Constructor which keeps the log in a `CommandStore` of the application's own,
+  /// see `MadeleineBuilder::command_store`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::LogBackend;
+  ///
+  /// let store = scratch_store();
+  /// let log = scratch_store();
+  /// let command_store = LogBackend::File.open(log.path())?;
+  /// let madeleine = Madeleine::new_with_command_store(&store, command_store, || 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.log_backend(), LogBackend::Custom);
+  /// assert_eq!(madeleine.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new_with_command_store<C>(
+    location: impl Into<StorePath>,
+    command_store: impl CommandStore + 'static,
+    constructor: C,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    Self::create(
+      location.into().resolve()?,
+      DirectoryPolicy::RequireEmptyOrStore,
+      StoreFormat {
+        log_backend: Some(LogBackend::Custom),
+        command_store: Some(SuppliedStore::new(command_store)),
+        ..StoreFormat::default()
+      },
+      VerificationLevel::default(),
+      None,
+      constructor,
+    )
+  }
+
+  /// Create a throwaway store for tests, e.g. of command logic, without setting up a directory.
+  /// It lives on a RAM-backed filesystem where there is one, such as `/dev/shm` on Linux, and in the system's
+  /// temporary directory otherwise, since the log and snapshots are always files, and is deleted when dropped.
+  /// It behaves like any other store until then, except that opening it again, e.g. with `Madeleine::resume`,
+  /// fails with `MadeleineError::InMemoryStore`.
+  ///
+  /// ```
+  /// # use madeleine::testing::Add;
+  /// # use madeleine::Madeleine;
+  /// let madeleine = Madeleine::new_in_memory(|| 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new_in_memory<C>(constructor: C) -> Result<Self, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    let removed_on_drop = RemoveOnDrop(StorePath::memory_backed().resolve()?);
+
+    let mut madeleine = Self::create(
+      removed_on_drop.0.clone(),
+      DirectoryPolicy::RequireEmptyOrStore,
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+      constructor,
+    )?;
+    StoreMetadata::mark_in_memory(&madeleine.location_dir_path, madeleine.hash_algo)?;
+    madeleine.removed_on_drop = Some(removed_on_drop);
+
+    Ok(madeleine)
+  }
+
+  /// Configure a store before creating or resuming it.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).strict(true).build(|| 0)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.is_strict());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn builder(location: impl Into<StorePath>) -> MadeleineBuilder<SystemState> {
+    MadeleineBuilder::new(location)
+  }
+
+  /// Create or reopen a store, starting from the constructor's state.
+  pub(crate) fn create<C>(
+    location_dir_path: PathBuf,
+    directory_policy: DirectoryPolicy,
+    format: StoreFormat,
+    verification: VerificationLevel,
+    cancellation: Option<CancellationToken>,
+    constructor: C,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: FnOnce() -> SystemState,
+  {
+    directory_policy.evaluate(&location_dir_path)?;
+    check_state_type::<SystemState>(&location_dir_path)?;
+    StoreMetadata::check_not_in_memory(&location_dir_path)?;
+
+    let madeleine = Self::open(
       location_dir_path,
-    })
+      constructor(),
+      format,
+      verification,
+      cancellation,
+    )?;
+
+    madeleine.mark_ready();
+
+    Ok(madeleine)
   }
 
   /// Resume from existing instance on disk.
-  pub fn resume(location_dir_path: PathBuf) -> Result<Self, MadeleineError> {
+  /// The directory must be an existing store, see `DirectoryPolicy::RequireExistingStore`.
+  /// Only the latest snapshot is loaded, so if commands were logged after it this fails with
+  /// `MadeleineError::ReplayError` rather than leaving them out of the state;
+  /// use `Madeleine::resume_replaying` to replay them too.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// let resumed = Madeleine::<u64>::resume(&store)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resume(location: impl Into<StorePath>) -> Result<Self, MadeleineError> {
+    Self::resume_with_directory_policy(location, DirectoryPolicy::RequireExistingStore)
+  }
+
+  /// Resume from existing instance on disk, checking the store directory's contents against the given policy first.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::DirectoryPolicy;
+  ///
+  /// let store = scratch_store();
+  ///
+  /// let policy = DirectoryPolicy::RequireExistingStore;
+  /// let missing = Madeleine::<u64>::resume_with_directory_policy(&store, policy);
+  /// assert!(missing.is_err());
+  ///
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// let resumed = Madeleine::<u64>::resume_with_directory_policy(&store, policy)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resume_with_directory_policy(
+    location: impl Into<StorePath>,
+    directory_policy: DirectoryPolicy,
+  ) -> Result<Self, MadeleineError> {
+    Self::resume_with(
+      location.into().resolve()?,
+      directory_policy,
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+    )
+  }
+
+  /// Resume from the latest snapshot of an existing store, which must use the requested hash function
+  /// and payload format if any.
+  pub(crate) fn resume_with(
+    location_dir_path: PathBuf,
+    directory_policy: DirectoryPolicy,
+    format: StoreFormat,
+    verification: VerificationLevel,
+    cancellation: Option<CancellationToken>,
+  ) -> Result<Self, MadeleineError> {
+    let (madeleine, snapshot_id) = Self::resume_snapshot(
+      location_dir_path,
+      directory_policy,
+      format,
+      verification,
+      cancellation,
+    )?;
+
+    // Snapshots taken before heads were recorded can't tell, so they're trusted as before.
+    // The log's head may be behind the snapshot's once compaction has deleted the commands it covers.
+    if let Some(snapshot_head_id) = madeleine.snapshot_head_id(snapshot_id)? {
+      if madeleine.head_id()? > snapshot_head_id {
+        return Err(MadeleineError::ReplayError(format!(
+          "commands were logged after snapshot {}, so resume with `Madeleine::resume_replaying` to replay them",
+          snapshot_id
+        )));
+      }
+    }
+
+    Ok(madeleine)
+  }
+
+  /// Resume from the latest snapshot like `resume_with`, also returning the id of the snapshot resumed from.
+  /// That's an earlier full snapshot if the latest is differential and its chain is broken by a missing snapshot.
+  fn resume_snapshot(
+    location_dir_path: PathBuf,
+    directory_policy: DirectoryPolicy,
+    format: StoreFormat,
+    verification: VerificationLevel,
+    cancellation: Option<CancellationToken>,
+  ) -> Result<(Self, usize), MadeleineError> {
+    directory_policy.evaluate(&location_dir_path)?;
+    check_state_type::<SystemState>(&location_dir_path)?;
+    StoreMetadata::check_not_in_memory(&location_dir_path)?;
+
     // Read snapshot file if it exists.
     let snapshot_id_path = snapshot_id_file_path(location_dir_path.clone());
 
     if snapshot_id_path.is_file() {
-      let snapshot_file_path_to_read = {
-        let raw_snapshot_id = fs::read(&snapshot_id_path)?;
-        let snapshot_id: usize = serde_json::from_slice(&raw_snapshot_id)?;
-        snapshot_file_path(snapshot_id, location_dir_path.clone())
+      let latest_snapshot_id: usize = serde_json::from_slice(&fs::read(&snapshot_id_path)?)?;
+      let snapshot_id = resolve_snapshot_alias(latest_snapshot_id, location_dir_path.clone())?;
+
+      let (resumed_snapshot_id, snapshot_id, snapshot_state) = match read_snapshot_state(
+        snapshot_id,
+        &location_dir_path,
+      )? {
+        Some(snapshot_state) => (latest_snapshot_id, snapshot_id, snapshot_state),
+        None => {
+          let full_snapshot_id =
+              previous_full_snapshot_id(snapshot_id, &location_dir_path)?.ok_or_else(|| {
+                MadeleineError::SnapshotError(format!(
+                  "the chain of differential snapshot {} is broken, and no earlier full snapshot exists",
+                  snapshot_id
+                ))
+              })?;
+
+          #[cfg(feature = "tracing")]
+          tracing::warn!(
+            snapshot_id,
+            full_snapshot_id,
+            "the chain of the latest snapshot is broken, resuming from the previous full snapshot"
+          );
+
+          let snapshot_state = read_snapshot_state(full_snapshot_id, &location_dir_path)?
+            .ok_or_else(|| {
+              MadeleineError::SnapshotError(format!("snapshot {} is missing", full_snapshot_id))
+            })?;
+
+          (full_snapshot_id, full_snapshot_id, snapshot_state)
+        }
       };
 
-      let raw_state = fs::read(snapshot_file_path_to_read)?;
+      let SnapshotState {
+        raw_state,
+        chain_length,
+      } = snapshot_state;
       let hydrated_state: SystemState = serde_json::from_slice(&raw_state)?;
-      let constructor = || hydrated_state;
-      let madeleine = Madeleine::new(location_dir_path, constructor)?;
-
-      Ok(madeleine)
+      let madeleine = Self::open(
+        location_dir_path,
+        hydrated_state,
+        format,
+        verification,
+        cancellation,
+      )?;
+      let state_hash = madeleine.state_hash()?;
+
+      madeleine.metrics.record_state_size(raw_state.len() as u64);
+
+      *lock_recovering(&madeleine.last_snapshot) = Some(SnapshotRecord {
+        state_hash,
+        snapshot_id,
+        state: None,
+        chain_length,
+      });
+
+      madeleine.mark_ready();
+
+      Ok((madeleine, resumed_snapshot_id))
     } else {
       Err(MadeleineError::SnapshotError(String::from(
         "No snapshots found",
       )))
     }
+  }
+
+  /// Resume from the latest snapshot, then replay every command logged after it, deserialized as `C`,
+  /// so no command is lost however long ago the snapshot was taken. A store without snapshots
+  /// replays every command onto the constructor's state.
+  ///
+  /// Replayed commands aren't validated or logged again. Fails with `MadeleineError::SnapshotError`
+  /// if the latest snapshot predates snapshots recording their last applied command, see `Madeleine::snapshot_head_id`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  /// drop(madeleine);
+  ///
+  /// let resumed = Madeleine::resume_replaying::<Add, _>(&store, || 0)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resume_replaying<C, F>(
+    location: impl Into<StorePath>,
+    constructor: F,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    Self::resume_replaying_with::<C, F>(
+      location.into().resolve()?,
+      StoreFormat::default(),
+      constructor,
+      None,
+      Vec::new(),
+    )
+  }
+
+  /// Resume and replay as `resume_replaying` does, failing with `MadeleineError::Cancelled` if `cancellation`
+  /// is cancelled while the log is verified or replayed, e.g. from another thread or by `SharedMadeleine::resume_replaying_async`.
+  ///
+  /// Replaying only changes the state in memory, so a cancelled resume leaves the store as a crash would,
+  /// to be opened again later. The token stays set on the instance, see `Madeleine::set_cancellation_token`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{CancellationToken, MadeleineError};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let cancellation = CancellationToken::new();
+  /// cancellation.cancel();
+  ///
+  /// let cancelled = Madeleine::resume_replaying_cancellable::<Add, _>(&store, || 0, cancellation);
+  /// assert!(matches!(cancelled.err(), Some(MadeleineError::Cancelled { .. })));
+  ///
+  /// let cancellation = CancellationToken::new();
+  /// let resumed = Madeleine::resume_replaying_cancellable::<Add, _>(&store, || 0, cancellation)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resume_replaying_cancellable<C, F>(
+    location: impl Into<StorePath>,
+    constructor: F,
+    cancellation: CancellationToken,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    Self::resume_replaying_with::<C, F>(
+      location.into().resolve()?,
+      StoreFormat::default(),
+      constructor,
+      Some(cancellation),
+      Vec::new(),
+    )
+  }
+
+  /// Resume and replay as `resume_replaying` does, requiring the given formats of the store,
+  /// checking `cancellation`, if any, between commands, and migrating them with `migrations`,
+  /// which are added to the instance before it replays.
+  pub(crate) fn resume_replaying_with<C, F>(
+    location_dir_path: PathBuf,
+    format: StoreFormat,
+    constructor: F,
+    cancellation: Option<CancellationToken>,
+    migrations: Vec<Arc<dyn CommandMigration>>,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let (madeleine, head_id) = if snapshot_id_file_path(location_dir_path.clone()).is_file() {
+      let (madeleine, snapshot_id) = Self::resume_snapshot(
+        location_dir_path,
+        DirectoryPolicy::RequireExistingStore,
+        format,
+        VerificationLevel::default(),
+        cancellation.clone(),
+      )?;
+      let head_id = madeleine.snapshot_head_id(snapshot_id)?.ok_or_else(|| {
+        MadeleineError::SnapshotError(format!(
+          "snapshot {} doesn't record its last applied command, so the commands to replay are unknown",
+          snapshot_id
+        ))
+      })?;
+
+      (madeleine, head_id)
+    } else {
+      let madeleine = Self::create(
+        location_dir_path,
+        DirectoryPolicy::RequireExistingStore,
+        format,
+        VerificationLevel::default(),
+        cancellation.clone(),
+        constructor,
+      )?;
+
+      (madeleine, Ulid::nil())
+    };
+
+    for migration in migrations {
+      madeleine.add_migration(migration)?;
+    }
+
+    let commands = madeleine.command_log.commands_after(head_id)?;
+
+    if !commands.is_empty() {
+      let commands_compacted = madeleine.commands_compacted.load(Ordering::Relaxed);
+      let mut state = madeleine.internal_state.write()?;
+      let mut replayed = state.to_owned();
+
+      for (replayed_count, logged) in commands.into_iter().enumerate() {
+        if let Some(cancellation) = &cancellation {
+          cancellation.check(replayed_count as u64)?;
+        }
+
+        if logged.is_skipped_on_replay() {
+          continue;
+        }
+
+        let command: C = madeleine.command_migrations.decode(&logged)?;
+
+        replayed = command.execute_with_ctx(
+          replayed,
+          &CommandContext::at(commands_compacted + logged.offset),
+        );
+      }
+
+      *state = replayed;
+    }
+
+    Ok(madeleine)
+  }
+
+  /// Resume the store at the location if there is one, replaying the commands logged after its latest snapshot
+  /// as `resume_replaying` does, or create it from the constructor's state if there isn't.
+  ///
+  /// Fails with `MadeleineError::IncompleteStore` if the directory holds some of a store's files but no command log,
+  /// e.g. snapshots left behind after the log was deleted, rather than starting afresh alongside them.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  ///
+  /// let madeleine = Madeleine::new_or_resume::<Add, _>(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let resumed = Madeleine::new_or_resume::<Add, _>(&store, || 0)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn new_or_resume<C, F>(
+    location: impl Into<StorePath>,
+    constructor: F,
+  ) -> Result<Self, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let location_dir_path = location.into().resolve()?;
+
+    if is_store_root(&location_dir_path) {
+      return Self::resume_replaying::<C, F>(location_dir_path, constructor);
+    }
+
+    if location_dir_path.is_dir() {
+      let layout = StoreLayout::of(&location_dir_path);
+      let mut store_entries = Vec::new();
+
+      for entry in fs::read_dir(&location_dir_path)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+
+        if is_store_entry(&file_name, &layout) {
+          store_entries.push(file_name);
+        }
+      }
+
+      if !store_entries.is_empty() {
+        store_entries.sort();
+
+        return Err(MadeleineError::IncompleteStore(format!(
+          "{} has no command log but holds {}",
+          location_dir_path.display(),
+          store_entries.join(", ")
+        )));
+      }
+    }
+
+    Self::new(location_dir_path, constructor)
+  }
+
+  /// Convert the state of an existing store from `V1` to this instance's type with `transform`,
+  /// rather than exporting, transforming and reimporting its history.
+  ///
+  /// The store is resumed as `V1` from its latest snapshot, which must hold the state after every logged command,
+  /// see `Madeleine::snapshot_head_id`, or this fails with `MadeleineError::SnapshotError`. The transformed state
+  /// is then snapshotted as the new baseline, and the change is recorded as a `StateMigration` in the store's metadata,
+  /// see `Madeleine::state_migrations`, and as an `AdminOperationKind::StateMigrated` in its admin log.
+  ///
+  /// Commands logged before the migration were executed against `V1`, so replaying across it, e.g. with
+  /// `ReadOnlyMadeleine`, needs the old command types and the same transform. Opening the store as `V1` afterwards
+  /// fails with `MadeleineError::StateMigrated`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// let migrated = Madeleine::migrate_state::<u64, _>(&store, |total| format!("total: {}", total))?;
+  ///
+  /// assert_eq!(migrated.tap(|state| state), "total: 2");
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn migrate_state<V1, F>(
+    location: impl Into<StorePath>,
+    transform: F,
+  ) -> Result<Self, MadeleineError>
+  where
+    V1: Clone + for<'a> Deserialize<'a> + Serialize,
+    F: FnOnce(V1) -> SystemState,
+  {
+    let location_dir_path = location.into().resolve()?;
+
+    let (old, snapshot_id) = Madeleine::<V1>::resume_snapshot(
+      location_dir_path.clone(),
+      DirectoryPolicy::RequireExistingStore,
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+    )?;
+    let head_id = old.head_id()?;
+
+    if old.snapshot_head_id(snapshot_id)? != Some(head_id) {
+      return Err(MadeleineError::SnapshotError(format!(
+        "commands were logged after snapshot {}, so take a snapshot before migrating",
+        snapshot_id
+      )));
+    }
+
+    let from_state_hash = old.state_hash()?;
+    old.record_clean_shutdown()?;
+
+    let madeleine = Self::open(
+      location_dir_path.clone(),
+      transform(old.into_inner()),
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+    )?;
+    let snapshot_id = madeleine.take_snapshot(true)?;
+    let to_state_hash = madeleine.state_hash()?;
+
+    let operation = admin_log::record(
+      &location_dir_path,
+      AdminOperationKind::StateMigrated {
+        from_type: std::any::type_name::<V1>().to_string(),
+        to_type: std::any::type_name::<SystemState>().to_string(),
+        from_state_hash: from_state_hash.clone(),
+        to_state_hash: to_state_hash.clone(),
+        snapshot_id,
+      },
+    )?;
+
+    let mut metadata = StoreMetadata::load_or_create(&location_dir_path, madeleine.hash_algo)?;
+    metadata.state_migrations.push(StateMigration {
+      from_type: std::any::type_name::<V1>().to_string(),
+      to_type: std::any::type_name::<SystemState>().to_string(),
+      from_state_hash,
+      to_state_hash,
+      snapshot_id,
+      head_id,
+      marker: operation.marker,
+    });
+    metadata.write(&location_dir_path)?;
+
+    madeleine.mark_ready();
+
+    Ok(madeleine)
+  }
+
+  /// Changes of the store's state type, oldest first, see `Madeleine::migrate_state`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// assert!(madeleine.state_migrations()?.is_empty());
+  /// madeleine.close()?;
+  ///
+  /// let migrated = Madeleine::migrate_state::<u64, _>(&store, |total| total.to_string())?;
+  /// let migrations = migrated.state_migrations()?;
+  ///
+  /// assert_eq!(migrations.len(), 1);
+  /// assert_eq!(migrations[0].from_type, std::any::type_name::<u64>());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn state_migrations(&self) -> Result<Vec<StateMigration>, MadeleineError> {
+    StoreMetadata::read_state_migrations(&self.location_dir_path)
+  }
+
+  /// Open the store's structures on disk with the given initial state.
+  /// A new store uses the requested formats, and an existing one must already use them,
+  /// see `StoreMetadata::open_for_write`.
+  /// Unless the store was shut down cleanly, the log is verified to the given level, see `VerificationLevel`.
+  fn open(
+    location_dir_path: PathBuf,
+    initial_state: SystemState,
+    format: StoreFormat,
+    verification: VerificationLevel,
+    cancellation: Option<CancellationToken>,
+  ) -> Result<Self, MadeleineError> {
+    fs::create_dir_all(&location_dir_path)?;
+
+    let command_store = format.command_store.clone();
+    let store_wrapper = format.store_wrapper.clone();
+    let mut metadata = StoreMetadata::open_for_write(&location_dir_path, format)?;
+    let compaction_recovered = compaction::recover(&location_dir_path, &mut metadata)?;
+    let clean_shutdown = metadata.take_clean_shutdown(&location_dir_path)?;
+    fs::create_dir_all(metadata.layout.snapshot_dir_path(&location_dir_path))?;
+    let command_log = match command_store {
+      Some(command_store) => {
+        // The log's directory is left empty, but it still marks the directory as a store.
+        fs::create_dir_all(command_log_dir_path(&location_dir_path))?;
+
+        CommandLog::with_store(command_store.take()?, metadata.log_backend)
+      }
+      None => CommandLog::with_backend(
+        command_log_dir_path(&location_dir_path),
+        metadata.log_backend,
+      )?,
+    }
+    .wrapped(store_wrapper);
+    command_log.set_payload_format(metadata.payload_format);
+    command_log.set_cancellation(cancellation);
+    let open_report = check_on_open(
+      &command_log,
+      clean_shutdown,
+      verification,
+      compaction_recovered,
+      admin_log::latest_marker(&location_dir_path)?,
+    )?;
+    let idempotency = IdempotencyCache::load(&location_dir_path)?;
+    idempotency.resolve_pending(&command_log)?;
+    let internal_state = StateLock::new(initial_state);
+
+    #[cfg(feature = "registry")]
+    let registration = Registration::new(
+      location_dir_path.clone(),
+      metadata.store_id,
+      std::any::type_name::<SystemState>(),
+      command_log.len(),
+    );
+
+    Ok(Self {
+      command_log,
+      internal_state,
+      command_lock: Mutex::new(()),
+      undo_lock: Mutex::new(()),
+      location_dir_path,
+      store_id: metadata.store_id,
+      hash_algo: metadata.hash_algo,
+      snapshot_codec: Mutex::new(None),
+      export_codec: Mutex::new(None),
+      open_report,
+      last_snapshot: Mutex::new(None),
+      full_snapshot_every: AtomicUsize::new(1),
+      append_notifier: AppendNotifier::default(),
+      strict: AtomicBool::new(false),
+      snapshot_failure_mode: Mutex::new(SnapshotFailureMode::default()),
+      snapshot_scheduler: SnapshotScheduler::default(),
+      maintenance_scheduler: MaintenanceScheduler::default(),
+      subscribers: Subscribers::default(),
+      events: Subscribers::default(),
+      idempotency,
+      quotas: QuotaTracker::default(),
+      rate_limiter: Arc::new(RateLimiter::default()),
+      query_cache: QueryCache::default(),
+      commands_compacted: AtomicU64::new(metadata.commands_compacted),
+      sequencer: Mutex::new(None),
+      #[cfg(feature = "allocation-check")]
+      allocation_check: Mutex::new(None),
+      metrics: Metrics::default(),
+      projections: Mutex::new(HashMap::new()),
+      middleware: MiddlewareChain::default(),
+      command_migrations: CommandMigrations::default(),
+      command_hooks: HookList::default(),
+      snapshot_hooks: HookList::default(),
+      #[cfg(feature = "registry")]
+      registration,
+      removed_on_drop: None,
+    })
+  }
+
+  /// List the administrative operations since `marker` in the open report.
+  pub(crate) fn report_admin_ops_since(
+    &mut self,
+    marker: AdminMarker,
+  ) -> Result<(), MadeleineError> {
+    let mut operations = admin_log::admin_ops_since(&self.location_dir_path, marker)?;
+    operations.retain(|operation| operation.marker <= self.open_report.admin_marker);
+
+    self.open_report.admin_ops_since = Some(operations);
+
+    Ok(())
+  }
 
-    // If it exists, read the last snapshot and spin up a new Madeleine with that as the initial state.
-    // Then we replay all the commands in the log, if any exist.
+  /// Signal that the store has finished opening.
+  fn mark_ready(&self) {
+    #[cfg(feature = "registry")]
+    self.registration.mark_ready();
   }
 
   /// Execute the command on the business object and update the application state.
   /// Then, log the command.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// assert_eq!(madeleine.len(), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
   pub fn execute_command<'a, C>(&self, command: C) -> Result<Offset, MadeleineError>
   where
     C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
   {
-    self
-      .internal_state
-      .replace_with(|old| command.execute(old.to_owned()));
+    let (offset, _id) = self.execute_logged(&command, Logging::Plain)?;
 
-    self.command_log.append_command(command)
+    Ok(offset)
   }
 
-  /// Consume the instance and return its internal state.
-  pub fn into_inner(self) -> SystemState {
-    self.internal_state.into_inner()
+  /// Execute and log a command as `execute_command` does, returning the output it produced.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::CommandWithOutput;
+  ///
+  /// /// Adds to the total, returning the total before.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct FetchAdd(u64);
+  ///
+  /// impl CommandWithOutput<'_> for FetchAdd {
+  ///   type SystemState = u64;
+  ///   type Output = u64;
+  ///
+  ///   fn execute_with_output(&self, old_state: u64) -> (u64, u64) {
+  ///     (old_state + self.0, old_state)
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.execute_command_with_output(FetchAdd(2))?, 0);
+  /// assert_eq!(madeleine.execute_command_with_output(FetchAdd(3))?, 2);
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_command_with_output<'a, C>(&self, command: C) -> Result<C::Output, MadeleineError>
+  where
+    C: CommandWithOutput<'a, SystemState = SystemState>,
+  {
+    let (_offset, _id, output) =
+      self.execute_logged_with(&command, Logging::Plain, |state, _ctx| {
+        command.try_execute_with_output(state)
+      })?;
+
+    Ok(output)
   }
 
-  /// Run a closure passed a reference to the instance's internal state.
-  pub fn tap<T, O>(&self, func: O) -> T
+  /// Execute and log a command which changes the state in place, without cloning it, returning its offset.
+  ///
+  /// Since changes made in place can't be rolled back, the command is validated and logged before it's executed,
+  /// so a failed append leaves the state untouched. With an `AllocationCheck` set, it's executed on a copy of the state
+  /// instead, as `execute_command` does, so that a command which allocated too much can be discarded.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::MutCommand;
+  ///
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Push(u8);
+  ///
+  /// impl Command<'_> for Push {
+  ///   type SystemState = Vec<u8>;
+  ///
+  ///   fn execute(&self, mut old_state: Vec<u8>) -> Vec<u8> {
+  ///     self.execute_mut(&mut old_state);
+  ///     old_state
+  ///   }
+  /// }
+  ///
+  /// impl MutCommand<'_> for Push {
+  ///   fn execute_mut(&self, state: &mut Vec<u8>) {
+  ///     state.push(self.0);
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, Vec::new)?;
+  ///
+  /// madeleine.execute_command_mut(Push(1))?;
+  /// madeleine.execute_command_mut(Push(2))?;
+  ///
+  /// assert_eq!(madeleine.metrics().state_clones(), 0);
+  /// assert_eq!(madeleine.tap(|state| state), vec![1, 2]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_command_mut<'a, C>(&self, command: C) -> Result<Offset, MadeleineError>
   where
-    O: Fn(SystemState) -> T,
+    C: MutCommand<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    self
+      .middleware
+      .observe::<C, _>(1, || self.execute_unobserved_mut(command))
+  }
+
+  /// Execute and log a command as `execute_command_mut` does, without calling the middleware.
+  fn execute_unobserved_mut<'a, C>(&self, command: C) -> Result<Offset, MadeleineError>
+  where
+    C: MutCommand<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
   {
-    let val = self.internal_state.borrow();
+    if self.allocation_check()?.is_some() {
+      return self
+        .execute_unobserved_with(&command, Logging::Plain, |mut state, ctx| {
+          command.execute_mut_with_ctx(&mut state, ctx);
+
+          Ok((state, ()))
+        })
+        .map(|(offset, _id, ())| offset);
+    }
+
+    let started = Instant::now();
+
+    self.admit(std::slice::from_ref(&command))?;
+
+    let _command_lock = lock_recovering(&self.command_lock);
+
+    self.quotas.check_limits(self.len())?;
+
+    // Fails rather than waiting forever while this thread holds a `StateReadGuard`.
+    let mut state = self.internal_state.write()?;
+
+    command
+      .validate(&state)
+      .map_err(MadeleineError::CommandRejected)?;
+
+    let (_id, entry) = self.metrics.time_phase(Phase::Serialize, || {
+      self.command_log.serialize_command(&command)
+    })?;
+    let sequence = self.next_sequence()?;
+
+    #[cfg(any(test, feature = "testing"))]
+    self.command_log.failpoint(StorageOperation::BeforeCommit)?;
+
+    let ctx = CommandContext::at(self.total_commands_ever());
+    let offset = self.metrics.time_phase(Phase::Append, || {
+      self.command_log.append_sequenced_entry(&entry, sequence)
+    })?;
 
-    func(val.clone())
+    self.metrics.time_phase(Phase::Execute, || {
+      command.execute_mut_with_ctx(&mut state, &ctx)
+    });
+
+    drop(state);
+
+    let duration = started.elapsed();
+    self.metrics.record_commands_executed(1, duration);
+
+    self.after_append(offset, sequence, &entry, &command, duration)?;
+    self.snapshot_if_due()?;
+
+    Ok(offset)
   }
 
-  /// Gets the length of the command history.
-  pub fn len(&self) -> u64 {
-    self.command_log.len()
+  /// Execute and log a command, returning both its offset and its ULID in the log.
+  fn execute_logged<'a, C>(
+    &self,
+    command: &C,
+    logging: Logging,
+  ) -> Result<(Offset, Ulid), MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    let (offset, id, ()) = self.execute_logged_with(command, logging, |state, ctx| {
+      command
+        .try_execute_with_ctx(state, ctx)
+        .map(|state| (state, ()))
+    })?;
+
+    Ok((offset, id))
   }
 
-  /// Determine if the instance has an empty command history.
-  pub fn is_empty(&self) -> bool {
-    self.command_log.len() == 0
+  /// Execute and log a command as `execute_logged` does, running it with `execute`, which also produces an output.
+  /// A transition which `execute` rejects leaves the state untouched and isn't logged.
+  fn execute_logged_with<'a, C, O, E>(
+    &self,
+    command: &C,
+    logging: Logging,
+    execute: E,
+  ) -> Result<(Offset, Ulid, O), MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+    E: FnOnce(SystemState, &CommandContext) -> Result<(SystemState, O), String>,
+  {
+    self.middleware.observe::<C, _>(1, || {
+      self.execute_unobserved_with(command, logging, execute)
+    })
   }
 
-  /// Take and persist a snapshot of the internal state.
-  pub fn take_snapshot(&self) -> Result<usize, MadeleineError> {
-    let state = self.internal_state.try_borrow()?;
-    let next_snapshot_id = self.next_snapshot_id()?;
-    let location = snapshot_file_path(next_snapshot_id, self.location_dir_path.clone());
+  /// Execute and log a command as `execute_logged_with` does, without calling the middleware.
+  fn execute_unobserved_with<'a, C, O, E>(
+    &self,
+    command: &C,
+    logging: Logging,
+    execute: E,
+  ) -> Result<(Offset, Ulid, O), MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+    E: FnOnce(SystemState, &CommandContext) -> Result<(SystemState, O), String>,
+  {
+    let started = Instant::now();
 
-    let serialized = serde_json::to_string(&*state)?;
-    fs::write(location, serialized)?;
+    self.admit(std::slice::from_ref(command))?;
 
-    write_snapshot_id_file(
-      self.location_dir_path.join(SNAPSHOT_FILE_SUFFIX),
-      next_snapshot_id,
-    )?;
+    let _command_lock = lock_recovering(&self.command_lock);
 
-    Ok(0)
+    self.execute_locked_with(started, command, logging, execute)
   }
 
-  /// Determine the next snapshot id in sequence.
-  pub fn next_snapshot_id(&self) -> Result<usize, MadeleineError> {
-    let snapshot_file_path = snapshot_id_file_path(self.location_dir_path.clone());
+  /// Execute and log a command as `execute_unobserved_with` does, once it's been admitted
+  /// and while the caller holds the command lock. `started` is when the caller started executing it.
+  fn execute_locked_with<'a, C, O, E>(
+    &self,
+    started: Instant,
+    command: &C,
+    logging: Logging,
+    execute: E,
+  ) -> Result<(Offset, Ulid, O), MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+    E: FnOnce(SystemState, &CommandContext) -> Result<(SystemState, O), String>,
+  {
+    self.quotas.check_limits(self.len())?;
 
-    if snapshot_file_path.is_file() {
-      let raw = fs::read(snapshot_file_path)?;
-      let parsed: usize = serde_json::from_slice(&raw)?;
+    // Fails rather than waiting forever while this thread holds a `StateReadGuard`.
+    let mut state = self.internal_state.write()?;
 
-      Ok(parsed + 1)
-    } else {
-      Ok(0)
+    command
+      .validate(&state)
+      .map_err(MadeleineError::CommandRejected)?;
+
+    let check = self.allocation_check()?;
+    let ctx = CommandContext::at(self.total_commands_ever());
+    let (executed, within_limit) = self.metrics.time_phase(Phase::Execute, || {
+      execute_metered(check.as_ref(), || execute(state.to_owned(), &ctx))
+    });
+    within_limit?;
+
+    let (new, output) = executed.map_err(MadeleineError::CommandRejected)?;
+    let previous_state = std::mem::replace(&mut *state, new);
+
+    // A command which isn't logged mustn't change the state, or it would be lost on replay.
+    let logged = self
+      .metrics
+      .time_phase(Phase::Serialize, || {
+        self.command_log.serialize_command(command)
+      })
+      .and_then(|(id, entry)| {
+        let sequence = self.next_sequence()?;
+
+        self
+          .append_logged(id, &entry, sequence, logging)
+          .inspect_err(|_error| {
+            if let Logging::Idempotent(key) = logging {
+              self.idempotency.abandon(key, id);
+            }
+          })
+          .map(|offset| (id, entry, sequence, offset))
+      });
+
+    let (id, entry, sequence, offset) = match logged {
+      Ok(logged) => logged,
+      Err(error) => {
+        *state = previous_state;
+
+        return Err(error);
+      }
+    };
+
+    drop(state);
+
+    let duration = started.elapsed();
+    self.metrics.record_commands_executed(1, duration);
+
+    self.after_append(offset, sequence, &entry, command, duration)?;
+    self.snapshot_if_due()?;
+
+    Ok((offset, id, output))
+  }
+
+  /// Append a serialized command logged as `id`, as `logging` says, saving its idempotency key first if it has one.
+  fn append_logged(
+    &self,
+    id: Ulid,
+    entry: &[u8],
+    sequence: Option<u64>,
+    logging: Logging,
+  ) -> Result<Offset, MadeleineError> {
+    if let Logging::Idempotent(key) = logging {
+      self.idempotency.write_ahead(key, id)?;
     }
+
+    #[cfg(any(test, feature = "testing"))]
+    self.command_log.failpoint(StorageOperation::BeforeCommit)?;
+
+    self.metrics.time_phase(Phase::Append, || match logging {
+      Logging::Inverse(undone) => self
+        .command_log
+        .append_sequenced_entries(&[
+          (entry.to_vec(), sequence),
+          (undo_marker_entry(undone)?, None),
+        ])
+        .map(|offsets| offsets[0]),
+      _ => self.command_log.append_sequenced_entry(entry, sequence),
+    })
   }
-}
 
-fn snapshot_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
-  let snapshot_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_FILE_SUFFIX);
-  location_dir_path.join(snapshot_file_name)
-}
+  /// Execute and log several commands, either all or nothing, or skipping those which fail validation,
+  /// see `BatchMode`. Returns the offsets of the logged commands and any rejections.
+  ///
+  /// In `BatchMode::Atomic`, the commands are validated and executed against a copy of the state,
+  /// then logged together. A command which fails validation or serialization rolls back the whole batch,
+  /// failing with `MadeleineError::BatchFailed`, which identifies the command and why it failed.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::BatchMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let report = madeleine.execute_batch(vec![Add(2), Add(3)], BatchMode::Atomic)?;
+  ///
+  /// assert_eq!(report.offsets.len(), 2);
+  /// assert!(report.rejected.is_empty());
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_batch<'a, C>(
+    &self,
+    commands: Vec<C>,
+    mode: BatchMode,
+  ) -> Result<BatchReport, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    match mode {
+      BatchMode::Atomic => self
+        .middleware
+        .observe::<C, _>(commands.len(), || self.execute_atomic_batch(commands)),
+      BatchMode::BestEffort => self.execute_best_effort_batch(commands),
+    }
+  }
 
-fn snapshot_id_file_path(location_dir_path: PathBuf) -> PathBuf {
-  location_dir_path.join(SNAPSHOT_FILE_SUFFIX)
-}
+  /// Execute and log a sequence of commands with a single write to the log, rather than one each.
+  /// Returns the number of commands executed. Like `execute_batch` in `BatchMode::Atomic`, it's all or nothing:
+  /// if any command is rejected or the append fails, none of the state changes are visible.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.execute_commands([Add(1), Add(2), Add(3)])?, 3);
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 6);
+  /// assert_eq!(madeleine.len(), 3);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_commands<'a, C, I>(&self, commands: I) -> Result<usize, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+    I: IntoIterator<Item = C>,
+  {
+    let report = self.execute_batch(commands.into_iter().collect(), BatchMode::Atomic)?;
 
-/// Write the snapshot ID file
-fn write_snapshot_id_file(
-  snapshot_file_id_name: PathBuf,
-  current_snapshot_id: usize,
-) -> Result<usize, MadeleineError> {
-  let raw = serde_json::to_string(&current_snapshot_id)?;
-  fs::write(snapshot_file_id_name, raw)?;
+    Ok(report.offsets.len())
+  }
 
-  Ok(current_snapshot_id)
-}
+  /// Stage every command against a copy of the state, then log them with a single append.
+  fn execute_atomic_batch<'a, C>(&self, commands: Vec<C>) -> Result<BatchReport, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    if commands.is_empty() {
+      return Ok(BatchReport::default());
+    }
 
-#[cfg(test)]
-mod tests {
-  use super::*;
+    let started = Instant::now();
 
-  use assert_fs::prelude::*;
-  use predicates::prelude::*;
-  use pretty_assertions::assert_eq;
+    self.admit(&commands)?;
 
-  use std::collections::HashMap;
+    let _command_lock = lock_recovering(&self.command_lock);
 
-  #[derive(Debug, Clone, Deserialize, Serialize)]
-  enum Action {
-    Increment(String, usize),
-    Decrement(String, usize),
+    self
+      .quotas
+      .check_limits(self.len() + commands.len() as u64 - 1)?;
+
+    let check = self.allocation_check()?;
+    let first_position = self.total_commands_ever();
+    let mut state = self.internal_state.write()?;
+    let mut staged_state = state.clone();
+    let mut entries = Vec::with_capacity(commands.len());
+
+    for (index, command) in commands.iter().enumerate() {
+      let failed = |error: String| MadeleineError::BatchFailed {
+        failure: BatchFailure::new::<C>(index, error),
+        staged: index,
+      };
+
+      command.validate(&staged_state).map_err(failed)?;
+
+      let (_id, entry) = self
+        .command_log
+        .serialize_command(command)
+        .map_err(|error| failed(error.to_string()))?;
+
+      let (executed, within_limit) = execute_metered(check.as_ref(), || {
+        command.try_execute_with_ctx(
+          staged_state,
+          &CommandContext::at(first_position + index as u64),
+        )
+      });
+      within_limit.map_err(|error| failed(error.to_string()))?;
+
+      staged_state = executed.map_err(failed)?;
+      entries.push((entry, self.next_sequence()?));
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    self.command_log.failpoint(StorageOperation::BeforeCommit)?;
+
+    let offsets = self.command_log.append_sequenced_entries(&entries)?;
+
+    *state = staged_state;
+    drop(state);
+
+    let duration = started.elapsed();
+    self
+      .metrics
+      .record_commands_executed(commands.len() as u64, duration);
+
+    for ((offset, (entry, sequence)), command) in offsets.iter().zip(&entries).zip(&commands) {
+      self.after_append(*offset, *sequence, entry, command, duration)?;
+    }
+
+    self.snapshot_if_due()?;
+
+    Ok(BatchReport {
+      offsets,
+      rejected: Vec::new(),
+    })
   }
 
-  impl Command<'_> for Action {
-    type SystemState = HashMap<String, usize>;
+  /// Execute the commands one by one, journaling those which fail validation.
+  fn execute_best_effort_batch<'a, C>(
+    &self,
+    commands: Vec<C>,
+  ) -> Result<BatchReport, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    let mut report = BatchReport::default();
+
+    for (index, command) in commands.iter().enumerate() {
+      match self.execute_logged(command, Logging::Plain) {
+        Ok((offset, _id)) => report.offsets.push(offset),
+        Err(MadeleineError::CommandRejected(reason)) => {
+          let failure = BatchFailure::new::<C>(index, reason);
+
+          journal_rejection(&self.location_dir_path, &failure, command)?;
+          report.rejected.push(failure);
+        }
+        Err(error) => return Err(error),
+      }
+    }
 
-    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
-      let mut new_state = old_state.clone();
+    Ok(report)
+  }
 
-      match self {
-        Self::Increment(key, amount) => new_state
-          .entry(key.to_string())
-          .and_modify(|e| *e += amount)
-          .or_insert(*amount),
-        Self::Decrement(key, amount) => new_state
+  /// Check how much each command allocated once it returns, or stop checking by passing `None`.
+  /// A command which allocated too much fails with `MadeleineError::CommandResourceLimit`, leaving the state and log
+  /// untouched. The check can't stop a command while it executes, see `AllocationCheck`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  ///
+  /// use madeleine::{AllocationCheck, AllocationCounter};
+  ///
+  /// /// Stands in for a counter reading the allocator's statistics for the calling thread.
+  /// struct NothingAllocated;
+  ///
+  /// impl AllocationCounter for NothingAllocated {
+  ///   fn thread_bytes_allocated(&self) -> u64 {
+  ///     0
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_allocation_check(Some(AllocationCheck::new(Arc::new(NothingAllocated), 1024)))?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  #[cfg(feature = "allocation-check")]
+  pub fn set_allocation_check(&self, check: Option<AllocationCheck>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.allocation_check) = check;
+
+    Ok(())
+  }
+
+  /// The allocation check, if one is set.
+  #[cfg(feature = "allocation-check")]
+  fn allocation_check(&self) -> Result<Option<AllocationCheck>, MadeleineError> {
+    Ok(lock_recovering(&self.allocation_check).clone())
+  }
+
+  /// Without the `allocation-check` feature, commands are never checked.
+  #[cfg(not(feature = "allocation-check"))]
+  fn allocation_check(&self) -> Result<Option<AllocationCheck>, MadeleineError> {
+    Ok(None)
+  }
+
+  /// The next global sequence number, if a sequencer is set.
+  fn next_sequence(&self) -> Result<Option<u64>, MadeleineError> {
+    Ok(
+      lock_recovering(&self.sequencer)
+        .as_ref()
+        .map(|sequencer| sequencer.next_sequence()),
+    )
+  }
+
+  /// Account for a logged command, and pass it on to followers, subscribers, hooks and projections.
+  /// `duration` is how long executing it took, or the batch it was executed in.
+  fn after_append<'a, C>(
+    &self,
+    offset: Offset,
+    sequence: Option<u64>,
+    entry: &[u8],
+    command: &C,
+    duration: Duration,
+  ) -> Result<(), MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    self.append_notifier.notify();
+    self.snapshot_scheduler.record_command();
+    self.quotas.record_append(entry.len() as u64);
+    self.check_quota_thresholds()?;
+
+    #[cfg(feature = "registry")]
+    self.registration.record_command_count(self.len());
+
+    self.metrics.time_phase(Phase::Hooks, || {
+      let publish = !self.subscribers.is_empty()?;
+
+      if publish || !self.command_hooks.is_empty() {
+        let logged = RawLoggedCommand::from_entry(offset, sequence, entry)?;
+
+        if publish {
+          for lag in self.subscribers.publish(&logged)? {
+            self.emit(StoreEvent::SubscriberLagging {
+              at: SystemTime::now(),
+              lag,
+            })?;
+          }
+        }
+
+        self.command_hooks.call(&logged, duration);
+      }
+
+      self.apply_to_projections(std::any::type_name::<C>(), || serde_json::to_value(command))
+    })
+  }
+
+  /// Execute a command unless a command was already executed with the same idempotency key,
+  /// e.g. because a client retried a request whose response it never received.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert!(madeleine.execute_idempotent("request-1", Add(2))?.was_applied());
+  /// assert!(!madeleine.execute_idempotent("request-1", Add(2))?.was_applied());
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_idempotent<'a, C>(
+    &self,
+    key: &str,
+    command: C,
+  ) -> Result<IdempotentOutcome<()>, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    self.execute_idempotent_with(key, &command, |state, ctx| {
+      command
+        .try_execute_with_ctx(state, ctx)
+        .map(|state| (state, ()))
+    })
+  }
+
+  /// Execute a command unless a command was already executed with the same idempotency key,
+  /// returning the output it produced.
+  /// The output is cached with the key, so that a repeated call receives the original output
+  /// as `IdempotentOutcome::Replayed`, subject to the limits set with `set_idempotency_options`.
+  /// Concurrent calls with the same key execute the command once.
+  ///
+  /// Keys are persisted before the command is logged, then again with the output. If the process dies in between,
+  /// the key is kept without its output when the command made it into the log, so a retry gets
+  /// `IdempotentOutcome::AlreadyApplied`, and forgotten otherwise, so a retry executes the command.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{CommandWithOutput, IdempotentOutcome};
+  ///
+  /// /// Adds to the total, returning the new total.
+  /// #[derive(serde::Deserialize, serde::Serialize)]
+  /// struct Deposit(u64);
+  ///
+  /// impl CommandWithOutput<'_> for Deposit {
+  ///   type SystemState = u64;
+  ///   type Output = u64;
+  ///
+  ///   fn execute_with_output(&self, old_state: u64) -> (u64, u64) {
+  ///     (old_state + self.0, old_state + self.0)
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let first = madeleine.execute_idempotent_with_output("request-1", Deposit(2))?;
+  /// let retried = madeleine.execute_idempotent_with_output("request-1", Deposit(2))?;
+  ///
+  /// assert_eq!(first, IdempotentOutcome::Applied(2));
+  /// assert_eq!(retried, IdempotentOutcome::Replayed(2));
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_idempotent_with_output<'a, C>(
+    &self,
+    key: &str,
+    command: C,
+  ) -> Result<IdempotentOutcome<C::Output>, MadeleineError>
+  where
+    C: CommandWithOutput<'a, SystemState = SystemState>,
+    C::Output: Serialize + DeserializeOwned,
+  {
+    self.execute_idempotent_with(key, &command, |state, _ctx| {
+      command.try_execute_with_output(state)
+    })
+  }
+
+  /// Execute and log a command under an idempotency key, running it with `execute` as `execute_logged_with` does.
+  /// The key is looked up, saved ahead of the command and saved again with its output all under the command lock,
+  /// so that a concurrent call with the same key finds it rather than executing the command again.
+  fn execute_idempotent_with<'a, C, O, E>(
+    &self,
+    key: &str,
+    command: &C,
+    execute: E,
+  ) -> Result<IdempotentOutcome<O>, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+    O: Serialize + DeserializeOwned,
+    E: FnOnce(SystemState, &CommandContext) -> Result<(SystemState, O), String>,
+  {
+    self.middleware.observe::<C, _>(1, || {
+      let started = Instant::now();
+      let _command_lock = lock_recovering(&self.command_lock);
+
+      if let Some(cached) = self.idempotency.lookup(key)? {
+        return match cached.output {
+          Some(output) => Ok(IdempotentOutcome::Replayed(serde_json::from_value(output)?)),
+          None => Ok(IdempotentOutcome::AlreadyApplied),
+        };
+      }
+
+      self.admit(std::slice::from_ref(command))?;
+
+      let (_offset, id, output) =
+        self.execute_locked_with(started, command, Logging::Idempotent(key), execute)?;
+
+      #[cfg(any(test, feature = "testing"))]
+      self
+        .command_log
+        .failpoint(StorageOperation::IdempotencyRecord)?;
+
+      self
+        .idempotency
+        .record(key, id, serde_json::to_value(&output)?)?;
+
+      Ok(IdempotentOutcome::Applied(output))
+    })
+  }
+
+  /// Change how long and how much is remembered about idempotency keys.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::IdempotencyOptions;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_idempotency_options(IdempotencyOptions {
+  ///   ttl: Duration::from_secs(60),
+  ///   ..IdempotencyOptions::default()
+  /// });
+  ///
+  /// madeleine.execute_idempotent("request-1", Add(2))?;
+  /// assert!(!madeleine.execute_idempotent("request-1", Add(2))?.was_applied());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_idempotency_options(&self, options: IdempotencyOptions) {
+    self.idempotency.set_options(options);
+  }
+
+  /// Make the state look like `desired` by logging a `ReconcileCommand` holding the structural difference
+  /// between the current and desired states, as an RFC 6902 JSON patch.
+  /// Returns the ULID of the logged command, or `None` if the states don't differ.
+  /// Differences too large to log fail with `MadeleineError::ReconcileError`; import such states from a snapshot instead.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.reconcile(&5)?.is_some());
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// assert_eq!(madeleine.reconcile(&5)?, None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn reconcile(&self, desired: &SystemState) -> Result<Option<Ulid>, MadeleineError> {
+    let desired = serde_json::to_value(desired)?;
+
+    // States which already match need no command, so don't reach the middleware or the command lock.
+    if self.reconcile_command(&desired)?.is_empty() {
+      return Ok(None);
+    }
+
+    self
+      .middleware
+      .observe::<ReconcileCommand<SystemState>, _>(1, || {
+        let started = Instant::now();
+        // Held from diffing the states until the patch is logged, so no command executed
+        // in between can leave the patch applying to a state it wasn't computed from.
+        let _command_lock = lock_recovering(&self.command_lock);
+
+        let command = self.reconcile_command(&desired)?;
+
+        if command.is_empty() {
+          return Ok(None);
+        }
+
+        let payload_bytes = serde_json::to_vec(&command)?.len();
+
+        if payload_bytes > MAX_PAYLOAD_BYTES {
+          return Err(MadeleineError::ReconcileError(format!(
+            "The difference to reconcile is {} bytes, over the {} byte limit for a single command. Import the desired state from a snapshot instead.",
+            payload_bytes, MAX_PAYLOAD_BYTES
+          )));
+        }
+
+        self.admit(std::slice::from_ref(&command))?;
+
+        let (_offset, id, ()) =
+          self.execute_locked_with(started, &command, Logging::Plain, |state, ctx| {
+            command
+              .try_execute_with_ctx(state, ctx)
+              .map(|state| (state, ()))
+          })?;
+
+        Ok(Some(id))
+      })
+  }
+
+  /// The difference between the current state and `desired`, as a command.
+  fn reconcile_command(
+    &self,
+    desired: &serde_json::Value,
+  ) -> Result<ReconcileCommand<SystemState>, MadeleineError> {
+    let current = serde_json::to_value(&*self.internal_state.read())?;
+
+    Ok(ReconcileCommand::diff(&current, desired))
+  }
+
+  /// Register a projection: a derived read model maintained incrementally as commands of type `C` are executed.
+  /// Commands of other types are never folded into it, even if they'd deserialize as a `C`.
+  /// The projection starts from `init` and is immediately rebuilt by folding every command already in the log,
+  /// so registering after `resume` brings it up to date with the full history. The log doesn't record the types
+  /// of commands, so as with `resume_replaying`, every command in it must be a `C`, or registering fails
+  /// with `MadeleineError::ProjectionError`. No command is executed while the log is being folded.
+  /// Projections live in memory only. If `fold` panics, the projection is marked as poisoned
+  /// while the system's state and the command log remain unaffected.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.register_projection::<Add, u64, _>("largest", 0, |largest, add| {
+  ///   *largest = (*largest).max(add.0)
+  /// })?;
+  /// madeleine.execute_command(Add(5))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.projection::<u64>("largest")?, 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn register_projection<C, P, F>(
+    &self,
+    name: &str,
+    init: P,
+    fold: F,
+  ) -> Result<(), MadeleineError>
+  where
+    C: DeserializeOwned + 'static,
+    P: Clone + Send + 'static,
+    F: Fn(&mut P, &C) + Send + 'static,
+  {
+    // Held until the projection is registered, so a command logged after the fold can't miss it.
+    let _command_lock = lock_recovering(&self.command_lock);
+
+    if lock_recovering(&self.projections).contains_key(name) {
+      return Err(MadeleineError::ProjectionError(format!(
+        "A projection named '{}' is already registered",
+        name
+      )));
+    }
+
+    let mut projection: Box<dyn ErasedProjection> = Box::new(Projection::new(init, fold));
+
+    self.fold_log_into(name, projection.as_mut())?;
+
+    lock_recovering(&self.projections).insert(name.to_string(), projection);
+
+    Ok(())
+  }
+
+  /// Read the current value of a registered projection.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.register_projection::<Add, u64, _>("commands", 0, |count, _add| *count += 1)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.projection::<u64>("commands")?, 2);
+  /// assert!(madeleine.projection::<u64>("missing").is_err());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn projection<P: Clone + 'static>(&self, name: &str) -> Result<P, MadeleineError> {
+    let projections = lock_recovering(&self.projections);
+
+    let projection = projections.get(name).ok_or_else(|| {
+      MadeleineError::ProjectionError(format!("No projection named '{}' is registered", name))
+    })?;
+
+    if projection.is_poisoned() {
+      return Err(MadeleineError::ProjectionPoisoned(name.to_string()));
+    }
+
+    projection
+      .state()
+      .downcast_ref::<P>()
+      .cloned()
+      .ok_or_else(|| {
+        MadeleineError::ProjectionError(format!(
+          "Projection '{}' does not hold a value of the requested type",
+          name
+        ))
+      })
+  }
+
+  /// Identifier assigned to the store when it was first created.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let store_id = madeleine.store_id();
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// assert_eq!(Madeleine::<u64>::resume(&store)?.store_id(), store_id);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn store_id(&self) -> Ulid {
+    self.store_id
+  }
+
+  /// Hash function used by the store's integrity features, chosen when it was created.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::HashAlgo;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.hash_algo(), HashAlgo::default());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn hash_algo(&self) -> HashAlgo {
+    self.hash_algo
+  }
+
+  /// How the store serializes commands in its log, see `PayloadFormat`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::PayloadFormat;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.payload_format(), PayloadFormat::Json);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn payload_format(&self) -> PayloadFormat {
+    self.command_log.payload_format()
+  }
+
+  /// How the store keeps its command log on disk, see `LogBackend`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::LogBackend;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.log_backend(), LogBackend::CommitLog);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn log_backend(&self) -> LogBackend {
+    self.command_log.backend()
+  }
+
+  /// Sync commands to disk from now on as `sync_mode` says, rather than as the store's `LogBackend` does by default.
+  /// `SyncMode::Normal` executes commands faster, at the risk of losing the latest ones if the machine goes down.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SyncMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_sync_mode(SyncMode::Full);
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.sync_mode(), SyncMode::Full);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_sync_mode(&self, sync_mode: SyncMode) {
+    self.command_log.set_sync_mode(sync_mode);
+  }
+
+  /// When commands are synced to disk, see `Madeleine::set_sync_mode`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{LogBackend, SyncMode};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::builder(&store).log_backend(LogBackend::File).build(|| 0)?;
+  ///
+  /// assert_eq!(madeleine.sync_mode(), SyncMode::Full);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn sync_mode(&self) -> SyncMode {
+    self.command_log.sync_mode()
+  }
+
+  /// Hex-encoded hash of the canonical JSON serialization of the state, using the store's `HashAlgo`,
+  /// in which maps are ordered by key. Equal states always have equal hashes.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let other_store = scratch_store();
+  /// let other = Madeleine::new(&other_store, || 2)?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.state_hash()?, other.state_hash()?);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn state_hash(&self) -> Result<String, MadeleineError> {
+    self.hash_algo.canonical_hash(&*self.internal_state.read())
+  }
+
+  /// Access the runtime counters and latency histograms for this instance.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::metrics::Phase;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.metrics().set_phase_timing(true);
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.metrics().phase_histogram(Phase::Execute).count(), 1);
+  /// assert_eq!(madeleine.metrics().commands_executed(), 1);
+  /// assert!(madeleine.metrics().p99_execute_ns() >= madeleine.metrics().mean_execute_ns());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn metrics(&self) -> &Metrics {
+    &self.metrics
+  }
+
+  /// Fold every command in the log into a projection.
+  fn fold_log_into(
+    &self,
+    name: &str,
+    projection: &mut dyn ErasedProjection,
+  ) -> Result<(), MadeleineError> {
+    self.command_log.for_each_entry(|offset, entry| {
+      let logged = RawLoggedCommand::from_entry(
+        offset,
+        None,
+        payload_format::require_json_entry(entry, "projections")?,
+      )?;
+
+      if logged.is_skipped_on_replay() {
+        return Ok(());
+      }
+
+      let value: serde_json::Value = serde_json::from_slice(&logged.payload)?;
+      let poisoned = projection.apply(&value).map_err(|error| {
+        MadeleineError::ProjectionError(format!(
+          "Projection '{}' can't be rebuilt, as the command at offset {} isn't a {}: {}",
+          name,
+          offset,
+          projection.command_type(),
+          error
+        ))
+      })?;
+
+      if poisoned {
+        self.record_projection_poisoned(name)?;
+      }
+
+      Ok(())
+    })
+  }
+
+  /// Fold an executed command into the registered projections of its type, recording any which become poisoned.
+  /// `command_type` tags the command's type, and `command` serializes it, only if some projection is of that type.
+  fn apply_to_projections<V>(&self, command_type: &str, command: V) -> Result<(), MadeleineError>
+  where
+    V: FnOnce() -> Result<serde_json::Value, serde_json::Error>,
+  {
+    let mut projections = lock_recovering(&self.projections);
+    let mut matching = projections
+      .iter_mut()
+      .filter(|(_name, projection)| projection.command_type() == command_type)
+      .peekable();
+
+    if matching.peek().is_none() {
+      return Ok(());
+    }
+
+    let command = command()?;
+
+    for (name, projection) in matching {
+      if projection.apply(&command)? {
+        self.record_projection_poisoned(name)?;
+      }
+    }
+
+    Ok(())
+  }
+
+  fn record_projection_poisoned(&self, name: &str) -> Result<(), MadeleineError> {
+    self.metrics.record_projection_poisoned();
+
+    self.emit(StoreEvent::ProjectionPoisoned {
+      at: SystemTime::now(),
+      name: name.to_string(),
+    })
+  }
+
+  /// Receive the events affecting this store's health from now on, in the order they happened.
+  /// Delivery is best-effort: each receiver buffers up to `options.capacity` events,
+  /// handling a full buffer according to `options.policy` just like `subscribe`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{StoreEvent, SubscribeOptions};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let events = madeleine.events(SubscribeOptions::default())?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let snapshot_id = madeleine.take_snapshot(true)?;
+  ///
+  /// assert!(matches!(
+  ///   events.try_recv()?,
+  ///   Some(StoreEvent::SnapshotTaken { snapshot_id: taken, .. }) if taken == snapshot_id
+  /// ));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn events(&self, options: SubscribeOptions) -> Result<Receiver<StoreEvent>, MadeleineError> {
+    self.events.subscribe(options)
+  }
+
+  /// Deliver an event to every receiver.
+  pub(crate) fn emit(&self, event: StoreEvent) -> Result<(), MadeleineError> {
+    if !self.events.is_empty()? {
+      self.events.publish(&event)?;
+    }
+
+    Ok(())
+  }
+
+  /// What happened while opening the store, e.g. whether it had been shut down cleanly.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let reopened = Madeleine::new(&store, || 0)?;
+  ///
+  /// assert!(!reopened.open_report().clean_shutdown);
+  /// assert_eq!(reopened.open_report().entries_verified, 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn open_report(&self) -> &OpenReport {
+    &self.open_report
+  }
+
+  /// Shut the store down cleanly, flushing the log and recording its head and the state's hash,
+  /// so that the next open can skip verification, see `VerificationLevel`.
+  /// Dropping an instance without closing it is treated as a crash.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.close()?;
+  ///
+  /// let reopened = Madeleine::new(&store, || 0)?;
+  ///
+  /// assert!(reopened.open_report().clean_shutdown);
+  /// assert_eq!(reopened.open_report().entries_verified, 0);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn close(self) -> Result<(), MadeleineError> {
+    self.record_clean_shutdown()
+  }
+
+  /// Flush the log and record a clean shutdown, as `close` does, without consuming the instance.
+  fn record_clean_shutdown(&self) -> Result<(), MadeleineError> {
+    self.command_log.flush()?;
+
+    let clean_shutdown = CleanShutdown {
+      head_id: self.head_id()?,
+      state_hash: self.state_hash()?,
+    };
+
+    let mut metadata = StoreMetadata::load_or_create(&self.location_dir_path, self.hash_algo)?;
+    metadata.clean_shutdown = Some(clean_shutdown);
+    metadata.write(&self.location_dir_path)
+  }
+
+  /// Consume the instance and return its internal state.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.into_inner(), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn into_inner(self) -> SystemState {
+    self.internal_state.into_inner()
+  }
+
+  /// Run a closure passed a clone of the instance's internal state.
+  /// Cloning a large state is expensive, so prefer `tap_ref` where a reference will do.
+  ///
+  /// # Panics
+  ///
+  /// In strict mode, panics if the closure mutated the internal state, see `try_tap`, which returns the error instead.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state * 10), 20);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn tap<T, O>(&self, func: O) -> T
+  where
+    O: Fn(SystemState) -> T,
+  {
+    match self.try_tap(func) {
+      Ok(result) => result,
+      Err(error) => panic!("{}", error),
+    }
+  }
+
+  /// Run a closure passed a clone of the instance's internal state as `tap` does, failing in strict mode
+  /// with `MadeleineError::StateMutatedOutsideCommand` if the live state changed while it ran, see `set_strict`.
+  /// The closure owns its clone, so it only reaches the live state through interior mutability the clone shares,
+  /// such as an `Arc<Mutex<_>>` or `Rc<RefCell<_>>`; a `Cell` or `RefCell` held by value is cloned along with it.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_strict(true);
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.try_tap(|state| state * 10)?, 20);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn try_tap<T, O>(&self, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(SystemState) -> T,
+  {
+    let started = Instant::now();
+    let val = self.internal_state.read();
+
+    if self.metrics.record_state_clone() {
+      warn_clone_rate(&self.metrics);
+    }
+
+    let tapped = self.guard_unmutated(&val, || func(val.clone()));
+    self.metrics.record_tap(started.elapsed());
+
+    tapped
+  }
+
+  /// Run a closure passed a reference to the instance's internal state, without cloning it.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || vec![1, 2, 3])?;
+  ///
+  /// assert_eq!(madeleine.tap_ref(|state| state.len())?, 3);
+  /// assert_eq!(madeleine.metrics().state_clones(), 0);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn tap_ref<T, O>(&self, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(&SystemState) -> T,
+  {
+    let started = Instant::now();
+    let val = self.internal_state.read();
+
+    let tapped = self.guard_unmutated(&val, || func(&val));
+    self.metrics.record_tap(started.elapsed());
+
+    tapped
+  }
+
+  /// Borrow the state, for reads which don't fit in a closure, e.g. passing a reference to other code.
+  /// Commands wait while the guard is held, see `StateReadGuard`, and this waits for a command executing
+  /// on another thread. See `try_read`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let state = madeleine.read();
+  ///
+  /// assert_eq!(*state, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn read(&self) -> StateReadGuard<'_, SystemState> {
+    StateReadGuard::new(self.internal_state.read())
+  }
+
+  /// Borrow the state as `read` does, failing with `MadeleineError::Contended` instead of waiting for a command.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(*madeleine.try_read()?, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn try_read(&self) -> Result<StateReadGuard<'_, SystemState>, MadeleineError> {
+    Ok(StateReadGuard::new(self.internal_state.try_read()?))
+  }
+
+  /// Copy the state into an owned snapshot which can be kept across `await` points or sent to other threads.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let snapshot = madeleine.arc_snapshot()?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// let reader = std::thread::spawn(move || *snapshot);
+  ///
+  /// assert_eq!(reader.join().expect("reader panicked"), 2);
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn arc_snapshot(&self) -> Result<ArcStateSnapshot<SystemState>, MadeleineError> {
+    // Commands append while holding the write lock, so the head read under the read lock is the state's.
+    let state = self.internal_state.read();
+    let head_id = self.head_id()?;
+
+    if self.metrics.record_state_clone() {
+      warn_clone_rate(&self.metrics);
+    }
+
+    Ok(ArcStateSnapshot::new(state.clone(), head_id))
+  }
+
+  /// Run a query against the state, see `Query`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::Query;
+  ///
+  /// struct IsAbove(u64);
+  ///
+  /// impl Query for IsAbove {
+  ///   type SystemState = u64;
+  ///   type Output = bool;
+  ///
+  ///   fn execute(&self, state: &u64) -> bool {
+  ///     *state > self.0
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.execute_query(&IsAbove(1))?);
+  /// assert!(!madeleine.execute_query(&IsAbove(2))?);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_query<Q>(&self, query: &Q) -> Result<Q::Output, MadeleineError>
+  where
+    Q: Query<SystemState = SystemState>,
+  {
+    self.tap_ref(|state| query.execute(state))
+  }
+
+  /// Run a query as `execute_query` does, reusing its output if an equal query, as serialized, already ran
+  /// since the last command. Hits and misses are counted in the metrics.
+  /// Without a cache, see `set_query_cache`, the query simply runs every time.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{Query, QueryCacheOptions};
+  ///
+  /// #[derive(serde::Serialize)]
+  /// struct IsAbove(u64);
+  ///
+  /// impl Query for IsAbove {
+  ///   type SystemState = u64;
+  ///   type Output = bool;
+  ///
+  ///   fn execute(&self, state: &u64) -> bool {
+  ///     *state > self.0
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_query_cache(Some(QueryCacheOptions::default()))?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.execute_query_cached(&IsAbove(1))?);
+  /// assert!(madeleine.execute_query_cached(&IsAbove(1))?);
+  /// assert_eq!(madeleine.metrics().query_cache_misses(), 1);
+  /// assert_eq!(madeleine.metrics().query_cache_hits(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_query_cached<Q>(&self, query: &Q) -> Result<Q::Output, MadeleineError>
+  where
+    Q: Query<SystemState = SystemState> + Serialize,
+    Q::Output: Clone + Serialize + Send + 'static,
+  {
+    if !self.query_cache.is_enabled() {
+      return self.execute_query(query);
+    }
+
+    let key = query_cache_key(query)?;
+    let head_id = self.head_id()?;
+
+    if let Some(output) = self.query_cache.lookup(&key, head_id)? {
+      self.metrics.record_query_cache_hit();
+
+      return Ok(output);
+    }
+
+    self.metrics.record_query_cache_miss();
+
+    let output = self.execute_query(query)?;
+
+    self.query_cache.insert(key, head_id, output.clone())?;
+
+    Ok(output)
+  }
+
+  /// Cache the outputs of `execute_query_cached` within the given limits, or stop caching them by passing `None`.
+  /// Cached outputs are dropped whenever a command is logged.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{Query, QueryCacheOptions};
+  ///
+  /// #[derive(serde::Serialize)]
+  /// struct Total;
+  ///
+  /// impl Query for Total {
+  ///   type SystemState = u64;
+  ///   type Output = u64;
+  ///
+  ///   fn execute(&self, state: &u64) -> u64 {
+  ///     *state
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_query_cache(Some(QueryCacheOptions {
+  ///   max_entries: 10,
+  ///   ..QueryCacheOptions::default()
+  /// }))?;
+  ///
+  /// assert_eq!(madeleine.execute_query_cached(&Total)?, 0);
+  /// madeleine.execute_command(Add(2))?;
+  /// assert_eq!(madeleine.execute_query_cached(&Total)?, 2);
+  /// assert_eq!(madeleine.execute_query_cached(&Total)?, 2);
+  ///
+  /// assert_eq!(madeleine.metrics().query_cache_hits(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_query_cache(&self, options: Option<QueryCacheOptions>) -> Result<(), MadeleineError> {
+    self.query_cache.set_options(options)
+  }
+
+  /// Enable or disable strict mode, in which every `tap`, `tap_ref` and query re-hashes the state afterwards
+  /// to check the closure didn't mutate it, e.g. through a `Mutex`, `RefCell` or `Cell` inside the state.
+  /// Such mutations aren't logged and so are lost on replay. Reads through a `SharedMadeleine` are checked too.
+  ///
+  /// Hashing the state on every read is expensive, so strict mode is off by default.
+  /// It's intended for tests, as the way to validate that an application only changes state through commands.
+  /// A violation is reported as `MadeleineError::StateMutatedOutsideCommand` by `try_tap`, `tap_ref`
+  /// and `execute_query`, and as a panic by `tap`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use std::cell::Cell;
+  ///
+  /// use madeleine::MadeleineError;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || Cell::new(0))?;
+  /// madeleine.set_strict(true);
+  ///
+  /// let mutated = madeleine.tap_ref(|state| state.set(1));
+  ///
+  /// assert!(matches!(mutated, Err(MadeleineError::StateMutatedOutsideCommand(_))));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_strict(&self, strict: bool) {
+    self.strict.store(strict, Ordering::Relaxed);
+  }
+
+  /// Determine if strict mode is enabled.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert!(!madeleine.is_strict());
+  ///
+  /// madeleine.set_strict(true);
+  ///
+  /// assert!(madeleine.is_strict());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn is_strict(&self) -> bool {
+    self.strict.load(Ordering::Relaxed)
+  }
+
+  /// Run a read-only closure, checking in strict mode that the state's canonical hash is unchanged afterwards.
+  pub(crate) fn guard_unmutated<T, O>(
+    &self,
+    state: &SystemState,
+    func: O,
+  ) -> Result<T, MadeleineError>
+  where
+    O: FnOnce() -> T,
+  {
+    if !self.strict.load(Ordering::Relaxed) {
+      return Ok(func());
+    }
+
+    let before = self.hash_algo.canonical_hash(state)?;
+    let result = func();
+    let after = self.hash_algo.canonical_hash(state)?;
+
+    if before == after {
+      Ok(result)
+    } else {
+      let description = format!(
+        "state hash changed from {} to {} while reading it",
+        before, after
+      );
+
+      self.emit(StoreEvent::CorruptionFound {
+        at: SystemTime::now(),
+        description: description.clone(),
+      })?;
+
+      Err(MadeleineError::StateMutatedOutsideCommand(description))
+    }
+  }
+
+  /// Create a follower of this instance's command log, which may be sent to other threads
+  /// and is woken as soon as this instance appends a command.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let follower = madeleine.follower();
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let commands = follower.commands_after(Ulid::nil())?;
+  ///
+  /// assert_eq!(commands.len(), 1);
+  /// assert_eq!(commands[0].deserialize::<Add>()?.0, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn follower(&self) -> Follower {
+    Follower::in_process(
+      command_log_dir_path(&self.location_dir_path),
+      self.append_notifier.clone(),
+    )
+  }
+
+  /// Block until at least one command newer than `after` has been logged, or until the timeout elapses,
+  /// returning the newer commands. Commands appended through this instance from other threads wake it
+  /// as soon as they're logged; commands appended by other processes are picked up by polling.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let commands = madeleine.wait_for_commands(Ulid::nil(), Duration::from_secs(1))?;
+  ///
+  /// assert_eq!(commands.len(), 1);
+  /// assert_eq!(commands[0].id, madeleine.head_id()?);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn wait_for_commands(
+    &self,
+    after: Ulid,
+    timeout: Duration,
+  ) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    self.follower().wait_for_commands(after, timeout)
+  }
+
+  /// Subscribe to the commands appended to this instance from now on.
+  /// Each subscription buffers up to `options.capacity` commands, handling a full buffer according to `options.policy`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SubscribeOptions;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let subscription = madeleine.subscribe(SubscribeOptions::default())?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let logged = subscription.try_recv()?.expect("a command was logged");
+  ///
+  /// assert_eq!(logged.deserialize::<Add>()?.0, 2);
+  /// assert_eq!(subscription.try_recv()?, None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn subscribe(&self, options: SubscribeOptions) -> Result<Subscription, MadeleineError> {
+    self.subscribers.subscribe(options)
+  }
+
+  /// How far behind each live subscription is.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SubscribeOptions;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let subscription = madeleine.subscribe(SubscribeOptions::default())?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// let lags = madeleine.subscriber_lags()?;
+  ///
+  /// assert_eq!(lags.len(), 1);
+  /// assert_eq!(lags[0].subscription_id, subscription.id());
+  /// assert_eq!(lags[0].buffered, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn subscriber_lags(&self) -> Result<Vec<SubscriberLag>, MadeleineError> {
+    self.subscribers.lags()
+  }
+
+  /// Limit the store's resources. Once a hard limit is reached, commands fail with `MadeleineError::QuotaExceeded`.
+  /// Before then, a `ResourceWarning` is passed to the hook set with `set_resource_warning_hook`
+  /// the first time usage crosses each warning threshold. Dropping back below a threshold re-arms its warning.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::quota::Quotas;
+  /// use madeleine::MadeleineError;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_quotas(Quotas {
+  ///   max_commands: Some(1),
+  ///   ..Quotas::default()
+  /// })?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// let rejected = madeleine.execute_command(Add(3));
+  ///
+  /// assert!(matches!(rejected, Err(MadeleineError::QuotaExceeded(_))));
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_quotas(&self, quotas: Quotas) -> Result<(), MadeleineError> {
+    self.quotas.set_quotas(quotas, &self.location_dir_path)?;
+    self.check_quota_thresholds()
+  }
+
+  /// Limit how quickly commands are executed, see `RateLimits`. Each limit starts with a full burst.
+  /// Commands over a limit fail with `MadeleineError::RateLimited` before any work is done,
+  /// and are counted in `Metrics::commands_rate_limited`. An atomic batch takes tokens for all its commands at once,
+  /// and like a command larger than the byte burst, a batch larger than the burst is admitted once the bucket is full.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{MadeleineError, RateLimit, RateLimits};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_rate_limits(RateLimits {
+  ///   commands: Some(RateLimit { per_second: 1, burst: 1 }),
+  ///   ..RateLimits::default()
+  /// })?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// let rejected = madeleine.execute_command(Add(3));
+  ///
+  /// assert!(matches!(rejected, Err(MadeleineError::RateLimited { .. })));
+  /// assert_eq!(madeleine.metrics().commands_rate_limited(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_rate_limits(&self, limits: RateLimits) -> Result<(), MadeleineError> {
+    self.rate_limiter.set_limits(limits)
+  }
+
+  /// The limits on how quickly commands are executed.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{RateLimit, RateLimits};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert!(!madeleine.rate_limits().is_limited());
+  ///
+  /// let limits = RateLimits {
+  ///   commands: Some(RateLimit::per_second(100)),
+  ///   ..RateLimits::default()
+  /// };
+  /// madeleine.set_rate_limits(limits)?;
+  ///
+  /// assert_eq!(madeleine.rate_limits(), limits);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn rate_limits(&self) -> RateLimits {
+    self.rate_limiter.limits()
+  }
+
+  /// Measure time for the rate limits and the maintenance schedule with another clock,
+  /// e.g. a `testing::ManualClock` in tests. Set it before the maintenance schedule, which is timed from when it's set.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::testing::ManualClock;
+  /// use madeleine::{RateLimit, RateLimits};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let clock = Arc::new(ManualClock::new());
+  /// madeleine.set_clock(clock.clone());
+  /// madeleine.set_rate_limits(RateLimits {
+  ///   commands: Some(RateLimit { per_second: 1, burst: 1 }),
+  ///   ..RateLimits::default()
+  /// })?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// assert!(madeleine.execute_command(Add(3)).is_err());
+  ///
+  /// clock.advance(Duration::from_secs(1));
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+    self.rate_limiter.set_clock(clock);
+  }
+
+  /// Call `middleware`'s hooks around each command executed from now on, after those of middleware added before.
+  /// The commands of an atomic batch are executed together, so their `before` hooks are all called first,
+  /// and their `after` hooks are given the duration of the whole batch.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::atomic::{AtomicUsize, Ordering};
+  /// use std::sync::Arc;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::CommandMiddleware;
+  ///
+  /// struct CountCommands(Arc<AtomicUsize>);
+  ///
+  /// impl CommandMiddleware for CountCommands {
+  ///   fn before(&self, _type_name: &str) {}
+  ///
+  ///   fn after(&self, _type_name: &str, _duration: Duration) {
+  ///     self.0.fetch_add(1, Ordering::SeqCst);
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let executed = Arc::new(AtomicUsize::new(0));
+  /// madeleine.add_middleware(Box::new(CountCommands(executed.clone())));
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_commands([Add(3), Add(4)])?;
+  ///
+  /// assert_eq!(executed.load(Ordering::SeqCst), 3);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn add_middleware(&self, middleware: Box<dyn CommandMiddleware>) {
+    self.middleware.add(middleware);
+  }
+
+  /// Migrate commands logged in an earlier serialized form as they're read back by replays and history,
+  /// e.g. once a field was added to a command, see `CommandMigration`.
+  ///
+  /// The store's metadata records the version commands are logged at from now on, the latest in the chain,
+  /// so the same migrations should be added every time the store is opened, e.g. with
+  /// `MadeleineBuilder::command_migration`, which also applies them to `MadeleineBuilder::resume_replaying`.
+  /// Fails with `MadeleineError::CommandMigrationError` if a migration to the same version was already added.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::{CommandMigration, MadeleineError};
+  ///
+  /// #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+  /// struct Add {
+  ///   amount: u64,
+  /// }
+  ///
+  /// impl Command<'_> for Add {
+  ///   type SystemState = u64;
+  ///
+  ///   fn execute(&self, old_state: u64) -> u64 {
+  ///     old_state + self.amount
+  ///   }
+  /// }
+  ///
+  /// /// Version 1 of `Add` was logged as a bare number.
+  /// struct NameAmount;
+  ///
+  /// impl CommandMigration for NameAmount {
+  ///   fn version(&self) -> u32 {
+  ///     2
+  ///   }
+  ///
+  ///   fn migrate(&self, old_json: &str, _old_version: u32) -> Result<String, MadeleineError> {
+  ///     Ok(format!("{{\"amount\":{}}}", old_json))
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.add_command_migration(Box::new(NameAmount))?;
+  /// madeleine.execute_command(Add { amount: 2 })?;
+  ///
+  /// let history = madeleine.history_page::<Add>(None, 10)?;
+  ///
+  /// assert_eq!(history.entries[0].command.amount, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn add_command_migration(
+    &self,
+    migration: Box<dyn CommandMigration>,
+  ) -> Result<(), MadeleineError> {
+    self.add_migration(Arc::from(migration))
+  }
+
+  /// Add a migration to the chain, and record the version commands are logged at from now on.
+  pub(crate) fn add_migration(
+    &self,
+    migration: Arc<dyn CommandMigration>,
+  ) -> Result<(), MadeleineError> {
+    let _command_lock = lock_recovering(&self.command_lock);
+    let version = self.command_migrations.add(migration)?;
+
+    self.command_migrations.set_versions(record_command_version(
+      &self.location_dir_path,
+      self.hash_algo,
+      version,
+      self.head_id()?,
+    )?);
+
+    Ok(())
+  }
+
+  /// Call `hook` after each command is executed and logged, once the state reflects it, after the hooks added before it.
+  /// It's given the `RawLoggedCommand` as logged, to downcast from `Any` and deserialize, and how long executing
+  /// the command took, or the whole batch for commands executed together. Unlike middleware, it isn't called for commands
+  /// which fail. A hook which panics is skipped, reported through `tracing` with the `tracing` feature,
+  /// and doesn't fail the command.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::{Arc, Mutex};
+  ///
+  /// use madeleine::RawLoggedCommand;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let executed = Arc::new(Mutex::new(Vec::new()));
+  /// let recorded = executed.clone();
+  /// madeleine.on_command_executed(Box::new(move |command, _duration| {
+  ///   if let Some(logged) = command.downcast_ref::<RawLoggedCommand>() {
+  ///     recorded.lock().unwrap().push(logged.deserialize::<Add>().unwrap().0);
+  ///   }
+  /// }));
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_commands([Add(3), Add(4)])?;
+  ///
+  /// assert_eq!(*executed.lock().unwrap(), vec![2, 3, 4]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn on_command_executed(&self, hook: ExecutionHook) {
+    self.command_hooks.add(hook);
+  }
+
+  /// Call `hook` after each snapshot is taken, after the hooks added before it, with the snapshot's id as a `usize`
+  /// and how long taking it took. Like `on_command_executed`, a hook which panics is skipped.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::{Arc, Mutex};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let taken = Arc::new(Mutex::new(Vec::<usize>::new()));
+  /// let recorded = taken.clone();
+  /// madeleine.on_snapshot_taken(Box::new(move |snapshot_id, _duration| {
+  ///   recorded.lock().unwrap().extend(snapshot_id.downcast_ref::<usize>());
+  /// }));
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// let snapshot_id = madeleine.take_snapshot(true)?;
+  ///
+  /// assert_eq!(*taken.lock().unwrap(), vec![snapshot_id]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn on_snapshot_taken(&self, hook: ExecutionHook) {
+    self.snapshot_hooks.add(hook);
+  }
+
+  /// Stop long-running operations on this instance, such as `export` or `compact_log`, once `cancellation` is cancelled,
+  /// or never by passing `None`. They check it between batches of log entries, failing with `MadeleineError::Cancelled`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::export::{ExportFormat, ExportRange};
+  /// use madeleine::{CancellationToken, MadeleineError};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let cancellation = CancellationToken::new();
+  /// madeleine.set_cancellation_token(Some(cancellation.clone()));
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// cancellation.cancel();
+  /// let exported = madeleine.export(ExportFormat::Jsonl, ExportRange::default(), Vec::new());
+  ///
+  /// assert!(matches!(exported, Err(MadeleineError::Cancelled { completed: 0 })));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_cancellation_token(&self, cancellation: Option<CancellationToken>) {
+    self.command_log.set_cancellation(cancellation);
+  }
+
+  /// Take rate limit tokens for commands about to be executed.
+  fn admit<C: Serialize>(&self, commands: &[C]) -> Result<(), MadeleineError> {
+    let admitted = self.rate_limiter.admit(commands.len() as u64, || {
+      logged_bytes(self.command_log.payload_format(), commands)
+    });
+
+    if let Err(MadeleineError::RateLimited { .. }) = admitted {
+      self.metrics.record_rate_limited(commands.len() as u64);
+    }
+
+    admitted
+  }
+
+  /// The rate limiter and payload format, for checking the rate limits without holding the instance.
+  #[cfg(feature = "async")]
+  pub(crate) fn rate_limiter(&self) -> (Arc<RateLimiter>, PayloadFormat) {
+    (self.rate_limiter.clone(), self.command_log.payload_format())
+  }
+
+  /// Raise warnings for newly crossed thresholds, both to the hook and as events.
+  fn check_quota_thresholds(&self) -> Result<(), MadeleineError> {
+    for warning in self.quotas.check_thresholds(self.len())? {
+      self.emit(StoreEvent::ResourceWarning {
+        at: SystemTime::now(),
+        warning,
+      })?;
+    }
+
+    Ok(())
+  }
+
+  /// Compress snapshots taken from now on with a codec, or stop compressing them by passing `None`.
+  /// Snapshots record their codec, so earlier ones can still be read.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::{self, GZIP_CODEC_ID};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_snapshot_codec(Some(codec::find(GZIP_CODEC_ID)?))?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.close()?;
+  ///
+  /// assert_eq!(Madeleine::<u64>::resume(&store)?.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_snapshot_codec(&self, codec: Option<Arc<dyn Codec>>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.snapshot_codec) = codec;
+
+    Ok(())
+  }
+
+  /// Compress commands logged from now on with a codec, or stop compressing them by passing `None`.
+  /// Each entry records its codec, so the log can mix compressed and uncompressed entries.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::{self, GZIP_CODEC_ID};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_payload_codec(Some(codec::find(GZIP_CODEC_ID)?))?;
+  /// madeleine.execute_command(Add(2))?;
+  /// drop(madeleine);
+  ///
+  /// let resumed = Madeleine::resume_replaying::<Add, _>(&store, || 0)?;
+  ///
+  /// assert_eq!(resumed.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_payload_codec(&self, codec: Option<Arc<dyn Codec>>) -> Result<(), MadeleineError> {
+    self.command_log.set_payload_codec(codec)
+  }
+
+  /// Compress exports with a codec, or stop compressing them by passing `None`. The manifest records the codec.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::codec::{self, GZIP_CODEC_ID};
+  /// use madeleine::export::{ExportFormat, ExportRange};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_export_codec(Some(codec::find(GZIP_CODEC_ID)?))?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let manifest = madeleine.export(ExportFormat::Jsonl, ExportRange::default(), Vec::new())?;
+  ///
+  /// assert_eq!(manifest.codec.as_deref(), Some(GZIP_CODEC_ID));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_export_codec(&self, codec: Option<Arc<dyn Codec>>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.export_codec) = codec;
+
+    Ok(())
+  }
+
+  /// Set the callback receiving `ResourceWarning`s, or remove it by passing `None`.
+  /// The same warnings are delivered as `StoreEvent::ResourceWarning` to receivers from `events`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::{Arc, Mutex};
+  ///
+  /// use madeleine::quota::{Quotas, Resource, ResourceWarning};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let warnings = Arc::new(Mutex::new(Vec::new()));
+  /// let received = warnings.clone();
+  /// madeleine.set_resource_warning_hook(Some(Box::new(move |warning: &ResourceWarning| {
+  ///   received.lock().expect("warnings poisoned").push(warning.clone());
+  /// })))?;
+  /// madeleine.set_quotas(Quotas {
+  ///   max_commands: Some(2),
+  ///   warning_thresholds: vec![50],
+  ///   ..Quotas::default()
+  /// })?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let warnings = warnings.lock().expect("warnings poisoned");
+  /// assert_eq!(warnings.len(), 1);
+  /// assert_eq!(warnings[0].resource, Resource::Commands);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_resource_warning_hook(
+    &self,
+    hook: Option<ResourceWarningHook>,
+  ) -> Result<(), MadeleineError> {
+    self.quotas.set_hook(hook)
+  }
+
+  /// Current resource usage alongside the configured quotas.
+  /// Store bytes are only measured while a limit is set.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::quota::Quotas;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_quotas(Quotas {
+  ///   max_commands: Some(10),
+  ///   ..Quotas::default()
+  /// })?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let usage = madeleine.resource_usage()?;
+  ///
+  /// assert_eq!(usage.commands, 1);
+  /// assert_eq!(usage.quotas.max_commands, Some(10));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn resource_usage(&self) -> Result<ResourceUsage, MadeleineError> {
+    self.quotas.usage(self.len())
+  }
+
+  /// Capture the whole store, i.e. its state, command log, snapshots and metadata, in a self-contained compressed blob,
+  /// e.g. to embed a fixture in a test binary. Fails if the store is larger than `MAX_DUMP_BYTES`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let bytes = madeleine.dump_bytes()?;
+  ///
+  /// let copy = scratch_store();
+  /// let restored = Madeleine::<u64>::restore_bytes(&copy, &bytes)?;
+  ///
+  /// assert_eq!(restored.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn dump_bytes(&self) -> Result<Vec<u8>, MadeleineError> {
+    let state = serde_json::to_vec(&*self.internal_state.read())?;
+
+    self.command_log.flush()?;
+
+    dump_store(&self.location_dir_path, &state)
+  }
+
+  /// Recreate a store dumped with `dump_bytes` at a location, which must be missing or empty,
+  /// and open it with the dumped state. The restored store keeps the original's store id.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let bytes = madeleine.dump_bytes()?;
+  ///
+  /// let copy = scratch_store();
+  /// let restored = Madeleine::<u64>::restore_bytes(&copy, &bytes)?;
+  ///
+  /// assert_eq!(restored.store_id(), madeleine.store_id());
+  /// assert_eq!(restored.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn restore_bytes(
+    location: impl Into<StorePath>,
+    bytes: &[u8],
+  ) -> Result<Self, MadeleineError> {
+    let location_dir_path = location.into().resolve()?;
+
+    DirectoryPolicy::RequireEmptyOrStore.evaluate(&location_dir_path)?;
+
+    if is_store_root(&location_dir_path) {
+      return Err(MadeleineError::DirectoryError(format!(
+        "{} already holds a store",
+        location_dir_path.display()
+      )));
+    }
+
+    let dump = read_dump(bytes)?;
+    let state: SystemState = serde_json::from_slice(&dump.state)?;
+
+    for (relative_path, contents) in dump.files {
+      let path = location_dir_path.join(relative_path);
+
+      if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+      }
+
+      fs::write(path, contents)?;
+    }
+
+    admin_log::record(&location_dir_path, AdminOperationKind::Restored)?;
+
+    let madeleine = Self::open(
+      location_dir_path,
+      state,
+      StoreFormat::default(),
+      VerificationLevel::default(),
+      None,
+    )?;
+
+    madeleine.mark_ready();
+
+    Ok(madeleine)
+  }
+
+  /// Write the commands in `range` to `writer` in the given format,
+  /// returning the manifest needed to verify and re-import them, see `export::import`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::export::{self, ExportFormat, ExportRange};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let mut exported = Vec::new();
+  /// let manifest = madeleine.export(ExportFormat::Jsonl, ExportRange::default(), &mut exported)?;
+  ///
+  /// assert_eq!(manifest.row_count, 1);
+  ///
+  /// let copy = scratch_store();
+  /// assert_eq!(export::import(&manifest, exported.as_slice(), copy.path().to_path_buf())?, 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn export<W: Write>(
+    &self,
+    format: ExportFormat,
+    range: ExportRange,
+    writer: W,
+  ) -> Result<ExportManifest, MadeleineError> {
+    self.export_filtered(format, range, None, writer)
+  }
+
+  /// Write one audit event per command in `range` to `writer`, for ingestion into a SIEM, returning the number written.
+  /// Events carry hashes of the commands rather than the commands themselves, see `AuditEvent` for the format.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::export::ExportRange;
+  /// use madeleine::{AuditEvent, AuditOutcome, AuditOptions};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let mut exported = Vec::new();
+  /// let options = AuditOptions::default();
+  /// let written = madeleine.export_audit(ExportRange::default(), &options, &mut exported)?;
+  ///
+  /// assert_eq!(written, 1);
+  ///
+  /// let event: AuditEvent = serde_json::from_slice(exported.trim_ascii_end())?;
+  /// assert_eq!(event.outcome, AuditOutcome::Applied);
+  /// assert_eq!(event.id, Some(madeleine.head_id()?));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn export_audit<W: Write>(
+    &self,
+    range: ExportRange,
+    options: &AuditOptions,
+    writer: W,
+  ) -> Result<u64, MadeleineError> {
+    self.command_log.flush()?;
+
+    audit::export_audit(
+      &self.command_log,
+      &self.location_dir_path,
+      self.store_id,
+      self.hash_algo,
+      range,
+      options,
+      writer,
+    )
+  }
+
+  /// Write every command in the log to `writer` as newline-delimited JSON, in the order they were logged,
+  /// returning how many were written. Each line is a `CommandRecord` holding the command's ULID,
+  /// when it was logged and the command itself. Unlike `Madeleine::export`, there's no manifest to re-import it with.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::export::CommandRecord;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let mut exported = Vec::new();
+  /// assert_eq!(madeleine.export_command_log(&mut exported)?, 1);
+  ///
+  /// let record: CommandRecord = serde_json::from_slice(exported.trim_ascii_end())?;
+  /// assert_eq!(record.ulid, madeleine.head_id()?);
+  /// assert_eq!(record.payload, serde_json::json!(2));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn export_command_log<W: Write>(&self, writer: W) -> Result<u64, MadeleineError> {
+    self.command_log.flush()?;
+    self.command_log.export_ndjson(writer)
+  }
+
+  /// Export the commands in `range`, limited to one tenant's if one is given.
+  pub(crate) fn export_filtered<W: Write>(
+    &self,
+    format: ExportFormat,
+    range: ExportRange,
+    tenant: Option<&TenantId>,
+    writer: W,
+  ) -> Result<ExportManifest, MadeleineError> {
+    export_log(
+      &self.command_log,
+      self.store_id,
+      self.hash_algo,
+      format,
+      ExportSelection { range, tenant },
+      lock_recovering(&self.export_codec).as_deref(),
+      writer,
+    )
+  }
+
+  /// The store's command log.
+  pub(crate) fn command_log(&self) -> &CommandLog {
+    &self.command_log
+  }
+
+  /// Stamp every command logged from now on with a number from `sequencer`, or stop stamping them by passing `None`.
+  /// Installing one sequencer on several stores gives their commands a total order, see `merge_ordered`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  ///
+  /// use madeleine::sequencer::{AtomicSequencer, Sequencer};
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let other_store = scratch_store();
+  /// let other = Madeleine::new(&other_store, || 0)?;
+  ///
+  /// let sequencer: Arc<dyn Sequencer> = Arc::new(AtomicSequencer::new());
+  /// madeleine.set_sequencer(Some(sequencer.clone()))?;
+  /// other.set_sequencer(Some(sequencer))?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// other.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.follower().commands_after(Ulid::nil())?[0].sequence, Some(0));
+  /// assert_eq!(other.follower().commands_after(Ulid::nil())?[0].sequence, Some(1));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_sequencer(&self, sequencer: Option<Arc<dyn Sequencer>>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.sequencer) = sequencer;
+
+    Ok(())
+  }
+
+  /// Script failures and delays for the store's storage operations, or stop by passing `None`, see `FailpointStore`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  ///
+  /// use madeleine::testing::{FailpointAction, FailpointStore, StorageOperation};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let failpoints = Arc::new(FailpointStore::new());
+  /// failpoints.on_nth(StorageOperation::Append, 2, FailpointAction::Fail);
+  /// madeleine.set_failpoints(Some(failpoints.clone()))?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// assert!(madeleine.execute_command(Add(3)).is_err());
+  ///
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// assert_eq!(failpoints.calls(StorageOperation::Append), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  #[cfg(any(test, feature = "testing"))]
+  pub fn set_failpoints(
+    &self,
+    failpoints: Option<Arc<FailpointStore>>,
+  ) -> Result<(), MadeleineError> {
+    self.command_log.set_failpoints(failpoints)
+  }
+
+  /// ULID of the most recently logged command, or `Ulid::nil()` if none have been logged.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.head_id()?, Ulid::nil());
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.follower().commands_after(Ulid::nil())?[0].id, madeleine.head_id()?);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn head_id(&self) -> Result<Ulid, MadeleineError> {
+    Ok(self.command_log.last_id()?.unwrap_or_else(Ulid::nil))
+  }
+
+  /// Gets the length of the command history.
+  /// Every backend keeps count as commands are appended rather than reading the log, so this is cheap to call.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.len(), 0);
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.len(), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn len(&self) -> u64 {
+    self.command_log.len()
+  }
+
+  /// Number of commands ever logged, including those since removed from the log by `compact` or `purge_tenant`,
+  /// unlike `len`. Counts from before this was recorded are lost, so older stores only count commands compacted since.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  /// madeleine.compact()?;
+  ///
+  /// assert_eq!(madeleine.len(), 0);
+  /// assert_eq!(madeleine.total_commands_ever(), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn total_commands_ever(&self) -> u64 {
+    self.commands_compacted.load(Ordering::Relaxed) + self.len()
+  }
+
+  /// Determine if the instance has an empty command history, as cheaply as `len`, e.g. from a health check.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert!(madeleine.is_empty());
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(!madeleine.is_empty());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn is_empty(&self) -> bool {
+    self.command_log.len() == 0
+  }
+
+  /// Take and persist a snapshot of the internal state, returning its id.
+  /// The ULID of the last command applied to the state is recorded with it, see `Madeleine::snapshot_head_id`.
+  /// Failing to serialize the state or to write the snapshot is reported as `MadeleineError::SnapshotError`.
+  ///
+  /// If the state is unchanged since the last snapshot file was written, no new file is written.
+  /// Instead, a lightweight alias pointing at the previous snapshot file is recorded under the new id.
+  /// Between full snapshots, only a patch against the previous one may be written, see `Madeleine::set_full_snapshot_every`.
+  /// Passing `force` always writes a full snapshot file.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let snapshot_id = madeleine.take_snapshot(false)?;
+  ///
+  /// assert_eq!(madeleine.snapshot_head_id(snapshot_id)?, Some(madeleine.head_id()?));
+  /// assert_eq!(madeleine.next_snapshot_id()?, snapshot_id + 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn take_snapshot(&self, force: bool) -> Result<usize, MadeleineError> {
+    let started = Instant::now();
+    let state = self.internal_state.read();
+    let next_snapshot_id = self.next_snapshot_id()?;
+    let state_hash = self.hash_algo.canonical_hash(&*state).map_err(|error| {
+      MadeleineError::SnapshotError(format!("unable to serialize state: {}", error))
+    })?;
+    let mut last_snapshot = lock_recovering(&self.last_snapshot);
+
+    let deduplicated = match last_snapshot.as_ref() {
+      Some(record) if !force && record.state_hash == state_hash => {
+        let location = snapshot_alias_file_path(next_snapshot_id, self.location_dir_path.clone());
+        let serialized = serde_json::to_string(&record.snapshot_id)?;
+        write_snapshot_file(&location, serialized.as_bytes())?;
+
+        true
+      }
+      _ => {
+        #[cfg(any(test, feature = "testing"))]
+        self
+          .command_log
+          .failpoint(StorageOperation::SnapshotWrite)?;
+
+        let snapshot_codec = lock_recovering(&self.snapshot_codec).clone();
+        let full_snapshot_every = self.full_snapshot_every();
+
+        let json_state = if full_snapshot_every > 1 {
+          Some(serde_json::to_value(&*state).map_err(|error| {
+            MadeleineError::SnapshotError(format!("unable to serialize state: {}", error))
+          })?)
+        } else {
+          None
+        };
+
+        let parent = match (last_snapshot.as_ref(), json_state.as_ref()) {
+          (Some(record), Some(json_state)) if !force => record
+            .state
+            .as_ref()
+            .filter(|_| record.chain_length + 1 < full_snapshot_every)
+            .map(|parent_state| (record, json_patch::diff(parent_state, json_state))),
+          _ => None,
+        };
+
+        let chain_length = match parent {
+          Some((record, patch)) => {
+            let location =
+              snapshot_diff_file_path(next_snapshot_id, self.location_dir_path.clone());
+            let serialized = serde_json::to_vec(&SnapshotDiff {
+              parent: record.snapshot_id,
+              patch,
+            })?;
+            write_snapshot_file(
+              &location,
+              &codec::encode(snapshot_codec.as_deref(), &serialized)?,
+            )?;
+
+            record.chain_length + 1
+          }
+          None => {
+            let location = snapshot_file_path(next_snapshot_id, self.location_dir_path.clone());
+            let serialized = match json_state.as_ref() {
+              Some(json_state) => serde_json::to_string(json_state),
+              None => serde_json::to_string(&*state),
+            }
+            .map_err(|error| {
+              MadeleineError::SnapshotError(format!("unable to serialize state: {}", error))
+            })?;
+            write_snapshot_file(
+              &location,
+              &codec::encode(snapshot_codec.as_deref(), serialized.as_bytes())?,
+            )?;
+
+            self.metrics.record_state_size(serialized.len() as u64);
+
+            0
+          }
+        };
+
+        *last_snapshot = Some(SnapshotRecord {
+          state_hash,
+          snapshot_id: next_snapshot_id,
+          state: json_state,
+          chain_length,
+        });
+
+        false
+      }
+    };
+
+    write_snapshot_file(
+      &snapshot_head_file_path(next_snapshot_id, self.location_dir_path.clone()),
+      serde_json::to_string(&self.head_id()?)?.as_bytes(),
+    )?;
+
+    write_snapshot_id_file(
+      snapshot_id_file_path(self.location_dir_path.clone()),
+      next_snapshot_id,
+    )?;
+
+    self.quotas.measure_store_bytes(&self.location_dir_path)?;
+    self.metrics.record_snapshot_taken();
+    self.snapshot_scheduler.record_snapshot();
+
+    self.emit(StoreEvent::SnapshotTaken {
+      at: SystemTime::now(),
+      snapshot_id: next_snapshot_id,
+      deduplicated,
+    })?;
+    self
+      .snapshot_hooks
+      .call(&next_snapshot_id, started.elapsed());
+
+    Ok(next_snapshot_id)
+  }
+
+  /// Take a snapshot on behalf of a schedule rather than a caller waiting for it, returning its id.
+  /// A failure is counted in `Metrics::pending_snapshot_debt`, then handled as the `SnapshotFailureMode` says:
+  /// either reported, or delivered as a `StoreEvent::SnapshotFailed` while this returns `None`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.take_scheduled_snapshot()?.is_some());
+  /// assert_eq!(madeleine.metrics().pending_snapshot_debt(), 0);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn take_scheduled_snapshot(&self) -> Result<Option<usize>, MadeleineError> {
+    let error = match self.take_snapshot(false) {
+      Ok(snapshot_id) => return Ok(Some(snapshot_id)),
+      Err(error) => error,
+    };
+
+    let pending_snapshot_debt = self.metrics.record_snapshot_failed();
+
+    if *lock_recovering(&self.snapshot_failure_mode) == SnapshotFailureMode::FailCommand {
+      return Err(error);
+    }
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+      store_id = %self.store_id,
+      pending_snapshot_debt,
+      "scheduled snapshot failed: {}",
+      error
+    );
+
+    self.emit(StoreEvent::SnapshotFailed {
+      at: SystemTime::now(),
+      error: error.to_string(),
+      pending_snapshot_debt,
+    })?;
+
+    Ok(None)
+  }
+
+  /// Write a full snapshot file only for every `every`th snapshot, and for those in between just a JSON patch
+  /// against the previous snapshot, saving disk and I/O for large states which change a little between snapshots.
+  /// Resuming applies the chain of patches onto the full snapshot it builds on, and falls back to the previous
+  /// full snapshot if a snapshot in the chain is missing. Compaction keeps the snapshots which later ones build on.
+  ///
+  /// The default of `1` writes every snapshot in full. Otherwise, the state as of the last snapshot is kept in memory
+  /// as JSON to diff the next one against, and the first snapshot after opening a store is full.
+  /// Fails with `MadeleineError::InvalidConfig` if `every` is zero.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_full_snapshot_every(10)?;
+  ///
+  /// assert_eq!(madeleine.full_snapshot_every(), 10);
+  /// assert!(madeleine.set_full_snapshot_every(0).is_err());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_full_snapshot_every(&self, every: usize) -> Result<(), MadeleineError> {
+    if every == 0 {
+      return Err(MadeleineError::InvalidConfig(String::from(
+        "full snapshots must be taken at least every snapshot",
+      )));
+    }
+
+    self.full_snapshot_every.store(every, Ordering::Relaxed);
+
+    if every == 1 {
+      if let Some(record) = lock_recovering(&self.last_snapshot).as_mut() {
+        record.state = None;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// How many snapshots are taken for each full snapshot file, see `Madeleine::set_full_snapshot_every`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.full_snapshot_every(), 1);
+  ///
+  /// madeleine.set_full_snapshot_every(4)?;
+  ///
+  /// assert_eq!(madeleine.full_snapshot_every(), 4);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn full_snapshot_every(&self) -> usize {
+    self.full_snapshot_every.load(Ordering::Relaxed)
+  }
+
+  /// Change what happens when a scheduled snapshot fails, see `take_scheduled_snapshot`.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotFailureMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_snapshot_failure_mode(SnapshotFailureMode::WarnAndContinue);
+  ///
+  /// assert_eq!(madeleine.snapshot_failure_mode(), SnapshotFailureMode::WarnAndContinue);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_snapshot_failure_mode(&self, mode: SnapshotFailureMode) {
+    *lock_recovering(&self.snapshot_failure_mode) = mode;
+  }
+
+  /// What happens when a scheduled snapshot fails.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotFailureMode;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.snapshot_failure_mode(), SnapshotFailureMode::FailCommand);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_failure_mode(&self) -> SnapshotFailureMode {
+    *lock_recovering(&self.snapshot_failure_mode)
+  }
+
+  /// Take snapshots automatically after executing commands, as often as the policy says.
+  /// Progress towards the next snapshot is counted from the last snapshot on disk, so it carries over restarts.
+  ///
+  /// Automatic snapshots are taken with `take_scheduled_snapshot`. Under `SnapshotFailureMode::FailCommand`,
+  /// a failure is reported by the command which triggered it, although that command was already logged and applied,
+  /// and every later command retries the snapshot until one succeeds.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::SnapshotPolicy;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_snapshot_policy(SnapshotPolicy::EveryNCommands(2))?;
+  /// let next_snapshot_id = madeleine.next_snapshot_id()?;
+  ///
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.next_snapshot_id()?, next_snapshot_id + 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_snapshot_policy(&self, policy: SnapshotPolicy) -> Result<(), MadeleineError> {
+    if policy != SnapshotPolicy::Never && !self.snapshot_scheduler.is_tracking() {
+      let (commands_since, last_snapshot_at) = self.progress_since_last_snapshot()?;
+
+      self
+        .snapshot_scheduler
+        .track_from(commands_since, last_snapshot_at);
+    }
+
+    self.snapshot_scheduler.set_policy(policy);
+
+    Ok(())
+  }
+
+  /// When snapshots are taken automatically.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::SnapshotPolicy;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// assert_eq!(madeleine.snapshot_policy(), SnapshotPolicy::Never);
+  ///
+  /// madeleine.set_snapshot_policy(SnapshotPolicy::Interval(Duration::from_secs(60)))?;
+  ///
+  /// assert_eq!(madeleine.snapshot_policy(), SnapshotPolicy::Interval(Duration::from_secs(60)));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_policy(&self) -> SnapshotPolicy {
+    self.snapshot_scheduler.policy()
+  }
+
+  /// Run maintenance tasks, such as snapshots and log verification, at intervals, replacing any previous schedule.
+  /// Each task is first due an interval after this is called, as measured by the clock passed to `set_clock`.
+  ///
+  /// Tasks only run when `tick_maintenance` is called, either by the caller's own runtime or by a background thread
+  /// started with `SharedMadeleine::start_maintenance`. Fails with `MadeleineError::InvalidConfig` if an interval is zero.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::maintenance::{MaintenanceSchedule, MaintenanceTask};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.set_maintenance_schedule(
+  ///   MaintenanceSchedule::default()
+  ///     .every(Duration::from_secs(3600), MaintenanceTask::Snapshot)
+  ///     .with_jitter(Duration::from_secs(60)),
+  /// )?;
+  ///
+  /// assert!(madeleine.next_maintenance_in() > Some(Duration::from_secs(3599)));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn set_maintenance_schedule(
+    &self,
+    schedule: MaintenanceSchedule,
+  ) -> Result<(), MadeleineError> {
+    if let Some((task, _interval)) = schedule
+      .tasks
+      .iter()
+      .find(|(_task, interval)| interval.is_zero())
+    {
+      return Err(MadeleineError::InvalidConfig(format!(
+        "maintenance task {:?} has a zero interval",
+        task
+      )));
+    }
+
+    self
+      .maintenance_scheduler
+      .set_schedule(schedule, self.rate_limiter.clock().now());
+
+    Ok(())
+  }
+
+  /// Which maintenance tasks are run, and how often.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// use madeleine::maintenance::MaintenanceSchedule;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  ///
+  /// assert_eq!(madeleine.maintenance_schedule(), MaintenanceSchedule::default());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn maintenance_schedule(&self) -> MaintenanceSchedule {
+    self.maintenance_scheduler.schedule()
+  }
+
+  /// Run the maintenance tasks which are due, in the schedule's order, returning how each went.
+  ///
+  /// A failing task doesn't stop the others. Every run is delivered as a `StoreEvent::MaintenanceRan`,
+  /// and recorded in the admin log unless it succeeded and only read the store, see `MaintenanceTask`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::sync::Arc;
+  /// use std::time::Duration;
+  ///
+  /// use madeleine::maintenance::{MaintenanceSchedule, MaintenanceTask};
+  /// use madeleine::testing::ManualClock;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let clock = Arc::new(ManualClock::new());
+  /// madeleine.set_clock(clock.clone());
+  /// madeleine.set_maintenance_schedule(
+  ///   MaintenanceSchedule::default().every(Duration::from_secs(60), MaintenanceTask::Snapshot),
+  /// )?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.tick_maintenance()?.is_empty());
+  ///
+  /// clock.advance(Duration::from_secs(60));
+  /// let runs = madeleine.tick_maintenance()?;
+  ///
+  /// assert_eq!(runs[0].task, MaintenanceTask::Snapshot);
+  /// assert_eq!(runs[0].error, None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn tick_maintenance(&self) -> Result<Vec<MaintenanceRun>, MadeleineError> {
+    let due = self
+      .maintenance_scheduler
+      .take_due(self.rate_limiter.clock().now());
+    let mut runs = Vec::with_capacity(due.len());
+
+    for task in due {
+      let started = Instant::now();
+      let error = self.run_maintenance_task(task).err();
+      let run = MaintenanceRun {
+        task,
+        duration: started.elapsed(),
+        error: error.map(|error| error.to_string()),
+      };
+
+      if run.error.is_some() || !task.is_read_only() {
+        admin_log::record(
+          &self.location_dir_path,
+          AdminOperationKind::MaintenanceRan {
+            task,
+            error: run.error.clone(),
+          },
+        )?;
+      }
+
+      self.emit(StoreEvent::MaintenanceRan {
+        at: SystemTime::now(),
+        run: run.clone(),
+      })?;
+
+      runs.push(run);
+    }
+
+    Ok(runs)
+  }
+
+  /// How long until the next maintenance task is due, or `None` if none are scheduled.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  ///
+  /// assert_eq!(madeleine.next_maintenance_in(), None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn next_maintenance_in(&self) -> Option<Duration> {
+    self
+      .maintenance_scheduler
+      .next_due()
+      .map(|next_due| next_due.saturating_duration_since(self.rate_limiter.clock().now()))
+  }
+
+  fn run_maintenance_task(&self, task: MaintenanceTask) -> Result<(), MadeleineError> {
+    match task {
+      MaintenanceTask::Snapshot => {
+        self.take_scheduled_snapshot()?;
+      }
+      MaintenanceTask::CompactLog => {
+        let Some(snapshot_id) = self.next_snapshot_id()?.checked_sub(1) else {
+          return Ok(());
+        };
+
+        if let Some(head_id) = self.snapshot_head_id(snapshot_id)? {
+          self.compact_log(head_id)?;
+        }
+      }
+      MaintenanceTask::Compact => {
+        self.compact()?;
+      }
+      MaintenanceTask::VerifyLog => {
+        verify_log(&self.command_log)?;
+      }
+      MaintenanceTask::RebuildDerived => {
+        self.rebuild_derived()?;
+      }
+      MaintenanceTask::SampleUsage => {
+        self.quotas.measure_store_bytes(&self.location_dir_path)?;
+        self.check_quota_thresholds()?;
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Commands logged after the last snapshot's head, and when that snapshot was taken.
+  /// Without a snapshot, every command counts and the clock starts now.
+  fn progress_since_last_snapshot(&self) -> Result<(u64, SystemTime), MadeleineError> {
+    let snapshot_id_file_path = snapshot_id_file_path(self.location_dir_path.clone());
+
+    if !snapshot_id_file_path.is_file() {
+      let commands_since = self.command_log.commands_after(Ulid::nil())?.len();
+
+      return Ok((commands_since as u64, SystemTime::now()));
+    }
+
+    let head_id = self
+      .snapshot_head_id(self.next_snapshot_id()? - 1)?
+      .unwrap_or_else(Ulid::nil);
+    let commands_since = self.command_log.commands_after(head_id)?.len();
+
+    Ok((
+      commands_since as u64,
+      fs::metadata(snapshot_id_file_path)?.modified()?,
+    ))
+  }
+
+  /// Take a scheduled snapshot if the snapshot policy calls for one.
+  fn snapshot_if_due(&self) -> Result<(), MadeleineError> {
+    if self.snapshot_scheduler.is_due() {
+      self.take_scheduled_snapshot()?;
+    }
+
+    Ok(())
+  }
+
+  /// Replace the command log's history with a snapshot of the current state, so the log stops growing without bound.
+  ///
+  /// Each stage is journaled in the store's metadata before the next begins, see `CompactionStage`.
+  /// If the process dies partway, the next open undoes the compaction if its snapshot wasn't complete, and finishes it otherwise.
+  /// Followers and replicas which read the log from the start only see commands executed after the compaction.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let report = madeleine.compact()?;
+  ///
+  /// assert_eq!(report.commands_removed, 1);
+  /// assert!(madeleine.is_empty());
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn compact(&self) -> Result<CompactionReport, MadeleineError> {
+    self.rewrite_history(None, None)
+  }
+
+  /// Remove the commands up to and including the one with ULID `up_to` from the log, returning how many were removed,
+  /// without taking a snapshot as `Madeleine::compact` does. Fails with `MadeleineError::SnapshotError`
+  /// unless the latest snapshot already holds the state after that command, see `Madeleine::snapshot_head_id`,
+  /// so that resuming loses nothing. Interrupted, the removal is finished the next time the store is opened.
+  ///
+  /// Followers and replicas which read the log from the start only see the commands kept.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let up_to = madeleine.head_id()?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.compact_log(up_to)?, 1);
+  /// assert_eq!(madeleine.len(), 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn compact_log(&self, up_to: Ulid) -> Result<u64, MadeleineError> {
+    let _command_lock = lock_recovering(&self.command_lock);
+    self.command_log.check_rewritable()?;
+
+    let snapshot_id = self.next_snapshot_id()?.checked_sub(1).ok_or_else(|| {
+      MadeleineError::SnapshotError(String::from(
+        "the log can't be compacted without a snapshot",
+      ))
+    })?;
+
+    match self.snapshot_head_id(snapshot_id)? {
+      Some(head_id) if head_id >= up_to => {}
+      _ => {
+        return Err(MadeleineError::SnapshotError(format!(
+          "snapshot {} doesn't hold the state after command {}",
+          snapshot_id, up_to
+        )))
+      }
+    }
+
+    let mut commands_removed = 0;
+
+    self.command_log.for_each_entry(|_offset, entry| {
+      if entry_id(entry)? <= up_to {
+        commands_removed += 1;
+      }
+
+      Ok(())
+    })?;
+
+    if commands_removed == 0 {
+      return Ok(0);
+    }
+
+    let mut journal = CompactionJournal {
+      stage: CompactionStage::SnapshotDone,
+      snapshot_id,
+      head_id: self.head_id()?,
+      purged_tenant: None,
+      commands_removed,
+      up_to: Some(up_to),
+    };
+
+    self.journal_compaction(&journal)?;
+
+    self.command_log.flush()?;
+    compaction::swap_in_log(
+      &self.location_dir_path,
+      |compacted| copy_log_after(&self.command_log, compacted, up_to).map(|_removed| ()),
+      || {
+        #[cfg(any(test, feature = "testing"))]
+        self
+          .command_log
+          .failpoint(StorageOperation::CompactionSwap)?;
+
+        Ok(())
+      },
+    )?;
+    self
+      .command_log
+      .reopen(command_log_dir_path(&self.location_dir_path))?;
+
+    journal.stage = CompactionStage::RowsDeleted;
+    self.journal_compaction(&journal)?;
+    self
+      .commands_compacted
+      .fetch_add(commands_removed, Ordering::Relaxed);
+
+    compaction::remove_retired_log(&self.location_dir_path)?;
+
+    journal.stage = CompactionStage::Vacuumed;
+    self.journal_compaction(&journal)?;
+
+    admin_log::record(
+      &self.location_dir_path,
+      AdminOperationKind::LogCompacted {
+        up_to,
+        commands_removed,
+      },
+    )?;
+    compaction::write_journal(&self.location_dir_path, self.hash_algo, None)?;
+
+    self.quotas.measure_store_bytes(&self.location_dir_path)?;
+
+    #[cfg(feature = "registry")]
+    self.registration.record_command_count(self.len());
+
+    Ok(commands_removed)
+  }
+
+  /// Revert the state to the one held in snapshot `snapshot_id` and remove the commands logged after it,
+  /// along with any later snapshots, returning how many commands were removed. Afterwards `len` is as it was
+  /// when the snapshot was taken, unless commands have since been compacted. Fails with `MadeleineError::SnapshotError`
+  /// if the snapshot doesn't exist or doesn't record its last applied command, see `Madeleine::snapshot_head_id`.
+  ///
+  /// Interrupted, the rollback is undone the next time the store is opened, unless the log was already truncated.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let snapshot_id = madeleine.take_snapshot(false)?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.rollback_to_snapshot(snapshot_id)?, 1);
+  /// assert_eq!(madeleine.len(), 1);
+  /// assert_eq!(madeleine.tap(|state| state), 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn rollback_to_snapshot(&self, snapshot_id: usize) -> Result<u64, MadeleineError> {
+    let _command_lock = lock_recovering(&self.command_lock);
+
+    if !list_snapshot_ids(&self.location_dir_path)?.contains(&snapshot_id) {
+      return Err(MadeleineError::SnapshotError(format!(
+        "snapshot {} doesn't exist",
+        snapshot_id
+      )));
+    }
+
+    let head_id = self.snapshot_head_id(snapshot_id)?.ok_or_else(|| {
+      MadeleineError::SnapshotError(format!(
+        "snapshot {} doesn't record its last applied command, so the log can't be rolled back to it",
+        snapshot_id
+      ))
+    })?;
+    let resolved_id = resolve_snapshot_alias(snapshot_id, self.location_dir_path.clone())?;
+    let SnapshotState {
+      raw_state,
+      chain_length,
+    } = read_snapshot_state(resolved_id, &self.location_dir_path)?.ok_or_else(|| {
+      MadeleineError::SnapshotError(format!(
+        "the chain of differential snapshot {} is broken",
+        resolved_id
+      ))
+    })?;
+    let rolled_back_state: SystemState = serde_json::from_slice(&raw_state)?;
+    let state_hash = self.hash_algo.canonical_hash(&rolled_back_state)?;
+
+    let len = self.command_log.len();
+    let keep = self
+      .command_log
+      .commands_after(head_id)?
+      .first()
+      .map_or(len, |logged| logged.offset);
+    let commands_removed = len - keep;
+
+    // Every command goes, which takes swapping in an empty log rather than truncating it.
+    if keep == 0 && commands_removed > 0 {
+      self.command_log.check_rewritable()?;
+    }
+
+    // Until the log is truncated, resuming from the snapshot replays the commands being removed, undoing the rollback.
+    compaction::remove_snapshots_after(&self.location_dir_path, snapshot_id)?;
+    write_snapshot_id_file(
+      snapshot_id_file_path(self.location_dir_path.clone()),
+      snapshot_id,
+    )?;
+
+    let mut state = self.internal_state.write()?;
+
+    if commands_removed > 0 {
+      self.command_log.flush()?;
+
+      if keep > 0 {
+        self.command_log.truncate(keep)?;
+      } else {
+        compaction::swap_in_log(&self.location_dir_path, |_empty| Ok(()), || Ok(()))?;
+        self
+          .command_log
+          .reopen(command_log_dir_path(&self.location_dir_path))?;
+        compaction::remove_retired_log(&self.location_dir_path)?;
+      }
+    }
+
+    *state = rolled_back_state;
+    drop(state);
+
+    *lock_recovering(&self.last_snapshot) = Some(SnapshotRecord {
+      state_hash,
+      snapshot_id: resolved_id,
+      state: None,
+      chain_length,
+    });
+
+    self.query_cache.invalidate()?;
+    self.idempotency.forget_after(head_id)?;
+
+    let mut projections = lock_recovering(&self.projections);
+
+    for (name, projection) in projections.iter_mut() {
+      projection.reset();
+      self.fold_log_into(name, projection.as_mut())?;
+    }
+
+    drop(projections);
+
+    admin_log::record(
+      &self.location_dir_path,
+      AdminOperationKind::RolledBack {
+        snapshot_id,
+        commands_removed,
+      },
+    )?;
+
+    self.quotas.measure_store_bytes(&self.location_dir_path)?;
+
+    #[cfg(feature = "registry")]
+    self.registration.record_command_count(self.len());
+
+    Ok(commands_removed)
+  }
+
+  /// Replace the command log with one holding only commands not executed for `purged_tenant`, or none at all,
+  /// after snapshotting the state, replaced beforehand by `staged` if given. See `Madeleine::compact`.
+  pub(crate) fn rewrite_history(
+    &self,
+    purged_tenant: Option<&TenantId>,
+    staged: Option<SystemState>,
+  ) -> Result<CompactionReport, MadeleineError> {
+    let _command_lock = lock_recovering(&self.command_lock);
+    self.command_log.check_rewritable()?;
+
+    let commands_removed = match purged_tenant {
+      Some(tenant) => count_by_tenant(&self.command_log)?
+        .get(tenant)
+        .copied()
+        .unwrap_or(0),
+      None => self.command_log.len(),
+    };
+    let mut journal = CompactionJournal {
+      stage: CompactionStage::Intent,
+      snapshot_id: self.next_snapshot_id()?,
+      head_id: self.head_id()?,
+      purged_tenant: purged_tenant.cloned(),
+      commands_removed,
+      up_to: None,
+    };
+
+    self.journal_compaction(&journal)?;
+
+    let previous_state = match staged {
+      Some(staged) => Some(std::mem::replace(
+        &mut *self.internal_state.write()?,
+        staged,
+      )),
+      None => None,
+    };
+
+    if previous_state.is_some() {
+      // Purging changes the state without logging a command, so the head alone can't tell cached outputs are stale.
+      self.query_cache.invalidate()?;
+    }
+
+    let snapshot_id = match self.take_snapshot(true) {
+      Ok(snapshot_id) => snapshot_id,
+      Err(error) => {
+        // Until the snapshot is complete, the rewrite is undone on recovery, so the state must be too.
+        if let Some(previous_state) = previous_state {
+          *self.internal_state.write()? = previous_state;
+        }
+
+        return Err(error);
+      }
+    };
+
+    if snapshot_id != journal.snapshot_id {
+      return Err(MadeleineError::SnapshotError(format!(
+        "compaction expected snapshot {}, but took {}",
+        journal.snapshot_id, snapshot_id
+      )));
+    }
+
+    journal.stage = CompactionStage::SnapshotDone;
+    self.journal_compaction(&journal)?;
+
+    self.command_log.flush()?;
+    compaction::swap_in_log(
+      &self.location_dir_path,
+      |compacted| match purged_tenant {
+        Some(tenant) => {
+          copy_log_without_tenant(&self.command_log, compacted, tenant).map(|_removed| ())
+        }
+        None => Ok(()),
+      },
+      || {
+        #[cfg(any(test, feature = "testing"))]
+        self
+          .command_log
+          .failpoint(StorageOperation::CompactionSwap)?;
+
+        Ok(())
+      },
+    )?;
+    self
+      .command_log
+      .reopen(command_log_dir_path(&self.location_dir_path))?;
+
+    journal.stage = CompactionStage::RowsDeleted;
+    self.journal_compaction(&journal)?;
+    self
+      .commands_compacted
+      .fetch_add(commands_removed, Ordering::Relaxed);
+
+    compaction::remove_retired_log(&self.location_dir_path)?;
+
+    if purged_tenant.is_some() {
+      compaction::remove_snapshots_before(&self.location_dir_path, snapshot_id)?;
+    }
+
+    journal.stage = CompactionStage::Vacuumed;
+    self.journal_compaction(&journal)?;
+
+    admin_log::record(
+      &self.location_dir_path,
+      match purged_tenant {
+        Some(tenant) => AdminOperationKind::TenantPurged {
+          tenant: tenant.clone(),
+          snapshot_id,
+          commands_removed,
+        },
+        None => AdminOperationKind::Compacted {
+          snapshot_id,
+          commands_removed,
+        },
+      },
+    )?;
+    compaction::write_journal(&self.location_dir_path, self.hash_algo, None)?;
+
+    self.quotas.measure_store_bytes(&self.location_dir_path)?;
+
+    #[cfg(feature = "registry")]
+    self.registration.record_command_count(self.len());
+
+    Ok(CompactionReport {
+      snapshot_id,
+      commands_removed,
+    })
+  }
+
+  /// Tombstone or rewrite the logged commands `predicate` picks, e.g. every command into which a bug wrote personal data.
+  /// Returning `None` from the predicate keeps the command, as `RedactionAction::Keep` does.
+  ///
+  /// A snapshot is taken first, so the state keeps what redacted commands did although they can no longer be replayed.
+  /// The log is then copied in batches, each of whose rewritten payloads must deserialize as `C`, the store's command type,
+  /// or the redaction is abandoned with a `MadeleineError::RedactionError` naming the offending ULID and the log is left untouched.
+  /// A `StoreEvent::RedactionProgress` follows every batch. The redacted copy replaces the log only once it's complete,
+  /// and the operation is recorded in the admin log by its counts alone.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::RedactionAction;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// let report = madeleine.redact_matching::<Add, _>(|logged| match logged.deserialize::<Add>() {
+  ///   Ok(Add(3)) => Some(RedactionAction::Tombstone),
+  ///   _ => None,
+  /// })?;
+  ///
+  /// assert_eq!(report.commands_scanned, 2);
+  /// assert_eq!(report.commands_tombstoned, 1);
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn redact_matching<C, P>(&self, predicate: P) -> Result<RedactionReport, MadeleineError>
+  where
+    C: DeserializeOwned,
+    P: FnMut(&RawLoggedCommand) -> Option<RedactionAction>,
+  {
+    let _command_lock = lock_recovering(&self.command_lock);
+    self.command_log.check_rewritable()?;
+
+    let snapshot_id = self.take_snapshot(false)?;
+
+    self.command_log.flush()?;
+
+    let mut report = None;
+
+    compaction::swap_in_log(
+      &self.location_dir_path,
+      |redacted| {
+        report = Some(redaction::copy_log_redacting::<C, _, _>(
+          &self.command_log,
+          redacted,
+          predicate,
+          |progress| {
+            self.emit(StoreEvent::RedactionProgress {
+              at: SystemTime::now(),
+              commands_scanned: progress.commands_scanned,
+              commands_changed: progress.commands_tombstoned + progress.commands_rewritten,
+            })
+          },
+        )?);
+
+        Ok(())
+      },
+      || Ok(()),
+    )
+    .inspect_err(|_error| {
+      let _ = fs::remove_dir_all(self.location_dir_path.join(COMPACTED_LOG_DIR_NAME));
+    })?;
+    self
+      .command_log
+      .reopen(command_log_dir_path(&self.location_dir_path))?;
+    compaction::remove_retired_log(&self.location_dir_path)?;
+
+    let report = RedactionReport {
+      snapshot_id,
+      ..report.unwrap_or_default()
+    };
+
+    admin_log::record(
+      &self.location_dir_path,
+      AdminOperationKind::Redacted {
+        commands_scanned: report.commands_scanned,
+        commands_tombstoned: report.commands_tombstoned,
+        commands_rewritten: report.commands_rewritten,
+      },
+    )?;
+
+    Ok(report)
+  }
+
+  /// Journal a compaction's progress, then give scripted failures the chance to simulate a crash.
+  fn journal_compaction(&self, journal: &CompactionJournal) -> Result<(), MadeleineError> {
+    compaction::write_journal(&self.location_dir_path, self.hash_algo, Some(journal))?;
+
+    #[cfg(any(test, feature = "testing"))]
+    self
+      .command_log
+      .failpoint(StorageOperation::CompactionStage(journal.stage))?;
+
+    Ok(())
+  }
+
+  /// Recompute derived bookkeeping from what is actually on disk, e.g. after the store was edited by hand.
+  ///
+  /// This resets the recorded latest snapshot id to the newest snapshot present, refreshes the hash
+  /// used to deduplicate snapshots from the snapshot file itself, and rebuilds poisoned projections
+  /// from the log. Every change made is listed in the report, so running it on a healthy store is a no-op.
+  /// The system's state is locked for the duration, so no commands may execute meanwhile.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// let report = madeleine.rebuild_derived()?;
+  ///
+  /// assert!(report.is_noop());
+  /// assert_eq!(report.command_count, 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn rebuild_derived(&self) -> Result<RebuildReport, MadeleineError> {
+    let _writer = self.internal_state.write()?;
+    let mut changes = Vec::new();
+
+    let snapshot_id_path = snapshot_id_file_path(self.location_dir_path.clone());
+    let recorded_snapshot_id = if snapshot_id_path.is_file() {
+      let raw = fs::read(&snapshot_id_path)?;
+      serde_json::from_slice(&raw).ok()
+    } else {
+      None
+    };
+    let actual_snapshot_id = list_snapshot_ids(&self.location_dir_path)?.last().copied();
+
+    if recorded_snapshot_id != actual_snapshot_id {
+      match actual_snapshot_id {
+        Some(snapshot_id) => {
+          write_snapshot_id_file(snapshot_id_path, snapshot_id)?;
+        }
+        None => fs::remove_file(snapshot_id_path)?,
+      }
+
+      changes.push(RebuildChange::SnapshotIdReset {
+        recorded: recorded_snapshot_id,
+        actual: actual_snapshot_id,
+      });
+    }
+
+    let actual_snapshot = match actual_snapshot_id {
+      Some(snapshot_id) => {
+        let snapshot_id = resolve_snapshot_alias(snapshot_id, self.location_dir_path.clone())?;
+        let snapshot_state = read_snapshot_state(snapshot_id, &self.location_dir_path)?
+          .ok_or_else(|| {
+            MadeleineError::SnapshotError(format!(
+              "the chain of differential snapshot {} is broken",
+              snapshot_id
+            ))
+          })?;
+        let state: serde_json::Value = serde_json::from_slice(&snapshot_state.raw_state)?;
+
+        Some(SnapshotRecord {
+          state_hash: self.hash_algo.canonical_hash(&state)?,
+          snapshot_id,
+          state: None,
+          chain_length: snapshot_state.chain_length,
+        })
+      }
+      None => None,
+    };
+
+    let mut last_snapshot = lock_recovering(&self.last_snapshot);
+
+    let snapshot_unchanged = match (last_snapshot.as_ref(), actual_snapshot.as_ref()) {
+      (Some(recorded), Some(actual)) => {
+        recorded.snapshot_id == actual.snapshot_id && recorded.state_hash == actual.state_hash
+      }
+      (None, None) => true,
+      _ => false,
+    };
+
+    if !snapshot_unchanged {
+      changes.push(RebuildChange::SnapshotHashRefreshed {
+        snapshot_id: actual_snapshot.as_ref().map(|record| record.snapshot_id),
+      });
+
+      *last_snapshot = actual_snapshot;
+    }
+
+    let mut projections = lock_recovering(&self.projections);
+
+    for (name, projection) in projections.iter_mut() {
+      if projection.is_poisoned() {
+        projection.reset();
+        self.fold_log_into(name, projection.as_mut())?;
+
+        changes.push(RebuildChange::ProjectionRebuilt {
+          name: name.to_string(),
+          poisoned: projection.is_poisoned(),
+        });
+      }
+    }
+
+    #[cfg(feature = "registry")]
+    self.registration.record_command_count(self.len());
+
+    for change in &changes {
+      self.emit(StoreEvent::CorruptionFound {
+        at: SystemTime::now(),
+        description: format!("derived bookkeeping repaired: {:?}", change),
+      })?;
+    }
+
+    Ok(RebuildReport {
+      command_count: self.len(),
+      changes,
+    })
+  }
+
+  /// Reconstruct the state as it was just after the command identified by `ulid` executed, by replaying
+  /// every command logged with a ULID no greater than it, deserialized as `C`, onto the constructor's state.
+  /// The live state and the log are left unchanged.
+  ///
+  /// Fails with `MadeleineError::ReplayError` if `ulid` isn't a valid ULID, or if the log was compacted,
+  /// since the commands it dropped can't be replayed.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let after_first = madeleine.head_id()?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// let then = madeleine.replay_from::<Add, _>(&after_first.to_string(), || 0)?;
+  ///
+  /// assert_eq!(then, 2);
+  /// assert_eq!(madeleine.tap(|state| state), 5);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn replay_from<C, F>(&self, ulid: &str, constructor: F) -> Result<SystemState, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let up_to = Ulid::from_string(ulid).map_err(|error| {
+      MadeleineError::ReplayError(format!("{:?} isn't a ULID: {}", ulid, error))
+    })?;
+
+    let commands_compacted = self.commands_compacted.load(Ordering::Relaxed);
+
+    if commands_compacted > 0 {
+      return Err(MadeleineError::ReplayError(format!(
+        "{} commands were compacted out of the log, so its history can't be replayed",
+        commands_compacted
+      )));
+    }
+
+    let mut state = constructor();
+
+    for logged in self.command_log.commands_up_to(up_to)? {
+      if logged.is_skipped_on_replay() {
+        continue;
+      }
+
+      let command: C = self.command_migrations.decode(&logged)?;
+
+      state = command.execute_with_ctx(state, &CommandContext::at(logged.offset));
+    }
+
+    Ok(state)
+  }
+
+  /// Undo the most recent command which hasn't been undone yet by executing and logging its inverse, deserializing
+  /// the log as `C`, so that the log stays an append-only record of what happened. The inverse is appended along with
+  /// a marker naming the command it undid, see `RawLoggedCommand::undone_command`, so undoing again walks further
+  /// back from the end of the log, skipping the inverses logged by earlier undos and the commands they undid.
+  ///
+  /// The command is inverted against the state it was executed on, which is reconstructed as `state_at` does
+  /// from the constructor's state or a snapshot. Fails with `MadeleineError::UndoError` if there's nothing left
+  /// to undo, e.g. in an empty log, and with `MadeleineError::ReplayError` if the state before the command
+  /// can't be reconstructed because the log was compacted since.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::{Command, Madeleine};
+  /// use madeleine::InvertibleCommand;
+  ///
+  /// #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+  /// struct Rename(String);
+  ///
+  /// impl Command<'_> for Rename {
+  ///   type SystemState = String;
+  ///
+  ///   fn execute(&self, _old_state: String) -> String {
+  ///     self.0.clone()
+  ///   }
+  /// }
+  ///
+  /// impl InvertibleCommand<'_> for Rename {
+  ///   fn invert(&self, state_before: &String) -> Self {
+  ///     Rename(state_before.clone())
+  ///   }
+  /// }
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, String::new)?;
+  /// madeleine.execute_command(Rename(String::from("panda")))?;
+  /// madeleine.execute_command(Rename(String::from("koala")))?;
+  ///
+  /// madeleine.undo_last::<Rename, _>(String::new)?;
+  /// assert_eq!(madeleine.tap(|state| state), "panda");
+  ///
+  /// madeleine.undo_last::<Rename, _>(String::new)?;
+  /// assert_eq!(madeleine.tap(|state| state), "");
+  /// assert_eq!(madeleine.len(), 6);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn undo_last<C, F>(&self, constructor: F) -> Result<Offset, MadeleineError>
+  where
+    C: for<'a> InvertibleCommand<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let _undo_lock = lock_recovering(&self.undo_lock);
+
+    let logged = self.latest_undoable()?;
+    let command: C = self.command_migrations.decode(&logged)?;
+    let state_before = self.state_at::<C, F>(Ulid::from(u128::from(logged.id) - 1), constructor)?;
+
+    let (offset, _inverse_id) =
+      self.execute_logged(&command.invert(&state_before), Logging::Inverse(logged.id))?;
+
+    Ok(offset)
+  }
+
+  /// Find the command `undo_last` undoes, walking back from the end of the log. Each undo marker is logged right after
+  /// its inverse and after the command it undid, so both are skipped, and undoing never undoes an undo.
+  fn latest_undoable(&self) -> Result<RawLoggedCommand, MadeleineError> {
+    let mut undone = HashSet::new();
+    let mut offset = self.command_log.len();
+
+    while let Some(previous) = offset.checked_sub(1) {
+      offset = previous;
+
+      let Some(logged) = self.command_log.command_at(offset)? else {
+        break;
+      };
+
+      if let Some(undone_id) = logged.undone_command() {
+        undone.insert(undone_id);
+        // The inverse.
+        offset = offset.saturating_sub(1);
+      } else if !logged.is_tombstone() && !undone.contains(&logged.id) {
+        return Ok(logged);
+      }
+    }
+
+    Err(MadeleineError::UndoError(String::from(
+      "there's nothing to undo",
+    )))
+  }
+
+  /// Reconstruct the state as it was once every command with a ULID no greater than `cutoff` had executed,
+  /// deserializing commands as `C`. The live state and the log are left unchanged.
+  ///
+  /// Unlike `replay_from`, replay starts from the latest snapshot taken at or before the cutoff, if there is one,
+  /// so it's quicker and works on compacted logs, as long as the snapshot was taken after the compacted commands. Otherwise it starts from the constructor's state,
+  /// which is what a cutoff before the first command returns. Fails with `MadeleineError::ReplayError`
+  /// if commands before the cutoff were compacted out of the log without a later snapshot to start from.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.execute_command(Add(3))?;
+  /// let after_second = madeleine.head_id()?;
+  /// madeleine.execute_command(Add(4))?;
+  ///
+  /// assert_eq!(madeleine.state_at::<Add, _>(after_second, || 0)?, 5);
+  /// assert_eq!(madeleine.state_at::<Add, _>(Ulid::nil(), || 0)?, 0);
+  /// assert_eq!(madeleine.tap(|state| state), 9);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn state_at<C, F>(&self, cutoff: Ulid, constructor: F) -> Result<SystemState, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    // Ties go to the later snapshot, since snapshot ids only grow.
+    let nearest = self
+      .replayable_snapshots()?
+      .into_iter()
+      .rfind(|(head_id, _)| *head_id <= cutoff);
+
+    let commands_compacted = self.commands_compacted.load(Ordering::Relaxed);
+
+    let (mut state, head_id) = match nearest {
+      Some((head_id, snapshot_id)) => (self.read_stored_snapshot(snapshot_id)?, head_id),
+      None if commands_compacted > 0 => {
+        return Err(MadeleineError::ReplayError(format!(
+          "{} commands were compacted out of the log and no snapshot was taken by {}, so the state then can't be reconstructed",
+          commands_compacted, cutoff
+        )));
+      }
+      None => (constructor(), Ulid::nil()),
+    };
+
+    for logged in self.command_log.commands() {
+      let logged = logged?;
+
+      if logged.id <= head_id || logged.id > cutoff || logged.is_skipped_on_replay() {
+        continue;
+      }
+
+      let command: C = self.command_migrations.decode(&logged)?;
+
+      state = command.execute_with_ctx(
+        state,
+        &CommandContext::at(commands_compacted + logged.offset),
+      );
+    }
+
+    Ok(state)
+  }
+
+  /// Reconstruct the state as it was at a point in time, as `state_at` does with a cutoff of the greatest ULID
+  /// in the millisecond of `time`, so that every command logged up to and within that millisecond is replayed.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use std::time::{Duration, SystemTime};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let before = SystemTime::now() - Duration::from_secs(1);
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert_eq!(madeleine.state_at_time::<Add, _>(before, || 0)?, 0);
+  /// assert_eq!(madeleine.state_at_time::<Add, _>(SystemTime::now(), || 0)?, 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn state_at_time<C, F>(
+    &self,
+    time: SystemTime,
+    constructor: F,
+  ) -> Result<SystemState, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+  {
+    let timestamp_ms = time
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .map_or(0, |since_epoch| {
+        u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+      });
+
+    // The random part of a ULID is masked to its 80 bits, making this the greatest ULID of the millisecond.
+    self.state_at::<C, F>(Ulid::from_parts(timestamp_ms, u128::MAX), constructor)
+  }
+
+  /// Check that replaying the log, deserialized as `C`, reproduces the live state, catching commands whose `execute`
+  /// reads the clock, a random number generator or anything else outside the state, which would otherwise go unnoticed
+  /// until a restart. Returns `None` if it does, or a `DivergenceReport` narrowing down where it stopped doing so.
+  ///
+  /// Replay starts from the constructor's state, or, if the log was compacted, from the earliest snapshot taken
+  /// since, and is also compared with each later snapshot as it passes it. The log is read a page at a time,
+  /// and commands wait until the check is done. Commands replaced with tombstones by `redact_matching` are skipped,
+  /// as in any replay, so a store with redacted commands is reported as diverging by the snapshot it took to redact them.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot(false)?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.verify_determinism::<Add, _>(|| 0)?, None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn verify_determinism<C, F>(
+    &self,
+    constructor: F,
+  ) -> Result<Option<DivergenceReport>, MadeleineError>
+  where
+    C: for<'a> Command<'a, SystemState = SystemState>,
+    F: FnOnce() -> SystemState,
+    SystemState: PartialEq,
+  {
+    let _command_lock = lock_recovering(&self.command_lock);
+
+    let checkpoints = self.replayable_snapshots()?;
+
+    let commands_compacted = self.commands_compacted.load(Ordering::Relaxed);
+    let mut checkpoints = checkpoints.into_iter().peekable();
+
+    let (mut state, mut matched_up_to) = if commands_compacted > 0 {
+      let (head_id, snapshot_id) = checkpoints.next().ok_or_else(|| {
+        MadeleineError::ReplayError(format!(
+          "{} commands were compacted out of the log and no snapshot taken since records where to replay the rest from",
+          commands_compacted
+        ))
+      })?;
+
+      (self.read_stored_snapshot(snapshot_id)?, Some(head_id))
+    } else {
+      (constructor(), None)
+    };
+
+    let start_id = matched_up_to.unwrap_or_else(Ulid::nil);
+    let mut last_id = start_id;
+
+    for logged in self.command_log.commands() {
+      let logged = logged?;
+
+      while let Some((head_id, snapshot_id)) =
+        checkpoints.next_if(|(head_id, _snapshot_id)| *head_id < logged.id)
+      {
+        if self.read_stored_snapshot(snapshot_id)? != state {
+          return Ok(Some(DivergenceReport {
+            matched_up_to,
+            diverged_by: head_id,
+            snapshot_id: Some(snapshot_id),
+          }));
+        }
+
+        matched_up_to = Some(head_id);
+      }
+
+      if logged.id <= start_id || logged.is_skipped_on_replay() {
+        continue;
+      }
+
+      let command: C = self.command_migrations.decode(&logged)?;
+
+      state = command.execute_with_ctx(
+        state,
+        &CommandContext::at(commands_compacted + logged.offset),
+      );
+      last_id = logged.id;
+    }
+
+    for (head_id, snapshot_id) in checkpoints {
+      if self.read_stored_snapshot(snapshot_id)? != state {
+        return Ok(Some(DivergenceReport {
+          matched_up_to,
+          diverged_by: head_id,
+          snapshot_id: Some(snapshot_id),
+        }));
+      }
+
+      matched_up_to = Some(head_id);
+    }
+
+    if *self.internal_state.read() != state {
+      return Ok(Some(DivergenceReport {
+        matched_up_to,
+        diverged_by: last_id,
+        snapshot_id: None,
+      }));
+    }
+
+    Ok(None)
+  }
+
+  /// Iterate over the commands in the log in the order they were logged, each deserialized as `C`
+  /// along with its ULID. The log is read a page at a time rather than loaded into memory,
+  /// and commands appended while iterating may or may not be seen. Tombstones left by
+  /// `Madeleine::redact_matching` and markers left by `Madeleine::undo_last` are skipped.
+  ///
+  /// A command which can't be deserialized as `C` yields an error and iteration carries on past it,
+  /// while an error reading the log ends the iteration.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// let commands = madeleine
+  ///   .iter_history::<Add>()?
+  ///   .map(|entry| entry.map(|entry| entry.command))
+  ///   .collect::<Result<Vec<_>, _>>()?;
+  ///
+  /// assert_eq!(commands, vec![Add(2), Add(3)]);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn iter_history<C: DeserializeOwned>(
+    &self,
+  ) -> Result<impl Iterator<Item = Result<HistoryEntry<C>, MadeleineError>> + '_, MadeleineError>
+  {
+    Ok(self.command_log.commands().filter_map(|logged| {
+      match logged {
+        Ok(logged) if logged.is_skipped_on_replay() => None,
+        Ok(logged) => Some(
+          self
+            .command_migrations
+            .decode(&logged)
+            .map(|command| HistoryEntry {
+              offset: logged.offset,
+              id: logged.id,
+              command,
+            }),
+        ),
+        Err(error) => Some(Err(error)),
+      }
+    }))
+  }
+
+  /// A page of up to `limit` commands deserialized as `C`, in the order they were logged, starting after
+  /// the command identified by `after`, or with the first command if it's `None`. Pass the page's `next`
+  /// as `after` to fetch the following page, until it's `None`. Tombstones left by `Madeleine::redact_matching`
+  /// and markers left by `Madeleine::undo_last` are left out, so a page can have fewer entries than `limit` without being the last.
+  ///
+  /// Pages follow the order of the log rather than that of ULIDs. If `after` isn't in the log,
+  /// e.g. because it was compacted away, the page starts with the first command with a greater ULID.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_commands((1..=5).map(Add))?;
+  ///
+  /// let first = madeleine.history_page::<Add>(None, 3)?;
+  /// let second = madeleine.history_page::<Add>(first.next, 3)?;
+  ///
+  /// assert_eq!(first.entries.len(), 3);
+  /// assert_eq!(second.entries[0].command, Add(4));
+  /// assert_eq!(second.entries.len(), 2);
+  /// assert_eq!(second.next, None);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn history_page<C: DeserializeOwned>(
+    &self,
+    after: Option<Ulid>,
+    limit: usize,
+  ) -> Result<HistoryPage<C>, MadeleineError> {
+    let commands = self.command_log.commands_in_range(after, limit)?;
+
+    let next = match commands.last() {
+      Some(last) if commands.len() == limit => Some(last.id),
+      _ => None,
+    };
+
+    let entries = commands
+      .into_iter()
+      .filter(|logged| !logged.is_skipped_on_replay())
+      .map(|logged| {
+        Ok(HistoryEntry {
+          offset: logged.offset,
+          id: logged.id,
+          command: self.command_migrations.decode(&logged)?,
+        })
+      })
+      .collect::<Result<_, MadeleineError>>()?;
+
+    Ok(HistoryPage { entries, next })
+  }
+
+  /// ULID of the last command applied to the state in a snapshot, or `Ulid::nil()` if none had been.
+  /// Commands logged after it are the ones to replay on top of the snapshot.
+  /// Returns `None` for snapshots taken before heads were recorded.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use ulid::Ulid;
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let before_commands = madeleine.take_snapshot(true)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// let after_commands = madeleine.take_snapshot(false)?;
+  ///
+  /// assert_eq!(madeleine.snapshot_head_id(before_commands)?, Some(Ulid::nil()));
+  /// assert_eq!(madeleine.snapshot_head_id(after_commands)?, Some(madeleine.head_id()?));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn snapshot_head_id(&self, snapshot_id: usize) -> Result<Option<Ulid>, MadeleineError> {
+    read_snapshot_head_id(snapshot_id, &self.location_dir_path)
+  }
+
+  /// Take a snapshot as `Madeleine::take_snapshot` does and label it, returning its id,
+  /// so that it can be found by name with `Madeleine::load_snapshot_named`.
+  /// The label is recorded with a fresh ULID, whose timestamp says when the snapshot was taken.
+  /// Labels may be reused, and must be made of ASCII letters, digits, hyphens and underscores,
+  /// or the snapshot isn't taken and this fails with `MadeleineError::SnapshotError`.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot_named("before-migration")?;
+  /// madeleine.execute_command(Add(3))?;
+  ///
+  /// assert_eq!(madeleine.load_snapshot_named("before-migration")?, 2);
+  /// assert!(madeleine.take_snapshot_named("release 2.0").is_err());
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn take_snapshot_named(&self, label: &str) -> Result<usize, MadeleineError> {
+    check_label(label)?;
+
+    let snapshot_id = self.take_snapshot(false)?;
+    let label = SnapshotLabel {
+      label: String::from(label),
+      ulid: Ulid::new(),
+    };
+
+    write_snapshot_file(
+      &snapshot_label_file_path(snapshot_id, self.location_dir_path.clone()),
+      serde_json::to_string(&label)?.as_bytes(),
+    )?;
+
+    Ok(snapshot_id)
+  }
+
+  /// Read the state held in the latest snapshot labelled `label` by `Madeleine::take_snapshot_named`,
+  /// leaving the live state alone. Fails with `MadeleineError::SnapshotError` if no snapshot has the label,
+  /// e.g. because a compaction removed it, see `Madeleine::rollback_to_snapshot` to revert the store to one.
+  pub fn load_snapshot_named(&self, label: &str) -> Result<SystemState, MadeleineError> {
+    check_label(label)?;
+
+    let mut latest = None;
+
+    // Snapshot ids only grow, unlike ULIDs made within the same millisecond, so the latest is the last found.
+    for snapshot_id in list_snapshot_ids(&self.location_dir_path)? {
+      if read_snapshot_label(snapshot_id, &self.location_dir_path)?
+        .is_some_and(|recorded| recorded.label == label)
+      {
+        latest = Some(snapshot_id);
+      }
+    }
+
+    let snapshot_id = latest.ok_or_else(|| {
+      MadeleineError::SnapshotError(format!("no snapshot is labelled {:?}", label))
+    })?;
+
+    self.read_stored_snapshot(snapshot_id)
+  }
+
+  /// Heads and ids of the snapshots which the log can be replayed from, sorted by head.
+  /// Leaves out snapshots taken before commands which have since been compacted out of the log,
+  /// and, for stores compacted before where that was recorded, every snapshot.
+  fn replayable_snapshots(&self) -> Result<Vec<(Ulid, usize)>, MadeleineError> {
+    let compacted_through = if self.commands_compacted.load(Ordering::Relaxed) > 0 {
+      Some(
+        StoreMetadata::read_compacted_through(&self.location_dir_path)?.unwrap_or(Ulid(u128::MAX)),
+      )
+    } else {
+      None
+    };
+
+    let mut snapshots = Vec::new();
+
+    for snapshot_id in list_snapshot_ids(&self.location_dir_path)? {
+      if let Some(head_id) = self.snapshot_head_id(snapshot_id)? {
+        if compacted_through.is_none_or(|compacted_through| head_id >= compacted_through) {
+          snapshots.push((head_id, snapshot_id));
+        }
+      }
+    }
+
+    snapshots.sort();
+
+    Ok(snapshots)
+  }
+
+  /// The state in a snapshot, read without changing the live state.
+  fn read_stored_snapshot(&self, snapshot_id: usize) -> Result<SystemState, MadeleineError> {
+    let resolved_id = resolve_snapshot_alias(snapshot_id, self.location_dir_path.clone())?;
+    let snapshot_state =
+      read_snapshot_state(resolved_id, &self.location_dir_path)?.ok_or_else(|| {
+        MadeleineError::SnapshotError(format!(
+          "the chain of differential snapshot {} is broken",
+          resolved_id
+        ))
+      })?;
+
+    Ok(serde_json::from_slice(&snapshot_state.raw_state)?)
+  }
+
+  /// List every snapshot in the store, in ascending order of id, with its label if it was given one.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.take_snapshot(true)?;
+  /// madeleine.execute_command(Add(2))?;
+  /// madeleine.take_snapshot_named("release-2")?;
+  ///
+  /// let snapshots = madeleine.list_snapshots()?;
+  ///
+  /// assert_eq!(snapshots.len(), 2);
+  /// assert_eq!(snapshots[0].label, None);
+  /// assert_eq!(snapshots[1].label.as_deref(), Some("release-2"));
+  /// assert_eq!(snapshots[1].head_id, Some(madeleine.head_id()?));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, MadeleineError> {
+    list_snapshot_ids(&self.location_dir_path)?
+      .into_iter()
+      .map(|snapshot_id| {
+        let label = read_snapshot_label(snapshot_id, &self.location_dir_path)?;
+        let bytes = [
+          snapshot_file_path(snapshot_id, self.location_dir_path.clone()),
+          snapshot_diff_file_path(snapshot_id, self.location_dir_path.clone()),
+        ]
+        .into_iter()
+        .find(|path| path.is_file())
+        .map_or(Ok(0), |path| {
+          fs::metadata(path).map(|metadata| metadata.len())
+        })?;
+
+        Ok(SnapshotInfo {
+          snapshot_id,
+          ulid: label.as_ref().map(|label| label.ulid),
+          label: label.map(|label| label.label),
+          head_id: self.snapshot_head_id(snapshot_id)?,
+          bytes,
+        })
+      })
+      .collect()
+  }
+
+  /// Determine the next snapshot id in sequence.
+  ///
+  /// ```
+  /// # use madeleine::testing::scratch_store;
+  /// # use madeleine::Madeleine;
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// let next_snapshot_id = madeleine.next_snapshot_id()?;
+  ///
+  /// assert_eq!(madeleine.take_snapshot(true)?, next_snapshot_id);
+  /// assert_eq!(madeleine.next_snapshot_id()?, next_snapshot_id + 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn next_snapshot_id(&self) -> Result<usize, MadeleineError> {
+    let snapshot_file_path = snapshot_id_file_path(self.location_dir_path.clone());
+
+    if snapshot_file_path.is_file() {
+      let raw = fs::read(snapshot_file_path)?;
+      let parsed: usize = serde_json::from_slice(&raw)?;
+
+      Ok(parsed + 1)
+    } else {
+      Ok(0)
+    }
+  }
+}
+
+/// What's logged along with a command by `Madeleine::execute_unobserved_with`.
+#[derive(Debug, Clone, Copy)]
+enum Logging<'k> {
+  /// Just the command.
+  Plain,
+  /// The command, having saved its idempotency key as pending before appending it, see `IdempotencyCache::write_ahead`.
+  Idempotent(&'k str),
+  /// The command, as the inverse of the command with this ULID, followed by an undo marker in the same append,
+  /// see `Madeleine::undo_last`.
+  Inverse(Ulid),
+}
+
+/// Stands in for `AllocationCheck` without the `allocation-check` feature, when there's never a check.
+#[cfg(not(feature = "allocation-check"))]
+enum AllocationCheck {}
+
+#[cfg(not(feature = "allocation-check"))]
+impl AllocationCheck {
+  fn start(&self) -> Self {
+    match *self {}
+  }
+
+  fn finish(self) -> Result<(), MadeleineError> {
+    match self {}
+  }
+}
+
+/// Execute a command, then check what it allocated, if there's an allocation check.
+fn execute_metered<T>(
+  check: Option<&AllocationCheck>,
+  execute: impl FnOnce() -> T,
+) -> (T, Result<(), MadeleineError>) {
+  let meter = check.map(AllocationCheck::start);
+  let executed = execute();
+
+  (executed, meter.map_or(Ok(()), |meter| meter.finish()))
+}
+
+/// Report that clone-based APIs are cloning more state than configured, see `Metrics::set_clone_rate_warning`.
+fn warn_clone_rate(metrics: &Metrics) {
+  #[cfg(feature = "tracing")]
+  tracing::warn!(
+    state_clones = metrics.state_clones(),
+    state_size_estimate = metrics.state_size_estimate(),
+    "Clone-based reads of the state exceeded the configured rate; consider tap_ref"
+  );
+
+  #[cfg(not(feature = "tracing"))]
+  let _ = metrics;
+}
+
+/// Location of a store's command log, as named by its layout.
+pub(crate) fn command_log_dir_path(location_dir_path: &Path) -> PathBuf {
+  StoreLayout::of(location_dir_path).command_log_dir_path(location_dir_path)
+}
+
+/// Location of a store's snapshots, as named by its layout.
+fn snapshot_dir_path(location_dir_path: &Path) -> PathBuf {
+  StoreLayout::of(location_dir_path).snapshot_dir_path(location_dir_path)
+}
+
+/// Determine if a directory holds a store.
+/// A store interrupted while compacting may briefly have only a retired log.
+pub(crate) fn is_store_root(location_dir_path: &Path) -> bool {
+  command_log_dir_path(location_dir_path).is_dir()
+    || location_dir_path.join(RETIRED_LOG_DIR_NAME).is_dir()
+}
+
+/// Determine if an entry in a store directory is one of the store's own files, given the store's layout.
+pub(crate) fn is_store_entry(file_name: &str, layout: &StoreLayout) -> bool {
+  layout.names_entry(file_name) || is_reserved_entry_name(file_name)
+}
+
+/// Determine if a name is taken by one of the files a store keeps in its directory whatever its layout,
+/// including the temporary file a crash may leave behind while one of them was being replaced.
+pub(crate) fn is_reserved_entry_name(file_name: &str) -> bool {
+  if let Some(replaced) = file_name.strip_suffix(TEMP_FILE_SUFFIX) {
+    return is_reserved_entry_name(replaced);
+  }
+
+  [
+    ADMIN_LOG_FILE_NAME,
+    COMPACTED_LOG_DIR_NAME,
+    RETIRED_LOG_DIR_NAME,
+    METADATA_FILE_NAME,
+    SNAPSHOT_FILE_SUFFIX,
+    IDEMPOTENCY_FILE_NAME,
+    IMPORT_CHECKPOINT_FILE_NAME,
+    IMPORT_META_FILE_NAME,
+    MERGE_CHECKPOINT_FILE_NAME,
+    REJECTIONS_FILE_NAME,
+  ]
+  .contains(&file_name)
+    || parse_snapshot_file_name(file_name).is_some()
+    || is_snapshot_record_file_name(file_name)
+}
+
+/// Determine if a file records the head or label of a snapshot, see `Madeleine::snapshot_head_id`
+/// and `Madeleine::take_snapshot_named`.
+fn is_snapshot_record_file_name(file_name: &str) -> bool {
+  file_name.split_once('.').is_some_and(|(id, suffix)| {
+    [SNAPSHOT_HEAD_FILE_SUFFIX, SNAPSHOT_LABEL_FILE_SUFFIX].contains(&suffix)
+      && id.chars().all(|c| c.is_ascii_digit())
+  })
+}
+
+/// Extract the snapshot id from the name of a snapshot, snapshot alias or differential snapshot file.
+fn parse_snapshot_file_name(file_name: &str) -> Option<usize> {
+  let (id, suffix) = file_name.split_once('.')?;
+
+  if [
+    SNAPSHOT_FILE_SUFFIX,
+    SNAPSHOT_ALIAS_FILE_SUFFIX,
+    SNAPSHOT_DIFF_FILE_SUFFIX,
+  ]
+  .contains(&suffix)
+    && id.chars().all(|c| c.is_ascii_digit())
+  {
+    id.parse().ok()
+  } else {
+    None
+  }
+}
+
+/// List the ids of every snapshot, snapshot alias and differential snapshot in the store, in ascending order.
+pub(crate) fn list_snapshot_ids(location_dir_path: &Path) -> Result<Vec<usize>, MadeleineError> {
+  let mut snapshot_ids = Vec::new();
+  let snapshot_dir_path = snapshot_dir_path(location_dir_path);
+
+  if !snapshot_dir_path.is_dir() {
+    return Ok(snapshot_ids);
+  }
+
+  for entry in fs::read_dir(snapshot_dir_path)? {
+    if let Some(snapshot_id) = parse_snapshot_file_name(&entry?.file_name().to_string_lossy()) {
+      snapshot_ids.push(snapshot_id);
+    }
+  }
+
+  snapshot_ids.sort_unstable();
+  snapshot_ids.dedup();
+
+  Ok(snapshot_ids)
+}
+
+pub(crate) fn snapshot_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
+  let snapshot_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_FILE_SUFFIX);
+  snapshot_dir_path(&location_dir_path).join(snapshot_file_name)
+}
+
+pub(crate) fn snapshot_alias_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
+  let snapshot_alias_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_ALIAS_FILE_SUFFIX);
+  snapshot_dir_path(&location_dir_path).join(snapshot_alias_file_name)
+}
+
+pub(crate) fn snapshot_diff_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
+  let snapshot_diff_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_DIFF_FILE_SUFFIX);
+  snapshot_dir_path(&location_dir_path).join(snapshot_diff_file_name)
+}
+
+pub(crate) fn snapshot_head_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
+  let snapshot_head_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_HEAD_FILE_SUFFIX);
+  snapshot_dir_path(&location_dir_path).join(snapshot_head_file_name)
+}
+
+pub(crate) fn snapshot_label_file_path(snapshot_id: usize, location_dir_path: PathBuf) -> PathBuf {
+  let snapshot_label_file_name = format!("{}.{}", snapshot_id, SNAPSHOT_LABEL_FILE_SUFFIX);
+  snapshot_dir_path(&location_dir_path).join(snapshot_label_file_name)
+}
+
+/// Read the ULID of the last command applied to the state in a snapshot, if it was recorded.
+pub(crate) fn read_snapshot_head_id(
+  snapshot_id: usize,
+  location_dir_path: &Path,
+) -> Result<Option<Ulid>, MadeleineError> {
+  let head_path = snapshot_head_file_path(snapshot_id, location_dir_path.to_path_buf());
+
+  if head_path.is_file() {
+    let raw = fs::read(head_path)?;
+
+    Ok(Some(serde_json::from_slice(&raw)?))
+  } else {
+    Ok(None)
+  }
+}
+
+/// Write one of a snapshot's files, reporting failure as a `MadeleineError::SnapshotError`.
+fn write_snapshot_file(location: &Path, contents: &[u8]) -> Result<(), MadeleineError> {
+  fs::write(location, contents).map_err(|error| {
+    MadeleineError::SnapshotError(format!("unable to write {}: {}", location.display(), error))
+  })
+}
+
+/// Determine the id of the snapshot file holding the state for a snapshot id, following an alias if one was recorded.
+pub(crate) fn resolve_snapshot_alias(
+  snapshot_id: usize,
+  location_dir_path: PathBuf,
+) -> Result<usize, MadeleineError> {
+  let alias_path = snapshot_alias_file_path(snapshot_id, location_dir_path);
+
+  if alias_path.is_file() {
+    let raw = fs::read(alias_path)?;
+    let target: usize = serde_json::from_slice(&raw)?;
+
+    Ok(target)
+  } else {
+    Ok(snapshot_id)
+  }
+}
+
+/// Find the latest full snapshot file before a snapshot, to resume from when that snapshot's chain is broken.
+fn previous_full_snapshot_id(
+  snapshot_id: usize,
+  location_dir_path: &Path,
+) -> Result<Option<usize>, MadeleineError> {
+  Ok(
+    list_snapshot_ids(location_dir_path)?
+      .into_iter()
+      .rev()
+      .find(|earlier| {
+        *earlier < snapshot_id
+          && snapshot_file_path(*earlier, location_dir_path.to_path_buf()).is_file()
+      }),
+  )
+}
+
+pub(crate) fn snapshot_id_file_path(location_dir_path: PathBuf) -> PathBuf {
+  snapshot_dir_path(&location_dir_path).join(SNAPSHOT_FILE_SUFFIX)
+}
+
+/// Write the snapshot ID file
+fn write_snapshot_id_file(
+  snapshot_file_id_name: PathBuf,
+  current_snapshot_id: usize,
+) -> Result<usize, MadeleineError> {
+  let raw = serde_json::to_string(&current_snapshot_id)?;
+  fs::write(snapshot_file_id_name, raw)?;
+
+  Ok(current_snapshot_id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use assert_fs::prelude::*;
+  use predicates::prelude::*;
+  use pretty_assertions::assert_eq;
+
+  use std::collections::HashMap;
+
+  use crate::command::TryCommand;
+  use crate::testing::FailpointAction;
+
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  enum Action {
+    Increment(String, usize),
+    Decrement(String, usize),
+  }
+
+  impl Command<'_> for Action {
+    type SystemState = HashMap<String, usize>;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      let mut new_state = old_state.clone();
+
+      match self {
+        Self::Increment(key, amount) => new_state
+          .entry(key.to_string())
+          .and_modify(|e| *e += amount)
+          .or_insert(*amount),
+        Self::Decrement(key, amount) => new_state
           .entry(key.to_string())
           .and_modify(|e| *e -= amount)
           .or_insert(*amount),
@@ -191,322 +5562,2449 @@ mod tests {
     }
   }
 
-  #[track_caller]
-  fn make_test_madeleine<T, C>(constructor: C) -> Madeleine<T>
-  where
-    C: Fn() -> T,
-    T: Clone + for<'a> Deserialize<'a> + Serialize,
-  {
-    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
-
-    let log_path = temp_dir.path().join("test_log");
-
-    Madeleine::new(log_path, constructor).expect("unable to instantiate madeleine in test")
+  #[track_caller]
+  fn make_test_madeleine<T, C>(constructor: C) -> Madeleine<T>
+  where
+    C: Fn() -> T,
+    T: Clone + for<'a> Deserialize<'a> + Serialize,
+  {
+    Madeleine::new_in_memory(constructor).expect("unable to instantiate madeleine in test")
+  }
+
+  #[test]
+  fn test_execute_commands_applies_commands_in_order() {
+    let madeleine = make_test_madeleine(HashMap::new);
+
+    let executed = madeleine
+      .execute_commands((1..=4).map(|amount| Action::Increment(String::from("panda"), amount)))
+      .expect("unable to execute commands in test");
+
+    assert_eq!(executed, 4);
+    assert_eq!(madeleine.len(), 4);
+    assert_eq!(madeleine.tap(|state| state.get("panda").copied()), Some(10));
+    assert_eq!(
+      madeleine
+        .execute_commands(Vec::<Action>::new())
+        .expect("unable to execute commands in test"),
+      0
+    );
+  }
+
+  #[test]
+  fn test_new_in_memory_is_removed_on_drop() {
+    let madeleine =
+      Madeleine::new_in_memory(HashMap::new).expect("unable to instantiate madeleine in test");
+
+    let location_dir_path = madeleine.location_dir_path.clone();
+
+    assert!(location_dir_path.is_dir());
+
+    madeleine
+      .execute_command(Action::Increment(String::from("panda"), 2))
+      .expect("unable to execute command in test");
+
+    assert_eq!(madeleine.len(), 1);
+    assert_eq!(madeleine.tap(|state| state.get("panda").copied()), Some(2));
+
+    drop(madeleine);
+
+    assert!(!location_dir_path.exists());
+  }
+
+  #[test]
+  fn test_new_in_memory_stays_out_of_working_directory_and_cant_be_reopened() {
+    let madeleine =
+      Madeleine::new_in_memory(HashMap::new).expect("unable to instantiate madeleine in test");
+
+    let working_dir_path = std::env::current_dir().expect("unable to get working dir in test");
+    let location_dir_path = madeleine.location_dir_path.clone();
+
+    assert!(!location_dir_path.starts_with(&working_dir_path));
+
+    for amount in 1..=3 {
+      madeleine
+        .execute_command(Action::Increment(String::from("panda"), amount))
+        .expect("unable to execute command in test");
+    }
+
+    assert_eq!(madeleine.len(), 3);
+    assert_eq!(madeleine.tap(|state| state.get("panda").copied()), Some(6));
+
+    assert!(matches!(
+      Madeleine::<HashMap<String, isize>>::resume(location_dir_path.clone()).err(),
+      Some(MadeleineError::InMemoryStore(_))
+    ));
+    assert!(matches!(
+      Madeleine::<HashMap<String, isize>>::new(location_dir_path, HashMap::new).err(),
+      Some(MadeleineError::InMemoryStore(_))
+    ));
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine.compact().expect("unable to compact in test");
+
+    assert_eq!(madeleine.len(), 0);
+    assert_eq!(madeleine.tap(|state| state.get("panda").copied()), Some(6));
+  }
+
+  #[test]
+  fn test_new_creates_command_log() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let log_path = temp_dir.path().join("test_log");
+
+    let state = 0;
+
+    temp_dir
+      .child("test_log")
+      .assert(predicate::path::missing());
+
+    Madeleine::new(log_path, &|| state).expect("unable to instantiate madeleine in test");
+
+    temp_dir.child("test_log").assert(predicate::path::exists());
+  }
+
+  #[test]
+  fn test_new_rejects_unrelated_directory() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    temp_dir
+      .child("notes.txt")
+      .write_str("not a store")
+      .expect("unable to write file in test");
+    temp_dir
+      .child("photos")
+      .create_dir_all()
+      .expect("unable to create dir in test");
+
+    let actual = Madeleine::new(temp_dir.path().to_path_buf(), || 0);
+
+    match actual {
+      Err(MadeleineError::DirectoryError(message)) => {
+        assert!(message.contains("notes.txt, photos"));
+      }
+      _ => panic!("expected a directory error in test"),
+    }
+
+    temp_dir
+      .child(COMMAND_LOG_DIR_NAME)
+      .assert(predicate::path::missing());
+
+    Madeleine::new_with_directory_policy(
+      temp_dir.path().to_path_buf(),
+      DirectoryPolicy::Permissive,
+      || 0,
+    )
+    .expect("unable to instantiate permissive madeleine in test");
+  }
+
+  #[test]
+  fn test_new_reopens_existing_store() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(store_path.clone(), || 0).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    drop(madeleine);
+
+    Madeleine::new(store_path, || 0).expect("unable to reopen madeleine in test");
+  }
+
+  #[test]
+  fn test_resume_rejects_directory_which_is_not_a_store() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let actual: Result<Madeleine<usize>, MadeleineError> =
+      Madeleine::resume(temp_dir.path().to_path_buf());
+
+    assert!(matches!(actual, Err(MadeleineError::DirectoryError(_))));
+
+    let missing: Result<Madeleine<usize>, MadeleineError> =
+      Madeleine::resume(temp_dir.path().join("missing"));
+
+    assert!(matches!(missing, Err(MadeleineError::DirectoryError(_))));
+
+    temp_dir
+      .child(COMMAND_LOG_DIR_NAME)
+      .assert(predicate::path::missing());
+  }
+
+  #[test]
+  fn test_into_inner() {
+    let state = 42;
+    let madeleine = make_test_madeleine(|| state);
+
+    assert_eq!(state, madeleine.into_inner());
+  }
+
+  #[test]
+  fn test_new_sets_constructor_result_as_state() {
+    let madeleine = make_test_madeleine(|| 41 + 1);
+
+    assert_eq!(42, madeleine.into_inner());
+  }
+
+  #[test]
+  fn test_execute_command_once() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    let action = Action::Increment("panda".to_string(), 613);
+
+    madeleine
+      .execute_command(action)
+      .expect("unable to execute increment action in test");
+
+    let state = madeleine.into_inner();
+
+    let actual = state.get("panda");
+
+    let val: usize = 613;
+
+    let expected = Some(&val);
+
+    assert_eq!(expected, actual);
+  }
+
+  #[test]
+  fn test_execute_command_many() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    let state = madeleine.into_inner();
+
+    let actual = state.get("panda");
+
+    let val: usize = 613;
+
+    let expected = Some(&val);
+
+    assert_eq!(expected, actual);
+  }
+
+  #[test]
+  fn test_madeleine_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<Madeleine<HashMap<String, usize>>>();
+  }
+
+  #[test]
+  fn test_execute_command_from_many_threads() {
+    let madeleine = std::sync::Arc::new(make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    }));
+
+    let threads: Vec<_> = (0..8)
+      .map(|_i| {
+        let madeleine = madeleine.clone();
+
+        std::thread::spawn(move || {
+          for _j in 0..1000 {
+            madeleine
+              .execute_command(Action::Increment("panda".to_string(), 1))
+              .expect("unable to execute increment action in test");
+
+            // Readers on other threads only ever see whole commands applied.
+            madeleine
+              .tap_ref(|state| assert!(state.get("panda").is_some_and(|count| *count <= 8000)))
+              .expect("unable to read state in test");
+          }
+        })
+      })
+      .collect();
+
+    for thread in threads {
+      thread.join().expect("executing thread panicked in test");
+    }
+
+    assert_eq!(madeleine.len(), 8000);
+    assert_eq!(
+      madeleine.tap(|state| state.get("panda").copied()),
+      Some(8000)
+    );
+  }
+
+  #[test]
+  fn test_tap() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    let internal_start = madeleine.tap(|state| state.get("panda").map(|v| v.to_owned()));
+
+    assert_eq!(internal_start, None);
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    let internal_mid = madeleine.tap(|state| state.get("panda").unwrap_or(&0).to_owned());
+
+    assert_eq!(internal_mid, 613);
+
+    for _i in 0..613 {
+      let action = Action::Decrement("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute decrement action in test");
+    }
+
+    let state = madeleine.into_inner();
+
+    let actual = state.get("panda");
+
+    let val = 0;
+
+    let expected = Some(&val);
+
+    assert_eq!(expected, actual);
+  }
+
+  #[test]
+  fn test_tap_ref_does_not_clone() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || {
+      let state: HashMap<String, usize> = HashMap::from([("panda".to_string(), 613)]);
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    let actual = madeleine
+      .tap_ref(|state| state.get("panda").copied())
+      .expect("unable to tap state in test");
+
+    assert_eq!(actual, Some(613));
+    assert_eq!(madeleine.metrics().state_clones(), 0);
+
+    madeleine.tap(|state| state.len());
+
+    assert_eq!(madeleine.metrics().state_clones(), 1);
+  }
+
+  #[test]
+  fn test_clone_rate_warning_emitted_once() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || {
+      let state: HashMap<String, usize> = (0..1000).map(|i| (i.to_string(), i)).collect();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    madeleine.metrics().set_clone_rate_warning(Some(10_000));
+
+    madeleine.tap(|state| state.len());
+
+    assert!(!madeleine.metrics().clone_rate_warning_emitted());
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    assert!(madeleine.metrics().state_size_estimate() > 5_000);
+
+    for _i in 0..3 {
+      madeleine.tap(|state| state.len());
+    }
+
+    assert!(madeleine.metrics().clone_rate_warning_emitted());
+    assert_eq!(madeleine.metrics().state_clones(), 4);
+  }
+
+  #[test]
+  fn test_len_with_empty() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    let actual = madeleine.len();
+
+    assert_eq!(actual, 0);
+  }
+
+  #[test]
+  fn test_len_with_some_commands() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    let length_at_start = madeleine.len();
+
+    assert_eq!(length_at_start, 0);
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    let actual = madeleine.len();
+
+    assert_eq!(actual, 613);
+  }
+
+  #[test]
+  fn test_len_unchanged_by_snapshot() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(
+      temp_dir.path().join("test_store"),
+      HashMap::<String, usize>::new,
+    )
+    .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..3 {
+      madeleine
+        .execute_command(Action::Increment("panda".to_string(), 1))
+        .expect("unable to execute increment action in test");
+    }
+
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    temp_dir
+      .child("test_store")
+      .child(format!("{}.snapshot", snapshot_id))
+      .assert(predicate::path::is_file());
+    assert_eq!(madeleine.len(), 3);
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 1))
+      .expect("unable to execute increment action in test");
+
+    assert_eq!(madeleine.len(), 4);
+  }
+
+  #[test]
+  fn test_next_snapshot_id_first() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    let actual = madeleine
+      .next_snapshot_id()
+      .expect("unable to determine next snapshot id in test");
+
+    assert_eq!(actual, 0);
+  }
+
+  #[test]
+  fn test_next_snapshot_id_subsequent() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let log_path = temp_dir.path().join("test_store");
+
+    let state = 0;
+
+    let madeleine =
+      Madeleine::new(log_path, &|| state).expect("unable to instantiate madeleine in test");
+
+    temp_dir
+      .child("test_store")
+      .child(SNAPSHOT_FILE_SUFFIX)
+      .assert(predicate::path::missing());
+
+    let actual_fresh = madeleine
+      .next_snapshot_id()
+      .expect("unable to determine next snapshot id in test");
+
+    assert_eq!(actual_fresh, 0);
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    temp_dir
+      .child("test_store")
+      .child(SNAPSHOT_FILE_SUFFIX)
+      .assert(predicate::path::exists());
+
+    let actual_after_one = madeleine
+      .next_snapshot_id()
+      .expect("unable to determine next snapshot id in test");
+
+    assert_eq!(actual_after_one, 1);
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let actual_after_two = madeleine
+      .next_snapshot_id()
+      .expect("unable to determine next snapshot id in test");
+
+    assert_eq!(actual_after_two, 2);
+  }
+
+  #[test]
+  fn test_basic_resume() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let expected = madeleine.into_inner();
+
+    let new_madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    let actual = new_madeleine.into_inner();
+
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn test_resume_rejects_commands_logged_after_snapshot() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 1))
+      .expect("unable to execute increment action in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 2))
+      .expect("unable to execute increment action in test");
+
+    drop(madeleine);
+
+    let resumed: Result<Madeleine<HashMap<String, usize>>, _> =
+      Madeleine::resume(store_path.clone());
+
+    assert!(matches!(resumed, Err(MadeleineError::ReplayError(_))));
+
+    let replayed = Madeleine::resume_replaying::<Action, _>(store_path, HashMap::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(replayed.tap(|state| state.get("panda").copied()), Some(3));
+  }
+
+  #[test]
+  fn test_snapshot_deduplicated_when_state_unchanged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 613))
+      .expect("unable to execute increment action in test");
+
+    let first = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let second = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    assert_eq!((first, second), (0, 1));
+
+    let store = temp_dir.child("test_store");
+
+    store.child("0.snapshot").assert(predicate::path::exists());
+    store.child("1.snapshot").assert(predicate::path::missing());
+    store.child("1.alias").assert(predicate::path::exists());
+
+    let expected = madeleine.into_inner();
+
+    let new_madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    new_madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    store.child("2.snapshot").assert(predicate::path::missing());
+    store.child("2.alias").assert(predicate::path::exists());
+
+    assert_eq!(new_madeleine.into_inner(), expected);
+  }
+
+  #[test]
+  fn test_snapshot_written_when_state_changed_or_forced() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path, || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 613))
+      .expect("unable to execute increment action in test");
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .take_snapshot(true)
+      .expect("unable to take snapshot in test");
+
+    let store = temp_dir.child("test_store");
+
+    store.child("0.snapshot").assert(predicate::path::exists());
+    store.child("1.snapshot").assert(predicate::path::exists());
+    store.child("2.snapshot").assert(predicate::path::exists());
+    store.child("2.alias").assert(predicate::path::missing());
+  }
+
+  /// Take a snapshot after each increment, every third of them written in full.
+  fn take_differential_snapshots(store_path: PathBuf, increments: usize) -> HashMap<String, usize> {
+    let madeleine = Madeleine::new(store_path, HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .set_full_snapshot_every(3)
+      .expect("unable to set full snapshot interval in test");
+
+    for _ in 0..increments {
+      madeleine
+        .execute_command(Action::Increment("panda".to_string(), 613))
+        .expect("unable to execute increment action in test");
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+    }
+
+    madeleine.into_inner()
+  }
+
+  #[test]
+  fn test_differential_snapshots_resume_through_chain() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let expected = take_differential_snapshots(store_path.clone(), 5);
+
+    let store = temp_dir.child("test_store");
+
+    for full in ["0.snapshot", "3.snapshot"] {
+      store.child(full).assert(predicate::path::exists());
+    }
+
+    for diff in ["1.diff", "2.diff", "4.diff"] {
+      store.child(diff).assert(predicate::path::exists());
+    }
+
+    store.child("4.snapshot").assert(predicate::path::missing());
+
+    let madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    assert_eq!(madeleine.into_inner(), expected);
+  }
+
+  #[test]
+  fn test_broken_snapshot_chain_falls_back_to_previous_full_snapshot() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let expected = take_differential_snapshots(store_path.clone(), 3);
+
+    fs::remove_file(snapshot_diff_file_path(1, store_path.clone()))
+      .expect("unable to remove snapshot in test");
+
+    // The full snapshot falls short of the log, so resuming without replaying it is refused.
+    let resumed: Result<Madeleine<HashMap<String, usize>>, _> =
+      Madeleine::resume(store_path.clone());
+
+    assert!(matches!(resumed, Err(MadeleineError::ReplayError(_))));
+
+    let replayed: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume_replaying::<Action, _>(store_path, HashMap::new)
+        .expect("unable to resume madeleine in test");
+
+    assert_eq!(replayed.into_inner(), expected);
+  }
+
+  #[test]
+  fn test_snapshot_removal_keeps_chain_of_later_snapshots() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let expected = take_differential_snapshots(store_path.clone(), 5);
+
+    crate::compaction::remove_snapshots_before(&store_path, 4)
+      .expect("unable to remove snapshots in test");
+
+    let store = temp_dir.child("test_store");
+
+    store.child("0.snapshot").assert(predicate::path::missing());
+    store.child("2.diff").assert(predicate::path::missing());
+    store.child("3.snapshot").assert(predicate::path::exists());
+
+    let madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    assert_eq!(madeleine.into_inner(), expected);
+  }
+
+  #[test]
+  fn test_snapshot_records_last_applied_command() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path, HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    let empty_snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 613))
+      .expect("unable to execute increment action in test");
+
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    let head_id = madeleine.head_id().expect("unable to read head in test");
+
+    assert_eq!(
+      madeleine
+        .snapshot_head_id(empty_snapshot_id)
+        .expect("unable to read snapshot head in test"),
+      Some(Ulid::nil())
+    );
+    assert_eq!(
+      madeleine
+        .snapshot_head_id(snapshot_id)
+        .expect("unable to read snapshot head in test"),
+      Some(head_id)
+    );
+    assert_eq!(
+      madeleine
+        .snapshot_head_id(snapshot_id + 1)
+        .expect("unable to read snapshot head in test"),
+      None
+    );
+  }
+
+  #[test]
+  fn test_snapshot_failures_are_snapshot_errors() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    let next_snapshot_id = madeleine
+      .next_snapshot_id()
+      .expect("unable to determine next snapshot id in test");
+    fs::create_dir(snapshot_file_path(next_snapshot_id, store_path))
+      .expect("unable to block snapshot file in test");
+
+    assert!(matches!(
+      madeleine.take_snapshot(true),
+      Err(MadeleineError::SnapshotError(message)) if message.contains("unable to write")
+    ));
+    assert_eq!(
+      madeleine
+        .next_snapshot_id()
+        .expect("unable to determine next snapshot id in test"),
+      next_snapshot_id
+    );
+
+    let unserializable = make_test_madeleine(|| HashMap::from([((1_u8, 2_u8), 3_u8)]));
+
+    assert!(matches!(
+      unserializable.take_snapshot(true),
+      Err(MadeleineError::SnapshotError(message)) if message.contains("unable to serialize state")
+    ));
+  }
+
+  #[test]
+  fn test_projection_tracks_commands() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    madeleine
+      .register_projection(
+        "total",
+        0,
+        |total: &mut usize, action: &Action| match action {
+          Action::Increment(_, amount) => *total += amount,
+          Action::Decrement(_, amount) => *total -= amount,
+        },
+      )
+      .expect("unable to register projection in test");
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 2);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    let actual: usize = madeleine
+      .projection("total")
+      .expect("unable to read projection in test");
+
+    assert_eq!(actual, 1226);
+  }
+
+  #[test]
+  fn test_projection_rebuilt_from_log_on_resume() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    for key in ["panda", "koala", "panda"] {
+      let action = Action::Increment(key.to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    drop(madeleine);
+
+    let new_madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+
+    new_madeleine
+      .register_projection(
+        "keys",
+        Vec::new(),
+        |keys: &mut Vec<String>, action: &Action| {
+          if let Action::Increment(key, _) = action {
+            keys.push(key.to_string());
+          }
+        },
+      )
+      .expect("unable to register projection in test");
+
+    let actual: Vec<String> = new_madeleine
+      .projection("keys")
+      .expect("unable to read projection in test");
+
+    assert_eq!(actual, vec!["panda", "koala", "panda"]);
+  }
+
+  #[test]
+  fn test_projection_duplicate_name() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    madeleine
+      .register_projection("total", 0, |_total: &mut usize, _action: &Action| {})
+      .expect("unable to register projection in test");
+
+    let actual =
+      madeleine.register_projection("total", 0, |_total: &mut usize, _action: &Action| {});
+
+    assert!(matches!(actual, Err(MadeleineError::ProjectionError(_))));
+  }
+
+  #[test]
+  fn test_arc_snapshot_head_matches_state() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let madeleine = Arc::new(
+      Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+        .expect("unable to instantiate madeleine in test"),
+    );
+
+    let writer = {
+      let madeleine = madeleine.clone();
+
+      std::thread::spawn(move || {
+        for _i in 0..100 {
+          madeleine
+            .execute_command(crate::testing::Add(1))
+            .expect("unable to execute command in test");
+        }
+      })
+    };
+
+    let mut snapshots = Vec::new();
+
+    for _i in 0..50 {
+      snapshots.push(
+        madeleine
+          .arc_snapshot()
+          .expect("unable to take snapshot in test"),
+      );
+    }
+
+    writer.join().expect("unable to join thread in test");
+
+    let logged_ids: Vec<Ulid> = crate::Follower::open(temp_dir.path().join("test_store"))
+      .expect("unable to open follower in test")
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test")
+      .iter()
+      .map(|logged| logged.id)
+      .collect();
+
+    for snapshot in snapshots {
+      let applied = logged_ids
+        .iter()
+        .position(|id| *id == snapshot.head_id())
+        .map_or(0, |position| position as u64 + 1);
+
+      assert_eq!(applied, *snapshot);
+    }
+  }
+
+  /// Logged in the same shape as `Action::Increment`, without changing anything.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  enum Echo {
+    Increment(String, usize),
+  }
+
+  impl Command<'_> for Echo {
+    type SystemState = HashMap<String, usize>;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state
+    }
+  }
+
+  #[test]
+  fn test_projection_ignores_commands_of_other_types() {
+    let madeleine = make_test_madeleine(HashMap::new);
+
+    madeleine
+      .register_projection("total", 0, |total: &mut usize, action: &Action| {
+        if let Action::Increment(_, amount) = action {
+          *total += amount;
+        }
+      })
+      .expect("unable to register projection in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 2))
+      .expect("unable to execute increment action in test");
+    madeleine
+      .execute_command(Echo::Increment("panda".to_string(), 600))
+      .expect("unable to execute echo in test");
+
+    assert_eq!(madeleine.projection::<usize>("total").ok(), Some(2));
+  }
+
+  #[test]
+  fn test_projection_rebuild_rejects_commands_of_other_types() {
+    let madeleine = make_test_madeleine(|| 0_u64);
+
+    madeleine
+      .execute_command(crate::testing::Add(2))
+      .expect("unable to execute command in test");
+
+    let registered =
+      madeleine.register_projection("count", 0, |count: &mut usize, _action: &Action| {
+        *count += 1
+      });
+
+    assert!(matches!(
+      registered,
+      Err(MadeleineError::ProjectionError(message)) if message.contains("offset 0")
+    ));
+    assert!(madeleine.projection::<usize>("count").is_err());
+  }
+
+  #[test]
+  fn test_projection_registered_while_commands_execute() {
+    let madeleine = Arc::new(make_test_madeleine(|| 0_u64));
+
+    let writer = {
+      let madeleine = madeleine.clone();
+
+      std::thread::spawn(move || {
+        for _i in 0..200 {
+          madeleine
+            .execute_command(crate::testing::Add(1))
+            .expect("unable to execute command in test");
+        }
+      })
+    };
+
+    madeleine
+      .register_projection(
+        "count",
+        0_u64,
+        |count: &mut u64, _add: &crate::testing::Add| *count += 1,
+      )
+      .expect("unable to register projection in test");
+
+    writer.join().expect("unable to join thread in test");
+
+    assert_eq!(
+      madeleine.projection::<u64>("count").ok(),
+      Some(madeleine.len())
+    );
+  }
+
+  #[test]
+  fn test_projection_poisoned_by_failing_fold() {
+    let madeleine = make_test_madeleine(|| {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    });
+
+    madeleine
+      .register_projection("fragile", 0, |_count: &mut usize, action: &Action| {
+        if let Action::Decrement(_, _) = action {
+          panic!("decrements are not supported by this projection");
+        }
+      })
+      .expect("unable to register projection in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 2))
+      .expect("unable to execute increment action in test");
+
+    madeleine
+      .execute_command(Action::Decrement("panda".to_string(), 1))
+      .expect("unable to execute decrement action in test");
+
+    let actual = madeleine.projection::<usize>("fragile");
+
+    assert!(matches!(actual, Err(MadeleineError::ProjectionPoisoned(_))));
+    assert_eq!(madeleine.metrics().projections_poisoned(), 1);
+    assert_eq!(madeleine.len(), 2);
+
+    let state = madeleine.into_inner();
+
+    assert_eq!(state.get("panda"), Some(&1));
+  }
+
+  #[test]
+  fn test_rebuild_derived_on_healthy_store_is_noop() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .register_projection("count", 0, |count: &mut usize, _action: &Action| {
+        *count += 1
+      })
+      .expect("unable to register projection in test");
+
+    for _i in 0..3 {
+      madeleine
+        .execute_command(Action::Increment("panda".to_string(), 1))
+        .expect("unable to execute increment action in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let report = madeleine
+      .rebuild_derived()
+      .expect("unable to rebuild derived data in test");
+
+    assert!(report.is_noop());
+    assert_eq!(report.command_count, 3);
+  }
+
+  #[test]
+  fn test_rebuild_derived_after_manual_edits() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path, || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    let failing = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+    let fold_failing = failing.clone();
+
+    madeleine
+      .register_projection("count", 0, move |count: &mut usize, _action: &Action| {
+        if fold_failing.load(std::sync::atomic::Ordering::Relaxed) {
+          panic!("projection failure in test");
+        }
+
+        *count += 1;
+      })
+      .expect("unable to register projection in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 1))
+      .expect("unable to execute increment action in test");
+
+    for _i in 0..2 {
+      madeleine
+        .take_snapshot(true)
+        .expect("unable to take snapshot in test");
+    }
+
+    let store = temp_dir.child("test_store");
+
+    fs::remove_file(store.child("1.snapshot").path()).expect("unable to remove file in test");
+    store
+      .child("0.snapshot")
+      .write_str("{\"panda\":612}")
+      .expect("unable to write file in test");
+
+    failing.store(false, std::sync::atomic::Ordering::Relaxed);
+
+    let report = madeleine
+      .rebuild_derived()
+      .expect("unable to rebuild derived data in test");
+
+    assert_eq!(
+      report.changes,
+      vec![
+        RebuildChange::SnapshotIdReset {
+          recorded: Some(1),
+          actual: Some(0),
+        },
+        RebuildChange::SnapshotHashRefreshed {
+          snapshot_id: Some(0),
+        },
+        RebuildChange::ProjectionRebuilt {
+          name: "count".to_string(),
+          poisoned: false,
+        },
+      ]
+    );
+
+    let count: usize = madeleine
+      .projection("count")
+      .expect("unable to read projection in test");
+
+    assert_eq!(count, 1);
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(1));
+
+    let second = madeleine
+      .rebuild_derived()
+      .expect("unable to rebuild derived data in test");
+
+    assert!(second.is_noop());
+  }
+
+  #[test]
+  fn test_reconcile_without_difference() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || {
+      let state: HashMap<String, usize> = HashMap::from([("panda".to_string(), 613)]);
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    let desired = HashMap::from([("panda".to_string(), 613)]);
+
+    let actual = madeleine
+      .reconcile(&desired)
+      .expect("unable to reconcile in test");
+
+    assert_eq!(actual, None);
+    assert!(madeleine.is_empty());
+  }
+
+  #[test]
+  fn test_reconcile_logs_replayable_patch() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let initial = HashMap::from([("panda".to_string(), 613), ("koala".to_string(), 1)]);
+    let constructor_initial = initial.clone();
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || constructor_initial)
+      .expect("unable to instantiate madeleine in test");
+
+    let desired = HashMap::from([("panda".to_string(), 612), ("sloth".to_string(), 3)]);
+
+    let id = madeleine
+      .reconcile(&desired)
+      .expect("unable to reconcile in test")
+      .expect("expected a reconcile command to be logged in test");
+
+    assert_eq!(madeleine.tap(|state| state), desired);
+
+    let mut replayed = initial;
+    let mut replayed_ids = Vec::new();
+
+    madeleine
+      .command_log
+      .for_each_entry(|_offset, payload| {
+        let (logged_id, command): (Ulid, ReconcileCommand<HashMap<String, usize>>) =
+          serde_json::from_slice(payload)?;
+
+        replayed = command.execute(replayed.clone());
+        replayed_ids.push(logged_id);
+
+        Ok(())
+      })
+      .expect("unable to read command log in test");
+
+    assert_eq!(replayed_ids, vec![id]);
+    assert_eq!(replayed, desired);
+  }
+
+  #[test]
+  fn test_reconcile_rejects_oversized_difference() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), String::new)
+      .expect("unable to instantiate madeleine in test");
+
+    let desired = "panda".repeat(MAX_PAYLOAD_BYTES / 4);
+
+    let actual = madeleine.reconcile(&desired);
+
+    match actual {
+      Err(MadeleineError::ReconcileError(message)) => assert!(message.contains("snapshot")),
+      _ => panic!("expected a reconcile error in test"),
+    }
+
+    assert!(madeleine.is_empty());
+    assert_eq!(madeleine.into_inner(), String::new());
+  }
+
+  struct IncrementBeforeReconcile(std::sync::Weak<Madeleine<HashMap<String, usize>>>);
+
+  impl CommandMiddleware for IncrementBeforeReconcile {
+    fn before(&self, type_name: &str) {
+      if type_name.contains("ReconcileCommand") {
+        let madeleine = self
+          .0
+          .upgrade()
+          .expect("expected madeleine to still exist in test");
+
+        madeleine
+          .execute_command(Action::Increment(String::from("koala"), 1))
+          .expect("unable to execute command in test");
+      }
+    }
+
+    fn after(&self, _type_name: &str, _duration: Duration) {}
+  }
+
+  #[test]
+  fn test_reconcile_diffs_against_state_under_command_lock() {
+    let madeleine = Arc::new(make_test_madeleine(|| {
+      HashMap::from([("panda".to_string(), 613)])
+    }));
+    madeleine.add_middleware(Box::new(IncrementBeforeReconcile(Arc::downgrade(
+      &madeleine,
+    ))));
+
+    let desired = HashMap::from([("panda".to_string(), 612)]);
+
+    madeleine
+      .reconcile(&desired)
+      .expect("unable to reconcile in test")
+      .expect("expected a reconcile command to be logged in test");
+
+    assert_eq!(madeleine.tap(|state| state), desired);
+    assert_eq!(madeleine.len(), 2);
+  }
+
+  #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+  struct Sneaky {
+    visits: std::cell::Cell<u64>,
+  }
+
+  #[test]
+  fn test_strict_mode_detects_mutation_in_tap_ref() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), Sneaky::default)
+      .expect("unable to instantiate madeleine in test");
+
+    let sneak = |state: &Sneaky| state.visits.set(state.visits.get() + 1);
+
+    assert!(!madeleine.is_strict());
+    assert!(madeleine.tap_ref(sneak).is_ok());
+
+    madeleine.set_strict(true);
+
+    assert_eq!(madeleine.tap_ref(|state| state.visits.get()).ok(), Some(1));
+    assert!(matches!(
+      madeleine.tap_ref(sneak),
+      Err(MadeleineError::StateMutatedOutsideCommand(_))
+    ));
+    assert_eq!(madeleine.tap(|state| state.visits.get()), 2);
+  }
+
+  /// State whose clones share a counter, so that a closure passed a clone can still change it.
+  #[derive(Debug, Clone, Default)]
+  struct SharedVisits(Arc<Mutex<u64>>);
+
+  impl Serialize for SharedVisits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      lock_recovering(&self.0).serialize(serializer)
+    }
+  }
+
+  impl<'de> Deserialize<'de> for SharedVisits {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      Ok(Self(Arc::new(Mutex::new(u64::deserialize(deserializer)?))))
+    }
+  }
+
+  #[test]
+  fn test_strict_mode_detects_mutation_through_clone_in_try_tap() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), SharedVisits::default)
+      .expect("unable to instantiate madeleine in test");
+    madeleine.set_strict(true);
+
+    let visit = |state: SharedVisits| *lock_recovering(&state.0) += 1;
+
+    assert_eq!(
+      madeleine.try_tap(|state| *lock_recovering(&state.0)).ok(),
+      Some(0)
+    );
+    assert!(matches!(
+      madeleine.try_tap(visit),
+      Err(MadeleineError::StateMutatedOutsideCommand(_))
+    ));
+
+    madeleine.set_strict(false);
+
+    assert!(madeleine.try_tap(visit).is_ok());
+  }
+
+  #[test]
+  fn test_complex_resume() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || {
+      let state: HashMap<String, usize> = HashMap::new();
+
+      state
+    })
+    .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..613 {
+      let action = Action::Increment("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute increment action in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    for _i in 0..600 {
+      let action = Action::Decrement("panda".to_string(), 1);
+
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute decrement action in test");
+    }
+
+    let expected = madeleine.into_inner();
+
+    let new_madeleine: Madeleine<HashMap<String, usize>> =
+      Madeleine::resume_replaying::<Action, _>(store_path, HashMap::new)
+        .expect("unable to resume madeleine in test");
+
+    let actual = new_madeleine.into_inner();
+
+    assert_eq!(actual, expected);
+    assert_eq!(actual.get("panda"), Some(&13));
+  }
+
+  #[test]
+  fn test_resume_replaying_without_snapshot_replays_everything() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(Action::Increment("panda".to_string(), amount))
+        .expect("unable to execute increment action in test");
+    }
+
+    drop(madeleine);
+
+    let resumed = Madeleine::resume_replaying::<Action, _>(store_path, HashMap::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed.tap_ref(|state| state.get("panda").copied()).ok(),
+      Some(Some(3))
+    );
+  }
+
+  #[test]
+  fn test_resume_replaying_twice_neither_loses_nor_relogs_commands() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 5))
+      .expect("unable to execute increment action in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Action::Increment("panda".to_string(), 2))
+      .expect("unable to execute increment action in test");
+
+    drop(madeleine);
+
+    let resumed = Madeleine::resume_replaying::<Action, _>(store_path.clone(), HashMap::new)
+      .expect("unable to resume madeleine in test");
+
+    resumed
+      .execute_command(Action::Decrement("panda".to_string(), 1))
+      .expect("unable to execute decrement action in test");
+
+    drop(resumed);
+
+    let resumed_again = Madeleine::resume_replaying::<Action, _>(store_path.clone(), HashMap::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed_again
+        .tap_ref(|state| state.get("panda").copied())
+        .ok(),
+      Some(Some(6))
+    );
+
+    drop(resumed_again);
+
+    assert_eq!(
+      Follower::open(store_path)
+        .expect("unable to open follower in test")
+        .commands_after(Ulid::nil())
+        .expect("unable to read commands in test")
+        .len(),
+      3
+    );
+  }
+
+  #[test]
+  fn test_new_or_resume_creates_then_resumes() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new_or_resume::<Action, _>(store_path.clone(), HashMap::new)
+      .expect("unable to create madeleine in test");
+
+    for i in 1..1024 {
+      madeleine
+        .execute_command(Action::Increment("panda".to_string(), i))
+        .expect("unable to execute increment action in test");
+    }
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    for i in 1..100 {
+      madeleine
+        .execute_command(Action::Decrement("panda".to_string(), i))
+        .expect("unable to execute decrement action in test");
+    }
+
+    let expected = madeleine.into_inner();
+
+    let resumed = Madeleine::new_or_resume::<Action, _>(store_path, || {
+      panic!("constructor called when resuming in test")
+    })
+    .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.into_inner(), expected);
+    assert_eq!(expected.get("panda"), Some(&(523_776 - 4_950)));
+  }
+
+  #[test]
+  fn test_new_or_resume_rejects_incomplete_store() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    drop(madeleine);
+
+    fs::remove_dir_all(command_log_dir_path(&store_path))
+      .expect("unable to remove command log in test");
+
+    assert!(matches!(
+      Madeleine::new_or_resume::<Action, _>(store_path.clone(), HashMap::new),
+      Err(MadeleineError::IncompleteStore(message)) if message.contains(SNAPSHOT_FILE_SUFFIX)
+    ));
+    assert!(!command_log_dir_path(&store_path).exists());
+  }
+
+  /// Inserts a name, returning the key it was stored under.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Insert(String);
+
+  impl CommandWithOutput<'_> for Insert {
+    type SystemState = Vec<String>;
+    type Output = usize;
+
+    fn execute_with_output(&self, old_state: Self::SystemState) -> (Self::SystemState, usize) {
+      let mut new_state = old_state;
+      new_state.push(self.0.clone());
+
+      let key = new_state.len() - 1;
+
+      (new_state, key)
+    }
+
+    fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+      if state.contains(&self.0) {
+        Err(format!("{} is already stored", self.0))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  #[test]
+  fn test_execute_command_with_output_logs_and_returns_output() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), Vec::new)
+      .expect("unable to instantiate madeleine in test");
+
+    let keys: Vec<usize> = ["panda", "koala"]
+      .into_iter()
+      .map(|name| {
+        madeleine
+          .execute_command_with_output(Insert(name.to_string()))
+          .expect("unable to execute command in test")
+      })
+      .collect();
+
+    assert_eq!(keys, vec![0, 1]);
+    assert!(matches!(
+      madeleine.execute_command_with_output(Insert("panda".to_string())),
+      Err(MadeleineError::CommandRejected(_))
+    ));
+
+    drop(madeleine);
+
+    let resumed = Madeleine::resume_replaying::<Insert, _>(store_path, Vec::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed.into_inner(),
+      vec!["panda".to_string(), "koala".to_string()]
+    );
+  }
+
+  #[test]
+  fn test_failed_append_leaves_state_and_log_unchanged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), Vec::new)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Insert("panda".to_string()))
+      .expect("unable to execute command in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.on_every(StorageOperation::Append, FailpointAction::Fail);
+
+    madeleine
+      .set_failpoints(Some(failpoints))
+      .expect("unable to set failpoints in test");
+
+    let expected = vec!["panda".to_string()];
+
+    assert!(matches!(
+      madeleine.execute_command(Insert("koala".to_string())),
+      Err(MadeleineError::FileIOError(_))
+    ));
+    assert!(madeleine
+      .execute_command_with_output(Insert("koala".to_string()))
+      .is_err());
+    assert!(madeleine
+      .execute_batch(vec![Insert("koala".to_string())], BatchMode::Atomic)
+      .is_err());
+    assert!(madeleine
+      .execute_commands(["koala", "kiwi"].map(|name| Insert(name.to_string())))
+      .is_err());
+    assert_eq!(madeleine.tap(|state| state), expected);
+    assert_eq!(madeleine.len(), 1);
+
+    drop(madeleine);
+
+    let resumed = Madeleine::resume_replaying::<Insert, _>(store_path, Vec::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.into_inner(), expected);
+  }
+
+  /// Issues an invoice, numbered by its position in history.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Invoice(String);
+
+  impl Command<'_> for Invoice {
+    type SystemState = Vec<(u64, String)>;
+
+    fn execute(&self, _old_state: Self::SystemState) -> Self::SystemState {
+      unreachable!("invoices are always executed with a context in test");
+    }
+
+    fn execute_with_ctx(
+      &self,
+      old_state: Self::SystemState,
+      ctx: &CommandContext,
+    ) -> Self::SystemState {
+      let mut new_state = old_state;
+      new_state.push((ctx.position(), self.0.clone()));
+
+      new_state
+    }
+  }
+
+  #[test]
+  fn test_command_positions_are_the_same_live_and_on_replay() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), Vec::new)
+      .expect("unable to instantiate madeleine in test");
+
+    let invoice = |customer: &str| Invoice(customer.to_string());
+
+    for customer in ["panda", "koala"] {
+      madeleine
+        .execute_command(invoice(customer))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine.compact().expect("unable to compact in test");
+
+    madeleine
+      .execute_batch(vec![invoice("otter"), invoice("lemur")], BatchMode::Atomic)
+      .expect("unable to execute batch in test");
+    madeleine
+      .execute_command(invoice("panda"))
+      .expect("unable to execute command in test");
+
+    let live = madeleine.tap(|state| state);
+
+    assert_eq!(
+      live
+        .iter()
+        .map(|(number, _customer)| *number)
+        .collect::<Vec<u64>>(),
+      vec![0, 1, 2, 3, 4]
+    );
+
+    drop(madeleine);
+
+    let resumed = Madeleine::resume_replaying::<Invoice, _>(store_path.clone(), Vec::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.into_inner(), live);
+
+    // Replicas only replay the commands since the compaction, but number them the same.
+    let replica =
+      crate::ReadOnlyMadeleine::<Invoice, Vec<(u64, String)>>::open(store_path, Vec::new)
+        .expect("unable to open replica in test");
+
+    assert_eq!(replica.into_inner(), live[2..].to_vec());
+  }
+
+  /// Times any `Tally` was cloned.
+  static TALLY_CLONES: AtomicU64 = AtomicU64::new(0);
+
+  /// Numbers recorded in order, counting how often they're cloned.
+  #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+  struct Tally(Vec<u64>);
+
+  impl Clone for Tally {
+    fn clone(&self) -> Self {
+      TALLY_CLONES.fetch_add(1, Ordering::SeqCst);
+
+      Self(self.0.clone())
+    }
+  }
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Record(u64);
+
+  impl Command<'_> for Record {
+    type SystemState = Tally;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      let mut new_state = old_state;
+      self.execute_mut(&mut new_state);
+
+      new_state
+    }
+  }
+
+  impl MutCommand<'_> for Record {
+    fn execute_mut(&self, state: &mut Self::SystemState) {
+      state.0.push(self.0);
+    }
   }
 
   #[test]
-  fn test_new_creates_command_log() {
+  fn test_execute_command_mut_changes_state_in_place() {
     let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
 
-    let log_path = temp_dir.path().join("test_log");
+    let store_path = temp_dir.path().join("test_store");
 
-    let state = 0;
+    let madeleine = Madeleine::new(store_path.clone(), Tally::default)
+      .expect("unable to instantiate madeleine in test");
 
-    temp_dir
-      .child("test_log")
-      .assert(predicate::path::missing());
+    for number in 1..=3 {
+      madeleine
+        .execute_command_mut(Record(number))
+        .expect("unable to execute command in test");
+    }
 
-    Madeleine::new(log_path, &|| state).expect("unable to instantiate madeleine in test");
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.fail_nth(StorageOperation::Append, 1);
 
-    temp_dir.child("test_log").assert(predicate::path::exists());
-  }
+    madeleine
+      .set_failpoints(Some(failpoints))
+      .expect("unable to set failpoints in test");
 
-  #[test]
-  fn test_into_inner() {
-    let state = 42;
-    let madeleine = make_test_madeleine(|| state);
+    // Commands are logged before they change the state, so a failed append leaves it untouched.
+    assert!(madeleine.execute_command_mut(Record(4)).is_err());
+    assert_eq!(
+      madeleine.tap_ref(|state| state.0.clone()).ok(),
+      Some(vec![1, 2, 3])
+    );
+    assert_eq!(TALLY_CLONES.load(Ordering::SeqCst), 0);
 
-    assert_eq!(state, madeleine.into_inner());
-  }
+    drop(madeleine);
 
-  #[test]
-  fn test_new_sets_constructor_result_as_state() {
-    let madeleine = make_test_madeleine(|| 41 + 1);
+    let resumed = Madeleine::resume_replaying::<Record, _>(store_path, Tally::default)
+      .expect("unable to resume madeleine in test");
 
-    assert_eq!(42, madeleine.into_inner());
+    // Replaying clones the state once, rather than once per command.
+    assert_eq!(resumed.into_inner(), Tally(vec![1, 2, 3]));
+    assert_eq!(TALLY_CLONES.load(Ordering::SeqCst), 1);
   }
 
   #[test]
-  fn test_execute_command_once() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
-
-      state
-    });
-
-    let action = Action::Increment("panda".to_string(), 613);
-
-    madeleine
-      .execute_command(action)
-      .expect("unable to execute increment action in test");
+  fn test_resume_replaying_rejects_snapshot_without_head() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
 
-    let state = madeleine.into_inner();
+    let store_path = temp_dir.path().join("test_store");
 
-    let actual = state.get("panda");
+    let madeleine = Madeleine::new(store_path.clone(), HashMap::<String, usize>::new)
+      .expect("unable to instantiate madeleine in test");
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
 
-    let val = 613 as usize;
+    drop(madeleine);
 
-    let expected = Some(&val);
+    // Snapshots taken by earlier releases have no head file.
+    fs::remove_file(snapshot_head_file_path(snapshot_id, store_path.clone()))
+      .expect("unable to remove head file in test");
 
-    assert_eq!(expected, actual);
+    assert!(matches!(
+      Madeleine::resume_replaying::<Action, _>(store_path, HashMap::new),
+      Err(MadeleineError::SnapshotError(message)) if message.contains("doesn't record its last applied command")
+    ));
   }
 
   #[test]
-  fn test_execute_command_many() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
+  fn test_replay_from_reconstructs_past_states_without_changing_the_live_one() {
+    let madeleine = make_test_madeleine(HashMap::<String, usize>::new);
 
-      state
-    });
+    madeleine
+      .execute_command(Action::Increment(String::from("panda"), 2))
+      .expect("unable to execute command in test");
+    let after_first = madeleine.head_id().expect("unable to get head in test");
+    madeleine
+      .execute_command(Action::Increment(String::from("koala"), 3))
+      .expect("unable to execute command in test");
+    let after_second = madeleine.head_id().expect("unable to get head in test");
+    madeleine
+      .execute_command(Action::Decrement(String::from("panda"), 1))
+      .expect("unable to execute command in test");
 
-    for _i in 0..613 {
-      let action = Action::Increment("panda".to_string(), 1);
+    let live = madeleine.tap(|state| state);
 
+    let replay = |ulid: Ulid| {
       madeleine
-        .execute_command(action)
-        .expect("unable to execute increment action in test");
-    }
-
-    let state = madeleine.into_inner();
+        .replay_from::<Action, _>(&ulid.to_string(), HashMap::new)
+        .expect("unable to replay in test")
+    };
+
+    assert_eq!(replay(Ulid::nil()), HashMap::new());
+    assert_eq!(
+      replay(after_first),
+      HashMap::from([(String::from("panda"), 2)])
+    );
+    assert_eq!(
+      replay(after_second),
+      HashMap::from([(String::from("panda"), 2), (String::from("koala"), 3)])
+    );
+    assert_eq!(
+      replay(madeleine.head_id().expect("unable to get head in test")),
+      live
+    );
+    assert_eq!(madeleine.tap(|state| state), live);
+    assert_eq!(madeleine.len(), 3);
+
+    assert!(matches!(
+      madeleine.replay_from::<Action, _>("panda", HashMap::new),
+      Err(MadeleineError::ReplayError(_))
+    ));
+  }
 
-    let actual = state.get("panda");
+  #[test]
+  fn test_replay_from_rejects_compacted_log() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-    let val = 613 as usize;
+    madeleine
+      .execute_command(Adjust(2))
+      .expect("unable to execute command in test");
+    madeleine.compact().expect("unable to compact in test");
+    madeleine
+      .execute_command(Adjust(3))
+      .expect("unable to execute command in test");
 
-    let expected = Some(&val);
+    let head_id = madeleine.head_id().expect("unable to get head in test");
 
-    assert_eq!(expected, actual);
+    assert!(matches!(
+      madeleine.replay_from::<Adjust, _>(&head_id.to_string(), || 0_u64),
+      Err(MadeleineError::ReplayError(message)) if message.contains("compacted")
+    ));
   }
 
   #[test]
-  fn test_tap() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
+  fn test_state_at_reconstructs_states_at_cutoffs_during_a_run() {
+    let madeleine = make_test_madeleine(HashMap::<String, usize>::new);
+    let panda = || Action::Increment(String::from("panda"), 1);
+    let mut expected = Vec::new();
 
-      state
-    });
+    for count in 1..=613 {
+      madeleine
+        .execute_command(panda())
+        .expect("unable to execute command in test");
+
+      if count == 250 {
+        madeleine
+          .take_snapshot(false)
+          .expect("unable to take snapshot in test");
+      }
+
+      if [100, 250, 400].contains(&count) {
+        expected.push((
+          madeleine.head_id().expect("unable to get head in test"),
+          madeleine.tap(|state| state),
+        ));
+      }
+    }
 
-    let internal_start = madeleine.tap(|state| state.get("panda").map(|v| v.to_owned()));
+    let state_at = |cutoff: Ulid| {
+      madeleine
+        .state_at::<Action, _>(cutoff, HashMap::new)
+        .expect("unable to reconstruct state in test")
+    };
 
-    assert_eq!(internal_start, None);
+    for (cutoff, state) in expected {
+      assert_eq!(state_at(cutoff), state);
+    }
 
-    for _i in 0..613 {
-      let action = Action::Increment("panda".to_string(), 1);
+    assert_eq!(state_at(Ulid::nil()), HashMap::new());
+    assert_eq!(
+      state_at(Ulid::from(u128::MAX)),
+      madeleine.tap(|state| state)
+    );
+    assert_eq!(
+      madeleine.tap(|state| state),
+      HashMap::from([(String::from("panda"), 613)])
+    );
+  }
+
+  #[test]
+  fn test_state_at_starts_from_snapshots_of_compacted_logs() {
+    let madeleine = make_test_madeleine(|| 0_u64);
+
+    madeleine
+      .execute_command(Adjust(2))
+      .expect("unable to execute command in test");
+    let before_compaction = madeleine.head_id().expect("unable to get head in test");
+    madeleine
+      .execute_command(Adjust(3))
+      .expect("unable to execute command in test");
+    madeleine.compact().expect("unable to compact in test");
+    madeleine
+      .execute_command(Adjust(4))
+      .expect("unable to execute command in test");
 
+    assert_eq!(
       madeleine
-        .execute_command(action)
-        .expect("unable to execute increment action in test");
-    }
+        .state_at::<Adjust, _>(Ulid::from(u128::MAX), || 0)
+        .expect("unable to reconstruct state in test"),
+      9
+    );
+    assert!(matches!(
+      madeleine.state_at::<Adjust, _>(before_compaction, || 0),
+      Err(MadeleineError::ReplayError(message)) if message.contains("compacted")
+    ));
+  }
 
-    let internal_mid = madeleine.tap(|state| state.get("panda").unwrap_or(&0).to_owned());
+  #[test]
+  fn test_state_at_doesnt_start_from_snapshots_older_than_compacted_commands() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-    assert_eq!(internal_mid, 613);
+    madeleine
+      .execute_command(Adjust(2))
+      .expect("unable to execute command in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Adjust(3))
+      .expect("unable to execute command in test");
+    let after_second = madeleine.head_id().expect("unable to get head in test");
+    madeleine
+      .execute_command(Adjust(4))
+      .expect("unable to execute command in test");
+    madeleine.compact().expect("unable to compact in test");
+
+    // The snapshot is older than the compacted commands, so replaying the log from it would miss them.
+    assert!(matches!(
+      madeleine.state_at::<Adjust, _>(after_second, || 0),
+      Err(MadeleineError::ReplayError(_))
+    ));
+    assert_eq!(
+      madeleine
+        .state_at::<Adjust, _>(Ulid::from(u128::MAX), || 0)
+        .expect("unable to reconstruct state in test"),
+      9
+    );
+  }
 
-    for _i in 0..613 {
-      let action = Action::Decrement("panda".to_string(), 1);
+  #[test]
+  fn test_deterministic_commands_verify_clean() {
+    let madeleine = make_test_madeleine(HashMap::<String, usize>::new);
 
+    for amount in 1..=5 {
       madeleine
-        .execute_command(action)
-        .expect("unable to execute decrement action in test");
+        .execute_command(Action::Increment(String::from("panda"), amount))
+        .expect("unable to execute command in test");
     }
 
-    let state = madeleine.into_inner();
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Action::Decrement(String::from("panda"), 3))
+      .expect("unable to execute command in test");
 
-    let actual = state.get("panda");
+    assert_eq!(
+      madeleine
+        .verify_determinism::<Action, _>(HashMap::new)
+        .expect("unable to verify determinism in test"),
+      None
+    );
 
-    let val = 0;
+    madeleine.compact().expect("unable to compact in test");
+    madeleine
+      .execute_command(Action::Increment(String::from("koala"), 2))
+      .expect("unable to execute command in test");
 
-    let expected = Some(&val);
+    assert_eq!(
+      madeleine
+        .verify_determinism::<Action, _>(HashMap::new)
+        .expect("unable to verify determinism in test"),
+      None
+    );
+  }
 
-    assert_eq!(expected, actual);
+  #[test]
+  fn test_iter_history_yields_commands_in_order() {
+    let madeleine = make_test_madeleine(HashMap::<String, usize>::new);
+
+    let actions = vec![
+      Action::Increment(String::from("panda"), 2),
+      Action::Decrement(String::from("panda"), 1),
+      Action::Increment(String::from("koala"), 3),
+      Action::Decrement(String::from("koala"), 2),
+      Action::Increment(String::from("panda"), 5),
+    ];
+
+    for action in &actions {
+      madeleine
+        .execute_command(action.clone())
+        .expect("unable to execute command in test");
+    }
+
+    let history: Vec<HistoryEntry<Action>> = madeleine
+      .iter_history()
+      .expect("unable to iterate over history in test")
+      .collect::<Result<_, _>>()
+      .expect("unable to read history in test");
+
+    assert_eq!(
+      history
+        .iter()
+        .map(|entry| entry.command.clone())
+        .collect::<Vec<_>>(),
+      actions
+    );
+    assert_eq!(
+      history.iter().map(|entry| entry.offset).collect::<Vec<_>>(),
+      (0..5).collect::<Vec<_>>()
+    );
+    assert!(history.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert_eq!(
+      history.last().map(|entry| entry.id),
+      Some(madeleine.head_id().expect("unable to get head in test"))
+    );
   }
 
   #[test]
-  fn test_len_with_empty() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
+  fn test_iter_history_carries_on_past_commands_it_cant_deserialize() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-      state
-    });
+    for amount in [5, -3, 2] {
+      madeleine
+        .execute_command(Adjust(amount))
+        .expect("unable to execute command in test");
+    }
 
-    let actual = madeleine.len();
+    let history: Vec<Result<HistoryEntry<Record>, MadeleineError>> = madeleine
+      .iter_history()
+      .expect("unable to iterate over history in test")
+      .collect();
 
-    assert_eq!(actual, 0);
+    assert_eq!(history.len(), 3);
+    assert!(matches!(&history[0], Ok(entry) if entry.command.0 == 5));
+    assert!(history[1].is_err());
+    assert!(matches!(&history[2], Ok(entry) if entry.command.0 == 2 && entry.offset == 2));
   }
 
   #[test]
-  fn test_len_with_some_commands() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
-
-      state
-    });
+  fn test_history_page_of_empty_log_is_empty() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-    let length_at_start = madeleine.len();
+    let page = madeleine
+      .history_page::<Adjust>(None, 50)
+      .expect("unable to read history page in test");
 
-    assert_eq!(length_at_start, 0);
+    assert!(page.entries.is_empty());
+    assert_eq!(page.next, None);
+  }
 
-    for _i in 0..613 {
-      let action = Action::Increment("panda".to_string(), 1);
+  #[test]
+  fn test_history_pages_follow_log_order_to_a_partial_final_page() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-      madeleine
-        .execute_command(action)
-        .expect("unable to execute increment action in test");
+    // Logged as one batch, so most of them share a millisecond.
+    madeleine
+      .execute_commands((1..=7).map(Adjust))
+      .expect("unable to execute commands in test");
+
+    let mut pages = Vec::new();
+    let mut after = None;
+
+    loop {
+      let page = madeleine
+        .history_page::<Adjust>(after, 3)
+        .expect("unable to read history page in test");
+
+      after = page.next;
+      pages.push(
+        page
+          .entries
+          .iter()
+          .map(|entry| (entry.offset, entry.command.0))
+          .collect::<Vec<_>>(),
+      );
+
+      if after.is_none() {
+        break;
+      }
     }
 
-    let actual = madeleine.len();
-
-    assert_eq!(actual, 613);
+    assert_eq!(
+      pages,
+      vec![
+        vec![(0, 1), (1, 2), (2, 3)],
+        vec![(3, 4), (4, 5), (5, 6)],
+        vec![(6, 7)],
+      ]
+    );
   }
 
   #[test]
-  fn test_next_snapshot_id_first() {
-    let madeleine = make_test_madeleine(|| {
-      let state: HashMap<String, usize> = HashMap::new();
+  fn test_history_page_after_the_end_is_empty() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
-      state
-    });
+    madeleine
+      .execute_commands((1..=3).map(Adjust))
+      .expect("unable to execute commands in test");
 
-    let actual = madeleine
-      .next_snapshot_id()
-      .expect("unable to determine next snapshot id in test");
+    let full = madeleine
+      .history_page::<Adjust>(None, 3)
+      .expect("unable to read history page in test");
 
-    assert_eq!(actual, 0);
+    assert_eq!(
+      full.next,
+      Some(madeleine.head_id().expect("unable to get head in test"))
+    );
+
+    for after in [full.next, Some(Ulid::from(u128::MAX))] {
+      let page = madeleine
+        .history_page::<Adjust>(after, 3)
+        .expect("unable to read history page in test");
+
+      assert!(page.entries.is_empty());
+      assert_eq!(page.next, None);
+    }
   }
 
   #[test]
-  fn test_next_snapshot_id_subsequent() {
+  fn test_rollback_to_snapshot_reverts_state_and_log() {
     let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let store_path = temp_dir.path().join("test_store");
+    let panda = |amount| Action::Increment(String::from("panda"), amount);
 
-    let log_path = temp_dir.path().join("test_store");
-
-    let state = 0;
+    {
+      let madeleine =
+        Madeleine::new(&store_path, HashMap::new).expect("unable to instantiate madeleine in test");
+      let empty = madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
 
-    let madeleine =
-      Madeleine::new(log_path, &|| state).expect("unable to instantiate madeleine in test");
+      madeleine
+        .execute_commands((1..=2).map(panda))
+        .expect("unable to execute commands in test");
+      let after_two = madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+      let len_after_two = madeleine.len();
 
-    temp_dir
-      .child("test_store")
-      .child(SNAPSHOT_FILE_SUFFIX)
-      .assert(predicate::path::missing());
+      madeleine
+        .execute_commands((3..=4).map(panda))
+        .expect("unable to execute commands in test");
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to take snapshot in test");
+
+      assert_eq!(
+        madeleine
+          .rollback_to_snapshot(after_two)
+          .expect("unable to roll back in test"),
+        2
+      );
+      assert_eq!(madeleine.len(), len_after_two);
+      assert_eq!(
+        madeleine.tap(|state| state),
+        HashMap::from([(String::from("panda"), 3)])
+      );
+      assert_eq!(
+        madeleine
+          .next_snapshot_id()
+          .expect("unable to get snapshot id in test"),
+        after_two + 1
+      );
 
-    let actual_fresh = madeleine
-      .next_snapshot_id()
-      .expect("unable to determine next snapshot id in test");
+      madeleine
+        .execute_command(panda(10))
+        .expect("unable to execute command in test");
 
-    assert_eq!(actual_fresh, 0);
+      assert_eq!(
+        madeleine
+          .rollback_to_snapshot(empty)
+          .expect("unable to roll back in test"),
+        3
+      );
+      assert!(madeleine.is_empty());
 
-    madeleine
-      .take_snapshot()
-      .expect("unable to take snapshot in test");
+      madeleine
+        .execute_command(panda(5))
+        .expect("unable to execute command in test");
+    }
 
-    temp_dir
-      .child("test_store")
-      .child(SNAPSHOT_FILE_SUFFIX)
-      .assert(predicate::path::exists());
+    let resumed = Madeleine::resume_replaying::<Action, _>(&store_path, HashMap::new)
+      .expect("unable to resume madeleine in test");
 
-    let actual_after_one = madeleine
-      .next_snapshot_id()
-      .expect("unable to determine next snapshot id in test");
+    assert_eq!(resumed.len(), 1);
+    assert_eq!(
+      resumed.tap(|state| state),
+      HashMap::from([(String::from("panda"), 5)])
+    );
+  }
 
-    assert_eq!(actual_after_one, 1);
+  #[test]
+  fn test_rollback_to_missing_snapshot_fails() {
+    let madeleine = make_test_madeleine(|| 0_u64);
 
     madeleine
-      .take_snapshot()
-      .expect("unable to take snapshot in test");
-
-    let actual_after_two = madeleine
-      .next_snapshot_id()
-      .expect("unable to determine next snapshot id in test");
-
-    assert_eq!(actual_after_two, 2);
+      .execute_command(Adjust(2))
+      .expect("unable to execute command in test");
+
+    assert!(matches!(
+      madeleine.rollback_to_snapshot(7),
+      Err(MadeleineError::SnapshotError(message)) if message.contains("doesn't exist")
+    ));
+    assert_eq!(madeleine.len(), 1);
+    assert_eq!(madeleine.tap(|state| state), 2);
   }
 
   #[test]
-  fn test_basic_resume() {
+  fn test_load_snapshot_named_reads_latest_snapshot_with_label() {
     let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
-
     let store_path = temp_dir.path().join("test_store");
+    let madeleine =
+      Madeleine::new(&store_path, || 0_u64).expect("unable to instantiate madeleine in test");
 
-    let madeleine = Madeleine::new(store_path.clone(), || {
-      let state: HashMap<String, usize> = HashMap::new();
-
-      state
-    })
-    .expect("unable to instantiate madeleine in test");
-
-    for _i in 0..613 {
-      let action = Action::Increment("panda".to_string(), 1);
+    madeleine
+      .execute_command(Adjust(2))
+      .expect("unable to execute command in test");
+    let first = madeleine
+      .take_snapshot_named("nightly")
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Adjust(3))
+      .expect("unable to execute command in test");
+    madeleine
+      .take_snapshot_named("nightly")
+      .expect("unable to take snapshot in test");
+    madeleine
+      .execute_command(Adjust(4))
+      .expect("unable to execute command in test");
 
+    assert_eq!(
       madeleine
-        .execute_command(action)
-        .expect("unable to execute increment action in test");
-    }
+        .load_snapshot_named("nightly")
+        .expect("unable to load snapshot in test"),
+      5
+    );
+    assert_eq!(madeleine.tap(|state| state), 9);
+    assert!(matches!(
+      madeleine.load_snapshot_named("weekly"),
+      Err(MadeleineError::SnapshotError(_))
+    ));
+
+    temp_dir
+      .child("test_store")
+      .child(format!("{}.label", first + 1))
+      .assert(predicate::path::is_file());
 
     madeleine
-      .take_snapshot()
-      .expect("unable to take snapshot in test");
+      .rollback_to_snapshot(first)
+      .expect("unable to roll back in test");
 
-    let expected = madeleine.into_inner();
+    temp_dir
+      .child("test_store")
+      .child(format!("{}.label", first + 1))
+      .assert(predicate::path::missing());
+    assert_eq!(
+      madeleine
+        .load_snapshot_named("nightly")
+        .expect("unable to load snapshot in test"),
+      2
+    );
+
+    let snapshots = madeleine
+      .list_snapshots()
+      .expect("unable to list snapshots in test");
+
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].snapshot_id, first);
+    assert_eq!(snapshots[0].label.as_deref(), Some("nightly"));
+    assert!(snapshots[0].ulid.is_some());
+    assert!(snapshots[0].bytes > 0);
+  }
 
-    let new_madeleine: Madeleine<HashMap<String, usize>> =
-      Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+  /// Changes a balance, which mustn't go below zero.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Adjust(i64);
 
-    let actual = new_madeleine.into_inner();
+  #[derive(Debug)]
+  struct Overdrawn(u64);
 
-    assert_eq!(actual, expected);
+  impl std::fmt::Display for Overdrawn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+      write!(f, "balance of {} is too low", self.0)
+    }
   }
 
-  // #[test]
-  // fn test_complex_resume() {
-  //   let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+  impl TryCommand<'_> for Adjust {
+    type SystemState = u64;
+    type Error = Overdrawn;
 
-  //   let store_path = temp_dir.path().join("test_store");
+    fn try_execute(&self, state: &Self::SystemState) -> Result<Self::SystemState, Self::Error> {
+      state.checked_add_signed(self.0).ok_or(Overdrawn(*state))
+    }
+  }
 
-  //   let madeleine = Madeleine::new(store_path.clone(), || {
-  //     let state: HashMap<String, usize> = HashMap::new();
+  #[test]
+  fn test_rejected_transition_is_neither_applied_nor_logged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
 
-  //     state
-  //   })
-  //   .expect("unable to instantiate madeleine in test");
+    let store_path = temp_dir.path().join("test_store");
 
-  //   for _i in 0..613 {
-  //     let action = Action::Increment("panda".to_string(), 1);
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
 
-  //     madeleine
-  //       .execute_command(action)
-  //       .expect("unable to execute increment action in test");
-  //   }
+    madeleine
+      .execute_command(Adjust(10))
+      .expect("unable to execute command in test");
 
-  //   madeleine
-  //     .take_snapshot()
-  //     .expect("unable to take snapshot in test");
+    let rejected = madeleine.execute_command(Adjust(-20));
 
-  //   for _i in 0..613 {
-  //     let action = Action::Decrement("panda".to_string(), 1);
+    assert!(matches!(
+      rejected,
+      Err(MadeleineError::CommandRejected(ref message)) if message == "balance of 10 is too low"
+    ));
+    assert_eq!(madeleine.len(), 1);
+    assert_eq!(madeleine.tap(|state| state), 10);
 
-  //     madeleine
-  //       .execute_command(action)
-  //       .expect("unable to execute decrement action in test");
-  //   }
+    let batch = madeleine.execute_batch(vec![Adjust(-5), Adjust(-10)], BatchMode::Atomic);
 
-  //   let expected = madeleine.into_inner();
+    assert!(matches!(
+      batch,
+      Err(MadeleineError::BatchFailed { ref failure, .. }) if failure.index == 1
+    ));
+    assert_eq!(madeleine.len(), 1);
 
-  //   let new_madeleine: Madeleine<HashMap<String, usize>> =
-  //     Madeleine::resume(store_path).expect("unable to resume madeleine in test");
+    drop(madeleine);
 
-  //   let actual = new_madeleine.into_inner();
+    let resumed = Madeleine::resume_replaying::<Adjust, _>(store_path, || 0_u64)
+      .expect("unable to resume madeleine in test");
 
-  //   assert_eq!(actual, expected);
-  // }
+    assert_eq!(resumed.into_inner(), 10);
+  }
 }
@@ -2,17 +2,26 @@ use thiserror::Error;
 
 use std::io;
 
+use crate::batch::BatchFailure;
+
 /// Custom error type for Madeleine.
 #[derive(Error, Debug)]
 pub enum MadeleineError {
   /// Error related to File I/O and disk operations.
   #[error("File I/O error")]
   FileIOError(#[from] io::Error),
+  /// The store directory's contents don't satisfy the directory policy.
+  #[error("Directory error: {0}")]
+  DirectoryError(String),
+  /// The store directory holds some of a store's files but no command log, so it can't be resumed,
+  /// and creating a store there would shadow the old data.
+  #[error("Incomplete store: {0}")]
+  IncompleteStore(String),
   /// Errors relating to snapshot files.
   #[error("Snapshot error: {0}")]
   SnapshotError(String),
   /// Any sort of serialization error (currently only JSON).
-  #[error("Serialization error")]
+  #[error("Serialization error: {0}")]
   SerializationError(#[from] serde_json::Error),
   // #[error("Deserialization error")]
   // DeserializationError(#[from] serde_json::de::Error),
@@ -22,10 +31,183 @@ pub enum MadeleineError {
   /// Errors relating to reading from the command log's commit log.
   #[error("Commit Log Read error")]
   CommitLogReadError(#[from] commitlog::ReadError),
+  /// A record in a `LogBackend::File` command log fails its CRC somewhere a crash can't explain.
+  #[error("Command log corrupted: {0}")]
+  CommandLogCorrupted(String),
   /// Internal error related to mutable borrowing.
   #[error("Mutable Borrow error")]
   BorrowMutError(#[from] std::cell::BorrowMutError),
   /// Internal error related to borrowing.
   #[error("Borrow error")]
   BorrowError(#[from] std::cell::BorrowError),
+  /// The store was last written by a newer build, or one using a capability this build lacks,
+  /// so this build refuses to write to it.
+  #[error("Incompatible writer: store requires {store_requires}, but this build is {this_build}")]
+  IncompatibleWriter {
+    /// The most recent writer of the store.
+    store_requires: String,
+    /// This build.
+    this_build: String,
+  },
+  /// A store was opened with a different hash function than the one it was created with.
+  #[error("Hash algorithm mismatch: {0}")]
+  HashAlgoMismatch(String),
+  /// A store was opened with a different payload format than the one it was created with,
+  /// or a feature which needs JSON payloads met bincode or MessagePack ones, see `PayloadFormat`.
+  #[error("Payload format mismatch: {0}")]
+  PayloadFormatMismatch(String),
+  /// A store was opened with a different log backend than the one it was created with, see `LogBackend`.
+  #[error("Log backend mismatch: {0}")]
+  LogBackendMismatch(String),
+  /// A store created by `Madeleine::new_in_memory` was opened again, rather than used through the instance owning it.
+  #[error("In-memory store: {0}")]
+  InMemoryStore(String),
+  /// A store was opened as a state type it was migrated away from, see `Madeleine::migrate_state`.
+  #[error("State migrated: {0}")]
+  StateMigrated(String),
+  /// A command can't be serialized or deserialized with bincode.
+  #[error("Bincode error: {0}")]
+  BincodeError(String),
+  /// A command can't be serialized or deserialized with MessagePack.
+  #[error("MessagePack error: {0}")]
+  MessagePackError(String),
+  /// The sled database holding a command log failed, see `LogBackend::Sled`.
+  #[error("Sled error: {0}")]
+  SledError(String),
+  /// A SQLite query being imported from failed, see `import::from_query`.
+  #[error("SQLite error: {0}")]
+  SqliteError(String),
+  /// A command failed its own validation, so it was neither executed nor logged.
+  #[error("Command rejected: {0}")]
+  CommandRejected(String),
+  /// A command allocated more than an `AllocationCheck` allows while executing, so it was neither applied nor logged.
+  #[error("Command resource limit: allocated {bytes_allocated} bytes")]
+  CommandResourceLimit {
+    /// Bytes the command allocated on the executing thread.
+    bytes_allocated: u64,
+  },
+  /// A command in an atomic batch failed, so the whole batch was rolled back.
+  #[error("Batch failed after staging {staged} commands: {failure}")]
+  BatchFailed {
+    /// The command which failed, and why.
+    failure: BatchFailure,
+    /// Number of commands staged before the failure, all of which were rolled back.
+    staged: usize,
+  },
+  /// Verifying a store on open, after it wasn't shut down cleanly, found a problem.
+  #[error("Verification failed: {0}")]
+  VerificationFailed(String),
+  /// Data was written with a capability which this build lacks, such as a codec whose feature isn't enabled.
+  #[error("Missing capability: {0}")]
+  MissingCapability(String),
+  /// Compressed data can't be decoded.
+  #[error("Codec error: {0}")]
+  CodecError(String),
+  /// A `MadeleineBuilder` was given invalid options, e.g. a store path whose parent directory doesn't exist.
+  #[error("Configuration error: {0}")]
+  ConfigurationError(String),
+  /// A `MadeleineConfig` has invalid fields, all of which are listed.
+  #[error("Invalid config: {0}")]
+  InvalidConfig(String),
+  /// A dump of a store can't be made or restored.
+  #[error("Dump error: {0}")]
+  DumpError(String),
+  /// An export doesn't match its manifest, or can't be imported.
+  #[error("Export error: {0}")]
+  ExportError(String),
+  /// Errors relating to importing history from another application.
+  #[error("Import error: {0}")]
+  ImportError(String),
+  /// Errors relating to merging two stores' histories.
+  #[error("Merge error: {0}")]
+  MergeError(String),
+  /// Errors relating to redacting logged commands.
+  #[error("Redaction error: {0}")]
+  RedactionError(String),
+  /// Errors relating to reconciling the state with a desired value.
+  #[error("Reconcile error: {0}")]
+  ReconcileError(String),
+  /// Errors relating to replaying the log to a point in its history, see `Madeleine::replay_from`.
+  #[error("Replay error: {0}")]
+  ReplayError(String),
+  /// Errors relating to registering or reading projections.
+  #[error("Projection error: {0}")]
+  ProjectionError(String),
+  /// A projection's fold failed and it can no longer be read.
+  #[error("Projection poisoned: {0}")]
+  ProjectionPoisoned(String),
+  /// A hard resource limit was reached, so the command was rejected.
+  #[error("Quota exceeded: {0}")]
+  QuotaExceeded(String),
+  /// Commands arrived faster than the store's rate limits allow, so the command was rejected before executing,
+  /// see `Madeleine::set_rate_limits`.
+  #[error("Rate limited, retry after {retry_after:?}")]
+  RateLimited {
+    /// How long until the command would be admitted, if no others take its place.
+    retry_after: std::time::Duration,
+  },
+  /// A long-running operation was stopped through its `CancellationToken` before it finished.
+  #[error("Cancelled after {completed} entries")]
+  Cancelled {
+    /// How many log entries were processed before the operation stopped, e.g. commands replayed.
+    completed: u64,
+  },
+  /// A subscriber fell too far behind and was disconnected.
+  #[error("Subscription lagged: {0}")]
+  SubscriptionLagged(String),
+  /// A command executed on tokio's blocking thread pool never completed, e.g. because the runtime shut down.
+  #[error("Async task failed: {0}")]
+  AsyncTaskFailed(String),
+  /// A thread panicked while holding a shared handle's lock, and the poisoning hasn't been acknowledged.
+  #[error("Poisoned: {0}")]
+  Poisoned(String),
+  /// A shared handle's lock is held by another thread, and the caller asked not to wait.
+  #[error("Contended: {0}")]
+  Contended(String),
+  /// A thread reading an instance's state tried to change it, e.g. by executing a command while holding a
+  /// `StateReadGuard`, which would wait forever.
+  #[error("Reentrant access: {0}")]
+  ReentrantAccess(String),
+  /// Strict mode detected a change to the state made outside of a command.
+  #[error("State mutated outside command: {0}")]
+  StateMutatedOutsideCommand(String),
+  /// There was nothing to undo or redo, e.g. when `Madeleine::undo_last` is called on an empty log.
+  #[error("Undo error: {0}")]
+  UndoError(String),
+  /// A logged command couldn't be brought up to the current version by the registered `CommandMigration`s.
+  #[error("Command migration error: {0}")]
+  CommandMigrationError(String),
+}
+
+#[cfg(feature = "bincode")]
+impl From<bincode::Error> for MadeleineError {
+  fn from(error: bincode::Error) -> Self {
+    Self::BincodeError(error.to_string())
+  }
+}
+
+#[cfg(feature = "sled")]
+impl From<sled::Error> for MadeleineError {
+  fn from(error: sled::Error) -> Self {
+    Self::SledError(error.to_string())
+  }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for MadeleineError {
+  fn from(error: rusqlite::Error) -> Self {
+    Self::SqliteError(error.to_string())
+  }
+}
+
+impl From<rmp_serde::encode::Error> for MadeleineError {
+  fn from(error: rmp_serde::encode::Error) -> Self {
+    Self::MessagePackError(error.to_string())
+  }
+}
+
+impl From<rmp_serde::decode::Error> for MadeleineError {
+  fn from(error: rmp_serde::decode::Error) -> Self {
+    Self::MessagePackError(error.to_string())
+  }
 }
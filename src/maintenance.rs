@@ -0,0 +1,325 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+
+/// Longest a maintenance thread sleeps between checks, so that it notices a changed schedule or clock.
+pub const MAX_MAINTENANCE_WAIT: Duration = Duration::from_secs(1);
+
+/// A chore which keeps a store healthy, run on a `MaintenanceSchedule`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaintenanceTask {
+  /// Take a snapshot, see `Madeleine::take_scheduled_snapshot`.
+  Snapshot,
+  /// Remove the commands covered by the latest snapshot from the log, see `Madeleine::compact_log`.
+  CompactLog,
+  /// Replace the log's history with a snapshot of the current state, see `Madeleine::compact`.
+  Compact,
+  /// Check that every entry in the log decodes, as `VerificationLevel::Full` does when opening after a crash.
+  VerifyLog,
+  /// Check the bookkeeping derived from the log, repairing it if it's wrong, see `Madeleine::rebuild_derived`.
+  RebuildDerived,
+  /// Measure the store's size on disk, raising warnings for newly crossed quota thresholds.
+  SampleUsage,
+}
+
+impl MaintenanceTask {
+  /// Whether the task only reads the store, so that its successful runs are left out of the admin log.
+  pub(crate) fn is_read_only(self) -> bool {
+    matches!(self, Self::VerifyLog | Self::SampleUsage)
+  }
+}
+
+/// Which maintenance tasks to run and how often, see `Madeleine::set_maintenance_schedule`.
+/// The default schedule runs nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceSchedule {
+  /// Each task, with the interval between its runs. The first run is an interval after the schedule is set.
+  pub tasks: Vec<(MaintenanceTask, Duration)>,
+  /// Most time added at random to each interval, so that stores opened together don't run their tasks together.
+  pub jitter: Duration,
+}
+
+impl MaintenanceSchedule {
+  /// Also run `task` every `interval`.
+  pub fn every(mut self, interval: Duration, task: MaintenanceTask) -> Self {
+    self.tasks.push((task, interval));
+    self
+  }
+
+  /// Add up to `jitter` to each interval.
+  pub fn with_jitter(mut self, jitter: Duration) -> Self {
+    self.jitter = jitter;
+    self
+  }
+}
+
+/// A run of a maintenance task, returned by `Madeleine::tick_maintenance` and delivered as a `StoreEvent::MaintenanceRan`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceRun {
+  /// The task which was run.
+  pub task: MaintenanceTask,
+  /// How long it took.
+  pub duration: Duration,
+  /// Why it failed, if it did.
+  pub error: Option<String>,
+}
+
+/// Tracks when each task of a schedule is next due.
+#[derive(Debug, Default)]
+pub(crate) struct MaintenanceScheduler {
+  /// The schedule, and when each of its tasks is next due.
+  scheduled: Mutex<(MaintenanceSchedule, Vec<Instant>)>,
+}
+
+impl MaintenanceScheduler {
+  pub fn schedule(&self) -> MaintenanceSchedule {
+    lock_recovering(&self.scheduled).0.clone()
+  }
+
+  /// Replace the schedule, with each task first due an interval after `now`.
+  pub fn set_schedule(&self, schedule: MaintenanceSchedule, now: Instant) {
+    let next_due = schedule
+      .tasks
+      .iter()
+      .map(|(_task, interval)| now + *interval + jitter(schedule.jitter))
+      .collect();
+
+    *lock_recovering(&self.scheduled) = (schedule, next_due);
+  }
+
+  /// Take the tasks due at `now`, in the schedule's order, and schedule their next runs.
+  pub fn take_due(&self, now: Instant) -> Vec<MaintenanceTask> {
+    let mut scheduled = lock_recovering(&self.scheduled);
+    let (schedule, next_due) = &mut *scheduled;
+    let mut due = Vec::new();
+
+    for ((task, interval), next_due) in schedule.tasks.iter().zip(next_due.iter_mut()) {
+      if *next_due <= now {
+        due.push(*task);
+        *next_due = now + *interval + jitter(schedule.jitter);
+      }
+    }
+
+    due
+  }
+
+  /// When the next task is due, if any are scheduled.
+  pub fn next_due(&self) -> Option<Instant> {
+    lock_recovering(&self.scheduled).1.iter().min().copied()
+  }
+}
+
+/// A random duration up to `max`.
+fn jitter(max: Duration) -> Duration {
+  if max.is_zero() {
+    return Duration::ZERO;
+  }
+
+  // The random bits of a fresh ULID are plenty to spread runs out.
+  Duration::from_nanos((Ulid::new().random() % max.as_nanos()) as u64)
+}
+
+/// A background thread running maintenance for a `SharedMadeleine`, which is stopped and joined when dropped.
+pub(crate) struct MaintenanceThread {
+  /// Set once the thread should stop, waking it if it's waiting.
+  stopping: Arc<(Mutex<bool>, Condvar)>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl MaintenanceThread {
+  /// Call `tick` until it returns `None` or the thread is stopped,
+  /// waiting as long as each call returns in between, up to `MAX_MAINTENANCE_WAIT`.
+  pub fn spawn<T>(mut tick: T) -> Result<Self, MadeleineError>
+  where
+    T: FnMut() -> Option<Duration> + Send + 'static,
+  {
+    let stopping = Arc::new((Mutex::new(false), Condvar::new()));
+    let thread_stopping = stopping.clone();
+
+    let thread = thread::Builder::new()
+      .name(String::from("madeleine-maintenance"))
+      .spawn(move || {
+        let (stopped, wake) = &*thread_stopping;
+
+        while !*lock_recovering(stopped) {
+          let Some(wait) = tick() else {
+            break;
+          };
+
+          let _stopped = wake
+            .wait_timeout_while(
+              lock_recovering(stopped),
+              wait.min(MAX_MAINTENANCE_WAIT),
+              |stopped| !*stopped,
+            )
+            .unwrap_or_else(PoisonError::into_inner);
+        }
+      })?;
+
+    Ok(Self {
+      stopping,
+      thread: Some(thread),
+    })
+  }
+}
+
+impl Drop for MaintenanceThread {
+  fn drop(&mut self) {
+    let (stopped, wake) = &*self.stopping;
+
+    *lock_recovering(stopped) = true;
+    wake.notify_all();
+
+    if let Some(thread) = self.thread.take() {
+      // The thread may drop the last handle itself, in which case it stops once this returns.
+      if thread.thread().id() != thread::current().id() {
+        let _ = thread.join();
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::admin_log::{admin_ops_since, AdminMarker, AdminOperationKind};
+  use crate::events::StoreEvent;
+  use crate::subscription::SubscribeOptions;
+  use crate::testing::{Add, ManualClock};
+  use crate::{Madeleine, SharedMadeleine};
+
+  #[test]
+  fn test_maintenance_runs_due_tasks_and_reports_them() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(store_path.clone(), || 0).expect("unable to instantiate madeleine in test");
+    let clock = Arc::new(ManualClock::new());
+    madeleine.set_clock(clock.clone());
+
+    let events = madeleine
+      .events(SubscribeOptions::default())
+      .expect("unable to subscribe to events in test");
+
+    madeleine
+      .set_maintenance_schedule(
+        MaintenanceSchedule::default()
+          .every(Duration::from_secs(60), MaintenanceTask::Snapshot)
+          .every(Duration::from_secs(10), MaintenanceTask::SampleUsage),
+      )
+      .expect("unable to set maintenance schedule in test");
+
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+
+    assert_eq!(
+      madeleine
+        .tick_maintenance()
+        .expect("unable to tick maintenance in test"),
+      Vec::new()
+    );
+    assert_eq!(
+      madeleine.next_maintenance_in(),
+      Some(Duration::from_secs(10))
+    );
+
+    clock.advance(Duration::from_secs(60));
+
+    let runs = madeleine
+      .tick_maintenance()
+      .expect("unable to tick maintenance in test");
+
+    assert_eq!(
+      runs
+        .iter()
+        .map(|run| (run.task, run.error.clone()))
+        .collect::<Vec<_>>(),
+      vec![
+        (MaintenanceTask::Snapshot, None),
+        (MaintenanceTask::SampleUsage, None)
+      ]
+    );
+    assert_eq!(
+      madeleine.next_maintenance_in(),
+      Some(Duration::from_secs(10))
+    );
+
+    let mut ran = Vec::new();
+
+    while let Some(event) = events.try_recv().expect("unable to receive event in test") {
+      if let StoreEvent::MaintenanceRan { run, .. } = event {
+        ran.push(run.task);
+      }
+    }
+
+    assert_eq!(
+      ran,
+      vec![MaintenanceTask::Snapshot, MaintenanceTask::SampleUsage]
+    );
+
+    let recorded: Vec<AdminOperationKind> = admin_ops_since(&store_path, AdminMarker::start())
+      .expect("unable to read admin log in test")
+      .into_iter()
+      .map(|operation| operation.kind)
+      .collect();
+
+    assert_eq!(
+      recorded,
+      vec![AdminOperationKind::MaintenanceRan {
+        task: MaintenanceTask::Snapshot,
+        error: None
+      }]
+    );
+  }
+
+  #[test]
+  fn test_shared_maintenance_thread_stops_cleanly() {
+    let madeleine =
+      Madeleine::new_in_memory(|| 0).expect("unable to instantiate madeleine in test");
+    let clock = Arc::new(ManualClock::new());
+    madeleine.set_clock(clock.clone());
+    madeleine
+      .set_maintenance_schedule(
+        MaintenanceSchedule::default()
+          .every(Duration::from_secs(60), MaintenanceTask::VerifyLog)
+          .with_jitter(Duration::from_secs(5)),
+      )
+      .expect("unable to set maintenance schedule in test");
+
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    shared
+      .start_maintenance()
+      .expect("unable to start maintenance in test");
+    shared
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+
+    shared.stop_maintenance();
+    clock.advance(Duration::from_secs(65));
+
+    let runs = shared
+      .run_pending_maintenance_now()
+      .expect("unable to run maintenance in test");
+
+    assert_eq!(
+      runs.iter().map(|run| run.task).collect::<Vec<_>>(),
+      vec![MaintenanceTask::VerifyLog]
+    );
+    assert_eq!(runs[0].error, None);
+    assert_eq!(shared.into_inner().ok(), Some(2));
+  }
+}
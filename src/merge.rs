@@ -0,0 +1,703 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::admin_log::{self, AdminOperationKind};
+use crate::command::{Command, CommandContext};
+use crate::command_log::{CommandIter, CommandLog};
+use crate::durable::write_atomically;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine::{command_log_dir_path, is_store_root, Madeleine};
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::{StoreFormat, StoreMetadata};
+
+pub(crate) const MERGE_CHECKPOINT_FILE_NAME: &str = "merge_checkpoint";
+
+/// Which of the merged stores a command came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub enum MergeSource {
+  /// The first store passed to `merge_stores`.
+  A,
+  /// The second store passed to `merge_stores`.
+  B,
+}
+
+/// A command touching keys which the other store touched since the histories diverged, passed to the resolver.
+#[derive(Debug)]
+pub struct MergeConflict<'a, C> {
+  /// Keys touched by both commands.
+  pub keys: &'a [String],
+  /// The command already merged, which last touched the first conflicting key.
+  pub earlier: &'a C,
+  /// Where the earlier command came from.
+  pub earlier_source: MergeSource,
+  /// The command being merged.
+  pub later: &'a C,
+  /// Where the later command came from.
+  pub later_source: MergeSource,
+}
+
+/// What to do with the later command of a conflict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution<C> {
+  /// Merge it as it is, after the earlier command.
+  Keep,
+  /// Leave it out of the merged history.
+  Skip,
+  /// Merge this command in its place, under its ULID.
+  Replace(C),
+}
+
+/// How a conflict was resolved, as listed in a `MergeReport`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolutionKind {
+  /// The later command was kept.
+  Kept,
+  /// The later command was left out.
+  Skipped,
+  /// The later command was replaced.
+  Replaced,
+}
+
+/// A conflict met while merging, and how it was resolved.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConflictRecord {
+  /// Keys touched by both commands.
+  pub keys: Vec<String>,
+  /// ULID of the command already merged.
+  pub earlier_id: Ulid,
+  /// Where the earlier command came from.
+  pub earlier_source: MergeSource,
+  /// ULID of the command being merged.
+  pub later_id: Ulid,
+  /// Where the later command came from.
+  pub later_source: MergeSource,
+  /// What the resolver decided.
+  pub resolution: ResolutionKind,
+}
+
+/// Settings for a merge.
+#[derive(Debug, Clone)]
+pub struct MergeOptions<SystemState> {
+  /// Number of commands from the stores merged between checkpoints.
+  pub checkpoint_every: u64,
+  /// The state the merged commands are replayed into, which is then snapshotted.
+  pub initial_state: SystemState,
+}
+
+impl<SystemState> MergeOptions<SystemState> {
+  /// Replay the merged commands into `initial_state`, checkpointing every 1000 commands.
+  pub fn new(initial_state: SystemState) -> Self {
+    Self {
+      checkpoint_every: 1000,
+      initial_state,
+    }
+  }
+}
+
+/// Outcome of a merge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeReport {
+  /// Number of commands in the merged store's log.
+  pub commands_merged: u64,
+  /// Number of commands present in both stores, from before they diverged, which were merged once.
+  pub duplicates: u64,
+  /// Every conflict and how it was resolved, in merged order.
+  pub conflicts: Vec<ConflictRecord>,
+  /// Number of commands from the stores which a previous, interrupted merge had already checkpointed and which were skipped.
+  pub commands_resumed: u64,
+  /// Id of the snapshot of the merged state.
+  pub snapshot_id: usize,
+}
+
+/// The last command which touched a key, so that a later command from the other store is detected as a conflict.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct KeyOwner {
+  /// Where the command came from, or `None` if both stores had it.
+  source: Option<MergeSource>,
+  id: Ulid,
+  command: Value,
+}
+
+/// Progress of a merge, persisted so that an interrupted merge can pick up where it left off.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct MergeCheckpoint {
+  /// Number of commands from the stores consumed, in merged order.
+  consumed: u64,
+  /// Number of commands written to the merged log.
+  written: u64,
+  duplicates: u64,
+  conflicts: Vec<ConflictRecord>,
+  owners: BTreeMap<String, KeyOwner>,
+}
+
+impl MergeCheckpoint {
+  fn load(location_dir_path: &Path) -> Result<Option<Self>, MadeleineError> {
+    let checkpoint_path = location_dir_path.join(MERGE_CHECKPOINT_FILE_NAME);
+
+    if checkpoint_path.is_file() {
+      let raw = fs::read(checkpoint_path)?;
+
+      Ok(Some(serde_json::from_slice(&raw)?))
+    } else {
+      Ok(None)
+    }
+  }
+
+  /// Replaced atomically, so that a crash while checkpointing leaves the previous checkpoint to resume from.
+  fn write(&self, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    write_atomically(
+      &location_dir_path.join(MERGE_CHECKPOINT_FILE_NAME),
+      &serde_json::to_vec(self)?,
+    )?;
+
+    Ok(())
+  }
+}
+
+/// Merge the histories of two stores which diverged, e.g. during a network partition, into a fresh store at `dest_path`.
+///
+/// Both logs are streamed in ULID order, a page at a time, and commands present in both, from before they diverged,
+/// are merged once. Fails with `MadeleineError::MergeError` if either store's log was compacted, since the history
+/// before its snapshot is gone, or if either log isn't in ULID order.
+/// `keys` lists the keys a command touches: a command touching a key last touched by a command from the other store
+/// is a conflict, which `resolver` decides. The merged commands are replayed into `options.initial_state`,
+/// and the resulting state is snapshotted.
+///
+/// Progress is checkpointed every `options.checkpoint_every` commands. If a merge fails partway, e.g. because
+/// the resolver failed, calling this again with the same, unchanged stores resumes after the last checkpoint.
+pub fn merge_stores<C, SystemState, K, R>(
+  a_path: &Path,
+  b_path: &Path,
+  dest_path: PathBuf,
+  options: MergeOptions<SystemState>,
+  mut keys: K,
+  mut resolver: R,
+) -> Result<MergeReport, MadeleineError>
+where
+  C: for<'a> Command<'a, SystemState = SystemState>,
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+  K: FnMut(&C) -> Vec<String>,
+  R: FnMut(&MergeConflict<'_, C>) -> Result<Resolution<C>, MadeleineError>,
+{
+  let checkpoint = match MergeCheckpoint::load(&dest_path)? {
+    Some(checkpoint) => {
+      discard_after_checkpoint(&dest_path, &checkpoint)?;
+
+      checkpoint
+    }
+    None => {
+      require_fresh_directory(&dest_path)?;
+      fs::create_dir_all(&dest_path)?;
+
+      let checkpoint = MergeCheckpoint::default();
+      checkpoint.write(&dest_path)?;
+
+      checkpoint
+    }
+  };
+
+  StoreMetadata::open_for_write(&dest_path, StoreFormat::default())?;
+
+  let a_log = open_source(a_path)?;
+  let b_log = open_source(b_path)?;
+  let merged = MergedLogs {
+    a: SourceLog::new(a_path, &a_log)?,
+    b: SourceLog::new(b_path, &b_log)?,
+  };
+  let command_log = CommandLog::new(command_log_dir_path(&dest_path))?;
+  let commands_resumed = checkpoint.consumed;
+  let checkpoint_every = options.checkpoint_every.max(1);
+  let mut progress = checkpoint;
+
+  for merged_command in merged.skip(commands_resumed as usize) {
+    let (source, logged, duplicate) = merged_command?;
+    let command: C = logged.deserialize()?;
+    let touched = keys(&command);
+    let owner_source = if duplicate {
+      progress.duplicates += 1;
+
+      None
+    } else {
+      Some(source)
+    };
+
+    let conflicting: Vec<String> = touched
+      .iter()
+      .filter(|key| {
+        progress.owners.get(*key).is_some_and(|owner| {
+          owner
+            .source
+            .is_some_and(|owner_source| owner_source != source)
+        })
+      })
+      .cloned()
+      .collect();
+
+    let merged_command = if conflicting.is_empty() || duplicate {
+      Some(command)
+    } else {
+      let earlier_owner = progress.owners[&conflicting[0]].clone();
+      let earlier: C = serde_json::from_value(earlier_owner.command.clone())?;
+      let earlier_source = earlier_owner.source.unwrap_or(source);
+
+      let resolution = resolver(&MergeConflict {
+        keys: &conflicting,
+        earlier: &earlier,
+        earlier_source,
+        later: &command,
+        later_source: source,
+      })?;
+
+      let (resolution_kind, merged_command) = match resolution {
+        Resolution::Keep => (ResolutionKind::Kept, Some(command)),
+        Resolution::Skip => (ResolutionKind::Skipped, None),
+        Resolution::Replace(replacement) => (ResolutionKind::Replaced, Some(replacement)),
+      };
+
+      progress.conflicts.push(ConflictRecord {
+        keys: conflicting,
+        earlier_id: earlier_owner.id,
+        earlier_source,
+        later_id: logged.id,
+        later_source: source,
+        resolution: resolution_kind,
+      });
+
+      merged_command
+    };
+
+    if let Some(merged_command) = merged_command {
+      let value = serde_json::to_value(&merged_command)?;
+
+      command_log.append_entry(&serde_json::to_vec(&(logged.id, &value))?)?;
+      progress.written += 1;
+
+      for key in keys(&merged_command) {
+        progress.owners.insert(
+          key,
+          KeyOwner {
+            source: owner_source,
+            id: logged.id,
+            command: value.clone(),
+          },
+        );
+      }
+    }
+
+    progress.consumed += 1;
+
+    if progress.consumed % checkpoint_every == 0 {
+      command_log.flush()?;
+      progress.write(&dest_path)?;
+    }
+  }
+
+  command_log.flush()?;
+  progress.write(&dest_path)?;
+
+  let mut state = options.initial_state;
+
+  for logged in command_log.commands() {
+    let logged = logged?;
+    let command: C = logged.deserialize()?;
+
+    state = command.execute_with_ctx(state, &CommandContext::at(logged.offset));
+  }
+
+  drop(command_log);
+
+  admin_log::record(
+    &dest_path,
+    AdminOperationKind::Merged {
+      commands: progress.written,
+      conflicts: progress.conflicts.len() as u64,
+    },
+  )?;
+
+  let madeleine = Madeleine::new(dest_path.clone(), || state)?;
+  let snapshot_id = madeleine.take_snapshot(true)?;
+
+  drop(madeleine);
+
+  fs::remove_file(dest_path.join(MERGE_CHECKPOINT_FILE_NAME))?;
+
+  Ok(MergeReport {
+    commands_merged: progress.written,
+    duplicates: progress.duplicates,
+    conflicts: progress.conflicts,
+    commands_resumed,
+    snapshot_id,
+  })
+}
+
+/// Open the log of a store being merged, which must hold its whole history.
+fn open_source(location_dir_path: &Path) -> Result<CommandLog, MadeleineError> {
+  if !is_store_root(location_dir_path) {
+    return Err(MadeleineError::MergeError(format!(
+      "{} is not a Madeleine store",
+      location_dir_path.display()
+    )));
+  }
+
+  let commands_compacted = StoreMetadata::read_commands_compacted(location_dir_path)?;
+
+  if commands_compacted > 0 {
+    return Err(MadeleineError::MergeError(format!(
+      "{} commands were compacted out of the log of {}, so its history can't be merged",
+      commands_compacted,
+      location_dir_path.display()
+    )));
+  }
+
+  CommandLog::new(command_log_dir_path(location_dir_path))
+}
+
+/// One store's log read a page at a time, with the next command to merge looked ahead.
+struct SourceLog<'a> {
+  location_dir_path: &'a Path,
+  commands: CommandIter<'a>,
+  next: Option<RawLoggedCommand>,
+}
+
+impl<'a> SourceLog<'a> {
+  fn new(location_dir_path: &'a Path, command_log: &'a CommandLog) -> Result<Self, MadeleineError> {
+    let mut commands = command_log.commands();
+    let next = commands.next().transpose()?;
+
+    Ok(Self {
+      location_dir_path,
+      commands,
+      next,
+    })
+  }
+
+  /// Take the next command, looking ahead to the one after it, which must have a greater ULID.
+  fn advance(&mut self) -> Result<Option<RawLoggedCommand>, MadeleineError> {
+    let following = self.commands.next().transpose()?;
+
+    if let (Some(next), Some(following)) = (&self.next, &following) {
+      if following.id <= next.id {
+        return Err(MadeleineError::MergeError(format!(
+          "the log of {} isn't in ULID order at offset {}",
+          self.location_dir_path.display(),
+          following.offset
+        )));
+      }
+    }
+
+    Ok(std::mem::replace(&mut self.next, following))
+  }
+}
+
+/// Both logs merged by ULID, with ties going to `A`. A command present in both, with the same ULID and payload,
+/// is yielded once and flagged as a duplicate.
+struct MergedLogs<'a> {
+  a: SourceLog<'a>,
+  b: SourceLog<'a>,
+}
+
+impl MergedLogs<'_> {
+  fn merge_next(
+    &mut self,
+  ) -> Result<Option<(MergeSource, RawLoggedCommand, bool)>, MadeleineError> {
+    let next = match (&self.a.next, &self.b.next) {
+      (Some(from_a), Some(from_b))
+        if from_a.id == from_b.id && from_a.payload == from_b.payload =>
+      {
+        self.b.advance()?;
+        self
+          .a
+          .advance()?
+          .map(|command| (MergeSource::A, command, true))
+      }
+      (Some(from_a), Some(from_b)) if from_b.id < from_a.id => self
+        .b
+        .advance()?
+        .map(|command| (MergeSource::B, command, false)),
+      (Some(_), _) => self
+        .a
+        .advance()?
+        .map(|command| (MergeSource::A, command, false)),
+      (None, Some(_)) => self
+        .b
+        .advance()?
+        .map(|command| (MergeSource::B, command, false)),
+      (None, None) => None,
+    };
+
+    Ok(next)
+  }
+}
+
+impl Iterator for MergedLogs<'_> {
+  type Item = Result<(MergeSource, RawLoggedCommand, bool), MadeleineError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.merge_next().transpose()
+  }
+}
+
+/// Merges start from nothing, unless resuming from a checkpoint.
+fn require_fresh_directory(location_dir_path: &Path) -> Result<(), MadeleineError> {
+  let is_empty = !location_dir_path.exists() || fs::read_dir(location_dir_path)?.next().is_none();
+
+  if is_empty && !is_store_root(location_dir_path) {
+    Ok(())
+  } else {
+    Err(MadeleineError::MergeError(format!(
+      "{} must be missing or empty to merge into it",
+      location_dir_path.display()
+    )))
+  }
+}
+
+/// Throw away anything an interrupted merge wrote after its last checkpoint.
+fn discard_after_checkpoint(
+  location_dir_path: &Path,
+  checkpoint: &MergeCheckpoint,
+) -> Result<(), MadeleineError> {
+  let log_dir_path = command_log_dir_path(location_dir_path);
+
+  if checkpoint.written == 0 {
+    if log_dir_path.is_dir() {
+      fs::remove_dir_all(&log_dir_path)?;
+    }
+  } else {
+    CommandLog::new(log_dir_path)?.truncate(checkpoint.written)?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::Follower;
+
+  /// Sets keys of a map to values.
+  #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+  struct Put(String, u64);
+
+  impl Command<'_> for Put {
+    type SystemState = BTreeMap<String, u64>;
+
+    fn execute(&self, mut old_state: Self::SystemState) -> Self::SystemState {
+      old_state.insert(self.0.clone(), self.1);
+      old_state
+    }
+  }
+
+  fn put(key: &str, value: u64) -> Put {
+    Put(key.to_string(), value)
+  }
+
+  fn copy_dir(source: &Path, target: &Path) {
+    fs::create_dir_all(target).expect("unable to create dir in test");
+
+    for entry in fs::read_dir(source).expect("unable to read dir in test") {
+      let entry = entry.expect("unable to read dir entry in test");
+      let target_path = target.join(entry.file_name());
+
+      if entry.path().is_dir() {
+        copy_dir(&entry.path(), &target_path);
+      } else {
+        fs::copy(entry.path(), target_path).expect("unable to copy file in test");
+      }
+    }
+  }
+
+  /// Two stores sharing a history of `x = 1`, which then diverge:
+  /// A writes `a = 1`, `x = 10` and B writes `b = 2`, `x = 20`, interleaved in time.
+  fn partitioned_stores(root: &Path) -> (PathBuf, PathBuf) {
+    let a_path = root.join("a");
+    let b_path = root.join("b");
+
+    let a = Madeleine::new(a_path.clone(), BTreeMap::new).expect("unable to create a in test");
+    a.execute_command(put("x", 1))
+      .expect("unable to execute command in test");
+    a.take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    drop(a);
+
+    // The partition: B starts as a copy of A.
+    copy_dir(&a_path, &b_path);
+
+    let a = Madeleine::<BTreeMap<String, u64>>::resume(a_path.clone())
+      .expect("unable to resume a in test");
+    let b = Madeleine::<BTreeMap<String, u64>>::resume(b_path.clone())
+      .expect("unable to resume b in test");
+
+    a.execute_command(put("a", 1))
+      .expect("unable to execute command in test");
+    b.execute_command(put("b", 2))
+      .expect("unable to execute command in test");
+    a.execute_command(put("x", 10))
+      .expect("unable to execute command in test");
+    b.execute_command(put("x", 20))
+      .expect("unable to execute command in test");
+
+    (a_path, b_path)
+  }
+
+  fn keys(command: &Put) -> Vec<String> {
+    vec![command.0.clone()]
+  }
+
+  #[test]
+  fn test_merge_orders_histories_and_resolves_conflicts() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (a_path, b_path) = partitioned_stores(temp_dir.path());
+    let dest_path = temp_dir.path().join("merged");
+
+    let mut conflicts_seen = Vec::new();
+    let report = merge_stores(
+      &a_path,
+      &b_path,
+      dest_path.clone(),
+      MergeOptions::new(BTreeMap::new()),
+      keys,
+      |conflict: &MergeConflict<'_, Put>| {
+        conflicts_seen.push((conflict.earlier.clone(), conflict.later.clone()));
+
+        Ok(Resolution::Replace(put(
+          "x",
+          conflict.earlier.1 + conflict.later.1,
+        )))
+      },
+    )
+    .expect("unable to merge in test");
+
+    assert_eq!(conflicts_seen, vec![(put("x", 10), put("x", 20))]);
+    assert_eq!(report.commands_merged, 5);
+    assert_eq!(report.duplicates, 1);
+    assert_eq!(report.commands_resumed, 0);
+    assert_eq!(report.conflicts.len(), 1);
+    assert_eq!(report.conflicts[0].keys, vec![String::from("x")]);
+    assert_eq!(report.conflicts[0].earlier_source, MergeSource::A);
+    assert_eq!(report.conflicts[0].later_source, MergeSource::B);
+    assert_eq!(report.conflicts[0].resolution, ResolutionKind::Replaced);
+
+    let merged: Vec<Put> = Follower::open(dest_path.clone())
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read merged log in test")
+      .iter()
+      .map(|command| {
+        command
+          .deserialize()
+          .expect("unable to deserialize in test")
+      })
+      .collect();
+
+    assert_eq!(
+      merged,
+      vec![
+        put("x", 1),
+        put("a", 1),
+        put("b", 2),
+        put("x", 10),
+        put("x", 30)
+      ]
+    );
+
+    let resumed = Madeleine::<BTreeMap<String, u64>>::resume(dest_path)
+      .expect("unable to resume merged store in test");
+
+    assert_eq!(
+      resumed.tap_ref(|state| state.clone()).ok(),
+      Some(BTreeMap::from([
+        (String::from("a"), 1),
+        (String::from("b"), 2),
+        (String::from("x"), 30)
+      ]))
+    );
+  }
+
+  #[test]
+  fn test_merge_rejects_compacted_store() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (a_path, b_path) = partitioned_stores(temp_dir.path());
+    let dest_path = temp_dir.path().join("merged");
+
+    Madeleine::resume_replaying::<Put, _>(b_path.clone(), BTreeMap::new)
+      .and_then(|b| b.compact())
+      .expect("unable to compact b in test");
+
+    let merged = merge_stores(
+      &a_path,
+      &b_path,
+      dest_path,
+      MergeOptions::new(BTreeMap::new()),
+      keys,
+      |_conflict: &MergeConflict<'_, Put>| Ok(Resolution::Keep),
+    );
+
+    assert!(matches!(
+      merged,
+      Err(MadeleineError::MergeError(message)) if message.contains("compacted")
+    ));
+  }
+
+  #[test]
+  fn test_interrupted_merge_resumes_from_checkpoint() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (a_path, b_path) = partitioned_stores(temp_dir.path());
+    let dest_path = temp_dir.path().join("merged");
+
+    let options = MergeOptions {
+      checkpoint_every: 2,
+      initial_state: BTreeMap::new(),
+    };
+
+    let interrupted = merge_stores(
+      &a_path,
+      &b_path,
+      dest_path.clone(),
+      options.clone(),
+      keys,
+      |_conflict: &MergeConflict<'_, Put>| {
+        Err(MadeleineError::MergeError(String::from(
+          "operator unavailable",
+        )))
+      },
+    );
+
+    assert!(matches!(interrupted, Err(MadeleineError::MergeError(_))));
+    assert!(dest_path.join(MERGE_CHECKPOINT_FILE_NAME).is_file());
+
+    let report = merge_stores(
+      &a_path,
+      &b_path,
+      dest_path.clone(),
+      options,
+      keys,
+      |_conflict: &MergeConflict<'_, Put>| Ok(Resolution::Skip),
+    )
+    .expect("unable to resume merge in test");
+
+    assert_eq!(report.commands_resumed, 4);
+    assert_eq!(report.commands_merged, 4);
+    assert_eq!(report.duplicates, 1);
+    assert_eq!(report.conflicts[0].resolution, ResolutionKind::Skipped);
+    assert!(!dest_path.join(MERGE_CHECKPOINT_FILE_NAME).exists());
+
+    let resumed = Madeleine::<BTreeMap<String, u64>>::resume(dest_path)
+      .expect("unable to resume merged store in test");
+
+    assert_eq!(
+      resumed.tap_ref(|state| state.get("x").copied()).ok(),
+      Some(Some(10))
+    );
+  }
+}
@@ -0,0 +1,536 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command_migration::CommandVersion;
+use crate::command_store::{LogBackend, StoreWrapper, SuppliedStore};
+use crate::compaction::CompactionJournal;
+use crate::durable::write_atomically;
+use crate::hashing::HashAlgo;
+use crate::integrity::CleanShutdown;
+use crate::layout::StoreLayout;
+use crate::madeleine_error::MadeleineError;
+use crate::migration::StateMigration;
+use crate::payload_format::PayloadFormat;
+
+pub(crate) const METADATA_FILE_NAME: &str = "metadata";
+
+/// Features of the on-disk format which this build writes, and so which any later writer must understand.
+const WRITER_CAPABILITIES: [&str; 10] = [
+  "json-payloads",
+  "sequenced-entries",
+  "snapshot-aliases",
+  "idempotency-keys",
+  "codec-frames",
+  "bincode-payloads",
+  "msgpack-payloads",
+  "file-log-backend",
+  "sled-log-backend",
+  "store-layouts",
+];
+
+/// The library version and on-disk capabilities of a build which opened a store for writing.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct WriterInfo {
+  /// Version of the library.
+  pub library_version: String,
+  /// Capabilities of the on-disk format the library writes.
+  pub capabilities: BTreeSet<String>,
+}
+
+impl WriterInfo {
+  /// Describe this build.
+  pub fn this_build() -> Self {
+    Self {
+      library_version: env!("CARGO_PKG_VERSION").to_string(),
+      capabilities: WRITER_CAPABILITIES
+        .iter()
+        .map(|capability| capability.to_string())
+        .collect(),
+    }
+  }
+
+  /// Determine if this writer can safely write to a store last written by `other`,
+  /// i.e. it's at least as new and has every capability `other` may have used.
+  fn can_follow(&self, other: &Self) -> bool {
+    parse_version(&self.library_version) >= parse_version(&other.library_version)
+      && other.capabilities.is_subset(&self.capabilities)
+  }
+}
+
+impl std::fmt::Display for WriterInfo {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let capabilities: Vec<&str> = self.capabilities.iter().map(String::as_str).collect();
+
+    write!(
+      f,
+      "version {} with capabilities [{}]",
+      self.library_version,
+      capabilities.join(", ")
+    )
+  }
+}
+
+/// Numeric components of a version, so that e.g. 0.10.0 sorts after 0.9.0.
+/// Anything after a `-` or `+`, such as a pre-release tag, is ignored.
+fn parse_version(version: &str) -> Vec<u64> {
+  version
+    .split(['-', '+'])
+    .next()
+    .unwrap_or_default()
+    .split('.')
+    .map(|component| component.parse().unwrap_or(0))
+    .collect()
+}
+
+/// The formats requested of a store when it's opened, each the default for a new store if left out,
+/// and whatever an existing store uses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct StoreFormat {
+  pub hash_algo: Option<HashAlgo>,
+  pub payload_format: Option<PayloadFormat>,
+  pub log_backend: Option<LogBackend>,
+  /// The store keeping the log, for `LogBackend::Custom`.
+  pub command_store: Option<SuppliedStore>,
+  /// Passes the log's store through, whenever it's opened.
+  pub store_wrapper: Option<StoreWrapper>,
+  pub layout: Option<StoreLayout>,
+}
+
+/// Durable facts about a store, kept in a small JSON file at the root of the store directory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct StoreMetadata {
+  /// Identifier assigned to the store when it was first created.
+  pub store_id: Ulid,
+  /// The build which most recently opened the store for writing, missing for stores written before it was recorded.
+  #[serde(default)]
+  pub last_writer: Option<WriterInfo>,
+  /// Hash function used by the store's integrity features, SHA-256 for stores created before it was recorded.
+  #[serde(default)]
+  pub hash_algo: HashAlgo,
+  /// How commands are serialized in the store's log, JSON for stores created before it was recorded.
+  #[serde(default)]
+  pub payload_format: PayloadFormat,
+  /// How the store's log is kept on disk, the commit log for stores created before it was recorded.
+  #[serde(default)]
+  pub log_backend: LogBackend,
+  /// Where the store keeps its command log and snapshots, the default layout for stores created before it was recorded.
+  #[serde(default)]
+  pub layout: StoreLayout,
+  /// Present only between a clean shutdown and the next time the store is opened for writing.
+  #[serde(default)]
+  pub clean_shutdown: Option<CleanShutdown>,
+  /// Progress of a compaction, present only while one is running or was interrupted.
+  #[serde(default)]
+  pub compaction: Option<CompactionJournal>,
+  /// Commands removed from the log by compactions and purges, which still count towards `Madeleine::total_commands_ever`.
+  #[serde(default)]
+  pub commands_compacted: u64,
+  /// ULID of the last command removed from the log by compactions and purges, missing for stores compacted before it was recorded.
+  /// Replaying the log from a snapshot taken before it would miss commands.
+  #[serde(default)]
+  pub compacted_through: Option<Ulid>,
+  /// Versions commands were logged at, in the order they took effect, see `Madeleine::add_command_migration`.
+  /// Empty while every command is at `INITIAL_COMMAND_VERSION`.
+  #[serde(default)]
+  pub command_versions: Vec<CommandVersion>,
+  /// Changes of the state type, oldest first, see `Madeleine::migrate_state`.
+  #[serde(default)]
+  pub state_migrations: Vec<StateMigration>,
+  /// Whether the store belongs to the `Madeleine::new_in_memory` instance which created it, and is removed with it.
+  #[serde(default)]
+  pub in_memory: bool,
+}
+
+impl StoreMetadata {
+  /// Read the store's metadata, creating and persisting it with `hash_algo` if the store doesn't have any yet.
+  pub fn load_or_create(
+    location_dir_path: &Path,
+    hash_algo: HashAlgo,
+  ) -> Result<Self, MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if metadata_path.is_file() {
+      let raw = fs::read(metadata_path)?;
+      let metadata: Self = serde_json::from_slice(&raw)?;
+
+      Ok(metadata)
+    } else {
+      let metadata = Self {
+        store_id: Ulid::new(),
+        last_writer: None,
+        hash_algo,
+        payload_format: PayloadFormat::default(),
+        log_backend: LogBackend::default(),
+        layout: StoreLayout::default(),
+        clean_shutdown: None,
+        compaction: None,
+        commands_compacted: 0,
+        compacted_through: None,
+        command_versions: Vec::new(),
+        state_migrations: Vec::new(),
+        in_memory: false,
+      };
+
+      metadata.write(location_dir_path)?;
+
+      Ok(metadata)
+    }
+  }
+
+  /// Mark the store as belonging to the `Madeleine::new_in_memory` instance which created it.
+  pub fn mark_in_memory(
+    location_dir_path: &Path,
+    hash_algo: HashAlgo,
+  ) -> Result<(), MadeleineError> {
+    let mut metadata = Self::load_or_create(location_dir_path, hash_algo)?;
+    metadata.in_memory = true;
+
+    metadata.write(location_dir_path)
+  }
+
+  /// Read the store's metadata as `load_or_create` does, then record this build as its most recent writer.
+  /// A new store uses the requested hash function, payload format, log backend and layout, or the defaults if none are requested.
+  /// Fails with `MadeleineError::IncompatibleWriter` if the store was last written by a newer build,
+  /// or one using a capability this build lacks, rather than risk corrupting it,
+  /// with `MadeleineError::HashAlgoMismatch` if an existing store uses a different hash function than requested,
+  /// with `MadeleineError::PayloadFormatMismatch` if it uses a different payload format than requested,
+  /// with `MadeleineError::LogBackendMismatch` if it uses a different log backend than requested,
+  /// and with `MadeleineError::ConfigurationError` if it has a different layout than requested.
+  pub fn open_for_write(
+    location_dir_path: &Path,
+    format: StoreFormat,
+  ) -> Result<Self, MadeleineError> {
+    let StoreFormat {
+      hash_algo,
+      payload_format,
+      log_backend,
+      command_store: _,
+      store_wrapper: _,
+      layout,
+    } = format;
+    let is_new = !location_dir_path.join(METADATA_FILE_NAME).is_file();
+    // Stores created before metadata was recorded already have a log, in the default layout.
+    let has_log = StoreLayout::default()
+      .command_log_dir_path(location_dir_path)
+      .exists();
+    let mut metadata = Self::load_or_create(location_dir_path, hash_algo.unwrap_or_default())?;
+    let this_build = WriterInfo::this_build();
+
+    if let Some(requested) = payload_format {
+      if is_new {
+        requested.check_available()?;
+        metadata.payload_format = requested;
+        metadata.write(location_dir_path)?;
+      } else if requested != metadata.payload_format {
+        return Err(MadeleineError::PayloadFormatMismatch(format!(
+          "store uses {:?} payloads, but {:?} was requested",
+          metadata.payload_format, requested
+        )));
+      }
+    }
+
+    metadata.payload_format.check_available()?;
+
+    if let Some(requested) = log_backend {
+      if is_new {
+        requested.check_available()?;
+        metadata.log_backend = requested;
+        metadata.write(location_dir_path)?;
+      } else if requested != metadata.log_backend {
+        return Err(MadeleineError::LogBackendMismatch(format!(
+          "store uses the {:?} log backend, but {:?} was requested",
+          metadata.log_backend, requested
+        )));
+      }
+    }
+
+    metadata.log_backend.check_available()?;
+
+    if let Some(requested) = layout {
+      if is_new && !has_log {
+        requested.validate()?;
+        metadata.layout = requested;
+        metadata.write(location_dir_path)?;
+      } else if requested != metadata.layout {
+        return Err(MadeleineError::ConfigurationError(format!(
+          "store keeps its {}, but its {} was requested",
+          metadata.layout, requested
+        )));
+      }
+    }
+
+    if let Some(requested) = hash_algo {
+      if requested != metadata.hash_algo {
+        return Err(MadeleineError::HashAlgoMismatch(format!(
+          "store uses {:?}, but {:?} was requested",
+          metadata.hash_algo, requested
+        )));
+      }
+    }
+
+    if let Some(last_writer) = &metadata.last_writer {
+      if !this_build.can_follow(last_writer) {
+        return Err(MadeleineError::IncompatibleWriter {
+          store_requires: last_writer.to_string(),
+          this_build: this_build.to_string(),
+        });
+      }
+    }
+
+    if metadata.last_writer.as_ref() != Some(&this_build) {
+      metadata.last_writer = Some(this_build);
+      metadata.write(location_dir_path)?;
+    }
+
+    Ok(metadata)
+  }
+
+  /// Remove the clean shutdown marker, persisting its removal before returning it,
+  /// so that a crash during the session which is starting can't be mistaken for a clean shutdown.
+  pub fn take_clean_shutdown(
+    &mut self,
+    location_dir_path: &Path,
+  ) -> Result<Option<CleanShutdown>, MadeleineError> {
+    let clean_shutdown = self.clean_shutdown.take();
+
+    if clean_shutdown.is_some() {
+      self.write(location_dir_path)?;
+    }
+
+    Ok(clean_shutdown)
+  }
+
+  /// Commands removed from the log of the store at `location_dir_path` so far,
+  /// read without creating metadata for a store which has none yet, e.g. by replicas.
+  pub fn read_commands_compacted(location_dir_path: &Path) -> Result<u64, MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if !metadata_path.is_file() {
+      return Ok(0);
+    }
+
+    let metadata: Self = serde_json::from_slice(&fs::read(metadata_path)?)?;
+
+    Ok(metadata.commands_compacted)
+  }
+
+  /// ULID of the last command removed from the log of the store at `location_dir_path`, if it's known,
+  /// read without creating metadata.
+  pub fn read_compacted_through(location_dir_path: &Path) -> Result<Option<Ulid>, MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if !metadata_path.is_file() {
+      return Ok(None);
+    }
+
+    let metadata: Self = serde_json::from_slice(&fs::read(metadata_path)?)?;
+
+    Ok(metadata.compacted_through)
+  }
+
+  /// Layout of the store at `location_dir_path`, read without creating metadata.
+  pub fn read_layout(location_dir_path: &Path) -> Result<StoreLayout, MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if !metadata_path.is_file() {
+      return Ok(StoreLayout::default());
+    }
+
+    let metadata: Self = serde_json::from_slice(&fs::read(metadata_path)?)?;
+
+    Ok(metadata.layout)
+  }
+
+  /// Fail with `MadeleineError::InMemoryStore` if the store at `location_dir_path` belongs to
+  /// the `Madeleine::new_in_memory` instance which created it, reading its metadata without creating any.
+  pub fn check_not_in_memory(location_dir_path: &Path) -> Result<(), MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if !metadata_path.is_file() {
+      return Ok(());
+    }
+
+    let metadata: Self = serde_json::from_slice(&fs::read(metadata_path)?)?;
+
+    if metadata.in_memory {
+      return Err(MadeleineError::InMemoryStore(format!(
+        "{} belongs to the in-memory instance which created it, and is removed along with it",
+        location_dir_path.display()
+      )));
+    }
+
+    Ok(())
+  }
+
+  /// Changes of the state type of the store at `location_dir_path`, read without creating metadata.
+  pub fn read_state_migrations(
+    location_dir_path: &Path,
+  ) -> Result<Vec<StateMigration>, MadeleineError> {
+    let metadata_path = location_dir_path.join(METADATA_FILE_NAME);
+
+    if !metadata_path.is_file() {
+      return Ok(Vec::new());
+    }
+
+    let metadata: Self = serde_json::from_slice(&fs::read(metadata_path)?)?;
+
+    Ok(metadata.state_migrations)
+  }
+
+  /// Persist the metadata to the store directory, atomically, as it journals compactions,
+  /// so a crash while it's being written leaves the previous metadata rather than a truncated file.
+  pub fn write(&self, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    let serialized = serde_json::to_vec(self)?;
+    write_atomically(&location_dir_path.join(METADATA_FILE_NAME), &serialized)?;
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::durable::TEMP_FILE_SUFFIX;
+  use crate::testing::Add;
+  use crate::{Follower, Madeleine, ReadOnlyMadeleine};
+
+  /// Create a store, then rewrite its recorded writer as though a different build had opened it.
+  fn store_last_written_by<F>(location_dir_path: &Path, edit: F)
+  where
+    F: FnOnce(&mut WriterInfo),
+  {
+    let madeleine = Madeleine::new(location_dir_path.to_path_buf(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..2 {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    drop(madeleine);
+
+    let mut metadata = StoreMetadata::load_or_create(location_dir_path, HashAlgo::default())
+      .expect("unable to load metadata in test");
+    let mut last_writer = metadata
+      .last_writer
+      .take()
+      .expect("writer not recorded in test");
+
+    assert_eq!(last_writer, WriterInfo::this_build());
+
+    edit(&mut last_writer);
+    metadata.last_writer = Some(last_writer);
+    metadata
+      .write(location_dir_path)
+      .expect("unable to write metadata in test");
+  }
+
+  #[test]
+  fn test_newer_writer_refuses_older_build() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    store_last_written_by(&store_path, |writer| {
+      writer.library_version = String::from("999.0.0");
+    });
+
+    let reopened = Madeleine::new(store_path.clone(), || 0_u64);
+
+    assert!(matches!(
+      reopened,
+      Err(MadeleineError::IncompatibleWriter { ref store_requires, .. })
+        if store_requires.contains("999.0.0")
+    ));
+
+    let follower = Follower::open(store_path.clone()).expect("unable to open follower in test");
+
+    assert_eq!(
+      follower
+        .commands_after(Ulid::nil())
+        .map(|commands| commands.len())
+        .ok(),
+      Some(2)
+    );
+
+    let replica = ReadOnlyMadeleine::<Add, u64>::open(store_path, || 0_u64)
+      .expect("unable to open replica in test");
+
+    assert_eq!(replica.into_inner(), 2);
+  }
+
+  #[test]
+  fn test_unknown_capability_refuses_build() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    store_last_written_by(&store_path, |writer| {
+      writer.capabilities.insert(String::from("zstd-payloads"));
+    });
+
+    let reopened = Madeleine::new(store_path, || 0_u64);
+
+    assert!(matches!(
+      reopened,
+      Err(MadeleineError::IncompatibleWriter { ref store_requires, .. })
+        if store_requires.contains("zstd-payloads")
+    ));
+  }
+
+  #[test]
+  fn test_torn_write_leaves_metadata() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    store_last_written_by(&store_path, |_writer| {});
+
+    let metadata_path = store_path.join(METADATA_FILE_NAME);
+    let written = fs::read(&metadata_path).expect("unable to read metadata in test");
+    // As left by a crash part way through replacing the metadata.
+    fs::write(
+      format!("{}{}", metadata_path.display(), TEMP_FILE_SUFFIX),
+      &written[..written.len() / 2],
+    )
+    .expect("unable to write in test");
+
+    let reopened =
+      Madeleine::new(store_path.clone(), || 0_u64).expect("unable to reopen madeleine in test");
+
+    assert_eq!(reopened.len(), 2);
+    assert!(StoreMetadata::load_or_create(&store_path, HashAlgo::default()).is_ok());
+  }
+
+  #[test]
+  fn test_older_writer_is_superseded() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    store_last_written_by(&store_path, |writer| {
+      writer.library_version = String::from("0.0.1");
+      writer.capabilities.clear();
+    });
+
+    let reopened =
+      Madeleine::new(store_path.clone(), || 0_u64).expect("unable to reopen madeleine in test");
+
+    drop(reopened);
+
+    let metadata = StoreMetadata::load_or_create(&store_path, HashAlgo::default())
+      .expect("unable to load metadata in test");
+
+    assert_eq!(metadata.last_writer, Some(WriterInfo::this_build()));
+    assert!(parse_version("0.10.0") > parse_version("0.9.1"));
+    assert!(parse_version("1.0.0-beta.1") == parse_version("1.0.0"));
+  }
+}
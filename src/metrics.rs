@@ -0,0 +1,740 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Upper bounds, in nanoseconds, of the fixed histogram buckets. A final bucket catches everything slower.
+const BUCKET_BOUNDS_NANOS: [u64; 8] = [
+  1_000,
+  10_000,
+  100_000,
+  1_000_000,
+  10_000_000,
+  100_000_000,
+  1_000_000_000,
+  10_000_000_000,
+];
+
+/// The phases of `Madeleine::execute_command` which are timed individually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+  /// Running the command's `execute` method.
+  Execute,
+  /// Serializing the command for the log.
+  Serialize,
+  /// Appending the serialized command to the log.
+  Append,
+  /// Running post-append work such as maintaining projections.
+  Hooks,
+}
+
+impl Phase {
+  /// Every phase, in pipeline order.
+  pub const ALL: [Phase; 4] = [
+    Phase::Execute,
+    Phase::Serialize,
+    Phase::Append,
+    Phase::Hooks,
+  ];
+
+  /// Lowercase name of the phase, suitable for labels.
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Execute => "execute",
+      Self::Serialize => "serialize",
+      Self::Append => "append",
+      Self::Hooks => "hooks",
+    }
+  }
+
+  fn index(&self) -> usize {
+    match self {
+      Self::Execute => 0,
+      Self::Serialize => 1,
+      Self::Append => 2,
+      Self::Hooks => 3,
+    }
+  }
+}
+
+/// Latency histogram with fixed, logarithmically spaced buckets from 1µs to 10s.
+#[derive(Debug, Default)]
+pub struct Histogram {
+  buckets: [AtomicU64; BUCKET_BOUNDS_NANOS.len() + 1],
+  count: AtomicU64,
+  sum_nanos: AtomicU64,
+  max_nanos: AtomicU64,
+}
+
+impl Histogram {
+  /// Record a single observation.
+  pub fn observe(&self, duration: Duration) {
+    self.observe_many(duration, 1);
+  }
+
+  /// Record several observations of the same duration, e.g. each command's share of a batch.
+  pub(crate) fn observe_many(&self, duration: Duration, count: u64) {
+    let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    let bucket = BUCKET_BOUNDS_NANOS
+      .iter()
+      .position(|bound| nanos <= *bound)
+      .unwrap_or(BUCKET_BOUNDS_NANOS.len());
+
+    self.buckets[bucket].fetch_add(count, Ordering::Relaxed);
+    self.count.fetch_add(count, Ordering::Relaxed);
+    self
+      .sum_nanos
+      .fetch_add(nanos.saturating_mul(count), Ordering::Relaxed);
+    self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+  }
+
+  /// Number of observations recorded.
+  pub fn count(&self) -> u64 {
+    self.count.load(Ordering::Relaxed)
+  }
+
+  /// Total of all observations recorded.
+  pub fn sum(&self) -> Duration {
+    Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+  }
+
+  /// Longest observation recorded.
+  pub fn max(&self) -> Duration {
+    Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed))
+  }
+
+  /// Mean of the observations recorded, or zero if there are none.
+  pub fn mean(&self) -> Duration {
+    match self.count() {
+      0 => Duration::ZERO,
+      count => Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed) / count),
+    }
+  }
+
+  /// Estimate the duration which a fraction `q` of observations took at most, e.g. `0.99` for the 99th percentile,
+  /// or zero if there are none. Since only bucket counts are kept, this is the upper bound of the bucket the quantile
+  /// falls in, or the longest observation if that's shorter.
+  pub fn quantile(&self, q: f64) -> Duration {
+    let count = self.count();
+    let max = self.max_nanos.load(Ordering::Relaxed);
+
+    if count == 0 {
+      return Duration::ZERO;
+    }
+
+    let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+    let mut cumulative = 0;
+
+    for (index, bucket) in self.buckets.iter().enumerate() {
+      cumulative += bucket.load(Ordering::Relaxed);
+
+      if cumulative >= rank {
+        let bound = BUCKET_BOUNDS_NANOS.get(index).copied().unwrap_or(max);
+
+        return Duration::from_nanos(bound.min(max));
+      }
+    }
+
+    Duration::from_nanos(max)
+  }
+
+  /// Forget every observation.
+  pub(crate) fn reset(&self) {
+    for bucket in &self.buckets {
+      bucket.store(0, Ordering::Relaxed);
+    }
+
+    self.count.store(0, Ordering::Relaxed);
+    self.sum_nanos.store(0, Ordering::Relaxed);
+    self.max_nanos.store(0, Ordering::Relaxed);
+  }
+
+  /// Number of observations in each bucket, paired with the bucket's inclusive upper bound.
+  /// The final bucket has no upper bound.
+  pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+    self
+      .buckets
+      .iter()
+      .enumerate()
+      .map(|(index, bucket)| {
+        let bound = BUCKET_BOUNDS_NANOS
+          .get(index)
+          .map(|nanos| Duration::from_nanos(*nanos));
+
+        (bound, bucket.load(Ordering::Relaxed))
+      })
+      .collect()
+  }
+}
+
+/// A problem which was recovered from automatically, but which may be worth investigating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntegrityFlag {
+  /// A thread panicked while holding a `SharedMadeleine`'s lock, leaving it poisoned, and the lock was recovered.
+  /// The state is unaffected, since commands change it only once `execute` returns.
+  LockPoisoned {
+    /// When the poisoned lock was noticed.
+    at: SystemTime,
+  },
+}
+
+/// Estimated bytes of state cloned during the current one second window.
+#[derive(Debug, Default)]
+struct CloneRateWindow {
+  started_at: Option<Instant>,
+  bytes: u64,
+}
+
+/// Counters describing the runtime behavior of a `Madeleine` instance.
+#[derive(Debug, Default)]
+pub struct Metrics {
+  projections_poisoned: AtomicU64,
+  phase_timing_enabled: AtomicBool,
+  phase_histograms: [Histogram; Phase::ALL.len()],
+  command_histogram: Histogram,
+  tap_histogram: Histogram,
+  state_clones: AtomicU64,
+  pending_snapshot_debt: AtomicU64,
+  query_cache_hits: AtomicU64,
+  query_cache_misses: AtomicU64,
+  commands_rate_limited: AtomicU64,
+  state_size_estimate: AtomicU64,
+  clone_rate_warning_threshold: AtomicU64,
+  clone_rate_warning_emitted: AtomicBool,
+  clone_rate_window: Mutex<CloneRateWindow>,
+  integrity_flags: Mutex<Vec<IntegrityFlag>>,
+}
+
+impl Metrics {
+  /// Number of projections which have been poisoned by a failing fold.
+  pub fn projections_poisoned(&self) -> u64 {
+    self.projections_poisoned.load(Ordering::Relaxed)
+  }
+
+  /// Record that a projection has been poisoned.
+  pub(crate) fn record_projection_poisoned(&self) {
+    self.projections_poisoned.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Every integrity problem recovered from so far, oldest first.
+  pub fn integrity_flags(&self) -> Vec<IntegrityFlag> {
+    self
+      .integrity_flags
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .clone()
+  }
+
+  /// Record an integrity problem which was recovered from.
+  pub(crate) fn record_integrity_flag(&self, flag: IntegrityFlag) {
+    self
+      .integrity_flags
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .push(flag);
+  }
+
+  /// Number of times a clone-based API such as `tap` cloned the whole state.
+  pub fn state_clones(&self) -> u64 {
+    self.state_clones.load(Ordering::Relaxed)
+  }
+
+  /// Scheduled snapshots which failed since the last snapshot which succeeded, see `SnapshotFailureMode`.
+  pub fn pending_snapshot_debt(&self) -> u64 {
+    self.pending_snapshot_debt.load(Ordering::Relaxed)
+  }
+
+  /// Record that a scheduled snapshot failed, returning the snapshots now overdue.
+  pub(crate) fn record_snapshot_failed(&self) -> u64 {
+    self.pending_snapshot_debt.fetch_add(1, Ordering::Relaxed) + 1
+  }
+
+  /// Record that a snapshot succeeded, so none are overdue.
+  pub(crate) fn record_snapshot_taken(&self) {
+    self.pending_snapshot_debt.store(0, Ordering::Relaxed);
+  }
+
+  /// Number of times `execute_query_cached` reused a cached output.
+  pub fn query_cache_hits(&self) -> u64 {
+    self.query_cache_hits.load(Ordering::Relaxed)
+  }
+
+  /// Number of times `execute_query_cached` ran its query because no output was cached.
+  pub fn query_cache_misses(&self) -> u64 {
+    self.query_cache_misses.load(Ordering::Relaxed)
+  }
+
+  /// Record that a cached query output was reused.
+  pub(crate) fn record_query_cache_hit(&self) {
+    self.query_cache_hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Record that a query ran because no output was cached.
+  pub(crate) fn record_query_cache_miss(&self) {
+    self.query_cache_misses.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Number of commands rejected by the store's rate limits, see `Madeleine::set_rate_limits`.
+  pub fn commands_rate_limited(&self) -> u64 {
+    self.commands_rate_limited.load(Ordering::Relaxed)
+  }
+
+  /// Record that commands were rejected by the rate limits.
+  pub(crate) fn record_rate_limited(&self, commands: u64) {
+    self
+      .commands_rate_limited
+      .fetch_add(commands, Ordering::Relaxed);
+  }
+
+  /// Estimated size of the state in bytes, taken from the size of the most recent snapshot written or read.
+  pub fn state_size_estimate(&self) -> u64 {
+    self.state_size_estimate.load(Ordering::Relaxed)
+  }
+
+  /// Warn once when clone-based APIs clone more than an estimated number of bytes of state per second.
+  /// Estimates are based on `state_size_estimate`. Pass `None` to turn the warning off, which is the default.
+  pub fn set_clone_rate_warning(&self, bytes_per_second: Option<u64>) {
+    self
+      .clone_rate_warning_threshold
+      .store(bytes_per_second.unwrap_or(0), Ordering::Relaxed);
+  }
+
+  /// Determine if the clone rate warning has been emitted.
+  pub fn clone_rate_warning_emitted(&self) -> bool {
+    self.clone_rate_warning_emitted.load(Ordering::Relaxed)
+  }
+
+  /// Record the size of the state as seen in a snapshot.
+  pub(crate) fn record_state_size(&self, bytes: u64) {
+    self.state_size_estimate.store(bytes, Ordering::Relaxed);
+  }
+
+  /// Record a clone of the whole state.
+  /// Returns `true` exactly once, when the estimated clone rate first exceeds the configured threshold.
+  pub(crate) fn record_state_clone(&self) -> bool {
+    self.state_clones.fetch_add(1, Ordering::Relaxed);
+
+    let threshold = self.clone_rate_warning_threshold.load(Ordering::Relaxed);
+
+    if threshold == 0 || self.clone_rate_warning_emitted() {
+      return false;
+    }
+
+    let mut window = self
+      .clone_rate_window
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let now = Instant::now();
+    let bytes = self.state_size_estimate();
+
+    match window.started_at {
+      Some(started_at) if now.duration_since(started_at) < Duration::from_secs(1) => {
+        window.bytes = window.bytes.saturating_add(bytes);
+      }
+      _ => {
+        window.started_at = Some(now);
+        window.bytes = bytes;
+      }
+    }
+
+    window.bytes > threshold
+      && !self
+        .clone_rate_warning_emitted
+        .swap(true, Ordering::Relaxed)
+  }
+
+  /// Turn per-phase timing of `execute_command` on or off. It is off by default,
+  /// in which case the only overhead is checking this flag once per phase.
+  pub fn set_phase_timing(&self, enabled: bool) {
+    self.phase_timing_enabled.store(enabled, Ordering::Relaxed);
+  }
+
+  /// Determine if per-phase timing is turned on.
+  pub fn phase_timing(&self) -> bool {
+    self.phase_timing_enabled.load(Ordering::Relaxed)
+  }
+
+  /// Latency histogram for a phase of `execute_command`.
+  pub fn phase_histogram(&self, phase: Phase) -> &Histogram {
+    &self.phase_histograms[phase.index()]
+  }
+
+  /// Latency histogram of executing commands, from `execute_command` being called to the command being logged
+  /// and applied. Always recorded, unlike the phase histograms. Each command of a batch is recorded as taking
+  /// an equal share of the batch's duration.
+  pub fn command_histogram(&self) -> &Histogram {
+    &self.command_histogram
+  }
+
+  /// Latency histogram of `tap` and `tap_ref`, including cloning the state for `tap` and running the closure.
+  pub fn tap_histogram(&self) -> &Histogram {
+    &self.tap_histogram
+  }
+
+  /// Number of commands executed since the instance was opened or the metrics were reset.
+  pub fn commands_executed(&self) -> u64 {
+    self.command_histogram.count()
+  }
+
+  /// Mean duration of executing a command, in nanoseconds, see `command_histogram`.
+  pub fn mean_execute_ns(&self) -> u64 {
+    u64::try_from(self.command_histogram.mean().as_nanos()).unwrap_or(u64::MAX)
+  }
+
+  /// Duration which 99% of commands took at most to execute, in nanoseconds, see `Histogram::quantile`.
+  pub fn p99_execute_ns(&self) -> u64 {
+    u64::try_from(self.command_histogram.quantile(0.99).as_nanos()).unwrap_or(u64::MAX)
+  }
+
+  /// Record that commands were executed, together taking `duration`.
+  pub(crate) fn record_commands_executed(&self, commands: u64, duration: Duration) {
+    if commands > 0 {
+      self.command_histogram.observe_many(
+        duration / u32::try_from(commands).unwrap_or(u32::MAX),
+        commands,
+      );
+    }
+  }
+
+  /// Record how long a `tap` took.
+  pub(crate) fn record_tap(&self, duration: Duration) {
+    self.tap_histogram.observe(duration);
+  }
+
+  /// Forget the latencies recorded in every histogram, e.g. after warming up a benchmark.
+  /// Counters such as `state_clones` are kept.
+  pub fn reset(&self) {
+    for histogram in self
+      .phase_histograms
+      .iter()
+      .chain([&self.command_histogram, &self.tap_histogram])
+    {
+      histogram.reset();
+    }
+  }
+
+  /// Run a closure, recording its duration against a phase if phase timing is turned on.
+  pub(crate) fn time_phase<T, F>(&self, phase: Phase, func: F) -> T
+  where
+    F: FnOnce() -> T,
+  {
+    if !self.phase_timing() {
+      return func();
+    }
+
+    let started_at = Instant::now();
+    let result = func();
+    self.phase_histogram(phase).observe(started_at.elapsed());
+
+    result
+  }
+
+  /// Render the metrics in the Prometheus text exposition format.
+  #[cfg(feature = "prometheus")]
+  pub fn to_prometheus(&self) -> String {
+    let mut output = String::new();
+
+    output.push_str(
+      "# HELP madeleine_projections_poisoned_total Projections poisoned by a failing fold.\n",
+    );
+    output.push_str("# TYPE madeleine_projections_poisoned_total counter\n");
+    output.push_str(&format!(
+      "madeleine_projections_poisoned_total {}\n",
+      self.projections_poisoned()
+    ));
+
+    output.push_str(
+      "# HELP madeleine_integrity_flags_total Integrity problems recovered from automatically.\n",
+    );
+    output.push_str("# TYPE madeleine_integrity_flags_total counter\n");
+    output.push_str(&format!(
+      "madeleine_integrity_flags_total {}\n",
+      self.integrity_flags().len()
+    ));
+
+    output.push_str(
+      "# HELP madeleine_pending_snapshot_debt Scheduled snapshots failed since the last success.\n",
+    );
+    output.push_str("# TYPE madeleine_pending_snapshot_debt gauge\n");
+    output.push_str(&format!(
+      "madeleine_pending_snapshot_debt {}\n",
+      self.pending_snapshot_debt()
+    ));
+
+    output.push_str("# HELP madeleine_query_cache_hits_total Cached query outputs reused.\n");
+    output.push_str("# TYPE madeleine_query_cache_hits_total counter\n");
+    output.push_str(&format!(
+      "madeleine_query_cache_hits_total {}\n",
+      self.query_cache_hits()
+    ));
+
+    output.push_str(
+      "# HELP madeleine_query_cache_misses_total Queries run because no output was cached.\n",
+    );
+    output.push_str("# TYPE madeleine_query_cache_misses_total counter\n");
+    output.push_str(&format!(
+      "madeleine_query_cache_misses_total {}\n",
+      self.query_cache_misses()
+    ));
+
+    output.push_str(
+      "# HELP madeleine_commands_rate_limited_total Commands rejected by the rate limits.\n",
+    );
+    output.push_str("# TYPE madeleine_commands_rate_limited_total counter\n");
+    output.push_str(&format!(
+      "madeleine_commands_rate_limited_total {}\n",
+      self.commands_rate_limited()
+    ));
+
+    output.push_str(
+      "# HELP madeleine_execute_phase_seconds Duration of each phase of execute_command.\n",
+    );
+    output.push_str("# TYPE madeleine_execute_phase_seconds histogram\n");
+
+    for phase in Phase::ALL {
+      push_prometheus_histogram(
+        &mut output,
+        "madeleine_execute_phase_seconds",
+        &format!("phase=\"{}\"", phase.name()),
+        self.phase_histogram(phase),
+      );
+    }
+
+    output.push_str("# HELP madeleine_command_seconds Duration of executing each command.\n");
+    output.push_str("# TYPE madeleine_command_seconds histogram\n");
+    push_prometheus_histogram(
+      &mut output,
+      "madeleine_command_seconds",
+      "",
+      self.command_histogram(),
+    );
+
+    output.push_str("# HELP madeleine_tap_seconds Duration of each tap of the state.\n");
+    output.push_str("# TYPE madeleine_tap_seconds histogram\n");
+    push_prometheus_histogram(
+      &mut output,
+      "madeleine_tap_seconds",
+      "",
+      self.tap_histogram(),
+    );
+
+    output
+  }
+}
+
+/// Render a histogram's buckets, sum and count in the Prometheus text exposition format,
+/// with `labels` such as `phase="execute"` added to each sample.
+#[cfg(feature = "prometheus")]
+fn push_prometheus_histogram(output: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+  let separator = if labels.is_empty() { "" } else { "," };
+  let mut cumulative = 0;
+
+  for (bound, count) in histogram.buckets() {
+    cumulative += count;
+
+    let le = match bound {
+      Some(bound) => bound.as_secs_f64().to_string(),
+      None => String::from("+Inf"),
+    };
+
+    output.push_str(&format!(
+      "{}_bucket{{{}{}le=\"{}\"}} {}\n",
+      name, labels, separator, le, cumulative
+    ));
+  }
+
+  let labels = if labels.is_empty() {
+    String::new()
+  } else {
+    format!("{{{}}}", labels)
+  };
+
+  output.push_str(&format!(
+    "{}_sum{} {}\n",
+    name,
+    labels,
+    histogram.sum().as_secs_f64()
+  ));
+  output.push_str(&format!("{}_count{} {}\n", name, labels, histogram.count()));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use std::thread;
+
+  use crate::{Command, Madeleine};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct SlowIncrement(u64);
+
+  impl Command<'_> for SlowIncrement {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      thread::sleep(Duration::from_millis(self.0));
+
+      old_state + 1
+    }
+  }
+
+  #[test]
+  fn test_histogram_buckets() {
+    let histogram = Histogram::default();
+
+    histogram.observe(Duration::from_nanos(500));
+    histogram.observe(Duration::from_millis(5));
+    histogram.observe(Duration::from_secs(60));
+
+    let counts: Vec<u64> = histogram
+      .buckets()
+      .iter()
+      .map(|(_, count)| *count)
+      .collect();
+
+    assert_eq!(counts, vec![1, 0, 0, 0, 1, 0, 0, 0, 1]);
+    assert_eq!(histogram.count(), 3);
+    assert_eq!(
+      histogram.buckets().last().map(|(bound, _)| *bound),
+      Some(None)
+    );
+  }
+
+  #[test]
+  fn test_histogram_quantiles_are_bucket_bounds_capped_by_the_max() {
+    let histogram = Histogram::default();
+
+    assert_eq!(histogram.quantile(0.99), Duration::ZERO);
+    assert_eq!(histogram.mean(), Duration::ZERO);
+
+    for _i in 0..98 {
+      histogram.observe(Duration::from_micros(5));
+    }
+
+    histogram.observe(Duration::from_millis(3));
+    histogram.observe(Duration::from_secs(20));
+
+    assert_eq!(histogram.quantile(0.5), Duration::from_micros(10));
+    assert_eq!(histogram.quantile(0.99), Duration::from_millis(10));
+    assert_eq!(histogram.quantile(1.0), Duration::from_secs(20));
+    assert_eq!(histogram.max(), Duration::from_secs(20));
+    assert_eq!(
+      histogram.mean(),
+      (Duration::from_micros(5) * 98 + Duration::from_millis(3) + Duration::from_secs(20)) / 100
+    );
+
+    histogram.reset();
+
+    assert_eq!(histogram.count(), 0);
+    assert_eq!(histogram.max(), Duration::ZERO);
+  }
+
+  #[test]
+  fn test_commands_and_taps_are_always_timed() {
+    let madeleine =
+      Madeleine::new_in_memory(|| 0).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(SlowIncrement(20))
+      .expect("unable to execute command in test");
+    madeleine
+      .execute_commands([SlowIncrement(0), SlowIncrement(0)])
+      .expect("unable to execute commands in test");
+
+    assert_eq!(madeleine.tap(|state| state), 3);
+    assert_eq!(
+      madeleine
+        .tap_ref(|state| *state)
+        .expect("unable to tap in test"),
+      3
+    );
+
+    let metrics = madeleine.metrics();
+
+    assert_eq!(metrics.commands_executed(), 3);
+    assert!(metrics.mean_execute_ns() >= 20_000_000 / 3);
+    assert!(metrics.p99_execute_ns() >= 20_000_000);
+    assert_eq!(metrics.tap_histogram().count(), 2);
+
+    metrics.reset();
+
+    assert_eq!(metrics.commands_executed(), 0);
+    assert_eq!(metrics.p99_execute_ns(), 0);
+    assert_eq!(metrics.tap_histogram().count(), 0);
+  }
+
+  #[test]
+  fn test_phase_timing_disabled_by_default() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(SlowIncrement(0))
+      .expect("unable to execute command in test");
+
+    for phase in Phase::ALL {
+      assert_eq!(madeleine.metrics().phase_histogram(phase).count(), 0);
+    }
+  }
+
+  #[test]
+  fn test_phase_timing_attributes_slow_execute() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine.metrics().set_phase_timing(true);
+
+    for _i in 0..3 {
+      madeleine
+        .execute_command(SlowIncrement(25))
+        .expect("unable to execute command in test");
+    }
+
+    let metrics = madeleine.metrics();
+    let execute = metrics.phase_histogram(Phase::Execute);
+    let append = metrics.phase_histogram(Phase::Append);
+
+    for phase in Phase::ALL {
+      assert_eq!(metrics.phase_histogram(phase).count(), 3);
+    }
+
+    assert!(execute.sum() >= Duration::from_millis(75));
+    assert!(append.sum() < Duration::from_millis(75));
+
+    let slow_executes: u64 = execute
+      .buckets()
+      .iter()
+      .filter(|(bound, _)| bound.is_none_or(|bound| bound > Duration::from_millis(10)))
+      .map(|(_, count)| count)
+      .sum();
+
+    assert_eq!(slow_executes, 3);
+  }
+
+  #[cfg(feature = "prometheus")]
+  #[test]
+  fn test_to_prometheus() {
+    let metrics = Metrics::default();
+
+    metrics.set_phase_timing(true);
+    metrics.time_phase(Phase::Append, || ());
+
+    let rendered = metrics.to_prometheus();
+
+    assert!(rendered.contains("madeleine_execute_phase_seconds_count{phase=\"append\"} 1"));
+    assert!(
+      rendered.contains("madeleine_execute_phase_seconds_bucket{phase=\"execute\",le=\"+Inf\"} 0")
+    );
+    assert!(rendered.contains("madeleine_projections_poisoned_total 0"));
+    assert!(rendered.contains("madeleine_query_cache_hits_total 0"));
+    assert!(rendered.contains("madeleine_command_seconds_bucket{le=\"+Inf\"} 0"));
+    assert!(rendered.contains("madeleine_tap_seconds_count 0"));
+  }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::locks::{read_recovering, write_recovering};
+
+/// Hooks called around the execution of each command, e.g. for logging, metrics or authorization,
+/// see `Madeleine::add_middleware`.
+///
+/// Both hooks are given the command's Rust type name, from `std::any::type_name`, to tell kinds of commands apart.
+/// A hook mustn't add middleware to the instance executing the command.
+pub trait CommandMiddleware: Send + Sync {
+  /// Called before a command is executed.
+  fn before(&self, type_name: &str);
+
+  /// Called once a command has been executed and logged, or has failed, with how long that took.
+  fn after(&self, type_name: &str, duration: Duration);
+}
+
+/// The middleware of an instance, called in the order it was added.
+#[derive(Default)]
+pub(crate) struct MiddlewareChain {
+  middleware: RwLock<Vec<Arc<dyn CommandMiddleware>>>,
+}
+
+impl MiddlewareChain {
+  pub fn add(&self, middleware: Box<dyn CommandMiddleware>) {
+    write_recovering(&self.middleware).push(Arc::from(middleware));
+  }
+
+  /// Run `execute`, which executes `count` commands of type `C`, between the `before` and `after` hooks of each one.
+  pub fn observe<C, T>(&self, count: usize, execute: impl FnOnce() -> T) -> T {
+    // Cloned, so that the hooks aren't called with the chain locked.
+    let middleware = read_recovering(&self.middleware).clone();
+
+    if middleware.is_empty() {
+      return execute();
+    }
+
+    let type_name = std::any::type_name::<C>();
+
+    for _ in 0..count {
+      for hook in &middleware {
+        hook.before(type_name);
+      }
+    }
+
+    let started = Instant::now();
+    let executed = execute();
+    let duration = started.elapsed();
+
+    for _ in 0..count {
+      for hook in &middleware {
+        hook.after(type_name, duration);
+      }
+    }
+
+    executed
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use std::sync::Mutex;
+
+  use crate::{BatchMode, Command, Madeleine};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Push(u8);
+
+  impl Command<'_> for Push {
+    type SystemState = Vec<u8>;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      let mut new_state = old_state;
+      new_state.push(self.0);
+
+      new_state
+    }
+  }
+
+  /// Records each hook called, labelled with the middleware's name.
+  struct Recorder {
+    name: &'static str,
+    calls: Arc<Mutex<Vec<String>>>,
+  }
+
+  impl CommandMiddleware for Recorder {
+    fn before(&self, type_name: &str) {
+      self
+        .calls
+        .lock()
+        .expect("unable to lock calls in test")
+        .push(format!("{} before {}", self.name, type_name));
+    }
+
+    fn after(&self, type_name: &str, _duration: Duration) {
+      self
+        .calls
+        .lock()
+        .expect("unable to lock calls in test")
+        .push(format!("{} after {}", self.name, type_name));
+    }
+  }
+
+  #[test]
+  fn test_middleware_is_called_in_order_around_commands() {
+    let madeleine =
+      Madeleine::new_in_memory(Vec::new).expect("unable to instantiate madeleine in test");
+
+    let calls = Arc::new(Mutex::new(Vec::new()));
+
+    for name in ["outer", "inner"] {
+      madeleine.add_middleware(Box::new(Recorder {
+        name,
+        calls: calls.clone(),
+      }));
+    }
+
+    madeleine
+      .execute_command(Push(1))
+      .expect("unable to execute command in test");
+    madeleine
+      .execute_batch(vec![Push(2), Push(3)], BatchMode::Atomic)
+      .expect("unable to execute batch in test");
+
+    let type_name = std::any::type_name::<Push>();
+    let single = vec![
+      format!("outer before {}", type_name),
+      format!("inner before {}", type_name),
+      format!("outer after {}", type_name),
+      format!("inner after {}", type_name),
+    ];
+    let batch = vec![
+      format!("outer before {}", type_name),
+      format!("inner before {}", type_name),
+      format!("outer before {}", type_name),
+      format!("inner before {}", type_name),
+      format!("outer after {}", type_name),
+      format!("inner after {}", type_name),
+      format!("outer after {}", type_name),
+      format!("inner after {}", type_name),
+    ];
+
+    assert_eq!(
+      *calls.lock().expect("unable to lock calls in test"),
+      [single, batch].concat()
+    );
+    assert_eq!(madeleine.tap(|state| state), vec![1, 2, 3]);
+  }
+}
@@ -0,0 +1,201 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use std::path::Path;
+
+use crate::admin_log::AdminMarker;
+use crate::madeleine_error::MadeleineError;
+use crate::metadata::StoreMetadata;
+
+/// A change of a store's state type, recorded in its metadata by `Madeleine::migrate_state`.
+///
+/// Commands logged up to `head_id` were executed against the old type. The migration's snapshot holds the state
+/// after them, so resuming from it replays only later commands, but replaying across the boundary, e.g. from an
+/// older snapshot or by a `ReadOnlyMadeleine`, needs the old command types and the same transform.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StateMigration {
+  /// Rust type name of the state before the migration.
+  pub from_type: String,
+  /// Rust type name of the state after the migration.
+  pub to_type: String,
+  /// Canonical hash of the state before the migration.
+  pub from_state_hash: String,
+  /// Canonical hash of the state after the migration.
+  pub to_state_hash: String,
+  /// Id of the snapshot holding the migrated state, the new baseline.
+  pub snapshot_id: usize,
+  /// ULID of the last command executed against the old type, or nil if none had been.
+  pub head_id: Ulid,
+  /// Position of the `AdminOperationKind::StateMigrated` operation in the admin log.
+  pub marker: AdminMarker,
+}
+
+/// Refuse to open a store as a state type it was migrated away from, see `Madeleine::migrate_state`.
+pub(crate) fn check_state_type<SystemState>(
+  location_dir_path: &Path,
+) -> Result<(), MadeleineError> {
+  let state_type = std::any::type_name::<SystemState>();
+  let migrations = StoreMetadata::read_state_migrations(location_dir_path)?;
+
+  let current_type = match migrations.last() {
+    Some(latest) => latest.to_type.as_str(),
+    None => return Ok(()),
+  };
+
+  match migrations
+    .iter()
+    .rev()
+    .find(|migration| migration.from_type == state_type)
+  {
+    Some(migration) if state_type != current_type => Err(MadeleineError::StateMigrated(format!(
+      "the state was migrated from {} to {} at snapshot {}, see admin operation {}; open the store as {}",
+      migration.from_type, migration.to_type, migration.snapshot_id, migration.marker, current_type
+    ))),
+    _ => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::admin_log::{admin_ops_since, AdminOperationKind};
+  use crate::{Command, Madeleine};
+
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct CounterV1 {
+    count: u64,
+  }
+
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct CounterV2 {
+    count: u64,
+    label: String,
+  }
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct AddV1(u64);
+
+  impl Command<'_> for AddV1 {
+    type SystemState = CounterV1;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      CounterV1 {
+        count: old_state.count + self.0,
+      }
+    }
+  }
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct AddV2(u64);
+
+  impl Command<'_> for AddV2 {
+    type SystemState = CounterV2;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      CounterV2 {
+        count: old_state.count + self.0,
+        ..old_state
+      }
+    }
+  }
+
+  fn upgrade(old: CounterV1) -> CounterV2 {
+    CounterV2 {
+      count: old.count,
+      label: String::from("panda"),
+    }
+  }
+
+  #[test]
+  fn test_migrated_state_becomes_new_baseline() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || CounterV1 { count: 0 })
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(AddV1(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let pre_migration_head = madeleine.head_id().expect("unable to read head in test");
+
+    drop(madeleine);
+
+    // Commands logged after the latest snapshot would be lost.
+    let unsnapshotted = Madeleine::<CounterV2>::migrate_state(store_path.clone(), upgrade);
+
+    assert!(matches!(
+      unsnapshotted.err(),
+      Some(MadeleineError::SnapshotError(_))
+    ));
+
+    Madeleine::<CounterV1>::resume_replaying::<AddV1, _>(store_path.clone(), || CounterV1 {
+      count: 0,
+    })
+    .and_then(|madeleine| madeleine.take_snapshot(false))
+    .expect("unable to take snapshot in test");
+
+    let migrated = Madeleine::<CounterV2>::migrate_state(store_path.clone(), upgrade)
+      .expect("unable to migrate state in test");
+
+    assert_eq!(
+      migrated.tap(|state| state),
+      CounterV2 {
+        count: 3,
+        label: String::from("panda"),
+      }
+    );
+
+    let migrations = migrated
+      .state_migrations()
+      .expect("unable to read migrations in test");
+
+    assert_eq!(migrations.len(), 1);
+    assert_eq!(migrations[0].from_type, std::any::type_name::<CounterV1>());
+    assert_eq!(migrations[0].to_type, std::any::type_name::<CounterV2>());
+    assert_eq!(migrations[0].head_id, pre_migration_head);
+    assert_eq!(
+      migrations[0].to_state_hash,
+      migrated.state_hash().expect("unable to hash state in test")
+    );
+
+    let operations = admin_ops_since(&store_path, AdminMarker::default())
+      .expect("unable to read admin log in test");
+
+    assert_eq!(
+      operations.last().map(|operation| operation.marker),
+      Some(migrations[0].marker)
+    );
+    assert!(matches!(
+      operations.last().map(|operation| &operation.kind),
+      Some(AdminOperationKind::StateMigrated { snapshot_id, .. }) if *snapshot_id == migrations[0].snapshot_id
+    ));
+
+    migrated
+      .execute_command(AddV2(4))
+      .expect("unable to execute command in test");
+
+    drop(migrated);
+
+    let refused = Madeleine::<CounterV1>::resume(store_path.clone());
+
+    assert!(matches!(
+      refused.err(),
+      Some(MadeleineError::StateMigrated(ref message)) if message.contains(&migrations[0].marker.to_string())
+    ));
+
+    let resumed = Madeleine::<CounterV2>::resume_replaying::<AddV2, _>(store_path, || {
+      unreachable!("the migration's snapshot is resumed from")
+    })
+    .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state.count), 7);
+  }
+}
@@ -0,0 +1,503 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::madeleine_error::MadeleineError;
+use crate::sealed::Sealed;
+
+/// Starts every log entry serialized with bincode, followed by the command's ULID as 16 bytes, then the command.
+/// JSON entries start with `[`, and compressed ones with a NUL byte, so entries of either format can be told apart.
+const BINCODE_ENTRY_MAGIC: &[u8] = b"\x01mb";
+
+/// Starts every log entry serialized with MessagePack, which is otherwise framed like a bincode entry.
+const MESSAGE_PACK_ENTRY_MAGIC: &[u8] = b"\x01mp";
+
+/// Serializes commands into the payloads of log entries, and back. Rather than picking a format with a feature flag,
+/// a store is created with one of the implementations here, `JsonSerializer`, `MessagePackSerializer`,
+/// or `BincodeSerializer` with the `bincode` feature, passed to `MadeleineBuilder::serializer`.
+///
+/// Each entry starts with a tag identifying its serializer's `format`, which is also recorded in the store's metadata,
+/// so opening a store with a different serializer fails with `MadeleineError::PayloadFormatMismatch`
+/// rather than misreading its commands. Every build must be able to read an entry back from its tag alone,
+/// so the trait is sealed, and only this crate's serializers implement it.
+///
+/// ```
+/// use madeleine::{CommandSerializer, JsonSerializer, MessagePackSerializer};
+///
+/// let json = JsonSerializer.serialize(&(1, "two"))?;
+/// let message_pack = MessagePackSerializer.serialize(&(1, "two"))?;
+///
+/// assert_eq!(json, b"[1,\"two\"]");
+/// assert!(message_pack.len() < json.len());
+/// assert_eq!(
+///   MessagePackSerializer.deserialize::<(u8, String)>(&message_pack)?,
+///   (1, String::from("two"))
+/// );
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait CommandSerializer: Sealed {
+  /// The format of the payloads, which tags every entry and is recorded in the store's metadata.
+  fn format(&self) -> PayloadFormat;
+
+  /// Serialize a command into a payload.
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError>;
+
+  /// Deserialize a command from a payload written by `serialize`.
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError>;
+}
+
+/// Serializes commands as JSON, which every feature can read. The default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonSerializer;
+
+impl Sealed for JsonSerializer {}
+
+impl CommandSerializer for JsonSerializer {
+  fn format(&self) -> PayloadFormat {
+    PayloadFormat::Json
+  }
+
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError> {
+    Ok(serde_json::to_vec(command)?)
+  }
+
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError> {
+    Ok(serde_json::from_slice(payload)?)
+  }
+}
+
+/// Serializes commands as MessagePack, more compact than JSON. Unlike bincode it keeps struct field names,
+/// so commands can gain fields with `#[serde(default)]`.
+/// Features which read commands without knowing their type need JSON, and fail with `MadeleineError::PayloadFormatMismatch`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessagePackSerializer;
+
+impl Sealed for MessagePackSerializer {}
+
+impl CommandSerializer for MessagePackSerializer {
+  fn format(&self) -> PayloadFormat {
+    PayloadFormat::MessagePack
+  }
+
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError> {
+    Ok(rmp_serde::to_vec_named(command)?)
+  }
+
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError> {
+    Ok(rmp_serde::from_slice(payload)?)
+  }
+}
+
+/// Serializes commands with bincode, smaller and faster to encode and decode than JSON.
+/// Features which read commands without knowing their type need JSON, as with MessagePack.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BincodeSerializer;
+
+#[cfg(feature = "bincode")]
+impl Sealed for BincodeSerializer {}
+
+#[cfg(feature = "bincode")]
+impl CommandSerializer for BincodeSerializer {
+  fn format(&self) -> PayloadFormat {
+    PayloadFormat::Bincode
+  }
+
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError> {
+    Ok(bincode::serialize(command)?)
+  }
+
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError> {
+    Ok(bincode::deserialize(payload)?)
+  }
+}
+
+/// How commands are serialized in a store's command log, naming one of the `CommandSerializer`s.
+/// It's chosen when the store is created and recorded in its metadata, so asking for a different format later
+/// fails rather than mixing them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum PayloadFormat {
+  /// JSON, see `JsonSerializer`.
+  #[default]
+  Json,
+  /// bincode, available with the `bincode` feature, see `BincodeSerializer`.
+  /// Features which read commands without knowing their type, such as projections, tenants, audits,
+  /// exports and redaction, need JSON, and fail with `MadeleineError::PayloadFormatMismatch`.
+  Bincode,
+  /// MessagePack, see `MessagePackSerializer`.
+  MessagePack,
+}
+
+impl PayloadFormat {
+  /// Fail with `MadeleineError::MissingCapability` unless this build can read and write the format.
+  pub(crate) fn check_available(self) -> Result<(), MadeleineError> {
+    match self {
+      #[cfg(not(feature = "bincode"))]
+      Self::Bincode => Err(missing_bincode()),
+      _ => Ok(()),
+    }
+  }
+
+  /// The format's name, for error messages.
+  pub(crate) fn name(self) -> &'static str {
+    match self {
+      Self::Json => "JSON",
+      Self::Bincode => "bincode",
+      Self::MessagePack => "MessagePack",
+    }
+  }
+
+  /// The tag starting entries in this format, or `None` for JSON, whose entries are arrays.
+  fn entry_magic(self) -> Option<&'static [u8]> {
+    match self {
+      Self::Json => None,
+      Self::Bincode => Some(BINCODE_ENTRY_MAGIC),
+      Self::MessagePack => Some(MESSAGE_PACK_ENTRY_MAGIC),
+    }
+  }
+
+  /// The format of a log entry, told by its tag.
+  pub(crate) fn of_entry(entry: &[u8]) -> Self {
+    if entry.starts_with(BINCODE_ENTRY_MAGIC) {
+      Self::Bincode
+    } else if entry.starts_with(MESSAGE_PACK_ENTRY_MAGIC) {
+      Self::MessagePack
+    } else {
+      Self::Json
+    }
+  }
+
+  /// Serialize a command and its ULID into a log entry: a JSON array of both,
+  /// or the format's tag followed by the ULID as 16 bytes, then the payload.
+  pub(crate) fn encode_entry<C: Serialize>(
+    self,
+    id: Ulid,
+    command: &C,
+  ) -> Result<Vec<u8>, MadeleineError> {
+    let payload = CommandSerializer::serialize(&self, command)?;
+
+    match self.entry_magic() {
+      None => {
+        let mut entry = b"[".to_vec();
+        entry.extend_from_slice(&serde_json::to_vec(&id)?);
+        entry.push(b',');
+        entry.extend_from_slice(&payload);
+        entry.push(b']');
+
+        Ok(entry)
+      }
+      Some(magic) => {
+        let mut entry = magic.to_vec();
+        entry.extend_from_slice(&id.to_bytes());
+        entry.extend_from_slice(&payload);
+
+        Ok(entry)
+      }
+    }
+  }
+
+  /// Deserialize a command from a payload, as split from its entry by `split_binary_entry` or re-serialized as JSON.
+  pub(crate) fn decode_payload<C: DeserializeOwned>(
+    self,
+    payload: &[u8],
+  ) -> Result<C, MadeleineError> {
+    CommandSerializer::deserialize(&self, payload)
+  }
+}
+
+impl Sealed for PayloadFormat {}
+
+/// The serializer a format names, so that the command log can serialize with whichever its store uses.
+impl CommandSerializer for PayloadFormat {
+  fn format(&self) -> PayloadFormat {
+    *self
+  }
+
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError> {
+    match self {
+      Self::Json => JsonSerializer.serialize(command),
+      #[cfg(feature = "bincode")]
+      Self::Bincode => BincodeSerializer.serialize(command),
+      #[cfg(not(feature = "bincode"))]
+      Self::Bincode => Err(missing_bincode()),
+      Self::MessagePack => MessagePackSerializer.serialize(command),
+    }
+  }
+
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError> {
+    match self {
+      Self::Json => JsonSerializer.deserialize(payload),
+      #[cfg(feature = "bincode")]
+      Self::Bincode => BincodeSerializer.deserialize(payload),
+      #[cfg(not(feature = "bincode"))]
+      Self::Bincode => Err(missing_bincode()),
+      Self::MessagePack => MessagePackSerializer.deserialize(payload),
+    }
+  }
+}
+
+/// Split a bincode or MessagePack log entry into the command's ULID and its payload.
+pub(crate) fn split_binary_entry(entry: &[u8]) -> Result<(Ulid, &[u8]), MadeleineError> {
+  let framed = entry
+    .strip_prefix(BINCODE_ENTRY_MAGIC)
+    .or_else(|| entry.strip_prefix(MESSAGE_PACK_ENTRY_MAGIC))
+    .unwrap_or(entry);
+
+  match framed.split_first_chunk::<16>() {
+    Some((id, payload)) => Ok((Ulid::from_bytes(*id), payload)),
+    None => {
+      let message = String::from("entry ends before its ULID");
+
+      match PayloadFormat::of_entry(entry) {
+        PayloadFormat::MessagePack => Err(MadeleineError::MessagePackError(message)),
+        _ => Err(MadeleineError::BincodeError(message)),
+      }
+    }
+  }
+}
+
+/// The ULID of a log entry of either format.
+pub(crate) fn entry_id(entry: &[u8]) -> Result<Ulid, MadeleineError> {
+  match PayloadFormat::of_entry(entry) {
+    PayloadFormat::Json => {
+      let (id, _command): (Ulid, serde::de::IgnoredAny) = serde_json::from_slice(entry)?;
+
+      Ok(id)
+    }
+    PayloadFormat::Bincode | PayloadFormat::MessagePack => {
+      split_binary_entry(entry).map(|(id, _payload)| id)
+    }
+  }
+}
+
+/// A JSON log entry, for features which read commands without knowing their type.
+/// Fails with `MadeleineError::PayloadFormatMismatch` for other entries, naming the feature.
+pub(crate) fn require_json_entry<'e>(
+  entry: &'e [u8],
+  feature: &str,
+) -> Result<&'e [u8], MadeleineError> {
+  match PayloadFormat::of_entry(entry) {
+    PayloadFormat::Json => Ok(entry),
+    format => Err(MadeleineError::PayloadFormatMismatch(format!(
+      "{} needs JSON payloads, but the log holds {} payloads",
+      feature,
+      format.name()
+    ))),
+  }
+}
+
+#[cfg(not(feature = "bincode"))]
+fn missing_bincode() -> MadeleineError {
+  MadeleineError::MissingCapability(String::from("bincode payloads need the bincode feature"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_existing_store_rejects_other_payload_format() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(location.clone(), || 0_u64).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    assert_eq!(madeleine.payload_format(), PayloadFormat::Json);
+
+    drop(madeleine);
+
+    let error = Madeleine::builder(location)
+      .payload_format(PayloadFormat::Bincode)
+      .build(|| 0_u64)
+      .err();
+
+    assert!(matches!(
+      error,
+      Some(MadeleineError::PayloadFormatMismatch(_))
+    ));
+  }
+
+  #[test]
+  fn test_payloads_round_trip_in_every_available_format() {
+    let mut formats = vec![PayloadFormat::Json, PayloadFormat::MessagePack];
+
+    if cfg!(feature = "bincode") {
+      formats.push(PayloadFormat::Bincode);
+    }
+
+    for format in formats {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+      let location = temp_dir.path().join("test_store");
+
+      let madeleine = Madeleine::builder(location.clone())
+        .payload_format(format)
+        .build(|| 0_u64)
+        .expect("unable to instantiate madeleine in test");
+
+      for amount in [1, 2, u64::from(u32::MAX)] {
+        madeleine
+          .execute_command(Add(amount))
+          .expect("unable to execute command in test");
+      }
+
+      let commands = madeleine
+        .follower()
+        .commands_after(Ulid::nil())
+        .expect("unable to read commands in test");
+
+      assert!(commands.iter().all(|logged| logged.format == format));
+      assert_eq!(
+        commands
+          .iter()
+          .map(|logged| logged.deserialize::<Add>().ok())
+          .collect::<Vec<_>>(),
+        vec![Some(Add(1)), Some(Add(2)), Some(Add(u64::from(u32::MAX)))]
+      );
+
+      drop(madeleine);
+
+      let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(location, || 0)
+        .expect("unable to resume madeleine in test");
+
+      assert_eq!(resumed.payload_format(), format);
+      assert_eq!(resumed.tap(|state| state), 3 + u64::from(u32::MAX));
+    }
+  }
+
+  #[cfg(not(feature = "bincode"))]
+  #[test]
+  fn test_bincode_store_needs_feature() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let error = Madeleine::builder(temp_dir.path().join("test_store"))
+      .payload_format(PayloadFormat::Bincode)
+      .build(|| 0_u64)
+      .err();
+
+    assert!(matches!(error, Some(MadeleineError::MissingCapability(_))));
+  }
+
+  #[test]
+  fn test_serializers_round_trip_the_same_command() {
+    let json = JsonSerializer
+      .serialize(&Add(613))
+      .expect("unable to serialize in test");
+    let message_pack = MessagePackSerializer
+      .serialize(&Add(613))
+      .expect("unable to serialize in test");
+
+    assert_eq!(json, b"613".to_vec());
+    assert_ne!(json, message_pack);
+    assert_eq!(
+      JsonSerializer.deserialize::<Add>(&json).ok(),
+      Some(Add(613))
+    );
+    assert_eq!(
+      MessagePackSerializer.deserialize::<Add>(&message_pack).ok(),
+      Some(Add(613))
+    );
+
+    let entry = PayloadFormat::Json
+      .encode_entry(Ulid::nil(), &Add(613))
+      .expect("unable to encode entry in test");
+
+    assert_eq!(
+      entry,
+      serde_json::to_vec(&(Ulid::nil(), Add(613))).expect("unable to serialize in test")
+    );
+  }
+
+  #[test]
+  fn test_message_pack_store_rejects_json() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::builder(location.clone())
+      .serializer(MessagePackSerializer)
+      .build(|| 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    let entry = PayloadFormat::MessagePack
+      .encode_entry(Ulid::nil(), &Add(1))
+      .expect("unable to encode entry in test");
+
+    assert!(matches!(
+      require_json_entry(&entry, "exporting"),
+      Err(MadeleineError::PayloadFormatMismatch(message)) if message.contains("MessagePack")
+    ));
+
+    drop(madeleine);
+
+    let error = Madeleine::builder(location)
+      .serializer(JsonSerializer)
+      .build(|| 0_u64)
+      .err();
+
+    assert!(matches!(
+      error,
+      Some(MadeleineError::PayloadFormatMismatch(_))
+    ));
+  }
+
+  #[cfg(feature = "bincode")]
+  #[test]
+  fn test_bincode_payloads_round_trip() {
+    use crate::export::{ExportFormat, ExportRange};
+
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::builder(location.clone())
+      .payload_format(PayloadFormat::Bincode)
+      .build(|| 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for amount in [1, 2, 3] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let commands = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert!(commands
+      .iter()
+      .all(|logged| logged.format == PayloadFormat::Bincode));
+    assert_eq!(commands[2].deserialize::<Add>().ok(), Some(Add(3)));
+
+    // Exports read commands without knowing their type, so they need JSON.
+    let error = madeleine
+      .export(ExportFormat::Jsonl, ExportRange::default(), Vec::new())
+      .expect_err("bincode payloads exported in test");
+
+    assert!(matches!(error, MadeleineError::PayloadFormatMismatch(_)));
+
+    drop(madeleine);
+
+    // Resuming without asking for a format uses the store's.
+    let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(location, || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.payload_format(), PayloadFormat::Bincode);
+    assert_eq!(resumed.tap(|state| state), 6);
+  }
+}
@@ -0,0 +1,7 @@
+pub use crate::builder::MadeleineBuilder;
+pub use crate::command::{Command, CommandWithOutput, MutCommand, TryCommand};
+pub use crate::logged_command::RawLoggedCommand as LoggedCommand;
+pub use crate::madeleine::Madeleine;
+pub use crate::madeleine_error::MadeleineError;
+pub use crate::query::Query;
+pub use crate::snapshot_policy::SnapshotPolicy;
@@ -0,0 +1,94 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+
+use serde::de::DeserializeOwned;
+
+/// Type-erased view of a projection so that projections over different commands and read models can live side by side.
+/// Projections are `Send` so that a `Madeleine` can move between threads.
+pub(crate) trait ErasedProjection: Send {
+  /// Tag of the type of command folded into the projection, compared with the type of each executed command.
+  /// Projections live in memory only, so like the type names given to middleware, it's never persisted.
+  fn command_type(&self) -> &'static str;
+
+  /// Fold a single command, which must be of the projection's type, into the projection.
+  /// Returns `true` if this call caused the projection to become poisoned,
+  /// or fails if the command doesn't deserialize as the projection's type.
+  fn apply(&mut self, command: &serde_json::Value) -> Result<bool, serde_json::Error>;
+
+  /// Determine if a previous fold failed, leaving the projection unusable.
+  fn is_poisoned(&self) -> bool;
+
+  /// Access the current read model for downcasting.
+  fn state(&self) -> &dyn Any;
+
+  /// Return the projection to its initial value, clearing any poisoning.
+  fn reset(&mut self);
+}
+
+/// Function folding a single command into a projection's read model.
+type Fold<C, P> = Box<dyn Fn(&mut P, &C) + Send>;
+
+/// A derived read model maintained incrementally by folding commands of type `C` into a value of type `P`.
+pub(crate) struct Projection<C, P> {
+  init: P,
+  state: P,
+  fold: Fold<C, P>,
+  poisoned: bool,
+  command_type: PhantomData<fn(C)>,
+}
+
+impl<C, P: Clone> Projection<C, P> {
+  /// Constructor function.
+  pub fn new<F>(init: P, fold: F) -> Self
+  where
+    F: Fn(&mut P, &C) + Send + 'static,
+  {
+    Self {
+      state: init.clone(),
+      init,
+      fold: Box::new(fold),
+      poisoned: false,
+      command_type: PhantomData,
+    }
+  }
+}
+
+impl<C, P> ErasedProjection for Projection<C, P>
+where
+  C: DeserializeOwned,
+  P: Clone + Send + 'static,
+{
+  fn command_type(&self) -> &'static str {
+    std::any::type_name::<C>()
+  }
+
+  fn apply(&mut self, command: &serde_json::Value) -> Result<bool, serde_json::Error> {
+    if self.poisoned {
+      return Ok(false);
+    }
+
+    let command = C::deserialize(command)?;
+
+    let fold = &self.fold;
+    let state = &mut self.state;
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| fold(state, &command)));
+
+    self.poisoned = outcome.is_err();
+
+    Ok(self.poisoned)
+  }
+
+  fn is_poisoned(&self) -> bool {
+    self.poisoned
+  }
+
+  fn state(&self) -> &dyn Any {
+    &self.state
+  }
+
+  fn reset(&mut self) {
+    self.state = self.init.clone();
+    self.poisoned = false;
+  }
+}
@@ -0,0 +1,416 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+
+/// A read-only computation over a store's state, executed with `Madeleine::execute_query`.
+/// Queries which are `Serialize`, with outputs which are `Clone` and `Serialize`, can also be cached,
+/// see `Madeleine::execute_query_cached`.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// use madeleine::{Madeleine, Query};
+///
+/// /// Counts the records starting with a prefix.
+/// struct CountPrefixed(&'static str);
+///
+/// impl Query for CountPrefixed {
+///   type SystemState = Vec<String>;
+///   type Output = usize;
+///
+///   fn execute(&self, state: &Vec<String>) -> usize {
+///     state.iter().filter(|record| record.starts_with(self.0)).count()
+///   }
+/// }
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, || {
+///   vec![String::from("apple"), String::from("avocado"), String::from("banana")]
+/// })?;
+///
+/// assert_eq!(madeleine.execute_query(&CountPrefixed("a"))?, 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub trait Query {
+  /// The type of the `Madeleine` instance's internal state.
+  type SystemState;
+  /// What the query computes.
+  type Output;
+
+  /// Core logic for a Query, left to the implementor to specify.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::Query;
+  ///
+  /// struct IsAbove(u64);
+  ///
+  /// impl Query for IsAbove {
+  ///   type SystemState = u64;
+  ///   type Output = bool;
+  ///
+  ///   fn execute(&self, state: &u64) -> bool {
+  ///     *state > self.0
+  ///   }
+  /// }
+  ///
+  /// assert!(IsAbove(1).execute(&2));
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, || 0)?;
+  /// madeleine.execute_command(Add(2))?;
+  ///
+  /// assert!(madeleine.execute_query(&IsAbove(1))?);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  fn execute(&self, state: &Self::SystemState) -> Self::Output;
+}
+
+/// Limits on what's remembered by a query cache, see `Madeleine::set_query_cache`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryCacheOptions {
+  /// Most outputs cached. The least recently used are evicted first.
+  pub max_entries: usize,
+  /// Most bytes of output cached, measured by each output's serialized size.
+  /// Outputs larger than this are never cached.
+  pub max_bytes: usize,
+}
+
+impl Default for QueryCacheOptions {
+  fn default() -> Self {
+    Self {
+      max_entries: 1_000,
+      max_bytes: 16 * 1024 * 1024,
+    }
+  }
+}
+
+/// A cached query output.
+struct CachedOutput {
+  output: Box<dyn Any + Send>,
+  bytes: usize,
+  last_used: u64,
+}
+
+/// Outputs of queries against the state as of one head, dropped as soon as the head moves on.
+/// Disabled until options are set.
+#[derive(Default)]
+pub(crate) struct QueryCache {
+  options: Mutex<Option<QueryCacheOptions>>,
+  cached: Mutex<CachedOutputs>,
+}
+
+/// The outputs cached against one head, with their total size.
+#[derive(Default)]
+struct CachedOutputs {
+  head_id: Ulid,
+  entries: HashMap<String, CachedOutput>,
+  bytes: usize,
+  clock: u64,
+}
+
+impl QueryCache {
+  /// Enable the cache with the given limits, or disable it. Either way, everything cached is forgotten.
+  pub fn set_options(&self, options: Option<QueryCacheOptions>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.options) = options;
+
+    self.invalidate()
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    lock_recovering(&self.options).is_some()
+  }
+
+  /// Forget everything cached, e.g. because the state changed without a new command.
+  pub fn invalidate(&self) -> Result<(), MadeleineError> {
+    lock_recovering(&self.cached).clear();
+
+    Ok(())
+  }
+
+  /// The cached output of a query against the state as of `head_id`.
+  pub fn lookup<O: Clone + 'static>(
+    &self,
+    key: &str,
+    head_id: Ulid,
+  ) -> Result<Option<O>, MadeleineError> {
+    let mut cached = lock_recovering(&self.cached);
+
+    cached.advance_to(head_id);
+
+    let last_used = cached.tick();
+
+    let Some(output) = cached.entries.get_mut(key) else {
+      return Ok(None);
+    };
+
+    output.last_used = last_used;
+
+    Ok(output.output.downcast_ref::<O>().cloned())
+  }
+
+  /// Cache the output of a query against the state as of `head_id`,
+  /// evicting the least recently used outputs to stay within the limits.
+  pub fn insert<O: Serialize + Send + 'static>(
+    &self,
+    key: String,
+    head_id: Ulid,
+    output: O,
+  ) -> Result<(), MadeleineError> {
+    let Some(options) = *lock_recovering(&self.options) else {
+      return Ok(());
+    };
+
+    let bytes = serde_json::to_vec(&output)?.len();
+
+    if bytes > options.max_bytes || options.max_entries == 0 {
+      return Ok(());
+    }
+
+    let mut cached = lock_recovering(&self.cached);
+
+    cached.advance_to(head_id);
+
+    if let Some(replaced) = cached.entries.remove(&key) {
+      cached.bytes -= replaced.bytes;
+    }
+
+    while cached.entries.len() >= options.max_entries || cached.bytes + bytes > options.max_bytes {
+      let Some(least_recently_used) = cached
+        .entries
+        .iter()
+        .min_by_key(|(_key, output)| output.last_used)
+        .map(|(key, _output)| key.clone())
+      else {
+        break;
+      };
+
+      if let Some(evicted) = cached.entries.remove(&least_recently_used) {
+        cached.bytes -= evicted.bytes;
+      }
+    }
+
+    let last_used = cached.tick();
+
+    cached.entries.insert(
+      key,
+      CachedOutput {
+        output: Box::new(output),
+        bytes,
+        last_used,
+      },
+    );
+    cached.bytes += bytes;
+
+    Ok(())
+  }
+}
+
+impl CachedOutputs {
+  fn clear(&mut self) {
+    self.entries.clear();
+    self.bytes = 0;
+  }
+
+  /// Forget everything cached against an earlier head.
+  fn advance_to(&mut self, head_id: Ulid) {
+    if self.head_id != head_id {
+      self.head_id = head_id;
+      self.clear();
+    }
+  }
+
+  fn tick(&mut self) -> u64 {
+    self.clock += 1;
+
+    self.clock
+  }
+}
+
+/// Identify a query by its type and serialized form, so equal queries share a cached output.
+pub(crate) fn query_cache_key<Q: Serialize>(query: &Q) -> Result<String, MadeleineError> {
+  Ok(format!(
+    "{}:{}",
+    std::any::type_name::<Q>(),
+    serde_json::to_string(query)?
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::Deserialize;
+
+  use crate::{Command, Madeleine, ReadOnlyMadeleine, SharedMadeleine};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Push(u64);
+
+  impl Command<'_> for Push {
+    type SystemState = Vec<u64>;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      let mut new_state = old_state;
+
+      new_state.push(self.0);
+
+      new_state
+    }
+  }
+
+  /// The values at least as large as the threshold.
+  #[derive(Debug, Serialize)]
+  struct AtLeast(u64);
+
+  impl Query for AtLeast {
+    type SystemState = Vec<u64>;
+    type Output = Vec<u64>;
+
+    fn execute(&self, state: &Self::SystemState) -> Self::Output {
+      state
+        .iter()
+        .copied()
+        .filter(|value| *value >= self.0)
+        .collect()
+    }
+  }
+
+  fn cached_store(
+    temp_dir: &assert_fs::TempDir,
+    options: QueryCacheOptions,
+  ) -> Madeleine<Vec<u64>> {
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), Vec::new)
+      .expect("unable to instantiate madeleine in test");
+
+    for value in [1, 2, 3] {
+      madeleine
+        .execute_command(Push(value))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+      .set_query_cache(Some(options))
+      .expect("unable to enable query cache in test");
+
+    madeleine
+  }
+
+  #[test]
+  fn test_queries_read_every_handle_without_logging() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(location.clone(), Vec::new).expect("unable to instantiate madeleine in test");
+
+    for value in [1, 2, 3] {
+      madeleine
+        .execute_command(Push(value))
+        .expect("unable to execute command in test");
+    }
+
+    let head_id = madeleine.head_id().expect("unable to read head in test");
+
+    assert_eq!(madeleine.execute_query(&AtLeast(2)).ok(), Some(vec![2, 3]));
+
+    let mut replica = ReadOnlyMadeleine::<Push, _>::open(location, Vec::new)
+      .expect("unable to open replica in test");
+
+    assert_eq!(replica.execute_query(&AtLeast(3)), vec![3]);
+
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    assert_eq!(shared.execute_query(&AtLeast(1)).ok(), Some(vec![1, 2, 3]));
+
+    // Nothing was logged by the queries.
+    assert_eq!(replica.catch_up().ok(), Some(0));
+    assert_eq!(replica.head_id(), head_id);
+    assert_eq!(shared.len().ok(), Some(3));
+  }
+
+  #[test]
+  fn test_cached_output_reused_until_head_moves() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = cached_store(&temp_dir, QueryCacheOptions::default());
+
+    for _i in 0..3 {
+      assert_eq!(
+        madeleine.execute_query_cached(&AtLeast(2)).ok(),
+        Some(vec![2, 3])
+      );
+    }
+
+    assert_eq!(madeleine.metrics().query_cache_hits(), 2);
+    assert_eq!(madeleine.metrics().query_cache_misses(), 1);
+
+    madeleine
+      .execute_command(Push(4))
+      .expect("unable to execute command in test");
+
+    assert_eq!(
+      madeleine.execute_query_cached(&AtLeast(2)).ok(),
+      Some(vec![2, 3, 4])
+    );
+    assert_eq!(
+      madeleine.execute_query_cached(&AtLeast(3)).ok(),
+      Some(vec![3, 4])
+    );
+    assert_eq!(madeleine.metrics().query_cache_misses(), 3);
+    assert_eq!(
+      madeleine.execute_query(&AtLeast(1)).ok(),
+      Some(vec![1, 2, 3, 4])
+    );
+    assert_eq!(madeleine.metrics().query_cache_misses(), 3);
+  }
+
+  #[test]
+  fn test_least_recently_used_evicted_within_limits() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = cached_store(
+      &temp_dir,
+      QueryCacheOptions {
+        max_entries: 2,
+        max_bytes: 8,
+      },
+    );
+
+    // `[2,3]` and `[3]` serialize to 8 bytes together, but `[1,2,3]` takes 7 bytes alone.
+    for threshold in [2, 3, 2, 1, 1] {
+      madeleine
+        .execute_query_cached(&AtLeast(threshold))
+        .expect("unable to query in test");
+    }
+
+    assert_eq!(madeleine.metrics().query_cache_hits(), 2);
+    assert_eq!(madeleine.metrics().query_cache_misses(), 3);
+
+    // `[3]` evicts `[1,2,3]`, after which `[2,3]` fits beside it.
+    for threshold in [3, 2, 3] {
+      madeleine
+        .execute_query_cached(&AtLeast(threshold))
+        .expect("unable to query in test");
+    }
+
+    assert_eq!(madeleine.metrics().query_cache_hits(), 3);
+    assert_eq!(madeleine.metrics().query_cache_misses(), 5);
+
+    madeleine
+      .set_query_cache(None)
+      .expect("unable to disable query cache in test");
+    madeleine
+      .execute_query_cached(&AtLeast(3))
+      .expect("unable to query in test");
+
+    assert_eq!(madeleine.metrics().query_cache_hits(), 3);
+  }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+
+/// Percentages of a limit at which `ResourceWarning`s are raised by default.
+const DEFAULT_WARNING_THRESHOLDS: [u8; 2] = [80, 90];
+
+/// A resource whose usage can be limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+  /// Bytes of command log segments and snapshots on disk.
+  StoreBytes,
+  /// Number of commands in the log.
+  Commands,
+}
+
+/// What an operator can do to bring usage back down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestedAction {
+  /// Take a snapshot and discard the commands before it.
+  Compact,
+  /// Delete old snapshots.
+  PruneSnapshots,
+}
+
+/// Usage of a resource crossed one of the soft warning thresholds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceWarning {
+  /// The resource concerned.
+  pub resource: Resource,
+  /// Current usage.
+  pub usage: u64,
+  /// The hard limit, at which commands are rejected.
+  pub limit: u64,
+  /// The threshold crossed, as a percentage of the limit.
+  pub threshold_percent: u8,
+  /// What to do about it.
+  pub suggested_actions: Vec<SuggestedAction>,
+}
+
+/// Hard limits on a store's resources, with soft thresholds to warn ahead of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quotas {
+  /// Most bytes of command log segments and snapshots. Approximate between snapshots.
+  pub max_store_bytes: Option<u64>,
+  /// Most commands in the log.
+  pub max_commands: Option<u64>,
+  /// Percentages of each limit at which to raise a `ResourceWarning`.
+  pub warning_thresholds: Vec<u8>,
+}
+
+impl Default for Quotas {
+  fn default() -> Self {
+    Self {
+      max_store_bytes: None,
+      max_commands: None,
+      warning_thresholds: DEFAULT_WARNING_THRESHOLDS.to_vec(),
+    }
+  }
+}
+
+/// Current usage of a store's resources alongside its quotas, e.g. for dashboards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceUsage {
+  /// Approximate bytes of command log segments and snapshots.
+  pub store_bytes: u64,
+  /// Number of commands in the log.
+  pub commands: u64,
+  /// The configured quotas.
+  pub quotas: Quotas,
+}
+
+/// Callback invoked with each `ResourceWarning`.
+pub type ResourceWarningHook = Box<dyn Fn(&ResourceWarning) + Send>;
+
+/// Tracks usage against quotas, rejecting commands at the hard limits
+/// and raising each warning once per crossing of its threshold.
+#[derive(Default)]
+pub(crate) struct QuotaTracker {
+  quotas: Mutex<Quotas>,
+  store_bytes: AtomicU64,
+  raised: Mutex<BTreeSet<(Resource, u8)>>,
+  hook: Mutex<Option<ResourceWarningHook>>,
+}
+
+impl QuotaTracker {
+  pub fn set_quotas(&self, quotas: Quotas, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.quotas) = quotas;
+    lock_recovering(&self.raised).clear();
+    self.measure_store_bytes(location_dir_path)
+  }
+
+  pub fn set_hook(&self, hook: Option<ResourceWarningHook>) -> Result<(), MadeleineError> {
+    *lock_recovering(&self.hook) = hook;
+
+    Ok(())
+  }
+
+  fn is_enabled(&self) -> Result<bool, MadeleineError> {
+    let quotas = lock_recovering(&self.quotas);
+
+    Ok(quotas.max_store_bytes.is_some() || quotas.max_commands.is_some())
+  }
+
+  /// Measure the store's size on disk. Index files are left out, since they're preallocated.
+  pub fn measure_store_bytes(&self, location_dir_path: &Path) -> Result<(), MadeleineError> {
+    if self.is_enabled()? {
+      self
+        .store_bytes
+        .store(dir_bytes(location_dir_path)?, Ordering::Relaxed);
+    }
+
+    Ok(())
+  }
+
+  pub fn record_append(&self, bytes: u64) {
+    self.store_bytes.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  /// Reject a command if a hard limit has been reached.
+  pub fn check_limits(&self, commands: u64) -> Result<(), MadeleineError> {
+    for (resource, usage, limit) in self.usages(commands)? {
+      if usage >= limit {
+        return Err(MadeleineError::QuotaExceeded(format!(
+          "{:?} usage {} has reached the limit of {}",
+          resource, usage, limit
+        )));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Raise warnings for newly crossed thresholds, and re-arm those usage has dropped back below.
+  /// The warnings raised are passed to the hook and returned.
+  pub fn check_thresholds(&self, commands: u64) -> Result<Vec<ResourceWarning>, MadeleineError> {
+    let thresholds = lock_recovering(&self.quotas).warning_thresholds.clone();
+    let mut warnings = Vec::new();
+
+    {
+      let mut raised = lock_recovering(&self.raised);
+
+      for (resource, usage, limit) in self.usages(commands)? {
+        for &threshold_percent in &thresholds {
+          let crossed =
+            u128::from(usage) * 100 >= u128::from(limit) * u128::from(threshold_percent);
+
+          if !crossed {
+            raised.remove(&(resource, threshold_percent));
+          } else if raised.insert((resource, threshold_percent)) {
+            warnings.push(ResourceWarning {
+              resource,
+              usage,
+              limit,
+              threshold_percent,
+              suggested_actions: suggested_actions(resource),
+            });
+          }
+        }
+      }
+    }
+
+    if let Some(hook) = lock_recovering(&self.hook).as_ref() {
+      for warning in &warnings {
+        hook(warning);
+      }
+    }
+
+    Ok(warnings)
+  }
+
+  pub fn usage(&self, commands: u64) -> Result<ResourceUsage, MadeleineError> {
+    Ok(ResourceUsage {
+      store_bytes: self.store_bytes.load(Ordering::Relaxed),
+      commands,
+      quotas: lock_recovering(&self.quotas).clone(),
+    })
+  }
+
+  /// Usage of each limited resource, with its limit.
+  fn usages(&self, commands: u64) -> Result<Vec<(Resource, u64, u64)>, MadeleineError> {
+    let quotas = lock_recovering(&self.quotas);
+
+    let usages = [
+      (
+        Resource::StoreBytes,
+        self.store_bytes.load(Ordering::Relaxed),
+        quotas.max_store_bytes,
+      ),
+      (Resource::Commands, commands, quotas.max_commands),
+    ]
+    .into_iter()
+    .filter_map(|(resource, usage, limit)| limit.map(|limit| (resource, usage, limit)))
+    .collect();
+
+    Ok(usages)
+  }
+}
+
+fn suggested_actions(resource: Resource) -> Vec<SuggestedAction> {
+  match resource {
+    Resource::StoreBytes => vec![SuggestedAction::Compact, SuggestedAction::PruneSnapshots],
+    Resource::Commands => vec![SuggestedAction::Compact],
+  }
+}
+
+fn dir_bytes(path: &Path) -> Result<u64, MadeleineError> {
+  let mut bytes = 0;
+
+  if !path.is_dir() {
+    return Ok(bytes);
+  }
+
+  for entry in fs::read_dir(path)? {
+    let entry = entry?;
+    let metadata = entry.metadata()?;
+
+    if metadata.is_dir() {
+      bytes += dir_bytes(&entry.path())?;
+    } else if entry
+      .path()
+      .extension()
+      .is_none_or(|extension| extension != "index")
+    {
+      bytes += metadata.len();
+    }
+  }
+
+  Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::{Arc, Mutex};
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  fn collecting_hook() -> (ResourceWarningHook, Arc<Mutex<Vec<ResourceWarning>>>) {
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let collected = warnings.clone();
+
+    let hook: ResourceWarningHook = Box::new(move |warning: &ResourceWarning| {
+      collected
+        .lock()
+        .expect("unable to lock warnings in test")
+        .push(warning.clone());
+    });
+
+    (hook, warnings)
+  }
+
+  #[test]
+  fn test_warnings_before_hard_limit() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let (hook, warnings) = collecting_hook();
+
+    madeleine
+      .set_resource_warning_hook(Some(hook))
+      .expect("unable to set hook in test");
+    madeleine
+      .set_quotas(Quotas {
+        max_commands: Some(10),
+        ..Quotas::default()
+      })
+      .expect("unable to set quotas in test");
+
+    for _i in 0..10 {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    let raised: Vec<(Resource, u64, u8)> = warnings
+      .lock()
+      .expect("unable to lock warnings in test")
+      .iter()
+      .map(|warning| (warning.resource, warning.usage, warning.threshold_percent))
+      .collect();
+
+    assert_eq!(
+      raised,
+      vec![(Resource::Commands, 8, 80), (Resource::Commands, 9, 90)]
+    );
+    assert!(matches!(
+      madeleine.execute_command(Add(1)),
+      Err(MadeleineError::QuotaExceeded(_))
+    ));
+
+    let usage = madeleine
+      .resource_usage()
+      .expect("unable to read usage in test");
+
+    assert_eq!(usage.commands, 10);
+    assert_eq!(usage.quotas.max_commands, Some(10));
+    assert!(usage.store_bytes > 0);
+  }
+
+  #[test]
+  fn test_dropping_below_threshold_rearms_warning() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let tracker = QuotaTracker::default();
+    let (hook, warnings) = collecting_hook();
+
+    tracker
+      .set_hook(Some(hook))
+      .expect("unable to set hook in test");
+    tracker
+      .set_quotas(
+        Quotas {
+          max_commands: Some(10),
+          warning_thresholds: vec![80],
+          ..Quotas::default()
+        },
+        temp_dir.path(),
+      )
+      .expect("unable to set quotas in test");
+
+    for commands in [8, 9, 5, 8] {
+      tracker
+        .check_thresholds(commands)
+        .expect("unable to check thresholds in test");
+    }
+
+    let usages: Vec<u64> = warnings
+      .lock()
+      .expect("unable to lock warnings in test")
+      .iter()
+      .map(|warning| warning.usage)
+      .collect();
+
+    assert_eq!(usages, vec![8, 8]);
+  }
+}
@@ -0,0 +1,404 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::locks::lock_recovering;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::PayloadFormat;
+use crate::sealed::Sealed;
+
+/// Source of the current time for rate limiting and maintenance, replaceable in tests, see `testing::ManualClock`.
+/// It's sealed, so `SystemClock` and `ManualClock` are the only implementations.
+pub trait Clock: Sealed + fmt::Debug + Send + Sync {
+  /// The current time.
+  fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, used unless another is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Sealed for SystemClock {}
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/// A token bucket's rate and size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+  /// Tokens added to the bucket each second.
+  pub per_second: u64,
+  /// Most tokens the bucket holds, and so the most admitted at once after a quiet spell.
+  pub burst: u64,
+}
+
+impl RateLimit {
+  /// A limit whose burst is one second's worth of tokens.
+  pub fn per_second(per_second: u64) -> Self {
+    Self {
+      per_second,
+      burst: per_second,
+    }
+  }
+
+  fn check(&self, name: &str) -> Result<(), MadeleineError> {
+    if self.per_second == 0 || self.burst == 0 {
+      return Err(MadeleineError::InvalidConfig(format!(
+        "the {} rate limit's rate and burst must be positive",
+        name
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+/// Limits on how quickly commands are executed, see `Madeleine::set_rate_limits`.
+/// Commands over either limit fail with `MadeleineError::RateLimited` before they're executed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+  /// Commands per second, unlimited by default.
+  pub commands: Option<RateLimit>,
+  /// Bytes of serialized commands per second, before compression, unlimited by default.
+  /// A command larger than the burst is admitted once the bucket is full, leaving it in debt.
+  pub bytes: Option<RateLimit>,
+  /// Longest `SharedMadeleine::execute_command_async` waits for the limits to admit a command
+  /// before failing, zero by default.
+  pub max_async_delay: Duration,
+}
+
+impl RateLimits {
+  /// Whether any limit is set.
+  pub fn is_limited(&self) -> bool {
+    self.commands.is_some() || self.bytes.is_some()
+  }
+}
+
+/// Tokens available, as of when they were last refilled.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+  tokens: f64,
+  refilled_at: Instant,
+}
+
+impl TokenBucket {
+  fn full(limit: &RateLimit, now: Instant) -> Self {
+    Self {
+      tokens: limit.burst as f64,
+      refilled_at: now,
+    }
+  }
+
+  fn refill(&mut self, limit: &RateLimit, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.refilled_at);
+
+    self.tokens =
+      (self.tokens + elapsed.as_secs_f64() * limit.per_second as f64).min(limit.burst as f64);
+    self.refilled_at = now;
+  }
+
+  /// How long until `cost` tokens, or a full bucket if it's smaller, are available.
+  fn wait_for(&self, limit: &RateLimit, cost: u64) -> Duration {
+    let needed = cost.min(limit.burst) as f64;
+
+    if self.tokens >= needed {
+      Duration::ZERO
+    } else {
+      Duration::from_secs_f64((needed - self.tokens) / limit.per_second as f64)
+    }
+  }
+}
+
+/// Bytes the commands take in the log, before compression, as counted by the byte rate limit.
+pub(crate) fn logged_bytes<C: Serialize>(
+  payload_format: PayloadFormat,
+  commands: &[C],
+) -> Result<u64, MadeleineError> {
+  commands.iter().try_fold(0, |bytes, command| {
+    Ok(bytes + payload_format.encode_entry(Ulid::nil(), command)?.len() as u64)
+  })
+}
+
+/// Token buckets for a store's `RateLimits`.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+  limits: Mutex<RateLimits>,
+  /// Buckets for commands and bytes, refilled lazily whenever commands are admitted.
+  buckets: Mutex<(Option<TokenBucket>, Option<TokenBucket>)>,
+  clock: Mutex<Arc<dyn Clock>>,
+}
+
+impl Default for RateLimiter {
+  fn default() -> Self {
+    Self {
+      limits: Mutex::new(RateLimits::default()),
+      buckets: Mutex::new((None, None)),
+      clock: Mutex::new(Arc::new(SystemClock)),
+    }
+  }
+}
+
+impl RateLimiter {
+  pub fn limits(&self) -> RateLimits {
+    *lock_recovering(&self.limits)
+  }
+
+  /// Replace the limits, starting each limited bucket full.
+  pub fn set_limits(&self, limits: RateLimits) -> Result<(), MadeleineError> {
+    if let Some(commands) = &limits.commands {
+      commands.check("commands")?;
+    }
+
+    if let Some(bytes) = &limits.bytes {
+      bytes.check("bytes")?;
+    }
+
+    let now = self.now();
+    let mut buckets = lock_recovering(&self.buckets);
+
+    *buckets = (
+      limits.commands.map(|limit| TokenBucket::full(&limit, now)),
+      limits.bytes.map(|limit| TokenBucket::full(&limit, now)),
+    );
+    *lock_recovering(&self.limits) = limits;
+
+    Ok(())
+  }
+
+  pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+    *lock_recovering(&self.clock) = clock;
+  }
+
+  pub fn clock(&self) -> Arc<dyn Clock> {
+    lock_recovering(&self.clock).clone()
+  }
+
+  /// Take tokens for `commands` commands of `bytes` bytes, which is only called if bytes are limited,
+  /// or fail with `MadeleineError::RateLimited` without taking any if either bucket is short.
+  pub fn admit<B>(&self, commands: u64, bytes: B) -> Result<(), MadeleineError>
+  where
+    B: FnOnce() -> Result<u64, MadeleineError>,
+  {
+    self.take(commands, bytes, true)
+  }
+
+  /// How long until `admit` would take tokens for `commands` commands of `bytes` bytes, without taking any.
+  #[cfg(feature = "async")]
+  pub fn wait<B>(&self, commands: u64, bytes: B) -> Result<Duration, MadeleineError>
+  where
+    B: FnOnce() -> Result<u64, MadeleineError>,
+  {
+    match self.take(commands, bytes, false) {
+      Ok(()) => Ok(Duration::ZERO),
+      Err(MadeleineError::RateLimited { retry_after }) => Ok(retry_after),
+      Err(error) => Err(error),
+    }
+  }
+
+  fn take<B>(&self, commands: u64, bytes: B, take_tokens: bool) -> Result<(), MadeleineError>
+  where
+    B: FnOnce() -> Result<u64, MadeleineError>,
+  {
+    let limits = self.limits();
+
+    if !limits.is_limited() {
+      return Ok(());
+    }
+
+    let bytes = match limits.bytes {
+      Some(_) => bytes()?,
+      None => 0,
+    };
+    let now = self.now();
+    let mut buckets = lock_recovering(&self.buckets);
+    let (command_bucket, byte_bucket) = &mut *buckets;
+    let mut retry_after = Duration::ZERO;
+
+    for (bucket, limit, cost) in [
+      (command_bucket, limits.commands, commands),
+      (byte_bucket, limits.bytes, bytes),
+    ] {
+      if let (Some(bucket), Some(limit)) = (bucket.as_mut(), limit) {
+        bucket.refill(&limit, now);
+        retry_after = retry_after.max(bucket.wait_for(&limit, cost));
+      }
+    }
+
+    if retry_after > Duration::ZERO {
+      return Err(MadeleineError::RateLimited { retry_after });
+    }
+
+    if !take_tokens {
+      return Ok(());
+    }
+
+    if let Some(bucket) = buckets.0.as_mut() {
+      bucket.tokens -= commands as f64;
+    }
+
+    if let Some(bucket) = buckets.1.as_mut() {
+      bucket.tokens -= bytes as f64;
+    }
+
+    Ok(())
+  }
+
+  fn now(&self) -> Instant {
+    lock_recovering(&self.clock).now()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::{Add, ManualClock};
+  use crate::{BatchMode, Madeleine};
+
+  fn limited_madeleine(
+    temp_dir: &assert_fs::TempDir,
+    limits: RateLimits,
+  ) -> (Madeleine<u64>, Arc<ManualClock>) {
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+    let clock = Arc::new(ManualClock::new());
+
+    madeleine.set_clock(clock.clone());
+    madeleine
+      .set_rate_limits(limits)
+      .expect("unable to set rate limits in test");
+
+    (madeleine, clock)
+  }
+
+  fn retry_after(result: Result<u64, MadeleineError>) -> Option<Duration> {
+    match result {
+      Err(MadeleineError::RateLimited { retry_after }) => Some(retry_after),
+      _ => None,
+    }
+  }
+
+  #[test]
+  fn test_command_bucket_refills_with_clock() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (madeleine, clock) = limited_madeleine(
+      &temp_dir,
+      RateLimits {
+        commands: Some(RateLimit {
+          per_second: 2,
+          burst: 3,
+        }),
+        ..RateLimits::default()
+      },
+    );
+
+    for _i in 0..3 {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    assert_eq!(
+      retry_after(madeleine.execute_command(Add(1))),
+      Some(Duration::from_millis(500))
+    );
+
+    clock.advance(Duration::from_millis(400));
+
+    assert_eq!(
+      retry_after(madeleine.execute_command(Add(1))),
+      Some(Duration::from_millis(100))
+    );
+
+    clock.advance(Duration::from_millis(100));
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    // A long quiet spell refills the bucket only up to its burst,
+    // and a batch bigger than the burst is admitted once it's full, leaving it in debt.
+    clock.advance(Duration::from_secs(60));
+
+    madeleine
+      .execute_batch(vec![Add(1), Add(1), Add(1), Add(1)], BatchMode::Atomic)
+      .expect("unable to execute batch in test");
+
+    assert_eq!(
+      retry_after(madeleine.execute_command(Add(1))),
+      Some(Duration::from_secs(1))
+    );
+
+    assert_eq!(madeleine.tap(|state| state), 8);
+    assert_eq!(madeleine.len(), 8);
+    assert_eq!(madeleine.metrics().commands_rate_limited(), 3);
+  }
+
+  #[test]
+  fn test_byte_bucket_admits_large_command_when_full() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (madeleine, clock) = limited_madeleine(
+      &temp_dir,
+      RateLimits {
+        bytes: Some(RateLimit::per_second(10)),
+        ..RateLimits::default()
+      },
+    );
+
+    // Every entry is larger than the burst, so it's admitted when the bucket is full, leaving it in debt.
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    let bytes = logged_bytes(PayloadFormat::Json, &[Add(1)]).expect("unable to measure in test");
+    let retry_after = retry_after(madeleine.execute_command(Add(1)))
+      .expect("expected the command to be rate limited in test");
+
+    // Refilling the debt and then a full burst takes as many tokens as the entry has bytes.
+    assert_eq!(retry_after, Duration::from_secs_f64(bytes as f64 / 10.0));
+
+    clock.advance(retry_after);
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    madeleine
+      .set_rate_limits(RateLimits::default())
+      .expect("unable to set rate limits in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    assert_eq!(madeleine.tap(|state| state), 3);
+  }
+
+  #[test]
+  fn test_invalid_limits_rejected() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let error = madeleine
+      .set_rate_limits(RateLimits {
+        commands: Some(RateLimit::per_second(0)),
+        ..RateLimits::default()
+      })
+      .expect_err("zero rate accepted in test");
+
+    assert!(matches!(error, MadeleineError::InvalidConfig(_)));
+    assert_eq!(madeleine.rate_limits(), RateLimits::default());
+  }
+}
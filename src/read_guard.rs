@@ -0,0 +1,233 @@
+use std::ops::Deref;
+use std::sync::{Arc, RwLockReadGuard};
+
+use ulid::Ulid;
+
+use crate::locks::StateRead;
+
+/// A borrow of a store's state, returned by `Madeleine::read`, for reads which don't fit in a closure.
+///
+/// While any guard is held, commands wait for it to be dropped, and on the thread holding it
+/// `Madeleine::execute_command` fails with `MadeleineError::ReentrantAccess` rather than waiting forever.
+/// Hold it for as short a time as possible, and never across an `await`.
+/// Strict mode doesn't check reads made through a guard.
+pub struct StateReadGuard<'a, SystemState> {
+  state: StateRead<'a, SystemState>,
+}
+
+impl<'a, SystemState> StateReadGuard<'a, SystemState> {
+  pub(crate) fn new(state: StateRead<'a, SystemState>) -> Self {
+    Self { state }
+  }
+}
+
+impl<SystemState> Deref for StateReadGuard<'_, SystemState> {
+  type Target = SystemState;
+
+  fn deref(&self) -> &Self::Target {
+    &self.state
+  }
+}
+
+/// A read lock on a `SharedMadeleine`, taken with `SharedMadeleine::read_lock`.
+///
+/// Other readers carry on while it's held, and so do commands, but they wait until it's dropped to publish their state.
+/// Hold it for as short a time as possible, and never across an `await`; use an `ArcStateSnapshot` instead.
+pub struct ReadLock<'a, SystemState> {
+  published: RwLockReadGuard<'a, ArcStateSnapshot<SystemState>>,
+}
+
+impl<'a, SystemState> ReadLock<'a, SystemState> {
+  pub(crate) fn new(published: RwLockReadGuard<'a, ArcStateSnapshot<SystemState>>) -> Self {
+    Self { published }
+  }
+
+  /// Borrow the state. Commands can't publish their state while the lock is held, so it can't change.
+  pub fn read(&self) -> &SystemState {
+    &self.published
+  }
+
+  /// The ULID of the last command applied to the state, see `Madeleine::head_id`.
+  pub fn head_id(&self) -> Ulid {
+    self.published.head_id()
+  }
+}
+
+/// An owned copy of a store's state, which holds no borrow or lock and so can be kept across `await` points
+/// or sent to other threads. Returned by `Madeleine::arc_snapshot` and `SharedMadeleine::arc_snapshot`.
+///
+/// Taking one clones the state, which is cheap if the state is itself built from `Arc`s.
+#[derive(Debug)]
+pub struct ArcStateSnapshot<SystemState> {
+  state: Arc<SystemState>,
+  head_id: Ulid,
+}
+
+impl<SystemState> ArcStateSnapshot<SystemState> {
+  pub(crate) fn new(state: SystemState, head_id: Ulid) -> Self {
+    Self {
+      state: Arc::new(state),
+      head_id,
+    }
+  }
+
+  /// The ULID of the last command applied to the state when it was copied, see `Madeleine::head_id`.
+  pub fn head_id(&self) -> Ulid {
+    self.head_id
+  }
+
+  /// Share the copied state without the snapshot.
+  pub fn into_arc(self) -> Arc<SystemState> {
+    self.state
+  }
+}
+
+impl<SystemState> Clone for ArcStateSnapshot<SystemState> {
+  fn clone(&self) -> Self {
+    Self {
+      state: self.state.clone(),
+      head_id: self.head_id,
+    }
+  }
+}
+
+impl<SystemState> Deref for ArcStateSnapshot<SystemState> {
+  type Target = SystemState;
+
+  fn deref(&self) -> &Self::Target {
+    &self.state
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::{Madeleine, MadeleineError, SharedMadeleine};
+
+  #[test]
+  fn test_guard_blocks_commands_until_dropped() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+
+    let guard = madeleine.read();
+    let other_guard = madeleine.try_read().expect("unable to read in test");
+
+    assert_eq!(*guard, 1);
+    assert_eq!(*other_guard, 1);
+
+    let blocked = madeleine.execute_command(Add(2));
+
+    assert!(matches!(blocked, Err(MadeleineError::ReentrantAccess(_))));
+    assert_eq!(*guard, 1);
+
+    drop(guard);
+    drop(other_guard);
+
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command after dropping guards in test");
+
+    assert_eq!(*madeleine.read(), 3);
+  }
+
+  #[test]
+  fn test_read_lock_contention() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    let read_lock = shared
+      .read_lock()
+      .expect("unable to take read lock in test");
+
+    let other_read_lock = shared
+      .try_read_lock()
+      .expect("unable to take a second read lock in test");
+
+    assert_eq!(*other_read_lock.read(), 0);
+
+    drop(other_read_lock);
+
+    let (executed_sender, executed_receiver) = mpsc::channel();
+    let writer = shared.clone();
+    let writing = thread::spawn(move || {
+      let result = writer.execute_command(Add(5)).map(|_offset| ());
+      executed_sender
+        .send(())
+        .expect("unable to signal execution in test");
+      result
+    });
+
+    assert!(executed_receiver
+      .recv_timeout(Duration::from_millis(100))
+      .is_err());
+    assert_eq!(*read_lock.read(), 0);
+
+    drop(read_lock);
+
+    executed_receiver
+      .recv_timeout(Duration::from_secs(5))
+      .expect("writer still blocked in test");
+    writing
+      .join()
+      .expect("writer panicked in test")
+      .expect("unable to execute command in test");
+
+    let read_lock = shared
+      .try_read_lock()
+      .expect("unable to take uncontended read lock in test");
+
+    assert_eq!(*read_lock.read(), 5);
+  }
+
+  #[test]
+  fn test_arc_snapshot_outlives_lock() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    for amount in [1, 2] {
+      shared
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let snapshot = shared
+      .arc_snapshot()
+      .expect("unable to take snapshot in test");
+    let head_id = shared
+      .read_lock()
+      .map(|read_lock| read_lock.head_id())
+      .expect("unable to read head in test");
+
+    shared
+      .execute_command(Add(3))
+      .expect("unable to execute command in test");
+
+    let copy = snapshot.clone();
+    let seen = thread::spawn(move || *copy)
+      .join()
+      .expect("reader panicked in test");
+
+    assert_eq!(seen, 3);
+    assert_eq!(snapshot.head_id(), head_id);
+    assert_eq!(*snapshot.into_arc(), 3);
+    assert_eq!(shared.tap_ref(|state| *state).ok(), Some(6));
+  }
+}
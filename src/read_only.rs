@@ -0,0 +1,176 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command::{Command, CommandContext};
+use crate::follower::Follower;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine_error::MadeleineError;
+use crate::query::Query;
+use crate::store_path::StorePath;
+
+/// A read-only replica of a store written by another instance, possibly in another process.
+/// It applies the writer's commands of type `C` to its own copy of the state, but only when asked to,
+/// so the state never changes while it's being read.
+pub struct ReadOnlyMadeleine<C, SystemState> {
+  follower: Follower,
+  state: SystemState,
+  head_id: Ulid,
+  command_type: PhantomData<fn(C)>,
+}
+
+impl<C, SystemState> ReadOnlyMadeleine<C, SystemState>
+where
+  C: for<'a> Command<'a, SystemState = SystemState>,
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+{
+  /// Open a store, replaying every command logged so far into the state built by `constructor`.
+  pub fn open<F>(location: impl Into<StorePath>, constructor: F) -> Result<Self, MadeleineError>
+  where
+    F: FnOnce() -> SystemState,
+  {
+    let mut replica = Self {
+      follower: Follower::open(location)?,
+      state: constructor(),
+      head_id: Ulid::nil(),
+      command_type: PhantomData,
+    };
+
+    replica.catch_up()?;
+
+    Ok(replica)
+  }
+
+  /// Apply every command logged since the last catch up, returning how many were applied.
+  pub fn catch_up(&mut self) -> Result<usize, MadeleineError> {
+    let commands = self.follower.commands_after(self.head_id)?;
+
+    self.apply(&commands)
+  }
+
+  /// Wait up to `timeout` for new commands, then apply them, returning how many were applied.
+  pub fn wait_and_catch_up(&mut self, timeout: Duration) -> Result<usize, MadeleineError> {
+    let commands = self.follower.wait_for_commands(self.head_id, timeout)?;
+
+    self.apply(&commands)
+  }
+
+  /// ULID of the last command applied, or `Ulid::nil()` if none have been.
+  pub fn head_id(&self) -> Ulid {
+    self.head_id
+  }
+
+  /// Run a closure passed the state and the ULID of the last command applied to it,
+  /// so that a report made of several reads sees one consistent state and can record which.
+  pub fn read_transaction<T, O>(&self, func: O) -> T
+  where
+    O: FnOnce(&SystemState, Ulid) -> T,
+  {
+    func(&self.state, self.head_id)
+  }
+
+  /// Run a query against the replica's state, see `Query`.
+  pub fn execute_query<Q>(&self, query: &Q) -> Q::Output
+  where
+    Q: Query<SystemState = SystemState>,
+  {
+    query.execute(&self.state)
+  }
+
+  /// Consume the replica and return its state.
+  pub fn into_inner(self) -> SystemState {
+    self.state
+  }
+
+  fn apply(&mut self, commands: &[RawLoggedCommand]) -> Result<usize, MadeleineError> {
+    let commands_compacted = self.follower.commands_compacted()?;
+
+    for logged in commands {
+      self.head_id = logged.id;
+
+      if logged.is_skipped_on_replay() {
+        continue;
+      }
+
+      let command: C = logged.deserialize()?;
+
+      self.state = command.execute_with_ctx(
+        self.state.clone(),
+        &CommandContext::at(commands_compacted + logged.offset),
+      );
+    }
+
+    Ok(commands.len())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  #[test]
+  fn test_read_transaction_is_pinned_while_writer_continues() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let writer = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..3 {
+      writer
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    let mut replica = ReadOnlyMadeleine::<Add, u64>::open(store_path, || 0_u64)
+      .expect("unable to open replica in test");
+
+    let writer_head = writer.head_id().expect("unable to get head in test");
+
+    let (first, second, head_id) = replica.read_transaction(|state, head_id| {
+      let first = *state;
+
+      writer
+        .execute_command(Add(10))
+        .expect("unable to execute command in test");
+
+      (first, *state, head_id)
+    });
+
+    assert_eq!((first, second), (3, 3));
+    assert_eq!(head_id, writer_head);
+
+    assert_eq!(replica.catch_up().ok(), Some(1));
+    assert_eq!(replica.read_transaction(|state, _head_id| *state), 13);
+    assert_eq!(
+      replica.head_id(),
+      writer.head_id().expect("unable to get head in test")
+    );
+  }
+
+  #[test]
+  fn test_empty_store_has_nil_head() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let _writer = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let replica = ReadOnlyMadeleine::<Add, u64>::open(store_path, || 0_u64)
+      .expect("unable to open replica in test");
+
+    assert_eq!(
+      replica.read_transaction(|state, head_id| (*state, head_id)),
+      (0, Ulid::nil())
+    );
+  }
+}
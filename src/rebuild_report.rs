@@ -0,0 +1,39 @@
+/// A single correction made by `Madeleine::rebuild_derived`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebuildChange {
+  /// The recorded latest snapshot id didn't match the newest snapshot present on disk.
+  SnapshotIdReset {
+    /// The id which was recorded, if any.
+    recorded: Option<usize>,
+    /// The id of the newest snapshot on disk, if any.
+    actual: Option<usize>,
+  },
+  /// The hash of the latest snapshot file, used to deduplicate snapshots, was out of date.
+  SnapshotHashRefreshed {
+    /// The id of the snapshot file now tracked, if any.
+    snapshot_id: Option<usize>,
+  },
+  /// A poisoned projection was rebuilt from the log.
+  ProjectionRebuilt {
+    /// The projection's name.
+    name: String,
+    /// Whether the projection was poisoned again while rebuilding.
+    poisoned: bool,
+  },
+}
+
+/// Outcome of `Madeleine::rebuild_derived`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildReport {
+  /// Number of commands in the log.
+  pub command_count: u64,
+  /// Every correction made, empty if the store was already consistent.
+  pub changes: Vec<RebuildChange>,
+}
+
+impl RebuildReport {
+  /// Determine if the rebuild found nothing to correct.
+  pub fn is_noop(&self) -> bool {
+    self.changes.is_empty()
+  }
+}
@@ -0,0 +1,56 @@
+use std::marker::PhantomData;
+
+use json_patch::Patch;
+use serde::{Deserialize, Serialize};
+
+use crate::command::Command;
+
+/// Built-in command which moves the state to a desired value by applying an RFC 6902 JSON patch.
+/// Logged by `Madeleine::reconcile`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(bound = "")]
+pub struct ReconcileCommand<SystemState> {
+  patch: Patch,
+  #[serde(skip)]
+  system_state: PhantomData<fn() -> SystemState>,
+}
+
+impl<SystemState> ReconcileCommand<SystemState> {
+  /// Compute the patch turning `current` into `desired`.
+  pub fn diff(current: &serde_json::Value, desired: &serde_json::Value) -> Self {
+    Self {
+      patch: json_patch::diff(current, desired),
+      system_state: PhantomData,
+    }
+  }
+
+  /// Determine if the patch has no operations.
+  pub fn is_empty(&self) -> bool {
+    self.patch.0.is_empty()
+  }
+
+  /// The patch which this command applies.
+  pub fn patch(&self) -> &Patch {
+    &self.patch
+  }
+}
+
+impl<SystemState> Command<'_> for ReconcileCommand<SystemState>
+where
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+{
+  type SystemState = SystemState;
+
+  /// Apply the patch to the JSON form of the old state.
+  /// The patch was computed against the exact state it is applied to, both live and on replay,
+  /// so failing to apply it means the log and state have diverged and this panics.
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    let mut document =
+      serde_json::to_value(old_state).expect("unable to serialize state for reconciliation");
+
+    json_patch::patch(&mut document, &self.patch)
+      .expect("reconciliation patch does not apply to the state it was computed against");
+
+    serde_json::from_value(document).expect("reconciled state does not deserialize")
+  }
+}
@@ -0,0 +1,303 @@
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use ulid::Ulid;
+
+use crate::command_log::CommandLog;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::require_json_entry;
+
+/// Commands checked and written out together while redacting, before progress is reported.
+pub(crate) const REDACTION_BATCH_SIZE: usize = 1_000;
+
+/// What to do with a logged command, decided for each by the predicate passed to `Madeleine::redact_matching`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedactionAction {
+  /// Leave the command as it is.
+  Keep,
+  /// Replace the command with a tombstone, keeping only its ULID and sequence number.
+  /// Tombstones are skipped when replaying the log, see `RawLoggedCommand::is_tombstone`.
+  Tombstone,
+  /// Replace the command's JSON payload, which must still deserialize as the store's command type.
+  RewritePayload(Vec<u8>),
+}
+
+/// Outcome of `Madeleine::redact_matching`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RedactionReport {
+  /// Number of commands the predicate was asked about.
+  pub commands_scanned: u64,
+  /// Number of commands replaced with tombstones.
+  pub commands_tombstoned: u64,
+  /// Number of commands whose payloads were rewritten.
+  pub commands_rewritten: u64,
+  /// Id of the snapshot taken beforehand, which holds the state the redacted commands produced.
+  pub snapshot_id: usize,
+}
+
+/// An entry ready to be appended to the redacted log.
+struct RedactedEntry {
+  id: Ulid,
+  sequence: Option<u64>,
+  entry: Vec<u8>,
+  rewritten: bool,
+}
+
+/// Copy every entry of `source` into a new log at `target_dir_path`, as `predicate` says, in batches.
+/// Each batch's rewritten payloads are checked to deserialize as `C` before any of the batch is written,
+/// and `progress` is told the report so far after every batch.
+pub(crate) fn copy_log_redacting<C, P, R>(
+  source: &CommandLog,
+  target_dir_path: &Path,
+  mut predicate: P,
+  mut progress: R,
+) -> Result<RedactionReport, MadeleineError>
+where
+  C: DeserializeOwned,
+  P: FnMut(&RawLoggedCommand) -> Option<RedactionAction>,
+  R: FnMut(&RedactionReport) -> Result<(), MadeleineError>,
+{
+  let target = CommandLog::with_backend(target_dir_path.to_path_buf(), source.backend())?;
+  target.set_payload_codec(source.payload_codec()?)?;
+
+  let mut report = RedactionReport::default();
+  let mut batch = Vec::with_capacity(REDACTION_BATCH_SIZE);
+
+  source.for_each_sequenced_entry(|offset, sequence, entry| {
+    let logged =
+      RawLoggedCommand::from_entry(offset, sequence, require_json_entry(entry, "redaction")?)?;
+
+    report.commands_scanned += 1;
+
+    let redacted = match predicate(&logged).unwrap_or(RedactionAction::Keep) {
+      RedactionAction::Keep => RedactedEntry {
+        id: logged.id,
+        sequence,
+        entry: entry.to_vec(),
+        rewritten: false,
+      },
+      RedactionAction::Tombstone => {
+        report.commands_tombstoned += 1;
+
+        RedactedEntry {
+          id: logged.id,
+          sequence,
+          entry: serde_json::to_vec(&(logged.id, serde_json::Value::Null))?,
+          rewritten: false,
+        }
+      }
+      RedactionAction::RewritePayload(payload) => {
+        report.commands_rewritten += 1;
+
+        let value: serde_json::Value = serde_json::from_slice(&payload).map_err(|error| {
+          MadeleineError::RedactionError(format!(
+            "rewritten payload of {} isn't JSON: {}",
+            logged.id, error
+          ))
+        })?;
+
+        RedactedEntry {
+          id: logged.id,
+          sequence,
+          entry: serde_json::to_vec(&(logged.id, value))?,
+          rewritten: true,
+        }
+      }
+    };
+
+    batch.push(redacted);
+
+    if batch.len() == REDACTION_BATCH_SIZE {
+      write_batch::<C>(&target, &mut batch)?;
+      progress(&report)?;
+    }
+
+    Ok(())
+  })?;
+
+  if !batch.is_empty() {
+    write_batch::<C>(&target, &mut batch)?;
+    progress(&report)?;
+  }
+
+  target.flush()?;
+
+  Ok(report)
+}
+
+/// Check that every rewritten entry in the batch still deserializes as `C`, then append the whole batch.
+fn write_batch<C: DeserializeOwned>(
+  target: &CommandLog,
+  batch: &mut Vec<RedactedEntry>,
+) -> Result<(), MadeleineError> {
+  for redacted in batch.iter().filter(|redacted| redacted.rewritten) {
+    serde_json::from_slice::<(Ulid, C)>(&redacted.entry).map_err(|error| {
+      MadeleineError::RedactionError(format!(
+        "rewritten payload of {} doesn't deserialize as {}: {}",
+        redacted.id,
+        std::any::type_name::<C>(),
+        error
+      ))
+    })?;
+  }
+
+  let entries: Vec<(Vec<u8>, Option<u64>)> = batch
+    .drain(..)
+    .map(|redacted| (redacted.entry, redacted.sequence))
+    .collect();
+
+  target.append_sequenced_entries(&entries)?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use serde::{Deserialize, Serialize};
+
+  use crate::admin_log::admin_ops_since;
+  use crate::{AdminMarker, AdminOperationKind, Command, Madeleine, ReadOnlyMadeleine};
+
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct Signup {
+    email: String,
+    plan: u64,
+  }
+
+  impl Command<'_> for Signup {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.plan
+    }
+  }
+
+  fn signups(madeleine: &Madeleine<u64>, emails: &[&str]) {
+    for email in emails {
+      madeleine
+        .execute_command(Signup {
+          email: email.to_string(),
+          plan: 1,
+        })
+        .expect("unable to execute command in test");
+    }
+  }
+
+  fn leaked(logged: &RawLoggedCommand) -> bool {
+    String::from_utf8_lossy(&logged.payload).contains("ada@example.com")
+  }
+
+  #[test]
+  fn test_redact_matching_tombstones_and_rewrites() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(location.clone(), || 0).expect("unable to instantiate madeleine in test");
+
+    signups(
+      &madeleine,
+      &["ada@example.com", "bob@example.com", "ada@example.com"],
+    );
+
+    let mut first = true;
+    let report = madeleine
+      .redact_matching::<Signup, _>(|logged| {
+        if !leaked(logged) {
+          return None;
+        }
+
+        if std::mem::take(&mut first) {
+          Some(RedactionAction::Tombstone)
+        } else {
+          Some(RedactionAction::RewritePayload(
+            br#"{"email":"[redacted]","plan":1}"#.to_vec(),
+          ))
+        }
+      })
+      .expect("unable to redact in test");
+
+    assert_eq!(report.commands_scanned, 3);
+    assert_eq!(report.commands_tombstoned, 1);
+    assert_eq!(report.commands_rewritten, 1);
+
+    let commands = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(commands.len(), 3);
+    assert!(commands[0].is_tombstone());
+    assert!(!commands.iter().any(leaked));
+    assert_eq!(
+      commands[2].deserialize::<Signup>().ok(),
+      Some(Signup {
+        email: String::from("[redacted]"),
+        plan: 1,
+      })
+    );
+
+    signups(&madeleine, &["cy@example.com"]);
+
+    drop(madeleine);
+
+    // The state the tombstoned command produced was kept in a snapshot, but replicas replaying the log lose it.
+    let resumed = Madeleine::<u64>::resume_replaying::<Signup, _>(location.clone(), || 0)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state), 4);
+
+    let replica = ReadOnlyMadeleine::<Signup, u64>::open(location.clone(), || 0)
+      .expect("unable to open replica in test");
+
+    assert_eq!(replica.read_transaction(|state, _head_id| *state), 3);
+
+    let operations =
+      admin_ops_since(&location, AdminMarker::start()).expect("unable to read admin log in test");
+
+    assert!(operations.iter().any(|operation| operation.kind
+      == AdminOperationKind::Redacted {
+        commands_scanned: 3,
+        commands_tombstoned: 1,
+        commands_rewritten: 1,
+      }));
+  }
+
+  #[test]
+  fn test_invalid_rewrite_aborts_leaving_log_untouched() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    signups(&madeleine, &["ada@example.com", "bob@example.com"]);
+
+    let error = madeleine
+      .redact_matching::<Signup, _>(|logged| {
+        leaked(logged).then(|| RedactionAction::RewritePayload(br#"{"email":null}"#.to_vec()))
+      })
+      .expect_err("invalid rewrite accepted in test");
+
+    let first_id = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test")[0]
+      .id;
+
+    assert!(
+      matches!(error, MadeleineError::RedactionError(ref message) if message.contains(&first_id.to_string()))
+    );
+
+    let commands = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(commands.len(), 2);
+    assert!(leaked(&commands[0]));
+  }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use ulid::Ulid;
+
+static NEXT_REGISTRATION_ID: AtomicU64 = AtomicU64::new(0);
+static OPEN_STORES: Mutex<BTreeMap<u64, OpenStoreInfo>> = Mutex::new(BTreeMap::new());
+
+/// Whether an open store is ready to accept commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+  /// The store is still being opened or resumed.
+  Opening,
+  /// The store is open and ready for use.
+  Ready,
+}
+
+/// Diagnostic information about a store which is currently open in this process.
+#[derive(Debug, Clone)]
+pub struct OpenStoreInfo {
+  /// Location of the store on disk.
+  pub path: PathBuf,
+  /// Identifier of the store, assigned when it was first created.
+  pub store_id: Ulid,
+  /// Name of the type of the store's system state.
+  pub state_type_name: &'static str,
+  /// Number of commands in the store's log as of its most recent command.
+  pub command_count: u64,
+  /// When the store was opened in this process.
+  pub opened_at: SystemTime,
+  /// Whether the store is ready for use.
+  pub readiness: Readiness,
+}
+
+/// List every store currently open in this process.
+pub fn open_stores() -> Vec<OpenStoreInfo> {
+  lock_open_stores().values().cloned().collect()
+}
+
+/// Recover the registry even if a thread panicked while holding it, since entries are always left consistent.
+fn lock_open_stores() -> MutexGuard<'static, BTreeMap<u64, OpenStoreInfo>> {
+  OPEN_STORES
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// An open store's entry in the registry, removed when dropped.
+pub(crate) struct Registration {
+  id: u64,
+}
+
+impl Registration {
+  /// Add a store to the registry in the `Opening` state.
+  pub fn new(
+    path: PathBuf,
+    store_id: Ulid,
+    state_type_name: &'static str,
+    command_count: u64,
+  ) -> Self {
+    let id = NEXT_REGISTRATION_ID.fetch_add(1, Ordering::Relaxed);
+
+    let info = OpenStoreInfo {
+      path,
+      store_id,
+      state_type_name,
+      command_count,
+      opened_at: SystemTime::now(),
+      readiness: Readiness::Opening,
+    };
+
+    lock_open_stores().insert(id, info);
+
+    Self { id }
+  }
+
+  /// Mark the store as ready for use.
+  pub fn mark_ready(&self) {
+    if let Some(info) = lock_open_stores().get_mut(&self.id) {
+      info.readiness = Readiness::Ready;
+    }
+  }
+
+  /// Update the recorded number of commands.
+  pub fn record_command_count(&self, command_count: u64) {
+    if let Some(info) = lock_open_stores().get_mut(&self.id) {
+      info.command_count = command_count;
+    }
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    lock_open_stores().remove(&self.id);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  fn find(path: &PathBuf) -> Option<OpenStoreInfo> {
+    open_stores().into_iter().find(|info| &info.path == path)
+  }
+
+  #[test]
+  fn test_open_stores_lists_open_stores() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let first_path = temp_dir.path().join("first_store");
+    let second_path = temp_dir.path().join("second_store");
+
+    let first = Madeleine::new(first_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let second = Madeleine::new(second_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    for _i in 0..3 {
+      second
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+
+    let first_info = find(&first_path).expect("first store missing from registry in test");
+    let second_info = find(&second_path).expect("second store missing from registry in test");
+
+    assert_eq!(first_info.command_count, 0);
+    assert_eq!(second_info.command_count, 3);
+    assert_eq!(second_info.state_type_name, "u64");
+    assert_eq!(second_info.readiness, Readiness::Ready);
+    assert!(first_info.store_id != second_info.store_id);
+
+    drop(first);
+
+    assert!(find(&first_path).is_none());
+    assert!(find(&second_path).is_some());
+
+    drop(second);
+
+    assert!(find(&second_path).is_none());
+  }
+
+  #[test]
+  fn test_store_id_survives_reopening() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let path = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(path.clone(), || 0_u64).expect("unable to instantiate madeleine in test");
+
+    let original = find(&path).expect("store missing from registry in test");
+
+    drop(madeleine);
+
+    let _madeleine =
+      Madeleine::new(path.clone(), || 0_u64).expect("unable to instantiate madeleine in test");
+
+    let reopened = find(&path).expect("store missing from registry in test");
+
+    assert_eq!(reopened.store_id, original.store_id);
+  }
+}
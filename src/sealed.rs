@@ -0,0 +1,5 @@
+/// Supertrait of public traits which only this crate implements, such as `rate_limit::Clock` and `CommandSerializer`,
+/// so that they can gain methods without breaking anyone. It's in a private module, so it can't be named,
+/// let alone implemented, elsewhere. Extension points meant for applications, such as `CommandStore` and `Codec`,
+/// aren't sealed.
+pub trait Sealed {}
@@ -0,0 +1,191 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::iter::Peekable;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ulid::Ulid;
+
+use crate::logged_command::RawLoggedCommand;
+
+/// Hands out sequence numbers which increase across every store it's installed on,
+/// giving their commands a total order.
+pub trait Sequencer: Send + Sync {
+  /// The next sequence number, greater than every number handed out before.
+  fn next_sequence(&self) -> u64;
+}
+
+/// A `Sequencer` shared by the stores within a single process.
+#[derive(Debug, Default)]
+pub struct AtomicSequencer {
+  next: AtomicU64,
+}
+
+impl AtomicSequencer {
+  /// Start handing out sequence numbers from zero.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Start handing out sequence numbers from `next`, e.g. to continue after the highest already logged.
+  pub fn starting_at(next: u64) -> Self {
+    Self {
+      next: AtomicU64::new(next),
+    }
+  }
+}
+
+impl Sequencer for AtomicSequencer {
+  fn next_sequence(&self) -> u64 {
+    self.next.fetch_add(1, Ordering::SeqCst)
+  }
+}
+
+/// Merge the commands of several stores sharing a `Sequencer` into a single stream in sequence order.
+/// Each input must already be in log order, as read from a store. Commands logged without a sequencer
+/// sort before sequenced ones, and ties are broken by ULID and then by input position, so that
+/// the merged order is deterministic.
+pub fn merge_ordered<I>(logs: Vec<I>) -> impl Iterator<Item = RawLoggedCommand>
+where
+  I: IntoIterator<Item = RawLoggedCommand>,
+{
+  MergeOrdered::new(
+    logs
+      .into_iter()
+      .map(|log| log.into_iter().peekable())
+      .collect(),
+  )
+}
+
+/// Key on which merged commands are ordered.
+type MergeKey = (Option<u64>, Ulid, usize);
+
+struct MergeOrdered<I: Iterator<Item = RawLoggedCommand>> {
+  logs: Vec<Peekable<I>>,
+  heads: BinaryHeap<Reverse<MergeKey>>,
+}
+
+impl<I: Iterator<Item = RawLoggedCommand>> MergeOrdered<I> {
+  fn new(mut logs: Vec<Peekable<I>>) -> Self {
+    let mut heads = BinaryHeap::new();
+
+    for (index, log) in logs.iter_mut().enumerate() {
+      if let Some(command) = log.peek() {
+        heads.push(Reverse((command.sequence, command.id, index)));
+      }
+    }
+
+    Self { logs, heads }
+  }
+}
+
+impl<I: Iterator<Item = RawLoggedCommand>> Iterator for MergeOrdered<I> {
+  type Item = RawLoggedCommand;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let Reverse((_sequence, _id, index)) = self.heads.pop()?;
+    let log = &mut self.logs[index];
+    let command = log.next();
+
+    if let Some(next) = log.peek() {
+      self.heads.push(Reverse((next.sequence, next.id, index)));
+    }
+
+    command
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::{Follower, Madeleine};
+
+  fn logged(madeleine: &Madeleine<u64>) -> Vec<RawLoggedCommand> {
+    madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test")
+  }
+
+  #[test]
+  fn test_merge_interleaved_stores() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let first = Madeleine::new(temp_dir.path().join("first_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let second = Madeleine::new(temp_dir.path().join("second_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let sequencer: Arc<dyn Sequencer> = Arc::new(AtomicSequencer::new());
+
+    for madeleine in [&first, &second] {
+      madeleine
+        .set_sequencer(Some(sequencer.clone()))
+        .expect("unable to set sequencer in test");
+    }
+
+    for (madeleine, amount) in [
+      (&second, 1),
+      (&first, 2),
+      (&first, 3),
+      (&second, 4),
+      (&first, 5),
+    ] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    let merged: Vec<(Option<u64>, Add)> = merge_ordered(vec![logged(&first), logged(&second)])
+      .map(|command| {
+        (
+          command.sequence,
+          command
+            .deserialize()
+            .expect("unable to deserialize in test"),
+        )
+      })
+      .collect();
+
+    assert_eq!(
+      merged,
+      (1..=5)
+        .map(|amount| (Some(amount - 1), Add(amount)))
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn test_unsequenced_commands_have_no_sequence() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+    madeleine
+      .set_sequencer(Some(Arc::new(AtomicSequencer::starting_at(613))))
+      .expect("unable to set sequencer in test");
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+
+    let sequences: Vec<Option<u64>> = Follower::open(store_path)
+      .and_then(|follower| follower.commands_after(Ulid::nil()))
+      .expect("unable to read commands in test")
+      .iter()
+      .map(|command| command.sequence)
+      .collect();
+
+    assert_eq!(sequences, vec![None, Some(613)]);
+  }
+}
@@ -0,0 +1,845 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LockResult, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+use std::thread;
+use std::time::SystemTime;
+
+use commitlog::Offset;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+#[cfg(feature = "async")]
+use crate::cancellation::{CancelOnDrop, CancellationToken};
+use crate::command::{Command, CommandWithOutput};
+use crate::events::StoreEvent;
+use crate::locks::lock_recovering;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::maintenance::{MaintenanceRun, MaintenanceThread, MAX_MAINTENANCE_WAIT};
+use crate::metrics::IntegrityFlag;
+#[cfg(feature = "async")]
+use crate::payload_format::PayloadFormat;
+use crate::query::Query;
+#[cfg(feature = "async")]
+use crate::rate_limit::{logged_bytes, RateLimiter};
+use crate::read_guard::{ArcStateSnapshot, ReadLock};
+#[cfg(feature = "async")]
+use crate::store_path::StorePath;
+use crate::subscription::{Receiver, SubscribeOptions};
+
+/// What a `SharedMadeleine` does after a thread panics while using the instance, e.g. in a command's `execute`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoisonPolicy {
+  /// Recover the lock and carry on, recording an `IntegrityFlag::LockPoisoned` in the metrics.
+  /// This is safe because a panicking command leaves the state as it was before the command.
+  #[default]
+  Recover,
+  /// Record the flag as with `Recover`, then fail every call with `MadeleineError::Poisoned`
+  /// until the poisoning is acknowledged with `acknowledge_poison`.
+  Strict,
+}
+
+/// Bookkeeping shared between clones of a handle.
+struct Shared<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> {
+  /// The instance, which serializes commands itself.
+  madeleine: Madeleine<SystemState>,
+  /// The instance's rate limiter and payload format, so async callers can wait for the limits without the lock.
+  #[cfg(feature = "async")]
+  rate_limiter: (Arc<RateLimiter>, PayloadFormat),
+  /// A copy of the state as of the last command, so that readers needn't touch the instance.
+  published: RwLock<ArcStateSnapshot<SystemState>>,
+  policy: Mutex<PoisonPolicy>,
+  unacknowledged_poison: AtomicBool,
+  /// The thread running the instance's maintenance schedule, if started.
+  maintenance: Mutex<Option<MaintenanceThread>>,
+}
+
+/// A handle to a `Madeleine` instance which can be cloned and shared between threads.
+///
+/// Readers share a read lock on a copy of the state, published after every command, so they
+/// run concurrently and never wait for one another. A command is executed and logged without that lock,
+/// which it only takes to swap in the copy of the state it left, so readers never wait for the log.
+/// Publishing clones the state, so commands cost one more clone than on an unshared instance.
+pub struct SharedMadeleine<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> {
+  shared: Arc<Shared<SystemState>>,
+}
+
+impl<SystemState: Clone + for<'a> Deserialize<'a> + Serialize> Clone
+  for SharedMadeleine<SystemState>
+{
+  fn clone(&self) -> Self {
+    Self {
+      shared: self.shared.clone(),
+    }
+  }
+}
+
+impl<SystemState> SharedMadeleine<SystemState>
+where
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize + Send + Sync,
+{
+  /// Share an instance between threads, using the default `PoisonPolicy`.
+  pub fn new(madeleine: Madeleine<SystemState>) -> Result<Self, MadeleineError> {
+    Ok(Self {
+      shared: Arc::new(Shared {
+        published: RwLock::new(madeleine.arc_snapshot()?),
+        #[cfg(feature = "async")]
+        rate_limiter: madeleine.rate_limiter(),
+        madeleine,
+        policy: Mutex::new(PoisonPolicy::default()),
+        unacknowledged_poison: AtomicBool::new(false),
+        maintenance: Mutex::new(None),
+      }),
+    })
+  }
+
+  /// Resume a store and replay its log on tokio's blocking thread pool, then share it,
+  /// see `Madeleine::resume_replaying_cancellable`.
+  ///
+  /// Dropping the future, e.g. when a `tokio::time::timeout` elapses, cancels `cancellation`, so the replay stops
+  /// between commands rather than holding a blocking thread until it's done. It can also be cancelled directly.
+  #[cfg(feature = "async")]
+  pub async fn resume_replaying_async<C, F>(
+    location: impl Into<StorePath>,
+    constructor: F,
+    cancellation: CancellationToken,
+  ) -> Result<Self, MadeleineError>
+  where
+    SystemState: 'static,
+    C: for<'a> Command<'a, SystemState = SystemState> + 'static,
+    F: FnOnce() -> SystemState + Send + 'static,
+  {
+    let location = location.into();
+    let cancel_on_drop = CancelOnDrop::new(cancellation.clone());
+
+    let resumed = tokio::task::spawn_blocking(move || {
+      Madeleine::resume_replaying_cancellable::<C, F>(location, constructor, cancellation)
+        .and_then(Self::new)
+    })
+    .await;
+
+    cancel_on_drop.disarm();
+
+    match resumed {
+      Ok(result) => result,
+      Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+      Err(error) => Err(MadeleineError::AsyncTaskFailed(error.to_string())),
+    }
+  }
+
+  /// Execute a command, then publish the state it left, see `Madeleine::execute_command`.
+  pub fn execute_command<'a, C>(&self, command: C) -> Result<Offset, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState> + Serialize + Deserialize<'a>,
+  {
+    let madeleine = self.madeleine()?;
+    let offset = {
+      let _panic_guard = PanicGuard(self);
+
+      madeleine.execute_command(command)?
+    };
+
+    self.publish()?;
+
+    Ok(offset)
+  }
+
+  /// Execute a command on tokio's blocking thread pool, so that waiting for the lock and logging the command
+  /// don't stall the async executor, see `execute_command`. A panicking command panics here too.
+  ///
+  /// While the rate limits wouldn't admit the command, this sleeps until they would,
+  /// failing with `MadeleineError::RateLimited` once that would take longer than `RateLimits::max_async_delay` in all.
+  /// Other commands may still take the tokens first, in which case it fails the same way.
+  #[cfg(feature = "async")]
+  pub async fn execute_command_async<C>(&self, command: C) -> Result<Offset, MadeleineError>
+  where
+    SystemState: 'static,
+    C: Command<'static, SystemState = SystemState>
+      + Serialize
+      + Deserialize<'static>
+      + Send
+      + 'static,
+  {
+    let mut waited = std::time::Duration::ZERO;
+
+    loop {
+      let (rate_limiter, payload_format) = &self.shared.rate_limiter;
+      let max_delay = rate_limiter.limits().max_async_delay;
+      let wait = rate_limiter.wait(1, || {
+        logged_bytes(*payload_format, std::slice::from_ref(&command))
+      })?;
+
+      if wait.is_zero() {
+        break;
+      }
+
+      if waited + wait > max_delay {
+        return Err(MadeleineError::RateLimited { retry_after: wait });
+      }
+
+      tokio::time::sleep(wait).await;
+      waited += wait;
+    }
+
+    let shared = self.clone();
+
+    match tokio::task::spawn_blocking(move || shared.execute_command(command)).await {
+      Ok(result) => result,
+      Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+      Err(error) => Err(MadeleineError::AsyncTaskFailed(error.to_string())),
+    }
+  }
+
+  /// Execute a command as `execute_command` does, returning its output, see `Madeleine::execute_command_with_output`.
+  pub fn execute_command_with_output<'a, C>(&self, command: C) -> Result<C::Output, MadeleineError>
+  where
+    C: CommandWithOutput<'a, SystemState = SystemState>,
+  {
+    let madeleine = self.madeleine()?;
+    let output = {
+      let _panic_guard = PanicGuard(self);
+
+      madeleine.execute_command_with_output(command)?
+    };
+
+    self.publish()?;
+
+    Ok(output)
+  }
+
+  /// Copy the instance's state, then take the write lock only to swap it in for the published one.
+  /// Commands on other threads may publish in between, so an older copy never replaces a newer one.
+  fn publish(&self) -> Result<(), MadeleineError> {
+    let snapshot = self.shared.madeleine.arc_snapshot()?;
+    let mut published = self.write()?;
+
+    if snapshot.head_id() >= published.head_id() {
+      *published = snapshot;
+    }
+
+    Ok(())
+  }
+
+  /// Run a closure passed a clone of the state while holding a read lock, see `Madeleine::try_tap`.
+  /// In strict mode, fails with `MadeleineError::StateMutatedOutsideCommand` if the published state changed meanwhile.
+  pub fn tap<T, O>(&self, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(SystemState) -> T,
+  {
+    let published = self.read()?;
+
+    self
+      .shared
+      .madeleine
+      .guard_unmutated(&published, || func(SystemState::clone(&published)))
+  }
+
+  /// Run a closure passed a reference to the state while holding a read lock, see `Madeleine::tap_ref`.
+  /// In strict mode, fails with `MadeleineError::StateMutatedOutsideCommand` if the closure mutated the state.
+  pub fn tap_ref<T, O>(&self, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(&SystemState) -> T,
+  {
+    let published = self.read()?;
+
+    self
+      .shared
+      .madeleine
+      .guard_unmutated(&published, || func(&published))
+  }
+
+  /// Run a query against the state while holding a read lock, see `Madeleine::execute_query`.
+  pub fn execute_query<Q>(&self, query: &Q) -> Result<Q::Output, MadeleineError>
+  where
+    Q: Query<SystemState = SystemState>,
+  {
+    self.tap_ref(|state| query.execute(state))
+  }
+
+  /// Run a closure passed the state and the ULID of the last command applied to it, see `Madeleine::head_id`.
+  /// A read lock is held throughout, so no command can publish its state while the closure reads.
+  pub fn read_transaction<T, O>(&self, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(&SystemState, Ulid) -> T,
+  {
+    let published = self.read()?;
+
+    self
+      .shared
+      .madeleine
+      .guard_unmutated(&published, || func(&published, published.head_id()))
+  }
+
+  /// Take a read lock through a `ReadLock`, for reads which don't fit in a closure.
+  /// Other readers carry on, but commands wait until it's dropped to publish their state.
+  pub fn read_lock(&self) -> Result<ReadLock<'_, SystemState>, MadeleineError> {
+    Ok(ReadLock::new(self.read()?))
+  }
+
+  /// Take a read lock as `read_lock` does, failing fast with `MadeleineError::Contended`
+  /// instead of waiting if a command is publishing its state.
+  pub fn try_read_lock(&self) -> Result<ReadLock<'_, SystemState>, MadeleineError> {
+    let published = match self.shared.published.try_read() {
+      Ok(published) => Ok(published),
+      Err(TryLockError::Poisoned(poisoned)) => Err(poisoned),
+      Err(TryLockError::WouldBlock) => {
+        return Err(MadeleineError::Contended(String::from(
+          "a command is publishing its state",
+        )))
+      }
+    };
+
+    Ok(ReadLock::new(self.recover_published(published)?))
+  }
+
+  /// Share the state as of the last command, see `Madeleine::arc_snapshot`.
+  /// This doesn't clone the state, and the snapshot can be kept across `await` points, unlike a `read_lock`.
+  pub fn arc_snapshot(&self) -> Result<ArcStateSnapshot<SystemState>, MadeleineError> {
+    Ok(self.read()?.clone())
+  }
+
+  /// Gets the length of the command history, see `Madeleine::len`.
+  pub fn len(&self) -> Result<u64, MadeleineError> {
+    Ok(self.madeleine()?.len())
+  }
+
+  /// Determine if the instance has an empty command history, see `Madeleine::is_empty`.
+  pub fn is_empty(&self) -> Result<bool, MadeleineError> {
+    Ok(self.madeleine()?.is_empty())
+  }
+
+  /// Consume the last handle and return the instance's internal state.
+  /// Fails, giving the handle back, while other clones of it exist.
+  /// Stops the maintenance thread first, if it was started, even if this fails.
+  pub fn into_inner(self) -> Result<SystemState, Self> {
+    self.stop_maintenance();
+
+    let shared = Arc::try_unwrap(self.shared).map_err(|shared| Self { shared })?;
+
+    Ok(shared.madeleine.into_inner())
+  }
+
+  /// Receive the events affecting the store's health, see `Madeleine::events`.
+  pub fn events(&self, options: SubscribeOptions) -> Result<Receiver<StoreEvent>, MadeleineError> {
+    self.madeleine()?.events(options)
+  }
+
+  /// Start a background thread which runs the instance's maintenance schedule, see `Madeleine::set_maintenance_schedule`.
+  /// Tasks are serialized with commands by the instance, as they are on an unshared one.
+  ///
+  /// The thread only keeps a weak reference to the instance, so it stops once the last handle is dropped,
+  /// or when `stop_maintenance` is called. Starting it again while it's running does nothing.
+  pub fn start_maintenance(&self) -> Result<(), MadeleineError>
+  where
+    SystemState: 'static,
+  {
+    let mut maintenance = lock_recovering(&self.shared.maintenance);
+
+    if maintenance.is_some() {
+      return Ok(());
+    }
+
+    let weak = Arc::downgrade(&self.shared);
+
+    *maintenance = Some(MaintenanceThread::spawn(move || {
+      let madeleine = Self {
+        shared: weak.upgrade()?,
+      };
+
+      // Failed runs are delivered as events, so the thread carries on regardless.
+      let _runs = madeleine.run_pending_maintenance_now();
+
+      Some(
+        madeleine
+          .madeleine()
+          .ok()
+          .and_then(|madeleine| madeleine.next_maintenance_in())
+          .unwrap_or(MAX_MAINTENANCE_WAIT),
+      )
+    })?);
+
+    Ok(())
+  }
+
+  /// Stop the maintenance thread, waiting for any task it's running to finish.
+  pub fn stop_maintenance(&self) {
+    let maintenance = lock_recovering(&self.shared.maintenance).take();
+
+    // Dropped with the lock released, as joining the thread waits for it.
+    drop(maintenance);
+  }
+
+  /// Run the maintenance tasks which are due on this thread, see `Madeleine::tick_maintenance`,
+  /// e.g. in tests which move a `testing::ManualClock` rather than waiting for the maintenance thread.
+  /// Tasks which compact or restore the store change the state, so it's published again afterwards.
+  pub fn run_pending_maintenance_now(&self) -> Result<Vec<MaintenanceRun>, MadeleineError> {
+    let madeleine = self.madeleine()?;
+    let runs = {
+      let _panic_guard = PanicGuard(self);
+
+      madeleine.tick_maintenance()?
+    };
+
+    self.publish()?;
+
+    Ok(runs)
+  }
+
+  /// Change what happens after a thread panics while using the instance.
+  pub fn set_poison_policy(&self, policy: PoisonPolicy) {
+    *lock_recovering(&self.shared.policy) = policy;
+  }
+
+  /// Accept that a thread panicked while using the instance, so that calls succeed again under `PoisonPolicy::Strict`.
+  pub fn acknowledge_poison(&self) {
+    self
+      .shared
+      .unacknowledged_poison
+      .store(false, Ordering::Relaxed);
+  }
+
+  /// Take a read lock on the published state, applying the poison policy if a thread panicked while holding it.
+  fn read(&self) -> Result<RwLockReadGuard<'_, ArcStateSnapshot<SystemState>>, MadeleineError> {
+    self.recover_published(self.shared.published.read())
+  }
+
+  /// Take the write lock on the published state, applying the poison policy as `read` does.
+  fn write(&self) -> Result<RwLockWriteGuard<'_, ArcStateSnapshot<SystemState>>, MadeleineError> {
+    let published = match self.shared.published.write() {
+      Ok(published) => published,
+      Err(poisoned) => {
+        self.shared.published.clear_poison();
+        self.record_poisoning();
+
+        poisoned.into_inner()
+      }
+    };
+
+    self.check_policy()?;
+
+    Ok(published)
+  }
+
+  /// Apply the poison policy to the outcome of taking a read lock on the published state.
+  /// The published state was left as it was before the panicking command, so it's still consistent.
+  fn recover_published<'a>(
+    &self,
+    locked: LockResult<RwLockReadGuard<'a, ArcStateSnapshot<SystemState>>>,
+  ) -> Result<RwLockReadGuard<'a, ArcStateSnapshot<SystemState>>, MadeleineError> {
+    let published = match locked {
+      Ok(published) => published,
+      Err(poisoned) => {
+        self.shared.published.clear_poison();
+        self.record_poisoning();
+
+        poisoned.into_inner()
+      }
+    };
+
+    self.check_policy()?;
+
+    Ok(published)
+  }
+
+  /// The instance, applying the poison policy.
+  fn madeleine(&self) -> Result<&Madeleine<SystemState>, MadeleineError> {
+    self.check_policy()?;
+
+    Ok(&self.shared.madeleine)
+  }
+
+  /// Record that a thread panicked while using the instance, for the poison policy to act on.
+  /// The instance recovers its own locks, and a panicking command leaves the state as it was.
+  fn record_poisoning(&self) {
+    let madeleine = &self.shared.madeleine;
+    let at = SystemTime::now();
+
+    madeleine
+      .metrics()
+      .record_integrity_flag(IntegrityFlag::LockPoisoned { at });
+    // Subscribers which can't take the event miss it, as the panic is recorded either way.
+    let _emitted = madeleine.emit(StoreEvent::LockPoisoned { at });
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(
+      store_id = %madeleine.store_id(),
+      "recovered from a thread panicking while using a shared Madeleine"
+    );
+
+    self
+      .shared
+      .unacknowledged_poison
+      .store(true, Ordering::Relaxed);
+  }
+
+  /// Fail under `PoisonPolicy::Strict` until a recovered poisoning is acknowledged.
+  fn check_policy(&self) -> Result<(), MadeleineError> {
+    if self.shared.unacknowledged_poison.load(Ordering::Relaxed)
+      && *lock_recovering(&self.shared.policy) == PoisonPolicy::Strict
+    {
+      return Err(MadeleineError::Poisoned(String::from(
+        "a thread panicked while using the instance, call acknowledge_poison to continue",
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+/// Records a panic unwinding through a call into the instance, see `PoisonPolicy`.
+struct PanicGuard<'a, SystemState>(&'a SharedMadeleine<SystemState>)
+where
+  SystemState: Clone + for<'b> Deserialize<'b> + Serialize + Send + Sync;
+
+impl<SystemState> Drop for PanicGuard<'_, SystemState>
+where
+  SystemState: Clone + for<'b> Deserialize<'b> + Serialize + Send + Sync,
+{
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.0.record_poisoning();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::thread;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::{FailpointAction, FailpointStore, StorageOperation};
+
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  enum Step {
+    Add(u64),
+    Explode,
+  }
+
+  impl Command<'_> for Step {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      match self {
+        Self::Add(amount) => old_state + amount,
+        Self::Explode => panic!("exploding command in test"),
+      }
+    }
+  }
+
+  fn shared_store(temp_dir: &assert_fs::TempDir) -> SharedMadeleine<u64> {
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    SharedMadeleine::new(madeleine).expect("unable to share madeleine in test")
+  }
+
+  fn explode_on_another_thread(shared: &SharedMadeleine<u64>) {
+    let exploding = shared.clone();
+
+    let outcome = thread::spawn(move || exploding.execute_command(Step::Explode)).join();
+
+    assert!(outcome.is_err());
+  }
+
+  #[test]
+  fn test_recover_policy_continues_after_panic() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let shared = shared_store(&temp_dir);
+
+    shared
+      .execute_command(Step::Add(600))
+      .expect("unable to execute command in test");
+
+    explode_on_another_thread(&shared);
+
+    shared
+      .execute_command(Step::Add(13))
+      .expect("unable to execute command after panic in test");
+
+    let (state, flags) = shared
+      .tap_ref(|state| *state)
+      .and_then(|state| Ok((state, shared.madeleine()?.metrics().integrity_flags())))
+      .expect("unable to read state in test");
+
+    assert_eq!(state, 613);
+    assert_eq!(flags.len(), 1);
+  }
+
+  #[test]
+  fn test_strict_policy_fails_until_acknowledged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let shared = shared_store(&temp_dir);
+
+    shared.set_poison_policy(PoisonPolicy::Strict);
+
+    explode_on_another_thread(&shared);
+
+    let reader = shared.clone();
+
+    let before_acknowledging = thread::spawn(move || reader.tap_ref(|state| *state))
+      .join()
+      .expect("reading thread panicked in test");
+
+    assert!(matches!(
+      before_acknowledging,
+      Err(MadeleineError::Poisoned(_))
+    ));
+    assert!(matches!(
+      shared.execute_command(Step::Add(1)),
+      Err(MadeleineError::Poisoned(_))
+    ));
+
+    shared.acknowledge_poison();
+
+    assert_eq!(shared.tap_ref(|state| *state).ok(), Some(0));
+  }
+
+  #[test]
+  fn test_read_transaction_never_sees_torn_state() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let shared = shared_store(&temp_dir);
+
+    let writer = shared.clone();
+
+    let writing = thread::spawn(move || {
+      for _i in 0..100 {
+        writer
+          .execute_command(Step::Add(1))
+          .expect("unable to execute command in test");
+      }
+    });
+
+    let mut observations = Vec::new();
+
+    for _i in 0..20 {
+      let observation = shared
+        .read_transaction(|state, head_id| {
+          let first = *state;
+
+          thread::sleep(std::time::Duration::from_millis(1));
+
+          (first, *state, head_id)
+        })
+        .expect("unable to read in test");
+
+      observations.push(observation);
+    }
+
+    writing.join().expect("writing thread panicked in test");
+
+    let logged_ids: Vec<Ulid> = crate::Follower::open(temp_dir.path().join("test_store"))
+      .expect("unable to open follower in test")
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test")
+      .iter()
+      .map(|logged| logged.id)
+      .collect();
+
+    for (first, second, head_id) in observations {
+      assert_eq!(first, second);
+
+      let applied = logged_ids
+        .iter()
+        .position(|id| *id == head_id)
+        .map_or(0, |position| position as u64 + 1);
+
+      assert_eq!(applied, first);
+    }
+  }
+
+  #[test]
+  fn test_shared_handle_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    assert_send_sync::<SharedMadeleine<u64>>();
+  }
+
+  #[test]
+  fn test_readers_share_the_lock_and_writers_wait() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let shared = shared_store(&temp_dir);
+
+    let barrier = Arc::new(std::sync::Barrier::new(2));
+
+    // Both readers must hold the read lock at once to pass the barrier.
+    let readers: Vec<_> = (0..2)
+      .map(|_i| {
+        let reader = shared.clone();
+        let barrier = barrier.clone();
+
+        thread::spawn(move || {
+          reader.tap_ref(|state| {
+            barrier.wait();
+
+            *state
+          })
+        })
+      })
+      .collect();
+
+    for reading in readers {
+      let seen = reading
+        .join()
+        .expect("reading thread panicked in test")
+        .expect("unable to read in test");
+
+      assert_eq!(seen, 0);
+    }
+
+    let writing_lock = shared
+      .shared
+      .published
+      .write()
+      .expect("unable to take write lock in test");
+
+    assert!(matches!(
+      shared.try_read_lock(),
+      Err(MadeleineError::Contended(_))
+    ));
+
+    drop(writing_lock);
+
+    let writers: Vec<_> = (0..2)
+      .map(|_i| {
+        let writer = shared.clone();
+
+        thread::spawn(move || {
+          for _j in 0..50 {
+            writer
+              .execute_command(Step::Add(1))
+              .expect("unable to execute command in test");
+          }
+        })
+      })
+      .collect();
+
+    for writing in writers {
+      writing.join().expect("writing thread panicked in test");
+    }
+
+    assert_eq!(shared.tap(|state| state).ok(), Some(100));
+    assert_eq!(shared.len().ok(), Some(100));
+    assert_eq!(shared.is_empty().ok(), Some(false));
+  }
+
+  #[test]
+  fn test_readers_dont_wait_for_commands_being_logged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let failpoints = FailpointStore::new();
+    failpoints.on_nth(
+      StorageOperation::Append,
+      1,
+      FailpointAction::Delay(std::time::Duration::from_millis(500)),
+    );
+    madeleine
+      .set_failpoints(Some(Arc::new(failpoints)))
+      .expect("unable to set failpoints in test");
+
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+    let writer = shared.clone();
+
+    let writing = thread::spawn(move || writer.execute_command(Step::Add(1)));
+
+    thread::sleep(std::time::Duration::from_millis(100));
+
+    let seen = shared
+      .try_read_lock()
+      .map(|state| *state.read())
+      .expect("reader waited for the log in test");
+
+    assert_eq!(seen, 0);
+
+    writing
+      .join()
+      .expect("writing thread panicked in test")
+      .expect("unable to execute command in test");
+
+    assert_eq!(shared.tap_ref(|state| *state).ok(), Some(1));
+  }
+
+  /// State whose clones share a counter, so that reading a clone can still change it.
+  #[derive(Debug, Clone, Default)]
+  struct Visits(Arc<Mutex<u64>>);
+
+  impl Serialize for Visits {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+      lock_recovering(&self.0).serialize(serializer)
+    }
+  }
+
+  impl<'de> Deserialize<'de> for Visits {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+      Ok(Self(Arc::new(Mutex::new(u64::deserialize(deserializer)?))))
+    }
+  }
+
+  struct Visit;
+
+  impl Query for Visit {
+    type SystemState = Visits;
+    type Output = ();
+
+    fn execute(&self, state: &Visits) {
+      *lock_recovering(&state.0) += 1;
+    }
+  }
+
+  #[test]
+  fn test_strict_mode_checks_reads_through_shared_handle() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), Visits::default)
+      .expect("unable to instantiate madeleine in test");
+    madeleine.set_strict(true);
+
+    let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+    assert_eq!(
+      shared.tap_ref(|state| *lock_recovering(&state.0)).ok(),
+      Some(0)
+    );
+    assert!(matches!(
+      shared.tap_ref(|state| Visit.execute(state)),
+      Err(MadeleineError::StateMutatedOutsideCommand(_))
+    ));
+    assert!(matches!(
+      shared.tap(|state| Visit.execute(&state)),
+      Err(MadeleineError::StateMutatedOutsideCommand(_))
+    ));
+    assert!(matches!(
+      shared.execute_query(&Visit),
+      Err(MadeleineError::StateMutatedOutsideCommand(_))
+    ));
+  }
+
+  #[test]
+  fn test_into_inner_needs_the_last_handle() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let shared = shared_store(&temp_dir);
+
+    shared
+      .execute_command(Step::Add(613))
+      .expect("unable to execute command in test");
+
+    let other = shared.clone();
+
+    let shared = match shared.into_inner() {
+      Ok(_state) => panic!("into_inner succeeded while another handle existed in test"),
+      Err(shared) => shared,
+    };
+
+    drop(other);
+
+    assert_eq!(shared.into_inner().ok(), Some(613));
+  }
+}
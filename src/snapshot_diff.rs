@@ -0,0 +1,121 @@
+use json_patch::Patch;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+use crate::codec;
+use crate::madeleine::{resolve_snapshot_alias, snapshot_diff_file_path, snapshot_file_path};
+use crate::madeleine_error::MadeleineError;
+
+/// Contents of a differential snapshot file: the snapshot it builds on, and the patch from that snapshot's state
+/// to this one's, see `Madeleine::set_full_snapshot_every`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct SnapshotDiff {
+  /// Id of the snapshot file holding the previous state in the chain, either full or differential itself.
+  pub parent: usize,
+  pub patch: Patch,
+}
+
+/// The serialized state held by a snapshot file, see `read_snapshot_state`.
+pub(crate) struct SnapshotState {
+  pub raw_state: Vec<u8>,
+  /// Number of differential snapshots applied onto the full snapshot the state was read from.
+  pub chain_length: usize,
+}
+
+/// Read a differential snapshot file, or `None` if the snapshot isn't differential.
+pub(crate) fn read_snapshot_diff(
+  snapshot_id: usize,
+  location_dir_path: &Path,
+) -> Result<Option<SnapshotDiff>, MadeleineError> {
+  let diff_path = snapshot_diff_file_path(snapshot_id, location_dir_path.to_path_buf());
+
+  if !diff_path.is_file() {
+    return Ok(None);
+  }
+
+  let diff: SnapshotDiff = serde_json::from_slice(&codec::decode(&fs::read(diff_path)?)?)?;
+
+  if diff.parent >= snapshot_id {
+    return Err(MadeleineError::SnapshotError(format!(
+      "differential snapshot {} builds on later snapshot {}",
+      snapshot_id, diff.parent
+    )));
+  }
+
+  Ok(Some(diff))
+}
+
+/// Read the serialized state held by a snapshot file, which is either full or differential.
+/// A differential snapshot's chain of patches is applied onto the full snapshot it builds on.
+/// Returns `None` if a snapshot in the chain is missing.
+pub(crate) fn read_snapshot_state(
+  snapshot_id: usize,
+  location_dir_path: &Path,
+) -> Result<Option<SnapshotState>, MadeleineError> {
+  let mut base_id = snapshot_id;
+  let mut patches = Vec::new();
+
+  while !snapshot_file_path(base_id, location_dir_path.to_path_buf()).is_file() {
+    match read_snapshot_diff(base_id, location_dir_path)? {
+      Some(diff) => {
+        patches.push(diff.patch);
+        base_id = diff.parent;
+      }
+      None => return Ok(None),
+    }
+  }
+
+  let raw_state = codec::decode(&fs::read(snapshot_file_path(
+    base_id,
+    location_dir_path.to_path_buf(),
+  ))?)?
+  .into_owned();
+
+  if patches.is_empty() {
+    return Ok(Some(SnapshotState {
+      raw_state,
+      chain_length: 0,
+    }));
+  }
+
+  let mut state: serde_json::Value = serde_json::from_slice(&raw_state)?;
+
+  for patch in patches.iter().rev() {
+    json_patch::patch(&mut state, patch).map_err(|error| {
+      MadeleineError::SnapshotError(format!(
+        "unable to apply the chain of snapshot {}: {}",
+        snapshot_id, error
+      ))
+    })?;
+  }
+
+  Ok(Some(SnapshotState {
+    raw_state: serde_json::to_vec(&state)?,
+    chain_length: patches.len(),
+  }))
+}
+
+/// Collect the ids of the snapshot files which the given snapshots need to be read: each one's alias target,
+/// and the chain of snapshots a differential one builds on, down to its full snapshot.
+pub(crate) fn snapshot_dependencies(
+  snapshot_ids: impl IntoIterator<Item = usize>,
+  location_dir_path: &Path,
+) -> Result<BTreeSet<usize>, MadeleineError> {
+  let mut dependencies = BTreeSet::new();
+
+  for snapshot_id in snapshot_ids {
+    let mut dependency = resolve_snapshot_alias(snapshot_id, location_dir_path.to_path_buf())?;
+
+    while dependencies.insert(dependency) {
+      match read_snapshot_diff(dependency, location_dir_path)? {
+        Some(diff) => dependency = diff.parent,
+        None => break,
+      }
+    }
+  }
+
+  Ok(dependencies)
+}
@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+/// What happens when a snapshot taken on a schedule fails, e.g. because the store's disk is unavailable,
+/// see `Madeleine::take_scheduled_snapshot`. Snapshots taken with `Madeleine::take_snapshot` always report their errors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotFailureMode {
+  /// Report the error, failing the command which triggered the snapshot if any.
+  #[default]
+  FailCommand,
+  /// Deliver the error as a `StoreEvent::SnapshotFailed` and carry on.
+  /// `Metrics::pending_snapshot_debt` counts the snapshots missed since the last one which succeeded.
+  WarnAndContinue,
+}
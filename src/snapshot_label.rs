@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::madeleine::snapshot_label_file_path;
+use crate::madeleine_error::MadeleineError;
+
+/// A snapshot as listed by `Madeleine::list_snapshots`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+  /// The snapshot's id.
+  pub snapshot_id: usize,
+  /// ULID given to the snapshot when it was labelled, whose timestamp is when it was taken,
+  /// or `None` for a snapshot taken without a label.
+  pub ulid: Option<Ulid>,
+  /// Label given with `Madeleine::take_snapshot_named`, if any.
+  pub label: Option<String>,
+  /// ULID of the last command applied to the state in the snapshot, see `Madeleine::snapshot_head_id`.
+  pub head_id: Option<Ulid>,
+  /// Bytes of the snapshot's full or differential snapshot file, or zero if it's an alias of an unchanged state.
+  pub bytes: u64,
+}
+
+/// What a snapshot's label file records.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct SnapshotLabel {
+  pub label: String,
+  pub ulid: Ulid,
+}
+
+/// Fail with `MadeleineError::SnapshotError` unless a label is made of ASCII letters, digits, hyphens and underscores.
+pub(crate) fn check_label(label: &str) -> Result<(), MadeleineError> {
+  if label.is_empty() {
+    return Err(MadeleineError::SnapshotError(String::from(
+      "snapshot labels can't be empty",
+    )));
+  }
+
+  match label
+    .chars()
+    .find(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'))
+  {
+    Some(invalid) => Err(MadeleineError::SnapshotError(format!(
+      "snapshot label {:?} contains {:?}, but only letters, digits, hyphens and underscores are allowed",
+      label, invalid
+    ))),
+    None => Ok(()),
+  }
+}
+
+/// Read the label of a snapshot, if it was given one.
+pub(crate) fn read_snapshot_label(
+  snapshot_id: usize,
+  location_dir_path: &Path,
+) -> Result<Option<SnapshotLabel>, MadeleineError> {
+  let label_path = snapshot_label_file_path(snapshot_id, location_dir_path.to_path_buf());
+
+  if label_path.is_file() {
+    Ok(Some(serde_json::from_slice(&fs::read(label_path)?)?))
+  } else {
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_label_accepts_only_letters_digits_hyphens_and_underscores() {
+    for label in ["before_migration", "release-2-0", "Panda42"] {
+      assert!(check_label(label).is_ok(), "{} should be valid", label);
+    }
+
+    for label in ["", "release 2.0", "../panda", "naïve"] {
+      assert!(
+        matches!(check_label(label), Err(MadeleineError::SnapshotError(_))),
+        "{:?} should be invalid",
+        label
+      );
+    }
+  }
+}
@@ -0,0 +1,207 @@
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::locks::lock_recovering;
+
+/// When to take snapshots automatically after executing commands, see `Madeleine::set_snapshot_policy`.
+/// Automatic snapshots are scheduled, so their failures are handled as the `SnapshotFailureMode` says.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SnapshotPolicy {
+  /// Only take snapshots when asked to.
+  #[default]
+  Never,
+  /// Take a snapshot once this many commands were executed since the last one.
+  EveryNCommands(u64),
+  /// Take a snapshot after the first command executed once this long has passed since the last one.
+  Interval(Duration),
+}
+
+/// Tracks progress towards the next snapshot a policy calls for.
+#[derive(Debug, Default)]
+pub(crate) struct SnapshotScheduler {
+  policy: Mutex<SnapshotPolicy>,
+  /// Commands executed since the last snapshot, and when it was taken, or `None` until counted from the log.
+  progress: Mutex<Option<(u64, SystemTime)>>,
+}
+
+impl SnapshotScheduler {
+  pub fn policy(&self) -> SnapshotPolicy {
+    *lock_recovering(&self.policy)
+  }
+
+  pub fn set_policy(&self, policy: SnapshotPolicy) {
+    *lock_recovering(&self.policy) = policy;
+  }
+
+  /// Whether progress since the last snapshot is being tracked.
+  pub fn is_tracking(&self) -> bool {
+    lock_recovering(&self.progress).is_some()
+  }
+
+  /// Start tracking from what's already happened since the last snapshot.
+  pub fn track_from(&self, commands_since: u64, last_snapshot_at: SystemTime) {
+    *lock_recovering(&self.progress) = Some((commands_since, last_snapshot_at));
+  }
+
+  pub fn record_command(&self) {
+    if let Some((commands_since, _last_snapshot_at)) = lock_recovering(&self.progress).as_mut() {
+      *commands_since += 1;
+    }
+  }
+
+  pub fn record_snapshot(&self) {
+    self.track_from(0, SystemTime::now());
+  }
+
+  /// Whether the policy calls for a snapshot now.
+  pub fn is_due(&self) -> bool {
+    let policy = self.policy();
+
+    if policy == SnapshotPolicy::Never {
+      return false;
+    }
+
+    let Some((commands_since, last_snapshot_at)) = *lock_recovering(&self.progress) else {
+      return false;
+    };
+
+    match policy {
+      SnapshotPolicy::Never => false,
+      SnapshotPolicy::EveryNCommands(commands) => commands_since >= commands,
+      SnapshotPolicy::Interval(interval) => {
+        commands_since > 0
+          && last_snapshot_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed >= interval)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::{Add, FailpointAction, FailpointStore, StorageOperation};
+  use crate::{Madeleine, SnapshotFailureMode};
+
+  fn execute_adds(madeleine: &Madeleine<u64>, count: u64) {
+    for _i in 0..count {
+      madeleine
+        .execute_command(Add(1))
+        .expect("unable to execute command in test");
+    }
+  }
+
+  #[test]
+  fn test_every_n_commands_counts_across_restarts() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let location = temp_dir.path().join("test_store");
+
+    let madeleine =
+      Madeleine::new(location.clone(), || 0).expect("unable to instantiate madeleine in test");
+
+    execute_adds(&madeleine, 4);
+
+    madeleine
+      .set_snapshot_policy(SnapshotPolicy::EveryNCommands(3))
+      .expect("unable to set snapshot policy in test");
+
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(0));
+
+    execute_adds(&madeleine, 5);
+
+    // The four commands before the policy was set count towards the first snapshot.
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(2));
+
+    drop(madeleine);
+
+    let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(location, || 0)
+      .expect("unable to resume madeleine in test");
+
+    resumed
+      .set_snapshot_policy(SnapshotPolicy::EveryNCommands(3))
+      .expect("unable to set snapshot policy in test");
+
+    // One command executed since the last snapshot is counted from the log.
+    execute_adds(&resumed, 1);
+
+    assert_eq!(resumed.next_snapshot_id().ok(), Some(2));
+
+    execute_adds(&resumed, 1);
+
+    assert_eq!(resumed.next_snapshot_id().ok(), Some(3));
+    assert_eq!(resumed.tap(|state| state), 11);
+  }
+
+  #[test]
+  fn test_never_and_elapsed_interval() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    execute_adds(&madeleine, 5);
+
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(0));
+
+    madeleine
+      .set_snapshot_policy(SnapshotPolicy::Interval(Duration::ZERO))
+      .expect("unable to set snapshot policy in test");
+
+    execute_adds(&madeleine, 2);
+
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(2));
+
+    madeleine
+      .set_snapshot_policy(SnapshotPolicy::Never)
+      .expect("unable to set snapshot policy in test");
+
+    execute_adds(&madeleine, 2);
+
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(2));
+  }
+
+  #[test]
+  fn test_failed_snapshots_retried_by_later_commands() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .set_snapshot_policy(SnapshotPolicy::EveryNCommands(2))
+      .expect("unable to set snapshot policy in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.on_every(StorageOperation::SnapshotWrite, FailpointAction::Fail);
+
+    madeleine
+      .set_failpoints(Some(failpoints.clone()))
+      .expect("unable to set failpoints in test");
+
+    execute_adds(&madeleine, 1);
+
+    // The command is logged, but reports the failure of the snapshot it triggered.
+    assert!(madeleine.execute_command(Add(1)).is_err());
+    assert_eq!(madeleine.len(), 2);
+
+    madeleine.set_snapshot_failure_mode(SnapshotFailureMode::WarnAndContinue);
+
+    execute_adds(&madeleine, 2);
+
+    assert_eq!(madeleine.metrics().pending_snapshot_debt(), 3);
+
+    failpoints.clear();
+
+    execute_adds(&madeleine, 1);
+
+    assert_eq!(madeleine.metrics().pending_snapshot_debt(), 0);
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(1));
+    assert_eq!(madeleine.tap(|state| state), 5);
+  }
+}
@@ -0,0 +1,255 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ulid::Ulid;
+
+use crate::compaction::{COMPACTED_LOG_DIR_NAME, RETIRED_LOG_DIR_NAME};
+use crate::layout::StoreLayout;
+use crate::madeleine::is_store_root;
+use crate::madeleine_error::MadeleineError;
+
+/// The root directory of a store, as opposed to one of the files or directories inside it.
+///
+/// Every constructor opening a store takes `impl Into<StorePath>`, so paths, strings and `PathBuf`s still work.
+/// Those conversions can't fail, so the path is checked when the store is opened: it is made absolute,
+/// the log directory of a store is corrected to the store itself, and any other path inside a store is rejected
+/// with `MadeleineError::DirectoryError`. `StorePath::new` checks it right away instead.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StorePath(PathBuf);
+
+impl StorePath {
+  /// Check and canonicalize the path of a store's root directory, which need not exist yet.
+  pub fn new(location_dir_path: impl AsRef<Path>) -> Result<Self, MadeleineError> {
+    Self(location_dir_path.as_ref().to_path_buf())
+      .resolve()
+      .map(Self)
+  }
+
+  /// A fresh, uniquely named directory under the system's temporary directory, e.g. for tests and benchmarks.
+  /// It isn't created until a store is opened there, and isn't removed afterwards.
+  pub fn ephemeral() -> Self {
+    Self(std::env::temp_dir().join(format!("madeleine-{}", Ulid::new())))
+  }
+
+  /// A fresh, uniquely named directory on a RAM-backed filesystem where there is one, such as `/dev/shm` on Linux,
+  /// and under the system's temporary directory otherwise, see `Madeleine::new_in_memory`.
+  pub(crate) fn memory_backed() -> Self {
+    let shared_memory = Path::new("/dev/shm");
+    let parent = if shared_memory.is_dir() {
+      shared_memory.to_path_buf()
+    } else {
+      std::env::temp_dir()
+    };
+
+    Self(parent.join(format!("madeleine-{}", Ulid::new())))
+  }
+
+  /// The path, as given or as checked.
+  pub fn as_path(&self) -> &Path {
+    &self.0
+  }
+
+  /// Check the path, returning the absolute path of the store's root directory.
+  pub(crate) fn resolve(self) -> Result<PathBuf, MadeleineError> {
+    let location_dir_path = if self.0.exists() {
+      fs::canonicalize(&self.0)?
+    } else {
+      std::path::absolute(&self.0)?
+    };
+
+    if location_dir_path.is_file() {
+      return Err(MadeleineError::DirectoryError(
+        match location_dir_path
+          .parent()
+          .filter(|parent| is_store_root(parent))
+        {
+          Some(store_root) => format!(
+            "{} is a file inside the store at {}, pass the store's directory instead",
+            location_dir_path.display(),
+            store_root.display()
+          ),
+          None => format!(
+            "{} is a file, not a store directory",
+            location_dir_path.display()
+          ),
+        },
+      ));
+    }
+
+    let Some(store_root) = location_dir_path
+      .ancestors()
+      .skip(1)
+      .find(|ancestor| is_store_root(ancestor))
+    else {
+      return Ok(location_dir_path);
+    };
+
+    let is_log_dir = location_dir_path.parent() == Some(store_root)
+      && location_dir_path.file_name().is_some_and(|name| {
+        name == StoreLayout::of(store_root).command_log_dir_name.as_str()
+          || [COMPACTED_LOG_DIR_NAME, RETIRED_LOG_DIR_NAME]
+            .iter()
+            .any(|log_dir_name| name == *log_dir_name)
+      });
+
+    if is_log_dir {
+      Ok(store_root.to_path_buf())
+    } else {
+      Err(MadeleineError::DirectoryError(format!(
+        "{} is inside the store at {}, pass the store's directory instead",
+        location_dir_path.display(),
+        store_root.display()
+      )))
+    }
+  }
+}
+
+/// A store directory which is deleted when dropped, along with the instance holding it.
+pub(crate) struct RemoveOnDrop(pub PathBuf);
+
+impl Drop for RemoveOnDrop {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
+
+impl fmt::Display for StorePath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0.display())
+  }
+}
+
+impl AsRef<Path> for StorePath {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl From<PathBuf> for StorePath {
+  fn from(location_dir_path: PathBuf) -> Self {
+    Self(location_dir_path)
+  }
+}
+
+impl From<&PathBuf> for StorePath {
+  fn from(location_dir_path: &PathBuf) -> Self {
+    Self(location_dir_path.clone())
+  }
+}
+
+impl From<&Path> for StorePath {
+  fn from(location_dir_path: &Path) -> Self {
+    Self(location_dir_path.to_path_buf())
+  }
+}
+
+impl From<String> for StorePath {
+  fn from(location_dir_path: String) -> Self {
+    Self(PathBuf::from(location_dir_path))
+  }
+}
+
+impl From<&str> for StorePath {
+  fn from(location_dir_path: &str) -> Self {
+    Self(PathBuf::from(location_dir_path))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::madeleine::COMMAND_LOG_DIR_NAME;
+  use crate::testing::Add;
+  use crate::{Follower, Madeleine};
+
+  #[test]
+  fn test_log_dir_is_corrected_to_store_root() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Add(2))
+      .expect("unable to execute command in test");
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    let log_dir_path = store_path.join(COMMAND_LOG_DIR_NAME);
+
+    assert_eq!(
+      StorePath::new(&log_dir_path)
+        .expect("unable to check store path in test")
+        .as_path(),
+      fs::canonicalize(&store_path).expect("unable to canonicalize in test")
+    );
+
+    let resumed =
+      Madeleine::<u64>::resume(log_dir_path.clone()).expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap_ref(|state| *state).ok(), Some(2));
+
+    drop(resumed);
+
+    assert!(Follower::open(log_dir_path).is_ok());
+  }
+
+  #[test]
+  fn test_paths_inside_a_store_are_rejected() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let madeleine = Madeleine::new(store_path.clone(), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+    let snapshot_id = madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+    madeleine
+      .close()
+      .expect("unable to close madeleine in test");
+
+    let snapshot_path = crate::madeleine::snapshot_file_path(snapshot_id, store_path.clone());
+
+    assert!(matches!(
+      Madeleine::<u64>::resume(snapshot_path),
+      Err(MadeleineError::DirectoryError(message)) if message.contains("is a file inside the store")
+    ));
+    assert!(matches!(
+      Madeleine::new(store_path.join("nested"), || 0_u64),
+      Err(MadeleineError::DirectoryError(message)) if message.contains("is inside the store")
+    ));
+  }
+
+  #[test]
+  fn test_relative_and_ephemeral_paths_are_made_absolute() {
+    let relative = StorePath::new("some_store").expect("unable to check store path in test");
+
+    assert!(relative.as_path().is_absolute());
+    assert!(relative.as_path().ends_with("some_store"));
+
+    let ephemeral = StorePath::ephemeral();
+
+    assert!(!ephemeral.as_path().exists());
+    assert_ne!(ephemeral, StorePath::ephemeral());
+
+    let madeleine =
+      Madeleine::new(ephemeral.clone(), || 0_u64).expect("unable to instantiate madeleine in test");
+
+    assert!(ephemeral.as_path().is_dir());
+
+    drop(madeleine);
+
+    fs::remove_dir_all(ephemeral.as_path()).expect("unable to clean up in test");
+  }
+}
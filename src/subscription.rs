@@ -0,0 +1,496 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::locks::lock_recovering;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine_error::MadeleineError;
+
+static NEXT_SUBSCRIPTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What to do when a subscriber's buffer is full and another item is published.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+  /// Make the appending thread wait for the subscriber to catch up, for at most the given time.
+  /// If the subscriber still hasn't made room, it's disconnected as with `Disconnect`;
+  /// the appended command is never rolled back.
+  Block(Duration),
+  /// Discard the oldest buffered command to make room, counting it towards the subscriber's lag.
+  #[default]
+  DropOldest,
+  /// Disconnect the subscriber. Once it has drained its buffer, receiving fails with
+  /// `MadeleineError::SubscriptionLagged`.
+  Disconnect,
+}
+
+/// Settings for a subscription to appended commands or store events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscribeOptions {
+  /// Most items buffered for the subscriber.
+  pub capacity: usize,
+  /// What to do when the buffer is full.
+  pub policy: OverflowPolicy,
+}
+
+impl Default for SubscribeOptions {
+  fn default() -> Self {
+    Self {
+      capacity: 1024,
+      policy: OverflowPolicy::default(),
+    }
+  }
+}
+
+/// How far behind a subscriber is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriberLag {
+  /// Identifier of the subscription.
+  pub subscription_id: u64,
+  /// Number of items buffered and not yet received.
+  pub buffered: usize,
+  /// Number of items discarded because the buffer was full.
+  pub dropped: u64,
+  /// Whether the subscriber was disconnected for falling behind.
+  pub disconnected: bool,
+}
+
+#[derive(Debug)]
+struct Buffer<T> {
+  items: VecDeque<T>,
+  dropped: u64,
+  /// Whether the previous item published found the buffer full.
+  overflowing: bool,
+  disconnected: bool,
+  unsubscribed: bool,
+}
+
+impl<T> Default for Buffer<T> {
+  fn default() -> Self {
+    Self {
+      items: VecDeque::new(),
+      dropped: 0,
+      overflowing: false,
+      disconnected: false,
+      unsubscribed: false,
+    }
+  }
+}
+
+/// What became of an item published to a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+  /// The item was buffered.
+  Buffered,
+  /// The item was buffered, but the buffer had just become full, so the subscriber is now lagging.
+  StartedLagging,
+  /// The subscription has ended, whether by unsubscribing or being disconnected.
+  Ended,
+}
+
+/// State shared between a subscription and the instance publishing to it.
+#[derive(Debug)]
+struct Channel<T> {
+  id: u64,
+  options: SubscribeOptions,
+  buffer: Mutex<Buffer<T>>,
+  /// Signalled when a command is buffered or the subscriber is disconnected.
+  filled: Condvar,
+  /// Signalled when the subscriber receives a command or unsubscribes.
+  drained: Condvar,
+}
+
+impl<T: Clone> Channel<T> {
+  /// Recover the buffer even if a thread panicked while holding it, since it's always left consistent.
+  fn lock(&self) -> MutexGuard<'_, Buffer<T>> {
+    self
+      .buffer
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn lag(&self) -> SubscriberLag {
+    let buffer = self.lock();
+
+    SubscriberLag {
+      subscription_id: self.id,
+      buffered: buffer.items.len(),
+      dropped: buffer.dropped,
+      disconnected: buffer.disconnected,
+    }
+  }
+
+  /// Buffer an item according to the overflow policy.
+  fn publish(&self, item: &T) -> Delivery {
+    let mut buffer = self.lock();
+    let capacity = self.options.capacity.max(1);
+
+    if buffer.unsubscribed || buffer.disconnected {
+      return Delivery::Ended;
+    }
+
+    let overflowed = buffer.items.len() >= capacity;
+
+    if overflowed {
+      match self.options.policy {
+        OverflowPolicy::Block(timeout) => {
+          let deadline = Instant::now() + timeout;
+
+          while buffer.items.len() >= capacity && !buffer.unsubscribed {
+            let now = Instant::now();
+
+            if now >= deadline {
+              break;
+            }
+
+            buffer = self
+              .drained
+              .wait_timeout(buffer, deadline - now)
+              .unwrap_or_else(|poisoned| poisoned.into_inner())
+              .0;
+          }
+
+          if buffer.unsubscribed {
+            return Delivery::Ended;
+          }
+
+          if buffer.items.len() >= capacity {
+            buffer.disconnected = true;
+            self.filled.notify_all();
+
+            return Delivery::Ended;
+          }
+        }
+        OverflowPolicy::DropOldest => {
+          buffer.items.pop_front();
+          buffer.dropped += 1;
+        }
+        OverflowPolicy::Disconnect => {
+          buffer.disconnected = true;
+          self.filled.notify_all();
+
+          return Delivery::Ended;
+        }
+      }
+    }
+
+    let started_lagging = overflowed && !buffer.overflowing;
+
+    buffer.overflowing = overflowed;
+    buffer.items.push_back(item.clone());
+    self.filled.notify_all();
+
+    if started_lagging {
+      Delivery::StartedLagging
+    } else {
+      Delivery::Buffered
+    }
+  }
+}
+
+/// A stream of items published by an instance after subscribing, in order.
+/// May be sent to another thread; dropping it unsubscribes.
+#[derive(Debug)]
+pub struct Receiver<T> {
+  channel: Arc<Channel<T>>,
+}
+
+/// A stream of the commands appended to an instance after subscribing, in order.
+pub type Subscription = Receiver<RawLoggedCommand>;
+
+impl<T: Clone> Receiver<T> {
+  /// Identifier of the subscription.
+  pub fn id(&self) -> u64 {
+    self.channel.id
+  }
+
+  /// How far behind this subscription is.
+  pub fn lag(&self) -> SubscriberLag {
+    self.channel.lag()
+  }
+
+  /// Receive the next item if one is buffered, without waiting.
+  pub fn try_recv(&self) -> Result<Option<T>, MadeleineError> {
+    self.recv_timeout(Duration::ZERO)
+  }
+
+  /// Receive the next item, waiting up to `timeout` for one. Returns `None` if the timeout elapsed first.
+  /// Fails with `MadeleineError::SubscriptionLagged` once a disconnected subscription's buffer is drained.
+  pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<T>, MadeleineError> {
+    let deadline = Instant::now() + timeout;
+    let mut buffer = self.channel.lock();
+
+    loop {
+      if let Some(item) = buffer.items.pop_front() {
+        self.channel.drained.notify_all();
+
+        return Ok(Some(item));
+      }
+
+      if buffer.disconnected {
+        return Err(MadeleineError::SubscriptionLagged(format!(
+          "subscription {} fell more than {} items behind and was disconnected",
+          self.channel.id, self.channel.options.capacity
+        )));
+      }
+
+      let now = Instant::now();
+
+      if now >= deadline {
+        return Ok(None);
+      }
+
+      buffer = self
+        .channel
+        .filled
+        .wait_timeout(buffer, deadline - now)
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .0;
+    }
+  }
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    self
+      .channel
+      .buffer
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .unsubscribed = true;
+    self.channel.drained.notify_all();
+  }
+}
+
+/// The subscriptions to something an instance publishes, such as its appended commands.
+#[derive(Debug)]
+pub(crate) struct Subscribers<T> {
+  channels: Mutex<Vec<Arc<Channel<T>>>>,
+}
+
+impl<T> Default for Subscribers<T> {
+  fn default() -> Self {
+    Self {
+      channels: Mutex::new(Vec::new()),
+    }
+  }
+}
+
+impl<T: Clone> Subscribers<T> {
+  /// Add a subscription.
+  pub fn subscribe(&self, options: SubscribeOptions) -> Result<Receiver<T>, MadeleineError> {
+    let channel = Arc::new(Channel {
+      id: NEXT_SUBSCRIPTION_ID.fetch_add(1, Ordering::Relaxed),
+      options,
+      buffer: Mutex::new(Buffer::default()),
+      filled: Condvar::new(),
+      drained: Condvar::new(),
+    });
+
+    lock_recovering(&self.channels).push(channel.clone());
+
+    Ok(Receiver { channel })
+  }
+
+  /// Determine if there are no subscriptions.
+  pub fn is_empty(&self) -> Result<bool, MadeleineError> {
+    Ok(lock_recovering(&self.channels).is_empty())
+  }
+
+  /// Deliver an item to every subscription, forgetting those which have ended.
+  /// Returns the lag of each subscription which has just started falling behind.
+  pub fn publish(&self, item: &T) -> Result<Vec<SubscriberLag>, MadeleineError> {
+    let mut lagging = Vec::new();
+
+    lock_recovering(&self.channels).retain(|channel| match channel.publish(item) {
+      Delivery::Buffered => true,
+      Delivery::StartedLagging => {
+        lagging.push(channel.lag());
+        true
+      }
+      Delivery::Ended => {
+        if channel.lag().disconnected {
+          lagging.push(channel.lag());
+        }
+        false
+      }
+    });
+
+    Ok(lagging)
+  }
+
+  /// How far behind each live subscription is.
+  pub fn lags(&self) -> Result<Vec<SubscriberLag>, MadeleineError> {
+    let lags = lock_recovering(&self.channels)
+      .iter()
+      .map(|channel| channel.lag())
+      .collect();
+
+    Ok(lags)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::thread;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::Add;
+  use crate::Madeleine;
+
+  fn execute_all(madeleine: &Madeleine<u64>, amounts: std::ops::RangeInclusive<u64>) {
+    for amount in amounts {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+  }
+
+  fn received(command: Option<RawLoggedCommand>) -> Option<Add> {
+    command.and_then(|command| command.deserialize().ok())
+  }
+
+  #[test]
+  fn test_drop_oldest_counts_lag() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let subscription = madeleine
+      .subscribe(SubscribeOptions {
+        capacity: 2,
+        policy: OverflowPolicy::DropOldest,
+      })
+      .expect("unable to subscribe in test");
+
+    execute_all(&madeleine, 1..=5);
+
+    let lag = subscription.lag();
+
+    assert_eq!(lag.buffered, 2);
+    assert_eq!(lag.dropped, 3);
+    assert_eq!(
+      madeleine
+        .subscriber_lags()
+        .expect("unable to read lags in test"),
+      vec![lag]
+    );
+
+    assert_eq!(
+      received(subscription.try_recv().ok().flatten()),
+      Some(Add(4))
+    );
+    assert_eq!(
+      received(subscription.try_recv().ok().flatten()),
+      Some(Add(5))
+    );
+    assert_eq!(subscription.try_recv().ok(), Some(None));
+  }
+
+  #[test]
+  fn test_disconnect_reports_lagged_after_draining() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let subscription = madeleine
+      .subscribe(SubscribeOptions {
+        capacity: 2,
+        policy: OverflowPolicy::Disconnect,
+      })
+      .expect("unable to subscribe in test");
+
+    execute_all(&madeleine, 1..=3);
+
+    assert!(subscription.lag().disconnected);
+    assert_eq!(
+      received(subscription.try_recv().ok().flatten()),
+      Some(Add(1))
+    );
+    assert_eq!(
+      received(subscription.try_recv().ok().flatten()),
+      Some(Add(2))
+    );
+    assert!(matches!(
+      subscription.try_recv(),
+      Err(MadeleineError::SubscriptionLagged(_))
+    ));
+    assert!(madeleine
+      .subscriber_lags()
+      .expect("unable to read lags in test")
+      .is_empty());
+  }
+
+  #[test]
+  fn test_block_waits_for_slow_consumer() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let subscription = madeleine
+      .subscribe(SubscribeOptions {
+        capacity: 1,
+        policy: OverflowPolicy::Block(Duration::from_secs(10)),
+      })
+      .expect("unable to subscribe in test");
+
+    let consumer = thread::spawn(move || {
+      let mut amounts = Vec::new();
+
+      while amounts.len() < 5 {
+        thread::sleep(Duration::from_millis(5));
+
+        if let Some(command) = subscription
+          .recv_timeout(Duration::from_secs(10))
+          .expect("unable to receive in test")
+        {
+          amounts.push(
+            command
+              .deserialize::<Add>()
+              .expect("unable to deserialize in test"),
+          );
+        }
+      }
+
+      (amounts, subscription.lag().dropped)
+    });
+
+    execute_all(&madeleine, 1..=5);
+
+    let (amounts, dropped) = consumer.join().expect("consumer thread panicked in test");
+
+    assert_eq!(amounts, (1..=5).map(Add).collect::<Vec<_>>());
+    assert_eq!(dropped, 0);
+  }
+
+  #[test]
+  fn test_block_disconnects_after_timeout() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let subscription = madeleine
+      .subscribe(SubscribeOptions {
+        capacity: 1,
+        policy: OverflowPolicy::Block(Duration::from_millis(10)),
+      })
+      .expect("unable to subscribe in test");
+
+    execute_all(&madeleine, 1..=2);
+
+    assert_eq!(madeleine.into_inner(), 3);
+    assert!(subscription.lag().disconnected);
+    assert_eq!(
+      received(subscription.try_recv().ok().flatten()),
+      Some(Add(1))
+    );
+    assert!(subscription.try_recv().is_err());
+  }
+}
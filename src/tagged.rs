@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Command, CommandContext, MutCommand};
+use crate::logged_command::RawLoggedCommand;
+
+/// A command logged with metadata tags provided by its caller, such as the ids of the request
+/// or user it was executed for, to audit it by without adding them to the command's own type.
+/// Executing it executes the wrapped command, so it can be passed to `Madeleine::execute_command`
+/// in place of the command. The tags are read back with `RawLoggedCommand::tags`.
+/// Tagged commands are logged, and so replayed, as `TaggedCommand<C>` rather than `C`,
+/// so a store whose commands are sometimes tagged should tag them all, if only with no tags.
+///
+/// ```
+/// # use madeleine::testing::{scratch_store, Add};
+/// # use madeleine::Madeleine;
+/// use madeleine::TaggedCommand;
+/// use ulid::Ulid;
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, || 0)?;
+///
+/// madeleine.execute_command(TaggedCommand::new(Add(2)).with_tag("user_id", "panda"))?;
+///
+/// let logged = madeleine.follower().commands_after(Ulid::nil())?;
+///
+/// assert_eq!(madeleine.tap(|state| state), 2);
+/// assert_eq!(logged[0].tags().unwrap()["user_id"], "panda");
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TaggedCommand<C> {
+  /// Metadata about the command, by name. Kept sorted so that the same tags are always logged the same way.
+  pub tags: BTreeMap<String, String>,
+  /// The command, executed as if it weren't tagged.
+  pub command: C,
+}
+
+impl<C> TaggedCommand<C> {
+  /// Wrap a command, with no tags yet.
+  pub fn new(command: C) -> Self {
+    Self {
+      tags: BTreeMap::new(),
+      command,
+    }
+  }
+
+  /// Tag the command, replacing any tag with the same name.
+  pub fn with_tag(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+    self.tags.insert(name.into(), value.into());
+    self
+  }
+}
+
+impl<'a, C: Command<'a>> Command<'a> for TaggedCommand<C> {
+  type SystemState = C::SystemState;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    self.command.execute(old_state)
+  }
+
+  fn execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Self::SystemState {
+    self.command.execute_with_ctx(old_state, ctx)
+  }
+
+  fn try_execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Result<Self::SystemState, String> {
+    self.command.try_execute_with_ctx(old_state, ctx)
+  }
+
+  fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+    self.command.validate(state)
+  }
+}
+
+impl<'a, C: MutCommand<'a>> MutCommand<'a> for TaggedCommand<C> {
+  fn execute_mut(&self, state: &mut Self::SystemState) {
+    self.command.execute_mut(state)
+  }
+}
+
+/// Just the tags of a logged `TaggedCommand`, ignoring the command itself.
+#[derive(Deserialize)]
+struct Tags {
+  tags: BTreeMap<String, String>,
+}
+
+impl RawLoggedCommand {
+  /// The tags the command was logged with, or `None` if it isn't a `TaggedCommand`.
+  pub fn tags(&self) -> Option<BTreeMap<String, String>> {
+    self.deserialize::<Tags>().ok().map(|tagged| tagged.tags)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+  use ulid::Ulid;
+
+  use crate::{Madeleine, MadeleineError};
+
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct Withdraw(u64);
+
+  impl Command<'_> for Withdraw {
+    type SystemState = u64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state - self.0
+    }
+
+    fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+      if self.0 > *state {
+        return Err(format!("can't withdraw {} from {}", self.0, state));
+      }
+
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_untagged_commands_have_no_tags() {
+    let madeleine =
+      Madeleine::new_in_memory(|| 10_u64).expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .execute_command(Withdraw(3))
+      .expect("unable to execute command in test");
+
+    let logged = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(logged[0].tags(), None);
+  }
+
+  #[test]
+  fn test_tagged_commands_are_executed_validated_and_replayed_like_the_command() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let store_path = temp_dir.path().join("test_store");
+
+    {
+      let madeleine =
+        Madeleine::new(&store_path, || 10_u64).expect("unable to instantiate madeleine in test");
+
+      madeleine
+        .execute_command(
+          TaggedCommand::new(Withdraw(3))
+            .with_tag("request_id", "613")
+            .with_tag("user_id", "panda"),
+        )
+        .expect("unable to execute command in test");
+      madeleine
+        .execute_command(TaggedCommand::new(Withdraw(2)))
+        .expect("unable to execute command in test");
+
+      assert!(matches!(
+        madeleine.execute_command(TaggedCommand::new(Withdraw(20))),
+        Err(MadeleineError::CommandRejected(_))
+      ));
+
+      let logged = madeleine
+        .follower()
+        .commands_after(Ulid::nil())
+        .expect("unable to read commands in test");
+
+      assert_eq!(
+        logged[0].tags(),
+        Some(BTreeMap::from([
+          (String::from("request_id"), String::from("613")),
+          (String::from("user_id"), String::from("panda")),
+        ]))
+      );
+      assert_eq!(logged[1].tags(), Some(BTreeMap::new()));
+      assert_eq!(
+        logged[0]
+          .deserialize::<TaggedCommand<Withdraw>>()
+          .expect("unable to deserialize in test")
+          .command,
+        Withdraw(3)
+      );
+    }
+
+    let madeleine =
+      Madeleine::resume_replaying::<TaggedCommand<Withdraw>, _>(&store_path, || 10_u64)
+        .expect("unable to resume madeleine in test");
+
+    assert_eq!(madeleine.tap(|state| state), 5);
+  }
+}
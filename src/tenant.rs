@@ -0,0 +1,710 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use commitlog::Offset;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command::{Command, CommandContext};
+use crate::command_log::CommandLog;
+use crate::compaction::CompactionReport;
+use crate::export::{ExportFormat, ExportManifest, ExportRange};
+use crate::follower::Follower;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::require_json_entry;
+
+/// Identifies one of the tenants sharing a store.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct TenantId(String);
+
+impl TenantId {
+  /// Identify a tenant.
+  pub fn new(id: impl Into<String>) -> Self {
+    Self(id.into())
+  }
+
+  /// The tenant's identifier as a string.
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+}
+
+impl fmt::Display for TenantId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+/// System state of a store shared by tenants: each tenant's own state, keyed by tenant.
+/// A tenant's state is created with `Default` by its first command.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TenantStates<SystemState> {
+  states: BTreeMap<TenantId, SystemState>,
+}
+
+impl<SystemState> Default for TenantStates<SystemState> {
+  fn default() -> Self {
+    Self {
+      states: BTreeMap::new(),
+    }
+  }
+}
+
+impl<SystemState> TenantStates<SystemState> {
+  /// Get a tenant's state, if it has executed any commands.
+  pub fn get(&self, tenant: &TenantId) -> Option<&SystemState> {
+    self.states.get(tenant)
+  }
+
+  /// Number of tenants with a state.
+  pub fn len(&self) -> usize {
+    self.states.len()
+  }
+
+  /// Determine if no tenant has a state.
+  pub fn is_empty(&self) -> bool {
+    self.states.is_empty()
+  }
+
+  /// Iterate over the tenants in order.
+  pub fn tenants(&self) -> impl Iterator<Item = &TenantId> {
+    self.states.keys()
+  }
+
+  /// Iterate over the tenants' states in tenant order.
+  pub fn iter(&self) -> impl Iterator<Item = (&TenantId, &SystemState)> {
+    self.states.iter()
+  }
+
+  pub(crate) fn remove(&mut self, tenant: &TenantId) -> Option<SystemState> {
+    self.states.remove(tenant)
+  }
+}
+
+/// A command executed on behalf of one tenant, applied to that tenant's state alone.
+/// The tenant is logged with the command, so history can be filtered, counted and purged by tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TenantCommand<C> {
+  /// The tenant the command belongs to.
+  pub tenant: TenantId,
+  /// The command, applied to the tenant's state.
+  pub command: C,
+}
+
+impl<'a, C> Command<'a> for TenantCommand<C>
+where
+  C: Command<'a>,
+  C::SystemState: Default,
+{
+  type SystemState = TenantStates<C::SystemState>;
+
+  fn execute(&self, mut old_state: Self::SystemState) -> Self::SystemState {
+    let state = old_state.remove(&self.tenant).unwrap_or_default();
+
+    old_state
+      .states
+      .insert(self.tenant.clone(), self.command.execute(state));
+
+    old_state
+  }
+
+  fn execute_with_ctx(
+    &self,
+    mut old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Self::SystemState {
+    let state = old_state.remove(&self.tenant).unwrap_or_default();
+
+    old_state.states.insert(
+      self.tenant.clone(),
+      self.command.execute_with_ctx(state, ctx),
+    );
+
+    old_state
+  }
+
+  fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+    match state.get(&self.tenant) {
+      Some(state) => self.command.validate(state),
+      None => self.command.validate(&C::SystemState::default()),
+    }
+  }
+}
+
+/// Just the tenant of a logged `TenantCommand`, ignoring the command itself.
+#[derive(Deserialize)]
+struct TenantTag {
+  tenant: TenantId,
+}
+
+/// The tenant of a raw log entry, or `None` if it isn't a `TenantCommand`.
+pub(crate) fn entry_tenant(entry: &[u8]) -> Option<TenantId> {
+  serde_json::from_slice::<(Ulid, TenantTag)>(entry)
+    .ok()
+    .map(|(_id, tag)| tag.tenant)
+}
+
+/// The tenant of a deserialized payload, or `None` if it isn't a `TenantCommand`.
+pub(crate) fn payload_tenant(payload: &serde_json::Value) -> Option<TenantId> {
+  TenantTag::deserialize(payload).ok().map(|tag| tag.tenant)
+}
+
+impl RawLoggedCommand {
+  /// The tenant the command was executed for, or `None` if it isn't a `TenantCommand`.
+  pub fn tenant(&self) -> Option<TenantId> {
+    serde_json::from_slice::<TenantTag>(&self.payload)
+      .ok()
+      .map(|tag| tag.tenant)
+  }
+}
+
+impl Follower {
+  /// Every command executed for `tenant` after the command identified by `after`, see `Follower::commands_after`.
+  pub fn tenant_commands_after(
+    &self,
+    tenant: &TenantId,
+    after: Ulid,
+  ) -> Result<Vec<RawLoggedCommand>, MadeleineError> {
+    let mut commands = self.commands_after(after)?;
+    commands.retain(|command| command.tenant().as_ref() == Some(tenant));
+
+    Ok(commands)
+  }
+}
+
+/// Rebuild one tenant's state by replaying its commands of type `C` from a store's log, onto the state built by `constructor`.
+/// Other tenants' commands are never deserialized.
+pub fn replay_tenant<C, SystemState, F>(
+  location_dir_path: PathBuf,
+  tenant: &TenantId,
+  constructor: F,
+) -> Result<SystemState, MadeleineError>
+where
+  C: for<'a> Command<'a, SystemState = SystemState>,
+  SystemState: Clone + DeserializeOwned + Serialize,
+  F: FnOnce() -> SystemState,
+{
+  let follower = Follower::open(location_dir_path)?;
+  let commands = follower.tenant_commands_after(tenant, Ulid::nil())?;
+  let commands_compacted = follower.commands_compacted()?;
+  let mut state = constructor();
+
+  for logged in commands
+    .iter()
+    .filter(|logged| !logged.is_skipped_on_replay())
+  {
+    let tenant_command: TenantCommand<C> = logged.deserialize()?;
+    state = tenant_command.command.execute_with_ctx(
+      state,
+      &CommandContext::at(commands_compacted + logged.offset),
+    );
+  }
+
+  Ok(state)
+}
+
+/// Copy every entry of `source` which doesn't belong to `tenant` into a new log at `target_dir_path`,
+/// keeping ULIDs and sequence numbers. Returns the number of entries left out.
+pub(crate) fn copy_log_without_tenant(
+  source: &CommandLog,
+  target_dir_path: &Path,
+  tenant: &TenantId,
+) -> Result<u64, MadeleineError> {
+  let target = CommandLog::with_backend(target_dir_path.to_path_buf(), source.backend())?;
+  target.set_payload_codec(source.payload_codec()?)?;
+  let mut left_out = 0;
+
+  source.for_each_sequenced_entry(|_offset, sequence, entry| {
+    if entry_tenant(require_json_entry(entry, "purging a tenant")?).as_ref() == Some(tenant) {
+      left_out += 1;
+
+      Ok(())
+    } else {
+      target
+        .append_sequenced_entry(entry, sequence)
+        .map(|_offset| ())
+    }
+  })?;
+
+  target.flush()?;
+
+  Ok(left_out)
+}
+
+/// Count the entries of a log belonging to each tenant.
+pub(crate) fn count_by_tenant(
+  command_log: &CommandLog,
+) -> Result<BTreeMap<TenantId, u64>, MadeleineError> {
+  let mut counts = BTreeMap::new();
+
+  command_log.for_each_entry(|_offset, entry| {
+    if let Some(tenant) = entry_tenant(require_json_entry(entry, "counting tenants")?) {
+      *counts.entry(tenant).or_insert(0) += 1;
+    }
+
+    Ok(())
+  })?;
+
+  Ok(counts)
+}
+
+impl<SystemState> Madeleine<TenantStates<SystemState>>
+where
+  SystemState: Clone + Default + DeserializeOwned + Serialize,
+{
+  /// Execute and log a command on behalf of `tenant`, applying it to that tenant's state alone.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{TenantId, TenantStates};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, TenantStates::default)?;
+  /// let acme = TenantId::new("acme");
+  /// let globex = TenantId::new("globex");
+  ///
+  /// madeleine.execute_command_for(&acme, Add(2))?;
+  /// madeleine.execute_command_for(&globex, Add(3))?;
+  ///
+  /// assert_eq!(madeleine.tap_tenant(&acme, |state| state.copied())?, Some(2));
+  /// assert_eq!(madeleine.tap_tenant(&globex, |state| state.copied())?, Some(3));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn execute_command_for<'a, C>(
+    &self,
+    tenant: &TenantId,
+    command: C,
+  ) -> Result<Offset, MadeleineError>
+  where
+    C: Command<'a, SystemState = SystemState>,
+  {
+    self.execute_command(TenantCommand {
+      tenant: tenant.clone(),
+      command,
+    })
+  }
+
+  /// Run a closure passed a tenant's state, or `None` if the tenant has no state.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{TenantId, TenantStates};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, TenantStates::default)?;
+  /// let acme = TenantId::new("acme");
+  ///
+  /// assert_eq!(madeleine.tap_tenant(&acme, |state| state.copied())?, None);
+  ///
+  /// madeleine.execute_command_for(&acme, Add(2))?;
+  ///
+  /// assert_eq!(madeleine.tap_tenant(&acme, |state| state.copied())?, Some(2));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn tap_tenant<T, O>(&self, tenant: &TenantId, func: O) -> Result<T, MadeleineError>
+  where
+    O: FnOnce(Option<&SystemState>) -> T,
+  {
+    self.tap_ref(|states| func(states.get(tenant)))
+  }
+
+  /// Number of commands in the log for each tenant. Tenants without logged commands are absent.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{TenantId, TenantStates};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, TenantStates::default)?;
+  /// let acme = TenantId::new("acme");
+  ///
+  /// madeleine.execute_command_for(&acme, Add(2))?;
+  /// madeleine.execute_command_for(&acme, Add(3))?;
+  /// madeleine.execute_command_for(&TenantId::new("globex"), Add(4))?;
+  ///
+  /// let counts = madeleine.tenant_command_counts()?;
+  ///
+  /// assert_eq!(counts.len(), 2);
+  /// assert_eq!(counts[&acme], 2);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn tenant_command_counts(&self) -> Result<BTreeMap<TenantId, u64>, MadeleineError> {
+    count_by_tenant(self.command_log())
+  }
+
+  /// Export only `tenant`'s commands in `range`, see `Madeleine::export`. The manifest records the tenant.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::export::{ExportFormat, ExportRange};
+  /// use madeleine::{TenantId, TenantStates};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, TenantStates::default)?;
+  /// let acme = TenantId::new("acme");
+  ///
+  /// madeleine.execute_command_for(&acme, Add(2))?;
+  /// madeleine.execute_command_for(&TenantId::new("globex"), Add(3))?;
+  ///
+  /// let range = ExportRange::default();
+  /// let manifest = madeleine.export_tenant(ExportFormat::Jsonl, range, &acme, Vec::new())?;
+  ///
+  /// assert_eq!(manifest.tenant, Some(acme));
+  /// assert_eq!(manifest.row_count, 1);
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn export_tenant<W: std::io::Write>(
+    &self,
+    format: ExportFormat,
+    range: ExportRange,
+    tenant: &TenantId,
+    writer: W,
+  ) -> Result<ExportManifest, MadeleineError> {
+    self.export_filtered(format, range, Some(tenant), writer)
+  }
+
+  /// Delete everything the store holds about `tenant`, e.g. when its account is deleted: its state,
+  /// its commands in the log, and every snapshot taken while it had a state.
+  ///
+  /// The purge is journaled like a compaction, see `Madeleine::compact`, so a crash leaves either the tenant
+  /// entirely present or entirely gone once the store is next opened. Other tenants' commands are kept in the log.
+  ///
+  /// ```
+  /// # use madeleine::testing::{scratch_store, Add};
+  /// # use madeleine::Madeleine;
+  /// use madeleine::{TenantId, TenantStates};
+  ///
+  /// let store = scratch_store();
+  /// let madeleine = Madeleine::new(&store, TenantStates::default)?;
+  /// let acme = TenantId::new("acme");
+  /// let globex = TenantId::new("globex");
+  ///
+  /// madeleine.execute_command_for(&acme, Add(2))?;
+  /// madeleine.execute_command_for(&globex, Add(3))?;
+  ///
+  /// let report = madeleine.purge_tenant(&acme)?;
+  ///
+  /// assert_eq!(report.commands_removed, 1);
+  /// assert_eq!(madeleine.tap_tenant(&acme, |state| state.copied())?, None);
+  /// assert_eq!(madeleine.tap_tenant(&globex, |state| state.copied())?, Some(3));
+  /// # Ok::<(), madeleine::MadeleineError>(())
+  /// ```
+  pub fn purge_tenant(&self, tenant: &TenantId) -> Result<CompactionReport, MadeleineError> {
+    let mut staged = self.tap_ref(TenantStates::clone)?;
+    staged.remove(tenant);
+
+    self.rewrite_history(Some(tenant), Some(staged))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::export::import;
+  use crate::testing::{FailpointStore, StorageOperation};
+  use crate::CompactionStage;
+
+  use std::sync::Arc;
+
+  /// Adds to a balance which must never go negative.
+  #[derive(Debug, Clone, Deserialize, Serialize)]
+  struct Add(i64);
+
+  impl Command<'_> for Add {
+    type SystemState = i64;
+
+    fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+      old_state + self.0
+    }
+
+    fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+      if state + self.0 < 0 {
+        Err(format!("balance of {} can't cover {}", state, self.0))
+      } else {
+        Ok(())
+      }
+    }
+  }
+
+  fn tenants() -> (TenantId, TenantId, TenantId) {
+    (TenantId::new("a"), TenantId::new("b"), TenantId::new("c"))
+  }
+
+  /// Create a store in which tenants a, b and c interleave their commands, ending with balances of 6, 20 and 100.
+  fn interleaved_store(location_dir_path: &Path) -> Madeleine<TenantStates<i64>> {
+    let madeleine = Madeleine::new(location_dir_path.to_path_buf(), TenantStates::default)
+      .expect("unable to instantiate madeleine in test");
+    let (a, b, c) = tenants();
+
+    for (tenant, amount) in [(&a, 1), (&b, 10), (&a, 2), (&c, 100), (&b, 10), (&a, 3)] {
+      madeleine
+        .execute_command_for(tenant, Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    madeleine
+  }
+
+  #[test]
+  fn test_tenants_are_isolated() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+    let madeleine = interleaved_store(&store_path);
+    let (a, b, c) = tenants();
+
+    let rejected = madeleine.execute_command_for(&a, Add(-7));
+
+    assert!(matches!(rejected, Err(MadeleineError::CommandRejected(_))));
+    assert_eq!(
+      madeleine.tap_tenant(&a, |state| state.copied()).ok(),
+      Some(Some(6))
+    );
+    assert_eq!(
+      madeleine.tap_tenant(&b, |state| state.copied()).ok(),
+      Some(Some(20))
+    );
+    assert_eq!(
+      madeleine
+        .tap_tenant(&TenantId::new("d"), |state| state.copied())
+        .ok(),
+      Some(None)
+    );
+    assert_eq!(
+      madeleine
+        .tenant_command_counts()
+        .expect("unable to count commands in test"),
+      BTreeMap::from([(a.clone(), 3), (b.clone(), 2), (c.clone(), 1)])
+    );
+
+    let follower = Follower::open(store_path.clone()).expect("unable to open follower in test");
+    let b_commands = follower
+      .tenant_commands_after(&b, Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(
+      b_commands
+        .iter()
+        .map(|command| command.offset)
+        .collect::<Vec<u64>>(),
+      vec![1, 4]
+    );
+    assert_eq!(
+      replay_tenant::<Add, i64, _>(store_path.clone(), &a, || 0)
+        .expect("unable to replay tenant in test"),
+      6
+    );
+
+    let mut exported = Vec::new();
+    let manifest = madeleine
+      .export_tenant(
+        ExportFormat::Jsonl,
+        ExportRange::default(),
+        &c,
+        &mut exported,
+      )
+      .expect("unable to export tenant in test");
+
+    assert_eq!(manifest.row_count, 1);
+    assert_eq!(manifest.tenant, Some(c.clone()));
+
+    let imported_path = temp_dir.path().join("imported_store");
+
+    import(&manifest, exported.as_slice(), imported_path.clone())
+      .expect("unable to import tenant export in test");
+
+    assert_eq!(
+      replay_tenant::<Add, i64, _>(imported_path, &c, || 0)
+        .expect("unable to replay import in test"),
+      100
+    );
+  }
+
+  /// Sums every tenant's balance.
+  #[derive(Debug, Serialize)]
+  struct TotalBalance;
+
+  impl crate::Query for TotalBalance {
+    type SystemState = TenantStates<i64>;
+    type Output = i64;
+
+    fn execute(&self, state: &Self::SystemState) -> Self::Output {
+      state.iter().map(|(_tenant, balance)| balance).sum()
+    }
+  }
+
+  #[test]
+  fn test_purge_invalidates_cached_queries() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+    let madeleine = interleaved_store(&store_path);
+    let (_a, b, _c) = tenants();
+
+    madeleine
+      .set_query_cache(Some(crate::QueryCacheOptions::default()))
+      .expect("unable to enable query cache in test");
+
+    assert_eq!(
+      madeleine.execute_query_cached(&TotalBalance).ok(),
+      Some(126)
+    );
+
+    let head_id = madeleine.head_id().expect("unable to read head in test");
+
+    madeleine
+      .purge_tenant(&b)
+      .expect("unable to purge tenant in test");
+
+    assert_eq!(madeleine.head_id().ok(), Some(head_id));
+    assert_eq!(
+      madeleine.execute_query_cached(&TotalBalance).ok(),
+      Some(106)
+    );
+    assert_eq!(madeleine.metrics().query_cache_misses(), 2);
+  }
+
+  #[test]
+  fn test_purge_removes_only_one_tenant() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+    let madeleine = interleaved_store(&store_path);
+    let (a, b, c) = tenants();
+
+    madeleine
+      .take_snapshot(false)
+      .expect("unable to take snapshot in test");
+
+    let report = madeleine
+      .purge_tenant(&a)
+      .expect("unable to purge tenant in test");
+
+    assert_eq!(
+      report,
+      CompactionReport {
+        snapshot_id: 1,
+        commands_removed: 3,
+      }
+    );
+    assert_eq!(
+      madeleine.tap_tenant(&a, |state| state.copied()).ok(),
+      Some(None)
+    );
+    assert_eq!(
+      madeleine
+        .tenant_command_counts()
+        .expect("unable to count commands in test"),
+      BTreeMap::from([(b.clone(), 2), (c.clone(), 1)])
+    );
+    assert!(!crate::madeleine::snapshot_file_path(0, store_path.clone()).exists());
+
+    madeleine
+      .execute_command_for(&b, Add(1))
+      .expect("unable to execute command after purge in test");
+
+    drop(madeleine);
+
+    assert_eq!(
+      replay_tenant::<Add, i64, _>(store_path.clone(), &b, || 0)
+        .expect("unable to replay tenant in test"),
+      21
+    );
+    assert_eq!(
+      replay_tenant::<Add, i64, _>(store_path.clone(), &a, || 0)
+        .expect("unable to replay tenant in test"),
+      0
+    );
+
+    let resumed =
+      Madeleine::resume_replaying::<TenantCommand<Add>, _>(store_path, TenantStates::default)
+        .expect("unable to resume madeleine in test");
+
+    assert_eq!(
+      resumed
+        .tap_ref(|states| states.tenants().cloned().collect::<Vec<TenantId>>())
+        .ok(),
+      Some(vec![b.clone(), c])
+    );
+    assert_eq!(
+      resumed.tap_tenant(&b, |state| state.copied()).ok(),
+      Some(Some(21))
+    );
+  }
+
+  #[test]
+  fn test_interrupted_purge_is_all_or_nothing() {
+    let cases = [
+      (StorageOperation::SnapshotWrite, Some(6)),
+      (StorageOperation::CompactionSwap, None),
+      (
+        StorageOperation::CompactionStage(CompactionStage::RowsDeleted),
+        None,
+      ),
+    ];
+
+    for (fail_point, balance) in cases {
+      let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+      let store_path = temp_dir.path().join("test_store");
+      let (a, b, _c) = tenants();
+
+      {
+        let madeleine = interleaved_store(&store_path);
+
+        madeleine
+          .take_snapshot(false)
+          .expect("unable to take snapshot in test");
+
+        let failpoints = FailpointStore::new();
+        failpoints.fail_nth(fail_point, 1);
+
+        madeleine
+          .set_failpoints(Some(Arc::new(failpoints)))
+          .expect("unable to set failpoints in test");
+
+        assert!(madeleine.purge_tenant(&a).is_err(), "{:?}", fail_point);
+
+        // A purge which failed before its snapshot leaves the tenant in place.
+        if balance.is_some() {
+          assert_eq!(
+            madeleine.tap_tenant(&a, |state| state.copied()).ok(),
+            Some(balance)
+          );
+        }
+      }
+
+      let resumed = Madeleine::<TenantStates<i64>>::resume(store_path.clone())
+        .expect("unable to resume madeleine in test");
+      let counts = resumed
+        .tenant_command_counts()
+        .expect("unable to count commands in test");
+
+      assert_eq!(
+        resumed.tap_tenant(&a, |state| state.copied()).ok(),
+        Some(balance),
+        "{:?}",
+        fail_point
+      );
+      assert_eq!(
+        counts.get(&a).copied(),
+        balance.map(|_balance| 3),
+        "{:?}",
+        fail_point
+      );
+      assert_eq!(counts.get(&b), Some(&2), "{:?}", fail_point);
+    }
+  }
+}
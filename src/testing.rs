@@ -0,0 +1,779 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command::{Command, CommandContext};
+use crate::command_store::{CommandStore, StoredRecord};
+use crate::compaction::CompactionStage;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::rate_limit::Clock;
+use crate::sealed::Sealed;
+use crate::store_path::StorePath;
+
+/// Message of the I/O errors raised by a `FaultInjector`, so tests can tell them apart from real failures.
+pub const INJECTED_FAULT_MESSAGE: &str = "injected append failure";
+
+/// Makes a pseudo-random fraction of appends fail with an I/O error, for soak and fault-tolerance tests.
+/// Give it to `FailpointCommandStore::with_fault_injector`. The same seed always fails the same appends.
+#[derive(Debug)]
+pub struct FaultInjector {
+  fail_one_in: u64,
+  rng_state: AtomicU64,
+  forced: AtomicU64,
+  attempts: AtomicU64,
+  injected: AtomicU64,
+}
+
+impl FaultInjector {
+  /// Fail roughly one append in `fail_one_in`, or none if it's zero, choosing which with a generator seeded by `seed`.
+  pub fn failing_one_in(fail_one_in: u64, seed: u64) -> Self {
+    Self {
+      fail_one_in,
+      // The generator never leaves zero, so nudge a zero seed.
+      rng_state: AtomicU64::new(seed.max(1)),
+      forced: AtomicU64::new(0),
+      attempts: AtomicU64::new(0),
+      injected: AtomicU64::new(0),
+    }
+  }
+
+  /// Fail the next `count` appends, regardless of chance.
+  pub fn fail_next(&self, count: u64) {
+    self.forced.fetch_add(count, Ordering::SeqCst);
+  }
+
+  /// Number of appends attempted since the injector was created.
+  pub fn attempts(&self) -> u64 {
+    self.attempts.load(Ordering::SeqCst)
+  }
+
+  /// Number of appends made to fail since the injector was created.
+  pub fn injected(&self) -> u64 {
+    self.injected.load(Ordering::SeqCst)
+  }
+
+  /// Decide the fate of an append about to be made.
+  pub(crate) fn before_append(&self) -> Result<(), MadeleineError> {
+    self.attempts.fetch_add(1, Ordering::SeqCst);
+
+    let forced = self
+      .forced
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |forced| {
+        forced.checked_sub(1)
+      })
+      .is_ok();
+
+    if forced || (self.fail_one_in > 0 && self.next_random().is_multiple_of(self.fail_one_in)) {
+      self.injected.fetch_add(1, Ordering::SeqCst);
+
+      return Err(MadeleineError::FileIOError(io::Error::other(
+        INJECTED_FAULT_MESSAGE,
+      )));
+    }
+
+    Ok(())
+  }
+
+  /// Advance the xorshift64 generator.
+  fn next_random(&self) -> u64 {
+    let step = |mut x: u64| {
+      x ^= x << 13;
+      x ^= x >> 7;
+      x ^= x << 17;
+      x
+    };
+
+    let previous = self
+      .rng_state
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| Some(step(x)))
+      .unwrap_or_else(|x| x);
+
+    step(previous)
+  }
+}
+
+/// A storage operation at which a `FailpointStore` can intervene.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StorageOperation {
+  /// Appending one or more commands to the command log.
+  Append,
+  /// After a command or batch has been applied to the state, but before it's appended to the log.
+  BeforeCommit,
+  /// Writing a snapshot file.
+  SnapshotWrite,
+  /// After a command executed with an idempotency key has been logged, but before its output is saved with the key.
+  IdempotencyRecord,
+  /// Just after a compaction journals that it reached a stage.
+  CompactionStage(CompactionStage),
+  /// Between a compaction moving the old command log away and moving the empty one in.
+  CompactionSwap,
+}
+
+/// What a `FailpointStore` does when a scripted operation happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailpointAction {
+  /// Fail with an I/O error, as though the process died at this point.
+  Fail,
+  /// Fail with an I/O error of kind `ResourceBusy`.
+  Busy,
+  /// Sleep, then carry on.
+  Delay(Duration),
+}
+
+/// A scripted intervention, applying to one call of an operation or every call.
+#[derive(Debug, Clone, Copy)]
+struct Rule {
+  operation: StorageOperation,
+  nth: Option<u64>,
+  action: FailpointAction,
+}
+
+/// Scripted failures and delays for a store's storage operations, for testing how code copes with them.
+/// Install it with `Madeleine::set_failpoints`; calls are counted per operation from then on.
+#[derive(Debug, Default)]
+pub struct FailpointStore {
+  rules: Mutex<Vec<Rule>>,
+  calls: Mutex<HashMap<StorageOperation, u64>>,
+}
+
+impl FailpointStore {
+  /// Script nothing yet, so that every operation succeeds.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Take `action` on the `nth` call of `operation`, counting from one.
+  pub fn on_nth(&self, operation: StorageOperation, nth: u64, action: FailpointAction) -> &Self {
+    self.add_rule(Rule {
+      operation,
+      nth: Some(nth),
+      action,
+    })
+  }
+
+  /// Take `action` on every call of `operation`.
+  pub fn on_every(&self, operation: StorageOperation, action: FailpointAction) -> &Self {
+    self.add_rule(Rule {
+      operation,
+      nth: None,
+      action,
+    })
+  }
+
+  /// Fail the `nth` call of `operation`, counting from one.
+  pub fn fail_nth(&self, operation: StorageOperation, nth: u64) -> &Self {
+    self.on_nth(operation, nth, FailpointAction::Fail)
+  }
+
+  /// Forget every scripted action, so that operations succeed again.
+  pub fn clear(&self) {
+    lock_recovering(&self.rules).clear();
+  }
+
+  /// Number of calls of `operation` so far.
+  pub fn calls(&self, operation: StorageOperation) -> u64 {
+    lock_recovering(&self.calls)
+      .get(&operation)
+      .copied()
+      .unwrap_or(0)
+  }
+
+  /// Count a call of `operation`, taking whatever actions are scripted for it.
+  pub(crate) fn check(&self, operation: StorageOperation) -> Result<(), MadeleineError> {
+    let call = {
+      let mut calls = lock_recovering(&self.calls);
+      let count = calls.entry(operation).or_insert(0);
+      *count += 1;
+      *count
+    };
+
+    let actions: Vec<FailpointAction> = lock_recovering(&self.rules)
+      .iter()
+      .filter(|rule| rule.operation == operation && rule.nth.is_none_or(|nth| nth == call))
+      .map(|rule| rule.action)
+      .collect();
+
+    for action in actions {
+      match action {
+        FailpointAction::Fail => {
+          return Err(MadeleineError::FileIOError(io::Error::other(format!(
+            "failpoint at {:?} call {}",
+            operation, call
+          ))))
+        }
+        FailpointAction::Busy => {
+          return Err(MadeleineError::FileIOError(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!("failpoint busy at {:?} call {}", operation, call),
+          )))
+        }
+        FailpointAction::Delay(duration) => thread::sleep(duration),
+      }
+    }
+
+    Ok(())
+  }
+
+  fn add_rule(&self, rule: Rule) -> &Self {
+    lock_recovering(&self.rules).push(rule);
+    self
+  }
+}
+
+/// A `CommandStore` wrapping another, which gives a `FailpointStore` the chance to intervene before each append,
+/// counted as `StorageOperation::Append`, then a `FaultInjector`, if given one, the chance to fail it, e.g. for soak tests.
+/// Give it to `MadeleineBuilder::command_store` to wrap a store of one's own, or to `MadeleineBuilder::wrap_command_store`
+/// to wrap a built-in backend.
+///
+/// ```
+/// # use madeleine::testing::{scratch_store, Add};
+/// # use madeleine::Madeleine;
+/// use std::sync::Arc;
+///
+/// use madeleine::testing::{FailpointCommandStore, FailpointStore, StorageOperation};
+/// use madeleine::LogBackend;
+///
+/// let store = scratch_store();
+/// let log = scratch_store();
+/// let failpoints = Arc::new(FailpointStore::new());
+/// failpoints.fail_nth(StorageOperation::Append, 2);
+///
+/// let command_store = FailpointCommandStore::new(LogBackend::File.open(log.path())?, failpoints);
+/// let madeleine = Madeleine::builder(&store).command_store(command_store).build(|| 0)?;
+///
+/// madeleine.execute_command(Add(2))?;
+/// assert!(madeleine.execute_command(Add(3)).is_err());
+/// madeleine.execute_command(Add(4))?;
+///
+/// assert_eq!(madeleine.tap(|state| state), 6);
+/// assert_eq!(madeleine.len(), 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub struct FailpointCommandStore<S> {
+  inner: S,
+  failpoints: Arc<FailpointStore>,
+  fault_injector: Option<Arc<FaultInjector>>,
+}
+
+impl<S: CommandStore> FailpointCommandStore<S> {
+  /// Wrap a store, scripting its failures with `failpoints`.
+  pub fn new(inner: S, failpoints: Arc<FailpointStore>) -> Self {
+    Self {
+      inner,
+      failpoints,
+      fault_injector: None,
+    }
+  }
+
+  /// Also fail appends as decided by `fault_injector`.
+  pub fn with_fault_injector(mut self, fault_injector: Arc<FaultInjector>) -> Self {
+    self.fault_injector = Some(fault_injector);
+    self
+  }
+
+  /// The wrapped store.
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+}
+
+impl<S: CommandStore> FailpointCommandStore<S> {
+  /// Give the failpoints, then the fault injector, the chance to fail an append about to be made.
+  fn before_append(&self) -> Result<(), MadeleineError> {
+    self.failpoints.check(StorageOperation::Append)?;
+
+    match &self.fault_injector {
+      Some(fault_injector) => fault_injector.before_append(),
+      None => Ok(()),
+    }
+  }
+}
+
+impl<S: CommandStore> CommandStore for FailpointCommandStore<S> {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.before_append()?;
+    self.inner.append(entry)
+  }
+
+  fn append_batch(&mut self, records: &[(&[u8], Option<u64>)]) -> Result<u64, MadeleineError> {
+    self.before_append()?;
+    self.inner.append_batch(records)
+  }
+
+  fn read(&self, offset: u64, max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    self.inner.read(offset, max_bytes)
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    self.inner.truncate(keep)
+  }
+
+  fn flush(&mut self) -> Result<(), MadeleineError> {
+    self.inner.flush()
+  }
+
+  fn len(&self) -> u64 {
+    self.inner.len()
+  }
+}
+
+/// A clock which only moves when told to, for testing rate limits deterministically.
+/// Install it with `Madeleine::set_clock`.
+#[derive(Debug)]
+pub struct ManualClock {
+  now: Mutex<Instant>,
+}
+
+impl ManualClock {
+  /// Start the clock at the current time.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Move the clock forward.
+  pub fn advance(&self, by: Duration) {
+    *lock_recovering(&self.now) += by;
+  }
+}
+
+impl Default for ManualClock {
+  fn default() -> Self {
+    Self {
+      now: Mutex::new(Instant::now()),
+    }
+  }
+}
+
+impl Sealed for ManualClock {}
+
+impl Clock for ManualClock {
+  fn now(&self) -> Instant {
+    *lock_recovering(&self.now)
+  }
+}
+
+/// The location of a throwaway store in a new temporary directory, which is removed when this is dropped,
+/// see `scratch_store`.
+#[derive(Debug)]
+pub struct ScratchStore {
+  root_dir_path: PathBuf,
+  store_path: PathBuf,
+}
+
+impl ScratchStore {
+  /// Where to create the store, inside the temporary directory.
+  pub fn path(&self) -> &Path {
+    &self.store_path
+  }
+}
+
+impl From<&ScratchStore> for StorePath {
+  fn from(scratch_store: &ScratchStore) -> Self {
+    Self::from(scratch_store.path())
+  }
+}
+
+impl Drop for ScratchStore {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.root_dir_path);
+  }
+}
+
+/// A location for a throwaway store, e.g. in examples and tests, whose directory is removed when it's dropped.
+/// Keep it for as long as the store is used, and declare it first so that it's dropped after the store.
+///
+/// ```
+/// # use madeleine::Madeleine;
+/// # use madeleine::testing::Add;
+/// let store = madeleine::testing::scratch_store();
+/// let madeleine = Madeleine::new(&store, || 0)?;
+///
+/// madeleine.execute_command(Add(2))?;
+///
+/// assert_eq!(madeleine.tap(|state| state), 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub fn scratch_store() -> ScratchStore {
+  let root_dir_path = std::env::temp_dir().join(format!("madeleine-scratch-{}", Ulid::new()));
+  // The store's parent must exist for `MadeleineBuilder::build`. Should this fail, so will creating the store.
+  let _ = fs::create_dir_all(&root_dir_path);
+
+  ScratchStore {
+    store_path: root_dir_path.join("store"),
+    root_dir_path,
+  }
+}
+
+/// Adds to a counter. A command for examples and tests which need one, but aren't about commands.
+///
+/// ```
+/// use madeleine::testing::{scratch_store, Add};
+/// use madeleine::Madeleine;
+///
+/// let store = scratch_store();
+/// let madeleine = Madeleine::new(&store, || 0)?;
+/// madeleine.execute_command(Add(2))?;
+///
+/// assert_eq!(madeleine.tap(|state| state), 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Add(pub u64);
+
+impl Command<'_> for Add {
+  type SystemState = u64;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    old_state + self.0
+  }
+}
+
+/// Drives a store in a temporary directory through commands, crashes, compactions and snapshots,
+/// keeping its own model of what the store should hold, so property tests can check persistence
+/// of their own command and state types with `assert_invariants` after any interleaving of operations.
+/// The directory is removed when the harness is dropped.
+pub struct PersistenceHarness<C, S: Clone + for<'a> Deserialize<'a> + Serialize> {
+  root_dir_path: PathBuf,
+  constructor: fn() -> S,
+  madeleine: Option<Madeleine<S>>,
+  /// Every command applied so far, folded into the state built by the constructor.
+  expected_state: S,
+  /// The state the command log starts from, i.e. as of the last compaction.
+  log_base_state: S,
+  commands_logged: u64,
+  commands_applied: u64,
+  command_type: PhantomData<fn() -> C>,
+}
+
+impl<C, S> PersistenceHarness<C, S>
+where
+  C: for<'a> Command<'a, SystemState = S> + Serialize + DeserializeOwned,
+  S: Clone + for<'a> Deserialize<'a> + Serialize,
+{
+  /// Create an empty store in a new temporary directory, whose state is built by `constructor`.
+  pub fn new(constructor: fn() -> S) -> Result<Self, MadeleineError> {
+    let root_dir_path = std::env::temp_dir().join(format!("madeleine-harness-{}", Ulid::new()));
+    fs::create_dir_all(&root_dir_path)?;
+
+    let madeleine = Madeleine::new(root_dir_path.join("store"), constructor)?;
+
+    Ok(Self {
+      root_dir_path,
+      constructor,
+      madeleine: Some(madeleine),
+      expected_state: constructor(),
+      log_base_state: constructor(),
+      commands_logged: 0,
+      commands_applied: 0,
+      command_type: PhantomData,
+    })
+  }
+
+  /// The store being driven.
+  pub fn madeleine(&self) -> &Madeleine<S> {
+    self
+      .madeleine
+      .as_ref()
+      .expect("the store is open except while resuming")
+  }
+
+  /// Execute each of `commands` in turn, stopping at the first which fails.
+  pub fn apply(&mut self, commands: impl IntoIterator<Item = C>) -> Result<(), MadeleineError> {
+    for command in commands {
+      let expected_state = command.execute_with_ctx(
+        self.expected_state.clone(),
+        &CommandContext::at(self.commands_applied),
+      );
+
+      self.madeleine().execute_command(command)?;
+
+      self.expected_state = expected_state;
+      self.commands_logged += 1;
+      self.commands_applied += 1;
+    }
+
+    Ok(())
+  }
+
+  /// Drop the store without closing it, as though the process died, then resume it from its snapshot and log.
+  pub fn crash_and_resume(&mut self) -> Result<(), MadeleineError> {
+    drop(self.madeleine.take());
+
+    let madeleine =
+      Madeleine::resume_replaying::<C, _>(self.root_dir_path.join("store"), self.constructor)?;
+
+    self.madeleine = Some(madeleine);
+
+    Ok(())
+  }
+
+  /// Compact the store, see `Madeleine::compact`.
+  pub fn compact(&mut self) -> Result<(), MadeleineError> {
+    self.madeleine().compact()?;
+
+    self.log_base_state = self.expected_state.clone();
+    self.commands_logged = 0;
+
+    Ok(())
+  }
+
+  /// Take a snapshot unless the state is unchanged since the last, returning its id.
+  pub fn snapshot(&mut self) -> Result<usize, MadeleineError> {
+    self.madeleine().take_snapshot(false)
+  }
+
+  /// Check the store against the harness's model of it:
+  /// its state hashes the same as every command applied so far, and as the commands in its log folded into
+  /// the state as of the last compaction; its length and head match the log; and nothing applied was lost.
+  ///
+  /// # Panics
+  ///
+  /// Panics describing the first invariant which doesn't hold.
+  pub fn assert_invariants(&self) {
+    let madeleine = self.madeleine();
+    let hash_algo = madeleine.hash_algo();
+    let hash = |state: &S| {
+      hash_algo
+        .canonical_hash(state)
+        .expect("unable to hash state")
+    };
+
+    let state_hash = madeleine.state_hash().expect("unable to hash state");
+
+    assert_eq!(
+      state_hash,
+      hash(&self.expected_state),
+      "state differs from every command applied"
+    );
+
+    let logged = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read command log");
+
+    let commands_compacted = self.commands_applied - self.commands_logged;
+    let folded = logged
+      .iter()
+      .filter(|logged| !logged.is_skipped_on_replay())
+      .fold(self.log_base_state.clone(), |state, logged| {
+        logged
+          .deserialize::<C>()
+          .expect("unable to deserialize logged command")
+          .execute_with_ctx(
+            state,
+            &CommandContext::at(commands_compacted + logged.offset),
+          )
+      });
+
+    assert_eq!(
+      state_hash,
+      hash(&folded),
+      "state differs from the fold of the command log"
+    );
+    assert_eq!(
+      logged.len() as u64,
+      self.commands_logged,
+      "command log holds the wrong number of commands"
+    );
+    assert_eq!(
+      madeleine.len(),
+      self.commands_logged,
+      "len differs from the command log"
+    );
+    assert_eq!(
+      madeleine.is_empty(),
+      self.commands_logged == 0,
+      "is_empty differs from the command log"
+    );
+    assert_eq!(
+      madeleine.total_commands_ever(),
+      self.commands_applied,
+      "total_commands_ever differs from every command applied"
+    );
+    assert_eq!(
+      madeleine.head_id().expect("unable to read head"),
+      logged.last().map_or(Ulid::nil(), |logged| logged.id),
+      "head differs from the last logged command"
+    );
+  }
+}
+
+impl<C, S: Clone + for<'a> Deserialize<'a> + Serialize> Drop for PersistenceHarness<C, S> {
+  fn drop(&mut self) {
+    drop(self.madeleine.take());
+
+    let _ = fs::remove_dir_all(&self.root_dir_path);
+  }
+}
+
+/// Scripts are always left consistent, even if a thread panicked while holding them.
+fn lock_recovering<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+  mutex
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use std::time::Instant;
+
+  use crate::{BatchMode, Madeleine, ReadOnlyMadeleine};
+
+  #[test]
+  fn test_failed_appends_leave_state_unchanged() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let store_path = temp_dir.path().join("test_store");
+
+    let fault_injector = Arc::new(FaultInjector::failing_one_in(3, 613));
+    let wrapped_injector = fault_injector.clone();
+
+    let madeleine = Madeleine::builder(store_path.clone())
+      .wrap_command_store(move |inner| {
+        Box::new(
+          FailpointCommandStore::new(inner, Arc::new(FailpointStore::new()))
+            .with_fault_injector(wrapped_injector.clone()),
+        )
+      })
+      .build(|| 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    fault_injector.fail_next(1);
+
+    let mut expected = 0;
+
+    for amount in 1..=30 {
+      match madeleine.execute_command(Add(amount)) {
+        Ok(_offset) => expected += amount,
+        Err(MadeleineError::FileIOError(error)) => {
+          assert_eq!(error.to_string(), INJECTED_FAULT_MESSAGE)
+        }
+        Err(error) => panic!("unexpected error in test: {}", error),
+      }
+    }
+
+    assert_eq!(fault_injector.attempts(), 30);
+    assert!(fault_injector.injected() > 1);
+    assert!(fault_injector.injected() < 30);
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(expected));
+
+    let replica = ReadOnlyMadeleine::<Add, u64>::open(store_path, || 0_u64)
+      .expect("unable to open replica in test");
+
+    assert_eq!(replica.into_inner(), expected);
+  }
+
+  fn store_with_failpoints(temp_dir: &assert_fs::TempDir) -> (Madeleine<u64>, Arc<FailpointStore>) {
+    let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+      .expect("unable to instantiate madeleine in test");
+
+    let failpoints = Arc::new(FailpointStore::new());
+
+    madeleine
+      .set_failpoints(Some(failpoints.clone()))
+      .expect("unable to set failpoints in test");
+
+    (madeleine, failpoints)
+  }
+
+  #[test]
+  fn test_failpoints_fail_nth_append_and_report_busy() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (madeleine, failpoints) = store_with_failpoints(&temp_dir);
+
+    failpoints.fail_nth(StorageOperation::Append, 2).on_nth(
+      StorageOperation::Append,
+      4,
+      FailpointAction::Busy,
+    );
+
+    let outcomes: Vec<bool> = (1..=5)
+      .map(|amount| madeleine.execute_command(Add(amount)).is_ok())
+      .collect();
+
+    assert_eq!(outcomes, vec![true, false, true, false, true]);
+    assert_eq!(failpoints.calls(StorageOperation::Append), 5);
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(1 + 3 + 5));
+
+    failpoints.on_every(StorageOperation::Append, FailpointAction::Busy);
+
+    assert!(matches!(
+      madeleine.execute_command(Add(6)),
+      Err(MadeleineError::FileIOError(ref error)) if error.kind() == io::ErrorKind::ResourceBusy
+    ));
+
+    failpoints.clear();
+
+    madeleine
+      .execute_command(Add(6))
+      .expect("unable to execute command after clearing failpoints in test");
+  }
+
+  #[test]
+  fn test_failpoints_before_commit_roll_back_batches() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (madeleine, failpoints) = store_with_failpoints(&temp_dir);
+
+    failpoints.fail_nth(StorageOperation::BeforeCommit, 1);
+
+    let failed = madeleine.execute_batch(vec![Add(1), Add(2)], BatchMode::Atomic);
+
+    assert!(matches!(failed, Err(MadeleineError::FileIOError(_))));
+    assert_eq!(failpoints.calls(StorageOperation::Append), 0);
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(0));
+
+    let report = madeleine
+      .execute_batch(vec![Add(1), Add(2)], BatchMode::Atomic)
+      .expect("unable to execute batch in test");
+
+    assert_eq!(report.offsets.len(), 2);
+    assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(3));
+  }
+
+  #[test]
+  fn test_failpoints_delay_and_fail_snapshots() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let (madeleine, failpoints) = store_with_failpoints(&temp_dir);
+
+    failpoints
+      .on_every(
+        StorageOperation::Append,
+        FailpointAction::Delay(Duration::from_millis(20)),
+      )
+      .fail_nth(StorageOperation::SnapshotWrite, 1);
+
+    let started = Instant::now();
+
+    for amount in [1, 2] {
+      madeleine
+        .execute_command(Add(amount))
+        .expect("unable to execute command in test");
+    }
+
+    assert!(started.elapsed() >= Duration::from_millis(40));
+    assert!(madeleine.take_snapshot(false).is_err());
+    assert_eq!(madeleine.next_snapshot_id().ok(), Some(0));
+    assert_eq!(madeleine.take_snapshot(false).ok(), Some(0));
+  }
+}
@@ -0,0 +1,479 @@
+use std::sync::Mutex;
+
+use commitlog::Offset;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::command::{Command, CommandContext};
+use crate::command_log::next_id;
+use crate::locks::lock_recovering;
+use crate::logged_command::RawLoggedCommand;
+use crate::madeleine::Madeleine;
+use crate::madeleine_error::MadeleineError;
+use crate::payload_format::PayloadFormat;
+
+/// A command which can be undone, for stores wrapped in an `UndoableMadeleine`.
+pub trait UndoableCommand<'a>: Command<'a> {
+  /// Revert the command's effect, given the state as it's left once every command executed after it has been undone.
+  fn undo(&self, current_state: Self::SystemState) -> Self::SystemState;
+}
+
+/// A command whose effect can be reverted by another command of the same type, see `Madeleine::undo_last`.
+/// Unlike an `UndoableCommand`, it's logged as itself, and so is its inverse.
+pub trait InvertibleCommand<'a>: Command<'a> {
+  /// The command which reverts this one's effect, given the state it was executed on.
+  fn invert(&self, state_before: &Self::SystemState) -> Self;
+}
+
+/// How an undo marker's JSON payload starts, to rule out other entries without parsing them.
+const UNDO_MARKER_PREFIX: &[u8] = b"{\"$undone\"";
+
+/// Logged by `Madeleine::undo_last` right after the inverse of the command it undid, in the same append,
+/// so that undoing again skips both. Markers are always JSON, and are skipped on replay like tombstones.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct UndoMarker {
+  /// ULID of the command which was undone.
+  #[serde(rename = "$undone")]
+  undone: Ulid,
+}
+
+/// A log entry marking the command identified by `undone` as undone by the entry before it.
+pub(crate) fn undo_marker_entry(undone: Ulid) -> Result<Vec<u8>, MadeleineError> {
+  Ok(serde_json::to_vec(&(next_id(), UndoMarker { undone }))?)
+}
+
+impl RawLoggedCommand {
+  /// The ULID of the command undone by `Madeleine::undo_last`, if this is the marker it logged after the inverse.
+  pub fn undone_command(&self) -> Option<Ulid> {
+    if self.format != PayloadFormat::Json || !self.payload.starts_with(UNDO_MARKER_PREFIX) {
+      return None;
+    }
+
+    serde_json::from_slice::<UndoMarker>(&self.payload)
+      .ok()
+      .map(|marker| marker.undone)
+  }
+}
+
+/// How an `UndoableMadeleine` logs its commands, so that replaying the log reproduces undos and redos.
+/// Stores written by one are resumed with `Madeleine::resume_replaying::<Undoable<C>, _>`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum Undoable<C> {
+  /// The command was executed.
+  Done(C),
+  /// The command was undone, and so replays by executing its `undo`.
+  Undone(C),
+  /// The command was executed again after being undone.
+  Redone(C),
+}
+
+impl<'a, C: UndoableCommand<'a>> Command<'a> for Undoable<C> {
+  type SystemState = C::SystemState;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    match self {
+      Self::Done(command) | Self::Redone(command) => command.execute(old_state),
+      Self::Undone(command) => command.undo(old_state),
+    }
+  }
+
+  fn execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Self::SystemState {
+    match self {
+      Self::Done(command) | Self::Redone(command) => command.execute_with_ctx(old_state, ctx),
+      Self::Undone(command) => command.undo(old_state),
+    }
+  }
+
+  fn try_execute_with_ctx(
+    &self,
+    old_state: Self::SystemState,
+    ctx: &CommandContext,
+  ) -> Result<Self::SystemState, String> {
+    match self {
+      Self::Done(command) | Self::Redone(command) => command.try_execute_with_ctx(old_state, ctx),
+      Self::Undone(command) => Ok(command.undo(old_state)),
+    }
+  }
+
+  /// Undos aren't validated, since they restore a state the command was executed on.
+  fn validate(&self, state: &Self::SystemState) -> Result<(), String> {
+    match self {
+      Self::Done(command) | Self::Redone(command) => command.validate(state),
+      Self::Undone(_command) => Ok(()),
+    }
+  }
+}
+
+/// Commands which an `UndoableMadeleine` can undo, most recent last, and those it can redo, most recently undone last.
+struct UndoStacks<C> {
+  undo: Vec<C>,
+  redo: Vec<C>,
+}
+
+/// An instance whose commands of type `C` can be undone and redone, e.g. for editors or interactive tools.
+///
+/// Undos and redos are logged as `Undoable` commands after the commands they act on, so the log stays append-only
+/// and resuming the store reproduces them. What can be undone and redone is kept in memory, so it starts afresh
+/// with each wrapper, and only covers commands executed through it: commands executed on the wrapped instance
+/// directly are only undone as far as the undone commands' `undo` copes with them.
+///
+/// ```
+/// # use madeleine::testing::scratch_store;
+/// # use madeleine::{Command, Madeleine};
+/// use madeleine::{Undoable, UndoableCommand, UndoableMadeleine};
+///
+/// #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+/// struct Add(u64);
+///
+/// impl Command<'_> for Add {
+///   type SystemState = u64;
+///
+///   fn execute(&self, old_state: u64) -> u64 {
+///     old_state + self.0
+///   }
+/// }
+///
+/// impl UndoableCommand<'_> for Add {
+///   fn undo(&self, current_state: u64) -> u64 {
+///     current_state - self.0
+///   }
+/// }
+///
+/// let store = scratch_store();
+///
+/// {
+///   let madeleine = UndoableMadeleine::new(Madeleine::new(&store, || 0)?);
+///   madeleine.execute_command(Add(2))?;
+///   madeleine.execute_command(Add(3))?;
+///   madeleine.undo()?;
+///
+///   assert_eq!(madeleine.madeleine().tap(|state| state), 2);
+///
+///   madeleine.redo()?;
+///   madeleine.undo()?;
+/// }
+///
+/// let resumed = Madeleine::resume_replaying::<Undoable<Add>, _>(&store, || 0)?;
+///
+/// assert_eq!(resumed.tap(|state| state), 2);
+/// # Ok::<(), madeleine::MadeleineError>(())
+/// ```
+pub struct UndoableMadeleine<C, SystemState: Clone + for<'a> Deserialize<'a> + Serialize> {
+  madeleine: Madeleine<SystemState>,
+  stacks: Mutex<UndoStacks<C>>,
+}
+
+impl<C, SystemState> UndoableMadeleine<C, SystemState>
+where
+  C: for<'a> UndoableCommand<'a, SystemState = SystemState> + Clone,
+  SystemState: Clone + for<'a> Deserialize<'a> + Serialize,
+{
+  /// Wrap an instance, with nothing to undo or redo yet.
+  pub fn new(madeleine: Madeleine<SystemState>) -> Self {
+    Self {
+      madeleine,
+      stacks: Mutex::new(UndoStacks {
+        undo: Vec::new(),
+        redo: Vec::new(),
+      }),
+    }
+  }
+
+  /// Execute and log a command as `Madeleine::execute_command` does, making it the next to be undone.
+  /// Commands undone before it can no longer be redone.
+  pub fn execute_command(&self, command: C) -> Result<Offset, MadeleineError> {
+    let mut stacks = lock_recovering(&self.stacks);
+    let offset = self
+      .madeleine
+      .execute_command(Undoable::Done(command.clone()))?;
+
+    stacks.undo.push(command);
+    stacks.redo.clear();
+
+    Ok(offset)
+  }
+
+  /// Undo the most recent command which hasn't been undone, making it the next to be redone.
+  /// Fails with `MadeleineError::UndoError` if there's none.
+  pub fn undo(&self) -> Result<(), MadeleineError> {
+    let mut stacks = lock_recovering(&self.stacks);
+    let command = stacks
+      .undo
+      .last()
+      .cloned()
+      .ok_or_else(|| MadeleineError::UndoError(String::from("there's nothing to undo")))?;
+
+    self.madeleine.execute_command(Undoable::Undone(command))?;
+
+    if let Some(command) = stacks.undo.pop() {
+      stacks.redo.push(command);
+    }
+
+    Ok(())
+  }
+
+  /// Execute the most recently undone command again, making it the next to be undone.
+  /// Fails with `MadeleineError::UndoError` if there's none, or with the command's own error
+  /// if the state no longer lets it execute, in which case it's left to be redone.
+  pub fn redo(&self) -> Result<(), MadeleineError> {
+    let mut stacks = lock_recovering(&self.stacks);
+    let command = stacks
+      .redo
+      .last()
+      .cloned()
+      .ok_or_else(|| MadeleineError::UndoError(String::from("there's nothing to redo")))?;
+
+    self.madeleine.execute_command(Undoable::Redone(command))?;
+
+    if let Some(command) = stacks.redo.pop() {
+      stacks.undo.push(command);
+    }
+
+    Ok(())
+  }
+
+  /// Determine if there's a command to undo.
+  pub fn can_undo(&self) -> bool {
+    !lock_recovering(&self.stacks).undo.is_empty()
+  }
+
+  /// Determine if there's a command to redo.
+  pub fn can_redo(&self) -> bool {
+    !lock_recovering(&self.stacks).redo.is_empty()
+  }
+
+  /// The wrapped instance, e.g. to read its state.
+  pub fn madeleine(&self) -> &Madeleine<SystemState> {
+    &self.madeleine
+  }
+
+  /// Unwrap the instance, forgetting what could be undone and redone.
+  pub fn into_inner(self) -> Madeleine<SystemState> {
+    self.madeleine
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::Arc;
+
+  use pretty_assertions::assert_eq;
+
+  use crate::testing::{FailpointStore, StorageOperation};
+
+  /// Appends to a document, keeping what it appended so that it can be removed.
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct Append(String);
+
+  impl Command<'_> for Append {
+    type SystemState = String;
+
+    fn execute(&self, mut old_state: Self::SystemState) -> Self::SystemState {
+      old_state.push_str(&self.0);
+      old_state
+    }
+
+    fn validate(&self, _state: &Self::SystemState) -> Result<(), String> {
+      if self.0.is_empty() {
+        return Err(String::from("nothing to append"));
+      }
+
+      Ok(())
+    }
+  }
+
+  impl UndoableCommand<'_> for Append {
+    fn undo(&self, mut current_state: Self::SystemState) -> Self::SystemState {
+      current_state.truncate(current_state.len().saturating_sub(self.0.len()));
+      current_state
+    }
+  }
+
+  #[test]
+  fn test_undo_and_redo_are_logged_and_replayed() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let store_path = temp_dir.path().join("test_store");
+
+    {
+      let madeleine = UndoableMadeleine::new(
+        Madeleine::new(&store_path, String::new).expect("unable to instantiate madeleine in test"),
+      );
+
+      for word in ["red", " panda", " naps"] {
+        madeleine
+          .execute_command(Append(String::from(word)))
+          .expect("unable to execute command in test");
+      }
+
+      madeleine.undo().expect("unable to undo in test");
+      madeleine.undo().expect("unable to undo in test");
+
+      assert_eq!(madeleine.madeleine().tap(|state| state), "red");
+
+      madeleine.redo().expect("unable to redo in test");
+
+      assert_eq!(madeleine.madeleine().tap(|state| state), "red panda");
+      assert!(madeleine.can_redo());
+
+      madeleine
+        .execute_command(Append(String::from(" eats")))
+        .expect("unable to execute command in test");
+
+      assert!(!madeleine.can_redo());
+      assert!(matches!(
+        madeleine.redo(),
+        Err(MadeleineError::UndoError(_))
+      ));
+      assert_eq!(madeleine.madeleine().tap(|state| state), "red panda eats");
+      assert_eq!(madeleine.madeleine().len(), 7);
+    }
+
+    let resumed = Madeleine::resume_replaying::<Undoable<Append>, _>(&store_path, String::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(resumed.tap(|state| state), "red panda eats");
+  }
+
+  #[test]
+  fn test_nothing_is_undone_by_failed_or_rejected_commands() {
+    let madeleine = UndoableMadeleine::new(
+      Madeleine::new_in_memory(String::new).expect("unable to instantiate madeleine in test"),
+    );
+
+    assert!(!madeleine.can_undo());
+    assert!(matches!(
+      madeleine.undo(),
+      Err(MadeleineError::UndoError(_))
+    ));
+
+    madeleine
+      .execute_command(Append(String::from("koala")))
+      .expect("unable to execute command in test");
+
+    assert!(matches!(
+      madeleine.execute_command(Append(String::new())),
+      Err(MadeleineError::CommandRejected(_))
+    ));
+
+    madeleine.undo().expect("unable to undo in test");
+
+    assert_eq!(madeleine.madeleine().tap(|state| state), "");
+    assert!(!madeleine.can_undo());
+    assert_eq!(madeleine.madeleine().len(), 2);
+  }
+
+  /// Renames a document, so that renaming it back inverts it.
+  #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+  struct Rename(String);
+
+  impl Command<'_> for Rename {
+    type SystemState = String;
+
+    fn execute(&self, _old_state: Self::SystemState) -> Self::SystemState {
+      self.0.clone()
+    }
+  }
+
+  impl InvertibleCommand<'_> for Rename {
+    fn invert(&self, state_before: &Self::SystemState) -> Self {
+      Rename(state_before.clone())
+    }
+  }
+
+  #[test]
+  fn test_undo_last_walks_back_through_commands_across_restarts() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+    let store_path = temp_dir.path().join("test_store");
+
+    {
+      let madeleine =
+        Madeleine::new(&store_path, String::new).expect("unable to instantiate madeleine in test");
+
+      assert!(matches!(
+        madeleine.undo_last::<Rename, _>(String::new),
+        Err(MadeleineError::UndoError(_))
+      ));
+
+      for name in ["red", "panda", "koala"] {
+        madeleine
+          .execute_command(Rename(String::from(name)))
+          .expect("unable to execute command in test");
+      }
+
+      madeleine
+        .undo_last::<Rename, _>(String::new)
+        .expect("unable to undo in test");
+
+      assert_eq!(madeleine.tap(|state| state), "panda");
+    }
+
+    let madeleine = Madeleine::resume_replaying::<Rename, _>(&store_path, String::new)
+      .expect("unable to resume madeleine in test");
+
+    assert_eq!(madeleine.tap(|state| state), "panda");
+
+    madeleine
+      .undo_last::<Rename, _>(String::new)
+      .expect("unable to undo in test");
+
+    assert_eq!(madeleine.tap(|state| state), "red");
+
+    madeleine
+      .undo_last::<Rename, _>(String::new)
+      .expect("unable to undo in test");
+
+    assert_eq!(madeleine.tap(|state| state), "");
+    assert!(matches!(
+      madeleine.undo_last::<Rename, _>(String::new),
+      Err(MadeleineError::UndoError(_))
+    ));
+    assert_eq!(madeleine.len(), 9);
+  }
+
+  #[test]
+  fn test_undo_last_appends_inverse_and_marker_together() {
+    let madeleine =
+      Madeleine::new_in_memory(String::new).expect("unable to instantiate madeleine in test");
+
+    for name in ["red", "panda"] {
+      madeleine
+        .execute_command(Rename(String::from(name)))
+        .expect("unable to execute command in test");
+    }
+
+    let failpoints = Arc::new(FailpointStore::new());
+    failpoints.fail_nth(StorageOperation::Append, 1);
+    madeleine
+      .set_failpoints(Some(failpoints))
+      .expect("unable to set failpoints in test");
+
+    assert!(madeleine.undo_last::<Rename, _>(String::new).is_err());
+    assert_eq!(madeleine.tap(|state| state), "panda");
+    assert_eq!(madeleine.len(), 2);
+
+    madeleine
+      .undo_last::<Rename, _>(String::new)
+      .expect("unable to undo in test");
+
+    let logged = madeleine
+      .follower()
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test");
+
+    assert_eq!(madeleine.tap(|state| state), "red");
+    assert_eq!(logged.len(), 4);
+    assert_eq!(
+      logged[2].deserialize::<Rename>().ok(),
+      Some(Rename(String::from("red")))
+    );
+    assert_eq!(logged[3].undone_command(), Some(logged[1].id));
+    assert!(logged[3].is_skipped_on_replay());
+  }
+}
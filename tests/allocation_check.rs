@@ -0,0 +1,161 @@
+//! Allocation checks, measured by a counting allocator installed for this test binary only.
+#![cfg(feature = "allocation-check")]
+
+use madeleine::{
+  AllocationCheck, AllocationCounter, BatchMode, Command, Madeleine, MadeleineError, MutCommand,
+};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::Arc;
+
+thread_local! {
+  static THREAD_ALLOCATED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Counts the bytes each thread allocates, then defers to the system allocator.
+struct CountingAllocator;
+
+// SAFETY: every call is forwarded unchanged to the system allocator.
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    let _ =
+      THREAD_ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size() as u64));
+
+    unsafe { System.alloc(layout) }
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe { System.dealloc(ptr, layout) }
+  }
+
+  unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    let _ = THREAD_ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + new_size as u64));
+
+    unsafe { System.realloc(ptr, layout, new_size) }
+  }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+struct ThreadCounter;
+
+impl AllocationCounter for ThreadCounter {
+  fn thread_bytes_allocated(&self) -> u64 {
+    THREAD_ALLOCATED.with(Cell::get)
+  }
+}
+
+/// Adds the length of a scratch buffer of the given size, which it builds on the way.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Scratch(usize);
+
+impl Command<'_> for Scratch {
+  type SystemState = u64;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    let scratch = vec![1_u8; self.0];
+
+    old_state + scratch.len() as u64
+  }
+}
+
+impl MutCommand<'_> for Scratch {
+  fn execute_mut(&self, state: &mut Self::SystemState) {
+    let scratch = vec![1_u8; self.0];
+
+    *state += scratch.len() as u64;
+  }
+}
+
+fn checked_madeleine(temp_dir: &assert_fs::TempDir) -> Madeleine<u64> {
+  let madeleine = Madeleine::new(temp_dir.path().join("test_store"), || 0_u64)
+    .expect("unable to instantiate madeleine in test");
+
+  madeleine
+    .set_allocation_check(Some(AllocationCheck::new(
+      Arc::new(ThreadCounter),
+      64 * 1024,
+    )))
+    .expect("unable to set allocation check in test");
+
+  madeleine
+}
+
+#[test]
+fn test_command_over_limit_leaves_state_and_log_untouched() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = checked_madeleine(&temp_dir);
+
+  madeleine
+    .execute_command(Scratch(1024))
+    .expect("unable to execute command in test");
+  madeleine
+    .execute_command(Scratch(1024))
+    .expect("unable to execute command in test");
+
+  let over_limit = madeleine.execute_command(Scratch(1024 * 1024));
+
+  assert!(matches!(
+    over_limit,
+    Err(MadeleineError::CommandResourceLimit { bytes_allocated }) if bytes_allocated >= 1024 * 1024
+  ));
+  assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(2048));
+  assert_eq!(madeleine.len(), 2);
+
+  madeleine
+    .set_allocation_check(None)
+    .expect("unable to clear allocation check in test");
+  madeleine
+    .execute_command(Scratch(1024 * 1024))
+    .expect("unable to execute command in test");
+
+  assert_eq!(
+    madeleine.tap_ref(|state| *state).ok(),
+    Some(2048 + 1024 * 1024)
+  );
+}
+
+#[test]
+fn test_atomic_batch_over_limit_is_rolled_back() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = checked_madeleine(&temp_dir);
+
+  let failed = madeleine.execute_batch(
+    vec![Scratch(1024), Scratch(1024 * 1024), Scratch(1024)],
+    BatchMode::Atomic,
+  );
+
+  assert!(matches!(
+    failed,
+    Err(MadeleineError::BatchFailed { failure, staged: 1 })
+      if failure.error.starts_with("Command resource limit")
+  ));
+  assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(0));
+  assert!(madeleine.is_empty());
+}
+
+#[test]
+fn test_mut_command_over_limit_leaves_state_and_log_untouched() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = checked_madeleine(&temp_dir);
+
+  madeleine
+    .execute_command_mut(Scratch(1024))
+    .expect("unable to execute command in test");
+
+  let over_limit = madeleine.execute_command_mut(Scratch(1024 * 1024));
+
+  assert!(matches!(
+    over_limit,
+    Err(MadeleineError::CommandResourceLimit { bytes_allocated }) if bytes_allocated >= 1024 * 1024
+  ));
+  assert_eq!(madeleine.tap_ref(|state| *state).ok(), Some(1024));
+  assert_eq!(madeleine.len(), 1);
+}
@@ -0,0 +1,169 @@
+//! Executing commands from async tasks on a tokio runtime.
+#![cfg(feature = "async")]
+
+use std::time::{Duration, Instant};
+
+use madeleine::testing::Add;
+use madeleine::{
+  CancellationToken, Command, Follower, Madeleine, MadeleineError, RateLimit, RateLimits,
+  SharedMadeleine,
+};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// Adds slowly, so replaying many takes a while.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SlowAdd(u64);
+
+impl Command<'_> for SlowAdd {
+  type SystemState = u64;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    std::thread::sleep(Duration::from_millis(2));
+
+    old_state + self.0
+  }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_commands_from_many_tasks_are_all_logged() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+  let location = temp_dir.path().join("test_store");
+
+  let madeleine =
+    Madeleine::new(location.clone(), || 0).expect("unable to instantiate madeleine in test");
+  let shared = SharedMadeleine::new(madeleine).expect("unable to share madeleine in test");
+
+  let tasks = (1..=20)
+    .map(|task| {
+      let shared = shared.clone();
+
+      tokio::spawn(async move {
+        for _i in 0..5 {
+          shared
+            .execute_command_async(Add(task))
+            .await
+            .expect("unable to execute command in test");
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+
+  for task in tasks {
+    task.await.expect("unable to join task in test");
+  }
+
+  assert_eq!(shared.tap(|state| state).ok(), Some(1050));
+  assert_eq!(
+    Follower::open(location.clone())
+      .expect("unable to open follower in test")
+      .commands_after(Ulid::nil())
+      .expect("unable to read commands in test")
+      .len(),
+    100
+  );
+
+  drop(shared);
+
+  let resumed = Madeleine::<u64>::resume_replaying::<Add, _>(location, || 0)
+    .expect("unable to resume madeleine in test");
+
+  assert_eq!(resumed.tap(|state| state), 1050);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_rate_limited_commands_wait_up_to_max_delay() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let shared_with_delay = |name: &str, max_async_delay: Duration| {
+    let madeleine = Madeleine::new(temp_dir.path().join(name), || 0)
+      .expect("unable to instantiate madeleine in test");
+
+    madeleine
+      .set_rate_limits(RateLimits {
+        commands: Some(RateLimit {
+          per_second: 50,
+          burst: 1,
+        }),
+        max_async_delay,
+        ..RateLimits::default()
+      })
+      .expect("unable to set rate limits in test");
+
+    SharedMadeleine::new(madeleine).expect("unable to share madeleine in test")
+  };
+
+  let patient = shared_with_delay("patient_store", Duration::from_secs(1));
+  let started_at = Instant::now();
+
+  for _i in 0..3 {
+    patient
+      .execute_command_async(Add(1))
+      .await
+      .expect("unable to execute command in test");
+  }
+
+  // The first command takes the burst, and each after it waits 20ms for a token.
+  assert!(started_at.elapsed() >= Duration::from_millis(40));
+  assert_eq!(patient.tap(|state| state).ok(), Some(3));
+
+  let impatient = shared_with_delay("impatient_store", Duration::ZERO);
+
+  impatient
+    .execute_command_async(Add(1))
+    .await
+    .expect("unable to execute command in test");
+
+  let limited = impatient.execute_command_async(Add(1)).await;
+
+  assert!(matches!(limited, Err(MadeleineError::RateLimited { .. })));
+  assert_eq!(impatient.tap(|state| state).ok(), Some(1));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dropping_async_resume_cancels_replay() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+  let location = temp_dir.path().join("test_store");
+
+  let madeleine =
+    Madeleine::new(location.clone(), || 0).expect("unable to instantiate madeleine in test");
+
+  for _i in 0..200 {
+    madeleine
+      .execute_command(Add(1))
+      .expect("unable to execute command in test");
+  }
+
+  drop(madeleine);
+
+  let cancellation = CancellationToken::new();
+  let timed_out = tokio::time::timeout(
+    Duration::from_millis(20),
+    SharedMadeleine::resume_replaying_async::<SlowAdd, _>(
+      location.clone(),
+      || 0,
+      cancellation.clone(),
+    ),
+  )
+  .await;
+
+  assert!(timed_out.is_err());
+  assert!(cancellation.is_cancelled());
+
+  let cancelled =
+    SharedMadeleine::resume_replaying_async::<SlowAdd, _>(location.clone(), || 0, cancellation)
+      .await;
+
+  assert!(matches!(
+    cancelled.err(),
+    Some(MadeleineError::Cancelled { completed: 0 })
+  ));
+
+  let resumed =
+    SharedMadeleine::resume_replaying_async::<Add, _>(location, || 0, CancellationToken::new())
+      .await
+      .expect("unable to resume madeleine in test");
+
+  assert_eq!(resumed.tap(|state| state).ok(), Some(200));
+}
@@ -0,0 +1,372 @@
+//! Golden stores written by earlier releases, which every later release must still be able to read.
+//! To add fixtures, delete or add a directory or golden file under `tests/fixtures` and run `cargo test --features gen-fixtures`.
+
+use madeleine::export::ExportRange;
+#[cfg(feature = "blake3")]
+use madeleine::HashAlgo;
+#[cfg(feature = "gen-fixtures")]
+use madeleine::{codec::GZIP_CODEC_ID, MadeleineBuilder};
+use madeleine::{
+  AuditOptions, Command, DirectoryPolicy, Follower, LogBackend, Madeleine, VerificationLevel,
+};
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PLAIN_FIXTURE: &str = "plain_json";
+const SNAPSHOT_FIXTURE: &str = "with_snapshot";
+/// Logged to a CRC-checked file, with a full snapshot followed by a differential one which builds on it.
+const CRC_FILE_FIXTURE: &str = "crc_file";
+/// Commands and snapshot compressed with gzip.
+const GZIP_FIXTURE: &str = "gzip_payloads";
+/// Commands and snapshot compressed with zstd.
+#[cfg(feature = "zstd")]
+const ZSTD_FIXTURE: &str = "zstd_payloads";
+/// Snapshot and state hashed with BLAKE3.
+#[cfg(feature = "blake3")]
+const BLAKE3_FIXTURE: &str = "blake3_hashes";
+/// Audit export of the plain fixture, which must stay byte for byte the same.
+const AUDIT_GOLDEN: &str = "plain_json.audit.jsonl";
+
+/// Hash of the state after replaying every command in either fixture.
+const FINAL_STATE_HASH: &str = "cfea9aced9e0908a2529cfe55f1ebcf463551bbb33d8abd29d15ddcc0a899bad";
+/// Hash of the state recorded in the snapshot fixture's snapshot.
+const SNAPSHOT_STATE_HASH: &str =
+  "852df05adecf981ae7819914d29356e56d78bc22aca91520a81376a925131d53";
+
+type Counters = BTreeMap<String, usize>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+enum Action {
+  Increment(String, usize),
+  Decrement(String, usize),
+}
+
+impl Command<'_> for Action {
+  type SystemState = Counters;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    let mut new_state = old_state;
+
+    match self {
+      Self::Increment(key, amount) => *new_state.entry(key.to_string()).or_insert(0) += amount,
+      Self::Decrement(key, amount) => *new_state.entry(key.to_string()).or_insert(0) -= amount,
+    }
+
+    new_state
+  }
+}
+
+fn actions() -> Vec<Action> {
+  vec![
+    Action::Increment("panda".to_string(), 600),
+    Action::Increment("koala".to_string(), 20),
+    Action::Increment("panda".to_string(), 15),
+    Action::Decrement("koala".to_string(), 7),
+    Action::Decrement("panda".to_string(), 2),
+  ]
+}
+
+fn fixture_path(name: &str) -> PathBuf {
+  Path::new(env!("CARGO_MANIFEST_DIR"))
+    .join("tests")
+    .join("fixtures")
+    .join(name)
+}
+
+/// Copy a fixture somewhere it can be opened, since opening a store may write to it.
+fn copy_fixture(name: &str, temp_dir: &assert_fs::TempDir) -> PathBuf {
+  let destination = temp_dir.path().join(name);
+
+  copy_dir(&fixture_path(name), &destination);
+
+  destination
+}
+
+fn copy_dir(source: &Path, destination: &Path) {
+  fs::create_dir_all(destination).expect("unable to create directory in test");
+
+  for entry in fs::read_dir(source).expect("unable to read fixture in test") {
+    let entry = entry.expect("unable to read fixture entry in test");
+    let target = destination.join(entry.file_name());
+
+    if entry.path().is_dir() {
+      copy_dir(&entry.path(), &target);
+    } else {
+      fs::copy(entry.path(), target).expect("unable to copy fixture in test");
+    }
+  }
+}
+
+/// Options for the audit export of the plain fixture.
+fn audit_options() -> AuditOptions {
+  AuditOptions {
+    payload_preview_bytes: Some(20),
+    ..AuditOptions::default()
+  }
+}
+
+/// Audit export of every command in a store.
+fn export_audit(madeleine: &Madeleine<Counters>) -> Vec<u8> {
+  let mut exported = Vec::new();
+
+  madeleine
+    .export_audit(ExportRange::default(), &audit_options(), &mut exported)
+    .expect("unable to export audit in test");
+
+  exported
+}
+
+/// Replay every logged command into a fresh state.
+fn replay(store_path: PathBuf) -> Madeleine<Counters> {
+  let commands = Follower::open(store_path.clone())
+    .and_then(|follower| follower.commands_after(Ulid::nil()))
+    .expect("unable to read fixture commands in test");
+
+  let state = commands.iter().fold(Counters::new(), |state, command| {
+    command
+      .deserialize::<Action>()
+      .expect("unable to deserialize fixture command in test")
+      .execute(state)
+  });
+
+  Madeleine::new_with_directory_policy(store_path, DirectoryPolicy::RequireExistingStore, || state)
+    .expect("unable to open fixture in test")
+}
+
+/// Resume a fixture by replaying its log, which is verified in full since fixtures are never closed,
+/// then check that replaying it from scratch agrees with its snapshots.
+fn resume_verified(store_path: PathBuf) -> Madeleine<Counters> {
+  let madeleine = Madeleine::builder(store_path)
+    .verification(VerificationLevel::Full)
+    .resume_replaying::<Action, _>(Counters::new)
+    .expect("unable to resume fixture in test");
+
+  let report = madeleine.open_report();
+
+  assert_eq!(report.verification, Some(VerificationLevel::Full));
+  assert_eq!(report.entries_verified, actions().len() as u64);
+  assert_eq!(
+    madeleine
+      .verify_determinism::<Action, _>(Counters::new)
+      .expect("unable to verify fixture in test"),
+    None
+  );
+
+  madeleine
+}
+
+#[test]
+fn test_plain_fixture_replays_to_pinned_hash() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = replay(copy_fixture(PLAIN_FIXTURE, &temp_dir));
+
+  assert_eq!(madeleine.len(), actions().len() as u64);
+  assert_eq!(
+    madeleine
+      .state_hash()
+      .expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+#[test]
+fn test_plain_fixture_audit_export_matches_golden_file() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = replay(copy_fixture(PLAIN_FIXTURE, &temp_dir));
+
+  let golden = fs::read(fixture_path(AUDIT_GOLDEN)).expect("unable to read golden file in test");
+
+  assert_eq!(
+    String::from_utf8_lossy(&export_audit(&madeleine)),
+    String::from_utf8_lossy(&golden)
+  );
+}
+
+#[test]
+fn test_snapshot_fixture_resumes_and_replays_to_pinned_hashes() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let store_path = copy_fixture(SNAPSHOT_FIXTURE, &temp_dir);
+
+  let resumed: Madeleine<Counters> =
+    Madeleine::resume(store_path.clone()).expect("unable to resume fixture in test");
+
+  assert_eq!(
+    resumed.state_hash().expect("unable to hash state in test"),
+    SNAPSHOT_STATE_HASH
+  );
+
+  drop(resumed);
+
+  let replayed = replay(store_path);
+
+  assert_eq!(
+    replayed.state_hash().expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+#[test]
+fn test_crc_file_fixture_resumes_through_snapshot_chain() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = resume_verified(copy_fixture(CRC_FILE_FIXTURE, &temp_dir));
+
+  assert_eq!(madeleine.log_backend(), LogBackend::File);
+  assert_eq!(
+    madeleine
+      .state_hash()
+      .expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+#[test]
+fn test_gzip_fixture_resumes_to_pinned_hash() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = resume_verified(copy_fixture(GZIP_FIXTURE, &temp_dir));
+
+  assert_eq!(
+    madeleine
+      .state_hash()
+      .expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd_fixture_resumes_to_pinned_hash() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = resume_verified(copy_fixture(ZSTD_FIXTURE, &temp_dir));
+
+  assert_eq!(
+    madeleine
+      .state_hash()
+      .expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+#[cfg(feature = "blake3")]
+#[test]
+fn test_blake3_fixture_resumes_to_pinned_hash() {
+  let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+  let madeleine = resume_verified(copy_fixture(BLAKE3_FIXTURE, &temp_dir));
+
+  assert_eq!(madeleine.hash_algo(), HashAlgo::Blake3);
+  // The pinned hash is SHA-256, whatever the store hashes with.
+  assert_eq!(
+    HashAlgo::Sha256
+      .canonical_hash(&madeleine.tap(|state| state))
+      .expect("unable to hash state in test"),
+    FINAL_STATE_HASH
+  );
+}
+
+/// Write a fixture unless it exists, executing every action and snapshotting after those at `snapshot_after`.
+#[cfg(feature = "gen-fixtures")]
+fn generate_fixture<F>(name: &str, snapshot_after: &[usize], configure: F)
+where
+  F: FnOnce(MadeleineBuilder<Counters>) -> MadeleineBuilder<Counters>,
+{
+  let path = fixture_path(name);
+
+  if path.exists() {
+    return;
+  }
+
+  let madeleine = configure(Madeleine::builder(path))
+    .build(Counters::new)
+    .expect("unable to create fixture in test");
+
+  for (index, action) in actions().into_iter().enumerate() {
+    madeleine
+      .execute_command(action)
+      .expect("unable to execute fixture command in test");
+
+    if snapshot_after.contains(&index) {
+      madeleine
+        .take_snapshot(false)
+        .expect("unable to snapshot fixture in test");
+    }
+  }
+}
+
+/// Write any fixtures which don't exist yet. Existing fixtures are never overwritten.
+/// Fixtures for optional codecs and hash algorithms also need their features, e.g. `--features gen-fixtures,zstd,blake3`.
+#[cfg(feature = "gen-fixtures")]
+#[test]
+fn generate_fixtures() {
+  let plain_path = fixture_path(PLAIN_FIXTURE);
+
+  if !plain_path.exists() {
+    let madeleine =
+      Madeleine::new(plain_path, Counters::new).expect("unable to create fixture in test");
+
+    for action in actions() {
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute fixture command in test");
+    }
+  }
+
+  let audit_path = fixture_path(AUDIT_GOLDEN);
+
+  if !audit_path.exists() {
+    let temp_dir = assert_fs::TempDir::new().expect("unable to create temp dir in test");
+
+    let madeleine = replay(copy_fixture(PLAIN_FIXTURE, &temp_dir));
+
+    fs::write(audit_path, export_audit(&madeleine)).expect("unable to write golden file in test");
+  }
+
+  let snapshot_path = fixture_path(SNAPSHOT_FIXTURE);
+
+  if !snapshot_path.exists() {
+    let madeleine =
+      Madeleine::new(snapshot_path, Counters::new).expect("unable to create fixture in test");
+
+    for (index, action) in actions().into_iter().enumerate() {
+      madeleine
+        .execute_command(action)
+        .expect("unable to execute fixture command in test");
+
+      if index == 2 {
+        madeleine
+          .take_snapshot(true)
+          .expect("unable to snapshot fixture in test");
+      }
+    }
+  }
+  generate_fixture(CRC_FILE_FIXTURE, &[1, 3], |builder| {
+    builder.log_backend(LogBackend::File).full_snapshot_every(2)
+  });
+  generate_fixture(GZIP_FIXTURE, &[2], |builder| {
+    builder
+      .payload_codec(GZIP_CODEC_ID)
+      .snapshot_codec(GZIP_CODEC_ID)
+  });
+
+  #[cfg(feature = "zstd")]
+  generate_fixture(ZSTD_FIXTURE, &[2], |builder| {
+    builder
+      .payload_codec(madeleine::codec::ZSTD_CODEC_ID)
+      .snapshot_codec(madeleine::codec::ZSTD_CODEC_ID)
+  });
+
+  #[cfg(feature = "blake3")]
+  generate_fixture(BLAKE3_FIXTURE, &[2], |builder| {
+    builder.hash_algo(HashAlgo::Blake3)
+  });
+}
@@ -0,0 +1,83 @@
+//! Property test of the crate's persistence with `madeleine::testing::PersistenceHarness`,
+//! driving arbitrary interleavings of commands, crashes, compactions and snapshots.
+#![cfg(feature = "testing")]
+
+use madeleine::testing::PersistenceHarness;
+use madeleine::Command;
+use proptest::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+enum Action {
+  Increment(String, usize),
+  Decrement(String, usize),
+}
+
+impl Command<'_> for Action {
+  type SystemState = HashMap<String, usize>;
+
+  fn execute(&self, old_state: Self::SystemState) -> Self::SystemState {
+    let mut new_state = old_state;
+
+    match self {
+      Self::Increment(key, amount) => *new_state.entry(key.to_string()).or_insert(0) += amount,
+      // Saturates, so that generated decrements can't underflow.
+      Self::Decrement(key, amount) => {
+        let count = new_state.entry(key.to_string()).or_insert(0);
+        *count = count.saturating_sub(*amount);
+      }
+    }
+
+    new_state
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+  Apply(Vec<Action>),
+  CrashAndResume,
+  Compact,
+  Snapshot,
+}
+
+fn action() -> impl Strategy<Value = Action> {
+  let key = prop_oneof![Just("panda"), Just("koala"), Just("otter")].prop_map(String::from);
+
+  prop_oneof![
+    (key.clone(), 0..100_usize).prop_map(|(key, amount)| Action::Increment(key, amount)),
+    (key, 0..100_usize).prop_map(|(key, amount)| Action::Decrement(key, amount)),
+  ]
+}
+
+fn operation() -> impl Strategy<Value = Operation> {
+  prop_oneof![
+    4 => prop::collection::vec(action(), 0..5).prop_map(Operation::Apply),
+    1 => Just(Operation::CrashAndResume),
+    1 => Just(Operation::Compact),
+    1 => Just(Operation::Snapshot),
+  ]
+}
+
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(32))]
+
+  #[test]
+  fn test_persistence_survives_any_interleaving(operations in prop::collection::vec(operation(), 1..16)) {
+    let mut harness = PersistenceHarness::<Action, HashMap<String, usize>>::new(HashMap::new)
+      .expect("unable to create harness in test");
+
+    for operation in operations {
+      match operation {
+        Operation::Apply(actions) => harness.apply(actions),
+        Operation::CrashAndResume => harness.crash_and_resume(),
+        Operation::Compact => harness.compact(),
+        Operation::Snapshot => harness.snapshot().map(|_snapshot_id| ()),
+      }
+      .expect("unable to run operation in test");
+
+      harness.assert_invariants();
+    }
+  }
+}
@@ -0,0 +1,11 @@
+//! Traits only Madeleine implements stay sealed, while the extension points meant for applications stay open.
+
+#[test]
+fn test_sealed_traits_cant_be_implemented_outside_the_crate() {
+  trybuild::TestCases::new().compile_fail("tests/ui/sealed/*.rs");
+}
+
+#[test]
+fn test_open_traits_can_be_implemented_outside_the_crate() {
+  trybuild::TestCases::new().pass("tests/ui/open/*.rs");
+}
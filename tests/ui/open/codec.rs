@@ -0,0 +1,28 @@
+use std::io::{self, Read, Write};
+
+use madeleine::{Codec, MadeleineError};
+
+/// Leaves data as it is.
+struct Identity;
+
+impl Codec for Identity {
+  fn codec_id(&self) -> &str {
+    "identity"
+  }
+
+  fn compress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(), MadeleineError> {
+    io::copy(reader, writer)?;
+
+    Ok(())
+  }
+
+  fn decompress(&self, reader: &mut dyn Read, writer: &mut dyn Write) -> Result<(), MadeleineError> {
+    io::copy(reader, writer)?;
+
+    Ok(())
+  }
+}
+
+fn main() {
+  madeleine::codec::register(std::sync::Arc::new(Identity));
+}
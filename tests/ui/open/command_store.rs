@@ -0,0 +1,42 @@
+use madeleine::{CommandStore, MadeleineError, StoredRecord};
+
+#[derive(Default)]
+struct VecStore(Vec<Vec<u8>>);
+
+impl CommandStore for VecStore {
+  fn append(&mut self, entry: &[u8]) -> Result<u64, MadeleineError> {
+    self.0.push(entry.to_vec());
+
+    Ok(self.0.len() as u64 - 1)
+  }
+
+  fn read(&self, offset: u64, _max_bytes: usize) -> Result<Vec<StoredRecord>, MadeleineError> {
+    Ok(
+      self
+        .0
+        .iter()
+        .enumerate()
+        .skip(offset as usize)
+        .map(|(offset, entry)| StoredRecord {
+          offset: offset as u64,
+          entry: entry.clone(),
+          sequence: None,
+        })
+        .collect(),
+    )
+  }
+
+  fn truncate(&mut self, keep: u64) -> Result<(), MadeleineError> {
+    self.0.truncate(keep as usize);
+
+    Ok(())
+  }
+
+  fn len(&self) -> u64 {
+    self.0.len() as u64
+  }
+}
+
+fn main() {
+  let _store: Box<dyn CommandStore> = Box::new(VecStore::default());
+}
@@ -0,0 +1,14 @@
+use std::time::Instant;
+
+use madeleine::rate_limit::Clock;
+
+#[derive(Debug)]
+struct FrozenClock(Instant);
+
+impl Clock for FrozenClock {
+  fn now(&self) -> Instant {
+    self.0
+  }
+}
+
+fn main() {}
@@ -0,0 +1,22 @@
+use madeleine::{CommandSerializer, MadeleineError, PayloadFormat};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Writes indented JSON, which would read back like any other JSON.
+struct PrettyJson;
+
+impl CommandSerializer for PrettyJson {
+  fn format(&self) -> PayloadFormat {
+    PayloadFormat::Json
+  }
+
+  fn serialize<C: Serialize>(&self, command: &C) -> Result<Vec<u8>, MadeleineError> {
+    Ok(serde_json::to_vec_pretty(command)?)
+  }
+
+  fn deserialize<C: DeserializeOwned>(&self, payload: &[u8]) -> Result<C, MadeleineError> {
+    Ok(serde_json::from_slice(payload)?)
+  }
+}
+
+fn main() {}